@@ -0,0 +1,53 @@
+//! Command line arguments
+
+use clap::Parser;
+
+/// Load tester for Pubky homeservers
+///
+/// Signs up `--users` keypairs, writes `--entries` entries for each, then
+/// keeps `--concurrency` requests in flight for `--duration` seconds,
+/// choosing reads, writes and listings by their relative weights.
+#[derive(Debug, Parser)]
+#[command(name = "pubky-bench", version, about)]
+pub struct Cli {
+    /// Homeserver URL
+    #[arg(
+        long,
+        env = "PUBKY_HOMESERVER",
+        value_name = "URL",
+        default_value = "http://127.0.0.1:3000"
+    )]
+    pub homeserver: String,
+
+    /// Keypairs to spread the load over
+    #[arg(long, default_value_t = 100)]
+    pub users: usize,
+
+    /// Requests in flight at once
+    #[arg(long, default_value_t = 32)]
+    pub concurrency: usize,
+
+    /// Seconds to run the load for, after seeding
+    #[arg(long, default_value_t = 30)]
+    pub duration: u64,
+
+    /// Relative weight of reads of single entries
+    #[arg(long, default_value_t = 70)]
+    pub reads: u32,
+
+    /// Relative weight of writes, overwriting seeded entries
+    #[arg(long, default_value_t = 20)]
+    pub writes: u32,
+
+    /// Relative weight of listings of a user's entries
+    #[arg(long, default_value_t = 10)]
+    pub lists: u32,
+
+    /// Entries per user
+    #[arg(long, default_value_t = 10)]
+    pub entries: usize,
+
+    /// Bytes per entry
+    #[arg(long, default_value_t = 1024)]
+    pub size: usize,
+}
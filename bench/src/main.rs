@@ -0,0 +1,210 @@
+//! Pubky load tester
+//!
+//! Drives a running homeserver with a configurable mix of reads, writes and
+//! listings across many keypairs, and reports throughput and latency
+//! percentiles, for capacity planning.
+
+mod cli;
+mod stats;
+
+use clap::Parser;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use pubky_client::{PubkyClient, RetryPolicy};
+use pubky_common::{Keypair, PublicKey};
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cli::Cli;
+use stats::Stats;
+
+type BenchResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// A signed-up keypair and its client
+struct User {
+    public_key: PublicKey,
+    client: PubkyClient,
+}
+
+/// Kinds of requests, in report order
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Read,
+    Write,
+    List,
+}
+
+const OPS: [(Op, &str); 3] = [(Op::Read, "read"), (Op::Write, "write"), (Op::List, "list")];
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    if let Err(e) = run(&cli).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: &Cli) -> BenchResult<()> {
+    let (rows, elapsed) = bench(cli).await?;
+    let mut rows: Vec<(&str, Stats)> = OPS.iter().map(|(_, name)| *name).zip(rows).collect();
+    let mut total = Stats::default();
+    for (_, stats) in &rows {
+        total.merge(stats.clone());
+    }
+    rows.push(("total", total));
+    print!("{}", stats::report(&mut rows, elapsed));
+    Ok(())
+}
+
+/// Seed the homeserver, then run the load, returning the stats of each
+/// kind of request and how long the load ran
+async fn bench(cli: &Cli) -> BenchResult<([Stats; 3], Duration)> {
+    let weights = [cli.reads, cli.writes, cli.lists];
+    if cli.users == 0 || cli.entries == 0 || weights.iter().all(|&weight| weight == 0) {
+        return Err("Need at least one user, one entry and one kind of request".into());
+    }
+
+    eprintln!("Signing up {} users...", cli.users);
+    let users: Vec<User> = stream::iter(0..cli.users)
+        .map(|_| seed(cli))
+        .buffer_unordered(cli.concurrency.max(1))
+        .try_collect()
+        .await?;
+    let users = Arc::new(users);
+
+    eprintln!(
+        "Running for {}s with {} requests in flight...",
+        cli.duration, cli.concurrency
+    );
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(cli.duration);
+    let workers: Vec<_> = (0..cli.concurrency.max(1))
+        .map(|_| {
+            let users = users.clone();
+            let (entries, size) = (cli.entries, cli.size);
+            tokio::spawn(async move { work(&users, weights, entries, size, deadline).await })
+        })
+        .collect();
+
+    let mut rows: [Stats; 3] = Default::default();
+    for worker in workers {
+        for (row, stats) in rows.iter_mut().zip(worker.await?) {
+            row.merge(stats);
+        }
+    }
+    Ok((rows, started.elapsed()))
+}
+
+/// Sign up a new keypair and write its entries
+async fn seed(cli: &Cli) -> BenchResult<User> {
+    let keypair = Keypair::random();
+    // Retries would hide the latency of failed attempts
+    let client = PubkyClient::builder()
+        .homeserver(cli.homeserver.clone())
+        .retry(RetryPolicy::none())
+        .build()?;
+    client.signup(&keypair, None).await?;
+    let public_key = keypair.public_key();
+    for entry in 0..cli.entries {
+        client
+            .put(public_key, &path(entry), vec![0u8; cli.size])
+            .await?;
+    }
+    Ok(User { public_key, client })
+}
+
+/// Send requests one after the other until `deadline`
+async fn work(
+    users: &[User],
+    weights: [u32; 3],
+    entries: usize,
+    size: usize,
+    deadline: Instant,
+) -> [Stats; 3] {
+    let mut rows: [Stats; 3] = Default::default();
+    let total: u32 = weights.iter().sum();
+    while Instant::now() < deadline {
+        let (user, entry, mut pick) = {
+            let mut rng = rand::rng();
+            (
+                &users[rng.random_range(0..users.len())],
+                rng.random_range(0..entries),
+                rng.random_range(0..total),
+            )
+        };
+        let index = weights
+            .iter()
+            .position(|&weight| match pick < weight {
+                true => true,
+                false => {
+                    pick -= weight;
+                    false
+                }
+            })
+            .expect("the pick is below the total weight");
+
+        let started = Instant::now();
+        let ok = match OPS[index].0 {
+            Op::Read => user.client.get(user.public_key, &path(entry)).await.is_ok(),
+            Op::Write => {
+                let value = vec![rand::random::<u8>(); size];
+                user.client
+                    .put(user.public_key, &path(entry), value)
+                    .await
+                    .is_ok()
+            }
+            Op::List => user.client.list(user.public_key, "bench/").await.is_ok(),
+        };
+        rows[index].record(started.elapsed(), ok);
+    }
+    rows
+}
+
+fn path(entry: usize) -> String {
+    format!("bench/{}", entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_server::Server;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bench() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let url = server.url();
+        let cli = Cli::parse_from([
+            "pubky-bench",
+            "--homeserver",
+            &url,
+            "--users",
+            "3",
+            "--entries",
+            "2",
+            "--concurrency",
+            "4",
+            "--duration",
+            "1",
+            "--lists",
+            "0",
+        ]);
+
+        let (rows, elapsed) = bench(&cli).await.unwrap();
+        assert!(elapsed >= Duration::from_secs(1));
+        let [reads, writes, lists] = rows;
+        assert!(reads.requests() > 0 && writes.requests() > 0);
+        assert_eq!(reads.errors() + writes.errors(), 0);
+        assert_eq!(lists.requests(), 0);
+        let users = server.storage().users();
+        assert_eq!(users.len(), 3);
+        assert!(users.iter().all(|user| user.entries == 2));
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,103 @@
+//! Latency statistics
+
+use std::fmt::Write as _;
+use std::time::Duration;
+
+/// Outcomes of one kind of request
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+impl Stats {
+    /// Record a request that took `latency`
+    pub fn record(&mut self, latency: Duration, ok: bool) {
+        self.latencies.push(latency);
+        if !ok {
+            self.errors += 1;
+        }
+    }
+
+    /// Add the outcomes recorded by another worker
+    pub fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+    }
+
+    pub fn requests(&self) -> usize {
+        self.latencies.len()
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// The latency under which `percent` of the requests completed
+    pub fn percentile(&mut self, percent: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        self.latencies.sort_unstable();
+        // Nearest rank
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+/// Render a table of `(name, stats)` rows for a run lasting `elapsed`
+pub fn report(rows: &mut [(&str, Stats)], elapsed: Duration) -> String {
+    let mut table = format!(
+        "{:<6} {:>9} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9}\n",
+        "op", "requests", "errors", "req/s", "p50", "p90", "p99", "max"
+    );
+    for (name, stats) in rows.iter_mut() {
+        let rate = stats.requests() as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        let _ = writeln!(
+            table,
+            "{:<6} {:>9} {:>7} {:>9.1} {:>9} {:>9} {:>9} {:>9}",
+            name,
+            stats.requests(),
+            stats.errors(),
+            rate,
+            millis(stats.percentile(50.0)),
+            millis(stats.percentile(90.0)),
+            millis(stats.percentile(99.0)),
+            millis(stats.percentile(100.0)),
+        );
+    }
+    table
+}
+
+fn millis(latency: Duration) -> String {
+    format!("{:.2}ms", latency.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.percentile(99.0), Duration::ZERO);
+        for ms in (1..=100).rev() {
+            stats.record(Duration::from_millis(ms), ms != 7);
+        }
+        assert_eq!(stats.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(stats.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(stats.percentile(100.0), Duration::from_millis(100));
+        assert_eq!(stats.percentile(0.0), Duration::from_millis(1));
+
+        let mut other = Stats::default();
+        other.record(Duration::from_millis(500), false);
+        stats.merge(other);
+        assert_eq!((stats.requests(), stats.errors()), (101, 2));
+
+        let mut rows = [("read", stats)];
+        let table = report(&mut rows, Duration::from_secs(2));
+        let row = table.lines().nth(1).unwrap();
+        assert!(row.starts_with("read         101       2      50.5"));
+        assert!(row.ends_with("500.00ms"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Node.js bindings
+//!
+//! Exposes the client and crypto primitives as a native module for Electron
+//! and server-side JavaScript. Build with `npm run build`, which uses
+//! `@napi-rs/cli` to produce the `.node` file along with `index.js` and
+//! TypeScript definitions.
+
+use napi::bindgen_prelude::Buffer;
+use napi::{Error, Result};
+use napi_derive::napi;
+
+fn to_napi(error: impl std::fmt::Display) -> Error {
+    Error::from_reason(error.to_string())
+}
+
+/// An Ed25519 keypair
+#[napi]
+pub struct Keypair(pubky_common::Keypair);
+
+#[napi]
+impl Keypair {
+    /// Generate a new random keypair
+    #[napi(factory)]
+    pub fn random() -> Self {
+        Self(pubky_common::Keypair::random())
+    }
+
+    /// Restore a keypair from its 32-byte secret key
+    #[napi(factory)]
+    pub fn from_secret_key(secret_key: Buffer) -> Result<Self> {
+        let secret_key: [u8; 32] = secret_key
+            .as_ref()
+            .try_into()
+            .map_err(|_| Error::from_reason("secret key must be 32 bytes"))?;
+        Ok(Self(pubky_common::Keypair::from_secret_key(&secret_key)))
+    }
+
+    /// The 32-byte secret key
+    #[napi]
+    pub fn secret_key(&self) -> Buffer {
+        self.0.secret_key().to_vec().into()
+    }
+
+    /// The z-base-32 public key
+    #[napi]
+    pub fn public_key(&self) -> String {
+        self.0.public_key().to_z32()
+    }
+
+    /// Sign a message, returning the 64-byte signature
+    #[napi]
+    pub fn sign(&self, message: Buffer) -> Buffer {
+        self.0.sign(&message).to_bytes().to_vec().into()
+    }
+}
+
+/// Whether `signature` is a valid signature of `message` by the z-base-32
+/// `public_key`
+#[napi]
+pub fn verify(public_key: String, message: Buffer, signature: Buffer) -> Result<bool> {
+    let public_key = pubky_common::PublicKey::from_z32(&public_key).map_err(to_napi)?;
+    let Ok(signature) = <[u8; 64]>::try_from(signature.as_ref()) else {
+        return Ok(false);
+    };
+    let signature = pubky_common::Signature::from_bytes(&signature);
+    Ok(public_key.verify(&message, &signature).is_ok())
+}
+
+/// A session on a homeserver
+#[napi(object)]
+pub struct Session {
+    pub public_key: String,
+    pub token: String,
+    /// Unix timestamp in milliseconds
+    pub created_at: i64,
+    /// Unix timestamp in milliseconds
+    pub expires_at: i64,
+}
+
+impl From<pubky_client::SessionInfo> for Session {
+    fn from(session: pubky_client::SessionInfo) -> Self {
+        Self {
+            public_key: session.public_key,
+            token: session.token,
+            created_at: session.created_at as i64,
+            expires_at: session.expires_at as i64,
+        }
+    }
+}
+
+/// Client for a homeserver's storage API
+///
+/// Owners are given as z-base-32 public keys or `pubky://` URLs.
+#[napi]
+pub struct PubkyClient(pubky_client::PubkyClient);
+
+#[napi]
+impl PubkyClient {
+    /// Create a client for the homeserver at the given base URL
+    #[napi(constructor)]
+    pub fn new(homeserver: String) -> Self {
+        Self(pubky_client::PubkyClient::new(homeserver))
+    }
+
+    /// Store `body` at `path` for the given owner
+    #[napi]
+    pub async fn put(&self, owner: String, path: String, body: Buffer) -> Result<()> {
+        let body = body.to_vec();
+        self.0.put(owner, &path, body).await.map_err(to_napi)
+    }
+
+    /// Retrieve the data at `path`, or `null` if there is none
+    #[napi]
+    pub async fn get(&self, owner: String, path: String) -> Result<Option<Buffer>> {
+        let data = self.0.get(owner, &path).await.map_err(to_napi)?;
+        Ok(data.map(|bytes| bytes.to_vec().into()))
+    }
+
+    /// Delete the data at `path`, returning whether it existed
+    #[napi]
+    pub async fn delete(&self, owner: String, path: String) -> Result<bool> {
+        self.0.delete(owner, &path).await.map_err(to_napi)
+    }
+
+    /// List the paths of all entries under `prefix`
+    #[napi]
+    pub async fn list(&self, owner: String, prefix: String) -> Result<Vec<String>> {
+        self.0.list(owner, &prefix).await.map_err(to_napi)
+    }
+
+    /// Register the keypair with the homeserver and start a session
+    #[napi]
+    pub async fn signup(&self, keypair: &Keypair, invite_code: Option<String>) -> Result<Session> {
+        let session = self.0.signup(&keypair.0, invite_code.as_deref()).await;
+        session.map(Session::from).map_err(to_napi)
+    }
+
+    /// Sign in to an account that signed up before
+    #[napi]
+    pub async fn signin(&self, keypair: &Keypair) -> Result<Session> {
+        let session = self.0.signin(&keypair.0).await;
+        session.map(Session::from).map_err(to_napi)
+    }
+
+    /// End the current session
+    #[napi]
+    pub async fn signout(&self) -> Result<()> {
+        self.0.signout().await.map_err(to_napi)
+    }
+
+    /// The current session, or `null` if signed out
+    #[napi]
+    pub fn session(&self) -> Option<Session> {
+        self.0.session().map(Session::from)
+    }
+}
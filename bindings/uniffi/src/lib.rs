@@ -0,0 +1,343 @@
+//! UniFFI bindings for mobile apps
+//!
+//! Exposes [`Keypair`], [`PublicKey`] and [`PubkyClient`] to Swift and Kotlin,
+//! so apps reuse the Rust signing and wire protocol instead of
+//! re-implementing them. Generate the foreign sources from the built
+//! library:
+//!
+//! ```bash
+//! cargo build -p pubky-uniffi --release
+//! cargo run -p pubky-uniffi --features cli --bin uniffi-bindgen -- \
+//!     generate --library target/release/libpubky_uniffi.so \
+//!     --language swift --out-dir out
+//! ```
+
+use pubky_client::ClientError;
+use std::sync::Arc;
+
+uniffi::setup_scaffolding!();
+
+/// Errors surfaced to foreign code
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+pub enum PubkyError {
+    #[error("Invalid key: {message}")]
+    InvalidKey { message: String },
+
+    #[error("Network error: {message}")]
+    Network { message: String },
+
+    #[error("Not found")]
+    NotFound,
+
+    #[error("Unauthorized")]
+    Unauthorized,
+
+    #[error("Storage quota exceeded")]
+    QuotaExceeded,
+
+    #[error("Conflict: expected {expected:?}, found {actual:?}")]
+    Conflict {
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+
+    #[error("Server returned {status}: {message}")]
+    Status {
+        status: u16,
+        code: Option<String>,
+        message: String,
+    },
+
+    #[error("I/O error: {message}")]
+    Io { message: String },
+
+    #[error("Unavailable: {message}")]
+    Unavailable { message: String },
+
+    #[error("Encryption error: {message}")]
+    Encryption { message: String },
+
+    #[error("Invalid URL: {message}")]
+    InvalidUrl { message: String },
+}
+
+impl From<pubky_common::Error> for PubkyError {
+    fn from(error: pubky_common::Error) -> Self {
+        PubkyError::InvalidKey {
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<ClientError> for PubkyError {
+    fn from(error: ClientError) -> Self {
+        match error {
+            ClientError::InvalidPublicKey(e) => e.into(),
+            ClientError::Network(e) => PubkyError::Network {
+                message: e.to_string(),
+            },
+            ClientError::Io(e) => PubkyError::Io {
+                message: e.to_string(),
+            },
+            ClientError::NotFound => PubkyError::NotFound,
+            ClientError::Unauthorized => PubkyError::Unauthorized,
+            ClientError::QuotaExceeded => PubkyError::QuotaExceeded,
+            ClientError::Conflict { expected, actual } => PubkyError::Conflict { expected, actual },
+            ClientError::Status {
+                status,
+                code,
+                message,
+            } => PubkyError::Status {
+                status: status.as_u16(),
+                code,
+                message,
+            },
+            ClientError::CircuitOpen => PubkyError::Unavailable {
+                message: error.to_string(),
+            },
+            ClientError::Encryption(message) => PubkyError::Encryption { message },
+            ClientError::InvalidUrl(message) => PubkyError::InvalidUrl { message },
+            ClientError::Pkarr(_) => PubkyError::Unavailable {
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+/// An Ed25519 keypair
+#[derive(uniffi::Object)]
+pub struct Keypair(pubky_common::Keypair);
+
+#[uniffi::export]
+impl Keypair {
+    /// Generate a new random keypair
+    #[uniffi::constructor]
+    pub fn random() -> Arc<Self> {
+        Arc::new(Self(pubky_common::Keypair::random()))
+    }
+
+    /// Restore a keypair from its 32-byte secret key
+    #[uniffi::constructor]
+    pub fn from_secret_key(secret_key: Vec<u8>) -> Result<Arc<Self>, PubkyError> {
+        let secret_key: [u8; 32] = secret_key.try_into().map_err(|_| PubkyError::InvalidKey {
+            message: "secret key must be 32 bytes".to_string(),
+        })?;
+        Ok(Arc::new(Self(pubky_common::Keypair::from_secret_key(
+            &secret_key,
+        ))))
+    }
+
+    /// The 32-byte secret key
+    pub fn secret_key(&self) -> Vec<u8> {
+        self.0.secret_key().to_vec()
+    }
+
+    /// The public key
+    pub fn public_key(&self) -> Arc<PublicKey> {
+        Arc::new(PublicKey(self.0.public_key()))
+    }
+
+    /// Sign a message, returning the 64-byte signature
+    pub fn sign(&self, message: Vec<u8>) -> Vec<u8> {
+        self.0.sign(&message).to_bytes().to_vec()
+    }
+}
+
+/// An Ed25519 public key
+#[derive(uniffi::Object)]
+pub struct PublicKey(pubky_common::PublicKey);
+
+#[uniffi::export]
+impl PublicKey {
+    /// Parse a z-base-32 public key
+    #[uniffi::constructor]
+    pub fn from_z32(z32: String) -> Result<Arc<Self>, PubkyError> {
+        Ok(Arc::new(Self(pubky_common::PublicKey::from_z32(&z32)?)))
+    }
+
+    /// Create a public key from its 32 bytes
+    #[uniffi::constructor]
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Arc<Self>, PubkyError> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| pubky_common::Error::InvalidPublicKey)?;
+        Ok(Arc::new(Self(pubky_common::PublicKey::from_bytes(&bytes)?)))
+    }
+
+    /// Encode as z-base-32
+    pub fn to_z32(&self) -> String {
+        self.0.to_z32()
+    }
+
+    /// The 32 key bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes().to_vec()
+    }
+
+    /// Whether `signature` is a valid signature of `message` by this key
+    pub fn verify(&self, message: Vec<u8>, signature: Vec<u8>) -> bool {
+        let Ok(signature) = <[u8; 64]>::try_from(signature) else {
+            return false;
+        };
+        let signature = pubky_common::Signature::from_bytes(&signature);
+        self.0.verify(&message, &signature).is_ok()
+    }
+}
+
+/// A session on a homeserver
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+pub struct Session {
+    pub public_key: String,
+    pub token: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl From<pubky_client::SessionInfo> for Session {
+    fn from(session: pubky_client::SessionInfo) -> Self {
+        Self {
+            public_key: session.public_key,
+            token: session.token,
+            created_at: session.created_at,
+            expires_at: session.expires_at,
+        }
+    }
+}
+
+impl From<Session> for pubky_client::SessionInfo {
+    fn from(session: Session) -> Self {
+        Self {
+            public_key: session.public_key,
+            token: session.token,
+            created_at: session.created_at,
+            expires_at: session.expires_at,
+        }
+    }
+}
+
+/// Client for a homeserver's storage API
+///
+/// Owners are given as z-base-32 public keys or `pubky://` URLs.
+#[derive(uniffi::Object)]
+pub struct PubkyClient(pubky_client::PubkyClient);
+
+#[uniffi::export(async_runtime = "tokio")]
+impl PubkyClient {
+    /// Create a client for the homeserver at the given base URL
+    #[uniffi::constructor]
+    pub fn new(homeserver: String) -> Arc<Self> {
+        Arc::new(Self(pubky_client::PubkyClient::new(homeserver)))
+    }
+
+    /// Create a client that resumes a previously persisted session
+    #[uniffi::constructor]
+    pub fn with_session(homeserver: String, session: Session) -> Result<Arc<Self>, PubkyError> {
+        let client = pubky_client::PubkyClient::builder()
+            .homeserver(homeserver)
+            .session(session.into())
+            .build()?;
+        Ok(Arc::new(Self(client)))
+    }
+
+    /// Store `body` at `path` for the given owner
+    pub async fn put(&self, owner: String, path: String, body: Vec<u8>) -> Result<(), PubkyError> {
+        Ok(self.0.put(owner, &path, body).await?)
+    }
+
+    /// Retrieve the data at `path`, or nothing if there is none
+    pub async fn get(&self, owner: String, path: String) -> Result<Option<Vec<u8>>, PubkyError> {
+        let data = self.0.get(owner, &path).await?;
+        Ok(data.map(|bytes| bytes.to_vec()))
+    }
+
+    /// Delete the data at `path`, returning whether it existed
+    pub async fn delete(&self, owner: String, path: String) -> Result<bool, PubkyError> {
+        Ok(self.0.delete(owner, &path).await?)
+    }
+
+    /// List the paths of all entries under `prefix`
+    pub async fn list(&self, owner: String, prefix: String) -> Result<Vec<String>, PubkyError> {
+        Ok(self.0.list(owner, &prefix).await?)
+    }
+
+    /// Register the keypair with the homeserver and start a session
+    pub async fn signup(
+        &self,
+        keypair: Arc<Keypair>,
+        invite_code: Option<String>,
+    ) -> Result<Session, PubkyError> {
+        let session = self.0.signup(&keypair.0, invite_code.as_deref()).await?;
+        Ok(session.into())
+    }
+
+    /// Sign in to an account that signed up before
+    pub async fn signin(&self, keypair: Arc<Keypair>) -> Result<Session, PubkyError> {
+        Ok(self.0.signin(&keypair.0).await?.into())
+    }
+
+    /// End the current session
+    pub async fn signout(&self) -> Result<(), PubkyError> {
+        Ok(self.0.signout().await?)
+    }
+
+    /// The current session, if signed in
+    pub fn session(&self) -> Option<Session> {
+        self.0.session().map(Session::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_server::Server;
+
+    #[test]
+    fn test_keys() {
+        let keypair = Keypair::random();
+        let restored = Keypair::from_secret_key(keypair.secret_key()).unwrap();
+        assert_eq!(
+            restored.public_key().to_z32(),
+            keypair.public_key().to_z32()
+        );
+
+        let public_key = PublicKey::from_z32(keypair.public_key().to_z32()).unwrap();
+        let signature = keypair.sign(b"hello".to_vec());
+        assert!(public_key.verify(b"hello".to_vec(), signature.clone()));
+        assert!(!public_key.verify(b"other".to_vec(), signature));
+
+        assert!(Keypair::from_secret_key(vec![0; 31]).is_err());
+        assert!(PublicKey::from_z32("invalid".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let client = PubkyClient::new(server.url());
+        let keypair = Keypair::random();
+        let owner = keypair.public_key().to_z32();
+
+        let session = client.signup(keypair, None).await.unwrap();
+        assert_eq!(client.session(), Some(session));
+
+        client
+            .put(owner.clone(), "app/a.txt".to_string(), b"hello".to_vec())
+            .await
+            .unwrap();
+        let data = client.get(owner.clone(), "app/a.txt".to_string()).await;
+        assert_eq!(data.unwrap(), Some(b"hello".to_vec()));
+        let keys = client.list(owner.clone(), "app/".to_string()).await;
+        assert_eq!(keys.unwrap(), vec!["app/a.txt"]);
+
+        let err = client
+            .get("invalid".to_string(), "app/a.txt".to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PubkyError::InvalidKey { .. }));
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,120 @@
+//! Command line arguments
+
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Command line client for Pubky homeservers
+///
+/// Entries are addressed as `pubky://<public_key>/<path>`; the scheme may
+/// be omitted.
+#[derive(Debug, Parser)]
+#[command(name = "pubky", version, about)]
+pub struct Cli {
+    /// Homeserver URL (defaults to the configured homeserver)
+    #[arg(long, global = true, env = "PUBKY_HOMESERVER", value_name = "URL")]
+    pub homeserver: Option<String>,
+
+    /// Sign in with this identity from the keystore before the command
+    #[arg(long, global = true, env = "PUBKY_KEY", value_name = "NAME")]
+    pub key: Option<String>,
+
+    /// Print every request and response to stderr, with secrets redacted
+    #[arg(long, global = true)]
+    pub trace: bool,
+
+    /// Print each request as an equivalent curl command to stderr
+    #[arg(long, global = true)]
+    pub curl: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Generate a new identity and store it in the keystore
+    Keygen {
+        /// Name of the identity in the keystore
+        #[arg(long, default_value = "default")]
+        name: String,
+    },
+    /// Manage identities in the encrypted keystore
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Store a file (or stdin) at a URL
+    Put {
+        url: String,
+        /// File to upload; reads stdin if omitted or `-`
+        file: Option<PathBuf>,
+    },
+    /// Download an entry to a file
+    Get {
+        url: String,
+        /// Destination file; defaults to the entry's file name
+        out: Option<PathBuf>,
+    },
+    /// Print an entry to stdout
+    Cat { url: String },
+    /// List the entries under a URL
+    Ls { url: String },
+    /// Delete an entry
+    Rm { url: String },
+    /// Upload a local directory to a URL prefix, or download it with --pull
+    ///
+    /// Only files that differ are transferred.
+    Sync {
+        dir: PathBuf,
+        url: String,
+        /// Download remote entries into the directory instead
+        #[arg(long)]
+        pull: bool,
+        /// Also delete remote entries that don't exist locally
+        #[arg(long, conflicts_with = "pull")]
+        delete: bool,
+    },
+    /// List or revoke the sessions of the identity given with --key
+    Sessions {
+        #[command(subcommand)]
+        command: Option<SessionsCommand>,
+    },
+    /// Approve another device's pubkyauth:// request with the identity
+    /// given with --key
+    Authorize {
+        /// URL scanned from the device's QR code
+        url: String,
+    },
+    /// Show or change the configuration
+    Config {
+        #[command(subcommand)]
+        command: Option<ConfigCommand>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyCommand {
+    /// List stored identities
+    List,
+    /// Import an identity
+    ///
+    /// Reads a file written by `key export`, or prompts for a hex-encoded
+    /// secret key if no file is given.
+    Import { name: String, file: Option<PathBuf> },
+    /// Write an identity, still encrypted, to a file or stdout
+    Export { name: String, file: Option<PathBuf> },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SessionsCommand {
+    /// List active sessions, one per signed-in device (the default)
+    List,
+    /// Revoke a session by its id, signing that device out
+    Revoke { id: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Set the default homeserver URL
+    SetHomeserver { url: String },
+}
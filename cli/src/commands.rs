@@ -0,0 +1,325 @@
+//! Implementation of the CLI subcommands
+
+use pubky_client::{AuthorizationUrl, Progress, PubkyClient, PubkyUrl, SyncMode, SyncReport};
+use pubky_common::keystore::EncryptedKeypair;
+use pubky_common::{Keypair, PublicKey};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::keystore::{read_passphrase, Keystore};
+use crate::CliResult;
+
+/// Split `pubky://<public_key>/<path>` (scheme optional) into its parts
+pub fn parse_url(url: &str) -> CliResult<(PublicKey, String)> {
+    let parsed = PubkyUrl::parse(url).map_err(|e| format!("invalid URL {:?}: {}", url, e))?;
+    Ok((parsed.public_key, parsed.path))
+}
+
+/// Generate a keypair and store it in the keystore
+pub fn keygen(keystore: &Keystore, name: &str) -> CliResult<()> {
+    let keypair = Keypair::random();
+    let passphrase = read_passphrase("New passphrase: ", true)?;
+    let path = keystore.store(name, &EncryptedKeypair::encrypt(&keypair, &passphrase)?)?;
+
+    eprintln!("Stored {} in {}", name, path.display());
+    println!("public key: {}", keypair.public_key());
+    println!("url:        pubky://{}/", keypair.public_key());
+    Ok(())
+}
+
+pub fn key_list(keystore: &Keystore) -> CliResult<()> {
+    for (name, key) in keystore.list()? {
+        println!("{}\t{}", name, key.public_key);
+    }
+    Ok(())
+}
+
+/// Import an exported identity, or a secret key typed at a hidden prompt
+pub fn key_import(keystore: &Keystore, name: &str, file: Option<&Path>) -> CliResult<()> {
+    let key = match file {
+        Some(file) => {
+            let key: EncryptedKeypair = serde_json::from_slice(&std::fs::read(file)?)?;
+            key.public_key()?;
+            key
+        }
+        None => {
+            let secret = rpassword::prompt_password("Secret key (hex): ")?;
+            let secret = from_hex(secret.trim()).ok_or("secret key must be 64 hex digits")?;
+            let keypair = Keypair::from_secret_key(&secret);
+            let passphrase = read_passphrase("New passphrase: ", true)?;
+            EncryptedKeypair::encrypt(&keypair, &passphrase)?
+        }
+    };
+
+    keystore.store(name, &key)?;
+    println!("{}\t{}", name, key.public_key);
+    Ok(())
+}
+
+/// Export an identity without decrypting it
+pub fn key_export(keystore: &Keystore, name: &str, file: Option<&Path>) -> CliResult<()> {
+    let json = serde_json::to_string_pretty(&keystore.load(name)?)?;
+    match file {
+        Some(file) => std::fs::write(file, json)?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+pub async fn put(client: &PubkyClient, url: &str, file: Option<&Path>) -> CliResult<()> {
+    let (public_key, path) = parse_url(url)?;
+    let body = match file {
+        Some(file) if file != Path::new("-") => tokio::fs::read(file).await?,
+        _ => {
+            let mut body = Vec::new();
+            tokio::io::stdin().read_to_end(&mut body).await?;
+            body
+        }
+    };
+
+    let len = body.len();
+    match std::io::stderr().is_terminal() {
+        true => {
+            client
+                .put_with_progress(&public_key, &path, body, show_progress)
+                .await?;
+            eprintln!();
+        }
+        false => client.put(&public_key, &path, body).await?,
+    }
+    eprintln!("Stored {} bytes at pubky://{}/{}", len, public_key, path);
+    Ok(())
+}
+
+pub async fn get(client: &PubkyClient, url: &str, out: Option<&Path>) -> CliResult<()> {
+    let (public_key, path) = parse_url(url)?;
+    let out = match out {
+        Some(out) => out.to_path_buf(),
+        None => match path.rsplit('/').next() {
+            Some(name) if !name.is_empty() => PathBuf::from(name),
+            _ => return Err("URL has no file name; give an output path".into()),
+        },
+    };
+
+    let data = match std::io::stderr().is_terminal() {
+        true => {
+            let data = client
+                .get_with_progress(&public_key, &path, show_progress)
+                .await?;
+            eprintln!();
+            found(data, &public_key, &path)?
+        }
+        false => fetch(client, &public_key, &path).await?,
+    };
+    tokio::fs::write(&out, &data).await?;
+    eprintln!("Saved {} bytes to {}", data.len(), out.display());
+    Ok(())
+}
+
+pub async fn cat(client: &PubkyClient, url: &str) -> CliResult<()> {
+    let (public_key, path) = parse_url(url)?;
+    let data = fetch(client, &public_key, &path).await?;
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&data).await?;
+    stdout.flush().await?;
+    Ok(())
+}
+
+pub async fn ls(client: &PubkyClient, url: &str) -> CliResult<()> {
+    let (public_key, path) = parse_url(url)?;
+    if path.is_empty() {
+        return Err("give a path prefix, e.g. pubky://<public_key>/my-app/".into());
+    }
+    for key in client.list(&public_key, &path).await? {
+        println!("pubky://{}/{}", public_key, key);
+    }
+    Ok(())
+}
+
+pub async fn rm(client: &PubkyClient, url: &str) -> CliResult<()> {
+    let (public_key, path) = parse_url(url)?;
+    if !client.delete(&public_key, &path).await? {
+        return Err(format!("{}: not found", url).into());
+    }
+    Ok(())
+}
+
+/// Print the sessions of the signed-in account, marking the current one
+pub async fn sessions(client: &PubkyClient) -> CliResult<()> {
+    for session in client.sessions().await? {
+        println!(
+            "{}{}\t{}\tlast used {} ago\t{}",
+            session.id,
+            if session.current { " *" } else { "" },
+            session.capabilities,
+            ago(session.last_used_at),
+            session.device.as_deref().unwrap_or("unknown device"),
+        );
+    }
+    Ok(())
+}
+
+pub async fn revoke_session(client: &PubkyClient, id: &str) -> CliResult<()> {
+    if !client.revoke_session(id).await? {
+        return Err(format!("{}: no such session", id).into());
+    }
+    Ok(())
+}
+
+/// Approve a device's authorization request after showing what it asks for
+pub async fn authorize(client: &PubkyClient, keypair: &Keypair, url: &str) -> CliResult<()> {
+    let url = AuthorizationUrl::parse(url)?;
+    eprintln!(
+        "Granting {} to a device via {}",
+        url.capabilities, url.relay
+    );
+    client.authorize(keypair, &url).await?;
+    eprintln!("Authorized");
+    Ok(())
+}
+
+/// Sync a local directory with a URL prefix
+pub async fn sync(
+    client: &PubkyClient,
+    dir: &Path,
+    url: &str,
+    mode: SyncMode,
+) -> CliResult<SyncReport> {
+    let (public_key, prefix) = parse_url(url)?;
+    if prefix.is_empty() {
+        return Err("give a path prefix, e.g. pubky://<public_key>/my-app/".into());
+    }
+
+    let report = client.sync_dir(dir, &public_key, &prefix, mode).await?;
+    for path in &report.uploaded {
+        println!("uploaded   {}", path);
+    }
+    for path in &report.downloaded {
+        println!("downloaded {}", path);
+    }
+    for path in &report.deleted {
+        println!("deleted    {}", path);
+    }
+    Ok(report)
+}
+
+async fn fetch(client: &PubkyClient, public_key: &PublicKey, path: &str) -> CliResult<Vec<u8>> {
+    found(client.get(public_key, path).await?, public_key, path)
+}
+
+fn found(
+    data: Option<impl Into<Vec<u8>>>,
+    public_key: &PublicKey,
+    path: &str,
+) -> CliResult<Vec<u8>> {
+    match data {
+        Some(data) => Ok(data.into()),
+        None => Err(format!("pubky://{}/{}: not found", public_key, path).into()),
+    }
+}
+
+/// Redraw the transfer progress on the current stderr line
+fn show_progress(progress: Progress) {
+    let mut stderr = std::io::stderr();
+    let _ = match progress.total {
+        Some(total) => write!(stderr, "\r{}/{} bytes", progress.transferred, total),
+        None => write!(stderr, "\r{} bytes", progress.transferred),
+    };
+    let _ = stderr.flush();
+}
+
+/// How long ago a Unix timestamp in milliseconds was, roughly
+fn ago(millis: u64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    let secs = now.saturating_sub(millis) / 1000;
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Parse a 64-digit hex string into a secret key
+fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_server::Server;
+
+    #[test]
+    fn test_parse_url() {
+        let public_key = Keypair::random().public_key();
+
+        let url = format!("pubky://{}/my-app/a.txt", public_key);
+        assert_eq!(
+            parse_url(&url).unwrap(),
+            (public_key, "my-app/a.txt".to_string())
+        );
+        let url = format!("{}/my-app/", public_key);
+        assert_eq!(parse_url(&url).unwrap().1, "my-app/");
+        let url = format!("pubky://{}", public_key);
+        assert_eq!(parse_url(&url).unwrap().1, "");
+
+        assert!(parse_url("pubky://nope/a.txt").is_err());
+    }
+
+    #[test]
+    fn test_from_hex() {
+        let hex: String = (0..32u8).map(|b| format!("{:02x}", b)).collect();
+        let bytes = from_hex(&hex).unwrap();
+        assert_eq!(bytes[31], 31);
+
+        assert!(from_hex(&hex[2..]).is_none());
+        assert!(from_hex(&hex.replace('0', "g")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let client = PubkyClient::new(server.url());
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        client.signup(&keypair, None).await.unwrap();
+
+        let dir = std::env::temp_dir().join(format!("pubky-sync-{}", public_key));
+        std::fs::create_dir_all(dir.join("posts")).unwrap();
+        std::fs::write(dir.join("profile.json"), "{}").unwrap();
+        std::fs::write(dir.join("posts/1.txt"), "first").unwrap();
+        client
+            .put(&public_key, "site/stale.txt", "old")
+            .await
+            .unwrap();
+
+        let url = format!("pubky://{}/site", public_key);
+        let report = sync(&client, &dir, &url, SyncMode::Mirror).await.unwrap();
+        assert_eq!(report.uploaded.len(), 2);
+        assert_eq!(report.deleted, vec!["site/stale.txt"]);
+        let data = client.get(&public_key, "site/posts/1.txt").await.unwrap();
+        assert_eq!(data.as_deref(), Some(&b"first"[..]));
+
+        let url = format!("pubky://{}", public_key);
+        assert!(sync(&client, &dir, &url, SyncMode::Push).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        server.shutdown().await;
+    }
+}
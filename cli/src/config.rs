@@ -0,0 +1,60 @@
+//! CLI configuration file
+//!
+//! Stored as TOML at `$PUBKY_CONFIG`, or `pubky/config.toml` under
+//! `$XDG_CONFIG_HOME` (falling back to `~/.config`).
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::CliResult;
+
+/// Homeserver used when none is configured
+pub const DEFAULT_HOMESERVER: &str = "http://127.0.0.1:3000";
+
+/// Persistent CLI settings
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    /// Default homeserver URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homeserver: Option<String>,
+}
+
+impl Config {
+    /// Location of the configuration file
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("PUBKY_CONFIG") {
+            return Some(path.into());
+        }
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(base.join("pubky").join("config.toml"))
+    }
+
+    /// Load the configuration, or the defaults if there is no file
+    pub fn load() -> CliResult<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Ok(toml::from_str(&text)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("reading {}: {}", path.display(), e).into()),
+        }
+    }
+
+    /// Write the configuration file, creating its directory
+    pub fn save(&self) -> CliResult<PathBuf> {
+        let path = Self::path().ok_or("no configuration directory; set PUBKY_CONFIG")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)?;
+        Ok(path)
+    }
+
+    /// The homeserver to use when none is given on the command line
+    pub fn homeserver(&self) -> &str {
+        self.homeserver.as_deref().unwrap_or(DEFAULT_HOMESERVER)
+    }
+}
@@ -0,0 +1,173 @@
+//! Encrypted identity storage
+//!
+//! Identities are stored as passphrase-protected [`EncryptedKeypair`] JSON
+//! files named `<name>.json`, in `$PUBKY_KEYSTORE` or the `keys` directory
+//! next to the config file. Secret keys never touch the disk unencrypted.
+//!
+//! Passphrases come from `$PUBKY_PASSPHRASE`, from the output of
+//! `$PUBKY_PASSPHRASE_COMMAND` (for password managers and agents), or
+//! from an interactive prompt.
+
+use pubky_common::keystore::EncryptedKeypair;
+use pubky_common::Keypair;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::CliResult;
+
+/// Directory of encrypted identities
+pub struct Keystore {
+    dir: PathBuf,
+}
+
+impl Keystore {
+    /// Open the keystore at the given directory
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Open the keystore at its configured location
+    pub fn open() -> CliResult<Self> {
+        if let Some(dir) = std::env::var_os("PUBKY_KEYSTORE") {
+            return Ok(Self::new(dir));
+        }
+        let config = Config::path().ok_or("no configuration directory; set PUBKY_KEYSTORE")?;
+        let dir = config.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(Self::new(dir.join("keys")))
+    }
+
+    /// All stored identities, sorted by name
+    pub fn list(&self) -> CliResult<Vec<(String, EncryptedKeypair)>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let name = match path.extension() == Some("json".as_ref()) {
+                true => path.file_stem().map(|s| s.to_string_lossy().into_owned()),
+                false => None,
+            };
+            if let Some(name) = name {
+                keys.push((name.clone(), self.load(&name)?));
+            }
+        }
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(keys)
+    }
+
+    /// Load an encrypted identity by name
+    pub fn load(&self, name: &str) -> CliResult<EncryptedKeypair> {
+        let path = self.path(name)?;
+        let json = std::fs::read(&path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!("no key named {:?}", name),
+            _ => format!("reading {}: {}", path.display(), e),
+        })?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// Store an encrypted identity under a new name
+    pub fn store(&self, name: &str, key: &EncryptedKeypair) -> CliResult<PathBuf> {
+        let path = self.path(name)?;
+        std::fs::create_dir_all(&self.dir)?;
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let file = options.open(&path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => format!("a key named {:?} already exists", name),
+            _ => format!("creating {}: {}", path.display(), e),
+        })?;
+        serde_json::to_writer_pretty(file, key)?;
+        Ok(path)
+    }
+
+    /// Load and decrypt an identity, asking for its passphrase
+    pub fn unlock(&self, name: &str) -> CliResult<Keypair> {
+        let key = self.load(name)?;
+        let passphrase = read_passphrase(&format!("Passphrase for {}: ", name), false)?;
+        Ok(key.decrypt(&passphrase)?)
+    }
+
+    fn path(&self, name: &str) -> CliResult<PathBuf> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(
+                format!("invalid key name {:?}: use letters, digits, - and _", name).into(),
+            );
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+}
+
+/// Get a passphrase from the environment, an agent command, or the terminal
+///
+/// With `confirm`, an interactively entered passphrase must be typed twice.
+pub fn read_passphrase(prompt: &str, confirm: bool) -> CliResult<String> {
+    if let Ok(passphrase) = std::env::var("PUBKY_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+
+    if let Ok(command) = std::env::var("PUBKY_PASSPHRASE_COMMAND") {
+        let output = std::process::Command::new("sh")
+            .args(["-c", &command])
+            .output()?;
+        if !output.status.success() {
+            return Err(format!("PUBKY_PASSPHRASE_COMMAND failed ({})", output.status).into());
+        }
+        let passphrase = String::from_utf8(output.stdout)?;
+        return Ok(passphrase.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let passphrase = rpassword::prompt_password(prompt)?;
+    if passphrase.is_empty() {
+        return Err("empty passphrase".into());
+    }
+    if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        return Err("passphrases don't match".into());
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::keystore::KdfParams;
+
+    #[test]
+    fn test_keystore() {
+        let dir = std::env::temp_dir().join(format!("pubky-keystore-{}", std::process::id()));
+        let keystore = Keystore::new(&dir);
+        assert!(keystore.list().unwrap().is_empty());
+
+        let keypair = Keypair::random();
+        let kdf = KdfParams {
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let encrypted = EncryptedKeypair::encrypt_with(&keypair, "secret", kdf).unwrap();
+        keystore.store("alice", &encrypted).unwrap();
+
+        // Names are unique and restricted to safe file names
+        assert!(keystore.store("alice", &encrypted).is_err());
+        assert!(keystore.store("../alice", &encrypted).is_err());
+
+        let keys = keystore.list().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].0, "alice");
+        let loaded = keystore.load("alice").unwrap().decrypt("secret").unwrap();
+        assert_eq!(loaded.public_key(), keypair.public_key());
+        assert!(keystore.load("bob").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
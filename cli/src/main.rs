@@ -0,0 +1,130 @@
+//! Pubky CLI
+//!
+//! Manage identities and read, write, list, and sync entries on a
+//! homeserver from the shell.
+
+mod cli;
+mod commands;
+mod config;
+mod keystore;
+
+use clap::Parser;
+use pubky_client::{PubkyClient, SyncMode, WireTrace};
+
+use cli::{Cli, Command, ConfigCommand, KeyCommand, SessionsCommand};
+use config::Config;
+use keystore::Keystore;
+
+type CliResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    if let Err(e) = run(cli).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> CliResult<()> {
+    let mut config = Config::load()?;
+    let homeserver = cli
+        .homeserver
+        .clone()
+        .unwrap_or_else(|| config.homeserver().to_string());
+    let mut builder = PubkyClient::builder().homeserver(homeserver);
+    if cli.trace || cli.curl {
+        let trace = WireTrace::stderr().wire(cli.trace).curl(cli.curl);
+        builder = builder.interceptor(trace);
+    }
+    let client = builder.build()?;
+
+    if let Some(name) = &cli.key {
+        signin(&client, &Keystore::open()?, name).await?;
+    }
+
+    match cli.command {
+        Command::Keygen { name } => commands::keygen(&Keystore::open()?, &name)?,
+        Command::Key { command } => match (command, Keystore::open()?) {
+            (KeyCommand::List, keystore) => commands::key_list(&keystore)?,
+            (KeyCommand::Import { name, file }, keystore) => {
+                commands::key_import(&keystore, &name, file.as_deref())?
+            }
+            (KeyCommand::Export { name, file }, keystore) => {
+                commands::key_export(&keystore, &name, file.as_deref())?
+            }
+        },
+        Command::Put { url, file } => commands::put(&client, &url, file.as_deref()).await?,
+        Command::Get { url, out } => commands::get(&client, &url, out.as_deref()).await?,
+        Command::Cat { url } => commands::cat(&client, &url).await?,
+        Command::Ls { url } => commands::ls(&client, &url).await?,
+        Command::Rm { url } => commands::rm(&client, &url).await?,
+        Command::Sync {
+            dir,
+            url,
+            pull,
+            delete,
+        } => {
+            let mode = match (pull, delete) {
+                (true, _) => SyncMode::Pull,
+                (false, true) => SyncMode::Mirror,
+                (false, false) => SyncMode::Push,
+            };
+            let report = commands::sync(&client, &dir, &url, mode).await?;
+            eprintln!(
+                "{} uploaded, {} downloaded, {} unchanged, {} deleted",
+                report.uploaded.len(),
+                report.downloaded.len(),
+                report.unchanged,
+                report.deleted.len()
+            );
+        }
+        Command::Sessions { command } => {
+            if cli.key.is_none() {
+                return Err("sign in with --key to manage sessions".into());
+            }
+            match command.unwrap_or(SessionsCommand::List) {
+                SessionsCommand::List => commands::sessions(&client).await?,
+                SessionsCommand::Revoke { id } => commands::revoke_session(&client, &id).await?,
+            }
+        }
+        Command::Authorize { url } => {
+            let name = cli
+                .key
+                .as_deref()
+                .ok_or("give the identity to approve with --key")?;
+            let keypair = Keystore::open()?.unlock(name)?;
+            commands::authorize(&client, &keypair, &url).await?
+        }
+        Command::Config { command: None } => {
+            if let Some(path) = Config::path() {
+                println!("# {}", path.display());
+            }
+            println!("homeserver = {:?}", config.homeserver());
+        }
+        Command::Config {
+            command: Some(ConfigCommand::SetHomeserver { url }),
+        } => {
+            config.homeserver = Some(url);
+            let path = config.save()?;
+            eprintln!("Saved {}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Sign in with a keystore identity, signing up on first use
+async fn signin(client: &PubkyClient, keystore: &Keystore, name: &str) -> CliResult<()> {
+    let keypair = keystore.unlock(name)?;
+    match client.signin(&keypair).await {
+        Err(pubky_client::ClientError::Unauthorized) => {
+            client.signup(&keypair, None).await?;
+        }
+        result => {
+            result?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Basic usage example for Pubky MVP
+//!
+//! This example demonstrates:
+//! 1. Creating a keypair
+//! 2. Storing data via HTTP
+//! 3. Retrieving data
+//! 4. Listing keys
+//! 5. Deleting data
+
+use pubky_client::PubkyClient;
+use pubky_common::Keypair;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Create a new keypair
+    let keypair = Keypair::random();
+    let public_key = keypair.public_key();
+
+    println!("=== Pubky MVP Example ===");
+    println!("\nGenerated keypair:");
+    println!("Public Key: {}", public_key);
+    println!("Public Key (z32): {}", public_key.to_z32());
+
+    let client = PubkyClient::new("http://127.0.0.1:3000");
+
+    println!("\n=== Testing Storage Operations ===");
+
+    // 1. PUT - Store some data
+    let path = "my-app/hello.txt";
+    let data = "Hello, Pubky MVP!";
+
+    println!("\n1. PUT {}/{}", public_key, path);
+    if let Err(e) = client.put(&public_key, path, data).await {
+        println!("   ✗ Failed to store data: {}", e);
+        println!("\nNote: Make sure the server is running with: cargo run --bin server");
+        return Ok(());
+    }
+    println!("   ✓ Data stored successfully");
+
+    // 2. GET - Retrieve the data
+    println!("\n2. GET {}/{}", public_key, path);
+    match client.get(&public_key, path).await? {
+        Some(retrieved) => {
+            let retrieved = String::from_utf8_lossy(&retrieved);
+            println!("   ✓ Retrieved: {}", retrieved);
+            assert_eq!(retrieved, data);
+        }
+        None => println!("   ✗ Data not found"),
+    }
+
+    // 3. PUT - Store more data
+    println!("\n3. Storing additional files...");
+    let files = vec![
+        ("my-app/data1.txt", "Content 1"),
+        ("my-app/data2.txt", "Content 2"),
+        ("other/data3.txt", "Content 3"),
+    ];
+
+    for (file_path, content) in &files {
+        client.put(&public_key, file_path, *content).await?;
+        println!("   ✓ Stored: {}", file_path);
+    }
+
+    // 4. LIST - List all files under my-app/
+    println!("\n4. LIST {}/my-app/", public_key);
+    let keys = client.list(&public_key, "my-app/").await?;
+    println!("   ✓ Found {} files:", keys.len());
+    for key in keys {
+        println!("     - {}", key);
+    }
+
+    // 5. DELETE - Delete a file
+    println!("\n5. DELETE {}/{}", public_key, path);
+    if client.delete(&public_key, path).await? {
+        println!("   ✓ File deleted successfully");
+    } else {
+        println!("   ✗ File was not found");
+    }
+
+    // 6. Verify deletion
+    println!("\n6. Verifying deletion...");
+    if client.get(&public_key, path).await?.is_none() {
+        println!("   ✓ File no longer exists");
+    } else {
+        println!("   ✗ File still exists!");
+    }
+
+    println!("\n=== Example Complete ===");
+
+    Ok(())
+}
@@ -0,0 +1,431 @@
+//! Cross-device authorization
+//!
+//! A device without the account's keypair, such as a new laptop, calls
+//! [`PubkyClient::request_authorization`] and shows the returned
+//! [`AuthorizationUrl`] as a QR code. The device holding the keypair scans
+//! it and calls [`PubkyClient::authorize`]. Meanwhile the first device waits
+//! in [`PubkyClient::await_authorization`] for a session limited to the
+//! requested capabilities. The homeserver of the requesting device relays
+//! the approval; no secret is ever typed or displayed.
+//!
+//! Apps that don't know the user's homeserver yet, such as web apps on
+//! another origin, call [`PubkyClient::request_relayed_authorization`]
+//! instead. The approval then goes through an auth relay, sealed with a key
+//! that only appears in the URL, and the app redeems it at the user's
+//! homeserver.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use pubky_common::auth::AuthGrant;
+use pubky_common::dto::{
+    AuthRequestInfo, NewAuthRequest, RedeemAuthRequest, RelayChannel, SessionInfo,
+};
+use pubky_common::encryption::EncryptionKey;
+use pubky_common::{Keypair, PublicKey};
+use reqwest::{StatusCode, Url};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::client::{check, PubkyClient};
+use crate::error::{Error, Result};
+use crate::retry::{self, Operation};
+
+/// Scheme of authorization URLs
+const AUTH_SCHEME: &str = "pubkyauth";
+
+/// How often a waiting device asks whether it was authorized
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the approving device needs to know about a request, shown to it as
+/// `pubkyauth:///?relay=<homeserver>&id=<id>&challenge=<challenge>&caps=<capabilities>`,
+/// with `&key=<key>` for requests going through an auth relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizationUrl {
+    /// Homeserver or auth relay relaying the request
+    pub relay: String,
+    pub id: String,
+    pub challenge: String,
+    /// Capabilities asked for, such as `/pub/my-app/:rw`
+    pub capabilities: String,
+    /// Base64url key sealing the grant, if `relay` is an auth relay
+    pub key: Option<String>,
+}
+
+impl AuthorizationUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidUrl(format!("{}: {}", url, reason));
+        let parsed = Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+        if parsed.scheme() != AUTH_SCHEME {
+            return Err(invalid("not a pubkyauth URL"));
+        }
+
+        let param = |name: &str| {
+            parsed
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .ok_or_else(|| invalid(&format!("missing {}", name)))
+        };
+        Ok(Self {
+            relay: param("relay")?,
+            id: param("id")?,
+            challenge: param("challenge")?,
+            capabilities: param("caps")?,
+            key: param("key").ok(),
+        })
+    }
+}
+
+impl FromStr for AuthorizationUrl {
+    type Err = Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        Self::parse(url)
+    }
+}
+
+impl fmt::Display for AuthorizationUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut url = Url::parse(&format!("{}:///", AUTH_SCHEME)).expect("valid base URL");
+        url.query_pairs_mut()
+            .append_pair("relay", &self.relay)
+            .append_pair("id", &self.id)
+            .append_pair("challenge", &self.challenge)
+            .append_pair("caps", &self.capabilities);
+        if let Some(key) = &self.key {
+            url.query_pairs_mut().append_pair("key", key);
+        }
+        write!(f, "{}", url)
+    }
+}
+
+/// A pending request of this device to be authorized
+#[derive(Debug, Clone)]
+pub struct AuthorizationRequest {
+    /// URL to show to the approving device, for example as a QR code
+    pub url: AuthorizationUrl,
+    /// Unix timestamp in milliseconds when the request expires
+    pub expires_at: u64,
+    /// Redeems the session; stays on this device
+    secret: String,
+}
+
+impl PubkyClient {
+    /// Ask the device holding the keypair for a session with
+    /// `capabilities`, such as `/pub/my-app/:rw`
+    pub async fn request_authorization(&self, capabilities: &str) -> Result<AuthorizationRequest> {
+        let url = format!("{}/auth/requests", self.homeserver());
+        let request = self.http.post(url).json(&NewAuthRequest {
+            capabilities: capabilities.to_string(),
+        });
+        let response = self.execute(Operation::Session, request, None).await?;
+        let info: AuthRequestInfo = check(response).await?.json().await?;
+
+        Ok(AuthorizationRequest {
+            url: AuthorizationUrl {
+                relay: self.homeserver().to_string(),
+                id: info.id,
+                challenge: info.challenge,
+                capabilities: info.capabilities,
+                key: None,
+            },
+            expires_at: info.expires_at,
+            secret: info.secret,
+        })
+    }
+
+    /// Like [`request_authorization`](Self::request_authorization), but
+    /// through the auth relay at `relay`, for apps that don't know the
+    /// user's homeserver
+    pub async fn request_relayed_authorization(
+        &self,
+        relay: &str,
+        capabilities: &str,
+    ) -> Result<AuthorizationRequest> {
+        let relay = relay.trim_end_matches('/');
+        let request = self.http.post(format!("{}/relay/channels", relay));
+        let response = self.execute(Operation::Session, request, None).await?;
+        let channel: RelayChannel = check(response).await?.json().await?;
+
+        Ok(AuthorizationRequest {
+            url: AuthorizationUrl {
+                relay: relay.to_string(),
+                id: channel.id,
+                challenge: BASE64_URL.encode(rand::random::<[u8; 32]>()),
+                capabilities: capabilities.to_string(),
+                key: Some(BASE64_URL.encode(rand::random::<[u8; 32]>())),
+            },
+            expires_at: channel.expires_at,
+            secret: channel.secret,
+        })
+    }
+
+    /// Wait until the request is approved, then use the granted session
+    ///
+    /// Fails with [`Error::NotFound`] once the request expires. The client
+    /// has no keypair, so it can't sign in again when the session ends.
+    ///
+    /// Relayed grants are redeemed at the homeserver of the approving
+    /// account, looked up through pkarr if needed.
+    pub async fn await_authorization(&self, request: &AuthorizationRequest) -> Result<SessionInfo> {
+        if let Some(key) = &request.url.key {
+            return self.await_relayed_authorization(request, key).await;
+        }
+        let url = format!(
+            "{}/auth/requests/{}/session",
+            request.url.relay, request.url.id
+        );
+        loop {
+            let body = RedeemAuthRequest {
+                secret: request.secret.clone(),
+            };
+            let redeem = self.http.post(&url).json(&body);
+            let response = self.execute(Operation::Session, redeem, None).await?;
+            if response.status() != StatusCode::ACCEPTED {
+                let session: SessionInfo = check(response).await?.json().await?;
+                self.set_session(session.clone());
+                return Ok(session);
+            }
+            retry::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Approve another device's request, scanned from its
+    /// [`AuthorizationUrl`], with the account's keypair
+    ///
+    /// Check the requested capabilities with the user before approving.
+    pub async fn authorize(&self, keypair: &Keypair, url: &AuthorizationUrl) -> Result<()> {
+        // Relayed grants are redeemed at the account's homeserver, others
+        // where the request was made
+        let homeserver = match &url.key {
+            Some(_) => self.locate(&keypair.public_key()).await,
+            None => url.relay.clone(),
+        };
+        let audience = self.identity_of(&homeserver).await?;
+        let grant = AuthGrant::sign(keypair, &audience, &url.challenge, &url.capabilities);
+        let request = match &url.key {
+            Some(key) => {
+                let grant = serde_json::to_vec(&grant).expect("grants serialize");
+                let sealed = relay_key(key)?.seal(&grant, url.id.as_bytes());
+                let endpoint = format!("{}/relay/channels/{}", url.relay, url.id);
+                self.http.post(endpoint).body(sealed)
+            }
+            None => {
+                let endpoint = format!("{}/auth/requests/{}/grant", url.relay, url.id);
+                self.http.post(endpoint).json(&grant)
+            }
+        };
+        let response = self.execute(Operation::Session, request, None).await?;
+        check(response).await?;
+        Ok(())
+    }
+}
+
+impl PubkyClient {
+    /// Wait for the grant sealed under `key` on the relay, then redeem it
+    async fn await_relayed_authorization(
+        &self,
+        request: &AuthorizationRequest,
+        key: &str,
+    ) -> Result<SessionInfo> {
+        let url = format!("{}/relay/channels/{}", request.url.relay, request.url.id);
+        let sealed = loop {
+            // The relay holds the request until the grant is delivered
+            let receive = self.http.get(&url).bearer_auth(&request.secret);
+            let response = self.execute(Operation::Watch, receive, None).await?;
+            if response.status() != StatusCode::NO_CONTENT {
+                break check(response).await?.bytes().await?;
+            }
+        };
+
+        let invalid = |reason: &str| Error::InvalidUrl(format!("Relayed grant {}", reason));
+        let grant = relay_key(key)?
+            .open(&sealed, request.url.id.as_bytes())
+            .map_err(|_| invalid("can't be opened"))?;
+        let grant: AuthGrant = serde_json::from_slice(&grant).map_err(|_| invalid("is invalid"))?;
+        if grant.challenge != request.url.challenge
+            || grant.capabilities != request.url.capabilities
+        {
+            return Err(invalid("doesn't match the request"));
+        }
+
+        let public_key = PublicKey::from_z32(&grant.public_key)?;
+        let homeserver = self.locate(&public_key).await;
+        grant.verify(&self.identity_of(&homeserver).await?)?;
+        let redeem = self
+            .http
+            .post(format!("{}/auth/grants", homeserver))
+            .json(&grant);
+        let response = self.execute(Operation::Session, redeem, None).await?;
+        let session: SessionInfo = check(response).await?.json().await?;
+        self.set_session(session.clone());
+        Ok(session)
+    }
+}
+
+/// The key sealing grants for an auth relay, from its base64url encoding
+fn relay_key(key: &str) -> Result<EncryptionKey> {
+    let key: [u8; 32] = BASE64_URL
+        .decode(key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::InvalidUrl(format!("Invalid relay key: {}", key)))?;
+    Ok(EncryptionKey::from_bytes(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_cross_device_authorization() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let phone = PubkyClient::new(server.url());
+        phone.signup(&keypair, None).await.unwrap();
+
+        let laptop = PubkyClient::builder()
+            .homeserver(server.url())
+            .user_agent("laptop")
+            .build()
+            .unwrap();
+        let request = laptop
+            .request_authorization("/pub/my-app/:rw")
+            .await
+            .unwrap();
+
+        // The QR code round-trips, and the phone approves what it scanned
+        let scanned: AuthorizationUrl = request.url.to_string().parse().unwrap();
+        assert_eq!(scanned, request.url);
+        let waiting = tokio::spawn({
+            let laptop = laptop.clone();
+            let request = request.clone();
+            async move { laptop.await_authorization(&request).await }
+        });
+        phone.authorize(&keypair, &scanned).await.unwrap();
+
+        let session = waiting.await.unwrap().unwrap();
+        assert_eq!(session.public_key, keypair.public_key().to_z32());
+        assert_eq!(laptop.session(), Some(session));
+        let sessions = phone.sessions().await.unwrap();
+        let granted = sessions
+            .iter()
+            .find(|s| s.device.as_deref() == Some("laptop"))
+            .unwrap();
+        assert_eq!(granted.capabilities, "/pub/my-app/:rw");
+
+        // Which is no session with root capabilities
+        let err = laptop.sessions().await.unwrap_err();
+        assert!(matches!(err, Error::Unauthorized));
+
+        // A redeemed request is gone, and unregistered keys can't approve
+        let err = phone.authorize(&keypair, &scanned).await.unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+        let request = laptop.request_authorization("/:rw").await.unwrap();
+        let err = phone
+            .authorize(&Keypair::random(), &request.url)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Unauthorized));
+        assert!(AuthorizationUrl::parse("pubky://nope").is_err());
+
+        // A session with full access writes without the keypair
+        phone.authorize(&keypair, &request.url).await.unwrap();
+        laptop.await_authorization(&request).await.unwrap();
+        laptop
+            .put(keypair.public_key(), "pub/notes.txt", "from the laptop")
+            .await
+            .unwrap();
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_relayed_authorization() {
+        let relay = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .auth_relay(true)
+            .start()
+            .await
+            .unwrap();
+        let homeserver = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let phone = PubkyClient::new(homeserver.url());
+        phone.signup(&keypair, None).await.unwrap();
+
+        // The app only knows the user's homeserver once it has the grant
+        let app = PubkyClient::builder()
+            .resolve(keypair.public_key(), homeserver.url())
+            .build()
+            .unwrap();
+        let request = app
+            .request_relayed_authorization(&relay.url(), "/pub/app/:rw")
+            .await
+            .unwrap();
+        let scanned: AuthorizationUrl = request.url.to_string().parse().unwrap();
+        assert_eq!(scanned, request.url);
+        assert!(scanned.key.is_some());
+        let waiting = tokio::spawn({
+            let app = app.clone();
+            let request = request.clone();
+            async move { app.await_authorization(&request).await }
+        });
+        phone.authorize(&keypair, &scanned).await.unwrap();
+
+        let session = waiting.await.unwrap().unwrap();
+        assert_eq!(session.public_key, keypair.public_key().to_z32());
+        // The session only reaches the granted paths
+        app.put(keypair.public_key(), "pub/app/data", "hi")
+            .await
+            .unwrap();
+        let err = app.put(keypair.public_key(), "pub/other/data", "hi").await;
+        assert!(matches!(err, Err(Error::Unauthorized)));
+
+        // Grants are only redeemed at the homeserver they are for
+        let grants = format!("{}/auth/grants", homeserver.url());
+        let http = reqwest::Client::new();
+        let elsewhere = AuthGrant::sign(&keypair, &relay.identity(), "challenge", "/:rw");
+        let response = http.post(&grants).json(&elsewhere).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // And only once, however their signature is encoded
+        let grant = AuthGrant::sign(&keypair, &homeserver.identity(), "challenge", "/:rw");
+        let response = http.post(&grants).json(&grant).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = http.post(&grants).json(&grant).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let mut reencoded = grant.clone();
+        reencoded.signature = reencode(&grant.signature);
+        assert_ne!(reencoded.signature, grant.signature);
+        assert_eq!(
+            reencoded.signature_bytes().unwrap(),
+            grant.signature_bytes().unwrap()
+        );
+        let response = http.post(&grants).json(&reencoded).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        relay.shutdown().await;
+        homeserver.shutdown().await;
+    }
+
+    /// The same z-base-32 signature with other values in the unused low
+    /// bits of its last character
+    fn reencode(signature: &str) -> String {
+        const ALPHABET: &[u8] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+        let (head, last) = signature.split_at(signature.len() - 1);
+        let value = ALPHABET
+            .iter()
+            .position(|c| *c == last.as_bytes()[0])
+            .unwrap();
+        format!("{}{}", head, ALPHABET[value ^ 1] as char)
+    }
+}
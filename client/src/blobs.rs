@@ -0,0 +1,274 @@
+//! QUIC blob transfers
+//!
+//! [`BlobClient`] moves large entries over the raw QUIC protocol of
+//! [`pubky_common::blob`], splitting them into chunks transferred on
+//! parallel streams, each verified against its SHA-256. Not available in
+//! browsers.
+
+use bytes::Bytes;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use pubky_common::blob::{
+    self, BlobRequest, BlobResponse, ChannelBinding, ALPN, CHUNK_SIZE, EXPORTER_LABEL,
+    KEYING_MATERIAL_LEN, MAX_FRAME_LEN,
+};
+use pubky_common::Keypair;
+use quinn::crypto::rustls::QuicClientConfig;
+use reqwest::StatusCode;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::client::IntoPublicKey;
+use crate::error::{ClientError, Result};
+
+/// A connection to the blob transfer endpoint of a homeserver,
+/// authenticated as a keypair
+#[derive(Debug)]
+pub struct BlobClient {
+    // Kept alive for as long as the connection
+    _endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+    concurrency: usize,
+}
+
+impl BlobClient {
+    /// Connect to the endpoint at `addr`, whose certificate must be valid
+    /// for `server_name` under `roots`, and authenticate as `keypair`
+    pub async fn connect(
+        addr: SocketAddr,
+        server_name: &str,
+        roots: rustls::RootCertStore,
+        keypair: &Keypair,
+    ) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(io_error)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let quic = QuicClientConfig::try_from(tls).map_err(io_error)?;
+
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let mut endpoint = quinn::Endpoint::client(bind)?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(quic)));
+        let connection = endpoint
+            .connect(addr, server_name)
+            .map_err(io_error)?
+            .await
+            .map_err(io_error)?;
+
+        let mut keying_material = [0u8; KEYING_MATERIAL_LEN];
+        connection
+            .export_keying_material(&mut keying_material, EXPORTER_LABEL, b"")
+            .map_err(|_| io_error("Failed to export keying material"))?;
+        let binding = ChannelBinding::sign(keypair, &keying_material);
+        let (mut send, mut recv) = connection.open_bi().await.map_err(io_error)?;
+        send.write_all(&binding.to_bytes())
+            .await
+            .map_err(io_error)?;
+        send.finish().map_err(io_error)?;
+        check(read_frame(&mut recv).await?)?;
+
+        Ok(Self {
+            _endpoint: endpoint,
+            connection,
+            concurrency: 8,
+        })
+    }
+
+    /// Transfer up to `max` chunks at once
+    pub fn concurrency(mut self, max: usize) -> Self {
+        self.concurrency = max.max(1);
+        self
+    }
+
+    /// Store `body` at `path` of the authenticated keypair
+    pub async fn put(&self, path: &str, body: impl Into<Bytes>) -> Result<()> {
+        let body: Bytes = body.into();
+        let writes = blob::chunks(body.len() as u64).map(|(offset, len)| {
+            let chunk = body.slice(offset as usize..(offset + len) as usize);
+            let request = BlobRequest::Write {
+                path: path.to_string(),
+                offset,
+                sha256: blob::sha256_hex(&chunk),
+            };
+            async move { self.request(&request, Some(chunk)).await }
+        });
+        stream::iter(writes)
+            .buffer_unordered(self.concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let commit = BlobRequest::Commit {
+            path: path.to_string(),
+            size: body.len() as u64,
+            sha256: blob::sha256_hex(&body),
+        };
+        self.request(&commit, None).await?;
+        Ok(())
+    }
+
+    /// Retrieve the entry at `path` of `owner`, or `None` if it doesn't
+    /// exist
+    pub async fn get(&self, owner: impl IntoPublicKey, path: &str) -> Result<Option<Bytes>> {
+        let public_key = owner.into_public_key()?.to_string();
+        let stat = BlobRequest::Stat {
+            public_key: public_key.clone(),
+            path: path.to_string(),
+        };
+        let stat = match self.request(&stat, None).await {
+            Ok((stat, _)) => stat,
+            Err(ClientError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let size = stat.size.unwrap_or_default();
+
+        let reads = blob::chunks(size).map(|(offset, len)| {
+            let request = BlobRequest::Read {
+                public_key: public_key.clone(),
+                path: path.to_string(),
+                offset,
+                len,
+            };
+            async move {
+                let (response, chunk) = self.request(&request, None).await?;
+                match chunk.len() as u64 == len && response.sha256 == Some(blob::sha256_hex(&chunk))
+                {
+                    true => Ok(chunk),
+                    false => Err(invalid_data("Chunk hash mismatch")),
+                }
+            }
+        });
+        let chunks: Vec<Bytes> = stream::iter(reads)
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        let body = Bytes::from(chunks.concat());
+        if stat.sha256 != Some(blob::sha256_hex(&body)) {
+            // The entry changed between the stat and the reads
+            return Err(invalid_data("Entry hash mismatch"));
+        }
+        Ok(Some(body))
+    }
+
+    /// Send a request on a new stream, followed by `data`, returning the
+    /// response and the data following it
+    async fn request(
+        &self,
+        request: &BlobRequest,
+        data: Option<Bytes>,
+    ) -> Result<(BlobResponse, Bytes)> {
+        let (mut send, mut recv) = self.connection.open_bi().await.map_err(io_error)?;
+        let json = serde_json::to_vec(request).map_err(io_error)?;
+        send.write_all(&(json.len() as u32).to_be_bytes())
+            .await
+            .map_err(io_error)?;
+        send.write_all(&json).await.map_err(io_error)?;
+        if let Some(data) = data {
+            send.write_chunk(data).await.map_err(io_error)?;
+        }
+        send.finish().map_err(io_error)?;
+
+        let response = check(read_frame(&mut recv).await?)?;
+        let data = recv
+            .read_to_end(CHUNK_SIZE as usize)
+            .await
+            .map_err(io_error)?;
+        Ok((response, data.into()))
+    }
+}
+
+/// Read a length-prefixed JSON response
+async fn read_frame(recv: &mut quinn::RecvStream) -> Result<BlobResponse> {
+    let mut len = [0u8; 4];
+    recv.read_exact(&mut len).await.map_err(io_error)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data("Frame too long"));
+    }
+    let mut frame = vec![0u8; len as usize];
+    recv.read_exact(&mut frame).await.map_err(io_error)?;
+    serde_json::from_slice(&frame).map_err(|e| invalid_data(e.to_string()))
+}
+
+/// Map error responses to errors
+fn check(response: BlobResponse) -> Result<BlobResponse> {
+    if response.status < 400 {
+        return Ok(response);
+    }
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::BAD_GATEWAY);
+    let body = serde_json::to_string(&response.error).unwrap_or_default();
+    Err(ClientError::from_response(status, &body))
+}
+
+fn io_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ClientError {
+    ClientError::Io(io::Error::other(e))
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ClientError {
+    ClientError::Io(io::Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PubkyClient;
+    use pubky_server::{BlobConfig, Server, TlsIdentity};
+
+    #[tokio::test]
+    async fn test_blob_transfer() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let identity = TlsIdentity::from_pem(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .blob_transfer(BlobConfig::new(identity, ([127, 0, 0, 1], 0).into()))
+            .start()
+            .await
+            .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+
+        let keypair = Keypair::random();
+        let blobs = BlobClient::connect(server.blob_addr().unwrap(), "localhost", roots, &keypair)
+            .await
+            .unwrap()
+            .concurrency(2);
+
+        // Several chunks, the last one partial
+        let body: Bytes = (0..5 * CHUNK_SIZE / 2).map(|i| (i % 251) as u8).collect();
+        blobs.put("pub/video.mp4", body.clone()).await.unwrap();
+        let fetched = blobs.get(keypair.public_key(), "pub/video.mp4").await;
+        assert_eq!(fetched.unwrap(), Some(body.clone()));
+
+        // The entry is the same over HTTP
+        let client = PubkyClient::new(server.url());
+        let fetched = client.get(keypair.public_key(), "pub/video.mp4").await;
+        assert_eq!(fetched.unwrap(), Some(body));
+
+        blobs.put("pub/empty", Bytes::new()).await.unwrap();
+        let fetched = blobs.get(keypair.public_key(), "pub/empty").await;
+        assert_eq!(fetched.unwrap(), Some(Bytes::new()));
+        let missing = blobs.get(keypair.public_key(), "pub/missing").await;
+        assert_eq!(missing.unwrap(), None);
+
+        // Commits must match what was uploaded
+        let commit = BlobRequest::Commit {
+            path: "pub/other".to_string(),
+            size: 3,
+            sha256: blob::sha256_hex(b"abc"),
+        };
+        let result = blobs.request(&commit, None).await;
+        assert_eq!(result.unwrap_err().status(), Some(StatusCode::BAD_REQUEST));
+
+        server.shutdown().await;
+    }
+}
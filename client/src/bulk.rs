@@ -0,0 +1,129 @@
+//! Bulk transfers
+//!
+//! [`PubkyClient::put_many`] and [`PubkyClient::get_many`] transfer many
+//! entries with up to [`concurrency`](crate::PubkyClientBuilder::concurrency)
+//! requests in flight, reporting a result per entry instead of stopping at
+//! the first failure.
+
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+
+use crate::client::{IntoPublicKey, PubkyClient};
+use crate::error::Result;
+
+impl PubkyClient {
+    /// Store each `(path, body)` pair, returning the result of each write in
+    /// the order given
+    pub async fn put_many<P, B>(
+        &self,
+        owner: impl IntoPublicKey,
+        entries: impl IntoIterator<Item = (P, B)>,
+    ) -> Result<Vec<(String, Result<()>)>>
+    where
+        P: Into<String>,
+        B: Into<Bytes>,
+    {
+        let public_key = owner.into_public_key()?;
+        let writes = entries.into_iter().map(|(path, body)| {
+            let path = path.into();
+            let body = body.into();
+            async move {
+                let result = self.put(public_key, &path, body).await;
+                (path, result)
+            }
+        });
+        Ok(stream::iter(writes)
+            .buffered(self.concurrency)
+            .collect()
+            .await)
+    }
+
+    /// Retrieve each path, returning the result of each read in the order
+    /// given
+    pub async fn get_many<P>(
+        &self,
+        owner: impl IntoPublicKey,
+        paths: impl IntoIterator<Item = P>,
+    ) -> Result<Vec<(String, Result<Option<Bytes>>)>>
+    where
+        P: Into<String>,
+    {
+        let public_key = owner.into_public_key()?;
+        let reads = paths.into_iter().map(|path| {
+            let path = path.into();
+            async move {
+                let result = self.get(public_key, &path).await;
+                (path, result)
+            }
+        });
+        Ok(stream::iter(reads)
+            .buffered(self.concurrency)
+            .collect()
+            .await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientError;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_put_get_many() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .concurrency(4)
+            .private_prefix("bulk/private/")
+            .build()
+            .unwrap();
+
+        let entries: Vec<_> = (0..20)
+            .map(|i| (format!("bulk/{:02}.txt", i), i.to_string()))
+            .collect();
+        let written = client.put_many(public_key, entries).await.unwrap();
+        assert_eq!(written.len(), 20);
+        assert!(written.iter().all(|(_, result)| result.is_ok()));
+        assert_eq!(client.list(public_key, "bulk/").await.unwrap().len(), 20);
+
+        let read = client
+            .get_many(public_key, ["bulk/07.txt", "bulk/missing.txt", "bulk/"])
+            .await
+            .unwrap();
+        let paths: Vec<_> = read.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["bulk/07.txt", "bulk/missing.txt", "bulk/"]);
+        assert_eq!(read[0].1.as_ref().unwrap().as_deref(), Some(&b"7"[..]));
+        assert!(read[1].1.as_ref().unwrap().is_none());
+
+        // Private entries are encrypted with the keypair
+        let entries = [("bulk/private/a.txt", "a"), ("bulk/b.txt", "b")];
+        let written = client.put_many(public_key, entries).await.unwrap();
+        assert!(written.iter().all(|(_, result)| result.is_ok()));
+        let stored = server
+            .storage()
+            .get(&public_key, "bulk/private/a.txt")
+            .unwrap();
+        assert_ne!(stored.unwrap(), b"a");
+
+        // A failed write doesn't stop the others, and each fails on its own
+        let stranger = PubkyClient::builder()
+            .homeserver(server.url())
+            .private_prefix("bulk/private/")
+            .build()
+            .unwrap();
+        let written = stranger.put_many(public_key, entries).await.unwrap();
+        assert!(matches!(written[0].1, Err(ClientError::Encryption(_))));
+        assert!(matches!(written[1].1, Err(ClientError::Unauthorized)));
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,187 @@
+//! HTTP cache for entries
+//!
+//! Entries read from a homeserver that sends `ETag`s are kept locally with
+//! their tag. Later reads send `If-None-Match`, and a `304 Not Modified`
+//! answer is served from the cache without transferring the entry again.
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+
+/// A cached entry and the `ETag` it was served with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CachedEntry {
+    pub etag: String,
+    pub body: Bytes,
+}
+
+/// Cache of entries keyed by URL, held in memory or on disk
+///
+/// Enable it with [`PubkyClientBuilder::cache`](crate::PubkyClientBuilder::cache).
+#[derive(Debug)]
+pub struct HttpCache {
+    backend: Backend,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Memory {
+        max_entries: usize,
+        entries: Mutex<MemoryEntries>,
+    },
+    #[cfg(not(target_arch = "wasm32"))]
+    Disk { dir: PathBuf },
+}
+
+#[derive(Debug, Default)]
+struct MemoryEntries {
+    entries: HashMap<String, CachedEntry>,
+    /// URLs from least to most recently stored, for eviction
+    order: VecDeque<String>,
+}
+
+impl HttpCache {
+    /// Keep up to `max_entries` entries in memory, evicting the oldest
+    pub fn memory(max_entries: usize) -> Self {
+        Self {
+            backend: Backend::Memory {
+                max_entries,
+                entries: Mutex::new(MemoryEntries::default()),
+            },
+        }
+    }
+
+    /// Keep entries as files in `dir`, which persists across restarts
+    ///
+    /// The directory is created on first write. Not available in browsers.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn disk(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            backend: Backend::Disk { dir: dir.into() },
+        }
+    }
+
+    pub(crate) fn get(&self, url: &str) -> Option<CachedEntry> {
+        match &self.backend {
+            Backend::Memory { entries, .. } => entries.lock().unwrap().entries.get(url).cloned(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Disk { dir } => {
+                let data = std::fs::read(disk_path(dir, url)).ok()?;
+                let split = data.iter().position(|&b| b == b'\n')?;
+                let etag = String::from_utf8(data[..split].to_vec()).ok()?;
+                let body = Bytes::from(data).slice(split + 1..);
+                Some(CachedEntry { etag, body })
+            }
+        }
+    }
+
+    pub(crate) fn insert(&self, url: &str, entry: CachedEntry) {
+        match &self.backend {
+            Backend::Memory {
+                max_entries,
+                entries,
+            } => {
+                let mut memory = entries.lock().unwrap();
+                memory.order.retain(|u| u != url);
+                while memory.order.len() >= *max_entries {
+                    let Some(oldest) = memory.order.pop_front() else {
+                        return;
+                    };
+                    memory.entries.remove(&oldest);
+                }
+                memory.order.push_back(url.to_string());
+                memory.entries.insert(url.to_string(), entry);
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Disk { dir } => {
+                // Caching is best effort; a failed write only costs a refetch
+                let path = disk_path(dir, url);
+                let tmp = path.with_extension("tmp");
+                let mut data = Vec::with_capacity(entry.etag.len() + 1 + entry.body.len());
+                data.extend_from_slice(entry.etag.as_bytes());
+                data.push(b'\n');
+                data.extend_from_slice(&entry.body);
+                let _ = std::fs::create_dir_all(dir)
+                    .and_then(|_| std::fs::write(&tmp, data))
+                    .and_then(|_| std::fs::rename(&tmp, &path));
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, url: &str) {
+        match &self.backend {
+            Backend::Memory { entries, .. } => {
+                let mut memory = entries.lock().unwrap();
+                memory.order.retain(|u| u != url);
+                memory.entries.remove(url);
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Backend::Disk { dir } => {
+                let _ = std::fs::remove_file(disk_path(dir, url));
+            }
+        }
+    }
+}
+
+/// File holding the cached entry for a URL, named by the URL's SHA-256
+#[cfg(not(target_arch = "wasm32"))]
+fn disk_path(dir: &std::path::Path, url: &str) -> PathBuf {
+    use sha2::{Digest, Sha256};
+
+    let name: String = Sha256::digest(url.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    dir.join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(etag: &str, body: &'static str) -> CachedEntry {
+        CachedEntry {
+            etag: etag.to_string(),
+            body: Bytes::from(body),
+        }
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        let cache = HttpCache::memory(2);
+        cache.insert("a", entry("\"1\"", "alpha"));
+        cache.insert("b", entry("\"2\"", "beta"));
+        cache.insert("a", entry("\"3\"", "alpha 2"));
+        assert_eq!(cache.get("a"), Some(entry("\"3\"", "alpha 2")));
+
+        // The least recently stored entry is evicted
+        cache.insert("c", entry("\"4\"", "gamma"));
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("a").is_some());
+
+        cache.remove("a");
+        assert_eq!(cache.get("a"), None);
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("pubky-cache-{}", std::process::id()));
+        let cache = HttpCache::disk(&dir);
+        assert_eq!(cache.get("http://a"), None);
+
+        cache.insert("http://a", entry("\"1\"", "line one\nline two"));
+        let reopened = HttpCache::disk(&dir);
+        assert_eq!(
+            reopened.get("http://a"),
+            Some(entry("\"1\"", "line one\nline two"))
+        );
+
+        reopened.remove("http://a");
+        assert_eq!(cache.get("http://a"), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
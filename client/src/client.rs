@@ -0,0 +1,1385 @@
+//! Homeserver client
+
+use bytes::Bytes;
+use pubky_common::auth::{AuthToken, WriteSignature, SIGNATURE_HEADER};
+use pubky_common::dto::{
+    ActiveSession, ListResponse, SearchResponse, SearchResult, SessionInfo, SignupRequest,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use pubky_common::krpc::DEFAULT_BOOTSTRAP;
+use pubky_common::pkarr::{DEFAULT_RELAYS, HOMESERVER_HEADER};
+use pubky_common::tags::TAG_HEADER;
+use pubky_common::url::PUBKY_SCHEME;
+use pubky_common::{Keypair, PublicKey};
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::{Request, RequestBuilder, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cache::{CachedEntry, HttpCache};
+use crate::conditional::Precondition;
+use crate::error::{Error, Result};
+use crate::interceptor::{Interceptor, Interceptors};
+use crate::pkarr::Resolver;
+use crate::progress::{self, OnProgress};
+use crate::queue::{OfflineQueue, Version};
+use crate::retry::{self, CircuitBreaker, CircuitBreakerConfig, Operation, RetryPolicy, Timeouts};
+
+/// URL scheme of identity-addressed pubky URLs
+/// Homeserver used when none is configured
+const DEFAULT_HOMESERVER: &str = "http://127.0.0.1:3000";
+
+/// Anything that identifies the owner of some data
+///
+/// Implemented for [`PublicKey`] and for strings holding either a
+/// z-base-32 public key or a `pubky://<public_key>` URL.
+pub trait IntoPublicKey {
+    fn into_public_key(self) -> Result<PublicKey>;
+}
+
+impl IntoPublicKey for PublicKey {
+    fn into_public_key(self) -> Result<PublicKey> {
+        Ok(self)
+    }
+}
+
+impl IntoPublicKey for &PublicKey {
+    fn into_public_key(self) -> Result<PublicKey> {
+        Ok(*self)
+    }
+}
+
+impl IntoPublicKey for &str {
+    fn into_public_key(self) -> Result<PublicKey> {
+        let z32 = self.strip_prefix(PUBKY_SCHEME).unwrap_or(self);
+        Ok(PublicKey::from_z32(z32.trim_end_matches('/'))?)
+    }
+}
+
+impl IntoPublicKey for &String {
+    fn into_public_key(self) -> Result<PublicKey> {
+        self.as_str().into_public_key()
+    }
+}
+
+impl IntoPublicKey for String {
+    fn into_public_key(self) -> Result<PublicKey> {
+        self.as_str().into_public_key()
+    }
+}
+
+/// Builder for [`PubkyClient`]
+#[derive(Debug, Clone)]
+pub struct PubkyClientBuilder {
+    homeserver: Option<String>,
+    homeservers: HashMap<PublicKey, String>,
+    pkarr_relays: Option<Vec<String>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    dht_bootstrap: Option<Vec<String>>,
+    pool_max_idle_per_host: usize,
+    pool_idle_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    timeouts: Timeouts,
+    retry: RetryPolicy,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    cache: Option<Arc<HttpCache>>,
+    queue: Option<Arc<OfflineQueue>>,
+    private_prefixes: Vec<String>,
+    session: Option<SessionInfo>,
+    keypair: Option<Keypair>,
+    concurrency: usize,
+    interceptors: Interceptors,
+    user_agent: String,
+    writer_id: Option<String>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pinned_keys: Vec<PublicKey>,
+    #[cfg(feature = "mock")]
+    pub(crate) router: Option<axum::Router>,
+}
+
+impl PubkyClientBuilder {
+    /// Set the base URL of the homeserver
+    ///
+    /// Without one, the client runs with zero configuration: it discovers
+    /// every homeserver on the Mainline DHT and through the public
+    /// [`DEFAULT_RELAYS`] unless [`pkarr_relays`](Self::pkarr_relays) are
+    /// set, and falls back to a homeserver on `http://127.0.0.1:3000`.
+    pub fn homeserver(mut self, url: impl Into<String>) -> Self {
+        self.homeserver = Some(url.into());
+        self
+    }
+
+    /// Use `homeserver` for the entries of `public_key` instead of the
+    /// client's own homeserver
+    ///
+    /// The session token is only sent to the client's own homeserver.
+    pub fn resolve(mut self, public_key: PublicKey, homeserver: impl Into<String>) -> Self {
+        let homeserver = homeserver.into().trim_end_matches('/').to_string();
+        self.homeservers.insert(public_key, homeserver);
+        self
+    }
+
+    /// Discover the homeservers of other users through these pkarr relays
+    ///
+    /// Users without pkarr records, or whose records can't be fetched, are
+    /// looked up on the client's own homeserver. Homeservers mapped with
+    /// [`resolve`](Self::resolve) take precedence. Pass no relays to turn
+    /// discovery off.
+    pub fn pkarr_relays(mut self, relays: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.pkarr_relays = Some(relays.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Look up pkarr records on the Mainline DHT, starting from these
+    /// `host:port` nodes, before asking the relays
+    ///
+    /// Clients without a homeserver start from [`DEFAULT_BOOTSTRAP`]. Pass
+    /// no nodes to only use the relays.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn dht_bootstrap(mut self, nodes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.dht_bootstrap = Some(nodes.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Maximum number of idle pooled connections kept per host
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = max;
+        self
+    }
+
+    /// How long idle pooled connections are kept open
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.pool_idle_timeout = timeout;
+        self
+    }
+
+    /// Timeout for establishing connections
+    ///
+    /// Ignored in browsers.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout for each request attempt, from sending to reading the body
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.default = Some(timeout);
+        self
+    }
+
+    /// Timeout for attempts of one kind of operation, overriding
+    /// [`timeout`](Self::timeout)
+    ///
+    /// Useful to give large uploads more time than small reads.
+    pub fn operation_timeout(mut self, operation: Operation, timeout: Duration) -> Self {
+        self.timeouts.operations.insert(operation, timeout);
+        self
+    }
+
+    /// How failed idempotent requests are retried
+    ///
+    /// Defaults to [`RetryPolicy::default`]; use [`RetryPolicy::none`] to
+    /// disable retries.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Fail fast after repeated failures instead of waiting on an
+    /// unreachable homeserver
+    ///
+    /// Disabled by default.
+    pub fn circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = Some(config);
+        self
+    }
+
+    /// Cache entries read from homeservers that send `ETag`s
+    ///
+    /// Disabled by default.
+    pub fn cache(mut self, cache: HttpCache) -> Self {
+        self.cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Queue writes made with [`PubkyClient::put_or_queue`] and
+    /// [`PubkyClient::delete_or_queue`] while offline
+    ///
+    /// Disabled by default.
+    pub fn offline_queue(mut self, queue: OfflineQueue) -> Self {
+        self.queue = Some(Arc::new(queue));
+        self
+    }
+
+    /// Encrypt entries under `prefix`, such as `priv/`
+    ///
+    /// Content is encrypted with a key derived from the keypair given to
+    /// [`PubkyClient::signup`] or [`PubkyClient::signin`], and decrypted
+    /// when read back; paths are not encrypted. Reading or writing the
+    /// signed-in user's private entries without a keypair fails. May be
+    /// called several times.
+    pub fn private_prefix(mut self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        self.private_prefixes
+            .push(prefix.trim_start_matches('/').to_string());
+        self
+    }
+
+    /// Sign writes to the keypair's entries with it, and sign in with it
+    /// when a session is needed, as after [`PubkyClient::signin`]
+    pub fn keypair(mut self, keypair: Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Resume a previously persisted session
+    ///
+    /// Without a keypair the client can't re-authenticate once the session
+    /// expires; call [`PubkyClient::signin`] to enable that.
+    pub fn session(mut self, session: SessionInfo) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Maximum number of requests in flight for bulk transfers like
+    /// [`PubkyClient::put_many`]; 8 by default
+    pub fn concurrency(mut self, max: usize) -> Self {
+        self.concurrency = max;
+        self
+    }
+
+    /// Add an interceptor that sees every request and response
+    ///
+    /// Interceptors see requests in the order they were added, and
+    /// responses in reverse order. May be called several times.
+    pub fn interceptor(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Name the client sends as its user agent, shown in the homeserver's
+    /// session list to tell devices apart; `pubky-client/<version>` by
+    /// default
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Id naming this client in the version of entries it writes with
+    /// [`PubkyClient::put_versioned`], such as a device name; random by
+    /// default
+    ///
+    /// Only ASCII letters, digits, `-`, `_` and `.` are allowed; homeservers
+    /// reject writes with other ids.
+    pub fn writer_id(mut self, id: impl Into<String>) -> Self {
+        self.writer_id = Some(id.into());
+        self
+    }
+
+    /// Trust homeservers serving a self-signed certificate of this Ed25519
+    /// public key, whatever their host name
+    ///
+    /// Other servers are still checked against the web's certificate
+    /// authorities. May be called several times. Not available in browsers.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn pin_homeserver_key(mut self, public_key: PublicKey) -> Self {
+        self.pinned_keys.push(public_key);
+        self
+    }
+
+    /// Build the client
+    ///
+    /// In browsers requests go through the Fetch API, which manages its own
+    /// connections, so the pool and connect timeout settings are ignored.
+    pub fn build(self) -> Result<PubkyClient> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let http = {
+            let mut builder = reqwest::Client::builder()
+                .user_agent(&self.user_agent)
+                .pool_max_idle_per_host(self.pool_max_idle_per_host)
+                .pool_idle_timeout(self.pool_idle_timeout);
+            if let Some(timeout) = self.connect_timeout {
+                builder = builder.connect_timeout(timeout);
+            }
+            if !self.pinned_keys.is_empty() {
+                let tls = crate::pinning::tls_config(self.pinned_keys.clone())
+                    .map_err(std::io::Error::other)?;
+                builder = builder.use_preconfigured_tls(tls);
+            }
+            builder.build()?
+        };
+        #[cfg(target_arch = "wasm32")]
+        let http = reqwest::Client::builder()
+            .user_agent(&self.user_agent)
+            .build()?;
+
+        // Clients set up for a homeserver only query the relays they're given
+        let pkarr_relays = self.pkarr_relays.unwrap_or_else(|| match self.homeserver {
+            Some(_) => Vec::new(),
+            None => DEFAULT_RELAYS.iter().map(|r| r.to_string()).collect(),
+        });
+        #[cfg(not(target_arch = "wasm32"))]
+        let dht_bootstrap = self.dht_bootstrap.unwrap_or_else(|| match self.homeserver {
+            Some(_) => Vec::new(),
+            None => DEFAULT_BOOTSTRAP.iter().map(|b| b.to_string()).collect(),
+        });
+        let pkarr = Resolver::new(pkarr_relays);
+        #[cfg(not(target_arch = "wasm32"))]
+        let pkarr = pkarr.with_dht(dht_bootstrap);
+        let homeserver = self.homeserver.as_deref().unwrap_or(DEFAULT_HOMESERVER);
+        let writer_id = self
+            .writer_id
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+        Ok(PubkyClient {
+            http,
+            homeserver: homeserver.trim_end_matches('/').to_string(),
+            homeservers: Arc::new(self.homeservers),
+            pkarr: Arc::new(pkarr),
+            timeouts: Arc::new(self.timeouts),
+            retry: self.retry,
+            circuit_breaker: self
+                .circuit_breaker
+                .map(|c| Arc::new(CircuitBreaker::new(c))),
+            cache: self.cache,
+            queue: self.queue,
+            private_prefixes: self.private_prefixes.into(),
+            concurrency: self.concurrency.max(1),
+            interceptors: Arc::new(self.interceptors),
+            writer_id: writer_id.into(),
+            #[cfg(feature = "mock")]
+            router: self.router,
+            auth: Arc::new(Mutex::new(AuthState {
+                session: self.session,
+                keypair: self.keypair,
+            })),
+            identities: Arc::default(),
+        })
+    }
+}
+
+impl Default for PubkyClientBuilder {
+    fn default() -> Self {
+        Self {
+            homeserver: None,
+            homeservers: HashMap::new(),
+            pkarr_relays: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            dht_bootstrap: None,
+            pool_max_idle_per_host: usize::MAX,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            connect_timeout: None,
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default(),
+            circuit_breaker: None,
+            cache: None,
+            queue: None,
+            private_prefixes: Vec::new(),
+            session: None,
+            keypair: None,
+            concurrency: 8,
+            interceptors: Interceptors::default(),
+            user_agent: concat!("pubky-client/", env!("CARGO_PKG_VERSION")).to_string(),
+            writer_id: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            pinned_keys: Vec::new(),
+            #[cfg(feature = "mock")]
+            router: None,
+        }
+    }
+}
+
+/// Session and credentials used to authenticate requests
+#[derive(Debug, Default)]
+struct AuthState {
+    session: Option<SessionInfo>,
+    /// Keypair signing writes, and signing in again when the session is
+    /// rejected
+    keypair: Option<Keypair>,
+}
+
+/// Client for a homeserver's storage API
+///
+/// Cloning is cheap: clones share the same connection pool, session, cache,
+/// offline queue, and circuit breaker. Requests carry the current session
+/// token, and a request rejected with `401 Unauthorized` is retried once
+/// after signing in again with the keypair given to [`signup`](Self::signup)
+/// or [`signin`](Self::signin). Writes to that keypair's entries are signed
+/// with it. Transient failures are retried according to the builder's
+/// [`RetryPolicy`].
+#[derive(Debug, Clone)]
+pub struct PubkyClient {
+    pub(crate) http: reqwest::Client,
+    homeserver: String,
+    /// Homeservers of other users, by public key
+    homeservers: Arc<HashMap<PublicKey, String>>,
+    pub(crate) pkarr: Arc<Resolver>,
+    timeouts: Arc<Timeouts>,
+    pub(crate) retry: RetryPolicy,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    cache: Option<Arc<HttpCache>>,
+    pub(crate) queue: Option<Arc<OfflineQueue>>,
+    pub(crate) private_prefixes: Arc<[String]>,
+    pub(crate) concurrency: usize,
+    interceptors: Arc<Interceptors>,
+    writer_id: Arc<str>,
+    auth: Arc<Mutex<AuthState>>,
+    /// Public keys homeservers sign auth tokens for, by homeserver URL
+    identities: Arc<Mutex<HashMap<String, PublicKey>>>,
+    #[cfg(feature = "mock")]
+    router: Option<axum::Router>,
+}
+
+/// Shorter name of [`PubkyClient`], the typed client of this crate
+pub type Client = PubkyClient;
+
+impl PubkyClient {
+    /// Create a client for the homeserver at the given base URL
+    pub fn new(homeserver: impl Into<String>) -> Self {
+        PubkyClientBuilder::default()
+            .homeserver(homeserver)
+            .build()
+            .expect("default HTTP client configuration is valid")
+    }
+
+    /// Create a builder with default settings
+    pub fn builder() -> PubkyClientBuilder {
+        PubkyClientBuilder::default()
+    }
+
+    /// Id naming this client in the version of entries it writes
+    pub fn writer_id(&self) -> &str {
+        &self.writer_id
+    }
+
+    /// Base URL of the homeserver
+    pub fn homeserver(&self) -> &str {
+        &self.homeserver
+    }
+
+    /// Base URL of the homeserver storing the entries of `public_key`, as
+    /// far as known without a pkarr lookup
+    pub fn homeserver_of(&self, public_key: &PublicKey) -> String {
+        match self.homeservers.get(public_key) {
+            Some(homeserver) => homeserver.clone(),
+            None => self
+                .pkarr
+                .cached(public_key)
+                .flatten()
+                .unwrap_or_else(|| self.homeserver.clone()),
+        }
+    }
+
+    /// Base URL of the homeserver storing the entries of `public_key`,
+    /// looking it up through pkarr if needed
+    pub(crate) async fn locate(&self, public_key: &PublicKey) -> String {
+        if self.homeservers.contains_key(public_key) || !self.pkarr.is_enabled() {
+            return self.homeserver_of(public_key);
+        }
+        match self.pkarr.resolve(&self.http, public_key).await {
+            Ok(Some(homeserver)) => homeserver,
+            Ok(None) | Err(_) => self.homeserver.clone(),
+        }
+    }
+
+    /// Store `body` at `path` for the given owner
+    pub async fn put(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        body: impl Into<Bytes>,
+    ) -> Result<()> {
+        self.put_inner(owner, path, body.into(), None, None)
+            .await
+            .map(drop)
+    }
+
+    /// Store `body` at `path` with `tags` such as `album:2024`, which
+    /// replace the tags of the entry
+    ///
+    /// Tags are sent in plain text, even for private entries. List entries
+    /// by tag with [`ListOptions::tags`](crate::ListOptions::tags).
+    pub async fn put_tagged(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        body: impl Into<Bytes>,
+        tags: &[&str],
+    ) -> Result<()> {
+        let public_key = owner.into_public_key()?;
+        let url = self.url(public_key, path).await?;
+        let payload = self.seal(&public_key, path, body.into())?;
+        let request = self.http.put(&url);
+        let mut request = self
+            .sign_write(request, "PUT", &public_key, path, &payload)
+            .await?
+            .body(payload);
+        for tag in tags {
+            request = request.header(TAG_HEADER, *tag);
+        }
+        let response = self.send(Operation::Put, request).await?;
+        self.uncache(&url);
+        let response = check(response).await?;
+        self.observe(&url, Version::of_response(&response));
+        Ok(())
+    }
+
+    /// Store `body` at `path` if `precondition` holds, returning the `ETag`
+    /// of the new entry, if the homeserver sent one
+    pub(crate) async fn put_inner(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        body: Bytes,
+        precondition: Option<&Precondition>,
+        upload: Option<OnProgress>,
+    ) -> Result<Option<String>> {
+        let public_key = owner.into_public_key()?;
+        let url = self.url(public_key, path).await?;
+        let payload = self.seal(&public_key, path, body)?;
+        let mut request = self.http.put(&url);
+        if let Some(precondition) = precondition {
+            request = precondition.apply(request);
+        }
+        let request = self
+            .sign_write(request, "PUT", &public_key, path, &payload)
+            .await?
+            .body(payload);
+        let response = self
+            .send_with(Operation::Put, request, upload.as_ref())
+            .await?;
+        self.uncache(&url);
+        let response = check(response).await?;
+        self.observe(&url, Version::of_response(&response));
+        Ok(etag_of(&response))
+    }
+
+    /// Retrieve the data at `path`, or `None` if there is none
+    ///
+    /// With a [cache](PubkyClientBuilder::cache), entries that haven't
+    /// changed since they were last read are served locally.
+    pub async fn get(&self, owner: impl IntoPublicKey, path: &str) -> Result<Option<Bytes>> {
+        let entry = self.get_inner(owner, path, None).await?;
+        Ok(entry.map(|(body, _)| body))
+    }
+
+    /// Retrieve the data at `path` with its `ETag`, if the homeserver sent
+    /// one
+    pub(crate) async fn get_inner(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        download: Option<OnProgress>,
+    ) -> Result<Option<(Bytes, Option<String>)>> {
+        let public_key = owner.into_public_key()?;
+        let url = self.url(public_key, path).await?;
+        let Some((stored, etag)) = self.fetch(&url, download.as_ref()).await? else {
+            self.observe(&url, Some(Version::Absent));
+            return Ok(None);
+        };
+        let body = self.open(&public_key, path, stored)?;
+        self.observe(&url, etag.as_deref().and_then(Version::of_etag));
+        Ok(Some((body, etag)))
+    }
+
+    /// Retrieve an entry as stored, with its `ETag`, through the cache
+    async fn fetch(
+        &self,
+        url: &str,
+        download: Option<&OnProgress>,
+    ) -> Result<Option<(Bytes, Option<String>)>> {
+        let cached = self.cache.as_ref().and_then(|cache| cache.get(url));
+
+        let mut request = self.http.get(url);
+        if let Some(cached) = &cached {
+            request = request.header(IF_NONE_MATCH, &cached.etag);
+        }
+        let response = self.send(Operation::Get, request).await?;
+        match (response.status(), cached) {
+            (StatusCode::NOT_MODIFIED, Some(cached)) => {
+                return Ok(Some((cached.body, Some(cached.etag))))
+            }
+            (StatusCode::NOT_FOUND, _) => {
+                self.uncache(url);
+                return Ok(None);
+            }
+            _ => {}
+        }
+
+        let response = check(response).await?;
+        let etag = etag_of(&response);
+        let body = match download {
+            Some(on_progress) => progress::read_body(response, on_progress).await?,
+            None => response.bytes().await?,
+        };
+        if let (Some(cache), Some(etag)) = (&self.cache, &etag) {
+            let entry = CachedEntry {
+                etag: etag.clone(),
+                body: body.clone(),
+            };
+            cache.insert(url, entry);
+        }
+        Ok(Some((body, etag)))
+    }
+
+    /// Delete the data at `path`, returning whether it existed
+    pub async fn delete(&self, owner: impl IntoPublicKey, path: &str) -> Result<bool> {
+        self.delete_inner(owner, path, None).await
+    }
+
+    /// Delete the data at `path` if `precondition` holds, returning whether
+    /// it existed
+    pub(crate) async fn delete_inner(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        precondition: Option<&Precondition>,
+    ) -> Result<bool> {
+        let public_key = owner.into_public_key()?;
+        let url = self.url(public_key, path).await?;
+        let mut request = self.http.delete(&url);
+        if let Some(precondition) = precondition {
+            request = precondition.apply(request);
+        }
+        let request = self
+            .sign_write(request, "DELETE", &public_key, path, &[])
+            .await?;
+        let response = self.send(Operation::Delete, request).await?;
+        self.uncache(&url);
+        if response.status() == StatusCode::NOT_FOUND {
+            self.observe(&url, Some(Version::Absent));
+            return Ok(false);
+        }
+
+        check(response).await?;
+        self.observe(&url, Some(Version::Absent));
+        Ok(true)
+    }
+
+    /// List the paths of all entries under `prefix`
+    ///
+    /// A trailing `/` is added to the prefix if missing. Every page is
+    /// fetched, see [`list_stream`](Self::list_stream) to stream them.
+    pub async fn list(&self, owner: impl IntoPublicKey, prefix: &str) -> Result<Vec<String>> {
+        let prefix = match prefix.ends_with('/') {
+            true => prefix.to_string(),
+            false => format!("{}/", prefix),
+        };
+        let url = self.url(owner, &prefix).await?;
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut request = self.http.get(&url);
+            if let Some(cursor) = &cursor {
+                request = request.query(&[("cursor", cursor)]);
+            }
+            let response = self.send(Operation::List, request).await?;
+            let list: ListResponse = check(response).await?.json().await?;
+            keys.extend(list.keys);
+            cursor = list.next_cursor;
+            if cursor.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+
+    /// Search the text entries of `owner`, best matches first
+    ///
+    /// Only public entries match, unless signed in as the owner. Requires a
+    /// homeserver with search enabled.
+    pub async fn search(
+        &self,
+        owner: impl IntoPublicKey,
+        query: &str,
+    ) -> Result<Vec<SearchResult>> {
+        let url = self.url(owner, "search").await?;
+        let request = self.http.get(&url).query(&[("q", query)]);
+        let response = self.send(Operation::List, request).await?;
+        let search: SearchResponse = check(response).await?.json().await?;
+
+        Ok(search.results)
+    }
+
+    /// Register the keypair with the homeserver and start a session
+    ///
+    /// `invite_code` is required by homeservers that only accept invited
+    /// users. The keypair is kept to re-authenticate when the session ends.
+    ///
+    /// If the client has pkarr relays and the homeserver announces itself
+    /// over pkarr, a `_pubky` record pointing at it is published as with
+    /// [`publish_homeserver`](Self::publish_homeserver). Failing to publish
+    /// it fails the signup, though the account is registered regardless.
+    pub async fn signup(
+        &self,
+        keypair: &Keypair,
+        invite_code: Option<&str>,
+    ) -> Result<SessionInfo> {
+        let identity = self.identity_of(&self.homeserver).await?;
+        let request = SignupRequest {
+            token: AuthToken::sign(keypair, &identity),
+            invite_code: invite_code.map(str::to_string),
+        };
+        let url = format!("{}/signup", self.homeserver);
+        let request = self.http.post(url).json(&request);
+        let response = check(self.execute(Operation::Session, request, None).await?).await?;
+        let homeserver = response
+            .headers()
+            .get(HOMESERVER_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| PublicKey::from_z32(value).ok());
+        let session: SessionInfo = response.json().await?;
+
+        {
+            let mut auth = self.auth.lock().unwrap();
+            auth.session = Some(session.clone());
+            auth.keypair = Some(keypair.clone());
+        }
+        if let Some(homeserver) = homeserver.filter(|_| self.pkarr.is_enabled()) {
+            self.publish_homeserver(keypair, homeserver).await?;
+        }
+        Ok(session)
+    }
+
+    /// Sign in to an account that signed up before
+    ///
+    /// The keypair is kept to re-authenticate when the session ends.
+    pub async fn signin(&self, keypair: &Keypair) -> Result<SessionInfo> {
+        let session = self.create_session(keypair).await?;
+
+        let mut auth = self.auth.lock().unwrap();
+        auth.session = Some(session.clone());
+        auth.keypair = Some(keypair.clone());
+        Ok(session)
+    }
+
+    /// End the current session and forget the keypair
+    pub async fn signout(&self) -> Result<()> {
+        let session = {
+            let mut auth = self.auth.lock().unwrap();
+            auth.keypair = None;
+            auth.session.take()
+        };
+
+        if let Some(session) = session {
+            let url = format!("{}/session", self.homeserver);
+            let request = self.http.delete(url).bearer_auth(&session.token);
+            let response = self.execute(Operation::Session, request, None).await?;
+            // An already expired session counts as signed out
+            if response.status() != StatusCode::UNAUTHORIZED {
+                check(response).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The current session, if signed in
+    ///
+    /// The session can be persisted and later resumed with
+    /// [`PubkyClientBuilder::session`].
+    pub fn session(&self) -> Option<SessionInfo> {
+        self.auth.lock().unwrap().session.clone()
+    }
+
+    /// Fetch the current session from the homeserver
+    ///
+    /// Signs in again if the homeserver no longer knows the session.
+    pub async fn refresh_session(&self) -> Result<SessionInfo> {
+        let url = format!("{}/session", self.homeserver);
+        let response = self.send(Operation::Session, self.http.get(&url)).await?;
+        let session: SessionInfo = check(response).await?.json().await?;
+
+        self.auth.lock().unwrap().session = Some(session.clone());
+        Ok(session)
+    }
+
+    /// List the sessions of the signed-in account, one per device
+    pub async fn sessions(&self) -> Result<Vec<ActiveSession>> {
+        let url = format!("{}/sessions", self.homeserver);
+        let response = self.send(Operation::Session, self.http.get(&url)).await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    /// Revoke a session of the signed-in account by its
+    /// [`id`](ActiveSession::id), returning whether it existed
+    ///
+    /// Revoking the current session signs the client out on its next
+    /// request, unless it has a keypair to sign in again.
+    pub async fn revoke_session(&self, id: &str) -> Result<bool> {
+        let url = format!("{}/sessions/{}", self.homeserver, id);
+        let response = self
+            .send(Operation::Session, self.http.delete(&url))
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => check(response).await.map(|_| true),
+        }
+    }
+
+    /// Send an authenticated request, signing in again once on `401`
+    pub(crate) async fn send(
+        &self,
+        operation: Operation,
+        request: RequestBuilder,
+    ) -> Result<Response> {
+        self.send_with(operation, request, None).await
+    }
+
+    /// Like [`send`](Self::send), reporting the upload of the body
+    async fn send_with(
+        &self,
+        operation: Operation,
+        request: RequestBuilder,
+        upload: Option<&OnProgress>,
+    ) -> Result<Response> {
+        // Other users' homeservers get no credentials
+        let request = request.build()?;
+        if !self.is_own(request.url()) {
+            let request = RequestBuilder::from_parts(self.http.clone(), request);
+            return self.execute(operation, request, upload).await;
+        }
+        let request = RequestBuilder::from_parts(self.http.clone(), request);
+
+        let (token, keypair) = {
+            let auth = self.auth.lock().unwrap();
+            let token = auth.session.as_ref().map(|s| s.token.clone());
+            (token, auth.keypair.clone())
+        };
+
+        let again = request.try_clone().expect("request bodies are buffered");
+        let request = match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = self.execute(operation, request, upload).await?;
+        let Some(keypair) = keypair.filter(|_| response.status() == StatusCode::UNAUTHORIZED)
+        else {
+            return Ok(response);
+        };
+
+        let session = self.create_session(&keypair).await?;
+        let token = session.token.clone();
+        self.auth.lock().unwrap().session = Some(session);
+        self.execute(operation, again.bearer_auth(token), upload)
+            .await
+    }
+
+    /// Whether `url` points to the client's own homeserver, or to the one
+    /// holding its session, as after a relayed authorization
+    fn is_own(&self, url: &reqwest::Url) -> bool {
+        let session_home = self
+            .session()
+            .and_then(|session| PublicKey::from_z32(&session.public_key).ok())
+            .map(|public_key| self.homeserver_of(&public_key));
+        [Some(self.homeserver.clone()), session_home]
+            .into_iter()
+            .flatten()
+            .any(|home| reqwest::Url::parse(&home).is_ok_and(|own| own.origin() == url.origin()))
+    }
+
+    /// Drop a cached entry after it was written or deleted
+    pub(crate) fn uncache(&self, url: &str) {
+        if let Some(cache) = &self.cache {
+            cache.remove(url);
+        }
+    }
+
+    /// Record the version of an entry for the offline queue's conflict
+    /// detection, forgetting it if unknown
+    pub(crate) fn observe(&self, url: &str, version: Option<Version>) {
+        if let Some(queue) = &self.queue {
+            queue.observe(url, version);
+        }
+    }
+
+    /// Send a request with the operation's timeout, retrying transient
+    /// failures of idempotent requests and feeding the circuit breaker
+    pub(crate) async fn execute(
+        &self,
+        operation: Operation,
+        request: RequestBuilder,
+        upload: Option<&OnProgress>,
+    ) -> Result<Response> {
+        let mut request = request.build()?;
+        if let Some(timeout) = self.timeouts.get(operation) {
+            *request.timeout_mut() = Some(timeout);
+        }
+        let max_retries = match retry::is_idempotent(request.method()) {
+            true => self.retry.max_retries,
+            false => 0,
+        };
+
+        let mut retries = 0;
+        loop {
+            if let Some(breaker) = &self.circuit_breaker {
+                if !breaker.allow() {
+                    return Err(Error::CircuitOpen);
+                }
+            }
+
+            #[cfg_attr(target_arch = "wasm32", allow(unused_mut))]
+            let mut attempt = request.try_clone().expect("request bodies are buffered");
+            #[cfg(not(target_arch = "wasm32"))]
+            if let Some(on_progress) = upload {
+                let body = attempt
+                    .body()
+                    .and_then(|b| b.as_bytes())
+                    .unwrap_or_default();
+                let body = progress::upload_body(Bytes::copy_from_slice(body), on_progress.clone());
+                *attempt.body_mut() = Some(body);
+            }
+            let result = self.dispatch(attempt).await;
+
+            if let Some(breaker) = &self.circuit_breaker {
+                match &result {
+                    Ok(response) if !response.status().is_server_error() => {
+                        breaker.record_success()
+                    }
+                    _ => breaker.record_failure(),
+                }
+            }
+
+            let retryable = match &result {
+                Ok(response) => retry::is_retryable_status(response.status()),
+                Err(error) => retry::is_retryable_error(error),
+            };
+            if !retryable || retries >= max_retries {
+                #[cfg(target_arch = "wasm32")]
+                if let (Some(on_progress), Some(body)) = (upload, request.body()) {
+                    let size = body.as_bytes().map(|b| b.len() as u64);
+                    on_progress(progress::Progress {
+                        transferred: size.unwrap_or(0),
+                        total: size,
+                    });
+                }
+                return Ok(result?);
+            }
+
+            // Honor the server's Retry-After, within the backoff limit
+            let delay = match result.as_ref().ok().and_then(retry::retry_after) {
+                Some(delay) => delay.min(self.retry.max_backoff),
+                None => self.retry.backoff(retries),
+            };
+            retry::sleep(delay).await;
+            retries += 1;
+        }
+    }
+
+    /// Use a session obtained without a keypair, such as by
+    /// [`await_authorization`](Self::await_authorization)
+    pub(crate) fn set_session(&self, session: SessionInfo) {
+        let mut auth = self.auth.lock().unwrap();
+        auth.session = Some(session);
+        auth.keypair = None;
+    }
+
+    /// The keypair of the signed-in user, if the client has it
+    pub(crate) fn keypair(&self) -> Option<Keypair> {
+        self.auth.lock().unwrap().keypair.clone()
+    }
+
+    /// Sign a write to `path` under `owner` with the client's keypair, if
+    /// it is theirs, for the owner's homeserver; homeservers reject writes
+    /// that aren't signed
+    pub(crate) async fn sign_write(
+        &self,
+        request: RequestBuilder,
+        method: &str,
+        owner: &PublicKey,
+        path: &str,
+        body: &[u8],
+    ) -> Result<RequestBuilder> {
+        let Some(keypair) = self.keypair().filter(|k| k.public_key() == *owner) else {
+            return Ok(request);
+        };
+        let identity = self.identity_of(&self.locate(owner).await).await?;
+        let path = path.trim_start_matches('/');
+        let signature = WriteSignature::sign(&keypair, &identity, method, path, body);
+        Ok(request.header(SIGNATURE_HEADER, signature.to_string()))
+    }
+
+    /// Send a request over the network, or to the in-process homeserver,
+    /// through the interceptors
+    async fn dispatch(&self, mut request: Request) -> reqwest::Result<Response> {
+        self.interceptors.on_request(&mut request);
+        #[cfg(feature = "mock")]
+        let response = match &self.router {
+            Some(router) => crate::mock::call(router, request).await,
+            None => self.http.execute(request).await,
+        };
+        #[cfg(not(feature = "mock"))]
+        let response = self.http.execute(request).await;
+        let mut response = response?;
+        self.interceptors.on_response(&mut response);
+        Ok(response)
+    }
+
+    /// Sign in with the keypair without touching the stored session
+    async fn create_session(&self, keypair: &Keypair) -> Result<SessionInfo> {
+        let identity = self.identity_of(&self.homeserver).await?;
+        let url = format!("{}/session", self.homeserver);
+        let token = AuthToken::sign(keypair, &identity);
+        let request = self.http.post(url).json(&token);
+        let response = self.execute(Operation::Session, request, None).await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    /// The public key a homeserver accepts auth tokens for, fetched once
+    /// from its `/identity`
+    pub(crate) async fn identity_of(&self, homeserver: &str) -> Result<PublicKey> {
+        if let Some(identity) = self.identities.lock().unwrap().get(homeserver) {
+            return Ok(*identity);
+        }
+        let request = self.http.get(format!("{homeserver}/identity"));
+        let response = self.execute(Operation::Session, request, None).await?;
+        let identity = PublicKey::from_z32(check(response).await?.text().await?.trim())?;
+        self.identities
+            .lock()
+            .unwrap()
+            .insert(homeserver.to_string(), identity);
+        Ok(identity)
+    }
+
+    /// Build the URL of an entry on the owner's homeserver
+    pub(crate) async fn url(&self, owner: impl IntoPublicKey, path: &str) -> Result<String> {
+        let public_key = owner.into_public_key()?;
+        let homeserver = self.locate(&public_key).await;
+        Ok(entry_url(&homeserver, &public_key, path))
+    }
+}
+
+/// URL of an entry on the given homeserver
+pub(crate) fn entry_url(homeserver: &str, public_key: &PublicKey, path: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        homeserver,
+        public_key.to_z32(),
+        path.trim_start_matches('/')
+    )
+}
+
+/// The `ETag` of a response, if it has one
+pub(crate) fn etag_of(response: &Response) -> Option<String> {
+    let etag = response.headers().get(ETAG)?;
+    etag.to_str().ok().map(str::to_string)
+}
+
+/// Turn non-success responses into errors
+pub(crate) async fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    Err(Error::from_response(status, &body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+    use pubky_server::faults::FaultyStorage;
+    use pubky_server::Server;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_client_operations() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .search(pubky_server::SearchConfig::new())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .build()
+            .unwrap();
+
+        client
+            .put(&public_key, "my-app/a.txt", "alpha")
+            .await
+            .unwrap();
+        client
+            .put(&public_key, "/my-app/b.txt", b"beta".to_vec())
+            .await
+            .unwrap();
+
+        let data = client.get(&public_key, "my-app/a.txt").await.unwrap();
+        assert_eq!(data.as_deref(), Some(&b"alpha"[..]));
+
+        // Owners can be given as z-base-32 strings or pubky:// URLs
+        let url = format!("pubky://{}", public_key);
+        let mut keys = client.list(&url, "my-app").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["my-app/a.txt", "my-app/b.txt"]);
+
+        assert!(client
+            .delete(public_key.to_z32(), "my-app/a.txt")
+            .await
+            .unwrap());
+        assert!(!client.delete(&public_key, "my-app/a.txt").await.unwrap());
+        assert_eq!(client.get(&public_key, "my-app/a.txt").await.unwrap(), None);
+
+        let err = client.get("not-a-key", "x").await.unwrap_err();
+        assert!(matches!(err, Error::InvalidPublicKey(_)));
+
+        // Only the owner's keypair can write
+        let stranger = PubkyClient::new(server.url());
+        let err = stranger.put(&public_key, "my-app/a.txt", "evil").await;
+        assert!(matches!(err, Err(Error::Unauthorized)));
+
+        // Public text entries are indexed in the background
+        client
+            .put(&public_key, "pub/notes.txt", "searchable notes")
+            .await
+            .unwrap();
+        let mut results = client.search(&public_key, "notes").await.unwrap();
+        while results.is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            results = client.search(&public_key, "notes").await.unwrap();
+        }
+        assert_eq!(results[0].path, "pub/notes.txt");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_client_sessions() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let client = PubkyClient::new(server.url());
+        let keypair = Keypair::random();
+        assert!(client.session().is_none());
+
+        let session = client.signup(&keypair, None).await.unwrap();
+        assert_eq!(session.public_key, keypair.public_key().to_z32());
+        assert_eq!(client.session(), Some(session.clone()));
+
+        // A persisted session can be resumed by another client
+        let resumed = PubkyClient::builder()
+            .homeserver(server.url())
+            .session(session.clone())
+            .build()
+            .unwrap();
+        assert_eq!(resumed.refresh_session().await.unwrap(), session);
+
+        // A rejected session is replaced by signing in again
+        server.storage().remove_session(&session.token);
+        let renewed = client.refresh_session().await.unwrap();
+        assert_ne!(renewed.token, session.token);
+        assert_eq!(client.session(), Some(renewed));
+
+        // Without the keypair the client can't recover
+        let err = resumed.refresh_session().await.unwrap_err();
+        assert!(matches!(err, Error::Unauthorized));
+
+        // Another device's session can be listed and revoked
+        let phone = PubkyClient::builder()
+            .homeserver(server.url())
+            .user_agent("phone")
+            .build()
+            .unwrap();
+        phone.signin(&keypair).await.unwrap();
+        let sessions = client.sessions().await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().all(|s| s.capabilities == "/:rw"));
+        let current = sessions.iter().find(|s| s.current).unwrap();
+        assert!(current
+            .device
+            .as_deref()
+            .unwrap()
+            .starts_with("pubky-client/"));
+        let lost = sessions.iter().find(|s| !s.current).unwrap();
+        assert_eq!(lost.device.as_deref(), Some("phone"));
+        assert!(client.revoke_session(&lost.id).await.unwrap());
+        assert!(!client.revoke_session(&lost.id).await.unwrap());
+        assert_eq!(client.sessions().await.unwrap().len(), 1);
+
+        client.signout().await.unwrap();
+        assert!(client.session().is_none());
+
+        server.shutdown().await;
+    }
+
+    /// Serve `503 Service Unavailable` to the first `failures` requests and
+    /// `200 OK` afterwards, counting requests other than for `/identity`
+    async fn flaky_server(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let identity = Keypair::random().public_key().to_z32();
+        let identity = format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{identity}",
+            identity.len()
+        );
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                if buf.starts_with(b"GET /identity ") {
+                    let _ = stream.write_all(identity.as_bytes()).await;
+                    continue;
+                }
+                let response = match counter.fetch_add(1, Ordering::SeqCst) < failures {
+                    true => "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    false => "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    /// Serve a fixed entry with an `ETag`, answering `304 Not Modified` to
+    /// matching `If-None-Match` requests, counting full `GET` responses
+    async fn etag_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let full = Arc::new(AtomicUsize::new(0));
+
+        let counter = full.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let response = match request.contains("if-none-match: \"v1\"") {
+                    true => {
+                        "HTTP/1.1 304 Not Modified\r\netag: \"v1\"\r\nconnection: close\r\n\r\n"
+                    }
+                    false => {
+                        if request.starts_with("get") {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                        }
+                        "HTTP/1.1 200 OK\r\netag: \"v1\"\r\ncontent-length: 5\r\nconnection: close\r\n\r\nhello"
+                    }
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, full)
+    }
+
+    #[tokio::test]
+    async fn test_client_cache() {
+        let (url, full) = etag_server().await;
+        let client = PubkyClient::builder()
+            .homeserver(url)
+            .cache(HttpCache::memory(16))
+            .build()
+            .unwrap();
+        let public_key = Keypair::random().public_key();
+
+        for _ in 0..3 {
+            let data = client.get(&public_key, "a.txt").await.unwrap();
+            assert_eq!(data.as_deref(), Some(&b"hello"[..]));
+        }
+        assert_eq!(full.load(Ordering::SeqCst), 1);
+
+        // Writing an entry drops it from the cache
+        client.put(&public_key, "a.txt", "new").await.unwrap();
+        client.get(&public_key, "a.txt").await.unwrap();
+        assert_eq!(full.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_client_retries() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+
+        let (url, requests) = flaky_server(2).await;
+        let client = PubkyClient::builder()
+            .homeserver(url)
+            .retry(policy)
+            .build()
+            .unwrap();
+        let data = client.get(&public_key, "a.txt").await.unwrap();
+        assert_eq!(data.as_deref(), Some(&b"ok"[..]));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Retries give up after max_retries
+        let (url, requests) = flaky_server(10).await;
+        let client = PubkyClient::builder()
+            .homeserver(url)
+            .retry(policy)
+            .build()
+            .unwrap();
+        let err = client.get(&public_key, "a.txt").await.unwrap_err();
+        assert!(
+            matches!(err, Error::Status { status, .. } if status == StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // Signup isn't idempotent and is never retried
+        let (url, requests) = flaky_server(1).await;
+        let client = PubkyClient::builder()
+            .homeserver(url)
+            .retry(policy)
+            .build()
+            .unwrap();
+        assert!(client.signup(&Keypair::random(), None).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Writes ride out a homeserver failing half of its requests
+        let faults = FaultyStorage::new().seed(3).transient_errors(0.5);
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .storage_layer(faults.clone())
+            .start()
+            .await
+            .unwrap();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .retry(RetryPolicy {
+                max_retries: 10,
+                ..policy
+            })
+            .build()
+            .unwrap();
+        for n in 0..20 {
+            let path = format!("{}.txt", n);
+            client.put(&public_key, &path, "data").await.unwrap();
+        }
+        assert!(faults.stats().transient_errors > 0);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_client_circuit_breaker() {
+        let (url, requests) = flaky_server(10).await;
+        let client = PubkyClient::builder()
+            .homeserver(url)
+            .retry(RetryPolicy::none())
+            .circuit_breaker(CircuitBreakerConfig {
+                failure_threshold: 2,
+                reset_timeout: Duration::from_secs(60),
+            })
+            .build()
+            .unwrap();
+        let public_key = Keypair::random().public_key();
+
+        for _ in 0..2 {
+            let err = client.get(&public_key, "a.txt").await.unwrap_err();
+            assert!(matches!(err, Error::Status { .. }));
+        }
+        let err = client.get(&public_key, "a.txt").await.unwrap_err();
+        assert!(matches!(err, Error::CircuitOpen));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,150 @@
+//! Conditional writes
+//!
+//! Entries are tagged with the SHA-256 hash of their stored value, sent as
+//! their `ETag`. [`PubkyClient::put_if`] and [`PubkyClient::delete_if`] only
+//! write an entry the homeserver still has in the expected version, so two
+//! devices editing it can't silently overwrite each other's changes: the
+//! second write fails with [`ClientError::Conflict`](crate::ClientError),
+//! and its device reads the entry again with
+//! [`get_with_etag`](PubkyClient::get_with_etag) to merge.
+
+use bytes::Bytes;
+use reqwest::header::{IF_MATCH, IF_NONE_MATCH};
+use reqwest::RequestBuilder;
+
+use crate::client::{IntoPublicKey, PubkyClient};
+use crate::error::Result;
+
+/// What a conditional write expects of the entry it replaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precondition {
+    /// The entry exists with this `ETag`, as returned by
+    /// [`get_with_etag`](PubkyClient::get_with_etag)
+    Matches(String),
+    /// There is no entry
+    Absent,
+}
+
+impl Precondition {
+    /// Add the header expressing the precondition to `request`
+    pub(crate) fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Precondition::Matches(etag) => request.header(IF_MATCH, etag),
+            Precondition::Absent => request.header(IF_NONE_MATCH, "*"),
+        }
+    }
+}
+
+impl PubkyClient {
+    /// Retrieve the data at `path` with its `ETag`, or `None` if there is
+    /// none
+    ///
+    /// The tag is `None` for homeservers that don't send one.
+    pub async fn get_with_etag(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+    ) -> Result<Option<(Bytes, Option<String>)>> {
+        self.get_inner(owner, path, None).await
+    }
+
+    /// Store `body` at `path` if the entry is as `precondition` expects,
+    /// returning the `ETag` of the new entry, if the homeserver sent one
+    ///
+    /// Fails with [`ClientError::Conflict`](crate::ClientError::Conflict),
+    /// writing nothing, if the entry changed.
+    pub async fn put_if(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        body: impl Into<Bytes>,
+        precondition: &Precondition,
+    ) -> Result<Option<String>> {
+        self.put_inner(owner, path, body.into(), Some(precondition), None)
+            .await
+    }
+
+    /// Delete the data at `path` if the entry is as `precondition` expects,
+    /// returning whether it existed
+    ///
+    /// Fails with [`ClientError::Conflict`](crate::ClientError::Conflict),
+    /// deleting nothing, if the entry changed.
+    pub async fn delete_if(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        precondition: &Precondition,
+    ) -> Result<bool> {
+        self.delete_inner(owner, path, Some(precondition)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ClientError;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_conditional_writes() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .build()
+            .unwrap();
+        let path = "notes/doc.txt";
+
+        // Absent entries are only created once
+        let etag = client
+            .put_if(&public_key, path, "draft", &Precondition::Absent)
+            .await
+            .unwrap()
+            .unwrap();
+        let error = client
+            .put_if(&public_key, path, "other", &Precondition::Absent)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ClientError::Conflict { .. }));
+        let (body, current) = client
+            .get_with_etag(&public_key, path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (body.as_ref(), current),
+            (b"draft".as_ref(), Some(etag.clone()))
+        );
+
+        // Writes based on a stale tag fail
+        let seen = Precondition::Matches(etag);
+        let edited = client
+            .put_if(&public_key, path, "edited", &seen)
+            .await
+            .unwrap()
+            .unwrap();
+        let error = client
+            .put_if(&public_key, path, "stale", &seen)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ClientError::Conflict { .. }));
+        let error = client
+            .delete_if(&public_key, path, &seen)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ClientError::Conflict { .. }));
+
+        let current = Precondition::Matches(edited);
+        assert!(client.delete_if(&public_key, path, &current).await.unwrap());
+        assert_eq!(client.get(&public_key, path).await.unwrap(), None);
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,58 @@
+//! Pkarr lookups on the Mainline DHT
+//!
+//! Pkarr packets are stored on the DHT as BEP 44 mutable items, keyed by the
+//! SHA-1 of the public key that signed them. The client walks towards the
+//! nodes closest to that key with `get` queries and keeps the newest valid
+//! packet they return, so resolution works without any relay.
+
+use pubky_common::krpc::{self, dict, Bencode, Node};
+use pubky_common::pkarr::SignedPacket;
+use pubky_common::PublicKey;
+
+use crate::error::Result;
+
+/// Looks up pkarr packets on the Mainline DHT
+#[derive(Debug, Default)]
+pub(crate) struct Dht {
+    /// `host:port` addresses of the nodes to start lookups from
+    bootstrap: Vec<String>,
+}
+
+impl Dht {
+    pub(crate) fn new(bootstrap: Vec<String>) -> Self {
+        Self { bootstrap }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.bootstrap.is_empty()
+    }
+
+    /// The newest packet of `public_key` held by the closest nodes
+    ///
+    /// Items that don't carry a valid signature of `public_key` are ignored.
+    pub(crate) async fn get(&self, public_key: &PublicKey) -> Result<Option<SignedPacket>> {
+        let bootstrap = krpc::resolve(&self.bootstrap).await;
+        if bootstrap.is_empty() {
+            return Ok(None);
+        }
+
+        let target = krpc::mutable_target(public_key);
+        let args = dict([("target", Bencode::Bytes(target.to_vec()))]);
+        let mut node = Node::bind().await?;
+        let answers = node.walk("get", &target, args, &bootstrap).await;
+
+        let packet = answers
+            .into_iter()
+            .filter_map(|(_, answer)| {
+                // The relay payload is the signature, the timestamp and the
+                // packet, which BEP 44 stores as `sig`, `seq` and `v`
+                let timestamp = u64::try_from(answer.int("seq")?).ok()?;
+                let mut payload = answer.bytes("sig").filter(|sig| sig.len() == 64)?.to_vec();
+                payload.extend_from_slice(&timestamp.to_be_bytes());
+                payload.extend_from_slice(answer.bytes("v")?);
+                SignedPacket::from_relay_payload(public_key, &payload).ok()
+            })
+            .max_by_key(SignedPacket::timestamp);
+        Ok(packet)
+    }
+}
@@ -0,0 +1,128 @@
+//! Transparent encryption of private prefixes
+//!
+//! Entries under the prefixes given to
+//! [`PubkyClientBuilder::private_prefix`](crate::PubkyClientBuilder::private_prefix)
+//! are encrypted before upload and decrypted after download, with a key
+//! derived from the signed-in keypair for each prefix. Only content is
+//! encrypted: paths stay visible to the homeserver.
+
+use bytes::Bytes;
+use pubky_common::encryption::EncryptionKey;
+use pubky_common::PublicKey;
+
+use crate::client::PubkyClient;
+use crate::error::{Error, Result};
+
+impl PubkyClient {
+    /// Encrypt `body` if `path` is under a private prefix of the signed-in
+    /// user
+    pub(crate) fn seal(&self, owner: &PublicKey, path: &str, body: Bytes) -> Result<Bytes> {
+        match self.encryption_key(owner, path)? {
+            Some(key) => Ok(key.seal(&body, aad(owner, path).as_bytes()).into()),
+            None => Ok(body),
+        }
+    }
+
+    /// Decrypt `body` if `path` is under a private prefix of the signed-in
+    /// user
+    pub(crate) fn open(&self, owner: &PublicKey, path: &str, body: Bytes) -> Result<Bytes> {
+        match self.encryption_key(owner, path)? {
+            Some(key) => key
+                .open(&body, aad(owner, path).as_bytes())
+                .map(Bytes::from)
+                .map_err(|e| Error::Encryption(e.to_string())),
+            None => Ok(body),
+        }
+    }
+
+    /// The key for the longest private prefix containing `path`, if the
+    /// entry is the signed-in user's
+    ///
+    /// Other users' entries are passed through as stored.
+    fn encryption_key(&self, owner: &PublicKey, path: &str) -> Result<Option<EncryptionKey>> {
+        let path = path.trim_start_matches('/');
+        let Some(prefix) = self
+            .private_prefixes
+            .iter()
+            .filter(|prefix| path.starts_with(prefix.as_str()))
+            .max_by_key(|prefix| prefix.len())
+        else {
+            return Ok(None);
+        };
+
+        let keypair = self.keypair().ok_or_else(|| {
+            Error::Encryption(format!("sign in to access the private prefix {}", prefix))
+        })?;
+        if keypair.public_key() != *owner {
+            return Ok(None);
+        }
+        Ok(Some(EncryptionKey::derive(&keypair, prefix)))
+    }
+}
+
+/// Associated data binding a ciphertext to its owner and path
+fn aad(owner: &PublicKey, path: &str) -> String {
+    format!("{}/{}", owner.to_z32(), path.trim_start_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_private_prefix() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .private_prefix("priv/")
+            .build()
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+
+        // Private entries can't be written without the keypair
+        let result = client
+            .put(&public_key, "priv/diary.txt", "dear diary")
+            .await;
+        assert!(matches!(result, Err(Error::Encryption(_))));
+
+        client.signup(&keypair, None).await.unwrap();
+        client
+            .put(&public_key, "priv/diary.txt", "dear diary")
+            .await
+            .unwrap();
+        client
+            .put(&public_key, "pub/hello.txt", "hello")
+            .await
+            .unwrap();
+        let data = client.get(&public_key, "priv/diary.txt").await.unwrap();
+        assert_eq!(data.unwrap(), "dear diary");
+
+        // Others only see ciphertext; public entries are untouched
+        let other = PubkyClient::new(server.url());
+        let stored = other.get(&public_key, "priv/diary.txt").await.unwrap();
+        assert_ne!(stored.as_deref(), Some(&b"dear diary"[..]));
+        let data = other.get(&public_key, "pub/hello.txt").await.unwrap();
+        assert_eq!(data.unwrap(), "hello");
+
+        // Ciphertext moved to another path doesn't decrypt
+        let raw = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .build()
+            .unwrap();
+        raw.put(&public_key, "priv/moved.txt", stored.unwrap())
+            .await
+            .unwrap();
+        let result = client.get(&public_key, "priv/moved.txt").await;
+        assert!(matches!(result, Err(Error::Encryption(_))));
+
+        server.shutdown().await;
+    }
+}
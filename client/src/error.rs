@@ -0,0 +1,151 @@
+//! Client error type
+
+use pubky_common::dto::ErrorResponse;
+use reqwest::StatusCode;
+
+/// Errors returned by [`PubkyClient`](crate::PubkyClient)
+///
+/// Error responses are mapped from the homeserver's machine-readable
+/// `code`, falling back to the HTTP status for homeservers that don't send
+/// one.
+#[derive(thiserror::Error, Debug)]
+pub enum ClientError {
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(#[from] pubky_common::Error),
+
+    /// The homeserver couldn't be reached or the connection failed
+    #[error("Network error: {0}")]
+    Network(#[from] reqwest::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Not found")]
+    NotFound,
+
+    /// No valid session, or the session may not access the resource
+    #[error("Unauthorized")]
+    Unauthorized,
+
+    /// The user's storage quota is used up
+    #[error("Storage quota exceeded")]
+    QuotaExceeded,
+
+    /// The entry isn't in the version the request expected
+    #[error("Conflict: expected {expected:?}, found {actual:?}")]
+    Conflict {
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+
+    /// Any other error response
+    #[error("Server returned {status}: {message}")]
+    Status {
+        status: StatusCode,
+        /// Machine-readable error code, if the homeserver sent one
+        code: Option<String>,
+        message: String,
+    },
+
+    #[error("Homeserver unavailable: circuit breaker is open")]
+    CircuitOpen,
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+
+    /// Pkarr records couldn't be published or were invalid
+    #[error("Pkarr error: {0}")]
+    Pkarr(String),
+}
+
+/// Former name of [`ClientError`]
+pub type Error = ClientError;
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+impl ClientError {
+    /// Map an error response
+    pub(crate) fn from_response(status: StatusCode, body: &str) -> Self {
+        let response = serde_json::from_str::<ErrorResponse>(body).ok();
+        let code = response.as_ref().and_then(|r| r.code.as_deref());
+        match (code, status) {
+            (Some("not_found"), _) | (None, StatusCode::NOT_FOUND) => ClientError::NotFound,
+            (Some("unauthorized" | "forbidden"), _)
+            | (None, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => ClientError::Unauthorized,
+            (Some("quota_exceeded"), _) | (None, StatusCode::INSUFFICIENT_STORAGE) => {
+                ClientError::QuotaExceeded
+            }
+            (Some("conflict"), _)
+            | (None, StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED) => {
+                let response = response.unwrap_or_default();
+                ClientError::Conflict {
+                    expected: response.expected,
+                    actual: response.actual,
+                }
+            }
+            _ => {
+                let code = code.map(str::to_string);
+                let message = match response {
+                    Some(response) => response.error,
+                    None => body.to_string(),
+                };
+                ClientError::Status {
+                    status,
+                    code,
+                    message,
+                }
+            }
+        }
+    }
+
+    /// The HTTP status of an error response, if this is one
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::NotFound => Some(StatusCode::NOT_FOUND),
+            ClientError::Unauthorized => Some(StatusCode::UNAUTHORIZED),
+            ClientError::QuotaExceeded => Some(StatusCode::INSUFFICIENT_STORAGE),
+            ClientError::Conflict { .. } => Some(StatusCode::CONFLICT),
+            ClientError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_response() {
+        let error = ClientError::from_response(StatusCode::NOT_FOUND, "");
+        assert!(matches!(error, ClientError::NotFound));
+
+        // The code takes precedence over the status
+        let body = r#"{"error":"Quota exceeded","code":"quota_exceeded"}"#;
+        let error = ClientError::from_response(StatusCode::FORBIDDEN, body);
+        assert!(matches!(error, ClientError::QuotaExceeded));
+        let error = ClientError::from_response(StatusCode::FORBIDDEN, "");
+        assert!(matches!(error, ClientError::Unauthorized));
+
+        let body = r#"{"error":"Changed","code":"conflict","expected":"\"a\"","actual":"\"b\""}"#;
+        let error = ClientError::from_response(StatusCode::PRECONDITION_FAILED, body);
+        assert!(matches!(
+            error,
+            ClientError::Conflict { expected: Some(e), actual: Some(a) } if e == "\"a\"" && a == "\"b\""
+        ));
+
+        let body = r#"{"error":"Account is frozen","code":"frozen"}"#;
+        let error = ClientError::from_response(StatusCode::LOCKED, body);
+        assert!(matches!(
+            error,
+            ClientError::Status { status: StatusCode::LOCKED, code: Some(code), message }
+                if code == "frozen" && message == "Account is frozen"
+        ));
+
+        let error = ClientError::from_response(StatusCode::BAD_GATEWAY, "upstream down");
+        assert!(matches!(error, ClientError::Status { message, .. } if message == "upstream down"));
+    }
+}
@@ -0,0 +1,126 @@
+//! Request and response interceptors
+//!
+//! Interceptors added with
+//! [`PubkyClientBuilder::interceptor`](crate::PubkyClientBuilder::interceptor)
+//! see every request just before it is sent, including retries, and every
+//! response as it arrives, before the client handles it. They can add
+//! headers, record telemetry, or replace the authentication of requests.
+
+use reqwest::{Request, Response};
+use std::fmt;
+use std::sync::Arc;
+
+/// Hook into the requests of a [`PubkyClient`](crate::PubkyClient)
+///
+/// Both methods do nothing by default. A closure taking `&mut Request` is an
+/// interceptor that only modifies requests.
+pub trait Interceptor: Send + Sync {
+    /// Inspect or modify a request before it is sent
+    fn on_request(&self, request: &mut Request) {
+        let _ = request;
+    }
+
+    /// Inspect or modify a response before the client handles it
+    fn on_response(&self, response: &mut Response) {
+        let _ = response;
+    }
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(&mut Request) + Send + Sync,
+{
+    fn on_request(&self, request: &mut Request) {
+        self(request)
+    }
+}
+
+/// Interceptors of a client, run in the order they were added
+#[derive(Clone, Default)]
+pub(crate) struct Interceptors(Vec<Arc<dyn Interceptor>>);
+
+impl Interceptors {
+    pub(crate) fn push(&mut self, interceptor: Arc<dyn Interceptor>) {
+        self.0.push(interceptor);
+    }
+
+    pub(crate) fn on_request(&self, request: &mut Request) {
+        for interceptor in &self.0 {
+            interceptor.on_request(request);
+        }
+    }
+
+    /// Responses are seen in reverse order, as by nested layers
+    pub(crate) fn on_response(&self, response: &mut Response) {
+        for interceptor in self.0.iter().rev() {
+            interceptor.on_response(response);
+        }
+    }
+}
+
+impl fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interceptors({})", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PubkyClient;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+    use reqwest::header::HeaderValue;
+    use std::sync::Mutex;
+
+    /// Records the status of each response
+    #[derive(Clone, Default)]
+    struct Statuses(Arc<Mutex<Vec<u16>>>);
+
+    impl Interceptor for Statuses {
+        fn on_response(&self, response: &mut Response) {
+            self.0.lock().unwrap().push(response.status().as_u16());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptors() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let statuses = Statuses::default();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .interceptor(|request: &mut Request| {
+                let value = HeaderValue::from_static("tests");
+                request.headers_mut().insert("x-app", value);
+            })
+            .interceptor(statuses.clone())
+            .build()
+            .unwrap();
+
+        client.put(public_key, "a.txt", "a").await.unwrap();
+        assert!(client.get(public_key, "b.txt").await.unwrap().is_none());
+        // The homeserver's identity is fetched to sign the first write
+        assert_eq!(*statuses.0.lock().unwrap(), [200, 201, 404]);
+
+        // Requests can be rewritten, here to another path
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .interceptor(|request: &mut Request| {
+                let url = request.url().as_str().replace("/b.txt", "/a.txt");
+                *request.url_mut() = url.parse().unwrap();
+            })
+            .build()
+            .unwrap();
+        let data = client.get(public_key, "b.txt").await.unwrap();
+        assert_eq!(data.unwrap(), "a");
+
+        server.shutdown().await;
+    }
+}
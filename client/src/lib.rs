@@ -0,0 +1,83 @@
+//! Pubky MVP Client
+//!
+//! A high-level HTTP client for talking to a homeserver, [`PubkyClient`],
+//! also available under the shorter name [`Client`]:
+//!
+//! ```no_run
+//! # async fn example() -> pubky_client::Result<()> {
+//! use pubky_client::Client;
+//! use pubky_common::Keypair;
+//!
+//! let client = Client::new("http://127.0.0.1:3000");
+//! let public_key = Keypair::random().public_key();
+//!
+//! client.put(&public_key, "my-app/hello.txt", "Hello, Pubky!").await?;
+//! let data = client.get(&public_key, "my-app/hello.txt").await?;
+//! let keys = client.list(&public_key, "my-app/").await?;
+//! client.delete(&public_key, "my-app/hello.txt").await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The client also compiles to `wasm32` for browsers, where requests use the
+//! Fetch API and JavaScript bindings are exported with `wasm-bindgen`.
+//!
+//! The `mock` feature adds [`PubkyClient::in_memory`], a client backed by a
+//! homeserver in the same process, for fast tests without network access.
+//!
+//! The `quic-blobs` feature adds [`BlobClient`], which transfers large
+//! entries over raw QUIC streams instead of HTTP.
+
+mod authorize;
+#[cfg(all(feature = "quic-blobs", not(target_arch = "wasm32")))]
+mod blobs;
+mod bulk;
+mod cache;
+mod client;
+mod conditional;
+#[cfg(not(target_arch = "wasm32"))]
+mod dht;
+mod encryption;
+mod error;
+mod interceptor;
+mod list;
+mod migration;
+#[cfg(feature = "mock")]
+mod mock;
+#[cfg(not(target_arch = "wasm32"))]
+mod pinning;
+mod pkarr;
+mod progress;
+mod queue;
+mod retry;
+#[cfg(not(target_arch = "wasm32"))]
+mod sync;
+mod trace;
+mod url;
+mod versions;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+mod watch;
+
+pub use authorize::{AuthorizationRequest, AuthorizationUrl};
+#[cfg(all(feature = "quic-blobs", not(target_arch = "wasm32")))]
+pub use blobs::BlobClient;
+pub use cache::HttpCache;
+pub use client::{Client, IntoPublicKey, PubkyClient, PubkyClientBuilder};
+pub use conditional::Precondition;
+pub use error::{ClientError, Error, Result};
+pub use interceptor::Interceptor;
+pub use list::{ListEntry, ListOptions, ListStream};
+pub use progress::Progress;
+pub use pubky_common::dto::{ActiveSession, ChangeEvent, MigrationReport, SessionInfo};
+pub use pubky_common::version::{Causality, VersionVector};
+pub use queue::{
+    Conflict, ConflictReason, OfflineQueue, QueuedOp, QueuedWrite, ReplayReport, WriteStatus,
+};
+pub use retry::{CircuitBreakerConfig, Operation, RetryPolicy};
+#[cfg(not(target_arch = "wasm32"))]
+pub use sync::{SyncMode, SyncReport};
+pub use trace::WireTrace;
+pub use url::{IntoPubkyUrl, PubkyUrl};
+pub use versions::EntryVersion;
+pub use watch::{ChangeOp, ChangeStream};
@@ -0,0 +1,288 @@
+//! Paginated listing
+//!
+//! [`PubkyClient::list_stream`] yields the entries under a prefix page by
+//! page, following the homeserver's `next_cursor` until the last page.
+
+use futures_util::stream;
+use pubky_common::dto::ListResponse;
+use pubky_common::PublicKey;
+use std::collections::VecDeque;
+
+use crate::client::{check, IntoPublicKey, PubkyClient};
+use crate::error::Result;
+use crate::retry::Operation;
+use crate::watch::boxed;
+
+pub use pubky_common::dto::ListEntry;
+
+/// Stream of entries returned by [`PubkyClient::list_stream`]
+#[cfg(not(target_arch = "wasm32"))]
+pub type ListStream = stream::BoxStream<'static, Result<ListEntry>>;
+
+/// Stream of entries returned by [`PubkyClient::list_stream`]
+#[cfg(target_arch = "wasm32")]
+pub type ListStream = stream::LocalBoxStream<'static, Result<ListEntry>>;
+
+/// Options of [`PubkyClient::list_stream`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListOptions {
+    /// Number of entries fetched per request
+    pub page_size: usize,
+    /// List in descending path order instead of ascending
+    pub reverse: bool,
+    /// Collapse deeper paths into their first directory, such as
+    /// `my-app/images/`, listed once
+    pub shallow: bool,
+    /// Fill in the [`size`](ListEntry::size), [`hash`](ListEntry::hash),
+    /// [`tags`](ListEntry::tags) and other details of entries
+    pub details: bool,
+    /// Only list entries carrying every one of these tags, such as
+    /// `album:2024`
+    pub tags: Vec<String>,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self {
+            page_size: 100,
+            reverse: false,
+            shallow: false,
+            details: false,
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl PubkyClient {
+    /// Stream the entries under `prefix`, fetching them a page at a time
+    ///
+    /// A trailing `/` is added to the prefix if missing. The stream ends
+    /// after the first error.
+    pub fn list_stream(
+        &self,
+        owner: impl IntoPublicKey,
+        prefix: &str,
+        options: ListOptions,
+    ) -> ListStream {
+        let prefix = match prefix.ends_with('/') {
+            true => prefix.to_string(),
+            false => format!("{}/", prefix),
+        };
+        let public_key = match owner.into_public_key() {
+            Ok(public_key) => public_key,
+            Err(e) => return boxed(stream::once(async { Err(e) })),
+        };
+
+        let pages = Pages {
+            client: self.clone(),
+            public_key,
+            prefix,
+            url: None,
+            options,
+            cursor: None,
+            pending: VecDeque::new(),
+            done: false,
+        };
+        boxed(stream::unfold(pages, |mut pages| async move {
+            let item = pages.next().await?;
+            Some((item, pages))
+        }))
+    }
+}
+
+/// State of a listing across pages
+struct Pages {
+    client: PubkyClient,
+    public_key: PublicKey,
+    prefix: String,
+    /// URL of the prefix, once the homeserver is located
+    url: Option<String>,
+    options: ListOptions,
+    /// Cursor of the next page to fetch
+    cursor: Option<String>,
+    pending: VecDeque<ListEntry>,
+    done: bool,
+}
+
+impl Pages {
+    async fn next(&mut self) -> Option<Result<ListEntry>> {
+        loop {
+            if let Some(entry) = self.pending.pop_front() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.fetch().await {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+
+    /// Fetch the next page into the pending entries
+    async fn fetch(&mut self) -> Result<()> {
+        if self.url.is_none() {
+            self.url = Some(self.client.url(self.public_key, &self.prefix).await?);
+        }
+        let url = self.url.as_deref().expect("located above");
+        let options = &self.options;
+        let mut request = self
+            .client
+            .http
+            .get(url)
+            .query(&[("limit", options.page_size)]);
+        for (flag, set) in [
+            ("reverse", options.reverse),
+            ("shallow", options.shallow),
+            ("details", options.details),
+        ] {
+            if set {
+                request = request.query(&[(flag, true)]);
+            }
+        }
+        if !options.tags.is_empty() {
+            request = request.query(&[("tag", options.tags.join(","))]);
+        }
+        if let Some(cursor) = &self.cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+
+        let response = self.client.send(Operation::List, request).await?;
+        let page: ListResponse = check(response).await?.json().await?;
+        self.done = page.next_cursor.is_none();
+        self.cursor = page.next_cursor;
+        match page.entries {
+            Some(entries) => self.pending.extend(entries),
+            None => self
+                .pending
+                .extend(page.keys.into_iter().map(|path| ListEntry {
+                    path,
+                    ..Default::default()
+                })),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::TryStreamExt;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_list_stream() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .build()
+            .unwrap();
+        for path in [
+            "app/a.txt",
+            "app/b.txt",
+            "app/img/1.png",
+            "app/img/2.png",
+            "app/z.txt",
+        ] {
+            client.put(&public_key, path, "data").await.unwrap();
+        }
+        client.put(&public_key, "other.txt", "data").await.unwrap();
+
+        let paths = |entries: Vec<ListEntry>| -> Vec<String> {
+            entries.into_iter().map(|e| e.path).collect()
+        };
+        let options = ListOptions {
+            page_size: 2,
+            ..Default::default()
+        };
+        let entries: Vec<_> = client
+            .list_stream(public_key, "app", options.clone())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(entries),
+            [
+                "app/a.txt",
+                "app/b.txt",
+                "app/img/1.png",
+                "app/img/2.png",
+                "app/z.txt"
+            ]
+        );
+
+        let shallow = ListOptions {
+            reverse: true,
+            shallow: true,
+            details: true,
+            ..options.clone()
+        };
+        let entries: Vec<_> = client
+            .list_stream(public_key, "app/", shallow)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(entries[0].size, Some(4));
+        assert_eq!(
+            entries[0].hash.as_deref(),
+            Some("3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7")
+        );
+        assert!(entries[0].modified.is_some());
+        assert_eq!(entries[1].size, None);
+        assert_eq!(entries[1].hash, None);
+        assert_eq!(
+            paths(entries),
+            ["app/z.txt", "app/img/", "app/b.txt", "app/a.txt"]
+        );
+
+        // Entries can be tagged, and listed by tag
+        let tags = ["album:2024", "place:alps"];
+        client
+            .put_tagged(&public_key, "app/img/1.png", "data", &tags)
+            .await
+            .unwrap();
+        client
+            .put_tagged(&public_key, "app/img/2.png", "data", &tags[..1])
+            .await
+            .unwrap();
+        let tagged = ListOptions {
+            details: true,
+            tags: vec!["album:2024".to_string()],
+            ..options.clone()
+        };
+        let entries: Vec<_> = client
+            .list_stream(public_key, "app", tagged)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(entries[0].tags, tags);
+        assert_eq!(paths(entries), ["app/img/1.png", "app/img/2.png"]);
+        let tagged = ListOptions {
+            tags: tags.map(String::from).to_vec(),
+            ..options
+        };
+        let entries: Vec<_> = client
+            .list_stream(public_key, "app", tagged)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(paths(entries), ["app/img/1.png"]);
+
+        let err = client
+            .put_tagged(&public_key, "app/a.txt", "data", &["Not a tag"])
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), Some(reqwest::StatusCode::BAD_REQUEST));
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,108 @@
+//! Moving an account to another homeserver
+//!
+//! [`PubkyClient::migrate`] signs a migration intent and hands it to the
+//! new homeserver, which copies every entry from the client's homeserver
+//! and verifies it. The old homeserver then redirects requests for the
+//! account to the new one.
+
+use pubky_common::auth::MigrationIntent;
+use pubky_common::dto::MigrationReport;
+use pubky_common::Keypair;
+
+use crate::client::{check, PubkyClient};
+use crate::error::Result;
+use crate::retry::Operation;
+
+impl PubkyClient {
+    /// Move the account of `keypair` from the client's homeserver to the
+    /// one at `homeserver`
+    ///
+    /// Build a client for the new homeserver to keep using the account, as
+    /// writes signed for the old one are rejected there, and publish it with
+    /// [`publish_homeserver`](Self::publish_homeserver) if others find it
+    /// through pkarr.
+    pub async fn migrate(&self, keypair: &Keypair, homeserver: &str) -> Result<MigrationReport> {
+        let homeserver = homeserver.trim_end_matches('/');
+        let intent = MigrationIntent::sign(keypair, self.homeserver(), homeserver);
+        let request = self
+            .http
+            .post(format!("{}/migrations", homeserver))
+            .json(&intent);
+        let response = self.execute(Operation::Session, request, None).await?;
+        Ok(check(response).await?.json().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_migration() {
+        let old = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let new = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let client = PubkyClient::new(old.url());
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        client.signup(&keypair, None).await.unwrap();
+        client.put(public_key, "pub/a.txt", "a").await.unwrap();
+        client.put(public_key, "pub/b/c.txt", "c").await.unwrap();
+
+        let report = client.migrate(&keypair, &new.url()).await.unwrap();
+        assert_eq!(
+            report,
+            MigrationReport {
+                entries: 2,
+                bytes: 2
+            }
+        );
+        assert_eq!(
+            new.storage()
+                .get(&public_key, "pub/b/c.txt")
+                .unwrap()
+                .unwrap(),
+            b"c"
+        );
+        assert!(new.storage().is_registered(&public_key));
+
+        // The old homeserver sends everyone to the new one, but writes are
+        // signed for the homeserver they're sent to
+        let reader = PubkyClient::new(old.url());
+        assert!(client.put(public_key, "pub/d.txt", "d").await.is_err());
+        let moved = PubkyClient::builder()
+            .homeserver(new.url())
+            .keypair(keypair.clone())
+            .build()
+            .unwrap();
+        moved.put(public_key, "pub/d.txt", "d").await.unwrap();
+        assert_eq!(
+            reader.get(public_key, "pub/a.txt").await.unwrap().unwrap(),
+            "a"
+        );
+        assert!(new
+            .storage()
+            .get(&public_key, "pub/d.txt")
+            .unwrap()
+            .is_some());
+        assert!(old
+            .storage()
+            .get(&public_key, "pub/d.txt")
+            .unwrap()
+            .is_none());
+
+        // The old homeserver has nothing left to export
+        assert!(client.migrate(&keypair, &new.url()).await.is_err());
+
+        old.shutdown().await;
+        new.shutdown().await;
+    }
+}
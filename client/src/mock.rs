@@ -0,0 +1,97 @@
+//! In-process homeserver for tests
+//!
+//! With the `mock` feature, a client can send its requests straight to a
+//! homeserver router in the same process instead of over TCP, so apps can
+//! test their pubky integration without binding ports. Everything else,
+//! from sessions to retries, behaves as with a real homeserver.
+
+use axum::Router;
+use pubky_server::{Server, Storage};
+use reqwest::{Body, Request, Response};
+use std::sync::Arc;
+use tower::ServiceExt as _;
+
+use crate::client::{PubkyClient, PubkyClientBuilder};
+
+/// Base URL of in-process homeservers; only the path of requests is used
+const IN_PROCESS_URL: &str = "http://homeserver.invalid";
+
+impl PubkyClientBuilder {
+    /// Send requests to `router` in this process instead of over the network
+    ///
+    /// Build the router with [`ServerBuilder::router`](pubky_server::ServerBuilder::router).
+    pub fn in_process(mut self, router: Router) -> Self {
+        self.router = Some(router);
+        self.homeserver(IN_PROCESS_URL)
+    }
+}
+
+impl PubkyClient {
+    /// Create a client for a default homeserver running in this process on
+    /// `storage`
+    ///
+    /// Keep a clone of the storage to seed or inspect it in tests.
+    pub fn in_memory(storage: Arc<Storage>) -> Self {
+        let router = Server::builder().storage(storage).router();
+        PubkyClientBuilder::default()
+            .in_process(router)
+            .build()
+            .expect("default HTTP client configuration is valid")
+    }
+}
+
+/// Serve a request with the router
+pub(crate) async fn call(router: &Router, request: Request) -> reqwest::Result<Response> {
+    let request: axum::http::Request<Body> = request.try_into()?;
+    let response = router
+        .clone()
+        .oneshot(request.map(axum::body::Body::new))
+        .await
+        .unwrap_or_else(|infallible| match infallible {});
+    Ok(response
+        .map(|body| Body::wrap_stream(body.into_data_stream()))
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChangeOp;
+    use futures_util::StreamExt;
+    use pubky_common::Keypair;
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        let storage = Arc::new(Storage::new());
+        let client = PubkyClient::in_memory(storage.clone());
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+
+        client.signup(&keypair, None).await.unwrap();
+        let mut changes = client.watch_since(public_key, "app/", storage.head_seq());
+        client.put(&public_key, "app/a.txt", "a").await.unwrap();
+
+        assert_eq!(
+            storage.get(&public_key, "app/a.txt").unwrap().unwrap(),
+            b"a"
+        );
+        let data = client.get(&public_key, "app/a.txt").await.unwrap();
+        assert_eq!(data.unwrap(), "a");
+        assert_eq!(
+            client.list(&public_key, "app").await.unwrap(),
+            vec!["app/a.txt"]
+        );
+        assert!(client
+            .get(&public_key, "app/b.txt")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Streaming responses work too
+        let change = changes.next().await.unwrap().unwrap();
+        assert_eq!(
+            (change.op, change.path.as_str()),
+            (ChangeOp::Put, "app/a.txt")
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Homeservers authenticated by public key
+//!
+//! A homeserver can serve TLS with a self-signed certificate of its own
+//! Ed25519 key instead of one issued by a certificate authority. Clients
+//! that pin the key with
+//! [`PubkyClientBuilder::pin_homeserver_key`](crate::PubkyClientBuilder::pin_homeserver_key)
+//! accept such a certificate, whatever the host name, as long as the
+//! server proves in the handshake that it holds the key. Other servers are
+//! still checked against the usual web roots. Not available in browsers.
+
+use pubky_common::PublicKey;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::ParsedCertificate;
+use rustls::{DigitallySignedStruct, Error, RootCertStore, SignatureScheme};
+use std::sync::Arc;
+
+/// DER of an Ed25519 subject public key info up to the 32-byte key
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// TLS configuration accepting the certificates of `pinned` keys, and
+/// certificates issued under the web roots
+pub(crate) fn tls_config(pinned: Vec<PublicKey>) -> Result<rustls::ClientConfig, Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let web_pki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| Error::General(e.to_string()))?;
+    let verifier = PinningVerifier { pinned, web_pki };
+
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(tls)
+}
+
+/// Accepts the certificates of pinned keys, deferring to the web roots for
+/// others
+#[derive(Debug)]
+struct PinningVerifier {
+    pinned: Vec<PublicKey>,
+    web_pki: Arc<WebPkiServerVerifier>,
+}
+
+impl PinningVerifier {
+    /// Whether the certificate is for one of the pinned keys
+    fn is_pinned(&self, cert: &CertificateDer<'_>) -> bool {
+        let Ok(cert) = ParsedCertificate::try_from(cert) else {
+            return false;
+        };
+        let spki = cert.subject_public_key_info();
+        let Some(key) = spki.strip_prefix(&ED25519_SPKI_PREFIX[..]) else {
+            return false;
+        };
+        self.pinned.iter().any(|pinned| pinned.to_bytes() == key)
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        // The key is the server's identity, so neither its name nor an
+        // issuer matter; the handshake signature proves it is held
+        if self.is_pinned(end_entity) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.web_pki
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.web_pki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.web_pki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.web_pki.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PubkyClient;
+    use pubky_common::Keypair;
+    use pubky_server::{Server, TlsConfig, TlsIdentity};
+
+    #[tokio::test]
+    async fn test_pinned_homeserver_key() {
+        let identity = Keypair::random();
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .tls(TlsConfig::new(TlsIdentity::from_keypair(&identity).unwrap()))
+            .start()
+            .await
+            .unwrap();
+        let user = Keypair::random();
+        let client = |pinned: Option<&Keypair>| {
+            let mut builder = PubkyClient::builder()
+                .homeserver(server.url())
+                .keypair(user.clone())
+                .retry(crate::RetryPolicy::none());
+            if let Some(pinned) = pinned {
+                builder = builder.pin_homeserver_key(pinned.public_key());
+            }
+            builder.build().unwrap()
+        };
+
+        // Pinning the server's key trusts its self-signed certificate
+        let pinned = client(Some(&identity));
+        pinned
+            .put(user.public_key(), "pub/hello.txt", "Hello!")
+            .await
+            .unwrap();
+        let value = pinned.get(user.public_key(), "pub/hello.txt").await.unwrap();
+        assert_eq!(value.unwrap(), "Hello!");
+
+        // Without the pin, or pinning another key, it isn't trusted
+        assert!(client(None).get(user.public_key(), "pub/hello.txt").await.is_err());
+        let other = client(Some(&Keypair::random()));
+        assert!(other.get(user.public_key(), "pub/hello.txt").await.is_err());
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,567 @@
+//! Homeserver discovery over pkarr
+//!
+//! Users publish a `_pubky` record under their public key pointing at their
+//! homeserver's public key, and homeservers publish an `HTTPS` record
+//! pointing at their endpoint. With
+//! [`PubkyClientBuilder::pkarr_relays`](crate::PubkyClientBuilder::pkarr_relays),
+//! the client follows both records through pkarr relays to find where a
+//! user's entries live, caching the result for the records' TTL. With
+//! [`PubkyClientBuilder::dht_bootstrap`](crate::PubkyClientBuilder::dht_bootstrap),
+//! records are looked up on the Mainline DHT first, and the relays are only
+//! asked for those the DHT doesn't hold. Records are always published
+//! through the relays.
+//!
+//! When neither can be reached, the client keeps using the last homeserver
+//! it found for a user, and otherwise falls back to its own homeserver.
+
+use pubky_common::pkarr::{self, Record, RecordData, SignedPacket, PUBKY_RECORD};
+use pubky_common::{Keypair, PublicKey};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::client::{IntoPublicKey, PubkyClient};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::dht::Dht;
+use crate::error::{Error, Result};
+
+/// Shortest time a resolved homeserver is cached
+const MIN_TTL: Duration = Duration::from_secs(60);
+
+/// Longest time a resolved homeserver is cached
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long to wait before querying relays again after they failed
+const FAILURE_TTL: Duration = Duration::from_secs(10);
+
+/// Timeout of each request to a relay
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TTL of the `_pubky` records published by the client
+const PUBLISHED_TTL: u32 = 60 * 60;
+
+/// Resolves public keys to homeserver URLs through the DHT and pkarr relays
+#[derive(Debug, Default)]
+pub(crate) struct Resolver {
+    relays: Vec<String>,
+    #[cfg(not(target_arch = "wasm32"))]
+    dht: Dht,
+    cache: Mutex<HashMap<PublicKey, Resolved>>,
+}
+
+/// Cached outcome of a lookup
+#[derive(Debug, Clone)]
+struct Resolved {
+    /// Homeserver URL, or `None` if the key has no usable records
+    homeserver: Option<String>,
+    expires_at: Instant,
+}
+
+impl Resolver {
+    pub(crate) fn new(relays: Vec<String>) -> Self {
+        Self {
+            relays,
+            ..Default::default()
+        }
+    }
+
+    /// Look records up on the DHT through these bootstrap nodes before
+    /// asking the relays
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn with_dht(mut self, bootstrap: Vec<String>) -> Self {
+        self.dht = Dht::new(bootstrap);
+        self
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.dht.is_enabled() {
+            return true;
+        }
+        !self.relays.is_empty()
+    }
+
+    /// The cached homeserver of `public_key`, if still fresh
+    pub(crate) fn cached(&self, public_key: &PublicKey) -> Option<Option<String>> {
+        let cache = self.cache.lock().unwrap();
+        let resolved = cache.get(public_key)?;
+        (resolved.expires_at > Instant::now()).then(|| resolved.homeserver.clone())
+    }
+
+    /// Look up the homeserver of `public_key`, through the cache
+    ///
+    /// While the relays fail, the last homeserver found is used; without
+    /// one, the error is returned and the key is treated as having no
+    /// records until the relays are queried again.
+    pub(crate) async fn resolve(
+        &self,
+        http: &reqwest::Client,
+        public_key: &PublicKey,
+    ) -> Result<Option<String>> {
+        if let Some(homeserver) = self.cached(public_key) {
+            return Ok(homeserver);
+        }
+
+        let (homeserver, ttl) = match self.lookup(http, public_key).await {
+            Ok(Some((homeserver, ttl))) => (Some(homeserver), ttl.clamp(MIN_TTL, MAX_TTL)),
+            Ok(None) => (None, MIN_TTL),
+            Err(e) => {
+                let mut cache = self.cache.lock().unwrap();
+                let stale = cache.entry(*public_key).or_insert(Resolved {
+                    homeserver: None,
+                    expires_at: Instant::now(),
+                });
+                stale.expires_at = Instant::now() + FAILURE_TTL;
+                return match &stale.homeserver {
+                    Some(homeserver) => Ok(Some(homeserver.clone())),
+                    None => Err(e),
+                };
+            }
+        };
+        let resolved = Resolved {
+            homeserver: homeserver.clone(),
+            expires_at: Instant::now() + ttl,
+        };
+        self.cache.lock().unwrap().insert(*public_key, resolved);
+        Ok(homeserver)
+    }
+
+    /// Follow the records of `public_key` to a homeserver URL, returning it
+    /// with the shortest TTL of the records used
+    async fn lookup(
+        &self,
+        http: &reqwest::Client,
+        public_key: &PublicKey,
+    ) -> Result<Option<(String, Duration)>> {
+        let found =
+            pkarr::resolve_homeserver(
+                public_key,
+                |key| async move { self.fetch(http, &key).await },
+            )
+            .await?;
+        Ok(found.map(|(url, ttl)| (url, Duration::from_secs(ttl.into()))))
+    }
+
+    /// Fetch the latest packet of `public_key` from the DHT, or else from the
+    /// first relay that has it
+    async fn fetch(
+        &self,
+        http: &reqwest::Client,
+        public_key: &PublicKey,
+    ) -> Result<Option<SignedPacket>> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.dht.is_enabled() {
+            let found = self.dht.get(public_key).await;
+            // Relays are only asked for packets the DHT doesn't give
+            if matches!(found, Ok(Some(_))) || self.relays.is_empty() {
+                return found;
+            }
+        }
+
+        let mut error = None;
+        for relay in &self.relays {
+            let url = format!("{}/{}", relay.trim_end_matches('/'), public_key);
+            match http.get(&url).timeout(RELAY_TIMEOUT).send().await {
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => {}
+                Ok(response) if response.status().is_success() => {
+                    let payload = response.bytes().await?;
+                    return Ok(Some(SignedPacket::from_relay_payload(
+                        public_key, &payload,
+                    )?));
+                }
+                Ok(response) => {
+                    error = Some(Error::Pkarr(format!(
+                        "{} returned {}",
+                        relay,
+                        response.status()
+                    )))
+                }
+                Err(e) => error = Some(e.into()),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    /// Publish `packet` to every relay, failing if none accepted it
+    async fn publish(&self, http: &reqwest::Client, packet: &SignedPacket) -> Result<()> {
+        if self.relays.is_empty() {
+            return Err(Error::Pkarr("no pkarr relays configured".to_string()));
+        }
+
+        // Relays share packets through the DHT, so one is enough
+        let payload = packet.to_relay_payload();
+        let mut published = 0;
+        let mut error = None;
+        for relay in &self.relays {
+            let url = format!("{}/{}", relay.trim_end_matches('/'), packet.public_key());
+            let request = http.put(&url).timeout(RELAY_TIMEOUT);
+            match request.body(payload.clone()).send().await {
+                Ok(response) if response.status().is_success() => published += 1,
+                Ok(response) => {
+                    error = Some(Error::Pkarr(format!(
+                        "{} returned {}",
+                        relay,
+                        response.status()
+                    )))
+                }
+                Err(e) => error = Some(e.into()),
+            }
+        }
+        if published == 0 {
+            return Err(error.expect("a relay was tried"));
+        }
+
+        self.cache.lock().unwrap().remove(packet.public_key());
+        Ok(())
+    }
+}
+
+impl PubkyClient {
+    /// Look up the homeserver of `owner` through pkarr, bypassing the
+    /// homeservers mapped with
+    /// [`PubkyClientBuilder::resolve`](crate::PubkyClientBuilder::resolve)
+    ///
+    /// Returns `None` if no relays are configured or the owner has no
+    /// usable records.
+    pub async fn resolve_homeserver(&self, owner: impl IntoPublicKey) -> Result<Option<String>> {
+        let public_key = owner.into_public_key()?;
+        if !self.pkarr.is_enabled() {
+            return Ok(None);
+        }
+        self.pkarr.resolve(&self.http, &public_key).await
+    }
+
+    /// Publish a `_pubky` record announcing that the entries of `keypair`
+    /// live on the homeserver with public key `homeserver`
+    pub async fn publish_homeserver(&self, keypair: &Keypair, homeserver: PublicKey) -> Result<()> {
+        let record = Record::new(
+            PUBKY_RECORD,
+            PUBLISHED_TTL,
+            RecordData::Svcb {
+                priority: 0,
+                target: homeserver.to_z32(),
+                port: None,
+            },
+        );
+        let packet = SignedPacket::sign(keypair, vec![record])?;
+        self.pkarr.publish(&self.http, &packet).await
+    }
+
+    /// Publish the `_pubky` record of `keypair` again, pointing at the same
+    /// homeserver, once half of its TTL has passed
+    ///
+    /// Relays and the DHT drop packets that aren't published again, so call
+    /// this periodically. Returns whether the record was published; keys
+    /// without a `_pubky` record are left alone.
+    pub async fn refresh_homeserver(&self, keypair: &Keypair) -> Result<bool> {
+        let public_key = keypair.public_key();
+        let Some(packet) = self.pkarr.fetch(&self.http, &public_key).await? else {
+            return Ok(false);
+        };
+        let Some(record) = packet.records_named(PUBKY_RECORD).next() else {
+            return Ok(false);
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        let age = Duration::from_micros(now.saturating_sub(packet.timestamp()));
+        if age < Duration::from_secs(record.ttl.into()) / 2 {
+            return Ok(false);
+        }
+
+        let packet = SignedPacket::sign(keypair, packet.records().to_vec())?;
+        self.pkarr.publish(&self.http, &packet).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_server::{PkarrConfig, Server};
+
+    #[tokio::test]
+    async fn test_pkarr_discovery() {
+        let relay = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr_relay(true)
+            .start()
+            .await
+            .unwrap();
+        let relays = vec![format!("{}/pkarr", relay.url())];
+        let mut config = PkarrConfig::new(Keypair::random(), "127.0.0.1");
+        config.relays = relays.clone();
+        let home = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr(config)
+            .start()
+            .await
+            .unwrap();
+        let fallback = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(fallback.url())
+            .keypair(keypair.clone())
+            .pkarr_relays(relays)
+            .build()
+            .unwrap();
+
+        assert_eq!(client.resolve_homeserver(public_key).await.unwrap(), None);
+        client
+            .publish_homeserver(&keypair, home.public_key().unwrap())
+            .await
+            .unwrap();
+
+        // Wait for the homeserver's own record to be published
+        let mut resolved = None;
+        for _ in 0..50 {
+            resolved = client.resolve_homeserver(public_key).await.unwrap();
+            if resolved.is_some() {
+                break;
+            }
+            client.pkarr.cache.lock().unwrap().clear();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(resolved.as_deref(), Some(home.url().as_str()));
+
+        // Requests for the user go to the resolved homeserver
+        client.put(public_key, "a.txt", "found").await.unwrap();
+        assert!(home.storage().get(&public_key, "a.txt").unwrap().is_some());
+        assert!(fallback
+            .storage()
+            .get(&public_key, "a.txt")
+            .unwrap()
+            .is_none());
+
+        // Resolved homeservers are cached
+        relay.shutdown().await;
+        assert_eq!(client.homeserver_of(&public_key), home.url());
+        assert_eq!(
+            client.get(public_key, "a.txt").await.unwrap().unwrap(),
+            "found"
+        );
+
+        home.shutdown().await;
+        fallback.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_zero_config_resolution() {
+        let relay = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr_relay(true)
+            .start()
+            .await
+            .unwrap();
+        let relays = vec![format!("{}/pkarr", relay.url())];
+        let mut config = PkarrConfig::new(Keypair::random(), "127.0.0.1");
+        config.relays = relays.clone();
+        let home = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr(config)
+            .start()
+            .await
+            .unwrap();
+        let homeserver = home.public_key().unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        home.storage()
+            .put(
+                public_key,
+                "pub/site/index.html".to_string(),
+                b"<h1>Hi</h1>".to_vec(),
+            )
+            .unwrap();
+
+        let client = PubkyClient::builder()
+            .pkarr_relays(relays)
+            .dht_bootstrap(Vec::<String>::new())
+            .build()
+            .unwrap();
+        client
+            .publish_homeserver(&keypair, homeserver)
+            .await
+            .unwrap();
+        let record = format!("{}/pkarr/{}", relay.url(), homeserver);
+        while !reqwest::get(&record).await.unwrap().status().is_success() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // No homeserver configured: the owner's is found through pkarr
+        let url = format!("pubky://{}/pub/site/index.html", public_key);
+        assert_eq!(client.get_url(&url).await.unwrap().unwrap(), "<h1>Hi</h1>");
+
+        // While the relays are down, the last homeserver found is used
+        relay.shutdown().await;
+        for resolved in client.pkarr.cache.lock().unwrap().values_mut() {
+            resolved.expires_at = Instant::now();
+        }
+        assert_eq!(client.get_url(&url).await.unwrap().unwrap(), "<h1>Hi</h1>");
+
+        // Users never found fall back to the client's own homeserver
+        let stranger = Keypair::random().public_key();
+        assert!(client.resolve_homeserver(stranger).await.is_err());
+        assert_eq!(client.locate(&stranger).await, client.homeserver());
+
+        // Clients set up for a homeserver only query the relays they're given
+        assert!(PubkyClient::builder().build().unwrap().pkarr.is_enabled());
+        assert!(!PubkyClient::new(home.url()).pkarr.is_enabled());
+
+        home.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_dht_resolution() {
+        use pubky_common::krpc::{dict, mutable_target, Answer, Bencode};
+        use tokio::net::UdpSocket;
+
+        let home = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let relay = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr_relay(true)
+            .start()
+            .await
+            .unwrap();
+        let record = Record::new(
+            PUBKY_RECORD,
+            PUBLISHED_TTL,
+            RecordData::Svcb {
+                priority: 0,
+                target: "127.0.0.1".to_string(),
+                port: Some(home.local_addr().port()),
+            },
+        );
+        let on_dht = Keypair::random();
+        let packet = SignedPacket::sign(&on_dht, vec![record.clone()]).unwrap();
+
+        // A single DHT node, holding the packet of `on_dht` as a BEP 44 item
+        let dht = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let bootstrap = vec![dht.local_addr().unwrap().to_string()];
+        let target = mutable_target(&on_dht.public_key());
+        tokio::spawn(async move {
+            let payload = packet.to_relay_payload();
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, from) = dht.recv_from(&mut buf).await.unwrap();
+                let Some(Bencode::Dict(query)) = Bencode::decode(&buf[..len]) else {
+                    continue;
+                };
+                let Some(Bencode::Dict(args)) = query.get(b"a".as_slice()) else {
+                    continue;
+                };
+                let mut answer = dict([("id", Bencode::Bytes([7; 20].to_vec()))]);
+                if Answer(args.clone()).bytes("target") == Some(target.as_slice()) {
+                    answer.extend(dict([
+                        ("sig", Bencode::Bytes(payload[..64].to_vec())),
+                        ("seq", Bencode::Int(packet.timestamp() as i64)),
+                        ("v", Bencode::Bytes(payload[72..].to_vec())),
+                    ]));
+                }
+                let response = Bencode::Dict(dict([
+                    ("t", query[b"t".as_slice()].clone()),
+                    ("y", Bencode::Bytes(b"r".to_vec())),
+                    ("r", Bencode::Dict(answer)),
+                ]));
+                dht.send_to(&response.encode(), from).await.unwrap();
+            }
+        });
+
+        // Records on the DHT are found without asking the relays
+        let client = PubkyClient::builder()
+            .homeserver(relay.url())
+            .pkarr_relays(Vec::<String>::new())
+            .dht_bootstrap(bootstrap.clone())
+            .build()
+            .unwrap();
+        let resolved = client.resolve_homeserver(on_dht.public_key()).await;
+        assert_eq!(resolved.unwrap().as_deref(), Some(home.url().as_str()));
+
+        // Records missing from the DHT are fetched from the relays
+        let on_relay = Keypair::random();
+        let relays = vec![format!("{}/pkarr", relay.url())];
+        let client = PubkyClient::builder()
+            .homeserver(relay.url())
+            .pkarr_relays(relays)
+            .dht_bootstrap(bootstrap)
+            .build()
+            .unwrap();
+        let packet = SignedPacket::sign(&on_relay, vec![record]).unwrap();
+        client.pkarr.publish(&client.http, &packet).await.unwrap();
+        let resolved = client.resolve_homeserver(on_relay.public_key()).await;
+        assert_eq!(resolved.unwrap().as_deref(), Some(home.url().as_str()));
+        let resolved = client.resolve_homeserver(on_dht.public_key()).await;
+        assert_eq!(resolved.unwrap().as_deref(), Some(home.url().as_str()));
+        let stranger = Keypair::random().public_key();
+        assert_eq!(client.resolve_homeserver(stranger).await.unwrap(), None);
+
+        home.shutdown().await;
+        relay.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_signup_publishes_homeserver() {
+        let relay = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr_relay(true)
+            .start()
+            .await
+            .unwrap();
+        let relays = vec![format!("{}/pkarr", relay.url())];
+        let mut config = PkarrConfig::new(Keypair::random(), "127.0.0.1");
+        config.relays = relays.clone();
+        let home = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr(config)
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let client = PubkyClient::builder()
+            .homeserver(home.url())
+            .pkarr_relays(relays.clone())
+            .build()
+            .unwrap();
+
+        // Signing up points the user's record at the homeserver
+        client.signup(&keypair, None).await.unwrap();
+        let public_key = keypair.public_key();
+        let packet = client.pkarr.fetch(&client.http, &public_key).await;
+        let packet = packet.unwrap().unwrap();
+        let record = packet.records_named(PUBKY_RECORD).next().unwrap();
+        let homeserver = home.public_key().unwrap().to_z32();
+        assert!(matches!(&record.data, RecordData::Svcb { target, .. } if *target == homeserver));
+
+        // Fresh records are left alone, old ones are published again
+        assert!(!client.refresh_homeserver(&keypair).await.unwrap());
+        let stale = Keypair::random();
+        let old = SignedPacket::sign_at(&stale, packet.records().to_vec(), 1).unwrap();
+        client.pkarr.publish(&client.http, &old).await.unwrap();
+        assert!(client.refresh_homeserver(&stale).await.unwrap());
+        let packet = client.pkarr.fetch(&client.http, old.public_key()).await;
+        let packet = packet.unwrap().unwrap();
+        assert!(packet.timestamp() > 1);
+        assert_eq!(packet.records(), old.records());
+
+        // Without a record there is nothing to refresh
+        let stranger = Keypair::random();
+        assert!(!client.refresh_homeserver(&stranger).await.unwrap());
+
+        home.shutdown().await;
+        relay.shutdown().await;
+    }
+}
@@ -0,0 +1,175 @@
+//! Transfer progress
+//!
+//! [`PubkyClient::put_with_progress`] and [`PubkyClient::get_with_progress`]
+//! report the bytes transferred so far as the body is sent or received, to
+//! drive progress bars for large entries.
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use reqwest::Response;
+use std::sync::Arc;
+
+use crate::client::{IntoPublicKey, PubkyClient};
+use crate::error::Result;
+
+/// Size of the chunks uploads are reported in
+#[cfg(not(target_arch = "wasm32"))]
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes transferred so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub transferred: u64,
+    /// Total size, if known
+    pub total: Option<u64>,
+}
+
+/// Progress callback shared across retries
+pub(crate) type OnProgress = Arc<dyn Fn(Progress) + Send + Sync>;
+
+impl PubkyClient {
+    /// Store `body` at `path` like [`put`](Self::put), reporting upload
+    /// progress
+    ///
+    /// A retried upload starts over from zero. In browsers, progress is only
+    /// reported once the upload completes.
+    pub async fn put_with_progress(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        body: impl Into<Bytes>,
+        on_progress: impl Fn(Progress) + Send + Sync + 'static,
+    ) -> Result<()> {
+        let on_progress: OnProgress = Arc::new(on_progress);
+        self.put_inner(owner, path, body.into(), None, Some(on_progress))
+            .await
+            .map(drop)
+    }
+
+    /// Retrieve the data at `path` like [`get`](Self::get), reporting
+    /// download progress
+    ///
+    /// Entries served from the cache report no progress.
+    pub async fn get_with_progress(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        on_progress: impl Fn(Progress) + Send + Sync + 'static,
+    ) -> Result<Option<Bytes>> {
+        let on_progress: OnProgress = Arc::new(on_progress);
+        let entry = self.get_inner(owner, path, Some(on_progress)).await?;
+        Ok(entry.map(|(body, _)| body))
+    }
+}
+
+/// A request body sent in chunks, reporting each one
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn upload_body(data: Bytes, on_progress: OnProgress) -> reqwest::Body {
+    let total = data.len() as u64;
+    on_progress(Progress {
+        transferred: 0,
+        total: Some(total),
+    });
+
+    let chunks: Vec<Bytes> = (0..data.len())
+        .step_by(CHUNK_SIZE)
+        .map(|start| data.slice(start..data.len().min(start + CHUNK_SIZE)))
+        .collect();
+    let mut transferred = 0;
+    let stream = futures_util::stream::iter(chunks).map(move |chunk| {
+        transferred += chunk.len() as u64;
+        on_progress(Progress {
+            transferred,
+            total: Some(total),
+        });
+        Ok::<_, std::convert::Infallible>(chunk)
+    });
+    reqwest::Body::wrap_stream(stream)
+}
+
+/// Read a response body, reporting each chunk received
+pub(crate) async fn read_body(response: Response, on_progress: &OnProgress) -> Result<Bytes> {
+    // Traced responses only carry the length in the header
+    let total = response.content_length().or_else(|| {
+        let length = response.headers().get(reqwest::header::CONTENT_LENGTH)?;
+        length.to_str().ok()?.parse().ok()
+    });
+    let mut body = Vec::with_capacity(total.unwrap_or(0) as usize);
+    on_progress(Progress {
+        transferred: 0,
+        total,
+    });
+
+    let mut chunks = response.bytes_stream();
+    while let Some(chunk) = chunks.next().await {
+        body.extend_from_slice(&chunk?);
+        on_progress(Progress {
+            transferred: body.len() as u64,
+            total,
+        });
+    }
+    Ok(body.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_progress() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .build()
+            .unwrap();
+        let data = vec![7u8; 3 * CHUNK_SIZE + 10];
+        let total = Some(data.len() as u64);
+
+        let uploads = Arc::new(Mutex::new(Vec::new()));
+        let reports = uploads.clone();
+        client
+            .put_with_progress(&public_key, "big.bin", data.clone(), move |p| {
+                reports.lock().unwrap().push(p)
+            })
+            .await
+            .unwrap();
+        let uploads = uploads.lock().unwrap().clone();
+        assert_eq!(uploads.len(), 5);
+        assert_eq!(uploads[0].transferred, 0);
+        assert!(uploads.iter().all(|p| p.total == total));
+        assert_eq!(uploads.last().unwrap().transferred, data.len() as u64);
+
+        let downloads = Arc::new(Mutex::new(Vec::new()));
+        let reports = downloads.clone();
+        let body = client
+            .get_with_progress(&public_key, "big.bin", move |p| {
+                reports.lock().unwrap().push(p)
+            })
+            .await
+            .unwrap();
+        assert_eq!(body.unwrap(), data);
+        let downloads = downloads.lock().unwrap().clone();
+        assert!(downloads
+            .windows(2)
+            .all(|w| w[0].transferred <= w[1].transferred));
+        assert_eq!(
+            *downloads.last().unwrap(),
+            Progress {
+                transferred: data.len() as u64,
+                total
+            }
+        );
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,593 @@
+//! Offline write queue
+//!
+//! Writes made with [`PubkyClient::put_or_queue`] and
+//! [`PubkyClient::delete_or_queue`] while the homeserver is unreachable are
+//! queued, optionally persisted to a file, and replayed in order once it is
+//! reachable again.
+//!
+//! Each queued write remembers the version of the entry the client last
+//! saw (its `ETag`, or its absence), and is replayed as a conditional write
+//! expecting it. A write whose entry was changed by someone else in the
+//! meantime is not applied but reported as a [`Conflict`], so the app can
+//! merge or overwrite.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use bytes::Bytes;
+use reqwest::Response;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+
+use crate::client::{entry_url, etag_of, IntoPublicKey, PubkyClient};
+use crate::conditional::Precondition;
+use crate::error::{Error, Result};
+use crate::retry;
+
+/// A write waiting to be sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedWrite {
+    /// z-base-32 public key of the owner
+    pub owner: String,
+    pub path: String,
+    pub op: QueuedOp,
+    /// Unix timestamp in milliseconds when the write was queued
+    pub queued_at: u64,
+    /// Version of the entry the write was based on, if known
+    base: Option<Version>,
+}
+
+/// The operation of a [`QueuedWrite`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum QueuedOp {
+    Put {
+        #[serde(with = "base64_bytes")]
+        body: Bytes,
+    },
+    Delete,
+}
+
+/// A queued write that wasn't applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflict {
+    pub write: QueuedWrite,
+    pub reason: ConflictReason,
+}
+
+/// Why a queued write wasn't applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "lowercase")]
+pub enum ConflictReason {
+    /// The entry changed on the homeserver since the write was queued;
+    /// `remote` is its current content, `None` if it was deleted
+    Changed {
+        #[serde(with = "base64_option")]
+        remote: Option<Bytes>,
+    },
+    /// The homeserver refused the write
+    Rejected { status: u16, message: String },
+}
+
+/// Whether a write was sent or queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStatus {
+    Sent,
+    Queued,
+}
+
+/// Outcome of [`PubkyClient::replay_queue`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Number of writes applied
+    pub applied: usize,
+    /// Number of writes still queued because the homeserver is unreachable
+    pub pending: usize,
+    /// Writes that weren't applied, including those found by replays
+    /// triggered by earlier writes
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Version of an entry: the SHA-256 of its stored value, as in its `ETag`,
+/// or its absence
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Version {
+    Absent,
+    Sha256(String),
+}
+
+impl Version {
+    /// The version of an entry stored as `body`, before the homeserver
+    /// tags it
+    ///
+    /// Only a guess for encrypted entries, corrected once written.
+    fn of(body: &[u8]) -> Self {
+        Version::Sha256(
+            Sha256::digest(body)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        )
+    }
+
+    /// The version named by a strong `ETag`
+    pub(crate) fn of_etag(etag: &str) -> Option<Self> {
+        let hash = etag.strip_prefix('"')?.strip_suffix('"')?;
+        Some(Version::Sha256(hash.to_string()))
+    }
+
+    /// The version of the entry a response was about, if it has an `ETag`
+    pub(crate) fn of_response(response: &Response) -> Option<Self> {
+        Self::of_etag(&etag_of(response)?)
+    }
+
+    /// The precondition of a write based on this version
+    fn precondition(&self) -> Precondition {
+        match self {
+            Version::Absent => Precondition::Absent,
+            Version::Sha256(hash) => Precondition::Matches(format!("\"{}\"", hash)),
+        }
+    }
+}
+
+/// What became of a queued write sent to the homeserver
+enum Outcome {
+    /// Applied, leaving the entry in this version, if known
+    Applied(Option<Version>),
+    /// Not applied
+    Conflict(ConflictReason),
+}
+
+/// Queued writes and unresolved conflicts, in memory or persisted to a file
+///
+/// Enable it with
+/// [`PubkyClientBuilder::offline_queue`](crate::PubkyClientBuilder::offline_queue).
+#[derive(Debug)]
+pub struct OfflineQueue {
+    #[cfg(not(target_arch = "wasm32"))]
+    file: Option<PathBuf>,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    writes: VecDeque<QueuedWrite>,
+    conflicts: Vec<Conflict>,
+    /// Last seen version of each entry by URL; not persisted
+    #[serde(skip)]
+    versions: HashMap<String, Version>,
+}
+
+impl OfflineQueue {
+    /// Keep queued writes in memory only
+    pub fn memory() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            file: None,
+            state: Mutex::new(QueueState::default()),
+        }
+    }
+
+    /// Persist queued writes to a JSON file, loading any already there
+    ///
+    /// Not available in browsers.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn file(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let state = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => QueueState::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            file: Some(path),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Writes waiting to be sent, oldest first
+    pub fn writes(&self) -> Vec<QueuedWrite> {
+        self.state.lock().unwrap().writes.iter().cloned().collect()
+    }
+
+    /// Record the version of an entry the client just read or wrote,
+    /// forgetting it if unknown
+    pub(crate) fn observe(&self, url: &str, version: Option<Version>) {
+        let mut state = self.state.lock().unwrap();
+        match version {
+            Some(version) => state.versions.insert(url.to_string(), version),
+            None => state.versions.remove(url),
+        };
+    }
+
+    fn push(&self, url: &str, owner: String, path: String, op: QueuedOp) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let base = state.versions.get(url).cloned();
+        // Later writes to the same entry build on this one
+        let next = match &op {
+            QueuedOp::Put { body } => Version::of(body),
+            QueuedOp::Delete => Version::Absent,
+        };
+        state.versions.insert(url.to_string(), next);
+        state.writes.push_back(QueuedWrite {
+            owner,
+            path,
+            op,
+            queued_at: now_millis(),
+            base,
+        });
+        self.save(&state)
+    }
+
+    fn front(&self) -> Option<QueuedWrite> {
+        self.state.lock().unwrap().writes.front().cloned()
+    }
+
+    /// Remove the oldest write, recording a conflict if it wasn't applied
+    fn pop(&self, outcome: Outcome) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(write) = state.writes.pop_front() {
+            match outcome {
+                Outcome::Applied(version) => {
+                    // The next write to the entry was based on this one
+                    let next = state
+                        .writes
+                        .iter_mut()
+                        .find(|next| next.owner == write.owner && next.path == write.path);
+                    if let (Some(next), Some(version)) = (next, version) {
+                        next.base = Some(version);
+                    }
+                }
+                Outcome::Conflict(reason) => state.conflicts.push(Conflict { write, reason }),
+            }
+        }
+        self.save(&state)
+    }
+
+    fn take_conflicts(&self) -> Result<Vec<Conflict>> {
+        let mut state = self.state.lock().unwrap();
+        let conflicts = std::mem::take(&mut state.conflicts);
+        self.save(&state)?;
+        Ok(conflicts)
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().writes.len()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save(&self, state: &QueueState) -> Result<()> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec(state).expect("queue serializes");
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn save(&self, _state: &QueueState) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl PubkyClient {
+    /// Store `body` at `path`, or queue the write if the homeserver is
+    /// unreachable
+    ///
+    /// Earlier queued writes are replayed first, so writes are applied in
+    /// order. Without an [`OfflineQueue`] this is [`put`](Self::put).
+    pub async fn put_or_queue(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        body: impl Into<Bytes>,
+    ) -> Result<WriteStatus> {
+        let public_key = owner.into_public_key()?;
+        let body = body.into();
+        let Some(queue) = self.queue.clone() else {
+            self.put(&public_key, path, body).await?;
+            return Ok(WriteStatus::Sent);
+        };
+
+        let op = QueuedOp::Put { body: body.clone() };
+        if self.flush_queue(&queue).await?.1 > 0 {
+            return self.enqueue(&queue, &public_key, path, op);
+        }
+        match self.put(&public_key, path, body).await {
+            Err(e) if is_offline(&e) => self.enqueue(&queue, &public_key, path, op),
+            result => result.map(|_| WriteStatus::Sent),
+        }
+    }
+
+    /// Delete the data at `path`, or queue the deletion if the homeserver
+    /// is unreachable
+    ///
+    /// Deleting an entry that doesn't exist is not an error.
+    pub async fn delete_or_queue(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+    ) -> Result<WriteStatus> {
+        let public_key = owner.into_public_key()?;
+        let Some(queue) = self.queue.clone() else {
+            self.delete(&public_key, path).await?;
+            return Ok(WriteStatus::Sent);
+        };
+
+        if self.flush_queue(&queue).await?.1 > 0 {
+            return self.enqueue(&queue, &public_key, path, QueuedOp::Delete);
+        }
+        match self.delete(&public_key, path).await {
+            Err(e) if is_offline(&e) => self.enqueue(&queue, &public_key, path, QueuedOp::Delete),
+            result => result.map(|_| WriteStatus::Sent),
+        }
+    }
+
+    /// Send queued writes, for example when the network comes back
+    ///
+    /// Stops at the first write that can't reach the homeserver. Returns
+    /// and clears the conflicts found so far.
+    pub async fn replay_queue(&self) -> Result<ReplayReport> {
+        let Some(queue) = self.queue.clone() else {
+            return Ok(ReplayReport::default());
+        };
+
+        let (applied, pending) = self.flush_queue(&queue).await?;
+        Ok(ReplayReport {
+            applied,
+            pending,
+            conflicts: queue.take_conflicts()?,
+        })
+    }
+
+    /// Writes waiting in the offline queue, oldest first
+    pub fn queued_writes(&self) -> Vec<QueuedWrite> {
+        self.queue.as_ref().map(|q| q.writes()).unwrap_or_default()
+    }
+
+    fn enqueue(
+        &self,
+        queue: &OfflineQueue,
+        public_key: &pubky_common::PublicKey,
+        path: &str,
+        op: QueuedOp,
+    ) -> Result<WriteStatus> {
+        // Offline, so only homeservers already known are used
+        let url = entry_url(&self.homeserver_of(public_key), public_key, path);
+        queue.push(&url, public_key.to_z32(), path.to_string(), op)?;
+        Ok(WriteStatus::Queued)
+    }
+
+    /// Apply queued writes in order, returning how many were applied and
+    /// how many are left
+    async fn flush_queue(&self, queue: &OfflineQueue) -> Result<(usize, usize)> {
+        let mut applied = 0;
+        while let Some(write) = queue.front() {
+            match self.apply(&write).await {
+                Ok(outcome) => {
+                    applied += usize::from(matches!(outcome, Outcome::Applied(_)));
+                    queue.pop(outcome)?;
+                }
+                Err(e) if is_offline(&e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((applied, queue.len()))
+    }
+
+    /// Apply one queued write as a conditional write, unless its entry
+    /// changed in the meantime
+    ///
+    /// Writes based on an entry never seen only create it.
+    async fn apply(&self, write: &QueuedWrite) -> Result<Outcome> {
+        let path = write.path.as_str();
+        let precondition = match &write.base {
+            Some(base) => base.precondition(),
+            None => Precondition::Absent,
+        };
+        let result = match &write.op {
+            QueuedOp::Put { body } => self
+                .put_if(&write.owner, path, body.clone(), &precondition)
+                .await
+                .map(|etag| etag.as_deref().and_then(Version::of_etag)),
+            QueuedOp::Delete => self
+                .delete_if(&write.owner, path, &precondition)
+                .await
+                .map(|_| Some(Version::Absent)),
+        };
+        match result {
+            Ok(version) => Ok(Outcome::Applied(version)),
+            Err(Error::Conflict { .. }) => {
+                // The write may have landed before its response was lost
+                let remote = self.get_with_etag(&write.owner, path).await?;
+                let target = match &write.op {
+                    QueuedOp::Put { body } => Some(body),
+                    QueuedOp::Delete => None,
+                };
+                if remote.as_ref().map(|(body, _)| body) == target {
+                    let version = match remote {
+                        Some((_, etag)) => etag.as_deref().and_then(Version::of_etag),
+                        None => Some(Version::Absent),
+                    };
+                    return Ok(Outcome::Applied(version));
+                }
+                let remote = remote.map(|(body, _)| body);
+                Ok(Outcome::Conflict(ConflictReason::Changed { remote }))
+            }
+            Err(e) => match e.status() {
+                Some(status) if !retry::is_retryable_status(status) => {
+                    Ok(Outcome::Conflict(ConflictReason::Rejected {
+                        status: status.as_u16(),
+                        message: e.to_string(),
+                    }))
+                }
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+/// Whether an error means the homeserver can't be reached right now
+fn is_offline(error: &Error) -> bool {
+    match error {
+        Error::Network(e) => retry::is_retryable_error(e),
+        Error::Status { status, .. } => retry::is_retryable_status(*status),
+        Error::CircuitOpen => true,
+        _ => false,
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before Unix epoch")
+        .as_millis() as u64
+}
+
+pub(crate) mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Bytes,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Bytes, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64
+            .decode(encoded)
+            .map(Bytes::from)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+mod base64_option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Option<Bytes>,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        bytes
+            .as_ref()
+            .map(|b| BASE64.encode(b))
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Option<Bytes>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|encoded| BASE64.decode(encoded).map(Bytes::from))
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RetryPolicy;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_offline_queue() {
+        // Reserve a port, and leave it closed until the server comes online
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let file = std::env::temp_dir().join(format!("pubky-queue-{}.json", public_key));
+        let client = PubkyClient::builder()
+            .homeserver(format!("http://{}", addr))
+            .keypair(keypair.clone())
+            .retry(RetryPolicy::none())
+            .offline_queue(OfflineQueue::file(&file).unwrap())
+            .build()
+            .unwrap();
+
+        let status = client
+            .put_or_queue(&public_key, "notes/a.txt", "draft")
+            .await
+            .unwrap();
+        assert_eq!(status, WriteStatus::Queued);
+        let status = client
+            .delete_or_queue(&public_key, "notes/b.txt")
+            .await
+            .unwrap();
+        assert_eq!(status, WriteStatus::Queued);
+
+        // Queued writes survive a restart
+        let reloaded = OfflineQueue::file(&file).unwrap();
+        assert_eq!(reloaded.writes(), client.queued_writes());
+        assert_eq!(reloaded.writes()[1].op, QueuedOp::Delete);
+
+        let server = Server::builder().bind(addr).start().await.unwrap();
+        let report = client.replay_queue().await.unwrap();
+        assert_eq!((report.applied, report.pending), (2, 0));
+        assert!(report.conflicts.is_empty());
+        let data = client.get(&public_key, "notes/a.txt").await.unwrap();
+        assert_eq!(data.unwrap(), "draft");
+
+        // Successive writes to an entry are each based on the previous one
+        let queue = client.queue.clone().unwrap();
+        for body in ["v2", "v3"] {
+            let op = QueuedOp::Put {
+                body: Bytes::from(body),
+            };
+            client
+                .enqueue(&queue, &public_key, "notes/a.txt", op)
+                .unwrap();
+        }
+        let report = client.replay_queue().await.unwrap();
+        assert_eq!((report.applied, report.pending), (2, 0));
+        assert!(report.conflicts.is_empty());
+
+        // A write based on content someone else changed is a conflict
+        let op = QueuedOp::Put {
+            body: Bytes::from("mine"),
+        };
+        client
+            .enqueue(&queue, &public_key, "notes/a.txt", op)
+            .unwrap();
+        let other = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .build()
+            .unwrap();
+        other
+            .put(&public_key, "notes/a.txt", "theirs")
+            .await
+            .unwrap();
+
+        let report = client.replay_queue().await.unwrap();
+        assert_eq!(report.applied, 0);
+        assert_eq!(
+            report.conflicts[0].reason,
+            ConflictReason::Changed {
+                remote: Some(Bytes::from("theirs"))
+            }
+        );
+        let data = client.get(&public_key, "notes/a.txt").await.unwrap();
+        assert_eq!(data.unwrap(), "theirs");
+        assert!(OfflineQueue::file(&file).unwrap().writes().is_empty());
+
+        std::fs::remove_file(&file).unwrap();
+        server.shutdown().await;
+    }
+}
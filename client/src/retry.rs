@@ -0,0 +1,264 @@
+//! Timeouts, retries, and circuit breaking
+//!
+//! Idempotent requests (everything except signup and signin) that fail
+//! with a transport error or a `429`, `502`, `503` or `504` response are
+//! retried with exponential backoff and full jitter. An optional circuit
+//! breaker stops sending requests to a homeserver after repeated failures,
+//! failing fast with [`Error::CircuitOpen`](crate::Error::CircuitOpen) until
+//! its reset timeout has passed.
+
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Method, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use web_time::Instant;
+
+/// Kind of operation, used to pick its timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Get,
+    Put,
+    Delete,
+    List,
+    /// Signup, signin, signout, and session refresh
+    Session,
+    /// Change feed connections
+    ///
+    /// Only a timeout set for this operation applies, since the response
+    /// never ends; it limits how long a connection stays open before the
+    /// watch reconnects.
+    Watch,
+}
+
+/// Request timeouts, overridable per operation
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Timeouts {
+    pub default: Option<Duration>,
+    pub operations: HashMap<Operation, Duration>,
+}
+
+impl Timeouts {
+    pub fn get(&self, operation: Operation) -> Option<Duration> {
+        let timeout = self.operations.get(&operation).copied();
+        match operation {
+            Operation::Watch => timeout,
+            _ => timeout.or(self.default),
+        }
+    }
+}
+
+/// How failed idempotent requests are retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; `0` disables retrying
+    pub max_retries: u32,
+    /// Backoff before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound for any single backoff
+    pub max_backoff: Duration,
+    /// Factor the backoff grows by after each retry
+    pub multiplier: f64,
+    /// Sleep a random duration up to the backoff ("full jitter"), so many
+    /// clients recovering at once don't retry in lockstep
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// The delay before retry number `retry` (starting at 0)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry as i32);
+        let capped = exponential.min(self.max_backoff.as_secs_f64());
+        let delay = match self.jitter {
+            true => capped * rand::random::<f64>(),
+            false => capped,
+        };
+        Duration::from_secs_f64(delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+/// When the circuit breaker opens and how long it stays open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed requests that open the circuit
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial request is allowed
+    pub reset_timeout: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            reset_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Shared circuit breaker state
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Debug, Default)]
+struct CircuitState {
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CircuitState::default()),
+        }
+    }
+
+    /// Whether a request may be sent
+    ///
+    /// Once the reset timeout has passed the circuit is half-open: requests
+    /// go through, and the first failure opens it again.
+    pub fn allow(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.opened_at {
+            Some(opened_at) => opened_at.elapsed() >= self.config.reset_timeout,
+            None => true,
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = CircuitState::default();
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        if state.opened_at.is_some() || state.failures >= self.config.failure_threshold {
+            state.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Whether a response status is worth retrying
+pub(crate) fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Whether a transport error is worth retrying
+pub(crate) fn is_retryable_error(error: &reqwest::Error) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    if error.is_connect() {
+        return true;
+    }
+    error.is_timeout() || error.is_request()
+}
+
+/// Whether a method can safely be sent more than once
+pub(crate) fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE
+    )
+}
+
+/// The delay requested by a `Retry-After: <seconds>` header
+pub(crate) fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Sleep without blocking the runtime
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+/// Sleep without blocking the runtime
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn sleep(duration: Duration) {
+    use wasm_bindgen::prelude::*;
+
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_name = setTimeout)]
+        fn set_timeout(handler: &js_sys::Function, timeout: i32) -> JsValue;
+    }
+
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        set_timeout(&resolve, duration.as_millis() as i32);
+    });
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(200));
+        assert_eq!(policy.backoff(2), Duration::from_millis(800));
+        assert_eq!(policy.backoff(10), Duration::from_secs(5));
+
+        let policy = RetryPolicy::default();
+        for retry in 0..10 {
+            assert!(policy.backoff(retry) <= Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            reset_timeout: Duration::from_millis(20),
+        });
+
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+
+        // Half-open after the reset timeout; one failure reopens it
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(25));
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.allow());
+    }
+}
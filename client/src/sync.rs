@@ -0,0 +1,273 @@
+//! Directory sync
+//!
+//! Compares the files of a local directory with the entries under a remote
+//! prefix and transfers only what differs. Files are compared by their
+//! SHA-256 hash with the hashes listed by the homeserver, so unchanged
+//! entries are never fetched. Not available in browsers.
+
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::client::{IntoPublicKey, PubkyClient};
+use crate::error::Result;
+use crate::list::ListOptions;
+
+/// Direction of a [`PubkyClient::sync_dir`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Upload new and changed local files
+    Push,
+    /// Download new and changed remote entries
+    Pull,
+    /// Upload like [`Push`](Self::Push), and delete remote entries that no
+    /// longer exist locally, making the prefix an exact copy of the
+    /// directory
+    Mirror,
+}
+
+/// What a sync transferred, as remote paths
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub uploaded: Vec<String>,
+    pub downloaded: Vec<String>,
+    pub deleted: Vec<String>,
+    /// Number of files that were already identical on both sides
+    pub unchanged: usize,
+}
+
+impl PubkyClient {
+    /// Synchronize the directory `local_dir` with the entries under
+    /// `remote_prefix`
+    ///
+    /// Files are matched by their `/`-separated path relative to the
+    /// directory. A trailing `/` is added to the prefix if missing.
+    pub async fn sync_dir(
+        &self,
+        local_dir: impl AsRef<Path>,
+        owner: impl IntoPublicKey,
+        remote_prefix: &str,
+        mode: SyncMode,
+    ) -> Result<SyncReport> {
+        let local_dir = local_dir.as_ref();
+        let public_key = owner.into_public_key()?;
+        let prefix = match remote_prefix.ends_with('/') {
+            true => remote_prefix.to_string(),
+            false => format!("{}/", remote_prefix),
+        };
+
+        // Pulling may create the directory
+        let local = match mode != SyncMode::Pull || tokio::fs::try_exists(local_dir).await? {
+            true => collect_files(local_dir).await?,
+            false => BTreeMap::new(),
+        };
+        // Hashes of the remote entries, by path; entries without one always
+        // differ
+        let options = ListOptions {
+            details: true,
+            ..Default::default()
+        };
+        let remote: BTreeMap<String, Option<String>> = self
+            .list_stream(public_key, &prefix, options)
+            .map_ok(|entry| (entry.path, entry.hash))
+            .try_collect()
+            .await?;
+
+        let mut report = SyncReport::default();
+        match mode {
+            SyncMode::Push | SyncMode::Mirror => {
+                for (relative, file) in &local {
+                    let path = format!("{}{}", prefix, relative);
+                    let data = Bytes::from(tokio::fs::read(file).await?);
+                    if remote.get(&path).cloned().flatten() == Some(sha256_hex(&data)) {
+                        report.unchanged += 1;
+                    } else {
+                        self.put(&public_key, &path, data).await?;
+                        report.uploaded.push(path);
+                    }
+                }
+            }
+            SyncMode::Pull => {
+                for (path, hash) in &remote {
+                    let relative = &path[prefix.len()..];
+                    let file = local_path(local_dir, relative)?;
+                    let existing = match local.get(relative) {
+                        Some(file) => Some(sha256_hex(&tokio::fs::read(file).await?)),
+                        None => None,
+                    };
+                    if hash.is_some() && existing == *hash {
+                        report.unchanged += 1;
+                        continue;
+                    }
+
+                    // Entries deleted since listing are skipped
+                    let Some(data) = self.get(&public_key, path).await? else {
+                        continue;
+                    };
+                    if let Some(parent) = file.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    tokio::fs::write(&file, &data).await?;
+                    report.downloaded.push(path.clone());
+                }
+            }
+        }
+
+        if mode == SyncMode::Mirror {
+            for path in remote.into_keys() {
+                if !local.contains_key(&path[prefix.len()..]) {
+                    self.delete(&public_key, &path).await?;
+                    report.deleted.push(path);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// Hex SHA-256 hash of `data`, as listed by the homeserver
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Recursively collect files under `root`, keyed by `/`-separated path
+async fn collect_files(root: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let relative = path.strip_prefix(root).expect("walked from the root");
+            let key: Vec<_> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect();
+            files.insert(key.join("/"), path);
+        }
+    }
+    Ok(files)
+}
+
+/// The local file for a remote path, refusing paths that would escape the
+/// directory
+fn local_path(root: &Path, relative: &str) -> Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in relative.split('/') {
+        if segment.is_empty() || segment == "." || segment == ".." || segment.contains('\\') {
+            let message = format!("refusing to write remote path {:?}", relative);
+            return Err(IoError::new(ErrorKind::InvalidData, message).into());
+        }
+        path.push(segment);
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+    use pubky_server::faults::FaultyStorage;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_sync_dir() {
+        // Injects no faults, only counting the requests to the user data
+        let requests = FaultyStorage::new();
+        let server = Server::builder()
+            .storage_layer(requests.clone())
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .build()
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("pubky-sync-dir-{}", public_key));
+        std::fs::create_dir_all(dir.join("posts")).unwrap();
+        std::fs::write(dir.join("index.html"), "<h1>hi</h1>").unwrap();
+        std::fs::write(dir.join("posts/1.txt"), "first").unwrap();
+        client
+            .put(&public_key, "site/stale.txt", "old")
+            .await
+            .unwrap();
+
+        // Push leaves remote-only entries alone
+        let report = client
+            .sync_dir(&dir, &public_key, "site", SyncMode::Push)
+            .await
+            .unwrap();
+        assert_eq!(report.uploaded, vec!["site/index.html", "site/posts/1.txt"]);
+        assert!(report.deleted.is_empty());
+
+        // Mirror only uploads changes and deletes what's gone locally
+        std::fs::write(dir.join("posts/1.txt"), "edited").unwrap();
+        let report = client
+            .sync_dir(&dir, &public_key, "site/", SyncMode::Mirror)
+            .await
+            .unwrap();
+        assert_eq!(report.uploaded, vec!["site/posts/1.txt"]);
+        assert_eq!(report.deleted, vec!["site/stale.txt"]);
+        assert_eq!(report.unchanged, 1);
+
+        // Pull recreates the tree elsewhere, then only fetches changes
+        let copy = dir.with_extension("copy");
+        let report = client
+            .sync_dir(&copy, &public_key, "site", SyncMode::Pull)
+            .await
+            .unwrap();
+        assert_eq!(report.downloaded.len(), 2);
+        let data = std::fs::read_to_string(copy.join("posts/1.txt")).unwrap();
+        assert_eq!(data, "edited");
+
+        client
+            .put(&public_key, "site/posts/2.txt", "second")
+            .await
+            .unwrap();
+        let report = client
+            .sync_dir(&copy, &public_key, "site", SyncMode::Pull)
+            .await
+            .unwrap();
+        assert_eq!(report.downloaded, vec!["site/posts/2.txt"]);
+        assert_eq!(report.unchanged, 2);
+
+        // Unchanged files are compared by hash, with a listing and without
+        // fetching them
+        let before = requests.stats().requests;
+        let report = client
+            .sync_dir(&copy, &public_key, "site", SyncMode::Pull)
+            .await
+            .unwrap();
+        assert!(report.downloaded.is_empty());
+        assert_eq!(report.unchanged, 3);
+        let report = client
+            .sync_dir(&dir, &public_key, "site", SyncMode::Push)
+            .await
+            .unwrap();
+        assert!(report.uploaded.is_empty());
+        assert_eq!(requests.stats().requests, before + 2);
+
+        assert!(local_path(&dir, "../escape").is_err());
+        assert!(local_path(&dir, "a//b").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&copy).unwrap();
+        server.shutdown().await;
+    }
+}
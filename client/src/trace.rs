@@ -0,0 +1,291 @@
+//! Wire tracing
+//!
+//! [`WireTrace`] is an [`Interceptor`] that writes every request and
+//! response line, with headers and the start of bodies, to a sink such as
+//! stderr. Session tokens and signatures are redacted. It can also write
+//! each request as an equivalent `curl` command to replay by hand.
+
+use pubky_common::auth::SIGNATURE_HEADER;
+use reqwest::header::{HeaderMap, AUTHORIZATION, COOKIE, SET_COOKIE};
+use reqwest::{Request, Response};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::interceptor::Interceptor;
+
+/// Placeholder for redacted secrets
+const REDACTED: &str = "[redacted]";
+
+/// JSON fields holding credentials, as in session and signup bodies
+const SECRET_FIELDS: &[&str] = &["token", "signature"];
+
+/// Writes the requests and responses of a client to a sink
+///
+/// ```no_run
+/// use pubky_client::{PubkyClient, WireTrace};
+///
+/// let client = PubkyClient::builder()
+///     .interceptor(WireTrace::stderr().curl(true))
+///     .build()?;
+/// # Ok::<_, pubky_client::Error>(())
+/// ```
+///
+/// Response bodies are traced as they are read, so streams like
+/// [`PubkyClient::watch`](crate::PubkyClient::watch) keep working. In
+/// browsers only the response line and headers are traced.
+#[derive(Clone)]
+pub struct WireTrace {
+    sink: Arc<dyn Fn(&str) + Send + Sync>,
+    body_limit: usize,
+    wire: bool,
+    curl: bool,
+}
+
+impl WireTrace {
+    /// Write trace lines to `sink`
+    pub fn new(sink: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Self {
+            sink: Arc::new(sink),
+            body_limit: 1024,
+            wire: true,
+            curl: false,
+        }
+    }
+
+    /// Write trace lines to stderr
+    pub fn stderr() -> Self {
+        Self::new(|line| eprintln!("{}", line))
+    }
+
+    /// Number of bytes of each body to show; 1 KiB by default
+    pub fn body_limit(mut self, limit: usize) -> Self {
+        self.body_limit = limit;
+        self
+    }
+
+    /// Write request and response lines; on by default
+    pub fn wire(mut self, enabled: bool) -> Self {
+        self.wire = enabled;
+        self
+    }
+
+    /// Also write each request as a `curl` command
+    pub fn curl(mut self, enabled: bool) -> Self {
+        self.curl = enabled;
+        self
+    }
+
+    fn emit(&self, line: &str) {
+        (self.sink)(line)
+    }
+
+    fn headers(&self, prefix: &str, headers: &HeaderMap) {
+        for (name, value) in headers {
+            let value = match is_secret(name) {
+                true => REDACTED.to_string(),
+                false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            };
+            self.emit(&format!("{} {}: {}", prefix, name, value));
+        }
+    }
+
+    fn curl_command(&self, request: &Request) -> String {
+        let mut command = format!(
+            "curl -X {} {}",
+            request.method(),
+            quote(request.url().as_str())
+        );
+        for (name, value) in request.headers() {
+            let value = match is_secret(name) {
+                true => REDACTED,
+                false => value.to_str().unwrap_or(REDACTED),
+            };
+            command += &format!(" -H {}", quote(&format!("{}: {}", name, value)));
+        }
+        match request.body().map(|body| body.as_bytes()) {
+            Some(Some(body)) => match std::str::from_utf8(body) {
+                Ok(text) if body.len() <= self.body_limit => {
+                    command += &format!(" --data-binary {}", quote(&redact_json(text)));
+                }
+                _ => command += &format!(" --data-binary @body  # {} bytes", body.len()),
+            },
+            Some(None) => command += " --data-binary @body  # streamed",
+            None => {}
+        }
+        command
+    }
+}
+
+impl Interceptor for WireTrace {
+    fn on_request(&self, request: &mut Request) {
+        if self.wire {
+            self.emit(&format!("> {} {}", request.method(), request.url()));
+            self.headers(">", request.headers());
+            match request.body().map(|body| body.as_bytes()) {
+                Some(Some(body)) => self.emit(&format!("> {}", preview(body, self.body_limit))),
+                Some(None) => self.emit("> <streamed body>"),
+                None => {}
+            }
+        }
+        if self.curl {
+            self.emit(&self.curl_command(request));
+        }
+    }
+
+    fn on_response(&self, response: &mut Response) {
+        if !self.wire {
+            return;
+        }
+        // The Fetch API doesn't tell which HTTP version was spoken
+        #[cfg(not(target_arch = "wasm32"))]
+        self.emit(&format!("< {:?} {}", response.version(), response.status()));
+        #[cfg(target_arch = "wasm32")]
+        self.emit(&format!("< {}", response.status()));
+        self.headers("<", response.headers());
+        #[cfg(not(target_arch = "wasm32"))]
+        trace_body(self.clone(), response);
+    }
+}
+
+impl fmt::Debug for WireTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WireTrace")
+            .field("body_limit", &self.body_limit)
+            .field("wire", &self.wire)
+            .field("curl", &self.curl)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Replace the body of `response` with one that traces the first bytes of
+/// each chunk as it is read, up to the body limit
+#[cfg(not(target_arch = "wasm32"))]
+fn trace_body(trace: WireTrace, response: &mut Response) {
+    use futures_util::StreamExt;
+
+    let placeholder = Response::from(http::Response::new(Vec::<u8>::new()));
+    let response_in = std::mem::replace(response, placeholder);
+    let mut builder = http::Response::builder()
+        .status(response_in.status())
+        .version(response_in.version());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = response_in.headers().clone();
+    }
+
+    let mut remaining = trace.body_limit;
+    let body = response_in.bytes_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            if remaining > 0 && !chunk.is_empty() {
+                trace.emit(&format!("< {}", preview(chunk, remaining)));
+                remaining = remaining.saturating_sub(chunk.len());
+            }
+        }
+    });
+    let body = reqwest::Body::wrap_stream(body);
+    *response = builder
+        .body(body)
+        .expect("status and headers come from a valid response")
+        .into();
+}
+
+fn is_secret(name: &reqwest::header::HeaderName) -> bool {
+    name == AUTHORIZATION || name == COOKIE || name == SET_COOKIE || name == SIGNATURE_HEADER
+}
+
+/// The start of a body as text, with credentials redacted
+fn preview(body: &[u8], limit: usize) -> String {
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => text,
+        // A chunk cut in the middle of a character
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&body[..e.valid_up_to()]).expect("valid up to the cut")
+        }
+        Err(_) => return format!("<{} bytes of binary data>", body.len()),
+    };
+    let text = redact_json(text);
+    match text.char_indices().nth(limit) {
+        Some((end, _)) => format!("{}... ({} bytes)", &text[..end], body.len()),
+        None => text,
+    }
+}
+
+/// Redact the credential fields of a JSON object, leaving other text as is
+fn redact_json(text: &str) -> String {
+    let Ok(serde_json::Value::Object(mut object)) = serde_json::from_str(text) else {
+        return text.to_string();
+    };
+    for field in SECRET_FIELDS {
+        if let Some(value) = object.get_mut(*field) {
+            *value = REDACTED.into();
+        }
+    }
+    serde_json::Value::Object(object).to_string()
+}
+
+/// Quote a shell argument
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PubkyClient;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_wire_trace() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let sink = lines.clone();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .interceptor(
+                WireTrace::new(move |line| sink.lock().unwrap().push(line.to_string())).curl(true),
+            )
+            .build()
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+
+        let session = client.signup(&keypair, None).await.unwrap();
+        client
+            .put(public_key, "a.txt", "it's a body")
+            .await
+            .unwrap();
+        let data = client.get(public_key, "a.txt").await.unwrap();
+        assert_eq!(data.unwrap(), "it's a body");
+
+        let lines = lines.lock().unwrap().join("\n");
+        let url = format!("{}/{}/a.txt", server.url(), public_key);
+        assert!(!lines.contains(&session.token));
+        assert!(lines.contains(r#""signature":"[redacted]""#));
+        assert!(lines.contains(r#""token":"[redacted]""#));
+        assert!(lines.contains(&format!("> PUT {}", url)));
+        assert!(lines.contains("> authorization: [redacted]"));
+        assert!(lines.contains("< HTTP/1.1 201 Created"));
+        assert!(lines.contains("< it's a body"));
+        assert!(lines.contains(&format!(
+            r"curl -X PUT '{}' -H 'x-pubky-signature: [redacted]' -H 'authorization: [redacted]' --data-binary 'it'\''s a body'",
+            url
+        )));
+
+        server.shutdown().await;
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview(b"hello world", 5), "hello... (11 bytes)");
+        assert_eq!(preview(&[0xff, 0xfe], 5), "<2 bytes of binary data>");
+        assert_eq!(
+            preview(r#"{"token":"t"}"#.as_bytes(), 100),
+            r#"{"token":"[redacted]"}"#
+        );
+    }
+}
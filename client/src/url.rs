@@ -0,0 +1,156 @@
+//! Identity-addressed URLs
+//!
+//! Entries are addressed as `pubky://<public_key>/<path>`, independent of
+//! the homeserver that stores them. The client resolves the public key to
+//! a homeserver: one mapped with
+//! [`PubkyClientBuilder::resolve`](crate::PubkyClientBuilder::resolve), the
+//! one announced in the owner's pkarr records, or its own as a fallback.
+//!
+//! URLs are parsed by [`PubkyUrl`], shared with the homeserver.
+
+use bytes::Bytes;
+use futures_util::stream;
+pub use pubky_common::url::PubkyUrl;
+
+use crate::client::PubkyClient;
+use crate::error::Result;
+use crate::watch::{boxed, ChangeStream};
+
+/// Anything that addresses an entry: a [`PubkyUrl`] or a string holding one
+pub trait IntoPubkyUrl {
+    fn into_pubky_url(self) -> Result<PubkyUrl>;
+}
+
+impl IntoPubkyUrl for PubkyUrl {
+    fn into_pubky_url(self) -> Result<PubkyUrl> {
+        Ok(self)
+    }
+}
+
+impl IntoPubkyUrl for &PubkyUrl {
+    fn into_pubky_url(self) -> Result<PubkyUrl> {
+        Ok(self.clone())
+    }
+}
+
+impl IntoPubkyUrl for &str {
+    fn into_pubky_url(self) -> Result<PubkyUrl> {
+        Ok(PubkyUrl::parse(self)?)
+    }
+}
+
+impl IntoPubkyUrl for &String {
+    fn into_pubky_url(self) -> Result<PubkyUrl> {
+        Ok(PubkyUrl::parse(self)?)
+    }
+}
+
+impl IntoPubkyUrl for String {
+    fn into_pubky_url(self) -> Result<PubkyUrl> {
+        Ok(PubkyUrl::parse(&self)?)
+    }
+}
+
+impl PubkyClient {
+    /// Store `body` at a `pubky://` URL
+    pub async fn put_url(&self, url: impl IntoPubkyUrl, body: impl Into<Bytes>) -> Result<()> {
+        let url = url.into_pubky_url()?;
+        self.put(url.public_key, &url.path, body).await
+    }
+
+    /// Retrieve the data at a `pubky://` URL, or `None` if there is none
+    pub async fn get_url(&self, url: impl IntoPubkyUrl) -> Result<Option<Bytes>> {
+        let url = url.into_pubky_url()?;
+        self.get(url.public_key, &url.path).await
+    }
+
+    /// Delete the data at a `pubky://` URL, returning whether it existed
+    pub async fn delete_url(&self, url: impl IntoPubkyUrl) -> Result<bool> {
+        let url = url.into_pubky_url()?;
+        self.delete(url.public_key, &url.path).await
+    }
+
+    /// List the entries under a `pubky://` URL, as `pubky://` URLs
+    pub async fn list_url(&self, url: impl IntoPubkyUrl) -> Result<Vec<PubkyUrl>> {
+        let url = url.into_pubky_url()?;
+        let paths = self.list(url.public_key, &url.path).await?;
+        Ok(paths
+            .into_iter()
+            .map(|path| PubkyUrl::new(url.public_key, path))
+            .collect())
+    }
+
+    /// Follow changes to the entries under a `pubky://` URL
+    pub fn watch_url(&self, url: impl IntoPubkyUrl) -> ChangeStream {
+        match url.into_pubky_url() {
+            Ok(url) => self.watch(url.public_key, &url.path),
+            Err(e) => boxed(stream::once(async { Err(e) })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_pubky_urls() {
+        let home = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let elsewhere = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let alice = Keypair::random();
+        let bob = Keypair::random();
+        // Writes are signed, so each owner writes with their own client
+        let client = PubkyClient::builder()
+            .homeserver(home.url())
+            .keypair(bob.clone())
+            .resolve(bob.public_key(), elsewhere.url())
+            .build()
+            .unwrap();
+        let alice_client = PubkyClient::builder()
+            .homeserver(home.url())
+            .keypair(alice.clone())
+            .build()
+            .unwrap();
+        let (alice, bob) = (alice.public_key(), bob.public_key());
+
+        let url: PubkyUrl = format!("pubky://{}/app/a.txt", alice).parse().unwrap();
+        assert_eq!(url.to_string(), format!("pubky://{}/app/a.txt", alice));
+        assert_eq!(PubkyUrl::parse(&alice.to_z32()).unwrap().path, "");
+        assert!(PubkyUrl::parse("pubky://nope/a.txt").is_err());
+
+        alice_client.put_url(&url, "alice").await.unwrap();
+        let bob_url = format!("pubky://{}/app/b.txt", bob);
+        client.put_url(&bob_url, "bob").await.unwrap();
+
+        // Each owner's entries live on their own homeserver
+        assert!(home.storage().get(&alice, "app/a.txt").unwrap().is_some());
+        assert!(elsewhere
+            .storage()
+            .get(&bob, "app/b.txt")
+            .unwrap()
+            .is_some());
+        assert_eq!(client.homeserver_of(&bob), elsewhere.url());
+        assert_eq!(client.get_url(&bob_url).await.unwrap().unwrap(), "bob");
+
+        let listed = client
+            .list_url(format!("pubky://{}/app/", bob))
+            .await
+            .unwrap();
+        assert_eq!(listed, vec![PubkyUrl::parse(&bob_url).unwrap()]);
+        assert!(client.delete_url(&bob_url).await.unwrap());
+        assert!(client.get_url(bob_url).await.unwrap().is_none());
+
+        home.shutdown().await;
+        elsewhere.shutdown().await;
+    }
+}
@@ -0,0 +1,213 @@
+//! Versioned writes
+//!
+//! Writes made with [`PubkyClient::put_versioned`] carry the client's
+//! [writer id](crate::PubkyClientBuilder::writer_id) and the version of the
+//! entry they are based on. When two devices write the same entry without
+//! seeing each other's write, such as while offline, the homeserver keeps
+//! both as siblings instead of letting the last one win. The entry stays in
+//! conflict until a write based on every sibling resolves it.
+
+use bytes::Bytes;
+use pubky_common::version::{VersionVector, SIBLINGS_HEADER, VERSION_HEADER, WRITER_HEADER};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::client::{check, IntoPublicKey, PubkyClient};
+use crate::error::Result;
+use crate::queue::Version;
+use crate::retry::Operation;
+
+/// Version of an entry and its number of siblings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryVersion {
+    /// Version covering every sibling; empty for entries written without a
+    /// writer id
+    pub version: VersionVector,
+    pub siblings: usize,
+}
+
+impl EntryVersion {
+    /// Whether concurrent writes left the entry with several siblings
+    pub fn is_conflict(&self) -> bool {
+        self.siblings > 1
+    }
+}
+
+/// A sibling as sent by the homeserver
+#[derive(Deserialize)]
+struct StoredSibling {
+    version: VersionVector,
+    #[serde(with = "crate::queue::base64_bytes")]
+    value: Bytes,
+}
+
+impl PubkyClient {
+    /// Retrieve the data at `path` with its version, or `None` if there is
+    /// none
+    ///
+    /// When the entry is in conflict, the data is its latest sibling.
+    pub async fn get_versioned(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+    ) -> Result<Option<(Bytes, EntryVersion)>> {
+        let public_key = owner.into_public_key()?;
+        let url = self.url(public_key, path).await?;
+        let response = self.send(Operation::Get, self.http.get(&url)).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            self.observe(&url, Some(Version::Absent));
+            return Ok(None);
+        }
+
+        let response = check(response).await?;
+        let version = entry_version(response.headers());
+        self.observe(&url, Version::of_response(&response));
+        let body = self.open(&public_key, path, response.bytes().await?)?;
+        Ok(Some((body, version)))
+    }
+
+    /// Store `body` at `path` as a write based on version `base`, as
+    /// returned by [`get_versioned`](Self::get_versioned)
+    ///
+    /// Pass an empty version for entries the client hasn't read. Siblings
+    /// `base` covers are replaced; if others remain, another device wrote
+    /// the entry concurrently and the returned version is a conflict.
+    pub async fn put_versioned(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+        body: impl Into<Bytes>,
+        base: &VersionVector,
+    ) -> Result<EntryVersion> {
+        let public_key = owner.into_public_key()?;
+        let url = self.url(public_key, path).await?;
+        let payload = self.seal(&public_key, path, body.into())?;
+        let request = self.http.put(&url).header(WRITER_HEADER, self.writer_id());
+        let mut request = self
+            .sign_write(request, "PUT", &public_key, path, &payload)
+            .await?
+            .body(payload);
+        if !base.is_empty() {
+            request = request.header(VERSION_HEADER, base.to_string());
+        }
+
+        let response = self.send(Operation::Put, request).await?;
+        self.uncache(&url);
+        let response = check(response).await?;
+        self.observe(&url, Version::of_response(&response));
+        Ok(entry_version(response.headers()))
+    }
+
+    /// Every concurrent version of the entry at `path`, oldest first
+    ///
+    /// To resolve a conflict, merge the siblings' data and write it with
+    /// [`put_versioned`](Self::put_versioned), based on the version of the
+    /// entry.
+    pub async fn siblings(
+        &self,
+        owner: impl IntoPublicKey,
+        path: &str,
+    ) -> Result<Vec<(VersionVector, Bytes)>> {
+        let public_key = owner.into_public_key()?;
+        let url = self.url(public_key, path).await?;
+        let request = self.http.get(&url).query(&[("siblings", "true")]);
+        let response = self.send(Operation::Get, request).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+
+        let siblings: Vec<StoredSibling> = check(response).await?.json().await?;
+        siblings
+            .into_iter()
+            .map(|sibling| {
+                let value = self.open(&public_key, path, sibling.value)?;
+                Ok((sibling.version, value))
+            })
+            .collect()
+    }
+}
+
+/// Read the version headers of a response
+fn entry_version(headers: &HeaderMap) -> EntryVersion {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    EntryVersion {
+        version: header(VERSION_HEADER)
+            .and_then(|version| version.parse().ok())
+            .unwrap_or_default(),
+        siblings: header(SIBLINGS_HEADER)
+            .and_then(|siblings| siblings.parse().ok())
+            .unwrap_or(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+
+    #[tokio::test]
+    async fn test_versioned_writes() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let owner = keypair.public_key();
+        let laptop = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair.clone())
+            .writer_id("laptop")
+            .build()
+            .unwrap();
+        let phone = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .writer_id("phone")
+            .build()
+            .unwrap();
+
+        let written = laptop
+            .put_versioned(owner, "notes.txt", "a", &VersionVector::new())
+            .await
+            .unwrap();
+        assert!(!written.is_conflict());
+        let (body, read) = phone
+            .get_versioned(owner, "notes.txt")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((body.as_ref(), &read), (&b"a"[..], &written));
+
+        // Both devices edit the version they read
+        laptop
+            .put_versioned(owner, "notes.txt", "ab", &read.version)
+            .await
+            .unwrap();
+        let conflict = phone
+            .put_versioned(owner, "notes.txt", "ac", &read.version)
+            .await
+            .unwrap();
+        assert!(conflict.is_conflict());
+        let siblings = laptop.siblings(owner, "notes.txt").await.unwrap();
+        let values: Vec<_> = siblings.iter().map(|(_, value)| value.as_ref()).collect();
+        assert_eq!(values, vec![&b"ab"[..], &b"ac"[..]]);
+
+        // A merge based on both siblings resolves the conflict
+        let merged = laptop
+            .put_versioned(owner, "notes.txt", "abc", &conflict.version)
+            .await
+            .unwrap();
+        assert!(!merged.is_conflict());
+        assert_eq!(phone.get(owner, "notes.txt").await.unwrap().unwrap(), "abc");
+        assert!(phone
+            .siblings(owner, "missing.txt")
+            .await
+            .unwrap()
+            .is_empty());
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,164 @@
+//! JavaScript bindings for browsers
+//!
+//! Build with `wasm-pack build client --target web`. The exported
+//! `PubkyClient` and `Keypair` classes wrap the Rust types, so browser apps
+//! share the same request and session logic as native ones.
+//!
+//! Web apps sign users in without ever seeing their keypair: they ask for
+//! capabilities through an auth relay, show the returned URL for the key
+//! holder to approve, and wait for the session.
+
+use js_sys::{Array, Uint8Array};
+use pubky_common::Keypair;
+use wasm_bindgen::prelude::*;
+
+use crate::{AuthorizationRequest, PubkyClient, SessionInfo};
+
+/// An Ed25519 keypair
+#[wasm_bindgen(js_name = Keypair)]
+pub struct JsKeypair(Keypair);
+
+#[wasm_bindgen(js_class = Keypair)]
+impl JsKeypair {
+    /// Generate a new random keypair
+    pub fn random() -> JsKeypair {
+        JsKeypair(Keypair::random())
+    }
+
+    /// Restore a keypair from its 32-byte secret key
+    #[wasm_bindgen(js_name = fromSecretKey)]
+    pub fn from_secret_key(secret_key: &[u8]) -> Result<JsKeypair, JsError> {
+        let secret_key: [u8; 32] = secret_key
+            .try_into()
+            .map_err(|_| JsError::new("secret key must be 32 bytes"))?;
+        Ok(JsKeypair(Keypair::from_secret_key(&secret_key)))
+    }
+
+    /// The 32-byte secret key
+    #[wasm_bindgen(js_name = secretKey)]
+    pub fn secret_key(&self) -> Uint8Array {
+        Uint8Array::from(&self.0.secret_key()[..])
+    }
+
+    /// The z-base-32 public key
+    #[wasm_bindgen(js_name = publicKey)]
+    pub fn public_key(&self) -> String {
+        self.0.public_key().to_z32()
+    }
+}
+
+/// Client for a homeserver's storage API
+///
+/// Owners are given as z-base-32 public keys or `pubky://` URLs.
+#[wasm_bindgen(js_name = PubkyClient)]
+pub struct JsClient(PubkyClient);
+
+#[wasm_bindgen(js_class = PubkyClient)]
+impl JsClient {
+    /// Create a client for the homeserver at the given base URL
+    #[wasm_bindgen(constructor)]
+    pub fn new(homeserver: String) -> JsClient {
+        JsClient(PubkyClient::new(homeserver))
+    }
+
+    /// Store `body` at `path` for the given owner
+    pub async fn put(&self, owner: String, path: String, body: Vec<u8>) -> Result<(), JsError> {
+        Ok(self.0.put(owner, &path, body).await?)
+    }
+
+    /// Retrieve the data at `path`, or `undefined` if there is none
+    pub async fn get(&self, owner: String, path: String) -> Result<Option<Vec<u8>>, JsError> {
+        let data = self.0.get(owner, &path).await?;
+        Ok(data.map(|bytes| bytes.to_vec()))
+    }
+
+    /// Delete the data at `path`, returning whether it existed
+    pub async fn delete(&self, owner: String, path: String) -> Result<bool, JsError> {
+        Ok(self.0.delete(owner, &path).await?)
+    }
+
+    /// List the paths of all entries under `prefix`
+    pub async fn list(&self, owner: String, prefix: String) -> Result<Array, JsError> {
+        let keys = self.0.list(owner, &prefix).await?;
+        Ok(keys.into_iter().map(JsValue::from).collect())
+    }
+
+    /// Register the keypair with the homeserver and start a session
+    pub async fn signup(
+        &self,
+        keypair: &JsKeypair,
+        invite_code: Option<String>,
+    ) -> Result<JsValue, JsError> {
+        let session = self.0.signup(&keypair.0, invite_code.as_deref()).await?;
+        session_to_js(&session)
+    }
+
+    /// Sign in to an account that signed up before
+    pub async fn signin(&self, keypair: &JsKeypair) -> Result<JsValue, JsError> {
+        let session = self.0.signin(&keypair.0).await?;
+        session_to_js(&session)
+    }
+
+    /// End the current session
+    pub async fn signout(&self) -> Result<(), JsError> {
+        Ok(self.0.signout().await?)
+    }
+
+    /// The current session, or `undefined` if signed out
+    pub fn session(&self) -> Result<JsValue, JsError> {
+        match self.0.session() {
+            Some(session) => session_to_js(&session),
+            None => Ok(JsValue::UNDEFINED),
+        }
+    }
+
+    /// Ask the user's key holder for a session with `capabilities`, such
+    /// as `/pub/my-app/:rw`, through the auth relay at `relay`
+    #[wasm_bindgen(js_name = requestAuthorization)]
+    pub async fn request_authorization(
+        &self,
+        relay: String,
+        capabilities: String,
+    ) -> Result<JsAuthorizationRequest, JsError> {
+        let request = self
+            .0
+            .request_relayed_authorization(&relay, &capabilities)
+            .await?;
+        Ok(JsAuthorizationRequest(request))
+    }
+
+    /// Wait until the request is approved, then use the granted session
+    #[wasm_bindgen(js_name = awaitAuthorization)]
+    pub async fn await_authorization(
+        &self,
+        request: &JsAuthorizationRequest,
+    ) -> Result<JsValue, JsError> {
+        let session = self.0.await_authorization(&request.0).await?;
+        session_to_js(&session)
+    }
+}
+
+/// A pending request to be authorized by the user's key holder
+#[wasm_bindgen(js_name = AuthorizationRequest)]
+pub struct JsAuthorizationRequest(AuthorizationRequest);
+
+#[wasm_bindgen(js_class = AuthorizationRequest)]
+impl JsAuthorizationRequest {
+    /// The `pubkyauth:` URL to show, for example as a QR code
+    #[wasm_bindgen(getter)]
+    pub fn url(&self) -> String {
+        self.0.url.to_string()
+    }
+
+    /// Unix timestamp in milliseconds when the request expires
+    #[wasm_bindgen(getter, js_name = expiresAt)]
+    pub fn expires_at(&self) -> f64 {
+        self.0.expires_at as f64
+    }
+}
+
+/// Convert a session into a plain JavaScript object
+fn session_to_js(session: &SessionInfo) -> Result<JsValue, JsError> {
+    let json = serde_json::to_string(session)?;
+    js_sys::JSON::parse(&json).map_err(|_| JsError::new("invalid session JSON"))
+}
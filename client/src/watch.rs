@@ -0,0 +1,251 @@
+//! Watching the change feed
+//!
+//! [`PubkyClient::watch`] follows a user's changes as server-sent events
+//! from `GET /events/{public_key}`. Dropped connections are re-established
+//! with backoff, resuming after the last delivered event.
+
+use futures_util::stream::{self, StreamExt};
+use pubky_common::dto::ChangeEvent;
+use pubky_common::PublicKey;
+use std::collections::VecDeque;
+
+use crate::client::{check, IntoPublicKey, PubkyClient};
+use crate::error::{Error, Result};
+use crate::retry::{self, Operation};
+
+pub use pubky_common::dto::ChangeOp;
+
+/// Stream of changes returned by [`PubkyClient::watch`]
+#[cfg(not(target_arch = "wasm32"))]
+pub type ChangeStream = stream::BoxStream<'static, Result<ChangeEvent>>;
+
+/// Stream of changes returned by [`PubkyClient::watch`]
+#[cfg(target_arch = "wasm32")]
+pub type ChangeStream = stream::LocalBoxStream<'static, Result<ChangeEvent>>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type Body = stream::BoxStream<'static, reqwest::Result<bytes::Bytes>>;
+#[cfg(target_arch = "wasm32")]
+type Body = stream::LocalBoxStream<'static, reqwest::Result<bytes::Bytes>>;
+
+impl PubkyClient {
+    /// Follow changes to the owner's entries under `prefix`, starting now
+    ///
+    /// The stream reconnects by itself when the connection drops. It ends
+    /// with an error if the homeserver refuses the watch, for example with
+    /// `410 Gone` after the client fell too far behind to resume; the app
+    /// then has to reread what it displays and watch again.
+    pub fn watch(&self, owner: impl IntoPublicKey, prefix: &str) -> ChangeStream {
+        self.watch_from(owner, prefix, None)
+    }
+
+    /// Follow changes after the event with sequence number `cursor`
+    ///
+    /// Persist the `seq` of the last processed [`ChangeEvent`] to catch up
+    /// on missed changes after a restart.
+    pub fn watch_since(
+        &self,
+        owner: impl IntoPublicKey,
+        prefix: &str,
+        cursor: u64,
+    ) -> ChangeStream {
+        self.watch_from(owner, prefix, Some(cursor))
+    }
+
+    fn watch_from(
+        &self,
+        owner: impl IntoPublicKey,
+        prefix: &str,
+        cursor: Option<u64>,
+    ) -> ChangeStream {
+        let public_key = match owner.into_public_key() {
+            Ok(public_key) => public_key,
+            Err(e) => return boxed(stream::once(async { Err(e) })),
+        };
+
+        let watcher = Watcher {
+            client: self.clone(),
+            public_key,
+            prefix: prefix.to_string(),
+            cursor,
+            body: None,
+            buffer: String::new(),
+            pending: VecDeque::new(),
+            reconnects: 0,
+            done: false,
+        };
+        boxed(stream::unfold(watcher, |mut watcher| async move {
+            let item = watcher.next().await?;
+            Some((item, watcher))
+        }))
+    }
+}
+
+/// State of a watch across reconnects
+struct Watcher {
+    client: PubkyClient,
+    public_key: PublicKey,
+    prefix: String,
+    /// Sequence number of the last delivered event
+    cursor: Option<u64>,
+    body: Option<Body>,
+    /// Received text not yet forming a complete event
+    buffer: String,
+    pending: VecDeque<ChangeEvent>,
+    /// Consecutive failed connections, for backoff
+    reconnects: u32,
+    done: bool,
+}
+
+impl Watcher {
+    async fn next(&mut self) -> Option<Result<ChangeEvent>> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if let Some(event) = self.pending.pop_front() {
+                self.cursor = Some(event.seq);
+                return Some(Ok(event));
+            }
+
+            let Some(body) = &mut self.body else {
+                match self.connect().await {
+                    Ok(body) => self.body = Some(body),
+                    Err(Error::Network(e)) if retry::is_retryable_error(&e) => self.backoff().await,
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+                continue;
+            };
+
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    self.reconnects = 0;
+                    self.buffer.push_str(&String::from_utf8_lossy(&chunk));
+                    self.parse_events();
+                }
+                // The connection dropped; resume after the last event
+                Some(Err(_)) | None => {
+                    self.body = None;
+                    self.buffer.clear();
+                    self.backoff().await;
+                }
+            }
+        }
+    }
+
+    // Takes `&mut self` to keep the future `Send`; the body isn't `Sync`
+    async fn connect(&mut self) -> Result<Body> {
+        let url = format!(
+            "{}/events/{}",
+            self.client.locate(&self.public_key).await,
+            self.public_key.to_z32()
+        );
+        let mut request = self.client.http.get(url).query(&[("prefix", &self.prefix)]);
+        if let Some(cursor) = self.cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+
+        let response = self.client.send(Operation::Watch, request).await?;
+        let body = check(response).await?.bytes_stream();
+        Ok(boxed(body))
+    }
+
+    async fn backoff(&mut self) {
+        let delay = self.client.retry.backoff(self.reconnects);
+        self.reconnects = self.reconnects.saturating_add(1);
+        retry::sleep(delay).await;
+    }
+
+    /// Move complete events from the buffer to the pending queue
+    fn parse_events(&mut self) {
+        while let Some(end) = self.buffer.find("\n\n") {
+            let block: String = self.buffer.drain(..end + 2).collect();
+            let data: Vec<&str> = block
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect();
+            // Comments such as keep-alives carry no data
+            if data.is_empty() {
+                continue;
+            }
+            if let Ok(event) = serde_json::from_str(&data.join("\n")) {
+                self.pending.push_back(event);
+            }
+        }
+    }
+}
+
+/// Box a stream, as `Send` where the platform allows it
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn boxed<T, S>(stream: S) -> stream::BoxStream<'static, T>
+where
+    S: stream::Stream<Item = T> + Send + 'static,
+{
+    stream.boxed()
+}
+
+/// Box a stream, as `Send` where the platform allows it
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn boxed<T, S>(stream: S) -> stream::LocalBoxStream<'static, T>
+where
+    S: stream::Stream<Item = T> + 'static,
+{
+    stream.boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+    use pubky_server::Server;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_watch() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let client = PubkyClient::builder()
+            .homeserver(server.url())
+            .keypair(keypair)
+            .build()
+            .unwrap();
+        client.put(&public_key, "app/old.txt", "old").await.unwrap();
+
+        let mut changes = client.watch(public_key, "app/");
+        let writer = client.clone();
+        tokio::spawn(async move {
+            // Give the watch time to connect
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            writer.put(&public_key, "other/x.txt", "x").await.unwrap();
+            writer.put(&public_key, "app/a.txt", "a").await.unwrap();
+            writer.delete(&public_key, "app/a.txt").await.unwrap();
+        });
+
+        let put = changes.next().await.unwrap().unwrap();
+        assert_eq!((put.op, put.path.as_str()), (ChangeOp::Put, "app/a.txt"));
+        let delete = changes.next().await.unwrap().unwrap();
+        assert_eq!(delete.op, ChangeOp::Delete);
+
+        // Resuming from a cursor replays later changes
+        let mut replay = client.watch_since(public_key, "app/", 0);
+        let first = replay.next().await.unwrap().unwrap();
+        assert_eq!(first.path, "app/old.txt");
+        assert_eq!(replay.next().await.unwrap().unwrap(), put);
+
+        // Refused watches end the stream with an error
+        let mut refused = client.watch("not-a-key", "");
+        assert!(refused.next().await.unwrap().is_err());
+        assert!(refused.next().await.is_none());
+
+        server.shutdown().await;
+    }
+}
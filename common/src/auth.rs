@@ -0,0 +1,584 @@
+//! Signed authentication tokens
+//!
+//! A client proves control of a keypair by signing the current time, the
+//! public key of the homeserver it signs in to and a random nonce. The
+//! homeserver accepts tokens addressed to it whose timestamp is within
+//! [`AuthToken::MAX_CLOCK_SKEW_MS`] of its own clock, each only once, so a
+//! token can't be replayed to the same or another homeserver.
+//!
+//! To authorize another device, the keypair instead signs an [`AuthGrant`]
+//! for the challenge the device displays, with the capabilities it asked
+//! for and the homeserver that may redeem it. To move to another
+//! homeserver, it signs a [`MigrationIntent`].
+//!
+//! Every PUT and DELETE of an entry carries a [`WriteSignature`] by the
+//! entry's owner in the [`SIGNATURE_HEADER`], covering the method, path,
+//! body, homeserver and a nonce, so nobody else can write under their
+//! public key, nor replay their writes.
+
+use rand::{rngs::OsRng, TryRngCore as _};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Error, Keypair, PublicKey, Result, Signature};
+
+/// Domain separator prepended to every signed token message
+const AUTH_NAMESPACE: &[u8] = b"PUBKY:AUTH:";
+
+/// Domain separator prepended to every signed grant message
+const GRANT_NAMESPACE: &[u8] = b"PUBKY:GRANT:";
+
+/// Domain separator prepended to every signed migration message
+const MIGRATION_NAMESPACE: &[u8] = b"PUBKY:MIGRATE:";
+
+/// Domain separator prepended to every signed write message
+const WRITE_NAMESPACE: &[u8] = b"PUBKY:WRITE:";
+
+/// Header carrying the [`WriteSignature`] of a write
+pub const SIGNATURE_HEADER: &str = "x-pubky-signature";
+
+/// Proof of control of a keypair at a point in time, for one homeserver
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthToken {
+    /// z-base-32 public key of the signer
+    pub public_key: String,
+    /// z-base-32 public key of the homeserver the token is for
+    pub audience: String,
+    /// Random value the homeserver accepts once
+    pub nonce: String,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    /// z-base-32 Ed25519 signature
+    pub signature: String,
+}
+
+impl AuthToken {
+    /// Maximum accepted difference between the token and server clocks
+    pub const MAX_CLOCK_SKEW_MS: u64 = 5 * 60 * 1000;
+
+    /// Sign a token for the homeserver `audience` at the current time, with
+    /// a random nonce
+    pub fn sign(keypair: &Keypair, audience: &PublicKey) -> Self {
+        Self::sign_at(keypair, audience, &random_nonce(), now_millis())
+    }
+
+    /// Sign a token with `nonce` for the given Unix timestamp in
+    /// milliseconds
+    pub fn sign_at(keypair: &Keypair, audience: &PublicKey, nonce: &str, timestamp: u64) -> Self {
+        let public_key = keypair.public_key();
+        let signature = keypair.sign(&message(&public_key, audience, nonce, timestamp));
+
+        Self {
+            public_key: public_key.to_z32(),
+            audience: audience.to_z32(),
+            nonce: nonce.to_string(),
+            timestamp,
+            signature: base32::encode(base32::Alphabet::Z, &signature.to_bytes()),
+        }
+    }
+
+    /// Verify the token is for the homeserver `audience` at the current
+    /// time, returning the signer
+    pub fn verify(&self, audience: &PublicKey) -> Result<PublicKey> {
+        self.verify_at(audience, now_millis())
+    }
+
+    /// Verify the token is for the homeserver `audience` at the given Unix
+    /// timestamp in milliseconds, returning the signer
+    ///
+    /// Whether the nonce was seen before is up to the homeserver to check.
+    pub fn verify_at(&self, audience: &PublicKey, now: u64) -> Result<PublicKey> {
+        if now.abs_diff(self.timestamp) > Self::MAX_CLOCK_SKEW_MS {
+            return Err(Error::ExpiredToken);
+        }
+        if self.audience != audience.to_z32() {
+            return Err(Error::WrongAudience);
+        }
+
+        let public_key = PublicKey::from_z32(&self.public_key)?;
+        let bytes = base32::decode(base32::Alphabet::Z, &self.signature)
+            .ok_or(Error::InvalidSignature)?;
+        let bytes: [u8; 64] = bytes.try_into().map_err(|_| Error::InvalidSignature)?;
+
+        public_key.verify(
+            &message(&public_key, audience, &self.nonce, self.timestamp),
+            &Signature::from_bytes(&bytes),
+        )?;
+        Ok(public_key)
+    }
+}
+
+/// Approval of another device's authorization request, signed with the
+/// account's keypair for one homeserver
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthGrant {
+    /// z-base-32 public key of the signer
+    pub public_key: String,
+    /// z-base-32 public key of the homeserver the grant is for
+    pub audience: String,
+    /// Challenge displayed by the device being authorized
+    pub challenge: String,
+    /// Capabilities granted to the device, such as `/pub/my-app/:rw`
+    pub capabilities: String,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    /// z-base-32 Ed25519 signature
+    pub signature: String,
+}
+
+impl AuthGrant {
+    /// Sign a grant of `capabilities` for `challenge`, to be redeemed at the
+    /// homeserver `audience`, at the current time
+    pub fn sign(
+        keypair: &Keypair,
+        audience: &PublicKey,
+        challenge: &str,
+        capabilities: &str,
+    ) -> Self {
+        Self::sign_at(keypair, audience, challenge, capabilities, now_millis())
+    }
+
+    /// Sign a grant for the given Unix timestamp in milliseconds
+    pub fn sign_at(
+        keypair: &Keypair,
+        audience: &PublicKey,
+        challenge: &str,
+        capabilities: &str,
+        timestamp: u64,
+    ) -> Self {
+        let public_key = keypair.public_key();
+        let message = grant_message(&public_key, audience, challenge, capabilities, timestamp);
+
+        Self {
+            public_key: public_key.to_z32(),
+            audience: audience.to_z32(),
+            challenge: challenge.to_string(),
+            capabilities: capabilities.to_string(),
+            timestamp,
+            signature: base32::encode(base32::Alphabet::Z, &keypair.sign(&message).to_bytes()),
+        }
+    }
+
+    /// Verify the grant is for the homeserver `audience` at the current
+    /// time, returning the signer
+    pub fn verify(&self, audience: &PublicKey) -> Result<PublicKey> {
+        self.verify_at(audience, now_millis())
+    }
+
+    /// Verify the grant is for the homeserver `audience` at the given Unix
+    /// timestamp in milliseconds, returning the signer
+    ///
+    /// Whether the grant was redeemed before is up to the homeserver to
+    /// check, by its [`signature_bytes`](Self::signature_bytes).
+    pub fn verify_at(&self, audience: &PublicKey, now: u64) -> Result<PublicKey> {
+        if now.abs_diff(self.timestamp) > AuthToken::MAX_CLOCK_SKEW_MS {
+            return Err(Error::ExpiredToken);
+        }
+        if self.audience != audience.to_z32() {
+            return Err(Error::WrongAudience);
+        }
+
+        let public_key = PublicKey::from_z32(&self.public_key)?;
+        let message = grant_message(
+            &public_key,
+            audience,
+            &self.challenge,
+            &self.capabilities,
+            self.timestamp,
+        );
+        let signature = Signature::from_bytes(&self.signature_bytes()?);
+        public_key.verify(&message, &signature)?;
+        Ok(public_key)
+    }
+
+    /// The signature of the grant, which identifies it whatever encoding of
+    /// it was sent
+    pub fn signature_bytes(&self) -> Result<[u8; 64]> {
+        let bytes = base32::decode(base32::Alphabet::Z, &self.signature)
+            .ok_or(Error::InvalidSignature)?;
+        bytes.try_into().map_err(|_| Error::InvalidSignature)
+    }
+}
+
+/// Consent to move an account's entries from one homeserver to another,
+/// signed with the account's keypair
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationIntent {
+    /// z-base-32 public key of the signer
+    pub public_key: String,
+    /// Base URL of the homeserver the account leaves
+    pub from: String,
+    /// Base URL of the homeserver the account moves to
+    pub to: String,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    /// z-base-32 Ed25519 signature
+    pub signature: String,
+}
+
+impl MigrationIntent {
+    /// Sign an intent to move from `from` to `to` at the current time
+    pub fn sign(keypair: &Keypair, from: &str, to: &str) -> Self {
+        Self::sign_at(keypair, from, to, now_millis())
+    }
+
+    /// Sign an intent for the given Unix timestamp in milliseconds
+    pub fn sign_at(keypair: &Keypair, from: &str, to: &str, timestamp: u64) -> Self {
+        let public_key = keypair.public_key();
+        let message = migration_message(&public_key, from, to, timestamp);
+
+        Self {
+            public_key: public_key.to_z32(),
+            from: from.to_string(),
+            to: to.to_string(),
+            timestamp,
+            signature: base32::encode(base32::Alphabet::Z, &keypair.sign(&message).to_bytes()),
+        }
+    }
+
+    /// Verify the intent against the current time, returning the signer
+    pub fn verify(&self) -> Result<PublicKey> {
+        self.verify_at(now_millis())
+    }
+
+    /// Verify the intent against the given Unix timestamp in milliseconds
+    pub fn verify_at(&self, now: u64) -> Result<PublicKey> {
+        if now.abs_diff(self.timestamp) > AuthToken::MAX_CLOCK_SKEW_MS {
+            return Err(Error::ExpiredToken);
+        }
+
+        let public_key = PublicKey::from_z32(&self.public_key)?;
+        let bytes = base32::decode(base32::Alphabet::Z, &self.signature)
+            .ok_or(Error::InvalidSignature)?;
+        let bytes: [u8; 64] = bytes.try_into().map_err(|_| Error::InvalidSignature)?;
+
+        let message = migration_message(&public_key, &self.from, &self.to, self.timestamp);
+        public_key.verify(&message, &Signature::from_bytes(&bytes))?;
+        Ok(public_key)
+    }
+}
+
+/// Signature of a write by the owner of the entry
+///
+/// Sent in the [`SIGNATURE_HEADER`] as `<timestamp>.<nonce>.<signature>`,
+/// with the Unix timestamp in milliseconds, a random z-base-32 nonce and the
+/// z-base-32 Ed25519 signature. The path is the entry's path under the
+/// owner, without a leading `/`, and the audience the public key of the
+/// homeserver written to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteSignature {
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    /// Random value the homeserver accepts once
+    pub nonce: String,
+    /// z-base-32 Ed25519 signature
+    pub signature: String,
+}
+
+impl WriteSignature {
+    /// Sign a write of `body` to `path` on the homeserver `audience` with
+    /// `method`, such as `PUT`, at the current time, with a random nonce
+    pub fn sign(
+        keypair: &Keypair,
+        audience: &PublicKey,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Self {
+        let nonce = random_nonce();
+        Self::sign_at(keypair, audience, method, path, body, &nonce, now_millis())
+    }
+
+    /// Sign a write with the given nonce and Unix timestamp in milliseconds
+    pub fn sign_at(
+        keypair: &Keypair,
+        audience: &PublicKey,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        nonce: &str,
+        timestamp: u64,
+    ) -> Self {
+        let public_key = keypair.public_key();
+        let message = write_message(&public_key, audience, method, path, body, nonce, timestamp);
+
+        Self {
+            timestamp,
+            nonce: nonce.to_string(),
+            signature: base32::encode(base32::Alphabet::Z, &keypair.sign(&message).to_bytes()),
+        }
+    }
+
+    /// Verify that `public_key` signed the write to the homeserver
+    /// `audience` against the given Unix timestamp in milliseconds
+    pub fn verify_at(
+        &self,
+        public_key: &PublicKey,
+        audience: &PublicKey,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        now: u64,
+    ) -> Result<()> {
+        if now.abs_diff(self.timestamp) > AuthToken::MAX_CLOCK_SKEW_MS {
+            return Err(Error::ExpiredToken);
+        }
+
+        let bytes = base32::decode(base32::Alphabet::Z, &self.signature)
+            .ok_or(Error::InvalidSignature)?;
+        let bytes: [u8; 64] = bytes.try_into().map_err(|_| Error::InvalidSignature)?;
+
+        let (nonce, timestamp) = (&self.nonce, self.timestamp);
+        let message = write_message(public_key, audience, method, path, body, nonce, timestamp);
+        public_key.verify(&message, &Signature::from_bytes(&bytes))
+    }
+}
+
+impl fmt::Display for WriteSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.timestamp, self.nonce, self.signature)
+    }
+}
+
+impl FromStr for WriteSignature {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(3, '.');
+        let (Some(timestamp), Some(nonce), Some(signature)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Error::InvalidSignature);
+        };
+        Ok(Self {
+            timestamp: timestamp.parse().map_err(|_| Error::InvalidSignature)?,
+            nonce: nonce.to_string(),
+            signature: signature.to_string(),
+        })
+    }
+}
+
+/// The bytes signed for a token; the nonce comes last, after the
+/// fixed-size fields
+fn message(public_key: &PublicKey, audience: &PublicKey, nonce: &str, timestamp: u64) -> Vec<u8> {
+    let mut message = AUTH_NAMESPACE.to_vec();
+    message.extend_from_slice(&public_key.to_bytes());
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&audience.to_bytes());
+    message.extend_from_slice(nonce.as_bytes());
+    message
+}
+
+/// The bytes signed for a grant; the challenge is length-prefixed so it
+/// can't run into the capabilities
+fn grant_message(
+    public_key: &PublicKey,
+    audience: &PublicKey,
+    challenge: &str,
+    capabilities: &str,
+    timestamp: u64,
+) -> Vec<u8> {
+    let mut message = GRANT_NAMESPACE.to_vec();
+    message.extend_from_slice(&public_key.to_bytes());
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&audience.to_bytes());
+    message.extend_from_slice(&(challenge.len() as u32).to_be_bytes());
+    message.extend_from_slice(challenge.as_bytes());
+    message.extend_from_slice(capabilities.as_bytes());
+    message
+}
+
+/// The bytes signed for a migration intent; the source is length-prefixed
+/// so it can't run into the destination
+fn migration_message(public_key: &PublicKey, from: &str, to: &str, timestamp: u64) -> Vec<u8> {
+    let mut message = MIGRATION_NAMESPACE.to_vec();
+    message.extend_from_slice(&public_key.to_bytes());
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&(from.len() as u32).to_be_bytes());
+    message.extend_from_slice(from.as_bytes());
+    message.extend_from_slice(to.as_bytes());
+    message
+}
+
+/// The bytes signed for a write; the method, path and nonce are
+/// length-prefixed so they can't run into each other, and the body is hashed
+fn write_message(
+    public_key: &PublicKey,
+    audience: &PublicKey,
+    method: &str,
+    path: &str,
+    body: &[u8],
+    nonce: &str,
+    timestamp: u64,
+) -> Vec<u8> {
+    let mut message = WRITE_NAMESPACE.to_vec();
+    message.extend_from_slice(&public_key.to_bytes());
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(&audience.to_bytes());
+    for field in [method, path, nonce] {
+        message.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        message.extend_from_slice(field.as_bytes());
+    }
+    message.extend_from_slice(&Sha256::digest(body));
+    message
+}
+
+/// 128 random bits in z-base-32, to make a signed message unique
+pub fn random_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng
+        .try_fill_bytes(&mut bytes)
+        .expect("OS random number generator failed");
+    base32::encode(base32::Alphabet::Z, &bytes)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_token_round_trip() {
+        let keypair = Keypair::random();
+        let homeserver = Keypair::random().public_key();
+        let token = AuthToken::sign_at(&keypair, &homeserver, "nonce", 1_000_000);
+
+        let verify = |token: &AuthToken| token.verify_at(&homeserver, 1_000_000);
+        assert_eq!(verify(&token).unwrap(), keypair.public_key());
+        assert!(matches!(
+            token.verify_at(&homeserver, 1_000_000 + AuthToken::MAX_CLOCK_SKEW_MS + 1),
+            Err(Error::ExpiredToken)
+        ));
+
+        // Tokens can't be replayed to another homeserver
+        let other = Keypair::random().public_key();
+        assert!(matches!(
+            token.verify_at(&other, 1_000_000),
+            Err(Error::WrongAudience)
+        ));
+        let mut forged = token.clone();
+        forged.audience = other.to_z32();
+        assert!(forged.verify_at(&other, 1_000_000).is_err());
+
+        // Nor moved to another time, nonce or signer
+        let mut forged = token.clone();
+        forged.timestamp += 1;
+        assert!(verify(&forged).is_err());
+
+        let mut forged = token.clone();
+        forged.nonce = "other".to_string();
+        assert!(verify(&forged).is_err());
+
+        let mut forged = token;
+        forged.public_key = Keypair::random().public_key().to_z32();
+        assert!(verify(&forged).is_err());
+
+        assert_ne!(random_nonce(), random_nonce());
+    }
+
+    #[test]
+    fn test_auth_grant() {
+        let keypair = Keypair::random();
+        let homeserver = Keypair::random().public_key();
+        let grant = AuthGrant::sign_at(
+            &keypair,
+            &homeserver,
+            "challenge",
+            "/pub/app/:rw",
+            1_000_000,
+        );
+        let verify = |grant: &AuthGrant| grant.verify_at(&homeserver, 1_000_000);
+        assert_eq!(verify(&grant).unwrap(), keypair.public_key());
+
+        // Grants can't be redeemed at another homeserver
+        let other = Keypair::random().public_key();
+        assert!(matches!(
+            grant.verify_at(&other, 1_000_000),
+            Err(Error::WrongAudience)
+        ));
+        let mut forged = grant.clone();
+        forged.audience = other.to_z32();
+        assert!(forged.verify_at(&other, 1_000_000).is_err());
+
+        // Nor widened or moved to another challenge
+        let mut forged = grant.clone();
+        forged.capabilities = "/:rw".to_string();
+        assert!(verify(&forged).is_err());
+        let mut forged = grant;
+        forged.challenge = "other".to_string();
+        assert!(verify(&forged).is_err());
+    }
+
+    #[test]
+    fn test_migration_intent() {
+        let keypair = Keypair::random();
+        let intent = MigrationIntent::sign_at(
+            &keypair,
+            "https://a.example",
+            "https://b.example",
+            1,
+        );
+        assert_eq!(intent.verify_at(1).unwrap(), keypair.public_key());
+
+        // Intents can't be redirected to another homeserver
+        let mut forged = intent;
+        forged.to = "https://evil.example".to_string();
+        assert!(forged.verify_at(1).is_err());
+    }
+
+    #[test]
+    fn test_write_signature() {
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let audience = Keypair::random().public_key();
+        let signature = WriteSignature::sign_at(
+            &keypair,
+            &audience,
+            "PUT",
+            "pub/a.txt",
+            b"data",
+            "nonce",
+            1_000_000,
+        );
+        let header: WriteSignature = signature.to_string().parse().unwrap();
+        assert_eq!(header, signature);
+        let verify = |signature: &WriteSignature, now| {
+            signature.verify_at(&public_key, &audience, "PUT", "pub/a.txt", b"data", now)
+        };
+        assert!(verify(&signature, 1_000_000).is_ok());
+        assert!(matches!(
+            verify(&signature, 2_000_000),
+            Err(Error::ExpiredToken)
+        ));
+
+        // Signatures cover the signer, homeserver, method, path and body
+        let other = Keypair::random().public_key();
+        for (public_key, audience, method, path, body) in [
+            (&other, &audience, "PUT", "pub/a.txt", &b"data"[..]),
+            (&public_key, &other, "PUT", "pub/a.txt", b"data"),
+            (&public_key, &audience, "DELETE", "pub/a.txt", b"data"),
+            (&public_key, &audience, "PUT", "pub/b.txt", b"data"),
+            (&public_key, &audience, "PUT", "pub/a.txt", b"evil"),
+        ] {
+            assert!(signature
+                .verify_at(public_key, audience, method, path, body, 1_000_000)
+                .is_err());
+        }
+
+        // And the nonce
+        let mut replayed = signature.clone();
+        replayed.nonce = "other".to_string();
+        assert!(verify(&replayed, 1_000_000).is_err());
+        assert!("1000000".parse::<WriteSignature>().is_err());
+        assert!("1000000.nonce".parse::<WriteSignature>().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Bulk blob transfer protocol
+//!
+//! Large entries can move between clients and homeservers over raw QUIC
+//! streams instead of HTTP. Connections negotiate the [`ALPN`] protocol,
+//! and their first stream authenticates the client with a
+//! [`ChannelBinding`]: its signature of keying material exported from the
+//! connection's TLS session, which can't be replayed on another connection.
+//!
+//! Every later stream carries one [`BlobRequest`] answered by one
+//! [`BlobResponse`]. Both are frames: a 4-byte big-endian length followed
+//! by JSON. The bytes of a chunk follow the frame of the request writing it
+//! or the response reading it, up to the end of the stream.
+//!
+//! Uploads send [`BlobRequest::Write`] chunks on parallel streams, each
+//! with its own SHA-256, then a [`BlobRequest::Commit`] with the hash of
+//! the whole entry. Downloads [`BlobRequest::Stat`] the entry, read its
+//! chunks in parallel and check them against its hash.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::dto::ErrorResponse;
+use crate::{Keypair, PublicKey, Result, Signature};
+
+/// ALPN protocol of blob transfer connections
+pub const ALPN: &[u8] = b"pubky-blob/1";
+
+/// Label of the keying material exported for the channel binding
+pub const EXPORTER_LABEL: &[u8] = b"EXPORTER-pubky-blob";
+
+/// Length of the exported keying material
+pub const KEYING_MATERIAL_LEN: usize = 32;
+
+/// Size of the chunks entries are split into
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Longest frame accepted
+pub const MAX_FRAME_LEN: u32 = 64 * 1024;
+
+/// Domain separator prepended to every signed channel binding
+const BINDING_NAMESPACE: &[u8] = b"PUBKY:BLOB:";
+
+/// Proof that the client of a connection holds a keypair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelBinding {
+    pub public_key: PublicKey,
+    pub signature: Signature,
+}
+
+impl ChannelBinding {
+    /// Length of the binding on the wire: the public key, then the signature
+    pub const LEN: usize = 96;
+
+    /// Sign the keying material exported from the connection
+    pub fn sign(keypair: &Keypair, keying_material: &[u8]) -> Self {
+        Self {
+            public_key: keypair.public_key(),
+            signature: keypair.sign(&message(keying_material)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[..32].copy_from_slice(&self.public_key.to_bytes());
+        bytes[32..].copy_from_slice(&self.signature.to_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; Self::LEN]) -> Result<Self> {
+        Ok(Self {
+            public_key: PublicKey::from_bytes(bytes[..32].try_into().unwrap())?,
+            signature: Signature::from_bytes(bytes[32..].try_into().unwrap()),
+        })
+    }
+
+    /// Verify the binding against the keying material exported on this
+    /// side of the connection, returning the client's public key
+    pub fn verify(&self, keying_material: &[u8]) -> Result<PublicKey> {
+        self.public_key
+            .verify(&message(keying_material), &self.signature)?;
+        Ok(self.public_key)
+    }
+}
+
+/// A request, the first frame of a stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BlobRequest {
+    /// The size and hash of an entry
+    Stat { public_key: String, path: String },
+    /// A range of an entry, sent after the response
+    Read {
+        public_key: String,
+        path: String,
+        offset: u64,
+        len: u64,
+    },
+    /// Upload a chunk of an entry of the client, sent after the request
+    Write {
+        path: String,
+        offset: u64,
+        /// Hex SHA-256 of the chunk
+        sha256: String,
+    },
+    /// Store the chunks uploaded for `path` as the entry, if they add up to
+    /// `size` bytes hashing to `sha256`
+    Commit {
+        path: String,
+        size: u64,
+        sha256: String,
+    },
+}
+
+/// The response to a [`BlobRequest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobResponse {
+    /// HTTP status the same request would get from the HTTP API
+    pub status: u16,
+    /// Size of the entry, for stats and reads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Hex SHA-256 of the entry for stats, or of the range for reads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+impl BlobResponse {
+    /// A successful response without details
+    pub fn ok() -> Self {
+        Self {
+            status: 200,
+            size: None,
+            sha256: None,
+            error: None,
+        }
+    }
+}
+
+/// Hex SHA-256 of `data`
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Offsets and lengths of the chunks of an entry of `size` bytes
+pub fn chunks(size: u64) -> impl Iterator<Item = (u64, u64)> {
+    (0..size)
+        .step_by(CHUNK_SIZE as usize)
+        .map(move |offset| (offset, CHUNK_SIZE.min(size - offset)))
+}
+
+/// The bytes signed for a channel binding
+fn message(keying_material: &[u8]) -> Vec<u8> {
+    let mut message = BINDING_NAMESPACE.to_vec();
+    message.extend_from_slice(keying_material);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_binding() {
+        let keypair = Keypair::random();
+        let binding = ChannelBinding::sign(&keypair, &[1; KEYING_MATERIAL_LEN]);
+        let parsed = ChannelBinding::from_bytes(&binding.to_bytes()).unwrap();
+        assert_eq!(
+            parsed.verify(&[1; KEYING_MATERIAL_LEN]).unwrap(),
+            keypair.public_key()
+        );
+        assert!(parsed.verify(&[2; KEYING_MATERIAL_LEN]).is_err());
+
+        let size = 2 * CHUNK_SIZE + 5;
+        let expected = vec![
+            (0, CHUNK_SIZE),
+            (CHUNK_SIZE, CHUNK_SIZE),
+            (2 * CHUNK_SIZE, 5),
+        ];
+        assert_eq!(chunks(size).collect::<Vec<_>>(), expected);
+        assert_eq!(chunks(0).count(), 0);
+    }
+}
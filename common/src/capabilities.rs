@@ -0,0 +1,172 @@
+//! Capabilities
+//!
+//! A capability grants access to part of an account: `/pub/my-app/:rw` lets
+//! its holder read and write the entries under `/pub/my-app/`, `/pub/feed:r`
+//! only read that one entry, and `/:rw` do anything. Several capabilities
+//! are separated by commas.
+//!
+//! The root key signs the capabilities it hands to an app in an
+//! [`AuthGrant`](crate::auth::AuthGrant), which the homeserver exchanges for
+//! a session limited to them, so the app never holds the keypair.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// What a request does to an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Read,
+    Write,
+}
+
+/// Access to the entries at or under one path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capability {
+    /// Absolute path, covering every path under it if it ends with `/`
+    pub scope: String,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Capability {
+    /// Read and write access to everything, `/:rw`
+    pub fn root() -> Self {
+        Self {
+            scope: "/".to_string(),
+            read: true,
+            write: true,
+        }
+    }
+
+    /// Whether the capability covers `path`, with or without its leading
+    /// `/`
+    pub fn covers(&self, path: &str) -> bool {
+        let path = path.trim_start_matches('/');
+        let scope = self.scope.trim_start_matches('/');
+        match self.scope.ends_with('/') {
+            true => path.starts_with(scope),
+            false => path == scope,
+        }
+    }
+
+    /// Whether the capability allows `action` on `path`
+    pub fn allows(&self, path: &str, action: Action) -> bool {
+        let permitted = match action {
+            Action::Read => self.read,
+            Action::Write => self.write,
+        };
+        permitted && self.covers(path)
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.scope)?;
+        if self.read {
+            f.write_str("r")?;
+        }
+        if self.write {
+            f.write_str("w")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidCapability(s.to_string());
+        let (scope, actions) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let (read, write) = match actions {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" => (true, true),
+            _ => return Err(invalid()),
+        };
+        let segments = scope.split('/').skip(1);
+        let dotted = segments
+            .clone()
+            .any(|segment| matches!(segment, "." | ".."));
+        if !scope.starts_with('/') || scope.contains(',') || dotted {
+            return Err(invalid());
+        }
+        Ok(Self {
+            scope: scope.to_string(),
+            read,
+            write,
+        })
+    }
+}
+
+/// Capabilities held together, allowing what any of them allows
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities(pub Vec<Capability>);
+
+impl Capabilities {
+    /// Whether any capability allows `action` on `path`
+    pub fn allows(&self, path: &str, action: Action) -> bool {
+        self.0.iter().any(|cap| cap.allows(path, action))
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, cap) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", cap)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Capabilities {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|cap| !cap.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let caps: Capabilities = "/pub/my-app/:rw, /pub/feed:r".parse().unwrap();
+        assert_eq!(caps.to_string(), "/pub/my-app/:rw,/pub/feed:r");
+        assert!(caps.allows("pub/my-app/notes.txt", Action::Write));
+        assert!(caps.allows("/pub/my-app/", Action::Read));
+        assert!(caps.allows("pub/feed", Action::Read));
+        assert!(!caps.allows("pub/feed", Action::Write));
+        assert!(!caps.allows("pub/feed/old", Action::Read));
+        assert!(!caps.allows("pub/my-app-2/notes.txt", Action::Read));
+        assert!(!caps.allows("pub/other.txt", Action::Read));
+
+        let root: Capabilities = "/:rw".parse().unwrap();
+        assert_eq!(root, Capabilities(vec![Capability::root()]));
+        assert!(root.allows("anything/at/all", Action::Write));
+        assert!(root.allows("", Action::Read));
+        assert!(!Capabilities::default().allows("pub/a.txt", Action::Read));
+
+        for invalid in [
+            "pub/app/:rw",
+            "/pub/app/",
+            "/pub/app/:x",
+            "/pub/:wr",
+            "/pub/../:rw",
+        ] {
+            assert!(invalid.parse::<Capabilities>().is_err(), "{}", invalid);
+        }
+    }
+}
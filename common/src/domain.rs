@@ -0,0 +1,132 @@
+//! Domain name proofs
+//!
+//! A user proves control of a domain such as `alice.com` by publishing a TXT
+//! record at `_pubky.alice.com` holding a [`DomainProof`]: their public key
+//! and its signature of the domain name. Publishing the record proves
+//! control of the domain, and the signature proves the key holder agrees to
+//! be known by it, so anyone can check the alias without trusting a server.
+//!
+//! The record reads `pk=<public key> sig=<signature>`, both z-base-32.
+
+use crate::{Error, Keypair, PublicKey, Result, Signature};
+
+/// Label prepended to a domain to name its proof record
+pub const RECORD_LABEL: &str = "_pubky";
+
+/// Domain separator prepended to every signed domain name
+const DOMAIN_NAMESPACE: &[u8] = b"PUBKY:DOMAIN:";
+
+/// A public key's claim to a domain name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainProof {
+    pub public_key: PublicKey,
+    pub signature: Signature,
+}
+
+impl DomainProof {
+    /// Sign a claim to `domain`
+    pub fn sign(keypair: &Keypair, domain: &str) -> Self {
+        Self {
+            public_key: keypair.public_key(),
+            signature: keypair.sign(&message(domain)),
+        }
+    }
+
+    /// Name of the TXT record holding the proof for `domain`
+    pub fn record_name(domain: &str) -> String {
+        format!("{}.{}", RECORD_LABEL, normalize(domain))
+    }
+
+    /// Text of the TXT record
+    pub fn to_txt(&self) -> String {
+        format!(
+            "pk={} sig={}",
+            self.public_key.to_z32(),
+            base32::encode(base32::Alphabet::Z, &self.signature.to_bytes())
+        )
+    }
+
+    /// Parse the text of a TXT record
+    pub fn from_txt(txt: &str) -> Result<Self> {
+        let mut public_key = None;
+        let mut signature = None;
+        for field in txt.split_whitespace() {
+            match field.split_once('=') {
+                Some(("pk", value)) => public_key = Some(PublicKey::from_z32(value)?),
+                Some(("sig", value)) => {
+                    let bytes = base32::decode(base32::Alphabet::Z, value)
+                        .ok_or(Error::InvalidSignature)?;
+                    let bytes: [u8; 64] = bytes.try_into().map_err(|_| Error::InvalidSignature)?;
+                    signature = Some(Signature::from_bytes(&bytes));
+                }
+                _ => {}
+            }
+        }
+
+        match (public_key, signature) {
+            (Some(public_key), Some(signature)) => Ok(Self {
+                public_key,
+                signature,
+            }),
+            _ => Err(Error::InvalidDomainProof(txt.to_string())),
+        }
+    }
+
+    /// Verify the proof for `domain`
+    pub fn verify(&self, domain: &str) -> Result<()> {
+        self.public_key.verify(&message(domain), &self.signature)
+    }
+}
+
+/// Lowercase `domain`, without a trailing dot
+pub fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether `domain` is a plausible host name: dot-separated labels of ASCII
+/// letters, digits and `-`, with at least two labels
+pub fn is_valid_domain(domain: &str) -> bool {
+    let domain = normalize(domain);
+    domain.len() <= 253
+        && domain.split('.').count() >= 2
+        && domain.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// The bytes signed for a domain
+fn message(domain: &str) -> Vec<u8> {
+    let mut message = DOMAIN_NAMESPACE.to_vec();
+    message.extend_from_slice(normalize(domain).as_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_proof() {
+        let keypair = Keypair::random();
+        let proof = DomainProof::sign(&keypair, "Alice.com.");
+        assert_eq!(DomainProof::record_name("Alice.com."), "_pubky.alice.com");
+
+        let txt = proof.to_txt();
+        assert!(txt.len() <= 255);
+        let parsed = DomainProof::from_txt(&txt).unwrap();
+        assert_eq!(parsed, proof);
+        parsed.verify("alice.com").unwrap();
+        assert!(parsed.verify("bob.com").is_err());
+        assert!(DomainProof::from_txt("v=spf1 -all").is_err());
+
+        assert!(is_valid_domain("alice.example.com"));
+        assert!(!is_valid_domain("localhost"));
+        assert!(!is_valid_domain("-bad.com"));
+        assert!(!is_valid_domain("a b.com"));
+    }
+}
@@ -0,0 +1,239 @@
+//! Data transfer objects shared by the server and clients
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::auth::AuthToken;
+use crate::version::VersionVector;
+
+/// Response body of a list request (`GET /{public_key}/{prefix}/`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListResponse {
+    /// Paths of the entries under the prefix
+    pub keys: Vec<String>,
+    /// Number of entries returned
+    pub count: usize,
+    /// Details of each entry, with `details=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<ListEntry>>,
+    /// Cursor for the next page, absent on the last one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// An entry in a list response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListEntry {
+    /// Path of the entry, or of a directory ending with `/` in shallow
+    /// listings
+    pub path: String,
+    /// Size in bytes, if known; directories have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// `Content-Type` the entry was written with, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// When the entry was last modified, in Unix milliseconds, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+    /// Hex SHA-256 hash of the value, as in its `ETag`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Tags of the entry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Custom metadata of the entry, by field name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+/// Body of an error response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    /// Human-readable error message
+    pub error: String,
+    /// Machine-readable error code, such as `not_found` or `conflict`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// For conflicts, the version the request expected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    /// For conflicts, the current version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actual: Option<String>,
+}
+
+impl ErrorResponse {
+    /// An error response with a code
+    pub fn new(code: &str, error: impl Into<String>) -> Self {
+        Self {
+            error: error.into(),
+            code: Some(code.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Request body of `POST /signup`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignupRequest {
+    #[serde(flatten)]
+    pub token: AuthToken,
+    /// Invite code, required when the homeserver only accepts invited users
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite_code: Option<String>,
+}
+
+/// A session on a homeserver, returned by signup and signin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// z-base-32 public key the session belongs to
+    pub public_key: String,
+    /// Bearer token identifying the session
+    pub token: String,
+    /// Unix timestamp in milliseconds when the session was created
+    pub created_at: u64,
+    /// Unix timestamp in milliseconds when the session expires
+    pub expires_at: u64,
+}
+
+/// Request body of `POST /auth/requests`, sent by a device asking to be
+/// authorized
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewAuthRequest {
+    /// Capabilities the device asks for, such as `/pub/my-app/:rw`
+    pub capabilities: String,
+}
+
+/// A pending authorization request, returned to the device that made it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthRequestInfo {
+    pub id: String,
+    /// Challenge to show to the approving device, for example as a QR code
+    pub challenge: String,
+    /// Secret the device redeems the session with; never displayed
+    pub secret: String,
+    pub capabilities: String,
+    /// Unix timestamp in milliseconds when the request expires
+    pub expires_at: u64,
+}
+
+/// Request body of `POST /auth/requests/{id}/session`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedeemAuthRequest {
+    pub secret: String,
+}
+
+/// A channel of an auth relay, returned to the app that opened it with
+/// `POST /relay/channels`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayChannel {
+    pub id: String,
+    /// Bearer token to receive the channel's message with; never displayed
+    pub secret: String,
+    /// Unix timestamp in milliseconds when the channel expires
+    pub expires_at: u64,
+}
+
+/// A session of an account, as listed by `GET /sessions`
+///
+/// Unlike [`SessionInfo`] it carries no token, only an id to revoke it
+/// with `DELETE /sessions/{id}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActiveSession {
+    pub id: String,
+    /// User agent of the device that signed in, if it sent one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Paths the session may access, such as `/:rw` for everything
+    pub capabilities: String,
+    /// Unix timestamp in milliseconds when the session was created
+    pub created_at: u64,
+    /// Unix timestamp in milliseconds when the session was last used
+    pub last_used_at: u64,
+    /// Unix timestamp in milliseconds when the session expires
+    pub expires_at: u64,
+    /// Whether this is the session making the request
+    #[serde(default)]
+    pub current: bool,
+}
+
+/// Entries of an account, listed by `POST /migrations/export` for the
+/// homeserver it moves to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationManifest {
+    pub public_key: String,
+    pub entries: Vec<MigrationEntry>,
+}
+
+/// An entry of a [`MigrationManifest`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationEntry {
+    pub path: String,
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the content
+    pub sha256: String,
+    /// `Content-Type` the entry was written with, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Custom metadata of the entry, by field name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+    /// Tags of the entry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// Outcome of `POST /migrations` on the homeserver an account moved to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationReport {
+    /// Number of entries copied
+    pub entries: usize,
+    /// Total size of the entries copied, in bytes
+    pub bytes: u64,
+}
+
+/// A version of an entry written concurrently with others, listed by
+/// `GET /{public_key}/{path}?siblings=true`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sibling {
+    pub version: VersionVector,
+    /// Base64-encoded content
+    pub value: String,
+}
+
+/// Kind of change to an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Put,
+    Delete,
+}
+
+/// A change to an entry, streamed by `GET /events/{public_key}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// Position in the homeserver's change feed, usable as a resume cursor
+    pub seq: u64,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    pub op: ChangeOp,
+    pub path: String,
+}
+
+/// Response body of a search (`GET /{public_key}/search?q=`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchResponse {
+    /// Matching entries, best first
+    pub results: Vec<SearchResult>,
+    /// Number of results returned
+    pub count: usize,
+}
+
+/// An entry matching a search
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub path: String,
+    /// Relevance of the entry; higher is better
+    pub score: f32,
+}
@@ -0,0 +1,136 @@
+//! Encryption of private data
+//!
+//! Keys are derived from a keypair's secret key with HKDF-SHA256, one per
+//! context (for example a path prefix), so no key material has to be stored
+//! besides the keypair. Data is sealed with XChaCha20-Poly1305 under a
+//! random nonce; the associated data binds a ciphertext to where it is
+//! stored, so it can't be moved to another path unnoticed.
+//!
+//! Sealed format: a version byte, the 24-byte nonce, then the ciphertext
+//! with its authentication tag.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, TryRngCore as _};
+use sha2::Sha256;
+
+use crate::{Error, Keypair, Result};
+
+/// Version byte of the sealed format
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+/// Domain separation for derived keys
+const INFO_PREFIX: &[u8] = b"pubky-mvp/encryption/";
+
+/// A symmetric key for sealing data
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: XChaCha20Poly1305,
+}
+
+impl EncryptionKey {
+    /// Derive the key for `context` from the keypair
+    ///
+    /// The same keypair and context always give the same key.
+    pub fn derive(keypair: &Keypair, context: &str) -> Self {
+        let info = [INFO_PREFIX, context.as_bytes()].concat();
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &keypair.secret_key())
+            .expand(&info, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    /// Use a random key shared out of band, such as the key of an
+    /// authorization URL
+    pub fn from_bytes(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    /// Encrypt `plaintext`, authenticating `aad` along with it
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng
+            .try_fill_bytes(&mut nonce)
+            .expect("OS random number generator failed");
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .expect("XChaCha20-Poly1305 encrypts any length");
+
+        let mut sealed = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        sealed.push(VERSION);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt data sealed with [`seal`](Self::seal) under the same `aad`
+    ///
+    /// Fails with [`Error::Decryption`] if the key or associated data are
+    /// wrong, or the data was tampered with.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let (&version, rest) = sealed
+            .split_first()
+            .ok_or_else(|| Error::Decryption("empty ciphertext".to_string()))?;
+        if version != VERSION {
+            return Err(Error::Decryption(format!("unknown version {}", version)));
+        }
+        if rest.len() < NONCE_LEN {
+            return Err(Error::Decryption("truncated ciphertext".to_string()));
+        }
+
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| Error::Decryption("wrong key or corrupted data".to_string()))
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let keypair = Keypair::random();
+        let key = EncryptionKey::derive(&keypair, "priv/");
+        let sealed = key.seal(b"secret", b"priv/a.txt");
+
+        assert_ne!(&sealed[1 + NONCE_LEN..], b"secret");
+        // Random nonces make every sealing different
+        assert_ne!(sealed, key.seal(b"secret", b"priv/a.txt"));
+        assert_eq!(key.open(&sealed, b"priv/a.txt").unwrap(), b"secret");
+
+        // Derivation is deterministic and separated by context
+        let again = EncryptionKey::derive(&keypair, "priv/");
+        assert_eq!(again.open(&sealed, b"priv/a.txt").unwrap(), b"secret");
+        let other = EncryptionKey::derive(&keypair, "notes/");
+        assert!(other.open(&sealed, b"priv/a.txt").is_err());
+
+        assert!(key.open(&sealed, b"priv/b.txt").is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.open(&tampered, b"priv/a.txt").is_err());
+        assert!(key.open(&sealed[..10], b"priv/a.txt").is_err());
+    }
+}
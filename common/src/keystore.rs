@@ -0,0 +1,164 @@
+//! Passphrase-protected keypairs
+//!
+//! Secret keys are encrypted with XChaCha20-Poly1305 under a key derived
+//! from the passphrase with Argon2id. The serialized form is safe to write
+//! to disk or copy between machines; it only reveals the public key.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::{rngs::OsRng, TryRngCore as _};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Keypair, PublicKey, Result};
+
+/// Argon2id cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub m_cost: u32,
+    /// Number of iterations
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    /// The OWASP recommended minimum: 19 MiB, 2 iterations
+    fn default() -> Self {
+        Self {
+            m_cost: 19 * 1024,
+            t_cost: 2,
+            p_cost: 1,
+        }
+    }
+}
+
+/// A keypair encrypted with a passphrase
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedKeypair {
+    /// z-base-32 public key, readable without the passphrase
+    pub public_key: String,
+    pub kdf: KdfParams,
+    /// z-base-32 Argon2 salt
+    pub salt: String,
+    /// z-base-32 XChaCha20-Poly1305 nonce
+    pub nonce: String,
+    /// z-base-32 encrypted secret key
+    pub ciphertext: String,
+}
+
+impl EncryptedKeypair {
+    /// Encrypt a keypair with the default KDF parameters
+    pub fn encrypt(keypair: &Keypair, passphrase: &str) -> Result<Self> {
+        Self::encrypt_with(keypair, passphrase, KdfParams::default())
+    }
+
+    /// Encrypt a keypair with the given KDF parameters
+    pub fn encrypt_with(keypair: &Keypair, passphrase: &str, kdf: KdfParams) -> Result<Self> {
+        let salt: [u8; 16] = random_bytes();
+        let nonce: [u8; 24] = random_bytes();
+        let cipher = cipher(passphrase, &salt, kdf)?;
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), &keypair.secret_key()[..])
+            .map_err(|_| Error::Keystore("encryption failed".to_string()))?;
+
+        Ok(Self {
+            public_key: keypair.public_key().to_z32(),
+            kdf,
+            salt: z32(&salt),
+            nonce: z32(&nonce),
+            ciphertext: z32(&ciphertext),
+        })
+    }
+
+    /// The public key of the encrypted keypair
+    pub fn public_key(&self) -> Result<PublicKey> {
+        PublicKey::from_z32(&self.public_key)
+    }
+
+    /// Decrypt the keypair
+    ///
+    /// Fails with [`Error::WrongPassphrase`] if the passphrase is wrong or
+    /// the data was tampered with.
+    pub fn decrypt(&self, passphrase: &str) -> Result<Keypair> {
+        let salt = from_z32(&self.salt)?;
+        let nonce: [u8; 24] = from_z32(&self.nonce)?
+            .try_into()
+            .map_err(|_| Error::Keystore("invalid nonce".to_string()))?;
+        let ciphertext = from_z32(&self.ciphertext)?;
+
+        let secret_key = cipher(passphrase, &salt, self.kdf)?
+            .decrypt(XNonce::from_slice(&nonce), &ciphertext[..])
+            .map_err(|_| Error::WrongPassphrase)?;
+        let secret_key: [u8; 32] = secret_key
+            .try_into()
+            .map_err(|_| Error::Keystore("invalid secret key length".to_string()))?;
+
+        let keypair = Keypair::from_secret_key(&secret_key);
+        if keypair.public_key().to_z32() != self.public_key {
+            return Err(Error::Keystore("public key mismatch".to_string()));
+        }
+        Ok(keypair)
+    }
+}
+
+/// Derive the encryption key from the passphrase
+fn cipher(passphrase: &str, salt: &[u8], kdf: KdfParams) -> Result<XChaCha20Poly1305> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| Error::Keystore(e.to_string()))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| Error::Keystore(e.to_string()))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng
+        .try_fill_bytes(&mut bytes)
+        .expect("OS random number generator failed");
+    bytes
+}
+
+fn z32(bytes: &[u8]) -> String {
+    base32::encode(base32::Alphabet::Z, bytes)
+}
+
+fn from_z32(s: &str) -> Result<Vec<u8>> {
+    base32::decode(base32::Alphabet::Z, s)
+        .ok_or_else(|| Error::Base32Error("Invalid base32".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters to keep tests fast
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_encrypted_keypair_round_trip() {
+        let keypair = Keypair::random();
+        let encrypted = EncryptedKeypair::encrypt_with(&keypair, "hunter2", TEST_KDF).unwrap();
+
+        assert_eq!(encrypted.public_key().unwrap(), keypair.public_key());
+        let decrypted = encrypted.decrypt("hunter2").unwrap();
+        assert_eq!(decrypted.secret_key(), keypair.secret_key());
+
+        assert!(matches!(
+            encrypted.decrypt("wrong"),
+            Err(Error::WrongPassphrase)
+        ));
+
+        // The public key can't be swapped without detection
+        let mut swapped = encrypted;
+        swapped.public_key = Keypair::random().public_key().to_z32();
+        assert!(swapped.decrypt("hunter2").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! Queries to the Mainline DHT (`dht` feature)
+//!
+//! The Mainline DHT used by BitTorrent speaks KRPC (BEP 5): bencoded
+//! queries and responses over UDP. A [`Node`] walks towards the nodes
+//! closest to a key, which is how homeservers announce content and how
+//! clients find the BEP 44 mutable items holding pkarr packets, see
+//! [`mutable_target`]. No routing table is kept and no queries are answered.
+
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::PublicKey;
+
+/// Nodes of the Mainline DHT lookups start from unless others are configured
+pub const DEFAULT_BOOTSTRAP: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Number of closest nodes a lookup ends with
+pub const K: usize = 8;
+
+/// Rounds of queries before a lookup settles on the closest nodes found
+const MAX_ROUNDS: usize = 8;
+
+/// How long to wait for the answers to a round of queries
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Deepest nesting of lists and dictionaries accepted in messages
+const MAX_DEPTH: usize = 16;
+
+/// A 160-bit DHT key, used both for node ids and targets
+pub type InfoHash = [u8; 20];
+
+/// Key of the BEP 44 mutable item signed by `public_key`, without salt
+pub fn mutable_target(public_key: &PublicKey) -> InfoHash {
+    Sha1::digest(public_key.to_bytes()).into()
+}
+
+/// XOR distance between two keys, which orders nodes by closeness
+pub fn distance(a: &InfoHash, b: &InfoHash) -> InfoHash {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// Resolve `host:port` addresses to the IPv4 addresses Mainline uses,
+/// skipping those that don't resolve
+pub async fn resolve(addresses: &[String]) -> Vec<SocketAddr> {
+    let mut resolved = Vec::new();
+    for address in addresses {
+        if let Ok(addrs) = tokio::net::lookup_host(address.as_str()).await {
+            resolved.extend(addrs.filter(SocketAddr::is_ipv4));
+        }
+    }
+    resolved
+}
+
+/// A DHT client sending queries from a single socket
+pub struct Node {
+    socket: UdpSocket,
+    id: InfoHash,
+    transaction: u16,
+}
+
+impl Node {
+    /// Bind a node with a random id to an ephemeral port
+    pub async fn bind() -> std::io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+            id: rand::random(),
+            transaction: 0,
+        })
+    }
+
+    /// Send `method` with `args` to nodes ever closer to `target`, starting
+    /// from `bootstrap`, and return the answers of the [`K`] closest that
+    /// answered, closest first
+    pub async fn walk(
+        &mut self,
+        method: &str,
+        target: &InfoHash,
+        args: Dict,
+        bootstrap: &[SocketAddr],
+    ) -> Vec<(SocketAddr, Answer)> {
+        let mut queried = HashSet::new();
+        let mut candidates = BTreeMap::new();
+        // Nodes that answered, by distance
+        let mut closest = BTreeMap::new();
+
+        let mut targets = bootstrap.to_vec();
+        for _ in 0..MAX_ROUNDS {
+            if targets.is_empty() {
+                break;
+            }
+            let queries = targets.iter().map(|addr| (*addr, args.clone())).collect();
+            let answers = self.round(method, queries).await;
+            queried.extend(targets);
+
+            for (addr, answer) in answers {
+                for (id, node) in answer.nodes() {
+                    if !queried.contains(&node) {
+                        candidates.insert(distance(&id, target), node);
+                    }
+                }
+                if let Some(id) = answer.id() {
+                    closest.insert(distance(&id, target), (addr, answer));
+                }
+            }
+
+            // Stop once no unqueried node is closer than the K closest found
+            let bound = closest.keys().nth(K - 1).copied();
+            targets = candidates
+                .iter()
+                .filter(|(d, node)| !queried.contains(*node) && bound.is_none_or(|b| **d < b))
+                .take(K)
+                .map(|(_, node)| *node)
+                .collect();
+        }
+
+        closest.into_values().take(K).collect()
+    }
+
+    /// Send a query to each node and collect the answers that arrive in
+    /// time
+    pub async fn round(
+        &mut self,
+        method: &str,
+        queries: Vec<(SocketAddr, Dict)>,
+    ) -> Vec<(SocketAddr, Answer)> {
+        let mut pending = HashMap::new();
+        for (addr, mut args) in queries {
+            self.transaction = self.transaction.wrapping_add(1);
+            let transaction = self.transaction.to_be_bytes().to_vec();
+            args.insert(b"id".to_vec(), Bencode::Bytes(self.id.to_vec()));
+            let query = Bencode::Dict(dict([
+                ("t", Bencode::Bytes(transaction.clone())),
+                ("y", Bencode::Bytes(b"q".to_vec())),
+                ("q", Bencode::Bytes(method.as_bytes().to_vec())),
+                ("a", Bencode::Dict(args)),
+            ]));
+            // Unreachable nodes are left out, as if they didn't answer
+            if self.socket.send_to(&query.encode(), addr).await.is_ok() {
+                pending.insert(transaction, addr);
+            }
+        }
+
+        let deadline = tokio::time::Instant::now() + QUERY_TIMEOUT;
+        let mut answers = Vec::new();
+        let mut buf = [0u8; 1500];
+        while !pending.is_empty() {
+            let Ok(Ok((len, from))) =
+                tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await
+            else {
+                break;
+            };
+            let Some(Bencode::Dict(mut message)) = Bencode::decode(&buf[..len]) else {
+                continue;
+            };
+            let Some(Bencode::Bytes(transaction)) = message.get(b"t".as_slice()) else {
+                continue;
+            };
+            if pending.get(transaction) != Some(&from) {
+                continue;
+            }
+            pending.remove(transaction);
+            if let Some(Bencode::Dict(answer)) = message.remove(b"r".as_slice()) {
+                answers.push((from, Answer(answer)));
+            }
+        }
+        answers
+    }
+}
+
+/// The `r` dictionary of a response, or the `a` dictionary of a query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer(pub Dict);
+
+impl Answer {
+    pub fn bytes(&self, key: &str) -> Option<&[u8]> {
+        match self.0.get(key.as_bytes()) {
+            Some(Bencode::Bytes(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn int(&self, key: &str) -> Option<i64> {
+        match self.0.get(key.as_bytes()) {
+            Some(Bencode::Int(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Id of the answering node
+    pub fn id(&self) -> Option<InfoHash> {
+        self.bytes("id")?.try_into().ok()
+    }
+
+    /// Nodes closer to the target, in compact form: a 20 byte id, a 4 byte
+    /// IPv4 address and a 2 byte port each
+    pub fn nodes(&self) -> Vec<(InfoHash, SocketAddr)> {
+        self.bytes("nodes")
+            .unwrap_or_default()
+            .chunks_exact(26)
+            .map(|node| {
+                let id = node[..20].try_into().unwrap();
+                let ip = Ipv4Addr::new(node[20], node[21], node[22], node[23]);
+                let port = u16::from_be_bytes([node[24], node[25]]);
+                (id, SocketAddr::V4(SocketAddrV4::new(ip, port)))
+            })
+            .filter(|(_, addr)| addr.port() != 0)
+            .collect()
+    }
+}
+
+/// A bencoded dictionary, with its keys in order
+pub type Dict = BTreeMap<Vec<u8>, Bencode>;
+
+/// Build a dictionary from string keys
+pub fn dict<const N: usize>(entries: [(&str, Bencode); N]) -> Dict {
+    entries
+        .into_iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value))
+        .collect()
+}
+
+/// A bencoded value, the encoding of KRPC messages
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(Dict),
+}
+
+impl Bencode {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Int(n) => out.extend_from_slice(format!("i{}e", n).as_bytes()),
+            Self::Bytes(bytes) => {
+                out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Self::List(items) => {
+                out.push(b'l');
+                items.iter().for_each(|item| item.encode_into(out));
+                out.push(b'e');
+            }
+            // Keys are sorted, as bencoding requires
+            Self::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    Self::Bytes(key.clone()).encode_into(out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    /// Decode a whole message
+    pub fn decode(mut input: &[u8]) -> Option<Self> {
+        let value = Self::parse(&mut input, 0)?;
+        input.is_empty().then_some(value)
+    }
+
+    fn parse(input: &mut &[u8], depth: usize) -> Option<Self> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let (&first, rest) = input.split_first()?;
+        match first {
+            b'i' => {
+                let end = rest.iter().position(|&b| b == b'e')?;
+                let n = std::str::from_utf8(&rest[..end]).ok()?.parse().ok()?;
+                *input = &rest[end + 1..];
+                Some(Self::Int(n))
+            }
+            b'l' | b'd' => {
+                *input = rest;
+                let mut items = Vec::new();
+                while input.first()? != &b'e' {
+                    items.push(Self::parse(input, depth + 1)?);
+                }
+                *input = &input[1..];
+                if first == b'l' {
+                    return Some(Self::List(items));
+                }
+                let mut dict = Dict::new();
+                let mut items = items.into_iter();
+                while let Some(key) = items.next() {
+                    let (Self::Bytes(key), Some(value)) = (key, items.next()) else {
+                        return None;
+                    };
+                    dict.insert(key, value);
+                }
+                Some(Self::Dict(dict))
+            }
+            b'0'..=b'9' => {
+                let colon = input.iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&input[..colon]).ok()?.parse().ok()?;
+                let bytes = input.get(colon + 1..colon + 1 + len)?.to_vec();
+                *input = &input[colon + 1 + len..];
+                Some(Self::Bytes(bytes))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bencode() {
+        let encoded = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
+        let message = Bencode::decode(encoded).unwrap();
+        assert_eq!(message.encode(), encoded);
+        assert!(Bencode::decode(b"d1:ai1ee").is_some());
+        assert!(Bencode::decode(b"d1:ai1e").is_none());
+        assert!(Bencode::decode(b"4:abc").is_none());
+        let nested = format!("{}{}", "l".repeat(MAX_DEPTH + 2), "e".repeat(MAX_DEPTH + 2));
+        assert!(Bencode::decode(nested.as_bytes()).is_none());
+    }
+}
@@ -4,9 +4,44 @@
 //! - Keypair generation and management
 //! - Public key serialization
 //! - Signature creation and verification
+//! - Signed authentication tokens
+//! - Capabilities limiting sessions to parts of an account
+//! - Passphrase-protected keypair storage
+//! - Encryption of private data with keys derived from a keypair
+//! - Signed DNS packets announcing homeservers (pkarr)
+//! - Signed TXT records aliasing domain names to public keys
+//! - Queries to the Mainline DHT (`dht` feature)
+//! - Range-based set reconciliation between replicas
+//! - The QUIC bulk blob transfer protocol
+//! - Version vectors for detecting concurrent writes
+//! - Metadata tags of entries
+//! - Custom metadata of entries
+//! - `pubky://` URLs addressing entries by public key
+//! - Nostr HTTP authentication with secp256k1 keys (`multi-alg` feature)
+//! - Request and response types shared by the server and clients
+//! - Golden test vectors of the wire formats
+
+pub mod auth;
+pub mod blob;
+pub mod capabilities;
+pub mod domain;
+pub mod dto;
+pub mod encryption;
+pub mod keystore;
+#[cfg(feature = "dht")]
+pub mod krpc;
+pub mod meta;
+#[cfg(feature = "multi-alg")]
+pub mod nostr;
+pub mod pkarr;
+pub mod reconcile;
+pub mod tags;
+pub mod test_vectors;
+pub mod url;
+pub mod version;
 
 use ed25519_dalek::{SigningKey, VerifyingKey, Signer as _, Verifier as _};
-use rand::rngs::OsRng;
+use rand::{rngs::OsRng, TryRngCore as _};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -20,8 +55,41 @@ pub enum Error {
     #[error("Invalid signature")]
     InvalidSignature,
     
+    #[error("Authentication token has expired")]
+    ExpiredToken,
+    
+    #[error("Authentication token is for another homeserver")]
+    WrongAudience,
+    
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+    
+    #[error("Keystore error: {0}")]
+    Keystore(String),
+    
+    #[error("Decryption failed: {0}")]
+    Decryption(String),
+    
     #[error("Base32 decode error: {0}")]
     Base32Error(String),
+
+    #[error("Invalid pkarr packet: {0}")]
+    InvalidPacket(String),
+
+    #[error("Invalid version vector: {0}")]
+    InvalidVersion(String),
+
+    #[error("Invalid Nostr event: {0}")]
+    InvalidNostrEvent(String),
+
+    #[error("Invalid domain proof: {0}")]
+    InvalidDomainProof(String),
+
+    #[error("Invalid capability: {0}")]
+    InvalidCapability(String),
+
+    #[error("Invalid path: {0}")]
+    InvalidPath(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -35,10 +103,26 @@ pub struct Keypair {
 impl Keypair {
     /// Generate a new random keypair
     pub fn random() -> Self {
-        let signing_key = SigningKey::generate(&mut OsRng);
+        let mut secret_key = [0u8; 32];
+        OsRng
+            .try_fill_bytes(&mut secret_key)
+            .expect("OS random number generator failed");
+        let signing_key = SigningKey::from_bytes(&secret_key);
         Self { signing_key }
     }
     
+    /// Create a keypair from a 32-byte secret key
+    pub fn from_secret_key(secret_key: &[u8; 32]) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(secret_key),
+        }
+    }
+
+    /// Get the 32-byte secret key
+    pub fn secret_key(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
     /// Get the public key
     pub fn public_key(&self) -> PublicKey {
         PublicKey {
@@ -140,6 +224,14 @@ mod tests {
         assert!(public_key.verify(wrong_message, &signature).is_err());
     }
     
+    #[test]
+    fn test_keypair_from_secret_key() {
+        let keypair = Keypair::random();
+        let restored = Keypair::from_secret_key(&keypair.secret_key());
+
+        assert_eq!(keypair.public_key(), restored.public_key());
+    }
+
     #[test]
     fn test_public_key_encoding() {
         let keypair = Keypair::random();
@@ -162,4 +254,52 @@ mod tests {
         
         assert_eq!(public_key, restored);
     }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_z32_round_trip(secret in proptest::prelude::any::<[u8; 32]>()) {
+            let public_key = Keypair::from_secret_key(&secret).public_key();
+            let encoded = public_key.to_z32();
+
+            proptest::prop_assert_eq!(encoded.len(), 52);
+            proptest::prop_assert_eq!(PublicKey::from_z32(&encoded).unwrap(), public_key);
+        }
+
+        #[test]
+        fn test_from_z32_arbitrary_input(
+            input in proptest::prop_oneof!["\\PC{0,64}", "[ybndrfg8ejkmcpqxot1uwisza345h769]{52}"],
+        ) {
+            // Never panics, and whatever decodes encodes back to the same key
+            if let Ok(public_key) = PublicKey::from_z32(&input) {
+                proptest::prop_assert_eq!(PublicKey::from_z32(&public_key.to_z32()).unwrap(), public_key);
+            }
+        }
+
+        #[test]
+        fn test_sign_verify_symmetry(
+            secret in proptest::prelude::any::<[u8; 32]>(),
+            other in proptest::prelude::any::<[u8; 32]>(),
+            message in proptest::collection::vec(proptest::prelude::any::<u8>(), 0..1024),
+            flip in proptest::prelude::any::<usize>(),
+        ) {
+            let keypair = Keypair::from_secret_key(&secret);
+            let signature = keypair.sign(&message);
+            proptest::prop_assert!(keypair.public_key().verify(&message, &signature).is_ok());
+
+            // Any changed byte invalidates the signature
+            if !message.is_empty() {
+                let mut tampered = message.clone();
+                tampered[flip % message.len()] ^= 1 << (flip % 8);
+                proptest::prop_assert!(keypair.public_key().verify(&tampered, &signature).is_err());
+            }
+
+            // And so does any other key
+            if other != secret {
+                let other = Keypair::from_secret_key(&other).public_key();
+                proptest::prop_assert!(other.verify(&message, &signature).is_err());
+            }
+        }
+    }
 }
@@ -0,0 +1,69 @@
+//! Custom metadata
+//!
+//! Entries can carry named metadata of the app's own, such as the original
+//! filename or the parameters of client-side encryption, given with one
+//! `X-Pubky-Meta-{name}` header per field when they are written. The
+//! homeserver returns the fields as the same headers on reads, and in
+//! detailed listings.
+//!
+//! Like tags, metadata is stored as sent, in plain text, even on encrypted
+//! entries, and belongs to the value it was written with.
+
+/// Prefix of the headers carrying metadata fields, followed by their name
+pub const META_HEADER_PREFIX: &str = "x-pubky-meta-";
+
+/// Most metadata fields an entry can carry
+pub const MAX_META_FIELDS: usize = 16;
+
+/// Most bytes of metadata an entry can carry, names and values included
+pub const MAX_META_LEN: usize = 4096;
+
+/// Header carrying the metadata field `name`
+pub fn meta_header(name: &str) -> String {
+    format!("{}{}", META_HEADER_PREFIX, name)
+}
+
+/// Name of the metadata field carried by the header `header`, if it
+/// carries a valid one
+pub fn meta_name(header: &str) -> Option<&str> {
+    header
+        .strip_prefix(META_HEADER_PREFIX)
+        .filter(|name| is_valid_meta_name(name))
+}
+
+/// Whether `name` is a valid name of a metadata field
+///
+/// Names are lowercase ASCII letters, digits, `-`, `_` and `.`, as header
+/// names are case-insensitive.
+pub fn is_valid_meta_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.'))
+}
+
+/// Whether `value` is a valid value of a metadata field: visible ASCII
+/// characters and spaces, as fit in a header
+pub fn is_valid_meta_value(value: &str) -> bool {
+    value.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meta() {
+        assert_eq!(meta_header("filename"), "x-pubky-meta-filename");
+        assert_eq!(meta_name("x-pubky-meta-filename"), Some("filename"));
+        assert_eq!(meta_name("x-pubky-meta-enc.nonce"), Some("enc.nonce"));
+        assert_eq!(meta_name("x-pubky-meta-"), None);
+        assert_eq!(meta_name("x-pubky-meta-File"), None);
+        assert_eq!(meta_name("x-pubky-tag"), None);
+
+        assert!(is_valid_meta_value("holiday photo.jpg"));
+        assert!(is_valid_meta_value(""));
+        assert!(!is_valid_meta_value("Zürich.jpg"));
+        assert!(!is_valid_meta_value("a\tb"));
+    }
+}
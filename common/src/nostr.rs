@@ -0,0 +1,262 @@
+//! Nostr HTTP authentication (NIP-98)
+//!
+//! Nostr users hold secp256k1 keys rather than Ed25519 ones. Instead of an
+//! [`AuthToken`](crate::auth::AuthToken), they can authenticate a single
+//! HTTP request with a signed event of kind [`HTTP_AUTH_KIND`], whose tags
+//! name the request's URL and method, sent as `Authorization: Nostr
+//! <base64 event>`. A homeserver accepts such events for accounts that
+//! linked the Nostr identity beforehand.
+//!
+//! Events are identified and signed as described by NIP-01: the id is the
+//! SHA-256 of the serialized event, signed with BIP-340 Schnorr.
+
+use k256::schnorr::{Signature, SigningKey, VerifyingKey};
+use rand::{rngs::OsRng, TryRngCore as _};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Error, Result};
+
+/// Kind of the events authenticating HTTP requests
+pub const HTTP_AUTH_KIND: u32 = 27235;
+
+/// Largest difference in seconds between an event's `created_at` and the
+/// time it is checked
+pub const MAX_CLOCK_SKEW: u64 = 60;
+
+/// secp256k1 keypair of a Nostr identity
+#[derive(Clone)]
+pub struct NostrKeypair {
+    signing_key: SigningKey,
+}
+
+impl NostrKeypair {
+    /// Generate a new random keypair
+    pub fn random() -> Self {
+        loop {
+            let mut secret_key = [0u8; 32];
+            OsRng
+                .try_fill_bytes(&mut secret_key)
+                .expect("OS random number generator failed");
+            if let Ok(keypair) = Self::from_secret_key(&secret_key) {
+                return keypair;
+            }
+        }
+    }
+
+    /// Create a keypair from a 32-byte secret key
+    pub fn from_secret_key(secret_key: &[u8; 32]) -> Result<Self> {
+        let signing_key = SigningKey::from_bytes(secret_key)
+            .map_err(|_| Error::InvalidNostrEvent("invalid secret key".to_string()))?;
+        Ok(Self { signing_key })
+    }
+
+    /// Hex-encoded x-only public key, as used by Nostr events
+    pub fn public_key(&self) -> String {
+        hex(&self.signing_key.verifying_key().to_bytes())
+    }
+}
+
+/// A signed Nostr event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NostrEvent {
+    /// Hex-encoded SHA-256 of the serialized event
+    pub id: String,
+    /// Hex-encoded x-only public key of the signer
+    pub pubkey: String,
+    /// Unix timestamp in seconds
+    pub created_at: u64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    /// Hex-encoded Schnorr signature of the id
+    pub sig: String,
+}
+
+impl NostrEvent {
+    /// Sign an event with `keypair`
+    pub fn sign(
+        keypair: &NostrKeypair,
+        created_at: u64,
+        kind: u32,
+        tags: Vec<Vec<String>>,
+        content: String,
+    ) -> Self {
+        let mut event = Self {
+            id: String::new(),
+            pubkey: keypair.public_key(),
+            created_at,
+            kind,
+            tags,
+            content,
+            sig: String::new(),
+        };
+        let id = event.hash();
+        let signature = keypair
+            .signing_key
+            .sign_raw(&id, &rand::random())
+            .expect("signing a 32-byte digest can't fail");
+        event.id = hex(&id);
+        event.sig = hex(&signature.to_bytes());
+        event
+    }
+
+    /// Sign an event authenticating a `method` request to `url`, tagged with
+    /// the SHA-256 of `payload` if the request has a body
+    pub fn http_auth(
+        keypair: &NostrKeypair,
+        url: &str,
+        method: &str,
+        payload: Option<&[u8]>,
+    ) -> Self {
+        let mut tags = vec![
+            vec!["u".to_string(), url.to_string()],
+            vec!["method".to_string(), method.to_uppercase()],
+        ];
+        if let Some(payload) = payload {
+            tags.push(vec!["payload".to_string(), hex(&Sha256::digest(payload))]);
+        }
+        Self::sign(keypair, now_secs(), HTTP_AUTH_KIND, tags, String::new())
+    }
+
+    /// Value of the first tag named `name`
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().map(String::as_str) == Some(name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+
+    /// Verify the id and signature of the event
+    pub fn verify(&self) -> Result<()> {
+        if decode_hex(&self.id).as_deref() != Some(&self.hash()[..]) {
+            return Err(Error::InvalidNostrEvent("id mismatch".to_string()));
+        }
+        let key = decode_hex(&self.pubkey)
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| Error::InvalidNostrEvent("invalid pubkey".to_string()))?;
+        let signature = decode_hex(&self.sig)
+            .and_then(|bytes| Signature::try_from(bytes.as_slice()).ok())
+            .ok_or(Error::InvalidSignature)?;
+        key.verify_raw(&self.hash(), &signature)
+            .map_err(|_| Error::InvalidSignature)
+    }
+
+    /// Verify that the event authenticates a `method` request to `url` with
+    /// the given body, returning the signer's public key
+    ///
+    /// `url` is compared without its scheme, since homeservers are often
+    /// behind a proxy terminating TLS. The `payload` tag is only checked
+    /// when the event has one.
+    pub fn verify_http_auth(&self, url: &str, method: &str, payload: &[u8]) -> Result<&str> {
+        let invalid = |reason: &str| Err(Error::InvalidNostrEvent(reason.to_string()));
+        if self.kind != HTTP_AUTH_KIND {
+            return invalid("wrong kind");
+        }
+        if now_secs().abs_diff(self.created_at) > MAX_CLOCK_SKEW {
+            return Err(Error::ExpiredToken);
+        }
+        if self.tag("u").map(strip_scheme) != Some(strip_scheme(url)) {
+            return invalid("wrong url");
+        }
+        if !self
+            .tag("method")
+            .is_some_and(|m| m.eq_ignore_ascii_case(method))
+        {
+            return invalid("wrong method");
+        }
+        if let Some(hash) = self.tag("payload") {
+            if !hash.eq_ignore_ascii_case(&hex(&Sha256::digest(payload))) {
+                return invalid("wrong payload");
+            }
+        }
+        self.verify()?;
+        Ok(&self.pubkey)
+    }
+
+    /// SHA-256 of `[0, pubkey, created_at, kind, tags, content]`
+    fn hash(&self) -> [u8; 32] {
+        let serialized = serde_json::json!([
+            0,
+            self.pubkey,
+            self.created_at,
+            self.kind,
+            self.tags,
+            self.content
+        ]);
+        Sha256::digest(serialized.to_string().as_bytes()).into()
+    }
+}
+
+fn strip_scheme(url: &str) -> &str {
+    url.split_once("://").map_or(url, |(_, rest)| rest)
+}
+
+fn now_secs() -> u64 {
+    web_time::SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_auth() {
+        let keypair = NostrKeypair::random();
+        let url = "https://example.com/nostr/session";
+        let event = NostrEvent::http_auth(&keypair, url, "post", Some(b"{}"));
+        assert_eq!(event.pubkey.len(), 64);
+        assert_eq!(event.tag("method"), Some("POST"));
+        assert_eq!(
+            event
+                .verify_http_auth("http://example.com/nostr/session", "POST", b"{}")
+                .unwrap(),
+            keypair.public_key()
+        );
+
+        // The event only authenticates the request it was signed for
+        assert!(event
+            .verify_http_auth("https://example.com/other", "POST", b"{}")
+            .is_err());
+        assert!(event.verify_http_auth(url, "GET", b"{}").is_err());
+        assert!(event.verify_http_auth(url, "POST", b"[]").is_err());
+
+        let mut tampered = event.clone();
+        tampered.content = "changed".to_string();
+        assert!(tampered.verify().is_err());
+        let mut forged = NostrEvent::http_auth(&NostrKeypair::random(), url, "POST", None);
+        forged.sig = event.sig.clone();
+        assert!(forged.verify().is_err());
+
+        let stale = NostrEvent::sign(
+            &keypair,
+            now_secs() - 2 * MAX_CLOCK_SKEW,
+            HTTP_AUTH_KIND,
+            event.tags.clone(),
+            String::new(),
+        );
+        stale.verify().unwrap();
+        assert!(matches!(
+            stale.verify_http_auth(url, "POST", b"{}"),
+            Err(Error::ExpiredToken)
+        ));
+    }
+}
@@ -0,0 +1,538 @@
+//! Signed DNS packets for public-key addressing (pkarr)
+//!
+//! A [`SignedPacket`] holds DNS records published under a public key,
+//! signed with its keypair the way BEP44 mutable items are, so it can be
+//! stored on the Mainline DHT and served by any pkarr relay. Record names
+//! are relative to the public key: `.` is the key itself, `_pubky` is
+//! `_pubky.<public_key>`.
+//!
+//! Homeservers publish an `HTTPS` record for `.` pointing at their
+//! endpoint; users publish an `SVCB` record for `_pubky` pointing at their
+//! homeserver's public key.
+
+use std::future::Future;
+use std::net::IpAddr;
+use web_time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Error, Keypair, PublicKey, Result, Signature};
+
+/// Largest DNS packet a BEP44 item can hold
+pub const MAX_PACKET_SIZE: usize = 1000;
+
+/// Name of the record pointing at a user's homeserver
+pub const PUBKY_RECORD: &str = "_pubky";
+
+/// Header of signup responses naming the public key the homeserver
+/// announces itself under, so clients can publish a `_pubky` record for it
+pub const HOMESERVER_HEADER: &str = "x-pubky-homeserver";
+
+/// Public pkarr relays, which store packets on the Mainline DHT
+pub const DEFAULT_RELAYS: &[&str] = &["https://relay.pkarr.org", "https://pkarr.pubky.org"];
+
+const TYPE_TXT: u16 = 16;
+const TYPE_SVCB: u16 = 64;
+const TYPE_HTTPS: u16 = 65;
+const CLASS_IN: u16 = 1;
+/// SvcParamKey of the port
+const PARAM_PORT: u16 = 3;
+
+/// A DNS record of a [`SignedPacket`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Name relative to the public key, `.` for the key itself
+    pub name: String,
+    /// Seconds the record may be cached
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+/// Data of the record types used by Pubky
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    /// Service binding to `target`, or to the owner name if `.`
+    Svcb {
+        priority: u16,
+        target: String,
+        port: Option<u16>,
+    },
+    /// Like [`Svcb`](Self::Svcb), for an HTTPS endpoint
+    Https {
+        priority: u16,
+        target: String,
+        port: Option<u16>,
+    },
+    Txt(String),
+}
+
+impl Record {
+    pub fn new(name: impl Into<String>, ttl: u32, data: RecordData) -> Self {
+        Self {
+            name: name.into(),
+            ttl,
+            data,
+        }
+    }
+}
+
+/// DNS records signed by the public key they are published under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedPacket {
+    public_key: PublicKey,
+    /// Unix timestamp in microseconds, the BEP44 sequence number
+    timestamp: u64,
+    records: Vec<Record>,
+    signature: Signature,
+    /// Encoded DNS packet
+    packet: Vec<u8>,
+}
+
+impl SignedPacket {
+    /// Sign `records` with the current time as timestamp
+    pub fn sign(keypair: &Keypair, records: Vec<Record>) -> Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        Self::sign_at(keypair, records, timestamp)
+    }
+
+    /// Sign `records` with the given timestamp in microseconds
+    pub fn sign_at(keypair: &Keypair, records: Vec<Record>, timestamp: u64) -> Result<Self> {
+        let public_key = keypair.public_key();
+        let packet = encode_packet(&public_key, &records)?;
+        let signature = keypair.sign(&signable(timestamp, &packet));
+        Ok(Self {
+            public_key,
+            timestamp,
+            records,
+            signature,
+            packet,
+        })
+    }
+
+    /// Parse and verify a packet in the pkarr relay format: the 64-byte
+    /// signature, the 8-byte big-endian timestamp, then the DNS packet
+    pub fn from_relay_payload(public_key: &PublicKey, payload: &[u8]) -> Result<Self> {
+        if payload.len() < 72 {
+            return Err(Error::InvalidPacket("payload too short".to_string()));
+        }
+        let signature: [u8; 64] = payload[..64].try_into().expect("64 bytes");
+        let signature = Signature::from_bytes(&signature);
+        let timestamp = u64::from_be_bytes(payload[64..72].try_into().expect("8 bytes"));
+        let packet = payload[72..].to_vec();
+        if packet.len() > MAX_PACKET_SIZE {
+            return Err(Error::InvalidPacket("packet too large".to_string()));
+        }
+
+        public_key.verify(&signable(timestamp, &packet), &signature)?;
+        let records = decode_packet(public_key, &packet)?;
+        Ok(Self {
+            public_key: *public_key,
+            timestamp,
+            records,
+            signature,
+            packet,
+        })
+    }
+
+    /// Encode in the pkarr relay format
+    pub fn to_relay_payload(&self) -> Vec<u8> {
+        let mut payload = self.signature.to_bytes().to_vec();
+        payload.extend_from_slice(&self.timestamp.to_be_bytes());
+        payload.extend_from_slice(&self.packet);
+        payload
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// Unix timestamp in microseconds when the packet was signed
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Records with the given relative name
+    pub fn records_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Record> {
+        self.records
+            .iter()
+            .filter(move |record| record.name == name)
+    }
+}
+
+/// Follow the records of `public_key` to the base URL of its homeserver,
+/// fetching packets with `fetch`
+///
+/// A user's `_pubky` record points at their homeserver's public key, whose
+/// `HTTPS` record points at its endpoint; a homeserver's own key resolves
+/// to its endpoint directly. Returns the URL with the shortest TTL of the
+/// records used, in seconds.
+pub async fn resolve_homeserver<F, Fut, E>(
+    public_key: &PublicKey,
+    fetch: F,
+) -> std::result::Result<Option<(String, u32)>, E>
+where
+    F: Fn(PublicKey) -> Fut,
+    Fut: Future<Output = std::result::Result<Option<SignedPacket>, E>>,
+{
+    let Some(packet) = fetch(*public_key).await? else {
+        return Ok(None);
+    };
+    let Some(record) = packet.records_named(PUBKY_RECORD).next() else {
+        return Ok(endpoint(&packet));
+    };
+    let Some((target, port)) = service(record) else {
+        return Ok(None);
+    };
+    let Ok(homeserver) = PublicKey::from_z32(target) else {
+        return Ok(Some((endpoint_url(target, port), record.ttl)));
+    };
+    let Some(packet) = fetch(homeserver).await? else {
+        return Ok(None);
+    };
+    Ok(endpoint(&packet).map(|(url, ttl)| (url, ttl.min(record.ttl))))
+}
+
+/// Base URL of an endpoint; local hosts are served over plain HTTP
+pub fn endpoint_url(host: &str, port: Option<u16>) -> String {
+    let ip = host.parse::<IpAddr>().ok();
+    let local = host == "localhost" || ip.is_some_and(|ip| ip.is_loopback());
+    let scheme = if local { "http" } else { "https" };
+    let host = match ip {
+        Some(IpAddr::V6(_)) => format!("[{}]", host),
+        _ => host.to_string(),
+    };
+    match port {
+        Some(port) if !(scheme == "https" && port == 443) => {
+            format!("{}://{}:{}", scheme, host, port)
+        }
+        _ => format!("{}://{}", scheme, host),
+    }
+}
+
+/// Target and port of a service binding record
+fn service(record: &Record) -> Option<(&str, Option<u16>)> {
+    match &record.data {
+        RecordData::Svcb { target, port, .. } | RecordData::Https { target, port, .. }
+            if target != "." =>
+        {
+            Some((target, *port))
+        }
+        _ => None,
+    }
+}
+
+/// URL and TTL of the endpoint a homeserver's packet announces
+fn endpoint(packet: &SignedPacket) -> Option<(String, u32)> {
+    packet.records_named(".").find_map(|record| {
+        let (target, port) = service(record)?;
+        Some((endpoint_url(target, port), record.ttl))
+    })
+}
+
+/// The bytes signed for a packet, as for a BEP44 mutable item
+fn signable(timestamp: u64, packet: &[u8]) -> Vec<u8> {
+    let mut signable = format!("3:seqi{}e1:v{}:", timestamp, packet.len()).into_bytes();
+    signable.extend_from_slice(packet);
+    signable
+}
+
+fn encode_packet(public_key: &PublicKey, records: &[Record]) -> Result<Vec<u8>> {
+    let count = u16::try_from(records.len())
+        .map_err(|_| Error::InvalidPacket("too many records".to_string()))?;
+    // Response, authoritative, no questions
+    let mut packet = vec![0, 0, 0x84, 0, 0, 0];
+    packet.extend_from_slice(&count.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+
+    let origin = public_key.to_z32();
+    for record in records {
+        encode_name(&mut packet, &absolute(&record.name, &origin))?;
+        let (kind, rdata) = match &record.data {
+            RecordData::Svcb {
+                priority,
+                target,
+                port,
+            } => (TYPE_SVCB, encode_svcb(*priority, target, *port)?),
+            RecordData::Https {
+                priority,
+                target,
+                port,
+            } => (TYPE_HTTPS, encode_svcb(*priority, target, *port)?),
+            RecordData::Txt(text) => (TYPE_TXT, encode_txt(text)),
+        };
+        packet.extend_from_slice(&kind.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&record.ttl.to_be_bytes());
+        packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&rdata);
+    }
+
+    if packet.len() > MAX_PACKET_SIZE {
+        return Err(Error::InvalidPacket("packet too large".to_string()));
+    }
+    Ok(packet)
+}
+
+fn decode_packet(public_key: &PublicKey, packet: &[u8]) -> Result<Vec<Record>> {
+    let mut reader = Reader { packet, pos: 0 };
+    reader.take(4)?;
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    reader.take(4)?;
+    for _ in 0..questions {
+        reader.name()?;
+        reader.take(4)?;
+    }
+
+    let origin = public_key.to_z32();
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        reader.u16()?;
+        let ttl = reader.u32()?;
+        let length = reader.u16()? as usize;
+        let end = reader.pos + length;
+        let data = match kind {
+            TYPE_SVCB | TYPE_HTTPS => {
+                let (priority, target, port) = reader.svcb(end)?;
+                match kind {
+                    TYPE_SVCB => RecordData::Svcb {
+                        priority,
+                        target,
+                        port,
+                    },
+                    _ => RecordData::Https {
+                        priority,
+                        target,
+                        port,
+                    },
+                }
+            }
+            TYPE_TXT => RecordData::Txt(reader.txt(end)?),
+            // Record types Pubky doesn't use
+            _ => {
+                reader.take(length)?;
+                continue;
+            }
+        };
+        reader.pos = end;
+        if let Some(name) = relative(&name, &origin) {
+            records.push(Record { name, ttl, data });
+        }
+    }
+    Ok(records)
+}
+
+/// Absolute name of a record relative to `origin`
+fn absolute(name: &str, origin: &str) -> String {
+    match name.trim_end_matches('.') {
+        "" | "@" => origin.to_string(),
+        name => format!("{}.{}", name, origin),
+    }
+}
+
+/// Name relative to `origin`, or `None` for names outside it
+fn relative(name: &str, origin: &str) -> Option<String> {
+    if name == origin {
+        return Some(".".to_string());
+    }
+    name.strip_suffix(origin)?
+        .strip_suffix('.')
+        .map(str::to_string)
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) -> Result<()> {
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        if label.len() > 63 {
+            return Err(Error::InvalidPacket(format!("label too long: {}", label)));
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+fn encode_svcb(priority: u16, target: &str, port: Option<u16>) -> Result<Vec<u8>> {
+    let mut rdata = priority.to_be_bytes().to_vec();
+    encode_name(&mut rdata, target)?;
+    if let Some(port) = port {
+        rdata.extend_from_slice(&PARAM_PORT.to_be_bytes());
+        rdata.extend_from_slice(&2u16.to_be_bytes());
+        rdata.extend_from_slice(&port.to_be_bytes());
+    }
+    Ok(rdata)
+}
+
+fn encode_txt(text: &str) -> Vec<u8> {
+    let mut rdata = Vec::new();
+    for chunk in text.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    if rdata.is_empty() {
+        rdata.push(0);
+    }
+    rdata
+}
+
+/// Cursor over a DNS packet
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .packet
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| Error::InvalidPacket("truncated packet".to_string()))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(
+            self.take(2)?.try_into().expect("2 bytes"),
+        ))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+
+    /// Read a name, following compression pointers
+    fn name(&mut self) -> Result<String> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // Bounds the pointers followed, so loops can't hang
+        for _ in 0..128 {
+            let invalid = || Error::InvalidPacket("invalid name".to_string());
+            let length = *self.packet.get(pos).ok_or_else(invalid)? as usize;
+            if length & 0xc0 == 0xc0 {
+                let low = *self.packet.get(pos + 1).ok_or_else(invalid)? as usize;
+                if !jumped {
+                    self.pos = pos + 2;
+                }
+                jumped = true;
+                pos = ((length & 0x3f) << 8) | low;
+                continue;
+            }
+            if length == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Ok(labels.join("."));
+            }
+            let label = self
+                .packet
+                .get(pos + 1..pos + 1 + length)
+                .ok_or_else(invalid)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            pos += 1 + length;
+        }
+        Err(Error::InvalidPacket("name has too many labels".to_string()))
+    }
+
+    fn svcb(&mut self, end: usize) -> Result<(u16, String, Option<u16>)> {
+        let priority = self.u16()?;
+        let target = match self.name()? {
+            name if name.is_empty() => ".".to_string(),
+            name => name,
+        };
+        let mut port = None;
+        while self.pos < end {
+            let key = self.u16()?;
+            let length = self.u16()? as usize;
+            let value = self.take(length)?;
+            if key == PARAM_PORT && length == 2 {
+                port = Some(u16::from_be_bytes(value.try_into().expect("2 bytes")));
+            }
+        }
+        Ok((priority, target, port))
+    }
+
+    fn txt(&mut self, end: usize) -> Result<String> {
+        let mut text = Vec::new();
+        while self.pos < end {
+            let length = self.take(1)?[0] as usize;
+            text.extend_from_slice(self.take(length)?);
+        }
+        String::from_utf8(text).map_err(|_| Error::InvalidPacket("TXT is not UTF-8".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_packet() {
+        let keypair = Keypair::random();
+        let homeserver = Keypair::random().public_key();
+        let records = vec![
+            Record::new(
+                ".",
+                300,
+                RecordData::Https {
+                    priority: 0,
+                    target: "homeserver.example".to_string(),
+                    port: Some(6286),
+                },
+            ),
+            Record::new(
+                PUBKY_RECORD,
+                300,
+                RecordData::Svcb {
+                    priority: 0,
+                    target: homeserver.to_z32(),
+                    port: None,
+                },
+            ),
+            Record::new("_note", 60, RecordData::Txt("x".repeat(300))),
+        ];
+        let packet = SignedPacket::sign_at(&keypair, records.clone(), 42).unwrap();
+
+        let payload = packet.to_relay_payload();
+        let parsed = SignedPacket::from_relay_payload(&keypair.public_key(), &payload).unwrap();
+        assert_eq!(parsed, packet);
+        assert_eq!(parsed.records(), records);
+        assert_eq!(parsed.timestamp(), 42);
+        assert_eq!(parsed.records_named(PUBKY_RECORD).count(), 1);
+
+        // The signature covers the timestamp and records, under this key
+        let mut forged = payload.clone();
+        forged[71] ^= 1;
+        assert!(SignedPacket::from_relay_payload(&keypair.public_key(), &forged).is_err());
+        let other = Keypair::random().public_key();
+        assert!(SignedPacket::from_relay_payload(&other, &payload).is_err());
+    }
+
+    #[test]
+    fn test_endpoint_url() {
+        assert_eq!(
+            endpoint_url("localhost", Some(3000)),
+            "http://localhost:3000"
+        );
+        assert_eq!(endpoint_url("::1", Some(3000)), "http://[::1]:3000");
+        assert_eq!(
+            endpoint_url("example.com", Some(443)),
+            "https://example.com"
+        );
+        assert_eq!(endpoint_url("example.com", None), "https://example.com");
+    }
+}
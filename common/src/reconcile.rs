@@ -0,0 +1,289 @@
+//! Range-based set reconciliation
+//!
+//! Two parties holding sets of keyed items find the items that differ
+//! without exchanging their full listings. The initiator asks the other side
+//! about ranges of keys, starting with [`Range::full`]. The other side
+//! answers each range with [`ItemSet::answer`]: with its items when there
+//! are few, and otherwise with the fingerprints of a handful of subranges.
+//! The initiator checks the replies against its own items with
+//! [`ItemSet::compare`], and only asks again about the subranges whose
+//! fingerprints differ. Finding a few differences among `n` items takes
+//! O(log n) round trips.
+//!
+//! Fingerprints XOR the digests of the items they cover. They are cheap to
+//! compute but not collision resistant against crafted sets, so both sides
+//! are expected to trust each other, like replicas of the same server.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Ranges with at most this many items are answered with the items
+pub const MAX_ITEMS: usize = 16;
+
+/// Number of subranges a larger range is split into
+pub const BRANCHES: usize = 16;
+
+/// A key with the hash of its value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Item {
+    pub key: String,
+    /// Hex-encoded SHA-256 of the value
+    pub hash: String,
+}
+
+impl Item {
+    /// An item for `key` holding `value`
+    pub fn new(key: impl Into<String>, value: &[u8]) -> Self {
+        Self {
+            key: key.into(),
+            hash: hex(&Sha256::digest(value)),
+        }
+    }
+
+    fn digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update((self.key.len() as u64).to_be_bytes());
+        hasher.update(self.key.as_bytes());
+        hasher.update(self.hash.as_bytes());
+        hasher.finalize().into()
+    }
+}
+
+/// Keys from `lower` inclusive to `upper` exclusive, or to the last key if
+/// `upper` is `None`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Range {
+    pub lower: String,
+    pub upper: Option<String>,
+}
+
+impl Range {
+    /// Every key
+    pub fn full() -> Self {
+        Self {
+            lower: String::new(),
+            upper: None,
+        }
+    }
+}
+
+/// Summary of the items in a range
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub count: usize,
+    /// Hex-encoded XOR of the item digests
+    pub hash: String,
+}
+
+/// A subrange with the fingerprint of its items
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subrange {
+    pub range: Range,
+    pub fingerprint: Fingerprint,
+}
+
+/// Answer about one queried range
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RangeReply {
+    /// Every item in the range
+    Items { range: Range, items: Vec<Item> },
+    /// Subranges covering the range
+    Split { subranges: Vec<Subrange> },
+}
+
+/// Keys found to differ, from the initiator's point of view
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Keys only the other side has
+    pub remote_only: Vec<String>,
+    /// Keys only the initiator has
+    pub local_only: Vec<String>,
+    /// Keys both sides have with different values
+    pub different: Vec<String>,
+}
+
+impl Diff {
+    /// Whether both sides hold the same items
+    pub fn is_empty(&self) -> bool {
+        self.remote_only.is_empty() && self.local_only.is_empty() && self.different.is_empty()
+    }
+}
+
+/// Items sorted by key
+#[derive(Debug, Clone, Default)]
+pub struct ItemSet {
+    items: Vec<Item>,
+}
+
+impl ItemSet {
+    /// Sort `items`, keeping the first item of each key
+    pub fn new(mut items: Vec<Item>) -> Self {
+        items.sort_by(|a, b| a.key.cmp(&b.key));
+        items.dedup_by(|a, b| a.key == b.key);
+        Self { items }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Fingerprint of the items in `range`
+    pub fn fingerprint(&self, range: &Range) -> Fingerprint {
+        fingerprint(self.slice(range))
+    }
+
+    /// Answer the ranges queried by the initiator
+    pub fn answer(&self, ranges: &[Range]) -> Vec<RangeReply> {
+        ranges
+            .iter()
+            .map(|range| {
+                let items = self.slice(range);
+                if items.len() <= MAX_ITEMS {
+                    return RangeReply::Items {
+                        range: range.clone(),
+                        items: items.to_vec(),
+                    };
+                }
+
+                let chunks: Vec<&[Item]> = items.chunks(items.len().div_ceil(BRANCHES)).collect();
+                let subranges = chunks
+                    .iter()
+                    .enumerate()
+                    .map(|(i, chunk)| {
+                        let lower = match i {
+                            0 => range.lower.clone(),
+                            _ => chunk[0].key.clone(),
+                        };
+                        let upper = match chunks.get(i + 1) {
+                            Some(next) => Some(next[0].key.clone()),
+                            None => range.upper.clone(),
+                        };
+                        Subrange {
+                            range: Range { lower, upper },
+                            fingerprint: fingerprint(chunk),
+                        }
+                    })
+                    .collect();
+                RangeReply::Split { subranges }
+            })
+            .collect()
+    }
+
+    /// Check replies from the other side against the local items, adding
+    /// the keys that differ to `diff`
+    ///
+    /// Returns the ranges to query next; reconciliation is done when there
+    /// are none.
+    pub fn compare(&self, replies: &[RangeReply], diff: &mut Diff) -> Vec<Range> {
+        let mut next = Vec::new();
+        for reply in replies {
+            match reply {
+                RangeReply::Items { range, items } => {
+                    let local = self.slice(range);
+                    let (mut i, mut j) = (0, 0);
+                    while i < local.len() || j < items.len() {
+                        match (local.get(i), items.get(j)) {
+                            (Some(l), Some(r)) if l.key == r.key => {
+                                if l.hash != r.hash {
+                                    diff.different.push(l.key.clone());
+                                }
+                                i += 1;
+                                j += 1;
+                            }
+                            (Some(l), Some(r)) if l.key < r.key => {
+                                diff.local_only.push(l.key.clone());
+                                i += 1;
+                            }
+                            (Some(l), None) => {
+                                diff.local_only.push(l.key.clone());
+                                i += 1;
+                            }
+                            (_, Some(r)) => {
+                                diff.remote_only.push(r.key.clone());
+                                j += 1;
+                            }
+                            (None, None) => unreachable!(),
+                        }
+                    }
+                }
+                RangeReply::Split { subranges } => {
+                    for subrange in subranges {
+                        if self.fingerprint(&subrange.range) != subrange.fingerprint {
+                            next.push(subrange.range.clone());
+                        }
+                    }
+                }
+            }
+        }
+        next
+    }
+
+    fn slice(&self, range: &Range) -> &[Item] {
+        let start = self.items.partition_point(|item| item.key < range.lower);
+        let end = match &range.upper {
+            Some(upper) => self.items.partition_point(|item| item.key < *upper),
+            None => self.items.len(),
+        };
+        &self.items[start..end.max(start)]
+    }
+}
+
+fn fingerprint(items: &[Item]) -> Fingerprint {
+    let mut hash = [0u8; 32];
+    for item in items {
+        for (a, b) in hash.iter_mut().zip(item.digest()) {
+            *a ^= b;
+        }
+    }
+    Fingerprint {
+        count: items.len(),
+        hash: hex(&hash),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconciliation() {
+        let items: Vec<Item> = (0..2000)
+            .map(|i| Item::new(format!("key/{:05}", i), &[i as u8]))
+            .collect();
+        let mut remote = items.clone();
+        remote.retain(|item| item.key != "key/00042");
+        remote[499] = Item::new("key/00500", b"changed");
+        remote.push(Item::new("key/99999", b"new"));
+        let local = ItemSet::new(items);
+        let remote = ItemSet::new(remote);
+        assert_eq!(local.len(), 2000);
+
+        let mut diff = Diff::default();
+        let mut ranges = vec![Range::full()];
+        let mut rounds = 0;
+        while !ranges.is_empty() {
+            ranges = local.compare(&remote.answer(&ranges), &mut diff);
+            rounds += 1;
+        }
+        assert_eq!(diff.local_only, vec!["key/00042"]);
+        assert_eq!(diff.remote_only, vec!["key/99999"]);
+        assert_eq!(diff.different, vec!["key/00500"]);
+        assert!(rounds <= 4);
+
+        // Identical sets agree after a single round
+        let mut diff = Diff::default();
+        assert!(local
+            .compare(&local.answer(&[Range::full()]), &mut diff)
+            .is_empty());
+        assert!(diff.is_empty());
+    }
+}
@@ -0,0 +1,52 @@
+//! Metadata tags
+//!
+//! Entries can carry a few small `key:value` tags, such as `album:2024`,
+//! given with one `X-Pubky-Tag` header per tag when they are written. The
+//! homeserver indexes entries by tag, so listings can be filtered with
+//! `?tag=album:2024`; several tags separated by commas must all match.
+//!
+//! Tags are stored as sent, in plain text, even on encrypted entries.
+
+/// Header carrying a tag of an entry, repeated for each tag
+pub const TAG_HEADER: &str = "x-pubky-tag";
+
+/// Most tags an entry can carry
+pub const MAX_TAGS: usize = 16;
+
+/// Longest tag, key and value included
+pub const MAX_TAG_LEN: usize = 128;
+
+/// Whether `tag` is a valid `key:value` tag
+///
+/// Keys are lowercase ASCII letters, digits, `-`, `_` and `.`; values are
+/// visible ASCII characters other than commas, and may be empty.
+pub fn is_valid_tag(tag: &str) -> bool {
+    let Some((key, value)) = tag.split_once(':') else {
+        return false;
+    };
+    tag.len() <= MAX_TAG_LEN
+        && !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.'))
+        && value.bytes().all(|b| b.is_ascii_graphic() && b != b',')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags() {
+        assert!(is_valid_tag("album:2024"));
+        assert!(is_valid_tag("starred:"));
+        assert!(is_valid_tag("place:Zurich/Old-Town"));
+        assert!(!is_valid_tag("place:Zürich"));
+        assert!(!is_valid_tag("album"));
+        assert!(!is_valid_tag(":2024"));
+        assert!(!is_valid_tag("Album:2024"));
+        assert!(!is_valid_tag("album:2024,2025"));
+        assert!(!is_valid_tag("album:summer 2024"));
+        assert!(!is_valid_tag(&format!("a:{}", "x".repeat(MAX_TAG_LEN))));
+    }
+}
@@ -0,0 +1,223 @@
+//! Golden test vectors for wire compatibility
+//!
+//! Fixed inputs and the exact bytes this crate produces from them, for
+//! other implementations (JavaScript, mobile) to check themselves against.
+//! The tests of this module fail if any encoding changes, so a change here
+//! is a breaking change of the wire format.
+//!
+//! Every vector is signed with the keypair of [`SECRET_KEY`], the first
+//! Ed25519 test vector of RFC 8032. Binary values are hex-encoded,
+//! timestamps are Unix milliseconds unless noted otherwise.
+
+/// Secret key of the test keypair
+pub const SECRET_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+/// Public key of the test keypair
+pub const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+/// [`PUBLIC_KEY`] in z-base-32, as used in URLs and JSON
+pub const PUBLIC_KEY_Z32: &str = "47pjoycnsrfmxikm95jh13y88e8qnhzu5kungjpxyepgt7a8krpy";
+
+/// Message signed in [`SIGNATURE`]
+pub const MESSAGE: &[u8] = b"Hello, Pubky!";
+
+/// Ed25519 signature of [`MESSAGE`]
+pub const SIGNATURE: &str = concat!(
+    "a0f143ad47e3d0dea23b2c5c116dd7b457aa4e82a0de51d9e583a26886660b85",
+    "7282aa3d4c4130ddd908336fc3e02871a32ec053c42b53551c46e1810c284f0d",
+);
+
+/// Time at which the tokens, grants and intents below are signed
+pub const TIMESTAMP: u64 = 1_700_000_000_000;
+
+/// Homeserver [`AUTH_TOKEN`] and [`AUTH_GRANT`] are for: the public key of the second Ed25519
+/// test vector of RFC 8032, in z-base-32
+pub const AUTH_AUDIENCE: &str = "8iybxo9eeqriirizbkuw4g56z1qjomgxf5njpdgy3ik9nkzwcagy";
+
+/// Nonce of [`AUTH_TOKEN`]
+pub const AUTH_NONCE: &str = "ybndrfg8ejkmcpqxot1uwisza345h769";
+
+/// [`AuthToken`](crate::auth::AuthToken) signed at [`TIMESTAMP`]
+pub const AUTH_TOKEN: &str = concat!(
+    r#"{"public_key":"47pjoycnsrfmxikm95jh13y88e8qnhzu5kungjpxyepgt7a8krpy","#,
+    r#""audience":"8iybxo9eeqriirizbkuw4g56z1qjomgxf5njpdgy3ik9nkzwcagy","#,
+    r#""nonce":"ybndrfg8ejkmcpqxot1uwisza345h769","timestamp":1700000000000,"#,
+    r#""signature":"kd1rdw6wgdhxqywgrurkfripjzdgzgayhw7hgjcgfj81n9ga57ta73xuxsnumi94datonixn9drhyddnyn634oxp79htptm179tboya"}"#,
+);
+
+/// Challenge of [`AUTH_GRANT`]
+pub const GRANT_CHALLENGE: &str = "challenge";
+/// Capabilities of [`AUTH_GRANT`]
+pub const GRANT_CAPABILITIES: &str = "/pub/example.com/:rw";
+
+/// [`AuthGrant`](crate::auth::AuthGrant) signed at [`TIMESTAMP`]
+pub const AUTH_GRANT: &str = concat!(
+    r#"{"public_key":"47pjoycnsrfmxikm95jh13y88e8qnhzu5kungjpxyepgt7a8krpy","#,
+    r#""audience":"8iybxo9eeqriirizbkuw4g56z1qjomgxf5njpdgy3ik9nkzwcagy","#,
+    r#""challenge":"challenge","capabilities":"/pub/example.com/:rw","timestamp":1700000000000,"#,
+    r#""signature":"e5bufc6dp7thakfs811yby9bwnzdux8mz36eahee8mi88pyitpspwykg8iox4845xtiub3xi31q69e9hjxrynno7w5d1n5jmigbirbo"}"#,
+);
+
+/// Homeserver left in [`MIGRATION_INTENT`]
+pub const MIGRATION_FROM: &str = "https://old.example.com";
+/// Homeserver moved to in [`MIGRATION_INTENT`]
+pub const MIGRATION_TO: &str = "https://new.example.com";
+
+/// [`MigrationIntent`](crate::auth::MigrationIntent) signed at [`TIMESTAMP`]
+pub const MIGRATION_INTENT: &str = concat!(
+    r#"{"public_key":"47pjoycnsrfmxikm95jh13y88e8qnhzu5kungjpxyepgt7a8krpy","#,
+    r#""from":"https://old.example.com","to":"https://new.example.com","timestamp":1700000000000,"#,
+    r#""signature":"9o6i53tdxhd1peyggamyjq4wbfy39zi73bxq7yx7k5mhibxp5z3xwancjdp9s7naetmse4b4k1xb87j1noyf4bhcujgkjpq8nrbqwno"}"#,
+);
+
+/// Domain claimed in [`DOMAIN_PROOF`]
+pub const DOMAIN: &str = "example.com";
+
+/// TXT record of the [`DomainProof`](crate::domain::DomainProof) of
+/// [`DOMAIN`]
+pub const DOMAIN_PROOF: &str = concat!(
+    "pk=47pjoycnsrfmxikm95jh13y88e8qnhzu5kungjpxyepgt7a8krpy ",
+    "sig=gnx5g6jbu33kcupbo4nraqd7byymxmenyb5smzy8srhaccua3bm5qajcbnyowat3717q33k189bi7xuymna9ie1kyc3ydrfprkytcna",
+);
+
+/// Homeserver announced in [`PKARR_PACKET`], in an HTTPS record named
+/// `_pubky` with priority 1 and a TTL of an hour
+pub const PKARR_HOMESERVER: &str = "homeserver.example.com";
+
+/// Timestamp of [`PKARR_PACKET`], in microseconds
+pub const PKARR_TIMESTAMP: u64 = 1_700_000_000_000_000;
+
+/// [`SignedPacket`](crate::pkarr::SignedPacket) in the relay format
+pub const PKARR_PACKET: &str = concat!(
+    "10ab4062543254ee2e1b0e22230e20185248cfd79e164ba80c7c4bf437e63ae0",
+    "9ad5694fd02943329b2841ff7450c68d73c1e329d8f70660e18e88dcda4c6108",
+    "00060a24181e4000000084000000000100000000065f7075626b79343437706a",
+    "6f79636e7372666d78696b6d39356a6831337938386538716e687a75356b756e",
+    "676a707879657067743761386b727079000041000100000e10001a00010a686f",
+    "6d65736572766572076578616d706c6503636f6d00",
+);
+
+/// TLS keying material bound in [`CHANNEL_BINDING`]: 32 bytes of `0x42`
+pub const KEYING_MATERIAL: [u8; 32] = [0x42; 32];
+
+/// [`ChannelBinding`](crate::blob::ChannelBinding) of [`KEYING_MATERIAL`]
+pub const CHANNEL_BINDING: &str = concat!(
+    "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+    "7ddf9a6a803e058138da97f800366b9ad9350f7ac37e7d08a00bb3ba017eda7d",
+    "71aba7566fe5613ff8f4f8ae2ac9ad06f34adf78113e9082b48f30e176007008",
+);
+
+/// Context of the [`EncryptionKey`](crate::encryption::EncryptionKey)
+/// sealing [`SEALED`]
+pub const ENCRYPTION_CONTEXT: &str = "private/";
+
+/// Plaintext of [`SEALED`]
+pub const PLAINTEXT: &[u8] = b"secret";
+/// Associated data of [`SEALED`]
+pub const AAD: &[u8] = b"private/notes.txt";
+
+/// [`PLAINTEXT`] sealed with the key derived for [`ENCRYPTION_CONTEXT`]:
+/// the 24-byte nonce, then the ciphertext and its 16-byte tag
+///
+/// Nonces are random, so only opening this can be checked.
+pub const SEALED: &str = concat!(
+    "012729ae92b74dd79b369679fcf3d1683a9aac0861d2512b",
+    "c546cd63e034061ab33728199ed85fc7d5c62fe12954bc",
+);
+
+/// `X-Pubky-Version` header of the vector where writer `alice` wrote
+/// twice and `bob` once
+pub const VERSION_VECTOR: &str = "alice=2,bob=1";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthGrant, AuthToken, MigrationIntent};
+    use crate::blob::ChannelBinding;
+    use crate::domain::DomainProof;
+    use crate::encryption::EncryptionKey;
+    use crate::pkarr::{Record, RecordData, SignedPacket};
+    use crate::version::VersionVector;
+    use crate::{Keypair, PublicKey, Signature};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_vectors() {
+        let keypair = Keypair::from_secret_key(&unhex(SECRET_KEY).try_into().unwrap());
+        let public_key = keypair.public_key();
+        assert_eq!(hex(&public_key.to_bytes()), PUBLIC_KEY);
+        assert_eq!(public_key.to_z32(), PUBLIC_KEY_Z32);
+        assert_eq!(PublicKey::from_z32(PUBLIC_KEY_Z32).unwrap(), public_key);
+
+        assert_eq!(hex(&keypair.sign(MESSAGE).to_bytes()), SIGNATURE);
+        let signature = Signature::from_bytes(&unhex(SIGNATURE).try_into().unwrap());
+        assert!(public_key.verify(MESSAGE, &signature).is_ok());
+
+        // Signed JSON bodies
+        let audience = PublicKey::from_z32(AUTH_AUDIENCE).unwrap();
+        let token = AuthToken::sign_at(&keypair, &audience, AUTH_NONCE, TIMESTAMP);
+        assert_eq!(serde_json::to_string(&token).unwrap(), AUTH_TOKEN);
+        let token: AuthToken = serde_json::from_str(AUTH_TOKEN).unwrap();
+        assert_eq!(token.verify_at(&audience, TIMESTAMP).unwrap(), public_key);
+
+        let grant = AuthGrant::sign_at(
+            &keypair,
+            &audience,
+            GRANT_CHALLENGE,
+            GRANT_CAPABILITIES,
+            TIMESTAMP,
+        );
+        assert_eq!(serde_json::to_string(&grant).unwrap(), AUTH_GRANT);
+        let grant: AuthGrant = serde_json::from_str(AUTH_GRANT).unwrap();
+        assert_eq!(grant.verify_at(&audience, TIMESTAMP).unwrap(), public_key);
+
+        let intent = MigrationIntent::sign_at(&keypair, MIGRATION_FROM, MIGRATION_TO, TIMESTAMP);
+        assert_eq!(serde_json::to_string(&intent).unwrap(), MIGRATION_INTENT);
+        let intent: MigrationIntent = serde_json::from_str(MIGRATION_INTENT).unwrap();
+        assert_eq!(intent.verify_at(TIMESTAMP).unwrap(), public_key);
+
+        // Records and envelopes
+        assert_eq!(DomainProof::sign(&keypair, DOMAIN).to_txt(), DOMAIN_PROOF);
+        let proof = DomainProof::from_txt(DOMAIN_PROOF).unwrap();
+        assert!(proof.verify(DOMAIN).is_ok());
+
+        let homeserver = RecordData::Https {
+            priority: 1,
+            target: PKARR_HOMESERVER.to_string(),
+            port: None,
+        };
+        let records = vec![Record::new("_pubky", 3600, homeserver)];
+        let packet = SignedPacket::sign_at(&keypair, records, PKARR_TIMESTAMP).unwrap();
+        assert_eq!(hex(&packet.to_relay_payload()), PKARR_PACKET);
+        let parsed = SignedPacket::from_relay_payload(&public_key, &unhex(PKARR_PACKET));
+        assert_eq!(parsed.unwrap(), packet);
+
+        let binding = ChannelBinding::sign(&keypair, &KEYING_MATERIAL);
+        assert_eq!(hex(&binding.to_bytes()), CHANNEL_BINDING);
+        let binding = ChannelBinding::from_bytes(&unhex(CHANNEL_BINDING).try_into().unwrap());
+        assert_eq!(
+            binding.unwrap().verify(&KEYING_MATERIAL).unwrap(),
+            public_key
+        );
+
+        let key = EncryptionKey::derive(&keypair, ENCRYPTION_CONTEXT);
+        assert_eq!(key.open(&unhex(SEALED), AAD).unwrap(), PLAINTEXT);
+
+        let mut version = VersionVector::new();
+        version.increment("alice");
+        version.increment("bob");
+        version.increment("alice");
+        assert_eq!(version.to_string(), VERSION_VECTOR);
+        assert_eq!(VERSION_VECTOR.parse::<VersionVector>().unwrap(), version);
+    }
+}
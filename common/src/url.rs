@@ -0,0 +1,137 @@
+//! Identity-addressed URLs
+//!
+//! Entries are addressed as `pubky://<public_key>/<path>`, independent of
+//! the homeserver that stores them. On a homeserver the same entry is at
+//! the HTTP path `/<public_key>/<path>`. [`PubkyUrl`] parses and formats
+//! both forms, so the server and clients agree on what a valid address is.
+//!
+//! Paths are relative to the public key and made of `/`-separated
+//! segments. A trailing `/` makes the path a prefix, as in listings; other
+//! empty segments, `.` and `..` segments and control characters are
+//! rejected.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Error, PublicKey, Result};
+
+/// Scheme of identity-addressed URLs
+pub const PUBKY_SCHEME: &str = "pubky://";
+
+/// A parsed `pubky://<public_key>/<path>` URL
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PubkyUrl {
+    pub public_key: PublicKey,
+    /// Path of the entry, or a prefix ending with `/`; empty for the root
+    pub path: String,
+}
+
+impl PubkyUrl {
+    /// The URL of `path` under `public_key`, ignoring a leading `/`
+    ///
+    /// The path is taken as is; use [`parse`](Self::parse) or
+    /// [`from_parts`](Self::from_parts) for paths that aren't known to be
+    /// valid.
+    pub fn new(public_key: PublicKey, path: impl Into<String>) -> Self {
+        let path = path.into();
+        Self {
+            public_key,
+            path: path.trim_start_matches('/').to_string(),
+        }
+    }
+
+    /// Parse a `pubky://` URL or an HTTP path `/<public_key>/<path>`; the
+    /// scheme may also be omitted
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = match url.strip_prefix(PUBKY_SCHEME) {
+            Some(rest) => rest,
+            None => url.strip_prefix('/').unwrap_or(url),
+        };
+        let (public_key, path) = rest.split_once('/').unwrap_or((rest, ""));
+        Self::from_parts(public_key, path)
+    }
+
+    /// Validate a z-base-32 public key and a path, as split by a router
+    pub fn from_parts(public_key: &str, path: &str) -> Result<Self> {
+        let public_key = PublicKey::from_z32(public_key)?;
+        validate_path(path)?;
+        Ok(Self::new(public_key, path))
+    }
+
+    /// The HTTP path of the entry on a homeserver, `/<public_key>/<path>`
+    pub fn http_path(&self) -> String {
+        format!("/{}/{}", self.public_key, self.path)
+    }
+
+    /// Whether the URL names a prefix rather than an entry
+    pub fn is_prefix(&self) -> bool {
+        self.path.is_empty() || self.path.ends_with('/')
+    }
+}
+
+impl FromStr for PubkyUrl {
+    type Err = Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        Self::parse(url)
+    }
+}
+
+impl fmt::Display for PubkyUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}/{}", PUBKY_SCHEME, self.public_key, self.path)
+    }
+}
+
+/// Check that `path` is a valid entry path or prefix
+pub fn validate_path(path: &str) -> Result<()> {
+    let invalid = |reason: &str| Err(Error::InvalidPath(format!("{:?} {}", path, reason)));
+    if path.chars().any(char::is_control) {
+        return invalid("contains control characters");
+    }
+    let segments = path.strip_suffix('/').unwrap_or(path);
+    if segments.is_empty() {
+        return Ok(());
+    }
+    for segment in segments.split('/') {
+        match segment {
+            "" => return invalid("has an empty segment"),
+            "." | ".." => return invalid("has a relative segment"),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Keypair;
+
+    #[test]
+    fn test_pubky_urls() {
+        let public_key = Keypair::random().public_key();
+        let url: PubkyUrl = format!("pubky://{}/app/a.txt", public_key).parse().unwrap();
+        assert_eq!(url, PubkyUrl::new(public_key, "/app/a.txt"));
+        assert_eq!(url.to_string(), format!("pubky://{}/app/a.txt", public_key));
+        assert_eq!(url.http_path(), format!("/{}/app/a.txt", public_key));
+        assert!(!url.is_prefix());
+
+        // The HTTP form and a bare key parse too
+        assert_eq!(PubkyUrl::parse(&url.http_path()).unwrap(), url);
+        let root = PubkyUrl::parse(&public_key.to_z32()).unwrap();
+        assert_eq!(root.path, "");
+        assert!(root.is_prefix());
+        let prefix = PubkyUrl::parse(&format!("/{}/app/", public_key)).unwrap();
+        assert!(prefix.is_prefix());
+
+        assert!(matches!(
+            PubkyUrl::parse("pubky://nope/a.txt"),
+            Err(Error::Base32Error(_))
+        ));
+        for path in ["../a", "app/./a", "app//a", "/app", "a\nb"] {
+            let url = format!("pubky://{}/{}", public_key, path);
+            assert!(matches!(PubkyUrl::parse(&url), Err(Error::InvalidPath(_))));
+        }
+    }
+}
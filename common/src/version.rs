@@ -0,0 +1,165 @@
+//! Version vectors
+//!
+//! Entries written with a writer id, such as a device name, carry a version
+//! vector counting the writes of each writer the entry has seen. Comparing
+//! two vectors tells whether one write saw the other, or whether they were
+//! made concurrently, like two devices editing the same entry offline. The
+//! homeserver keeps concurrent writes as siblings instead of letting the
+//! last one silently replace the other.
+//!
+//! On the wire a vector is written as `writer=counter` pairs separated by
+//! commas, such as `laptop=3,phone=1`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// Header naming the writer of a versioned write
+pub const WRITER_HEADER: &str = "x-pubky-writer";
+
+/// Header carrying a version vector: the version a write is based on in
+/// requests, and the entry's version in responses
+pub const VERSION_HEADER: &str = "x-pubky-version";
+
+/// Header carrying the number of siblings of an entry; more than one means
+/// the entry is in conflict
+pub const SIBLINGS_HEADER: &str = "x-pubky-siblings";
+
+/// How two versions relate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// The first version was seen by the second one
+    Before,
+    /// The first version saw the second one
+    After,
+    /// Neither version saw the other
+    Concurrent,
+}
+
+/// Write counters by writer id
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Number of writes seen from `writer`
+    pub fn get(&self, writer: &str) -> u64 {
+        self.0.get(writer).copied().unwrap_or(0)
+    }
+
+    /// Set the number of writes seen from `writer`
+    pub fn insert(&mut self, writer: impl Into<String>, counter: u64) {
+        self.0.insert(writer.into(), counter);
+    }
+
+    /// Count one more write from `writer`
+    pub fn increment(&mut self, writer: &str) {
+        *self.0.entry(writer.to_string()).or_insert(0) += 1;
+    }
+
+    /// Add the writes seen by `other`
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (writer, &counter) in &other.0 {
+            let entry = self.0.entry(writer.clone()).or_insert(0);
+            *entry = (*entry).max(counter);
+        }
+    }
+
+    /// Whether this version saw every write seen by `other`
+    pub fn descends(&self, other: &VersionVector) -> bool {
+        other
+            .0
+            .iter()
+            .all(|(writer, &counter)| self.get(writer) >= counter)
+    }
+
+    /// How this version relates to `other`
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        match (self.descends(other), other.descends(self)) {
+            (true, true) => Causality::Equal,
+            (true, false) => Causality::After,
+            (false, true) => Causality::Before,
+            (false, false) => Causality::Concurrent,
+        }
+    }
+}
+
+/// Whether `id` can name a writer: ASCII letters, digits, `-`, `_` and `.`
+pub fn is_valid_writer(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+impl fmt::Display for VersionVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (writer, counter)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", writer, counter)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for VersionVector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut vector = VersionVector::new();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let invalid = || Error::InvalidVersion(pair.to_string());
+            let (writer, counter) = pair.split_once('=').ok_or_else(invalid)?;
+            if !is_valid_writer(writer) {
+                return Err(invalid());
+            }
+            vector.insert(writer, counter.parse().map_err(|_| invalid())?);
+        }
+        Ok(vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_vectors() {
+        let mut base = VersionVector::new();
+        base.increment("laptop");
+
+        let mut laptop = base.clone();
+        laptop.increment("laptop");
+        let mut phone = base.clone();
+        phone.increment("phone");
+        assert_eq!(laptop.compare(&base), Causality::After);
+        assert_eq!(base.compare(&phone), Causality::Before);
+        assert_eq!(laptop.compare(&phone), Causality::Concurrent);
+
+        let mut merged = laptop.clone();
+        merged.merge(&phone);
+        assert!(merged.descends(&laptop) && merged.descends(&phone));
+        assert_eq!(merged.to_string(), "laptop=2,phone=1");
+        assert_eq!(
+            "laptop=2, phone=1".parse::<VersionVector>().unwrap(),
+            merged
+        );
+        assert_eq!("".parse::<VersionVector>().unwrap(), VersionVector::new());
+        assert!("laptop".parse::<VersionVector>().is_err());
+        assert!("a b=1".parse::<VersionVector>().is_err());
+    }
+}
@@ -0,0 +1,14 @@
+//! Capabilities, as requested by apps and signed into grants
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_common::capabilities::{Action, Capabilities};
+
+fuzz_target!(|input: (&str, &str)| {
+    let (capabilities, path) = input;
+    if let Ok(parsed) = capabilities.parse::<Capabilities>() {
+        assert_eq!(parsed.to_string().parse::<Capabilities>().unwrap(), parsed);
+        let _ = parsed.allows(path, Action::Read);
+        let _ = parsed.allows(path, Action::Write);
+    }
+});
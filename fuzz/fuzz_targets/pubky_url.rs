@@ -0,0 +1,11 @@
+//! `pubky://` URLs, as pasted by users or found in shared links
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_client::PubkyUrl;
+
+fuzz_target!(|input: &str| {
+    if let Ok(url) = PubkyUrl::parse(input) {
+        assert_eq!(PubkyUrl::parse(&url.to_string()).unwrap(), url);
+    }
+});
@@ -0,0 +1,12 @@
+//! Public keys in request paths and tokens
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_common::PublicKey;
+
+fuzz_target!(|input: &str| {
+    if let Ok(public_key) = PublicKey::from_z32(input) {
+        let encoded = public_key.to_z32();
+        assert_eq!(PublicKey::from_z32(&encoded).unwrap(), public_key);
+    }
+});
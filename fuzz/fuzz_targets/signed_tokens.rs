@@ -0,0 +1,30 @@
+//! Signed request bodies: auth tokens, capability grants, migration
+//! intents and write signatures
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_common::auth::{AuthGrant, AuthToken, MigrationIntent, WriteSignature};
+use pubky_common::PublicKey;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(token) = serde_json::from_slice::<AuthToken>(data) {
+        if let Ok(audience) = PublicKey::from_z32(&token.audience) {
+            let _ = token.verify_at(&audience, token.timestamp);
+        }
+    }
+    if let Ok(grant) = serde_json::from_slice::<AuthGrant>(data) {
+        if let Ok(audience) = PublicKey::from_z32(&grant.audience) {
+            let _ = grant.verify_at(&audience, grant.timestamp);
+        }
+    }
+    if let Ok(intent) = serde_json::from_slice::<MigrationIntent>(data) {
+        let _ = intent.verify_at(intent.timestamp);
+    }
+    if let Some(signature) = std::str::from_utf8(data)
+        .ok()
+        .and_then(|header| header.parse::<WriteSignature>().ok())
+    {
+        let parsed = signature.to_string().parse::<WriteSignature>().ok();
+        assert_eq!(parsed, Some(signature));
+    }
+});
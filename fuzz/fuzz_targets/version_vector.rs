@@ -0,0 +1,11 @@
+//! Version vectors in the `X-Pubky-Version` header of writes
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_common::version::VersionVector;
+
+fuzz_target!(|input: &str| {
+    if let Ok(vector) = input.parse::<VersionVector>() {
+        assert_eq!(vector.to_string().parse::<VersionVector>().unwrap(), vector);
+    }
+});
@@ -0,0 +1,125 @@
+//! Storage benchmarks
+//!
+//! Measures put, get, list and delete at several entry sizes and counts,
+//! for each storage backend. Run with `cargo bench -p pubky-server`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use pubky_common::Keypair;
+use pubky_server::{DiskBackend, Storage, SystemClock};
+use std::sync::{Arc, Once};
+
+/// Creates an empty storage
+type Backend = fn() -> Storage;
+
+/// Backends under test, by name
+fn backends() -> Vec<(&'static str, Backend)> {
+    vec![("memory", Storage::new), ("disk", disk)]
+}
+
+/// An empty storage on disk, under a directory of the temporary directory
+/// that is cleared once per run
+fn disk() -> Storage {
+    static CLEAR: Once = Once::new();
+    let root = std::env::temp_dir().join("pubky-bench");
+    CLEAR.call_once(|| {
+        let _ = std::fs::remove_dir_all(&root);
+    });
+    let dir = root.join(format!("{:016x}", rand::random::<u64>()));
+    let backend = DiskBackend::open(dir).expect("temporary directory is writable");
+    Storage::with_backend(Arc::new(backend), Arc::new(SystemClock))
+}
+
+const SIZES: [usize; 3] = [64, 4 * 1024, 256 * 1024];
+const COUNTS: [usize; 3] = [100, 1_000, 10_000];
+
+/// A storage holding `count` entries of `size` bytes under `app/`
+fn seeded(new: Backend, count: usize, size: usize) -> (Storage, Keypair) {
+    let storage = new();
+    let keypair = Keypair::random();
+    for i in 0..count {
+        storage
+            .put(keypair.public_key(), format!("app/{:06}", i), vec![7; size])
+            .unwrap();
+    }
+    (storage, keypair)
+}
+
+fn put(c: &mut Criterion) {
+    let mut group = c.benchmark_group("put");
+    for (backend, new) in backends() {
+        for size in SIZES {
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(backend, size), &size, |b, &size| {
+                let (storage, keypair) = seeded(new, 0, 0);
+                let public_key = keypair.public_key();
+                let mut i = 0u64;
+                b.iter_batched(
+                    || {
+                        i += 1;
+                        (format!("app/{}", i % 1_000), vec![7; size])
+                    },
+                    |(path, value)| storage.put(public_key, path, value).unwrap(),
+                    BatchSize::SmallInput,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for (backend, new) in backends() {
+        for size in SIZES {
+            let (storage, keypair) = seeded(new, 1_000, size);
+            let public_key = keypair.public_key();
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_function(BenchmarkId::new(backend, size), |b| {
+                let mut i = 0;
+                b.iter(|| {
+                    i = (i + 1) % 1_000;
+                    storage.get(&public_key, &format!("app/{:06}", i))
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+fn list(c: &mut Criterion) {
+    let mut group = c.benchmark_group("list");
+    for (backend, new) in backends() {
+        for count in COUNTS {
+            let (storage, keypair) = seeded(new, count, 64);
+            let public_key = keypair.public_key();
+            group.throughput(Throughput::Elements(count as u64));
+            group.bench_function(BenchmarkId::new(backend, count), |b| {
+                b.iter(|| storage.list(&public_key, "app/"));
+            });
+        }
+    }
+    group.finish();
+}
+
+fn delete(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delete");
+    for (backend, new) in backends() {
+        for count in COUNTS {
+            // Put back the entry deleted, instead of seeding every run
+            let (storage, keypair) = seeded(new, count, 64);
+            let public_key = keypair.public_key();
+            let path = format!("app/{:06}", count / 2);
+            group.bench_function(BenchmarkId::new(backend, count), |b| {
+                b.iter_batched(
+                    || storage.put(public_key, path.clone(), vec![7; 64]).unwrap(),
+                    |()| storage.delete(&public_key, &path).unwrap(),
+                    BatchSize::SmallInput,
+                );
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, put, get, list, delete);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // The gRPC service is generated from the protobuf definition, with a
+    // bundled protoc so builds don't depend on one being installed
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("bundled protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("../proto/pubky.proto").expect("proto/pubky.proto compiles");
+    }
+}
@@ -0,0 +1,137 @@
+//! Certificates from ACME
+//!
+//! With [`TlsConfig::acme`](crate::TlsConfig::acme), the HTTPS listener
+//! gets its certificate from an ACME certificate authority, Let's Encrypt
+//! by default, and renews it once two thirds of its validity have passed.
+//! Domains are validated with the TLS-ALPN-01 challenge on the listener
+//! itself, so it must be reachable on port 443 of each domain. The account
+//! and certificate are cached in a directory, so restarts don't order new
+//! ones.
+//! Available with the `acme` feature.
+
+use futures_util::StreamExt;
+use rustls_acme::caches::DirCache;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+pub use rustls_acme::acme::{LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY};
+
+/// ALPN protocol of TLS-ALPN-01 challenge connections
+pub(crate) const ACME_TLS_ALPN: &[u8] = rustls_acme::acme::ACME_TLS_ALPN_NAME;
+
+/// Configuration of ACME certificates
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    /// Domains of the certificate
+    pub domains: Vec<String>,
+    /// Contacts of the account, such as `mailto:admin@example.com`
+    pub contact: Vec<String>,
+    /// Directory caching the account and certificate; without one, a new
+    /// certificate is ordered on every start
+    pub cache_dir: Option<PathBuf>,
+    /// Directory URL of the certificate authority
+    pub directory_url: String,
+}
+
+impl AcmeConfig {
+    pub fn new(domains: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            domains: domains.into_iter().map(Into::into).collect(),
+            contact: Vec::new(),
+            cache_dir: None,
+            directory_url: LETS_ENCRYPT_PRODUCTION_DIRECTORY.to_string(),
+        }
+    }
+}
+
+/// Order and renew certificates in a background task, returning it with a
+/// TLS configuration serving them
+pub(crate) fn spawn(config: &AcmeConfig) -> io::Result<(rustls::ServerConfig, JoinHandle<()>)> {
+    if config.domains.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "ACME needs at least one domain",
+        ));
+    }
+    let mut state = rustls_acme::AcmeConfig::new(&config.domains)
+        .contact(&config.contact)
+        .cache_option(config.cache_dir.clone().map(DirCache::new))
+        .directory(&config.directory_url)
+        .state();
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .with_no_client_auth()
+        .with_cert_resolver(state.resolver());
+
+    let task = tokio::spawn(async move {
+        // The state never ends, retrying failed orders with a backoff
+        while let Some(event) = state.next().await {
+            match event {
+                Ok(event) => tracing::info!("ACME: {:?}", event),
+                Err(e) => tracing::error!("ACME error: {}", e),
+            }
+        }
+    });
+    Ok((tls, task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, TlsConfig};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use sha2::{Digest, Sha256};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cached_certificate() {
+        // No authority answers here, so only the cached certificate can be
+        // served
+        let dir = std::env::temp_dir().join(format!("pubky-acme-{}", rand::random::<u64>()));
+        let mut config = AcmeConfig::new(["localhost"]);
+        config.directory_url = "https://127.0.0.1:9/directory".to_string();
+        config.cache_dir = Some(dir.clone());
+
+        // Cached as the key followed by the chain, under a name hashing the
+        // domains and directory
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let mut hash = Sha256::new();
+        hash.update(b"localhost\0");
+        hash.update(config.directory_url.as_bytes());
+        let name = format!("cached_cert_{}", URL_SAFE_NO_PAD.encode(hash.finalize()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pem = format!("{}\n{}", cert.key_pair.serialize_pem(), cert.cert.pem());
+        std::fs::write(dir.join(name), pem).unwrap();
+
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .tls(TlsConfig::acme(config))
+            .start()
+            .await
+            .unwrap();
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(cert.cert.der()).unwrap())
+            .build()
+            .unwrap();
+        let url = format!("https://localhost:{}/", server.local_addr().port());
+
+        // The cache is read in the background
+        let mut response = client.get(&url).send().await;
+        for _ in 0..50 {
+            if response.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            response = client.get(&url).send().await;
+        }
+        assert!(response.unwrap().status().is_success());
+
+        server.shutdown().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+//! Read-only ActivityPub bridge
+//!
+//! With [`ServerBuilder::activitypub`](crate::ServerBuilder::activitypub),
+//! every user hosted here appears on the Fediverse as an actor whose posts
+//! are the entries under `pub/posts/`. Fediverse servers find the actor
+//! through [WebFinger](crate::webfinger), by the user's handle or public
+//! key, and read its posts from the outbox. Entries are served as notes holding their text.
+//!
+//! The bridge is read-only: the inbox accepts no activities, so nothing is
+//! delivered to followers and replies are not stored.
+
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use pubky_common::PublicKey;
+use serde_json::{json, Value};
+use std::io;
+use std::sync::Arc;
+
+use crate::routes::{ensure_readable, ApiError};
+use crate::storage::Storage;
+
+/// Prefix of the entries published as posts
+pub const POSTS_PREFIX: &str = "pub/posts/";
+
+/// Media type of ActivityPub objects
+pub(crate) const ACTIVITY_JSON: &str = "application/activity+json";
+
+/// JSON-LD context of ActivityStreams objects
+const CONTEXT: &str = "https://www.w3.org/ns/activitystreams";
+
+/// How to expose users over ActivityPub
+#[derive(Debug, Clone)]
+pub struct ActivityPubConfig {
+    /// Public base URL of the server, such as `https://example.com`, used in
+    /// the ids of actors and posts
+    pub base_url: String,
+}
+
+impl ActivityPubConfig {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Id of the actor of a user
+    pub(crate) fn actor_id(&self, public_key: &PublicKey) -> String {
+        format!("{}/ap/users/{}", self.base_url, public_key)
+    }
+}
+
+#[derive(Clone)]
+struct BridgeState {
+    storage: Arc<Storage>,
+    config: Arc<ActivityPubConfig>,
+}
+
+/// Create the ActivityPub routes
+pub(crate) fn activitypub_routes<S>(storage: Arc<Storage>, config: ActivityPubConfig) -> Router<S> {
+    let state = BridgeState {
+        storage,
+        config: Arc::new(config),
+    };
+    Router::new()
+        .route("/ap/users/{public_key}", get(actor))
+        .route("/ap/users/{public_key}/outbox", get(outbox))
+        .route("/ap/users/{public_key}/inbox", get(inbox))
+        .route("/ap/users/{public_key}/posts/{*name}", get(post))
+        .with_state(state)
+}
+
+/// GET /ap/users/{public_key}
+/// The actor of a user
+async fn actor(
+    State(state): State<BridgeState>,
+    Path(public_key): Path<String>,
+) -> Result<Response, ApiError> {
+    let public_key = hosted_user(&state, &public_key)?;
+    let id = state.config.actor_id(&public_key);
+    Ok(activity_json(json!({
+        "@context": CONTEXT,
+        "id": id,
+        "type": "Person",
+        "preferredUsername": public_key.to_z32(),
+        "name": public_key.to_z32(),
+        "url": format!("{}/{}/pub/", state.config.base_url, public_key),
+        "inbox": format!("{}/inbox", id),
+        "outbox": format!("{}/outbox", id),
+    })))
+}
+
+/// GET /ap/users/{public_key}/outbox
+/// Every post of a user, wrapped in `Create` activities, last path first
+async fn outbox(
+    State(state): State<BridgeState>,
+    Path(public_key): Path<String>,
+) -> Result<Response, ApiError> {
+    let public_key = hosted_user(&state, &public_key)?;
+    let mut paths = state.storage.list(&public_key, POSTS_PREFIX);
+    paths.sort_unstable_by(|a, b| b.cmp(a));
+
+    let actor = state.config.actor_id(&public_key);
+    let mut items = Vec::new();
+    for path in &paths {
+        let Some(note) = note(&state, &public_key, path)? else {
+            continue;
+        };
+        items.push(json!({
+            "id": format!("{}/activity", note["id"].as_str().unwrap_or_default()),
+            "type": "Create",
+            "actor": actor,
+            "to": note["to"],
+            "object": note,
+        }));
+    }
+    Ok(activity_json(json!({
+        "@context": CONTEXT,
+        "id": format!("{}/outbox", actor),
+        "type": "OrderedCollection",
+        "totalItems": items.len(),
+        "orderedItems": items,
+    })))
+}
+
+/// GET /ap/users/{public_key}/inbox
+/// Always empty, as the bridge accepts no activities
+async fn inbox(
+    State(state): State<BridgeState>,
+    Path(public_key): Path<String>,
+) -> Result<Response, ApiError> {
+    let public_key = hosted_user(&state, &public_key)?;
+    Ok(activity_json(json!({
+        "@context": CONTEXT,
+        "id": format!("{}/inbox", state.config.actor_id(&public_key)),
+        "type": "OrderedCollection",
+        "totalItems": 0,
+        "orderedItems": [],
+    })))
+}
+
+/// GET /ap/users/{public_key}/posts/{name}
+/// A single post
+async fn post(
+    State(state): State<BridgeState>,
+    Path((public_key, name)): Path<(String, String)>,
+) -> Result<Response, ApiError> {
+    let public_key = hosted_user(&state, &public_key)?;
+    let path = format!("{}{}", POSTS_PREFIX, name);
+    let mut note = note(&state, &public_key, &path)?.ok_or(ApiError::NotFound)?;
+    note["@context"] = json!(CONTEXT);
+    Ok(activity_json(note))
+}
+
+/// The user with the given public key, if they are hosted here and readable
+fn hosted_user(state: &BridgeState, public_key: &str) -> Result<PublicKey, ApiError> {
+    let public_key = PublicKey::from_z32(public_key).map_err(|_| ApiError::NotFound)?;
+    if !state.storage.hosts(&public_key) {
+        return Err(ApiError::NotFound);
+    }
+    ensure_readable(&state.storage, &public_key)?;
+    Ok(public_key)
+}
+
+/// The note of the post at `path`, if it holds text
+fn note(state: &BridgeState, public_key: &PublicKey, path: &str) -> io::Result<Option<Value>> {
+    let Some(value) = state.storage.get(public_key, path)? else {
+        return Ok(None);
+    };
+    let (Ok(text), Some(name)) = (String::from_utf8(value), path.strip_prefix(POSTS_PREFIX)) else {
+        return Ok(None);
+    };
+    let actor = state.config.actor_id(public_key);
+    Ok(Some(json!({
+        "id": format!("{}/posts/{}", actor, name),
+        "type": "Note",
+        "attributedTo": actor,
+        "content": html(&text),
+        "url": format!("{}/{}/{}", state.config.base_url, public_key, path),
+        "to": [format!("{}#Public", CONTEXT)],
+    })))
+}
+
+/// Plain text as HTML paragraphs
+fn html(text: &str) -> String {
+    text.split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| {
+            let escaped = paragraph
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\n', "<br>");
+            format!("<p>{}</p>", escaped)
+        })
+        .collect()
+}
+
+fn activity_json(value: Value) -> Response {
+    ([(header::CONTENT_TYPE, ACTIVITY_JSON)], Json(value)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use http_body_util::BodyExt;
+    use pubky_common::Keypair;
+    use tower::ServiceExt;
+
+    async fn get_json(router: &Router, uri: &str) -> (StatusCode, Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_activitypub_bridge() {
+        let storage = Arc::new(Storage::new());
+        let config = ActivityPubConfig::new("https://example.com:8443/");
+        let router = activitypub_routes(storage.clone(), config);
+        let public_key = Keypair::random().public_key();
+        storage
+            .put(
+                public_key,
+                "pub/posts/001".to_string(),
+                b"Hello <world>".to_vec(),
+            )
+            .unwrap();
+        storage
+            .put(
+                public_key,
+                "pub/posts/002".to_string(),
+                b"One\n\nTwo".to_vec(),
+            )
+            .unwrap();
+        storage
+            .put(public_key, "pub/posts/003".to_string(), vec![0xff])
+            .unwrap();
+        storage
+            .put(public_key, "pub/profile.json".to_string(), b"{}".to_vec())
+            .unwrap();
+
+        let actor_id = format!("https://example.com:8443/ap/users/{}", public_key);
+        let stranger = Keypair::random().public_key();
+        let uri = format!("/ap/users/{}", stranger);
+        assert_eq!(get_json(&router, &uri).await.0, StatusCode::NOT_FOUND);
+
+        let (_, actor) = get_json(&router, &format!("/ap/users/{}", public_key)).await;
+        assert_eq!(actor["id"], actor_id);
+        assert_eq!(actor["outbox"], format!("{}/outbox", actor_id));
+
+        // The outbox holds the text posts, last path first
+        let uri = format!("/ap/users/{}/outbox", public_key);
+        let (_, outbox) = get_json(&router, &uri).await;
+        assert_eq!(outbox["totalItems"], 2);
+        let items = outbox["orderedItems"].as_array().unwrap();
+        assert_eq!(items[0]["object"]["content"], "<p>One</p><p>Two</p>");
+        assert_eq!(items[1]["object"]["content"], "<p>Hello &lt;world&gt;</p>");
+        assert_eq!(items[1]["actor"], actor_id);
+
+        let uri = format!("/ap/users/{}/posts/001", public_key);
+        let (status, note) = get_json(&router, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(note["id"], items[1]["object"]["id"]);
+        let uri = format!("/ap/users/{}/posts/003", public_key);
+        assert_eq!(get_json(&router, &uri).await.0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,621 @@
+//! Admin API routes
+//!
+//! Provides user listing, purging, account freezes, moderation quarantine,
+//! backups and restores, invite codes, replication, reconciliation, metrics,
+//! and audit log queries for operators.
+//! Every request must carry the configured admin password in the
+//! `X-Admin-Password` header.
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use bytes::Bytes;
+use futures_util::{stream, TryStreamExt};
+use pubky_common::reconcile::{Range, RangeReply};
+use pubky_common::PublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, BufReader};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+use crate::audit::{AuditLog, AuditQuery};
+use crate::cbor::Negotiated;
+use crate::mirror::{self, ReplicationBatch, ReplicationStatus};
+use crate::routes::{self, ApiError};
+use crate::storage::{EntryMeta, Freeze, Storage};
+use std::sync::atomic::Ordering;
+
+/// Header carrying the admin password
+pub const ADMIN_PASSWORD_HEADER: &str = "x-admin-password";
+
+/// Lines of a backup read ahead of the client downloading it
+const BACKUP_BUFFER: usize = 16;
+
+/// State shared by admin handlers
+#[derive(Clone)]
+pub(crate) struct AdminState {
+    pub storage: Arc<Storage>,
+    pub password: Arc<str>,
+    pub replication: Arc<ReplicationStatus>,
+    pub audit: Option<Arc<AuditLog>>,
+}
+
+impl AdminState {
+    pub(crate) fn new(storage: Arc<Storage>, password: &str) -> Self {
+        Self {
+            storage,
+            password: password.into(),
+            replication: Default::default(),
+            audit: None,
+        }
+    }
+}
+
+/// A single entry in a backup file, one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupEntry {
+    pub public_key: String,
+    pub path: String,
+    /// Base64-encoded value
+    pub value: String,
+    /// What the entry was written with besides its value
+    #[serde(default, flatten)]
+    pub meta: EntryMeta,
+}
+
+/// Create the admin routes, protected by the state's password
+pub(crate) fn admin_routes<S>(state: AdminState) -> Router<S> {
+    Router::new()
+        .route("/users", get(list_users))
+        .route("/users/{public_key}", delete(purge_user))
+        .route(
+            "/users/{public_key}/freeze",
+            put(freeze_user).delete(unfreeze_user),
+        )
+        .route("/frozen", get(list_frozen))
+        .route("/quarantine", get(list_quarantine))
+        .route(
+            "/quarantine/{public_key}/{*path}",
+            post(release_quarantined).delete(discard_quarantined),
+        )
+        .route("/backup", get(backup))
+        .route("/restore", post(restore))
+        .route("/reconcile", post(reconcile))
+        .route("/reconcile/entries", post(reconcile_entries))
+        .route("/invites", get(list_invites).post(create_invite))
+        .route(
+            "/replication",
+            get(replication_status).post(apply_replication),
+        )
+        .route("/metrics", get(metrics))
+        .route("/audit", get(query_audit))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_password,
+        ))
+        .with_state(state)
+}
+
+/// Reject requests without the correct admin password
+async fn require_password(
+    State(state): State<AdminState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let provided = request.headers().get(ADMIN_PASSWORD_HEADER);
+    if !provided.is_some_and(|provided| is_password(provided.as_bytes(), &state.password)) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Whether `provided` is the admin password, compared in constant time
+///
+/// Both are hashed first, so neither their contents nor their lengths leak
+/// through the comparison's timing.
+fn is_password(provided: &[u8], password: &str) -> bool {
+    let provided = Sha256::digest(provided);
+    let password = Sha256::digest(password.as_bytes());
+    provided.ct_eq(&password).into()
+}
+
+/// GET /admin/users
+/// List all users with stored data
+async fn list_users(State(state): State<AdminState>) -> Json<Value> {
+    let users: Vec<Value> = state
+        .storage
+        .users()
+        .into_iter()
+        .map(|user| {
+            json!({
+                "public_key": user.public_key.to_z32(),
+                "entries": user.entries,
+                "bytes": user.bytes,
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "count": users.len(),
+        "users": users,
+    }))
+}
+
+/// DELETE /admin/users/{public_key}
+/// Delete all data stored for a user
+async fn purge_user(
+    State(state): State<AdminState>,
+    Path(public_key_str): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let public_key = PublicKey::from_z32(&public_key_str)
+        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+
+    // Frozen accounts are under legal hold and must not be cleaned up
+    routes::ensure_writable(&state.storage, &public_key)?;
+    let deleted = state.storage.purge(&public_key);
+    tracing::info!("Admin purged {} entries for {}", deleted, public_key);
+
+    Ok(Json(json!({ "deleted": deleted })))
+}
+
+/// Request body for freezing an account
+#[derive(Debug, Default, Deserialize)]
+struct FreezeRequest {
+    #[serde(default)]
+    reason: String,
+    /// Also reject reads of the account's data
+    #[serde(default)]
+    block_reads: bool,
+}
+
+/// PUT /admin/users/{public_key}/freeze
+/// Freeze an account: writes and deletes are rejected, data is retained
+async fn freeze_user(
+    State(state): State<AdminState>,
+    Path(public_key_str): Path<String>,
+    body: Option<Json<FreezeRequest>>,
+) -> Result<Json<Value>, ApiError> {
+    let public_key = PublicKey::from_z32(&public_key_str)
+        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+    let Json(request) = body.unwrap_or_default();
+
+    let freeze = state
+        .storage
+        .freeze(public_key, request.reason, request.block_reads);
+
+    Ok(Json(freeze_json(&public_key, &freeze)))
+}
+
+/// DELETE /admin/users/{public_key}/freeze
+/// Lift the freeze on an account
+async fn unfreeze_user(
+    State(state): State<AdminState>,
+    Path(public_key_str): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let public_key = PublicKey::from_z32(&public_key_str)
+        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+
+    if state.storage.unfreeze(&public_key) {
+        tracing::info!("Admin unfroze account {}", public_key);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+/// GET /admin/frozen
+/// List frozen accounts
+async fn list_frozen(State(state): State<AdminState>) -> Json<Value> {
+    let accounts: Vec<Value> = state
+        .storage
+        .frozen_accounts()
+        .iter()
+        .map(|(public_key, freeze)| freeze_json(public_key, freeze))
+        .collect();
+
+    Json(json!({
+        "count": accounts.len(),
+        "accounts": accounts,
+    }))
+}
+
+fn freeze_json(public_key: &PublicKey, freeze: &Freeze) -> Value {
+    json!({
+        "public_key": public_key.to_z32(),
+        "reason": freeze.reason,
+        "block_reads": freeze.block_reads,
+        "since": freeze.since,
+    })
+}
+
+/// GET /admin/quarantine
+/// List content held back by moderation or pending a scan
+async fn list_quarantine(State(state): State<AdminState>) -> Json<Value> {
+    let entries: Vec<Value> = state
+        .storage
+        .quarantined()
+        .into_iter()
+        .map(|(public_key, path, quarantined)| {
+            json!({
+                "public_key": public_key.to_z32(),
+                "path": path,
+                "reason": quarantined.reason,
+                "bytes": quarantined.value.len(),
+                "since": quarantined.since,
+                "pending": quarantined.pending,
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "count": entries.len(),
+        "entries": entries,
+    }))
+}
+
+/// POST /admin/quarantine/{public_key}/{*path}
+/// Release quarantined or pending content, storing it at its path
+async fn release_quarantined(
+    State(state): State<AdminState>,
+    Path((public_key_str, path)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let public_key = PublicKey::from_z32(&public_key_str)
+        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+
+    routes::ensure_writable(&state.storage, &public_key)?;
+    if state.storage.release(&public_key, &path)? {
+        tracing::info!("Admin released {}/{} from quarantine", public_key, path);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+/// DELETE /admin/quarantine/{public_key}/{*path}
+/// Discard quarantined content
+async fn discard_quarantined(
+    State(state): State<AdminState>,
+    Path((public_key_str, path)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let public_key = PublicKey::from_z32(&public_key_str)
+        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+
+    match state.storage.discard(&public_key, &path) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::NotFound),
+    }
+}
+
+/// GET /admin/backup
+/// Stream every stored entry as newline-delimited JSON, in no particular
+/// order, reading entries only as fast as the client downloads them
+///
+/// A storage failure aborts the response, so a backup that ends cleanly is
+/// complete.
+async fn backup(State(state): State<AdminState>) -> Response {
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(BACKUP_BUFFER);
+    // The storage reads synchronously
+    tokio::task::spawn_blocking(move || {
+        let storage = &state.storage;
+        let mut failure = None;
+        let result = storage.try_for_each(|public_key, path, value| {
+            // Skip the remaining entries once the client is gone or the
+            // backup failed
+            if tx.is_closed() || failure.is_some() {
+                return;
+            }
+            let meta = match storage.meta(public_key, path) {
+                Ok(meta) => meta,
+                Err(e) => return failure = Some(e),
+            };
+            let entry = BackupEntry {
+                meta,
+                public_key: public_key.to_z32(),
+                path: path.to_string(),
+                value: BASE64.encode(value),
+            };
+            let mut line = serde_json::to_vec(&entry).expect("backup entries serialize");
+            line.push(b'\n');
+            let _ = tx.blocking_send(Ok(Bytes::from(line)));
+        });
+        if let Some(e) = failure.or(result.err()) {
+            tracing::error!("Backup failed: {}", e);
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    let lines = stream::unfold(rx, |mut rx| async move {
+        let line = rx.recv().await?;
+        Some((line, rx))
+    });
+    let content_type = [(header::CONTENT_TYPE, "application/x-ndjson")];
+    (content_type, Body::from_stream(lines)).into_response()
+}
+
+/// POST /admin/restore
+/// Store every entry of a backup, streamed in the format of
+/// `GET /admin/backup`, over the entries at the same paths
+///
+/// Entries missing from the backup are kept. Lines before an invalid one
+/// are restored, each recorded in the change feed as a write.
+async fn restore(State(state): State<AdminState>, body: Body) -> Result<Json<Value>, ApiError> {
+    let chunks = body.into_data_stream().map_err(io::Error::other);
+    let reader = BufReader::new(SyncIoBridge::new(StreamReader::new(chunks)));
+    let restored = tokio::task::spawn_blocking(move || restore_from(&state.storage, reader))
+        .await
+        .map_err(|e| ApiError::InternalError(e.to_string()))??;
+
+    Ok(Json(json!({ "restored": restored })))
+}
+
+/// Store the entries of the backup read from `reader`, returning how many
+fn restore_from(storage: &Storage, reader: impl BufRead) -> Result<usize, ApiError> {
+    let mut restored = 0;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| ApiError::BadRequest(e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |e: String| ApiError::BadRequest(format!("Line {}: {}", number + 1, e));
+        let entry: BackupEntry = serde_json::from_str(&line).map_err(|e| invalid(e.to_string()))?;
+        let public_key =
+            PublicKey::from_z32(&entry.public_key).map_err(|e| invalid(e.to_string()))?;
+        let value = BASE64
+            .decode(&entry.value)
+            .map_err(|e| invalid(e.to_string()))?;
+        storage.put_with_meta(public_key, entry.path, value, entry.meta)?;
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+/// POST /admin/reconcile
+/// Answer range queries about the stored entries, keyed by
+/// `{public_key}/{path}`
+async fn reconcile(
+    State(state): State<AdminState>,
+    Json(ranges): Json<Vec<Range>>,
+) -> Json<Vec<RangeReply>> {
+    Json(state.storage.item_set().answer(&ranges))
+}
+
+/// POST /admin/reconcile/entries
+/// The entries with the given `{public_key}/{path}` keys; unknown keys are
+/// skipped
+async fn reconcile_entries(
+    State(state): State<AdminState>,
+    Json(keys): Json<Vec<String>>,
+) -> Result<Json<Vec<BackupEntry>>, ApiError> {
+    let mut entries = Vec::new();
+    for (public_key, path) in keys.iter().filter_map(|key| split_key(key)) {
+        let Some(value) = state.storage.get(&public_key, path)? else {
+            continue;
+        };
+        entries.push(BackupEntry {
+            public_key: public_key.to_z32(),
+            path: path.to_string(),
+            value: BASE64.encode(value),
+            meta: state.storage.meta(&public_key, path)?,
+        });
+    }
+
+    Ok(Json(entries))
+}
+
+/// Split a reconciliation key into its public key and path
+pub(crate) fn split_key(key: &str) -> Option<(PublicKey, &str)> {
+    let (public_key, path) = key.split_once('/')?;
+    Some((PublicKey::from_z32(public_key).ok()?, path))
+}
+
+/// GET /admin/invites
+/// List unused invite codes
+async fn list_invites(State(state): State<AdminState>) -> Json<Value> {
+    Json(json!({ "invites": state.storage.invites() }))
+}
+
+/// POST /admin/invites
+/// Create a new invite code
+async fn create_invite(State(state): State<AdminState>) -> (StatusCode, Json<Value>) {
+    let code = generate_invite_code();
+    state.storage.add_invite(code.clone());
+
+    (StatusCode::CREATED, Json(json!({ "code": code })))
+}
+
+/// GET /admin/replication
+/// Replication progress and mirror lag
+async fn replication_status(State(state): State<AdminState>) -> Json<Value> {
+    Json(state.replication.to_json(&state.storage))
+}
+
+/// POST /admin/replication
+/// Apply a batch of mutations streamed from a primary
+async fn apply_replication(
+    State(state): State<AdminState>,
+    Negotiated(batch): Negotiated<ReplicationBatch>,
+) -> Result<Json<Value>, ApiError> {
+    let applied_seq = mirror::apply_batch(&state.storage, batch).map_err(ApiError::BadRequest)?;
+    state
+        .replication
+        .applied_seq
+        .fetch_max(applied_seq, Ordering::Relaxed);
+
+    Ok(Json(json!({ "applied_seq": applied_seq })))
+}
+
+/// GET /admin/metrics
+/// Storage operation counts and latency percentiles
+async fn metrics(State(state): State<AdminState>) -> Json<Value> {
+    Json(json!({
+        "storage": state.storage.metrics().to_json(),
+        "memory": crate::memory::to_json(&state.storage),
+    }))
+}
+
+/// GET /admin/audit
+/// Query the audit log, newest records first
+async fn query_audit(
+    State(state): State<AdminState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let audit = state.audit.as_ref().ok_or(ApiError::NotFound)?;
+    let records = audit.query(&query);
+
+    Ok(Json(json!({
+        "count": records.len(),
+        "records": records,
+    })))
+}
+
+/// Generate a random invite code like `A1B2-C3D4-E5F6-0718`
+fn generate_invite_code() -> String {
+    let bytes: [u8; 8] = rand::random();
+    bytes
+        .chunks(2)
+        .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::faults::FaultyStorage;
+    use crate::storage::EventOp;
+    use crate::MemoryBackend;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use pubky_common::Keypair;
+    use tower::ServiceExt;
+
+    async fn call(router: &Router, method: &str, uri: &str, password: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(ADMIN_PASSWORD_HEADER, password)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_password() {
+        let router = admin_routes(AdminState::new(Arc::new(Storage::new()), "secret"));
+
+        for wrong in ["wrong", "secre", "secret2", ""] {
+            let (status, _) = call(&router, "GET", "/users", wrong).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let (status, json) = call(&router, "GET", "/users", "secret").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["count"], 0);
+
+        let (status, json) = call(&router, "GET", "/metrics", "secret").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["storage"]["backend"], "memory");
+    }
+
+    #[tokio::test]
+    async fn test_admin_operations() {
+        let storage = Arc::new(Storage::new());
+        let public_key = Keypair::random().public_key();
+        storage
+            .put(public_key, "app/a.txt".to_string(), b"hello".to_vec())
+            .unwrap();
+        let router = admin_routes(AdminState::new(storage.clone(), "secret"));
+
+        let (_, json) = call(&router, "GET", "/users", "secret").await;
+        assert_eq!(json["users"][0]["public_key"], public_key.to_z32());
+        assert_eq!(json["users"][0]["bytes"], 5);
+
+        let request = Request::get("/backup")
+            .header(ADMIN_PASSWORD_HEADER, "secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let backup = response.into_body().collect().await.unwrap().to_bytes();
+        let entries: Vec<BackupEntry> = backup
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(BASE64.decode(&entries[0].value).unwrap(), b"hello");
+
+        // Restoring brings back the entries since overwritten
+        storage
+            .put(public_key, "app/a.txt".to_string(), b"changed".to_vec())
+            .unwrap();
+        let request = Request::post("/restore")
+            .header(ADMIN_PASSWORD_HEADER, "secret")
+            .body(Body::from(backup))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["restored"], 1);
+        let event = storage.events_since(0, 10).unwrap().pop().unwrap();
+        assert_eq!((event.op, event.path.as_str()), (EventOp::Put, "app/a.txt"));
+        assert_eq!(event.seq, storage.head_seq());
+        assert_eq!(
+            storage.get(&public_key, "app/a.txt").unwrap().unwrap(),
+            b"hello"
+        );
+
+        let request = Request::post("/restore")
+            .header(ADMIN_PASSWORD_HEADER, "secret")
+            .body(Body::from("{}\n"))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // A failing backend aborts the backup instead of cutting it short
+        let faults = FaultyStorage::new().transient_errors(1.0);
+        faults.set_enabled(false);
+        let backend = faults.backend(Arc::new(MemoryBackend::new()));
+        let failing = Arc::new(Storage::with_backend(
+            Arc::new(backend),
+            Arc::new(SystemClock),
+        ));
+        failing
+            .put(public_key, "app/a.txt".to_string(), b"hello".to_vec())
+            .unwrap();
+        let failing = admin_routes(AdminState::new(failing, "secret"));
+        faults.set_enabled(true);
+        let request = Request::get("/backup")
+            .header(ADMIN_PASSWORD_HEADER, "secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = failing.oneshot(request).await.unwrap();
+        assert!(response.into_body().collect().await.is_err());
+
+        let (status, json) = call(&router, "POST", "/invites", "secret").await;
+        assert_eq!(status, StatusCode::CREATED);
+        let code = json["code"].as_str().unwrap().to_string();
+        assert_eq!(storage.invites(), vec![code]);
+
+        let uri = format!("/users/{}", public_key);
+        let (_, json) = call(&router, "DELETE", &uri, "secret").await;
+        assert_eq!(json["deleted"], 1);
+        assert!(storage.users().is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+//! Request audit log
+//!
+//! Records an append-only trail of mutating operations on user data (which
+//! account, what path, when, from where, and the result). Recent records
+//! are kept in memory for the admin query endpoint, bounded by the
+//! retention policy; optionally every record is also appended to a JSON
+//! lines file, which is never truncated by the server.
+
+use axum::{
+    extract::{ConnectInfo, OriginalUri, Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+
+/// Default number of records kept in memory
+pub const DEFAULT_MAX_RECORDS: usize = 10_000;
+
+/// Default age after which in-memory records are dropped
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Audit log settings
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    /// Append every record to this JSON lines file
+    pub file: Option<PathBuf>,
+    /// Maximum number of records kept in memory
+    pub max_records: usize,
+    /// Maximum age of records kept in memory
+    pub retention: Duration,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            max_records: DEFAULT_MAX_RECORDS,
+            retention: DEFAULT_RETENTION,
+        }
+    }
+}
+
+/// A single audited operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    /// Account (z-base-32 public key) the operation targeted
+    pub public_key: String,
+    pub method: String,
+    pub path: String,
+    /// HTTP status code of the response
+    pub status: u16,
+    /// Remote address of the client, when known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+}
+
+/// Filters for querying the audit log
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditQuery {
+    pub public_key: Option<String>,
+    pub path_prefix: Option<String>,
+    /// Only records at or after this Unix timestamp in milliseconds
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// Append-only audit trail with in-memory retention
+pub struct AuditLog {
+    config: AuditConfig,
+    records: Mutex<VecDeque<AuditRecord>>,
+    next_seq: Mutex<u64>,
+    file: Option<Mutex<File>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AuditLog {
+    /// Create an audit log, opening the configured file for appending
+    pub fn new(config: AuditConfig) -> io::Result<Self> {
+        let file = match &config.file {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+
+        Ok(Self {
+            config,
+            records: Mutex::new(VecDeque::new()),
+            next_seq: Mutex::new(1),
+            file,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Timestamp records and apply the retention age with `clock`
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Append a record, assigning its sequence number and timestamp
+    pub fn record(
+        &self,
+        public_key: String,
+        method: String,
+        path: String,
+        status: u16,
+        client: Option<String>,
+    ) {
+        let mut records = self.records.lock().unwrap();
+        let mut next_seq = self.next_seq.lock().unwrap();
+        let record = AuditRecord {
+            seq: *next_seq,
+            timestamp: self.clock.now_millis(),
+            public_key,
+            method,
+            path,
+            status,
+            client,
+        };
+        *next_seq += 1;
+
+        if let Some(file) = &self.file {
+            let mut line = serde_json::to_vec(&record).expect("audit record serializes");
+            line.push(b'\n');
+            if let Err(e) = file.lock().unwrap().write_all(&line) {
+                tracing::error!("Failed to write audit record: {}", e);
+            }
+        }
+
+        records.push_back(record);
+        self.apply_retention(&mut records);
+    }
+
+    /// Query in-memory records, newest first
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditRecord> {
+        let mut records = self.records.lock().unwrap();
+        self.apply_retention(&mut records);
+
+        records
+            .iter()
+            .rev()
+            .filter(|r| {
+                query
+                    .public_key
+                    .as_ref()
+                    .is_none_or(|pk| &r.public_key == pk)
+            })
+            .filter(|r| {
+                query
+                    .path_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| r.path.starts_with(prefix.as_str()))
+            })
+            .filter(|r| query.since.is_none_or(|since| r.timestamp >= since))
+            .take(query.limit.unwrap_or(100))
+            .cloned()
+            .collect()
+    }
+
+    /// Drop records beyond the count limit or older than the retention age
+    fn apply_retention(&self, records: &mut VecDeque<AuditRecord>) {
+        while records.len() > self.config.max_records {
+            records.pop_front();
+        }
+
+        let cutoff = self
+            .clock
+            .now_millis()
+            .saturating_sub(self.config.retention.as_millis() as u64);
+        while records.front().is_some_and(|r| r.timestamp < cutoff) {
+            records.pop_front();
+        }
+    }
+}
+
+/// Middleware recording mutating requests on user data
+pub(crate) async fn record_requests(
+    State(audit): State<Arc<AuditLog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    if matches!(method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(request).await;
+    }
+
+    // Nested routers see a stripped path, so use the original one
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.0.path().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.to_string());
+
+    let (public_key, path) = uri
+        .trim_start_matches('/')
+        .split_once('/')
+        .unwrap_or((uri.trim_start_matches('/'), ""));
+    let (public_key, path) = (public_key.to_string(), path.to_string());
+
+    let response = next.run(request).await;
+    audit.record(
+        public_key,
+        method.to_string(),
+        path,
+        response.status().as_u16(),
+        client,
+    );
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    #[test]
+    fn test_audit_query_and_retention() {
+        let clock = MockClock::new();
+        let audit = AuditLog::new(AuditConfig {
+            max_records: 3,
+            retention: Duration::from_secs(60),
+            ..Default::default()
+        })
+        .unwrap()
+        .with_clock(Arc::new(clock.clone()));
+
+        for n in 0..4 {
+            let public_key = if n % 2 == 0 { "alice" } else { "bob" };
+            audit.record(
+                public_key.to_string(),
+                "PUT".to_string(),
+                format!("app/{}.txt", n),
+                201,
+                None,
+            );
+        }
+
+        // The oldest record was dropped by the count limit
+        let all = audit.query(&AuditQuery::default());
+        let seqs: Vec<u64> = all.iter().map(|r| r.seq).collect();
+        assert_eq!(seqs, vec![4, 3, 2]);
+
+        let alice = audit.query(&AuditQuery {
+            public_key: Some("alice".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice[0].path, "app/2.txt");
+
+        let limited = audit.query(&AuditQuery {
+            limit: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(limited[0].seq, 4);
+
+        // Records older than the retention age are dropped
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(audit.query(&AuditQuery::default()).len(), 3);
+        clock.advance(Duration::from_millis(1));
+        assert!(audit.query(&AuditQuery::default()).is_empty());
+    }
+
+    #[test]
+    fn test_audit_file_is_append_only() {
+        let path =
+            std::env::temp_dir().join(format!("pubky-audit-{}.jsonl", rand::random::<u64>()));
+        let config = AuditConfig {
+            file: Some(path.clone()),
+            max_records: 1,
+            ..Default::default()
+        };
+
+        let audit = AuditLog::new(config).unwrap();
+        for n in 0..3 {
+            audit.record("alice".into(), "DELETE".into(), format!("{}", n), 204, None);
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let records: Vec<AuditRecord> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(audit.query(&AuditQuery::default()).len(), 1);
+    }
+}
@@ -0,0 +1,169 @@
+//! Cross-device authorization
+//!
+//! A device without the account's keypair asks to be authorized with
+//! `POST /auth/requests` and displays the returned challenge, typically as
+//! a QR code. The device holding the keypair scans it and approves it with
+//! a signed [`AuthGrant`]. The requesting device then redeems its secret
+//! for a session limited to the granted capabilities. Requests expire
+//! after [`AUTH_REQUEST_TTL`].
+//!
+//! Apps on other origins get grants through an [auth relay](crate::relay)
+//! instead, and redeem them with `POST /auth/grants` at the user's
+//! homeserver. Grants name the homeserver they are for, by the public key
+//! `GET /identity` returns, and each can be redeemed once.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use pubky_common::auth::{AuthGrant, AuthToken};
+use pubky_common::capabilities::Capabilities;
+use pubky_common::dto::{AuthRequestInfo, NewAuthRequest, RedeemAuthRequest};
+use pubky_common::PublicKey;
+use std::time::Duration;
+
+use crate::routes::ApiError;
+use crate::session::{start_session, SessionState};
+use crate::storage::AuthRequest;
+
+/// How long an authorization request can be approved and redeemed
+pub const AUTH_REQUEST_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Create the authorization routes
+pub(crate) fn authorize_routes<S>(state: SessionState) -> Router<S> {
+    Router::new()
+        .route("/requests", post(create_request))
+        .route("/requests/{id}/grant", post(grant_request))
+        .route("/requests/{id}/session", post(redeem_request))
+        .route("/grants", post(redeem_grant))
+        .with_state(state)
+}
+
+/// POST /auth/requests
+/// Ask to be authorized by the device holding the keypair
+async fn create_request(
+    State(state): State<SessionState>,
+    Json(request): Json<NewAuthRequest>,
+) -> Result<(StatusCode, Json<AuthRequestInfo>), ApiError> {
+    check_capabilities(&request.capabilities)?;
+    let id = BASE64_URL.encode(rand::random::<[u8; 12]>());
+    let request = AuthRequest {
+        challenge: BASE64_URL.encode(rand::random::<[u8; 32]>()),
+        secret: BASE64_URL.encode(rand::random::<[u8; 32]>()),
+        capabilities: request.capabilities,
+        expires_at: state.storage.now_millis() + AUTH_REQUEST_TTL.as_millis() as u64,
+        approved_by: None,
+    };
+    state
+        .storage
+        .insert_auth_request(id.clone(), request.clone());
+
+    let info = AuthRequestInfo {
+        id,
+        challenge: request.challenge,
+        secret: request.secret,
+        capabilities: request.capabilities,
+        expires_at: request.expires_at,
+    };
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+/// POST /auth/requests/{id}/grant
+/// Approve a request with a grant signed by a registered account
+async fn grant_request(
+    State(state): State<SessionState>,
+    Path(id): Path<String>,
+    Json(grant): Json<AuthGrant>,
+) -> Result<StatusCode, ApiError> {
+    let request = state.storage.auth_request(&id).ok_or(ApiError::NotFound)?;
+    if grant.challenge != request.challenge || grant.capabilities != request.capabilities {
+        return Err(ApiError::BadRequest(
+            "Grant doesn't match the request".to_string(),
+        ));
+    }
+    let public_key = verify(&state, &grant)?;
+    if !state.storage.is_registered(&public_key) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    match state.storage.approve_auth_request(&id, public_key) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::BadRequest("Request already approved".to_string())),
+    }
+}
+
+/// POST /auth/requests/{id}/session
+/// Redeem an approved request for a session; `202 Accepted` while pending
+async fn redeem_request(
+    State(state): State<SessionState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(redeem): Json<RedeemAuthRequest>,
+) -> Result<Response, ApiError> {
+    let request = state.storage.auth_request(&id).ok_or(ApiError::NotFound)?;
+    if redeem.secret != request.secret {
+        return Err(ApiError::Unauthorized);
+    }
+    let Some(public_key) = request.approved_by else {
+        return Ok(StatusCode::ACCEPTED.into_response());
+    };
+
+    state.storage.remove_auth_request(&id);
+    tracing::info!(
+        "Authorized a device of {} for {}",
+        public_key,
+        request.capabilities
+    );
+    let response = start_session(&state.storage, public_key, &request.capabilities, &headers);
+    Ok((StatusCode::CREATED, response).into_response())
+}
+
+/// POST /auth/grants
+/// Redeem a grant relayed to an app for a session limited to its
+/// capabilities
+async fn redeem_grant(
+    State(state): State<SessionState>,
+    headers: HeaderMap,
+    Json(grant): Json<AuthGrant>,
+) -> Result<Response, ApiError> {
+    let public_key = verify(&state, &grant)?;
+    check_capabilities(&grant.capabilities)?;
+    if !state.storage.is_registered(&public_key) {
+        return Err(ApiError::Unauthorized);
+    }
+    let signature = grant
+        .signature_bytes()
+        .expect("verified grants have a signature");
+    let expires_at = grant.timestamp + AuthToken::MAX_CLOCK_SKEW_MS;
+    if !state.storage.redeem_grant(signature, expires_at) {
+        return Err(ApiError::Conflict("Grant already redeemed".to_string()));
+    }
+
+    tracing::info!(
+        "Authorized an app of {} for {}",
+        public_key,
+        grant.capabilities
+    );
+    let response = start_session(&state.storage, public_key, &grant.capabilities, &headers);
+    Ok((StatusCode::CREATED, response).into_response())
+}
+
+/// Verify that `grant` is addressed to this homeserver, returning its
+/// signer
+fn verify(state: &SessionState, grant: &AuthGrant) -> Result<PublicKey, ApiError> {
+    grant
+        .verify_at(&state.identity, state.storage.now_millis())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid grant: {}", e)))
+}
+
+/// Reject capabilities sessions couldn't be held to
+fn check_capabilities(capabilities: &str) -> Result<(), ApiError> {
+    match capabilities.parse::<Capabilities>() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(ApiError::BadRequest(e.to_string())),
+    }
+}
@@ -0,0 +1,428 @@
+//! Storage backends
+//!
+//! A [`StorageBackend`] holds the entries of a [`Storage`](crate::Storage),
+//! the values stored under each public key and path with the
+//! [metadata](EntryMeta) they were written with, and the account state
+//! the storage saves with them, while the storage keeps everything else
+//! around them in memory, such as the event log. Entries live in a
+//! [`MemoryBackend`] unless the server is given another backend with
+//! [`ServerBuilder::backend`](crate::ServerBuilder::backend):
+//!
+//! ```
+//! use pubky_server::{MemoryBackend, Server};
+//! use std::sync::Arc;
+//!
+//! let router = Server::builder()
+//!     .backend(Arc::new(MemoryBackend::new()))
+//!     .router();
+//! ```
+//!
+//! Values are [staged](StorageBackend::stage) before the storage holds back
+//! other writes to store them, and values streamed in by uploads as they are
+//! read, so backends that can write them incrementally never hold them in
+//! memory, and those that send them over the network do so without holding
+//! back other writes. Likewise, backends that
+//! keep their paths in order [list them a page at a
+//! time](StorageBackend::list_page) without reading the others.
+
+use pubky_common::PublicKey;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read};
+use std::ops::{Bound, Range};
+use std::sync::RwLock;
+
+use crate::storage::EntryMeta;
+
+/// Stores a staged value, replacing the previous one; dropping it instead
+/// discards the value
+pub type Commit<'a> = Box<dyn FnOnce() -> io::Result<()> + 'a>;
+
+/// Where the entries of a storage live
+///
+/// The storage serializes writes, so a backend only has to stay consistent
+/// with itself under concurrent reads.
+pub trait StorageBackend: Send + Sync {
+    /// Short name of the backend, labeling its metrics
+    fn name(&self) -> &'static str;
+
+    /// Store `value` at `path`, replacing any previous value
+    fn put(&self, public_key: PublicKey, path: String, value: Vec<u8>) -> io::Result<()>;
+
+    /// Write the value read from `reader` for `path` without replacing the
+    /// stored one yet, returning how to store it
+    ///
+    /// Reads the whole value into memory unless overridden.
+    fn stage<'a>(
+        &'a self,
+        public_key: PublicKey,
+        path: String,
+        reader: &mut dyn Read,
+    ) -> io::Result<Commit<'a>> {
+        let mut value = Vec::new();
+        reader.read_to_end(&mut value)?;
+        Ok(Box::new(move || self.put(public_key, path, value)))
+    }
+
+    /// Stage `value` for `path` as [`stage`](Self::stage) does
+    ///
+    /// Only puts the value once committed unless overridden.
+    fn stage_value<'a>(
+        &'a self,
+        public_key: PublicKey,
+        path: String,
+        value: Vec<u8>,
+    ) -> io::Result<Commit<'a>> {
+        Ok(Box::new(move || self.put(public_key, path, value)))
+    }
+
+    /// The value at `path`, if any
+    fn get(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Size in bytes of the value at `path`, if any
+    ///
+    /// Reads the whole value unless overridden.
+    fn size(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<u64>> {
+        Ok(self.get(public_key, path)?.map(|value| value.len() as u64))
+    }
+
+    /// Unix timestamp in milliseconds of the last write of the value at
+    /// `path`, if there is one and the backend keeps track
+    ///
+    /// Unknown unless overridden.
+    fn modified(&self, _public_key: &PublicKey, _path: &str) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// The bytes in `range` of the value at `path`, if any, cut short at
+    /// its end
+    ///
+    /// Reads the whole value unless overridden.
+    fn get_range(
+        &self,
+        public_key: &PublicKey,
+        path: &str,
+        range: Range<u64>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.get(public_key, path)?.map(|value| {
+            let end = range.end.min(value.len() as u64) as usize;
+            let start = (range.start as usize).min(end);
+            value[start..end].to_vec()
+        }))
+    }
+
+    /// Store `meta` as the metadata of the entry at `path`, replacing any
+    /// previous metadata; empty metadata is removed
+    ///
+    /// Called once the value is stored.
+    fn put_meta(&self, public_key: PublicKey, path: String, meta: EntryMeta) -> io::Result<()>;
+
+    /// Metadata of the entry at `path`, empty if it has none
+    fn meta(&self, public_key: &PublicKey, path: &str) -> io::Result<EntryMeta>;
+
+    /// Remove the value at `path` and its metadata, returning whether there
+    /// was a value
+    fn delete(&self, public_key: &PublicKey, path: &str) -> io::Result<bool>;
+
+    /// Paths of the entries of `public_key` starting with `prefix`, in no
+    /// particular order
+    fn list(&self, public_key: &PublicKey, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Up to `limit` paths of the entries of `public_key` starting with
+    /// `prefix` and coming after `after`, in ascending order, or descending
+    /// with `reverse`
+    ///
+    /// Lists and sorts every path under the prefix unless overridden.
+    fn list_page(
+        &self,
+        public_key: &PublicKey,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        reverse: bool,
+    ) -> io::Result<Vec<String>> {
+        let mut paths = self.list(public_key, prefix)?;
+        paths.sort_unstable();
+        if reverse {
+            paths.reverse();
+        }
+        Ok(paths
+            .into_iter()
+            .filter(|path| after.is_none_or(|after| comes_after(path, after, reverse)))
+            .take(limit)
+            .collect())
+    }
+
+    /// Call `visit` with every entry, in no particular order
+    fn for_each(&self, visit: &mut dyn FnMut(&PublicKey, &str, &[u8])) -> io::Result<()>;
+
+    /// Call `visit` with the size in bytes of every entry, in no particular
+    /// order
+    ///
+    /// Reads every value with [`for_each`](Self::for_each) unless overridden.
+    fn for_each_size(&self, visit: &mut dyn FnMut(&PublicKey, &str, u64)) -> io::Result<()> {
+        self.for_each(&mut |public_key, path, value| visit(public_key, path, value.len() as u64))
+    }
+
+    /// Whether the backend keeps the account state given to
+    /// [`save_state`](Self::save_state) across restarts
+    ///
+    /// False unless overridden, when the state is never saved.
+    fn keeps_state(&self) -> bool {
+        false
+    }
+
+    /// The account state last given to [`save_state`](Self::save_state),
+    /// if any
+    fn load_state(&self) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Keep `state`, the accounts, sessions and other state around the
+    /// entries, replacing what was kept
+    fn save_state(&self, _state: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Rough bytes the backend holds in memory
+    fn memory_bytes(&self) -> usize {
+        0
+    }
+}
+
+/// Whether `path` comes after `cursor` in ascending order, or descending
+/// with `reverse`
+pub(crate) fn comes_after(path: &str, cursor: &str, reverse: bool) -> bool {
+    match reverse {
+        true => path < cursor,
+        false => path > cursor,
+    }
+}
+
+/// The first string after all those starting with `prefix`, if any
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.to_string();
+    while let Some(last) = end.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Entries in maps in memory, ordered by path, lost on restart
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    data: RwLock<HashMap<PublicKey, BTreeMap<String, Vec<u8>>>>,
+    meta: RwLock<HashMap<(PublicKey, String), EntryMeta>>,
+}
+
+impl MemoryBackend {
+    /// An empty backend
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn put(&self, public_key: PublicKey, path: String, value: Vec<u8>) -> io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        data.entry(public_key).or_default().insert(path, value);
+        Ok(())
+    }
+
+    fn get(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<Vec<u8>>> {
+        let data = self.data.read().unwrap();
+        Ok(data
+            .get(public_key)
+            .and_then(|paths| paths.get(path))
+            .cloned())
+    }
+
+    fn put_meta(&self, public_key: PublicKey, path: String, meta: EntryMeta) -> io::Result<()> {
+        let mut metas = self.meta.write().unwrap();
+        match meta.is_empty() {
+            true => metas.remove(&(public_key, path)),
+            false => metas.insert((public_key, path), meta),
+        };
+        Ok(())
+    }
+
+    fn meta(&self, public_key: &PublicKey, path: &str) -> io::Result<EntryMeta> {
+        let metas = self.meta.read().unwrap();
+        Ok(metas
+            .get(&(*public_key, path.to_string()))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn delete(&self, public_key: &PublicKey, path: &str) -> io::Result<bool> {
+        let key = (*public_key, path.to_string());
+        self.meta.write().unwrap().remove(&key);
+        let mut data = self.data.write().unwrap();
+        let Some(paths) = data.get_mut(public_key) else {
+            return Ok(false);
+        };
+        let existed = paths.remove(path).is_some();
+        if paths.is_empty() {
+            data.remove(public_key);
+        }
+        Ok(existed)
+    }
+
+    fn list(&self, public_key: &PublicKey, prefix: &str) -> io::Result<Vec<String>> {
+        self.list_page(public_key, prefix, None, usize::MAX, false)
+    }
+
+    fn list_page(
+        &self,
+        public_key: &PublicKey,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        reverse: bool,
+    ) -> io::Result<Vec<String>> {
+        let data = self.data.read().unwrap();
+        let Some(paths) = data.get(public_key) else {
+            return Ok(Vec::new());
+        };
+
+        // Only the paths under the prefix past the cursor are visited
+        let end = prefix_end(prefix);
+        let mut lower = Bound::Included(prefix);
+        let mut upper = end.as_deref();
+        match after {
+            Some(after) if reverse => upper = Some(upper.map_or(after, |upper| upper.min(after))),
+            Some(after) if after >= prefix => lower = Bound::Excluded(after),
+            _ => {}
+        }
+        if let (Bound::Included(first) | Bound::Excluded(first), Some(upper)) = (lower, upper) {
+            if first >= upper {
+                return Ok(Vec::new());
+            }
+        }
+        let upper = upper.map_or(Bound::Unbounded, Bound::Excluded);
+
+        let range = paths
+            .range::<str, _>((lower, upper))
+            .map(|(path, _)| path.clone());
+        Ok(match reverse {
+            true => range.rev().take(limit).collect(),
+            false => range.take(limit).collect(),
+        })
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(&PublicKey, &str, &[u8])) -> io::Result<()> {
+        let data = self.data.read().unwrap();
+        for (public_key, paths) in data.iter() {
+            for (path, value) in paths {
+                visit(public_key, path, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_bytes(&self) -> usize {
+        const KEY: usize = size_of::<(PublicKey, String)>();
+        let data = self.data.read().unwrap();
+        let values: usize = data
+            .values()
+            .flatten()
+            .map(|(path, value)| KEY + path.len() + size_of::<Vec<u8>>() + value.len())
+            .sum();
+        let metas = self.meta.read().unwrap();
+        let metas: usize = metas
+            .iter()
+            .map(|((_, path), meta)| KEY + path.len() + meta.memory_bytes())
+            .sum();
+        values + metas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::Storage;
+    use pubky_common::Keypair;
+    use std::sync::Arc;
+
+    /// A backend whose every operation fails
+    struct Broken;
+
+    impl StorageBackend for Broken {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        fn put(&self, _: PublicKey, _: String, _: Vec<u8>) -> io::Result<()> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn get(&self, _: &PublicKey, _: &str) -> io::Result<Option<Vec<u8>>> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn put_meta(&self, _: PublicKey, _: String, _: EntryMeta) -> io::Result<()> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn meta(&self, _: &PublicKey, _: &str) -> io::Result<EntryMeta> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn delete(&self, _: &PublicKey, _: &str) -> io::Result<bool> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn list(&self, _: &PublicKey, _: &str) -> io::Result<Vec<String>> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn for_each(&self, _: &mut dyn FnMut(&PublicKey, &str, &[u8])) -> io::Result<()> {
+            Err(io::Error::other("broken"))
+        }
+    }
+
+    #[test]
+    fn test_storage_backend() {
+        let backend = Arc::new(MemoryBackend::new());
+        let storage = Storage::with_backend(backend.clone(), Arc::new(SystemClock));
+        let public_key = Keypair::random().public_key();
+        storage
+            .put(public_key, "a.txt".to_string(), b"stored".to_vec())
+            .unwrap();
+        assert_eq!(
+            backend.get(&public_key, "a.txt").unwrap().unwrap(),
+            b"stored"
+        );
+
+        // Entries already in the backend are served, without events
+        let value = b"preloaded".to_vec();
+        backend.put(public_key, "b.txt".to_string(), value).unwrap();
+        assert_eq!(
+            storage.get(&public_key, "b.txt").unwrap().unwrap(),
+            b"preloaded"
+        );
+        assert_eq!(storage.entries().len(), 2);
+        assert_eq!(storage.head_seq(), 1);
+        assert_eq!(storage.purge(&public_key), 2);
+        assert!(backend.list(&public_key, "").unwrap().is_empty());
+        assert_eq!(storage.metrics().backend(), "memory");
+
+        // Failures are returned, and write no events
+        let storage = Storage::with_backend(Arc::new(Broken), Arc::new(SystemClock));
+        let value = b"lost".to_vec();
+        assert!(storage.put(public_key, "a.txt".to_string(), value).is_err());
+        assert!(storage.get(&public_key, "a.txt").is_err());
+        assert!(storage.delete(&public_key, "a.txt").is_err());
+        assert!(storage.list(&public_key, "").is_empty());
+        assert!(storage.users().is_empty());
+        assert_eq!(storage.head_seq(), 0);
+        assert_eq!(storage.metrics().backend(), "broken");
+    }
+}
@@ -0,0 +1,337 @@
+//! QUIC blob transfer endpoint
+//!
+//! With [`ServerBuilder::blob_transfer`](crate::ServerBuilder::blob_transfer),
+//! the server moves large entries over raw QUIC streams, as described in
+//! [`pubky_common::blob`]. Clients authenticate with a channel binding
+//! signed by their keypair, then upload chunks of their own entries and
+//! read chunks of anyone's on parallel streams.
+//!
+//! Uploaded chunks are kept per connection until committed, up to
+//! [`BlobConfig::max_size`] bytes.
+
+use bytes::Bytes;
+use pubky_common::blob::{
+    self, BlobRequest, BlobResponse, ChannelBinding, ALPN, CHUNK_SIZE, EXPORTER_LABEL,
+    KEYING_MATERIAL_LEN, MAX_FRAME_LEN,
+};
+use pubky_common::url::PubkyUrl;
+use pubky_common::PublicKey;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::quic;
+use crate::routes::{ensure_readable, ensure_writable, entry_url, ApiError};
+use crate::storage::Storage;
+use crate::tls::TlsIdentity;
+
+/// Default limit of the chunks a connection keeps uncommitted
+pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Configuration of the blob transfer endpoint
+#[derive(Debug, Clone)]
+pub struct BlobConfig {
+    pub identity: TlsIdentity,
+    /// UDP address of the endpoint
+    pub bind: SocketAddr,
+    /// Bytes of uncommitted chunks a connection may hold
+    pub max_size: u64,
+}
+
+impl BlobConfig {
+    pub fn new(identity: TlsIdentity, bind: SocketAddr) -> Self {
+        Self {
+            identity,
+            bind,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Chunks uploaded on a connection, by path and offset
+#[derive(Default)]
+struct Uploads {
+    chunks: HashMap<String, BTreeMap<u64, Bytes>>,
+    size: u64,
+}
+
+/// Bind the endpoint and serve it in a background task, returning the task
+/// and the bound address
+pub(crate) fn spawn(
+    config: &BlobConfig,
+    storage: Arc<Storage>,
+) -> io::Result<(JoinHandle<()>, SocketAddr)> {
+    let endpoint = quic::endpoint(&config.identity, ALPN, config.bind)?;
+    let local_addr = endpoint.local_addr()?;
+    let max_size = config.max_size;
+
+    tracing::info!("Serving blob transfers on udp://{}", local_addr);
+    let task = tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(incoming, storage, max_size).await {
+                    tracing::debug!("Blob connection failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok((task, local_addr))
+}
+
+/// Authenticate the client of a connection, then serve its streams
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    storage: Arc<Storage>,
+    max_size: u64,
+) -> Result<(), Error> {
+    let connection = incoming.await?;
+    let (mut send, mut recv) = connection.accept_bi().await?;
+    let mut binding = [0u8; ChannelBinding::LEN];
+    recv.read_exact(&mut binding).await?;
+    let mut keying_material = [0u8; KEYING_MATERIAL_LEN];
+    connection
+        .export_keying_material(&mut keying_material, EXPORTER_LABEL, b"")
+        .map_err(|_| "Failed to export keying material")?;
+
+    let public_key = match ChannelBinding::from_bytes(&binding)
+        .and_then(|binding| binding.verify(&keying_material))
+    {
+        Ok(public_key) => public_key,
+        Err(_) => {
+            let response = error_response(&ApiError::Unauthorized);
+            send.write_all(&frame(&response)?).await?;
+            send.finish()?;
+            let _ = send.stopped().await;
+            return Ok(());
+        }
+    };
+    send.write_all(&frame(&BlobResponse::ok())?).await?;
+    send.finish()?;
+    tracing::debug!("Blob connection from {}", public_key);
+
+    let uploads = Arc::new(Mutex::new(Uploads::default()));
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let storage = storage.clone();
+        let uploads = uploads.clone();
+        tokio::spawn(async move {
+            let stream = Stream {
+                storage,
+                public_key,
+                uploads,
+                max_size,
+            };
+            if let Err(e) = stream.serve(send, recv).await {
+                tracing::debug!("Blob stream failed: {}", e);
+            }
+        });
+    }
+}
+
+/// What a stream of an authenticated connection may access
+struct Stream {
+    storage: Arc<Storage>,
+    public_key: PublicKey,
+    uploads: Arc<Mutex<Uploads>>,
+    max_size: u64,
+}
+
+impl Stream {
+    /// Answer the request of a stream
+    async fn serve(
+        &self,
+        mut send: quinn::SendStream,
+        mut recv: quinn::RecvStream,
+    ) -> Result<(), Error> {
+        let mut len = [0u8; 4];
+        recv.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len);
+        let result = match len <= MAX_FRAME_LEN {
+            true => {
+                let mut request = vec![0u8; len as usize];
+                recv.read_exact(&mut request).await?;
+                match serde_json::from_slice(&request) {
+                    Ok(request) => self.handle(request, &mut recv).await,
+                    Err(e) => Err(ApiError::BadRequest(e.to_string())),
+                }
+            }
+            false => Err(ApiError::BadRequest("Frame too long".to_string())),
+        };
+
+        let (response, data) = result.unwrap_or_else(|e| (error_response(&e), None));
+        send.write_all(&frame(&response)?).await?;
+        if let Some(data) = data {
+            send.write_chunk(data).await?;
+        }
+        send.finish()?;
+        Ok(())
+    }
+
+    async fn handle(
+        &self,
+        request: BlobRequest,
+        recv: &mut quinn::RecvStream,
+    ) -> Result<(BlobResponse, Option<Bytes>), ApiError> {
+        match request {
+            BlobRequest::Stat { public_key, path } => {
+                let data = self.read(&public_key, &path)?;
+                let response = BlobResponse {
+                    size: Some(data.len() as u64),
+                    sha256: Some(blob::sha256_hex(&data)),
+                    ..BlobResponse::ok()
+                };
+                Ok((response, None))
+            }
+            BlobRequest::Read {
+                public_key,
+                path,
+                offset,
+                len,
+            } => {
+                let data = self.read(&public_key, &path)?;
+                let size = data.len() as u64;
+                if len > CHUNK_SIZE || offset.checked_add(len).is_none_or(|end| end > size) {
+                    return Err(ApiError::BadRequest("Invalid range".to_string()));
+                }
+                let range = Bytes::from(data).slice(offset as usize..(offset + len) as usize);
+                let response = BlobResponse {
+                    size: Some(size),
+                    sha256: Some(blob::sha256_hex(&range)),
+                    ..BlobResponse::ok()
+                };
+                Ok((response, Some(range)))
+            }
+            BlobRequest::Write {
+                path,
+                offset,
+                sha256,
+            } => {
+                ensure_writable(&self.storage, &self.public_key)?;
+                if offset % CHUNK_SIZE != 0 {
+                    return Err(ApiError::BadRequest("Unaligned chunk".to_string()));
+                }
+                let chunk = recv
+                    .read_to_end(CHUNK_SIZE as usize)
+                    .await
+                    .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+                if blob::sha256_hex(&chunk) != sha256 {
+                    return Err(ApiError::BadRequest("Chunk hash mismatch".to_string()));
+                }
+
+                let mut uploads = self.uploads.lock().unwrap();
+                if uploads.size + chunk.len() as u64 > self.max_size {
+                    return Err(ApiError::BadRequest(
+                        "Too many uncommitted chunks".to_string(),
+                    ));
+                }
+                uploads.size += chunk.len() as u64;
+                let replaced = uploads
+                    .chunks
+                    .entry(path)
+                    .or_default()
+                    .insert(offset, chunk.into());
+                if let Some(replaced) = replaced {
+                    uploads.size -= replaced.len() as u64;
+                }
+                Ok((BlobResponse::ok(), None))
+            }
+            BlobRequest::Commit { path, size, sha256 } => {
+                ensure_writable(&self.storage, &self.public_key)?;
+                let path = entry_url(&self.public_key.to_z32(), &path)?.path;
+                let chunks = {
+                    let mut uploads = self.uploads.lock().unwrap();
+                    let chunks = uploads.chunks.remove(&path).unwrap_or_default();
+                    uploads.size -= chunks.values().map(|c| c.len() as u64).sum::<u64>();
+                    chunks
+                };
+                let data = assemble(chunks, size, &sha256)?;
+                self.storage.put(self.public_key, path, data)?;
+                let response = BlobResponse {
+                    status: 201,
+                    ..BlobResponse::ok()
+                };
+                Ok((response, None))
+            }
+        }
+    }
+
+    /// The entry at `path` of `public_key`, if readable
+    fn read(&self, public_key: &str, path: &str) -> Result<Vec<u8>, ApiError> {
+        let PubkyUrl { public_key, path } = entry_url(public_key, path)?;
+        ensure_readable(&self.storage, &public_key)?;
+        self.storage
+            .get(&public_key, &path)?
+            .ok_or(ApiError::NotFound)
+    }
+}
+
+/// Join uploaded chunks into an entry of `size` bytes hashing to `sha256`
+fn assemble(chunks: BTreeMap<u64, Bytes>, size: u64, sha256: &str) -> Result<Vec<u8>, ApiError> {
+    let mut data = Vec::with_capacity(size.min(DEFAULT_MAX_SIZE) as usize);
+    for ((offset, len), (chunk_offset, chunk)) in blob::chunks(size).zip(&chunks) {
+        if offset != *chunk_offset || len != chunk.len() as u64 {
+            return Err(ApiError::BadRequest(format!("Missing chunk at {}", offset)));
+        }
+        data.extend_from_slice(chunk);
+    }
+    if data.len() as u64 != size || chunks.len() != blob::chunks(size).count() {
+        return Err(ApiError::BadRequest("Size mismatch".to_string()));
+    }
+    if blob::sha256_hex(&data) != sha256 {
+        return Err(ApiError::BadRequest("Hash mismatch".to_string()));
+    }
+    Ok(data)
+}
+
+/// Encode a frame: its length, then its JSON
+fn frame(value: &BlobResponse) -> serde_json::Result<Vec<u8>> {
+    let json = serde_json::to_vec(value)?;
+    let mut frame = (json.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&json);
+    Ok(frame)
+}
+
+fn error_response(error: &ApiError) -> BlobResponse {
+    let (status, body) = error.status_and_body();
+    BlobResponse {
+        status: status.as_u16(),
+        error: Some(body),
+        ..BlobResponse::ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let data: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let sha256 = blob::sha256_hex(&data);
+        let chunks: BTreeMap<u64, Bytes> = blob::chunks(data.len() as u64)
+            .map(|(offset, len)| {
+                let chunk = data[offset as usize..(offset + len) as usize].to_vec();
+                (offset, chunk.into())
+            })
+            .collect();
+
+        let assembled = assemble(chunks.clone(), data.len() as u64, &sha256).unwrap();
+        assert_eq!(assembled, data);
+        assert!(assemble(chunks.clone(), data.len() as u64, &blob::sha256_hex(b"")).is_err());
+
+        let mut missing = chunks.clone();
+        missing.remove(&0);
+        assert!(assemble(missing, data.len() as u64, &sha256).is_err());
+        assert!(assemble(chunks, data.len() as u64 + 1, &sha256).is_err());
+        assert!(assemble(BTreeMap::new(), 0, &blob::sha256_hex(b"")).is_ok());
+    }
+}
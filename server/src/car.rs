@@ -0,0 +1,197 @@
+//! IPFS CAR archives
+//!
+//! Packages entries as content-addressed blocks in a CARv1 file, so they
+//! can be imported into IPFS nodes and pinning services. Each entry is split
+//! into raw blocks of at most [`CHUNK_SIZE`] bytes, and the archive's root is
+//! a DAG-CBOR manifest mapping every path to its size and blocks:
+//!
+//! ```text
+//! { "entries": { path: { "size": n, "blocks": [CID, ...] } },
+//!   "version": 1, "public_key": "<z-base-32>" }
+//! ```
+//!
+//! The manifest holds no timestamps, so exporting the same entries always
+//! yields the same root CID.
+
+use pubky_common::PublicKey;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+use crate::cbor::Encoder;
+
+/// Largest block, matching the default chunk size of IPFS
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Multicodec of raw binary blocks
+const RAW: u8 = 0x55;
+
+/// Multicodec of DAG-CBOR blocks
+const DAG_CBOR: u8 = 0x71;
+
+/// Multihash code of SHA-256
+const SHA2_256: u8 = 0x12;
+
+/// CBOR tag of IPLD links
+const CID_TAG: u64 = 42;
+
+/// A version 1 content identifier
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cid(Vec<u8>);
+
+impl Cid {
+    /// Identify `data` encoded with the multicodec `codec`
+    fn new(codec: u8, data: &[u8]) -> Self {
+        let mut bytes = vec![0x01, codec, SHA2_256, 32];
+        bytes.extend_from_slice(&Sha256::digest(data));
+        Self(bytes)
+    }
+}
+
+/// The usual text form: lowercase base32 with the `b` multibase prefix
+impl std::fmt::Display for Cid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let encoded = base32::encode(base32::Alphabet::Rfc4648Lower { padding: false }, &self.0);
+        write!(f, "b{}", encoded)
+    }
+}
+
+/// Build a CAR archive of `entries`, returning it with its root CID
+pub fn build(public_key: &PublicKey, entries: &[(String, Vec<u8>)]) -> (Vec<u8>, Cid) {
+    let mut blocks = Vec::new();
+    let mut seen = HashSet::new();
+    let mut manifest_entries = Vec::new();
+
+    for (path, value) in entries {
+        let mut links = Vec::new();
+        for chunk in value.chunks(CHUNK_SIZE) {
+            let cid = Cid::new(RAW, chunk);
+            if seen.insert(cid.clone()) {
+                blocks.push((cid.clone(), chunk.to_vec()));
+            }
+            links.push(cid);
+        }
+
+        let mut entry = Encoder::default();
+        entry.map(2);
+        entry.text("size");
+        entry.uint(value.len() as u64);
+        entry.text("blocks");
+        entry.array(links.len());
+        for cid in &links {
+            link(&mut entry, cid);
+        }
+        manifest_entries.push((path.as_str(), entry.0));
+    }
+
+    let mut manifest = Encoder::default();
+    manifest.map(3);
+    manifest.text("entries");
+    manifest.map(manifest_entries.len());
+    manifest_entries.sort_by(|a, b| dag_cbor_key_order(a.0, b.0));
+    for (path, entry) in manifest_entries {
+        manifest.text(path);
+        manifest.0.extend_from_slice(&entry);
+    }
+    manifest.text("version");
+    manifest.uint(1);
+    manifest.text("public_key");
+    manifest.text(&public_key.to_z32());
+    let root = Cid::new(DAG_CBOR, &manifest.0);
+
+    let mut header = Encoder::default();
+    header.map(2);
+    header.text("roots");
+    header.array(1);
+    link(&mut header, &root);
+    header.text("version");
+    header.uint(1);
+
+    let mut car = Vec::new();
+    varint(&mut car, header.0.len() as u64);
+    car.extend_from_slice(&header.0);
+    for (cid, data) in std::iter::once((root.clone(), manifest.0)).chain(blocks) {
+        varint(&mut car, (cid.0.len() + data.len()) as u64);
+        car.extend_from_slice(&cid.0);
+        car.extend_from_slice(&data);
+    }
+    (car, root)
+}
+
+/// DAG-CBOR sorts map keys by length, then bytewise
+fn dag_cbor_key_order(a: &str, b: &str) -> std::cmp::Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// Unsigned LEB128, as used for CAR section lengths
+fn varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Links are byte strings of the CID behind a zero byte, tagged 42
+fn link(cbor: &mut Encoder, cid: &Cid) {
+    let mut bytes = vec![0];
+    bytes.extend_from_slice(&cid.0);
+    cbor.tag(CID_TAG);
+    cbor.bytes(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+
+    /// Read a varint, returning it with the rest of the input
+    fn read_varint(mut data: &[u8]) -> (u64, &[u8]) {
+        let mut n = 0;
+        let mut shift = 0;
+        loop {
+            let byte = data[0];
+            data = &data[1..];
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return (n, data);
+            }
+            shift += 7;
+        }
+    }
+
+    #[test]
+    fn test_car_archive() {
+        let public_key = Keypair::random().public_key();
+        let large = vec![7u8; CHUNK_SIZE * 2 + 1];
+        let entries = vec![
+            ("pub/a.txt".to_string(), b"alpha".to_vec()),
+            ("pub/copy.txt".to_string(), b"alpha".to_vec()),
+            ("pub/large.bin".to_string(), large),
+        ];
+        let (car, root) = build(&public_key, &entries);
+        assert!(root.to_string().starts_with("bafy"));
+
+        // The header names the manifest as the only root
+        let (len, rest) = read_varint(&car);
+        let (header, mut rest) = rest.split_at(len as usize);
+        assert_eq!(&header[..9], b"\xa2\x65roots\x81\xd8");
+        assert!(header.windows(root.0.len()).any(|w| w == root.0.as_slice()));
+
+        // Every block matches its CID, and identical chunks are stored once
+        let mut cids = Vec::new();
+        while !rest.is_empty() {
+            let (len, next) = read_varint(rest);
+            let (section, next) = next.split_at(len as usize);
+            let (cid, data) = section.split_at(36);
+            assert_eq!(cid, Cid::new(cid[1], data).0);
+            cids.push(cid.to_vec());
+            rest = next;
+        }
+        assert_eq!(cids[0], root.0);
+        // The manifest, "alpha", one full chunk of 7s and the last byte
+        assert_eq!(cids.len(), 4);
+
+        // Exports of the same entries share a root
+        assert_eq!(build(&public_key, &entries).1, root);
+    }
+}
@@ -0,0 +1,350 @@
+//! CBOR payloads
+//!
+//! List responses, replication batches and change feeds can be exchanged as
+//! CBOR instead of JSON, negotiated with the `Accept` and `Content-Type`
+//! headers. Payloads have the same structure as their JSON form: values go
+//! through [`serde_json::Value`], so every type that serializes to JSON
+//! serializes to CBOR too. Byte strings are read as base64 text, the form
+//! JSON payloads carry binary values in, so clients may send raw bytes
+//! where the JSON API expects base64.
+
+use axum::{
+    extract::{FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Number, Value};
+
+use crate::routes::ApiError;
+
+/// Media type of a single CBOR item
+pub const CBOR: &str = "application/cbor";
+
+/// Media type of a sequence of CBOR items, used by streams
+pub const CBOR_SEQ: &str = "application/cbor-seq";
+
+/// Deepest nesting of arrays and maps accepted when decoding
+const MAX_DEPTH: usize = 64;
+
+/// Encoder of CBOR items, written head by head
+#[derive(Default)]
+pub(crate) struct Encoder(pub Vec<u8>);
+
+impl Encoder {
+    fn head(&mut self, major: u8, n: u64) {
+        let major = major << 5;
+        match n {
+            0..=23 => self.0.push(major | n as u8),
+            24..=0xff => self.0.extend_from_slice(&[major | 24, n as u8]),
+            0x100..=0xffff => {
+                self.0.push(major | 25);
+                self.0.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.0.push(major | 26);
+                self.0.extend_from_slice(&(n as u32).to_be_bytes());
+            }
+            _ => {
+                self.0.push(major | 27);
+                self.0.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    }
+
+    pub fn uint(&mut self, n: u64) {
+        self.head(0, n);
+    }
+
+    pub fn int(&mut self, n: i64) {
+        match u64::try_from(n) {
+            Ok(n) => self.head(0, n),
+            Err(_) => self.head(1, !(n as u64)),
+        }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.head(2, bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub fn text(&mut self, s: &str) {
+        self.head(3, s.len() as u64);
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    pub fn array(&mut self, len: usize) {
+        self.head(4, len as u64);
+    }
+
+    pub fn map(&mut self, len: usize) {
+        self.head(5, len as u64);
+    }
+
+    pub fn tag(&mut self, tag: u64) {
+        self.head(6, tag);
+    }
+
+    /// Encode a JSON value
+    pub fn value(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.0.push(0xf6),
+            Value::Bool(false) => self.0.push(0xf4),
+            Value::Bool(true) => self.0.push(0xf5),
+            Value::Number(n) => {
+                if let Some(n) = n.as_u64() {
+                    self.uint(n);
+                } else if let Some(n) = n.as_i64() {
+                    self.int(n);
+                } else {
+                    self.0.push(0xfb);
+                    let n = n.as_f64().unwrap_or(f64::NAN);
+                    self.0.extend_from_slice(&n.to_be_bytes());
+                }
+            }
+            Value::String(s) => self.text(s),
+            Value::Array(items) => {
+                self.array(items.len());
+                items.iter().for_each(|item| self.value(item));
+            }
+            Value::Object(map) => {
+                self.map(map.len());
+                for (key, value) in map {
+                    self.text(key);
+                    self.value(value);
+                }
+            }
+        }
+    }
+}
+
+/// Encode `value` as CBOR
+pub fn to_vec<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+    encoder.value(&serde_json::to_value(value).expect("payloads serialize to JSON"));
+    encoder.0
+}
+
+/// Decode a CBOR item holding a `T`
+pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    let mut decoder = Decoder { input: bytes };
+    let value = decoder.value(0)?;
+    if !decoder.input.is_empty() {
+        return Err("trailing bytes after the CBOR item".to_string());
+    }
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+/// Whether the client asked for CBOR, or a sequence of items if `sequence`
+pub(crate) fn accepts(headers: &HeaderMap, sequence: bool) -> bool {
+    let wanted = if sequence { CBOR_SEQ } else { CBOR };
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.split(';').next().unwrap_or_default().trim() == wanted)
+}
+
+/// Respond with `value` as CBOR if the client accepts it, or as JSON
+pub(crate) fn negotiate<T: Serialize>(headers: &HeaderMap, value: &T) -> Response {
+    match accepts(headers, false) {
+        true => (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(CBOR))],
+            to_vec(value),
+        )
+            .into_response(),
+        false => Json(value).into_response(),
+    }
+}
+
+/// Request body decoded from CBOR or JSON, according to its `Content-Type`
+pub(crate) struct Negotiated<T>(pub T);
+
+impl<T, S> FromRequest<S> for Negotiated<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_cbor = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(';').next().unwrap_or_default().trim() == CBOR);
+        if !is_cbor {
+            let Json(value) = Json::from_request(request, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self(value));
+        }
+
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        from_slice(&body)
+            .map(Self)
+            .map_err(|e| ApiError::BadRequest(format!("Invalid CBOR body: {}", e)).into_response())
+    }
+}
+
+/// Decoder of the CBOR items that map to JSON values
+struct Decoder<'a> {
+    input: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.input.len() < len {
+            return Err("truncated CBOR item".to_string());
+        }
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    /// Read the argument of a head with the additional information `info`
+    fn argument(&mut self, info: u8) -> Result<u64, String> {
+        let len = match info {
+            0..=23 => return Ok(info as u64),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err("indefinite lengths are not supported".to_string()),
+        };
+        let mut bytes = [0u8; 8];
+        bytes[8 - len..].copy_from_slice(self.take(len)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Read a length, which can't exceed the remaining input
+    fn length(&mut self, info: u8) -> Result<usize, String> {
+        let len = self.argument(info)?;
+        match usize::try_from(len) {
+            Ok(len) if len <= self.input.len() => Ok(len),
+            _ => Err("CBOR length exceeds the input".to_string()),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("CBOR item nested too deeply".to_string());
+        }
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        Ok(match major {
+            0 => Value::from(self.argument(info)?),
+            1 => {
+                let n = self.argument(info)?;
+                let n = i64::try_from(n).map_err(|_| "negative integer out of range")?;
+                Value::from(-1 - n)
+            }
+            2 => {
+                let len = self.length(info)?;
+                Value::String(BASE64.encode(self.take(len)?))
+            }
+            3 => {
+                let len = self.length(info)?;
+                let text = std::str::from_utf8(self.take(len)?).map_err(|e| e.to_string())?;
+                Value::String(text.to_string())
+            }
+            4 => {
+                let len = self.length(info)?;
+                let items = (0..len)
+                    .map(|_| self.value(depth + 1))
+                    .collect::<Result<_, _>>()?;
+                Value::Array(items)
+            }
+            5 => {
+                let len = self.length(info)?;
+                let mut map = Map::new();
+                for _ in 0..len {
+                    let Value::String(key) = self.value(depth + 1)? else {
+                        return Err("CBOR map keys must be text".to_string());
+                    };
+                    map.insert(key, self.value(depth + 1)?);
+                }
+                Value::Object(map)
+            }
+            6 => {
+                self.argument(info)?;
+                self.value(depth + 1)?
+            }
+            _ => match info {
+                20 => Value::Bool(false),
+                21 => Value::Bool(true),
+                22 | 23 => Value::Null,
+                25 => float(f16_to_f64(u16::from_be_bytes(
+                    self.take(2)?.try_into().unwrap(),
+                ))),
+                26 => float(f32::from_be_bytes(self.take(4)?.try_into().unwrap()) as f64),
+                27 => float(f64::from_be_bytes(self.take(8)?.try_into().unwrap())),
+                _ => return Err(format!("unsupported CBOR simple value {}", info)),
+            },
+        })
+    }
+}
+
+/// A float as a JSON number; JSON has no NaN or infinities
+fn float(n: f64) -> Value {
+    Number::from_f64(n).map_or(Value::Null, Value::Number)
+}
+
+fn f16_to_f64(half: u16) -> f64 {
+    let exponent = (half >> 10) & 0x1f;
+    let mantissa = (half & 0x3ff) as f64;
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent as i32 - 15),
+    };
+    match half >> 15 {
+        0 => magnitude,
+        _ => -magnitude,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_cbor_round_trip() {
+        let value = json!({
+            "count": 2,
+            "keys": ["a", "b"],
+            "offset": -500,
+            "ratio": 0.5,
+            "next_cursor": null,
+            "reverse": true,
+        });
+        let bytes = to_vec(&value);
+        assert_eq!(from_slice::<Value>(&bytes).unwrap(), value);
+
+        // RFC 8949 appendix A examples
+        assert_eq!(to_vec(&json!(1000000)), [0x1a, 0x00, 0x0f, 0x42, 0x40]);
+        assert_eq!(to_vec(&json!(-1000)), [0x39, 0x03, 0xe7]);
+        assert_eq!(to_vec(&json!({"a": [2, 3]})), b"\xa1\x61a\x82\x02\x03");
+        assert_eq!(from_slice::<f64>(&[0xf9, 0x3e, 0x00]).unwrap(), 1.5);
+        assert_eq!(from_slice::<f64>(&[0xf9, 0xc4, 0x00]).unwrap(), -4.0);
+
+        // Byte strings read as base64, tags are ignored
+        assert_eq!(
+            from_slice::<String>(&[0xc2, 0x43, 1, 2, 3]).unwrap(),
+            "AQID"
+        );
+
+        assert!(from_slice::<Value>(&[0x82, 0x01]).is_err());
+        assert!(from_slice::<Value>(&[0x5b, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]).is_err());
+        assert!(from_slice::<Value>(&[0x01, 0x02]).is_err());
+        assert!(from_slice::<Value>(&[0x81; 100]).is_err());
+    }
+}
@@ -0,0 +1,554 @@
+//! Command line interface of the server binary
+//!
+//! Admin subcommands talk to a running server's admin API.
+
+use clap::{Args, Parser, Subcommand};
+use pubky_server::ADMIN_PASSWORD_HEADER;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::config::LogFormat;
+
+pub type CliResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Pubky MVP homeserver
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub serve: ServeArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options for running the server
+///
+/// Options also found in the configuration file override it, and are read
+/// from the environment variables named in their help.
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Read settings from this TOML file
+    #[arg(long, value_name = "PATH", env = "PUBKY_SERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on [default: 127.0.0.1:3000]
+    #[arg(long, env = "PUBKY_BIND")]
+    pub bind: Option<SocketAddr>,
+
+    /// Keep entries as files under this directory instead of in memory
+    #[arg(long, value_name = "DIR", env = "PUBKY_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Keep entries in the object store at this URL, such as
+    /// s3://bucket/prefix, instead of in memory
+    #[cfg(feature = "object-store")]
+    #[arg(long, value_name = "URL", env = "PUBKY_OBJECT_STORE", conflicts_with = "data_dir")]
+    pub object_store: Option<String>,
+
+    /// Setting of the object store, such as aws_region=eu-central-1
+    /// (repeatable)
+    #[cfg(feature = "object-store")]
+    #[arg(long = "object-store-option", value_name = "KEY=VALUE", value_parser = parse_option, requires = "object_store")]
+    pub object_store_options: Vec<(String, String)>,
+
+    /// Reject request bodies larger than this many bytes [default: 2 MiB]
+    #[arg(long, value_name = "BYTES", env = "PUBKY_BODY_LIMIT")]
+    pub body_limit: Option<usize>,
+
+    /// Only allow cross-origin requests from this origin, such as
+    /// https://app.example, instead of from any (repeatable)
+    #[arg(long = "cors-origin", value_name = "ORIGIN", env = "PUBKY_CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Vec<String>,
+
+    /// How to print logs [default: text]
+    #[arg(long, value_enum, env = "PUBKY_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Which logs to print, such as pubky_server=info
+    #[arg(long, value_name = "FILTER", env = "RUST_LOG")]
+    pub log_filter: Option<String>,
+
+    /// Listen on HTTPS with this PEM certificate chain
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", env = "PUBKY_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the HTTPS certificate
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "PATH", env = "PUBKY_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Listen on HTTPS with a self-signed certificate of the server's
+    /// identity key, for clients pinning it
+    #[cfg(feature = "tls")]
+    #[arg(long, conflicts_with = "tls_cert")]
+    pub tls_self_signed: bool,
+
+    /// Redirect plain HTTP at this address, such as 0.0.0.0:80, to HTTPS
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "ADDR", env = "PUBKY_HTTPS_REDIRECT")]
+    pub https_redirect: Option<SocketAddr>,
+
+    /// Listen on HTTPS with a certificate for this domain from Let's
+    /// Encrypt (repeatable)
+    #[cfg(feature = "acme")]
+    #[arg(long = "acme-domain", value_name = "DOMAIN", env = "PUBKY_ACME_DOMAINS", value_delimiter = ',', conflicts_with = "tls_cert")]
+    pub acme_domains: Vec<String>,
+
+    /// Contact of the ACME account, such as mailto:admin@example.com
+    /// (repeatable)
+    #[cfg(feature = "acme")]
+    #[arg(long = "acme-contact", value_name = "URL", env = "PUBKY_ACME_CONTACT", value_delimiter = ',')]
+    pub acme_contact: Vec<String>,
+
+    /// Order certificates from this ACME directory instead of Let's Encrypt
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "URL", env = "PUBKY_ACME_DIRECTORY")]
+    pub acme_directory: Option<String>,
+
+    /// Order untrusted test certificates from the Let's Encrypt staging
+    /// environment
+    #[cfg(feature = "acme")]
+    #[arg(long, conflicts_with = "acme_directory")]
+    pub acme_staging: bool,
+
+    /// Cache the ACME account and certificate in this directory
+    /// [default: .acme under the data directory]
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "DIR", env = "PUBKY_ACME_CACHE_DIR")]
+    pub acme_cache_dir: Option<PathBuf>,
+
+    /// Developer mode: seed well-known test users and sample data
+    #[arg(long)]
+    pub dev: bool,
+
+    /// Only accept signups with an invite code created by an admin
+    #[arg(long)]
+    pub require_invite: bool,
+
+    /// Enable the admin API with this password
+    #[arg(long, env = "PUBKY_ADMIN_PASSWORD")]
+    pub admin_password: Option<String>,
+
+    /// Run as a read replica of the server at this URL
+    #[arg(long, value_name = "URL")]
+    pub replica_of: Option<String>,
+
+    /// Admin password of the primary, used by replicas to pull snapshots
+    #[arg(long, env = "PUBKY_PRIMARY_PASSWORD", requires = "replica_of")]
+    pub primary_password: Option<String>,
+
+    /// Seconds between replica snapshot pulls
+    #[arg(long, default_value_t = 30, requires = "replica_of")]
+    pub replica_sync_secs: u64,
+
+    /// Throttle writes per public key (see the --throttle-* limits)
+    #[arg(long)]
+    pub write_throttle: bool,
+
+    /// Sustained write operations per second per public key
+    #[arg(long, requires = "write_throttle")]
+    pub throttle_ops: Option<f64>,
+
+    /// Burst of write operations per public key
+    #[arg(long, requires = "write_throttle")]
+    pub throttle_ops_burst: Option<f64>,
+
+    /// Sustained bytes written per second per public key
+    #[arg(long, requires = "write_throttle")]
+    pub throttle_bytes: Option<f64>,
+
+    /// Burst of bytes written per public key
+    #[arg(long, requires = "write_throttle")]
+    pub throttle_bytes_burst: Option<f64>,
+
+    /// Record an audit log of mutating requests
+    #[arg(long)]
+    pub audit: bool,
+
+    /// Also append audit records to this JSON lines file
+    #[arg(long, value_name = "PATH", requires = "audit")]
+    pub audit_file: Option<PathBuf>,
+
+    /// Days to keep audit records queryable in memory
+    #[arg(long, default_value_t = 7, requires = "audit")]
+    pub audit_retention_days: u64,
+
+    /// Refuse public content whose SHA-256 is listed in this file, one hex
+    /// hash per line
+    #[arg(long, value_name = "PATH")]
+    pub blocklist: Option<PathBuf>,
+
+    /// Quarantine blocklisted content for admin review instead of refusing
+    /// it
+    #[arg(long, requires = "blocklist")]
+    pub blocklist_quarantine: bool,
+
+    /// Hold large public writes back until this command, such as
+    /// "clamdscan --no-summary -", accepts them on its standard input
+    #[arg(long, value_name = "COMMAND")]
+    pub scan_command: Option<String>,
+
+    /// Smallest write scanned, in bytes
+    #[arg(long, default_value_t = 1024 * 1024, requires = "scan_command")]
+    pub scan_threshold: usize,
+
+    /// Accept resumable uploads, keeping their bytes under this directory
+    /// until complete [default with --data-dir: <DATA_DIR>/.uploads]
+    #[arg(long, value_name = "DIR")]
+    pub upload_dir: Option<PathBuf>,
+
+    /// Asynchronously replicate all mutations to the server at this URL
+    #[arg(long, value_name = "URL")]
+    pub mirror_to: Option<String>,
+
+    /// Admin password of the mirror server
+    #[arg(long, env = "PUBKY_MIRROR_PASSWORD", requires = "mirror_to")]
+    pub mirror_password: Option<String>,
+
+    /// Announce the server over pkarr as reachable at this host name
+    #[arg(long, value_name = "HOST")]
+    pub pkarr_host: Option<String>,
+
+    /// Secret key of the server's identity, announced over pkarr, named in
+    /// auth tokens, reachable through tunnels and certified by
+    /// --tls-self-signed, as 64 hex digits; a random identity is used
+    /// otherwise
+    #[arg(long, env = "PUBKY_PKARR_SECRET_KEY")]
+    pub pkarr_secret_key: Option<String>,
+
+    /// Publish to this pkarr relay instead of the default ones (repeatable)
+    #[arg(long = "pkarr-relay", value_name = "URL", requires = "pkarr_host")]
+    pub pkarr_relays: Vec<String>,
+
+    /// Act as a pkarr relay under /pkarr, for testnets
+    #[arg(long)]
+    pub pkarr_relay_server: bool,
+
+    /// Stay reachable from behind NAT through a tunnel to the relay at this
+    /// URL
+    #[arg(long, value_name = "URL")]
+    pub tunnel_relay: Option<String>,
+
+    /// Relay requests to homeservers that open a tunnel to this server
+    #[arg(long)]
+    pub tunnel_relay_server: bool,
+
+    /// Relay sign-in grants under /relay to apps on other origins
+    #[arg(long)]
+    pub auth_relay_server: bool,
+
+    /// PEM certificate chain of the QUIC endpoints
+    #[cfg(feature = "quic")]
+    #[arg(long, value_name = "PATH", requires = "quic_key")]
+    pub quic_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key of the QUIC certificate
+    #[cfg(feature = "quic")]
+    #[arg(long, value_name = "PATH", requires = "quic_cert")]
+    pub quic_key: Option<std::path::PathBuf>,
+
+    /// Also serve HTTP/3 on the UDP port of the listener
+    #[cfg(feature = "http3")]
+    #[arg(long, requires = "quic_cert")]
+    pub http3: bool,
+
+    /// Transfer large entries over raw QUIC streams at this UDP address
+    #[cfg(feature = "quic-blobs")]
+    #[arg(long, value_name = "ADDR", requires = "quic_cert")]
+    pub blob_bind: Option<std::net::SocketAddr>,
+
+    /// Also serve the gRPC API at this TCP address
+    #[cfg(feature = "grpc")]
+    #[arg(long, value_name = "ADDR")]
+    pub grpc_bind: Option<std::net::SocketAddr>,
+
+    /// Index text entries for full-text search
+    #[cfg(feature = "search")]
+    #[arg(long)]
+    pub search: bool,
+
+    /// Serve reads for users hosted elsewhere from their homeserver
+    #[arg(long)]
+    pub federate: bool,
+
+    /// Redirect reads for users hosted elsewhere instead of proxying them
+    #[arg(long, requires = "federate")]
+    pub federation_redirect: bool,
+
+    /// Keep users' public entries when proxying them, and serve them while
+    /// their homeserver is down
+    #[arg(long, requires = "federate", conflicts_with = "federation_redirect")]
+    pub federation_mirror: bool,
+
+    /// Find other homeservers through this pkarr relay instead of the
+    /// default ones (repeatable)
+    #[arg(long = "federation-relay", value_name = "URL", requires = "federate")]
+    pub federation_relays: Vec<String>,
+
+    /// Expose users' posts over ActivityPub, with this public URL of the
+    /// server in the ids of actors and posts
+    #[arg(long, value_name = "URL")]
+    pub activitypub_url: Option<String>,
+
+    /// Announce the content hashes of entries under this prefix, such as
+    /// pub/, on the Mainline DHT
+    #[arg(long, value_name = "PREFIX")]
+    pub dht_prefix: Option<String>,
+
+    /// Start DHT lookups from this node, as host:port, instead of the
+    /// default ones (repeatable)
+    #[arg(long = "dht-bootstrap", value_name = "ADDR", requires = "dht_prefix")]
+    pub dht_bootstrap: Vec<String>,
+
+    /// DNS-over-HTTPS resolver (JSON API) used to verify domain aliases
+    #[arg(long, value_name = "URL", default_value = pubky_server::DEFAULT_DOH_RESOLVER)]
+    pub doh_resolver: String,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Administer a running server
+    Admin(AdminArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct AdminArgs {
+    /// Base URL of the running server
+    #[arg(long, default_value = "http://127.0.0.1:3000")]
+    pub url: String,
+
+    /// Admin password configured on the server
+    #[arg(long, env = "PUBKY_ADMIN_PASSWORD")]
+    pub password: String,
+
+    #[command(subcommand)]
+    pub command: AdminCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AdminCommand {
+    /// List users with stored data
+    ListUsers,
+    /// Delete all data stored for a public key
+    Purge {
+        /// Public key (z-base-32) of the user to purge
+        public_key: String,
+    },
+    /// Freeze an account: reject writes and deletes, exclude it from purges
+    Freeze {
+        /// Public key (z-base-32) of the account
+        public_key: String,
+        /// Reason recorded with the freeze
+        #[arg(long, default_value = "")]
+        reason: String,
+        /// Reject reads of the account's data as well
+        #[arg(long)]
+        block_reads: bool,
+    },
+    /// Lift the freeze on an account
+    Unfreeze {
+        /// Public key (z-base-32) of the account
+        public_key: String,
+    },
+    /// Download every entry into a backup file
+    Backup {
+        /// File to write the backup to
+        path: PathBuf,
+    },
+    /// Store every entry of a backup file, over those at the same paths
+    Restore {
+        /// Backup file to read the entries from
+        path: PathBuf,
+    },
+    /// Manage invite codes
+    Invite {
+        #[command(subcommand)]
+        command: InviteCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum InviteCommand {
+    /// Create a new invite code
+    New,
+    /// List unused invite codes
+    List,
+}
+
+/// Parse a `KEY=VALUE` setting
+#[cfg(feature = "object-store")]
+fn parse_option(option: &str) -> Result<(String, String), String> {
+    let (key, value) = option
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {:?}", option))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Execute an admin subcommand against the server's admin API
+pub async fn run_admin(args: AdminArgs) -> CliResult<()> {
+    let client = AdminClient::new(&args.url, &args.password);
+
+    match args.command {
+        AdminCommand::ListUsers => {
+            let json = client.send(reqwest::Method::GET, "users").await?;
+            let users = json["users"].as_array().cloned().unwrap_or_default();
+            println!("{} users", users.len());
+            for user in users {
+                println!(
+                    "{}  {} entries  {} bytes",
+                    user["public_key"].as_str().unwrap_or_default(),
+                    user["entries"],
+                    user["bytes"]
+                );
+            }
+        }
+        AdminCommand::Purge { public_key } => {
+            let path = format!("users/{}", public_key);
+            let json = client.send(reqwest::Method::DELETE, &path).await?;
+            println!("Deleted {} entries", json["deleted"]);
+        }
+        AdminCommand::Freeze {
+            public_key,
+            reason,
+            block_reads,
+        } => {
+            let path = format!("users/{}/freeze", public_key);
+            let body = serde_json::json!({ "reason": reason, "block_reads": block_reads });
+            client
+                .send_json(reqwest::Method::PUT, &path, Some(body))
+                .await?;
+            println!("Froze {}", public_key);
+        }
+        AdminCommand::Unfreeze { public_key } => {
+            let path = format!("users/{}/freeze", public_key);
+            client.send(reqwest::Method::DELETE, &path).await?;
+            println!("Unfroze {}", public_key);
+        }
+        AdminCommand::Backup { path } => {
+            let response = client
+                .request(reqwest::Method::GET, "backup")
+                .send()
+                .await?;
+            let mut response = AdminClient::check(response).await?;
+            // Entries are written one per line as they arrive
+            let mut file = tokio::fs::File::create(&path).await?;
+            let (mut entries, mut bytes) = (0, 0);
+            let written: CliResult<()> = async {
+                while let Some(chunk) = response.chunk().await? {
+                    entries += chunk.iter().filter(|b| **b == b'\n').count();
+                    bytes += chunk.len();
+                    file.write_all(&chunk).await?;
+                }
+                Ok(file.flush().await?)
+            }
+            .await;
+            // A backup cut short by a server failure is incomplete
+            if let Err(e) = written {
+                let _ = tokio::fs::remove_file(&path).await;
+                return Err(format!("Backup failed: {}", e).into());
+            }
+            println!(
+                "Backed up {} entries ({} bytes) to {}",
+                entries,
+                bytes,
+                path.display()
+            );
+        }
+        AdminCommand::Restore { path } => {
+            let file = tokio::fs::File::open(&path).await?;
+            let body = reqwest::Body::wrap_stream(ReaderStream::new(file));
+            let response = client
+                .request(reqwest::Method::POST, "restore")
+                .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+                .body(body)
+                .send()
+                .await?;
+            let json = AdminClient::json(response).await?;
+            println!(
+                "Restored {} entries from {}",
+                json["restored"],
+                path.display()
+            );
+        }
+        AdminCommand::Invite {
+            command: InviteCommand::New,
+        } => {
+            let json = client.send(reqwest::Method::POST, "invites").await?;
+            println!("{}", json["code"].as_str().unwrap_or_default());
+        }
+        AdminCommand::Invite {
+            command: InviteCommand::List,
+        } => {
+            let json = client.send(reqwest::Method::GET, "invites").await?;
+            for code in json["invites"].as_array().cloned().unwrap_or_default() {
+                println!("{}", code.as_str().unwrap_or_default());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Minimal HTTP client for the admin API
+struct AdminClient {
+    http: reqwest::Client,
+    base_url: String,
+    password: String,
+}
+
+impl AdminClient {
+    fn new(base_url: &str, password: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    async fn send(&self, method: reqwest::Method, path: &str) -> CliResult<Value> {
+        self.send_json(method, path, None).await
+    }
+
+    async fn send_json(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> CliResult<Value> {
+        let mut request = self.request(method, path);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        Self::json(request.send().await?).await
+    }
+
+    /// A request to the admin API, carrying the password
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}/admin/{}", self.base_url, path);
+        self.http
+            .request(method, &url)
+            .header(ADMIN_PASSWORD_HEADER, &self.password)
+    }
+
+    /// The JSON body of `response`, or its error
+    async fn json(response: reqwest::Response) -> CliResult<Value> {
+        let response = Self::check(response).await?;
+        Ok(response.json().await.unwrap_or(Value::Null))
+    }
+
+    /// `response` if it succeeded, or the error it carries
+    async fn check(response: reqwest::Response) -> CliResult<reqwest::Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let json: Value = response.json().await.unwrap_or(Value::Null);
+        let message = json["error"].as_str().unwrap_or("request failed");
+        Err(format!("{} ({})", message, status).into())
+    }
+}
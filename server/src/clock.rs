@@ -0,0 +1,148 @@
+//! Time as seen by the server
+//!
+//! Session and authorization expiry, rate limits, caches and timestamps all
+//! read the [`Clock`] of the [`Storage`](crate::Storage), so tests can swap
+//! the system clock for a [`MockClock`] and move time forward at will
+//! instead of sleeping through TTLs:
+//!
+//! ```
+//! use pubky_server::{MockClock, Storage};
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! let clock = MockClock::new();
+//! let storage = Arc::new(Storage::with_clock(Arc::new(clock.clone())));
+//! let before = storage.now_millis();
+//! clock.advance(Duration::from_secs(60));
+//! assert_eq!(storage.now_millis(), before + 60_000);
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    /// Current Unix time in milliseconds
+    fn now_millis(&self) -> u64;
+}
+
+/// The system's wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock standing still until moved, for deterministic tests
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<AtomicU64>);
+
+impl MockClock {
+    /// A clock reading the current time, from then on only moved by hand
+    ///
+    /// Starting at the current time keeps tokens signed by clients, which
+    /// use their own clocks, within the allowed clock skew.
+    pub fn new() -> Self {
+        Self::at(SystemClock.now_millis())
+    }
+
+    /// A clock reading `millis`, in Unix time
+    pub fn at(millis: u64) -> Self {
+        Self(Arc::new(AtomicU64::new(millis)))
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Set the clock to `millis`, in Unix time, possibly back
+    pub fn set(&self, millis: u64) {
+        self.0.store(millis, Ordering::SeqCst);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, Storage};
+    use pubky_common::{auth::AuthToken, dto::SessionInfo, Keypair};
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        let clock = MockClock::at(1_000);
+        clock.clone().advance(Duration::from_secs(2));
+        assert_eq!(clock.now_millis(), 3_000);
+        clock.set(500);
+        assert_eq!(clock.now_millis(), 500);
+
+        // Sessions expire in virtual time
+        let clock = MockClock::new();
+        let storage = Arc::new(Storage::with_clock(Arc::new(clock.clone())));
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .storage(storage.clone())
+            .start()
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let keypair = Keypair::random();
+        let token = AuthToken::sign(&keypair, &server.identity());
+        let response = http
+            .post(format!("{}/signup", server.url()))
+            .json(&token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 201);
+        let session: SessionInfo = response.json().await.unwrap();
+        assert_eq!(
+            session.expires_at,
+            storage.now_millis() + 7 * 24 * 3_600_000
+        );
+        let sessions = || {
+            http.get(format!("{}/sessions", server.url()))
+                .bearer_auth(&session.token)
+                .send()
+        };
+        assert_eq!(sessions().await.unwrap().status(), 200);
+        clock.advance(crate::SESSION_TTL - Duration::from_secs(1));
+        assert_eq!(sessions().await.unwrap().status(), 200);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(sessions().await.unwrap().status(), 401);
+
+        // Tokens are checked against it too
+        assert!(storage.sessions_of(&keypair.public_key()).is_empty());
+        let stale = AuthToken::sign(&keypair, &server.identity());
+        let response = http
+            .post(format!("{}/session", server.url()))
+            .json(&stale)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        server.shutdown().await;
+    }
+}
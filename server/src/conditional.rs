@@ -0,0 +1,534 @@
+//! Conditional requests
+//!
+//! Entries are tagged with the SHA-256 hash of their value, returned as a
+//! strong `ETag` by GET and PUT, along with when they were last modified in
+//! `Last-Modified`. A GET with `If-None-Match` naming the current tag, or
+//! `*`, gets `304 Not Modified` without the value, as does one without it
+//! but with an `If-Modified-Since` date the entry wasn't modified after, so
+//! clients and caches only download entries that changed.
+//!
+//! A PUT or DELETE with `If-Match` only replaces the entry if it still has
+//! one of the given tags, or exists at all for `*`, and gets
+//! `412 Precondition Failed` otherwise. Two devices editing the same entry
+//! can't silently overwrite each other's changes that way: the second write
+//! fails, and its device reads the entry again to merge. Without
+//! `If-Match`, `If-Unmodified-Since` does the same by date, and GET requests
+//! fail the same way when either doesn't hold. Writes with `If-None-Match`
+//! fail the other way around, when the entry has one of the given tags, or
+//! exists at all for `*`, so `If-None-Match: *` only creates entries.
+
+use axum::http::{header, HeaderMap, HeaderName};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The `ETag` of a value with the given hex hash
+pub(crate) fn etag(hash: &str) -> String {
+    format!("\"{}\"", hash)
+}
+
+/// The `Last-Modified` date of an entry modified at a Unix timestamp in
+/// milliseconds
+pub(crate) fn last_modified(millis: u64) -> String {
+    httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// The date in the header `name` of a request, as a Unix timestamp in
+/// seconds, if it is valid
+fn date(headers: &HeaderMap, name: HeaderName) -> Option<u64> {
+    let date = httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()?;
+    Some(date.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Whether the copy of the client is current: `If-None-Match` names
+/// `etag`, or without it, the entry wasn't modified after the
+/// `If-Modified-Since` date
+///
+/// Tags are compared weakly, as HTTP requires for `If-None-Match`, and
+/// modification times to the second, as precise as HTTP dates are.
+pub(crate) fn not_modified(headers: &HeaderMap, etag: &str, modified: Option<u64>) -> bool {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+    }
+    match (date(headers, header::IF_MODIFIED_SINCE), modified) {
+        (Some(since), Some(modified)) => modified / 1000 <= since,
+        _ => false,
+    }
+}
+
+/// What a request expects of the entry it reads or replaces
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Preconditions {
+    /// The `If-Match` header, if any
+    pub(crate) if_match: Option<String>,
+    /// The `If-Unmodified-Since` date, as a Unix timestamp in seconds
+    if_unmodified_since: Option<u64>,
+    /// The `If-None-Match` header of a write, if any
+    if_none_match: Option<String>,
+}
+
+impl Preconditions {
+    /// The preconditions in the headers of a request
+    pub(crate) fn of(headers: &HeaderMap) -> Self {
+        let values: Vec<_> = headers
+            .get_all(header::IF_MATCH)
+            .iter()
+            .map(|value| value.to_str().unwrap_or_default())
+            .collect();
+        Self {
+            if_match: (!values.is_empty()).then(|| values.join(", ")),
+            if_unmodified_since: date(headers, header::IF_UNMODIFIED_SINCE),
+            if_none_match: None,
+        }
+    }
+
+    /// The preconditions in the headers of a write, where `If-None-Match`
+    /// is one too
+    pub(crate) fn of_write(headers: &HeaderMap) -> Self {
+        let values: Vec<_> = headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .map(|value| value.to_str().unwrap_or_default())
+            .collect();
+        Self {
+            if_none_match: (!values.is_empty()).then(|| values.join(", ")),
+            ..Self::of(headers)
+        }
+    }
+
+    /// Whether the request sets none
+    pub(crate) fn is_empty(&self) -> bool {
+        self.if_match.is_none()
+            && self.if_unmodified_since.is_none()
+            && self.if_none_match.is_none()
+    }
+
+    /// Whether they hold for the entry with the hex hash returned by `hash`,
+    /// modified at the Unix timestamp in milliseconds returned by
+    /// `modified`, either `None` if there is no entry
+    ///
+    /// Tags are compared strongly, as HTTP requires for `If-Match`, so weak
+    /// tags never match, and weakly for `If-None-Match`.
+    /// `If-Unmodified-Since` only applies without `If-Match`, and to entries
+    /// that exist. Only what they need is looked up.
+    pub(crate) fn hold(
+        &self,
+        hash: impl FnOnce() -> Option<String>,
+        modified: impl FnOnce() -> Option<u64>,
+    ) -> bool {
+        let mut hash = Some(hash);
+        let mut current = None;
+        let mut current_etag = || -> Option<String> {
+            let hash = hash.take();
+            current
+                .get_or_insert_with(|| hash.and_then(|hash| hash()).map(|hash| etag(&hash)))
+                .clone()
+        };
+        let matched = match &self.if_match {
+            Some(if_match) => current_etag().is_some_and(|etag| {
+                if_match
+                    .split(',')
+                    .map(str::trim)
+                    .any(|tag| tag == "*" || tag == etag)
+            }),
+            None => match (self.if_unmodified_since, modified()) {
+                (Some(since), Some(modified)) => modified / 1000 <= since,
+                _ => true,
+            },
+        };
+        let Some(if_none_match) = self.if_none_match.as_ref().filter(|_| matched) else {
+            return matched;
+        };
+        let Some(etag) = current_etag() else {
+            return true;
+        };
+        !if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockClock, Server, Storage};
+    use pubky_common::auth::AuthToken;
+    use pubky_common::dto::{ErrorResponse, SessionInfo};
+    use pubky_common::Keypair;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = etag("ab12");
+        assert_eq!(etag, "\"ab12\"");
+        let matches = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, value.parse().unwrap());
+            not_modified(&headers, &etag, None)
+        };
+        assert!(matches("\"ab12\""));
+        assert!(matches("W/\"ab12\""));
+        assert!(matches("\"cd34\", \"ab12\""));
+        assert!(matches("*"));
+        assert!(!matches("\"cd34\""));
+        assert!(!matches("ab12"));
+        assert!(!not_modified(&HeaderMap::new(), &etag, Some(0)));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let at = 784_111_777_000;
+        assert_eq!(last_modified(at + 999), date);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MODIFIED_SINCE, date.parse().unwrap());
+        assert!(not_modified(&headers, "\"ab12\"", Some(at + 999)));
+        assert!(!not_modified(&headers, "\"ab12\"", Some(at + 1_000)));
+        assert!(!not_modified(&headers, "\"ab12\"", None));
+
+        // If-None-Match takes precedence
+        headers.insert(header::IF_NONE_MATCH, "\"cd34\"".parse().unwrap());
+        assert!(!not_modified(&headers, "\"ab12\"", Some(at)));
+
+        headers.insert(header::IF_MODIFIED_SINCE, "yesterday".parse().unwrap());
+        headers.remove(header::IF_NONE_MATCH);
+        assert!(!not_modified(&headers, "\"ab12\"", Some(at)));
+    }
+
+    #[test]
+    fn test_preconditions() {
+        let of = |name: HeaderName, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            Preconditions::of(&headers)
+        };
+        let hold = |preconditions: &Preconditions, hash: Option<&str>, modified: Option<u64>| {
+            preconditions.hold(|| hash.map(str::to_string), || modified)
+        };
+        let at = 784_111_777_000;
+
+        let if_match = of(header::IF_MATCH, "\"cd34\", \"ab12\"");
+        assert!(hold(&if_match, Some("ab12"), None));
+        assert!(!hold(&if_match, Some("ef56"), None));
+        assert!(!hold(&if_match, None, None));
+        assert!(!hold(
+            &of(header::IF_MATCH, "W/\"ab12\""),
+            Some("ab12"),
+            None
+        ));
+        assert!(hold(&of(header::IF_MATCH, "*"), Some("ab12"), None));
+        assert!(!hold(&of(header::IF_MATCH, "*"), None, None));
+
+        let since = of(header::IF_UNMODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(hold(&since, None, Some(at + 999)));
+        assert!(!hold(&since, None, Some(at + 1_000)));
+        assert!(hold(&since, None, None));
+
+        let mut headers = HeaderMap::new();
+        assert!(Preconditions::of(&headers).is_empty());
+        headers.append(header::IF_MATCH, "\"ab12\"".parse().unwrap());
+        headers.append(header::IF_MATCH, "\"cd34\"".parse().unwrap());
+        headers.insert(
+            header::IF_UNMODIFIED_SINCE,
+            "Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap(),
+        );
+        let both = Preconditions::of(&headers);
+        assert_eq!(both.if_match.as_deref(), Some("\"ab12\", \"cd34\""));
+        // If-Match takes precedence
+        assert!(hold(&both, Some("ab12"), Some(at + 1_000)));
+        assert!(!hold(&both, Some("ef56"), Some(at)));
+
+        // If-None-Match only counts for writes
+        headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, "*".parse().unwrap());
+        assert!(Preconditions::of(&headers).is_empty());
+        let create = Preconditions::of_write(&headers);
+        assert!(hold(&create, None, None));
+        assert!(!hold(&create, Some("ab12"), None));
+        headers.insert(header::IF_NONE_MATCH, "W/\"ab12\"".parse().unwrap());
+        let other = Preconditions::of_write(&headers);
+        assert!(!hold(&other, Some("ab12"), None));
+        assert!(hold(&other, Some("cd34"), None));
+        headers.insert(header::IF_MATCH, "\"cd34\"".parse().unwrap());
+        assert!(!hold(
+            &Preconditions::of_write(&headers),
+            Some("ef56"),
+            None
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_etags() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let session: SessionInfo = http
+            .post(format!("{}/signup", server.url()))
+            .json(&AuthToken::sign(&keypair, &server.identity()))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let url = format!("{}/{}/pub/a.txt", server.url(), public_key);
+        let put = |value: &'static str| {
+            http.put(&url)
+                .bearer_auth(&session.token)
+                .body(value)
+                .send()
+        };
+        let get = |etag: &str| http.get(&url).header(header::IF_NONE_MATCH, etag).send();
+
+        let response = put("first").await.unwrap();
+        assert_eq!(response.status(), 201);
+        let first = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(response.headers()[header::ETAG], first.as_str());
+
+        let response = get(&first).await.unwrap();
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()[header::ETAG], first.as_str());
+        assert!(response.bytes().await.unwrap().is_empty());
+
+        // A changed value is sent again, with its new tag
+        let second = put("second").await.unwrap().headers()[header::ETAG].clone();
+        assert_ne!(second, first.as_str());
+        let response = get(&first).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::ETAG], second);
+        assert_eq!(response.text().await.unwrap(), "second");
+
+        // Tags only depend on the value
+        server
+            .storage()
+            .put(public_key, "pub/b.txt".to_string(), b"first".to_vec())
+            .unwrap();
+        let response = http
+            .get(format!("{}/{}/pub/b.txt", server.url(), public_key))
+            .header(header::IF_NONE_MATCH, &first)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 304);
+
+        assert_eq!(
+            http.get(format!("{}/{}/pub/c.txt", server.url(), public_key))
+                .header(header::IF_NONE_MATCH, "*")
+                .send()
+                .await
+                .unwrap()
+                .status(),
+            404
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_swap() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let session: SessionInfo = http
+            .post(format!("{}/signup", server.url()))
+            .json(&AuthToken::sign(&keypair, &server.identity()))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let url = format!("{}/{}/pub/doc.txt", server.url(), public_key);
+        let put = |value: &'static str, if_match: &str| {
+            http.put(&url)
+                .bearer_auth(&session.token)
+                .header(header::IF_MATCH, if_match)
+                .body(value)
+                .send()
+        };
+
+        // Nothing to match yet
+        let response = put("draft", "*").await.unwrap();
+        assert_eq!(response.status(), 412);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.code.as_deref(), Some("conflict"));
+        assert_eq!(error.actual, None);
+        assert!(server
+            .storage()
+            .get(&public_key, "pub/doc.txt")
+            .unwrap()
+            .is_none());
+
+        server
+            .storage()
+            .put(public_key, "pub/doc.txt".to_string(), b"first".to_vec())
+            .unwrap();
+        let first = etag(&server.storage().hash(&public_key, "pub/doc.txt").unwrap());
+
+        // Two devices saw the first value; the second to write loses
+        let response = put("laptop", &first).await.unwrap();
+        assert_eq!(response.status(), 201);
+        let laptop = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = put("phone", &first).await.unwrap();
+        assert_eq!(response.status(), 412);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.expected, Some(first));
+        assert_eq!(error.actual, Some(laptop.clone()));
+        assert_eq!(
+            server
+                .storage()
+                .get(&public_key, "pub/doc.txt")
+                .unwrap()
+                .unwrap(),
+            b"laptop"
+        );
+
+        assert_eq!(put("merged", &laptop).await.unwrap().status(), 201);
+        assert_eq!(put("again", "*").await.unwrap().status(), 201);
+
+        // Only missing entries are created with If-None-Match: *
+        let create = |value: &'static str| {
+            http.put(&url)
+                .bearer_auth(&session.token)
+                .header(header::IF_NONE_MATCH, "*")
+                .body(value)
+                .send()
+        };
+        assert_eq!(create("other").await.unwrap().status(), 412);
+
+        // Deletes only happen in the expected version too
+        let delete = |if_match: &str| {
+            http.delete(&url)
+                .bearer_auth(&session.token)
+                .header(header::IF_MATCH, if_match)
+                .send()
+        };
+        assert_eq!(delete(&laptop).await.unwrap().status(), 412);
+        let again = etag(&server.storage().hash(&public_key, "pub/doc.txt").unwrap());
+        assert_eq!(delete(&again).await.unwrap().status(), 204);
+        assert_eq!(delete(&again).await.unwrap().status(), 412);
+        assert_eq!(create("fresh").await.unwrap().status(), 201);
+
+        // Versioned writes keep concurrent values as siblings instead
+        let response = http
+            .put(&url)
+            .bearer_auth(&session.token)
+            .header(header::IF_MATCH, "*")
+            .header(pubky_common::version::WRITER_HEADER, "laptop")
+            .body("v")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_last_modified() {
+        let clock = MockClock::new();
+        let storage = Arc::new(Storage::with_clock(Arc::new(clock.clone())));
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .storage(storage.clone())
+            .start()
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let session: SessionInfo = http
+            .post(format!("{}/signup", server.url()))
+            .json(&AuthToken::sign(&keypair, &server.identity()))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let url = format!("{}/{}/pub/doc.txt", server.url(), public_key);
+
+        clock.set(1_700_000_000_500);
+        let response = http
+            .put(&url)
+            .bearer_auth(&session.token)
+            .body("first")
+            .send()
+            .await
+            .unwrap();
+        let created = "Tue, 14 Nov 2023 22:13:20 GMT";
+        assert_eq!(response.headers()[header::LAST_MODIFIED], created);
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(response.headers()[header::LAST_MODIFIED], created);
+
+        let get = |name: HeaderName, date: &str| http.get(&url).header(name, date).send();
+        assert_eq!(
+            get(header::IF_MODIFIED_SINCE, created)
+                .await
+                .unwrap()
+                .status(),
+            304
+        );
+        let earlier = "Tue, 14 Nov 2023 22:13:19 GMT";
+        assert_eq!(
+            get(header::IF_MODIFIED_SINCE, earlier)
+                .await
+                .unwrap()
+                .status(),
+            200
+        );
+        assert_eq!(
+            get(header::IF_UNMODIFIED_SINCE, earlier)
+                .await
+                .unwrap()
+                .status(),
+            412
+        );
+
+        // Writes based on an older copy fail
+        clock.advance(Duration::from_secs(60));
+        let put = |value: &'static str, date: &str| {
+            http.put(&url)
+                .bearer_auth(&session.token)
+                .header(header::IF_UNMODIFIED_SINCE, date)
+                .body(value)
+                .send()
+        };
+        assert_eq!(put("second", created).await.unwrap().status(), 201);
+        assert_eq!(put("third", created).await.unwrap().status(), 412);
+        assert_eq!(
+            storage.get(&public_key, "pub/doc.txt").unwrap().unwrap(),
+            b"second"
+        );
+
+        let timestamps = storage.timestamps(&public_key, "pub/doc.txt").unwrap();
+        assert_eq!(timestamps.created, 1_700_000_000_500);
+        assert_eq!(timestamps.modified, 1_700_000_060_500);
+        storage.delete(&public_key, "pub/doc.txt").unwrap();
+        assert_eq!(storage.timestamps(&public_key, "pub/doc.txt"), None);
+
+        server.shutdown().await;
+    }
+}
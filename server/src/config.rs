@@ -0,0 +1,318 @@
+//! Configuration of the server binary
+//!
+//! Settings are read from a TOML file given with `--config`, then
+//! overridden by environment variables and command line flags:
+//!
+//! ```toml
+//! bind = "0.0.0.0:3000"
+//! data-dir = "/var/lib/pubky"
+//! body-limit = 10485760
+//! cors-origins = ["https://app.example"]
+//! log-format = "json"
+//! log-filter = "pubky_server=info"
+//! tls-cert = "/etc/pubky/cert.pem"
+//! tls-key = "/etc/pubky/key.pem"
+//! https-redirect = "0.0.0.0:80"
+//! # or instead of tls-cert and tls-key, tls-self-signed = true or
+//! acme-domains = ["home.example"]
+//! acme-contact = ["mailto:admin@home.example"]
+//! ```
+//!
+//! Other options are only given as flags.
+
+use clap::ValueEnum;
+use pubky_server::DEFAULT_BIND;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::cli::{CliResult, ServeArgs};
+
+/// Log filter used when none is configured
+const DEFAULT_LOG_FILTER: &str = "pubky_server=debug,tower_http=debug";
+
+/// Directory of the ACME cache under the data directory, hidden from the
+/// storage as it isn't a public key
+#[cfg(feature = "acme")]
+const ACME_CACHE_DIR: &str = ".acme";
+
+/// Settings of the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Address to listen on
+    pub bind: SocketAddr,
+    /// Where entries are kept
+    pub storage: StorageConfig,
+    /// Largest request body accepted, in bytes
+    pub body_limit: Option<usize>,
+    /// Origins allowed to make cross-origin requests; any if empty
+    pub cors_origins: Vec<String>,
+    pub log_format: LogFormat,
+    /// Which logs to print, in `RUST_LOG` syntax
+    pub log_filter: String,
+    /// Listen on HTTPS instead of plain HTTP
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsSettings>,
+}
+
+/// How to serve HTTPS
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsSettings {
+    pub certificate: CertificateSource,
+    /// Where to redirect plain HTTP from
+    pub redirect_from: Option<SocketAddr>,
+}
+
+/// Where the HTTPS certificate comes from
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone, PartialEq)]
+pub enum CertificateSource {
+    /// PEM certificate chain and private key files
+    Files { cert: PathBuf, key: PathBuf },
+    /// Self-signed with the server's identity key
+    SelfSigned,
+    /// Ordered over ACME for these domains
+    #[cfg(feature = "acme")]
+    Acme {
+        domains: Vec<String>,
+        contact: Vec<String>,
+        directory_url: String,
+        /// Where the account and certificate are cached
+        cache_dir: Option<PathBuf>,
+    },
+}
+
+/// Where entries are kept
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageConfig {
+    Memory,
+    /// As files under a directory
+    Disk(PathBuf),
+    /// In the object store at a URL, with its settings
+    #[cfg(feature = "object-store")]
+    ObjectStore {
+        url: String,
+        options: Vec<(String, String)>,
+    },
+}
+
+/// How logs are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Contents of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    bind: Option<SocketAddr>,
+    data_dir: Option<PathBuf>,
+    #[cfg(feature = "object-store")]
+    object_store: Option<String>,
+    #[cfg(feature = "object-store")]
+    object_store_options: std::collections::BTreeMap<String, String>,
+    body_limit: Option<usize>,
+    cors_origins: Vec<String>,
+    log_format: Option<LogFormat>,
+    log_filter: Option<String>,
+    #[cfg(feature = "tls")]
+    tls_cert: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    tls_key: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    tls_self_signed: bool,
+    #[cfg(feature = "tls")]
+    https_redirect: Option<SocketAddr>,
+    #[cfg(feature = "acme")]
+    acme_domains: Vec<String>,
+    #[cfg(feature = "acme")]
+    acme_contact: Vec<String>,
+    #[cfg(feature = "acme")]
+    acme_directory: Option<String>,
+    #[cfg(feature = "acme")]
+    acme_cache_dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// Read the configuration file named by `args`, if any, and apply the
+    /// environment and flags over it
+    pub fn load(args: &ServeArgs) -> CliResult<Self> {
+        let file = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| format!("reading {}: {}", path.display(), e))?;
+                toml::from_str(&text).map_err(|e| format!("in {}: {}", path.display(), e))?
+            }
+            None => ConfigFile::default(),
+        };
+        Self::merge(file, args)
+    }
+
+    /// Apply the environment and flags over the file's settings
+    fn merge(file: ConfigFile, args: &ServeArgs) -> CliResult<Self> {
+        Self::check_passwords(args)?;
+        let storage = match Self::storage_of_args(args) {
+            Some(storage) => storage,
+            None => Self::storage_of_file(&file)?,
+        };
+        #[cfg(feature = "tls")]
+        let tls = Self::tls_settings(&file, args, &storage)?;
+        let cors_origins = match args.cors_origins.is_empty() {
+            true => file.cors_origins,
+            false => args.cors_origins.clone(),
+        };
+        for origin in &cors_origins {
+            axum::http::HeaderValue::from_str(origin)
+                .map_err(|_| format!("invalid CORS origin {:?}", origin))?;
+        }
+
+        Ok(Self {
+            bind: args.bind.or(file.bind).unwrap_or(DEFAULT_BIND),
+            storage,
+            body_limit: args.body_limit.or(file.body_limit),
+            cors_origins,
+            log_format: args.log_format.or(file.log_format).unwrap_or_default(),
+            log_filter: args
+                .log_filter
+                .clone()
+                .or(file.log_filter)
+                .unwrap_or_else(|| DEFAULT_LOG_FILTER.to_string()),
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+
+    /// Refuse empty passwords, which would open the admin API, or those of
+    /// the servers replicated from or to, to anyone
+    fn check_passwords(args: &ServeArgs) -> CliResult<()> {
+        let present = |password: &Option<String>| password.as_ref().is_some_and(|p| !p.is_empty());
+        if args.admin_password.is_some() && !present(&args.admin_password) {
+            return Err("admin-password can't be empty".into());
+        }
+        if args.replica_of.is_some() && !present(&args.primary_password) {
+            return Err("replica-of requires a non-empty primary-password".into());
+        }
+        if args.mirror_to.is_some() && !present(&args.mirror_password) {
+            return Err("mirror-to requires a non-empty mirror-password".into());
+        }
+        Ok(())
+    }
+
+    /// How to serve HTTPS, if at all
+    #[cfg(feature = "tls")]
+    #[cfg_attr(not(feature = "acme"), allow(unused_variables))]
+    fn tls_settings(
+        file: &ConfigFile,
+        args: &ServeArgs,
+        storage: &StorageConfig,
+    ) -> CliResult<Option<TlsSettings>> {
+        let cert = args.tls_cert.clone().or_else(|| file.tls_cert.clone());
+        let key = args.tls_key.clone().or_else(|| file.tls_key.clone());
+        let redirect_from = args.https_redirect.or(file.https_redirect);
+        let files = match (cert, key) {
+            (Some(cert), Some(key)) => Some(CertificateSource::Files { cert, key }),
+            (None, None) => None,
+            _ => return Err("tls-cert and tls-key must be set together".into()),
+        };
+        let self_signed = (args.tls_self_signed || file.tls_self_signed)
+            .then_some(CertificateSource::SelfSigned);
+        #[cfg(feature = "acme")]
+        let acme = Self::acme_source(file, args, storage);
+        #[cfg(not(feature = "acme"))]
+        let acme = None;
+
+        let mut sources = [files, self_signed, acme].into_iter().flatten();
+        let certificate = match (sources.next(), sources.next()) {
+            (Some(certificate), None) => certificate,
+            (None, _) if redirect_from.is_none() => return Ok(None),
+            (None, _) => return Err("https-redirect requires a TLS certificate".into()),
+            (Some(_), Some(_)) => {
+                return Err(
+                    "only one of tls-cert, tls-self-signed and acme-domains can be set".into(),
+                );
+            }
+        };
+        Ok(Some(TlsSettings {
+            certificate,
+            redirect_from,
+        }))
+    }
+
+    /// The ACME settings, if any domain is set; the account and certificate
+    /// are cached in the data directory unless configured otherwise
+    #[cfg(feature = "acme")]
+    fn acme_source(
+        file: &ConfigFile,
+        args: &ServeArgs,
+        storage: &StorageConfig,
+    ) -> Option<CertificateSource> {
+        let domains = match args.acme_domains.is_empty() {
+            true => file.acme_domains.clone(),
+            false => args.acme_domains.clone(),
+        };
+        if domains.is_empty() {
+            return None;
+        }
+        let contact = match args.acme_contact.is_empty() {
+            true => file.acme_contact.clone(),
+            false => args.acme_contact.clone(),
+        };
+        let directory_url = match args.acme_staging {
+            true => Some(pubky_server::LETS_ENCRYPT_STAGING_DIRECTORY.to_string()),
+            false => args.acme_directory.clone(),
+        };
+        let cache_dir = args
+            .acme_cache_dir
+            .clone()
+            .or_else(|| file.acme_cache_dir.clone())
+            .or_else(|| match storage {
+                StorageConfig::Disk(dir) => Some(dir.join(ACME_CACHE_DIR)),
+                _ => None,
+            });
+        Some(CertificateSource::Acme {
+            domains,
+            contact,
+            directory_url: directory_url
+                .or_else(|| file.acme_directory.clone())
+                .unwrap_or_else(|| pubky_server::LETS_ENCRYPT_PRODUCTION_DIRECTORY.to_string()),
+            cache_dir,
+        })
+    }
+
+    /// The storage chosen by flags or the environment, if any
+    fn storage_of_args(args: &ServeArgs) -> Option<StorageConfig> {
+        #[cfg(feature = "object-store")]
+        if let Some(url) = &args.object_store {
+            return Some(StorageConfig::ObjectStore {
+                url: url.clone(),
+                options: args.object_store_options.clone(),
+            });
+        }
+        args.data_dir.clone().map(StorageConfig::Disk)
+    }
+
+    /// The storage chosen by the configuration file
+    fn storage_of_file(file: &ConfigFile) -> CliResult<StorageConfig> {
+        #[cfg(feature = "object-store")]
+        if let Some(url) = &file.object_store {
+            if file.data_dir.is_some() {
+                return Err("data-dir and object-store can't both be set".into());
+            }
+            return Ok(StorageConfig::ObjectStore {
+                url: url.clone(),
+                options: file.object_store_options.clone().into_iter().collect(),
+            });
+        }
+        Ok(match &file.data_dir {
+            Some(dir) => StorageConfig::Disk(dir.clone()),
+            None => StorageConfig::Memory,
+        })
+    }
+}
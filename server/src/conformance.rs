@@ -0,0 +1,517 @@
+//! Conformance suite for storage
+//!
+//! Checks of the behavior routes, replication and sync rely on: reads see
+//! the latest write, metadata stays with its value, listings match prefixes
+//! exactly and page through them in order, snapshots are sorted, deletes leave nothing behind but a
+//! `Delete` event, and concurrent versioned writes are all kept as
+//! siblings, even under a multi-threaded stress load. Run them all against
+//! a storage with [`storage_conformance_tests!`](crate::storage_conformance_tests)
+//! in a test module:
+//!
+//! ```ignore
+//! mod conformance {
+//!     pubky_server::storage_conformance_tests!(pubky_server::Storage::new);
+//! }
+//! ```
+//!
+//! A [`StorageBackend`](crate::StorageBackend) is checked through a storage
+//! over it, made with `|| Storage::with_backend(MyBackend::new(), clock)`.
+//!
+//! Each check takes an empty storage and panics on the first violation.
+//! Quotas are applied by the routes on top of storage, so they aren't
+//! covered here. Available with the `conformance` feature.
+
+use pubky_common::version::VersionVector;
+use pubky_common::{Keypair, PublicKey};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::storage::{EntryMeta, EventOp, Storage};
+
+fn user() -> PublicKey {
+    Keypair::random().public_key()
+}
+
+fn sorted(paths: Vec<String>) -> Vec<String> {
+    let set: BTreeSet<_> = paths.into_iter().collect();
+    set.into_iter().collect()
+}
+
+/// Reads return the latest value written, byte for byte, per user
+pub fn check_round_trip(storage: &Storage) {
+    let (alice, bob) = (user(), user());
+    assert_eq!(storage.get(&alice, "a.txt").unwrap(), None);
+
+    storage
+        .put(alice, "a.txt".to_string(), b"first".to_vec())
+        .unwrap();
+    storage
+        .put(alice, "a.txt".to_string(), b"second".to_vec())
+        .unwrap();
+    assert_eq!(storage.get(&alice, "a.txt").unwrap().unwrap(), b"second");
+    assert_eq!(storage.get(&bob, "a.txt").unwrap(), None);
+
+    // Empty and binary values, and paths beyond ASCII
+    storage.put(alice, "empty".to_string(), Vec::new()).unwrap();
+    assert_eq!(storage.get(&alice, "empty").unwrap().unwrap(), b"");
+    let binary: Vec<u8> = (0..=255).collect();
+    storage
+        .put(bob, "pub/données/ü.bin".to_string(), binary.clone())
+        .unwrap();
+    assert_eq!(
+        storage.get(&bob, "pub/données/ü.bin").unwrap().unwrap(),
+        binary
+    );
+}
+
+/// Listings return every path starting with the prefix, of that user only
+pub fn check_listing(storage: &Storage) {
+    let (alice, bob) = (user(), user());
+    for path in ["pub/a", "pub/ab", "pub/a/b", "pub/b/c", "private/a"] {
+        storage.put(alice, path.to_string(), b"x".to_vec()).unwrap();
+    }
+    storage
+        .put(bob, "pub/a".to_string(), b"x".to_vec())
+        .unwrap();
+
+    assert_eq!(
+        sorted(storage.list(&alice, "pub/a")),
+        ["pub/a", "pub/a/b", "pub/ab"]
+    );
+    assert_eq!(sorted(storage.list(&alice, "pub/a/")), ["pub/a/b"]);
+    assert_eq!(storage.list(&alice, "").len(), 5);
+    assert!(storage.list(&alice, "pub/c").is_empty());
+    assert_eq!(storage.list(&bob, "").len(), 1);
+}
+
+/// Pages of a listing come in path order, either way, after the cursor and
+/// together hold the whole listing
+pub fn check_list_pages(storage: &Storage) {
+    let (alice, bob) = (user(), user());
+    let paths = [
+        "pub/a",
+        "pub/a/b",
+        "pub/a/c/d",
+        "pub/a-b",
+        "pub/b",
+        "pub/c/ü",
+    ];
+    for path in paths.iter().chain(&["pub", "private/a"]) {
+        storage.put(alice, path.to_string(), b"x".to_vec()).unwrap();
+    }
+    storage
+        .put(bob, "pub/a0".to_string(), b"x".to_vec())
+        .unwrap();
+
+    for reverse in [false, true] {
+        let mut expected: Vec<_> = paths.iter().map(|path| path.to_string()).collect();
+        expected.sort();
+        if reverse {
+            expected.reverse();
+        }
+        for limit in 1..=expected.len() {
+            let mut listed = Vec::new();
+            loop {
+                let after = listed.last().map(String::as_str);
+                let page = storage.list_page(&alice, "pub/", after, limit, reverse);
+                assert!(page.len() <= limit);
+                let done = page.len() < limit;
+                listed.extend(page);
+                if done {
+                    break;
+                }
+            }
+            assert_eq!(listed, expected);
+        }
+    }
+
+    // Cursors need not be paths, nor under the prefix
+    let page = |after, reverse| storage.list_page(&alice, "pub/a", Some(after), 10, reverse);
+    assert_eq!(page("pub/a/", false), ["pub/a/b", "pub/a/c/d"]);
+    assert_eq!(page("pub/a/c", true), ["pub/a/b", "pub/a-b", "pub/a"]);
+    assert_eq!(page("a", false).len(), 4);
+    assert_eq!(page("pub/b", true).len(), 4);
+    assert!(page("pub/b", false).is_empty());
+    assert!(page("a", true).is_empty());
+}
+
+/// Snapshots of entries and users are sorted by public key, then path
+pub fn check_ordering(storage: &Storage) {
+    let users: Vec<_> = (0..5).map(|_| user()).collect();
+    for (i, public_key) in users.iter().enumerate() {
+        for path in ["b", "a/z", "a", "c/d"] {
+            storage
+                .put(*public_key, path.to_string(), vec![i as u8])
+                .unwrap();
+        }
+    }
+
+    let entries = storage.entries();
+    assert_eq!(entries.len(), 20);
+    let keys: Vec<_> = entries
+        .iter()
+        .map(|(pk, path, _)| (pk.to_z32(), path.clone()))
+        .collect();
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let usage = storage.users();
+    assert_eq!(usage.len(), 5);
+    assert!(usage
+        .windows(2)
+        .all(|pair| pair[0].public_key.to_z32() < pair[1].public_key.to_z32()));
+    assert!(usage
+        .iter()
+        .all(|user| user.entries == 4 && user.bytes == 4));
+}
+
+/// Deleted entries are gone from reads, listings and snapshots, and leave
+/// exactly one `Delete` event
+pub fn check_tombstones(storage: &Storage) {
+    let alice = user();
+    storage.put(alice, "a".to_string(), b"x".to_vec()).unwrap();
+    storage.put(alice, "b".to_string(), b"y".to_vec()).unwrap();
+    let tags = BTreeSet::from(["tag".to_string()]);
+    storage.set_tags(alice, "a", tags).unwrap();
+    let head = storage.head_seq();
+
+    assert!(storage.delete(&alice, "a").unwrap());
+    assert!(!storage.delete(&alice, "a").unwrap());
+    assert!(!storage.delete(&alice, "missing").unwrap());
+    assert_eq!(storage.get(&alice, "a").unwrap(), None);
+    assert_eq!(storage.list(&alice, ""), ["b"]);
+    assert!(storage.tags(&alice, "a").unwrap().is_empty());
+    assert!(storage.tagged(&alice, "", &["tag".to_string()]).is_empty());
+    assert!(storage.siblings(&alice, "a").unwrap().is_empty());
+
+    let events = storage.events_since(head, 10).unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].op, EventOp::Delete);
+    assert_eq!(events[0].path, "a");
+
+    // Writing again brings the entry back without its old tags
+    storage.put(alice, "a".to_string(), b"z".to_vec()).unwrap();
+    assert_eq!(storage.get(&alice, "a").unwrap().unwrap(), b"z");
+    assert!(storage.tags(&alice, "a").unwrap().is_empty());
+
+    // Purges tombstone every entry of the user
+    let head = storage.head_seq();
+    assert_eq!(storage.purge(&alice), 2);
+    assert!(storage.list(&alice, "").is_empty());
+    let events = storage.events_since(head, 10).unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|event| event.op == EventOp::Delete));
+}
+
+/// Metadata is kept with the value it was written with, left out of
+/// listings, and dropped when the value is replaced or deleted
+pub fn check_metadata(storage: &Storage) {
+    let alice = user();
+    let meta = EntryMeta {
+        content_type: Some("text/markdown".to_string()),
+        custom: [("title".to_string(), "Hello".to_string())].into(),
+        tags: ["post".to_string()].into(),
+        ..Default::default()
+    };
+    let path = "pub/posts/1.md";
+    storage
+        .put_with_meta(alice, path.to_string(), b"# Hi".to_vec(), meta.clone())
+        .unwrap();
+    assert_eq!(storage.meta(&alice, path).unwrap(), meta);
+    assert_eq!(storage.tags(&alice, path).unwrap(), ["post"]);
+    assert_eq!(
+        storage.tagged(&alice, "pub/", &["post".to_string()]),
+        [path]
+    );
+    assert_eq!(storage.list(&alice, ""), [path]);
+    assert_eq!(storage.entries().len(), 1);
+
+    storage
+        .put(alice, path.to_string(), b"# Hi again".to_vec())
+        .unwrap();
+    assert!(storage.meta(&alice, path).unwrap().is_empty());
+    assert!(storage.tagged(&alice, "", &["post".to_string()]).is_empty());
+
+    storage
+        .put_with_meta(alice, path.to_string(), b"# Hi".to_vec(), meta)
+        .unwrap();
+    assert!(storage.delete(&alice, path).unwrap());
+    assert!(storage.meta(&alice, path).unwrap().is_empty());
+    assert!(storage.list(&alice, "").is_empty());
+}
+
+/// Every mutation gets the next sequence number, and events can be read
+/// from any point
+pub fn check_events(storage: &Storage) {
+    let alice = user();
+    let head = storage.head_seq();
+    for i in 0..10 {
+        storage.put(alice, format!("{}", i), Vec::new()).unwrap();
+    }
+    assert_eq!(storage.head_seq(), head + 10);
+
+    let events = storage.events_since(head, 100).unwrap();
+    let seqs: Vec<_> = events.iter().map(|event| event.seq).collect();
+    assert_eq!(seqs, (head + 1..=head + 10).collect::<Vec<_>>());
+    assert!(events.iter().all(|event| event.op == EventOp::Put));
+    assert!(events.windows(2).all(|e| e[0].timestamp <= e[1].timestamp));
+
+    let page = storage.events_since(head + 4, 3).unwrap();
+    assert_eq!(page.first().unwrap().seq, head + 5);
+    assert_eq!(page.len(), 3);
+    assert!(storage
+        .events_since(storage.head_seq(), 10)
+        .unwrap()
+        .is_empty());
+}
+
+/// Versioned writes racing from many threads are all kept as siblings,
+/// and each writer's counter counts all of its writes
+pub fn check_versioned_atomicity(storage: Arc<Storage>) {
+    const WRITERS: usize = 8;
+    const WRITES: usize = 25;
+    let alice = user();
+
+    let threads: Vec<_> = (0..WRITERS)
+        .map(|w| {
+            let storage = storage.clone();
+            thread::spawn(move || {
+                let writer = format!("writer-{}", w);
+                for i in 0..WRITES {
+                    // Each writer only ever saw its own writes
+                    let mut context = VersionVector::new();
+                    context.insert(writer.clone(), i as u64);
+                    let value = format!("{}:{}", w, i).into_bytes();
+                    let meta = EntryMeta::default();
+                    storage
+                        .put_versioned(alice, "doc".to_string(), value, meta, &writer, &context)
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let siblings = storage.siblings(&alice, "doc").unwrap();
+    assert_eq!(siblings.len(), WRITERS);
+    let version = storage.version(&alice, "doc").unwrap().unwrap();
+    for w in 0..WRITERS {
+        assert_eq!(version.get(&format!("writer-{}", w)), WRITES as u64);
+    }
+    let last: BTreeSet<_> = siblings.into_iter().map(|(_, value)| value).collect();
+    let expected: BTreeSet<_> = (0..WRITERS)
+        .map(|w| format!("{}:{}", w, WRITES - 1).into_bytes())
+        .collect();
+    assert_eq!(last, expected);
+
+    // A plain write replaces every sibling
+    storage
+        .put(alice, "doc".to_string(), b"merged".to_vec())
+        .unwrap();
+    assert_eq!(storage.siblings(&alice, "doc").unwrap().len(), 1);
+    assert_eq!(storage.version(&alice, "doc").unwrap(), None);
+}
+
+/// Longest a read may wait while writers hammer the storage before the
+/// stress check blames lock contention
+pub const MAX_READ_STALL: Duration = Duration::from_secs(1);
+
+/// Mixed operations from many threads across many users, `ops` per thread,
+/// land exactly as if each thread ran alone, and never stall readers
+///
+/// Each thread owns a few users and checks every read, delete and listing
+/// against its own model of them, and all threads race versioned writes
+/// on one shared entry. Meanwhile a reader times reads of that entry,
+/// failing the check if one waits longer than [`MAX_READ_STALL`].
+pub fn check_concurrent_stress(storage: Arc<Storage>, ops: usize) {
+    const THREADS: usize = 8;
+    const USERS: usize = 4;
+    const PATHS: usize = 16;
+    let shared = user();
+    let head = storage.head_seq();
+    let done = Arc::new(AtomicBool::new(false));
+
+    let reader = {
+        let (storage, done) = (storage.clone(), done.clone());
+        thread::spawn(move || {
+            let mut stall = Duration::ZERO;
+            while !done.load(Ordering::Relaxed) {
+                let started = Instant::now();
+                storage.get(&shared, "doc").unwrap();
+                storage.list(&shared, "");
+                stall = stall.max(started.elapsed());
+            }
+            stall
+        })
+    };
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let storage = storage.clone();
+            thread::spawn(move || {
+                let mut rng = StdRng::seed_from_u64(t as u64);
+                let users: Vec<_> = (0..USERS).map(|_| user()).collect();
+                let mut model: HashMap<(usize, String), Vec<u8>> = HashMap::new();
+                let writer = format!("thread-{}", t);
+                let (mut mutations, mut versioned) = (0, 0);
+                for n in 0..ops {
+                    let u = rng.random_range(0..USERS);
+                    let path = format!("k{}", rng.random_range(0..PATHS));
+                    let key = (u, path.clone());
+                    match rng.random_range(0..10) {
+                        0..=3 => {
+                            let value = format!("{}:{}", t, n).into_bytes();
+                            storage.put(users[u], path, value.clone()).unwrap();
+                            model.insert(key, value);
+                            mutations += 1;
+                        }
+                        4..=6 => {
+                            assert_eq!(
+                                storage.get(&users[u], &path).unwrap(),
+                                model.get(&key).cloned()
+                            )
+                        }
+                        7 => {
+                            let deleted = storage.delete(&users[u], &path).unwrap();
+                            assert_eq!(deleted, model.remove(&key).is_some());
+                            mutations += deleted as u64;
+                        }
+                        8 => {
+                            let expected: BTreeSet<_> = model
+                                .keys()
+                                .filter(|(owner, _)| *owner == u)
+                                .map(|(_, path)| path.clone())
+                                .collect();
+                            let listed: BTreeSet<_> =
+                                storage.list(&users[u], "").into_iter().collect();
+                            assert_eq!(listed, expected);
+                        }
+                        _ => {
+                            let mut context = VersionVector::new();
+                            context.insert(writer.clone(), versioned);
+                            let value = writer.clone().into_bytes();
+                            storage
+                                .put_versioned(
+                                    shared,
+                                    "doc".to_string(),
+                                    value,
+                                    EntryMeta::default(),
+                                    &writer,
+                                    &context,
+                                )
+                                .unwrap();
+                            versioned += 1;
+                            mutations += 1;
+                        }
+                    }
+                }
+
+                // Nobody else touched this thread's users
+                for (u, public_key) in users.iter().enumerate() {
+                    for path in storage.list(public_key, "") {
+                        let expected = model.remove(&(u, path.clone()));
+                        assert_eq!(storage.get(public_key, &path).unwrap(), expected);
+                    }
+                }
+                assert!(model.is_empty());
+                (writer, versioned, mutations)
+            })
+        })
+        .collect();
+    let results: Vec<_> = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect();
+    done.store(true, Ordering::Relaxed);
+    let stall = reader.join().unwrap();
+
+    // Every versioned write counted, and one sibling per writer left
+    let version = storage.version(&shared, "doc").unwrap().unwrap_or_default();
+    let writers = results.iter().filter(|(_, versioned, _)| *versioned > 0);
+    assert_eq!(
+        storage.siblings(&shared, "doc").unwrap().len(),
+        writers.count()
+    );
+    for (writer, versioned, _) in &results {
+        assert_eq!(version.get(writer), *versioned);
+    }
+
+    // Every mutation got exactly one event
+    let mutations: u64 = results.iter().map(|(_, _, mutations)| mutations).sum();
+    assert_eq!(storage.head_seq() - head, mutations);
+    assert!(
+        stall < MAX_READ_STALL,
+        "A read waited {:?} on writers",
+        stall
+    );
+}
+
+/// Define a `#[test]` for every conformance check, each on a fresh storage
+/// made by `$make`
+#[macro_export]
+macro_rules! storage_conformance_tests {
+    ($make:expr) => {
+        #[test]
+        fn test_conformance_round_trip() {
+            $crate::conformance::check_round_trip(&$make());
+        }
+
+        #[test]
+        fn test_conformance_listing() {
+            $crate::conformance::check_listing(&$make());
+        }
+
+        #[test]
+        fn test_conformance_list_pages() {
+            $crate::conformance::check_list_pages(&$make());
+        }
+
+        #[test]
+        fn test_conformance_ordering() {
+            $crate::conformance::check_ordering(&$make());
+        }
+
+        #[test]
+        fn test_conformance_tombstones() {
+            $crate::conformance::check_tombstones(&$make());
+        }
+
+        #[test]
+        fn test_conformance_metadata() {
+            $crate::conformance::check_metadata(&$make());
+        }
+
+        #[test]
+        fn test_conformance_events() {
+            $crate::conformance::check_events(&$make());
+        }
+
+        #[test]
+        fn test_conformance_versioned_atomicity() {
+            $crate::conformance::check_versioned_atomicity(::std::sync::Arc::new($make()));
+        }
+
+        #[test]
+        fn test_conformance_concurrent_stress() {
+            // Raise PUBKY_STRESS_OPS for a longer run
+            let ops = ::std::env::var("PUBKY_STRESS_OPS")
+                .ok()
+                .and_then(|ops| ops.parse().ok())
+                .unwrap_or(2_000);
+            $crate::conformance::check_concurrent_stress(::std::sync::Arc::new($make()), ops);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Storage;
+
+    crate::storage_conformance_tests!(Storage::new);
+}
@@ -0,0 +1,94 @@
+//! Developer mode
+//!
+//! Seeds the server with well-known test identities and sample entries so
+//! frontend developers get a working playground without any setup.
+//! Never use these keypairs for real data: their secret keys are public.
+
+use std::io;
+
+use pubky_common::Keypair;
+
+use crate::storage::Storage;
+
+/// Admin password used in developer mode unless one is configured
+pub const DEV_ADMIN_PASSWORD: &str = "dev";
+
+/// A well-known developer identity
+#[derive(Debug, Clone)]
+pub struct DevUser {
+    pub name: &'static str,
+    pub keypair: Keypair,
+}
+
+/// The deterministic developer identities
+///
+/// User `n` (starting at 1) has the secret key `[n; 32]`.
+pub fn users() -> Vec<DevUser> {
+    ["alice", "bob", "carol"]
+        .into_iter()
+        .zip(1u8..)
+        .map(|(name, seed)| DevUser {
+            name,
+            keypair: Keypair::from_secret_key(&[seed; 32]),
+        })
+        .collect()
+}
+
+/// Register every developer identity and populate it with sample entries
+pub fn seed(storage: &Storage) -> io::Result<()> {
+    for user in users() {
+        let public_key = user.keypair.public_key();
+        storage.register(public_key);
+        let profile = format!(
+            r#"{{"name":"{}","bio":"Developer test account"}}"#,
+            user.name
+        );
+
+        storage.put(
+            public_key,
+            "pub/profile.json".to_string(),
+            profile.into_bytes(),
+        )?;
+        for n in 1..=3 {
+            let post = format!("Post {} by {}", n, user.name);
+            storage.put(
+                public_key,
+                format!("pub/posts/{}.txt", n),
+                post.into_bytes(),
+            )?;
+        }
+        storage.put(
+            public_key,
+            "my-app/settings.json".to_string(),
+            br#"{"theme":"dark"}"#.to_vec(),
+        )?;
+    }
+
+    tracing::info!("Seeded developer data for {} users", users().len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dev_users_are_deterministic() {
+        let first: Vec<_> = users().iter().map(|u| u.keypair.public_key()).collect();
+        let second: Vec<_> = users().iter().map(|u| u.keypair.public_key()).collect();
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 3);
+    }
+
+    #[test]
+    fn test_seed() {
+        let storage = Storage::new();
+        seed(&storage).unwrap();
+
+        let alice = users()[0].keypair.public_key();
+        assert_eq!(storage.list(&alice, "pub/posts/").len(), 3);
+        assert!(storage.get(&alice, "pub/profile.json").unwrap().is_some());
+        assert!(storage.is_registered(&alice));
+        assert_eq!(storage.users().len(), 3);
+    }
+}
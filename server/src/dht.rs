@@ -0,0 +1,266 @@
+//! Content announcement on the Mainline DHT
+//!
+//! With [`ServerBuilder::dht`](crate::ServerBuilder::dht), the server
+//! announces the entries under a public prefix, such as `pub/`, on the
+//! Mainline DHT used by BitTorrent. Each entry is announced under its
+//! content hash, the first 160 bits of the SHA-256 of its value, as a peer
+//! reachable on the server's HTTP port. Anyone holding a hash can find every
+//! homeserver serving that content with a `get_peers` lookup and fetch it
+//! from their `GET /content/{hash}`, not only from the origin homeserver.
+//!
+//! Queries go through [`pubky_common::krpc`]: for each hash the server walks
+//! towards the closest nodes with `get_peers` and sends them
+//! `announce_peer`, without keeping a routing table or answering queries of
+//! its own.
+
+use axum::{
+    extract::{Path, State},
+    routing::get,
+    Router,
+};
+use pubky_common::krpc::{dict, resolve, Bencode, Node};
+use pubky_common::PublicKey;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::routes::{ensure_readable, ApiError};
+use crate::storage::Storage;
+
+pub use pubky_common::krpc::{InfoHash, DEFAULT_BOOTSTRAP};
+
+/// The content hash a value is announced under
+pub fn info_hash(value: &[u8]) -> InfoHash {
+    Sha256::digest(value)[..20].try_into().unwrap()
+}
+
+/// What to announce on the DHT
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// Entries under this prefix, for every user, are announced
+    pub prefix: String,
+    /// `host:port` addresses of the nodes to start lookups from
+    pub bootstrap: Vec<String>,
+    /// Port clients reach the homeserver at; the bound port by default
+    pub port: Option<u16>,
+    /// How often to announce the content again
+    pub interval: Duration,
+}
+
+impl DhtConfig {
+    /// Announce the entries under `prefix` through the default bootstrap
+    /// nodes, every 15 minutes
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|b| b.to_string()).collect(),
+            port: None,
+            interval: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// The entry holding each announced content hash
+pub(crate) type ContentIndex = Arc<RwLock<HashMap<InfoHash, (PublicKey, String)>>>;
+
+/// Announce the content under the configured prefix on the configured
+/// interval, until the task is aborted
+pub(crate) async fn announce_loop(
+    config: DhtConfig,
+    storage: Arc<Storage>,
+    index: ContentIndex,
+    port: u16,
+) {
+    let mut node = match Node::bind().await {
+        Ok(node) => node,
+        Err(e) => {
+            tracing::error!("Failed to bind the DHT socket: {}", e);
+            return;
+        }
+    };
+    let port = config.port.unwrap_or(port);
+    loop {
+        let hashes = index_content(&storage, &config.prefix, &index);
+        let bootstrap = resolve(&config.bootstrap).await;
+        let mut announced = 0;
+        for hash in &hashes {
+            if announce(&mut node, hash, port, &bootstrap).await > 0 {
+                announced += 1;
+            }
+        }
+        tracing::info!(
+            "Announced {} of {} content hash(es) on the DHT",
+            announced,
+            hashes.len()
+        );
+        tokio::time::sleep(config.interval).await;
+    }
+}
+
+/// Index the entries under `prefix` by content hash, returning the hashes
+fn index_content(storage: &Storage, prefix: &str, index: &ContentIndex) -> Vec<InfoHash> {
+    let content: HashMap<_, _> = storage
+        .entries()
+        .into_iter()
+        .filter(|(_, path, _)| path.starts_with(prefix))
+        .map(|(public_key, path, value)| (info_hash(&value), (public_key, path)))
+        .collect();
+    let hashes = content.keys().copied().collect();
+    *index.write().unwrap() = content;
+    hashes
+}
+
+/// Create the content routes
+pub(crate) fn content_routes<S>(storage: Arc<Storage>, index: ContentIndex) -> Router<S> {
+    Router::new()
+        .route("/content/{hash}", get(get_content))
+        .with_state((storage, index))
+}
+
+/// GET /content/{hash}
+/// The value of an announced entry, by hex content hash
+async fn get_content(
+    State((storage, index)): State<(Arc<Storage>, ContentIndex)>,
+    Path(hash): Path<String>,
+) -> Result<Vec<u8>, ApiError> {
+    let hash = parse_hash(&hash)
+        .ok_or_else(|| ApiError::BadRequest(format!("Invalid content hash: {}", hash)))?;
+    let (public_key, path) = index
+        .read()
+        .unwrap()
+        .get(&hash)
+        .cloned()
+        .ok_or(ApiError::NotFound)?;
+    ensure_readable(&storage, &public_key)?;
+
+    // The entry may have changed since it was indexed
+    storage
+        .get(&public_key, &path)?
+        .filter(|value| info_hash(value) == hash)
+        .ok_or(ApiError::NotFound)
+}
+
+/// Parse a content hash written as 40 hex digits
+fn parse_hash(hex: &str) -> Option<InfoHash> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; 20];
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Announce `port` as a peer for `hash` to the closest nodes, returning
+/// how many acknowledged it
+async fn announce(node: &mut Node, hash: &InfoHash, port: u16, bootstrap: &[SocketAddr]) -> usize {
+    let args = dict([("info_hash", Bencode::Bytes(hash.to_vec()))]);
+    let closest = node.walk("get_peers", hash, args, bootstrap).await;
+
+    // Only nodes that handed out a token accept announcements
+    let queries = closest
+        .into_iter()
+        .filter_map(|(addr, answer)| {
+            let token = answer.bytes("token")?;
+            let args = dict([
+                ("info_hash", Bencode::Bytes(hash.to_vec())),
+                ("port", Bencode::Int(port as i64)),
+                ("token", Bencode::Bytes(token.to_vec())),
+            ]);
+            Some((addr, args))
+        })
+        .collect();
+    node.round("announce_peer", queries).await.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use pubky_common::krpc::Answer;
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_dht_announce() {
+        // A single DHT node, recording announcements
+        let dht = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dht_addr = dht.local_addr().unwrap();
+        let (announced, mut announcements) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let (len, from) = dht.recv_from(&mut buf).await.unwrap();
+                let Some(Bencode::Dict(query)) = Bencode::decode(&buf[..len]) else {
+                    continue;
+                };
+                let Some(Bencode::Dict(args)) = query.get(b"a".as_slice()) else {
+                    continue;
+                };
+                if query.get(b"q".as_slice()) == Some(&Bencode::Bytes(b"announce_peer".to_vec())) {
+                    announced.send(Answer(args.clone())).unwrap();
+                }
+                let response = Bencode::Dict(dict([
+                    ("t", query[b"t".as_slice()].clone()),
+                    ("y", Bencode::Bytes(b"r".to_vec())),
+                    (
+                        "r",
+                        Bencode::Dict(dict([
+                            ("id", Bencode::Bytes([7; 20].to_vec())),
+                            ("token", Bencode::Bytes(b"token".to_vec())),
+                        ])),
+                    ),
+                ]));
+                dht.send_to(&response.encode(), from).await.unwrap();
+            }
+        });
+
+        let storage = Arc::new(Storage::new());
+        let public_key = pubky_common::Keypair::random().public_key();
+        storage
+            .put(public_key, "pub/post.txt".to_string(), b"Hello!".to_vec())
+            .unwrap();
+        storage
+            .put(public_key, "private.txt".to_string(), b"Secret".to_vec())
+            .unwrap();
+        let mut config = DhtConfig::new("pub/");
+        config.bootstrap = vec![dht_addr.to_string()];
+        let server = Server::builder()
+            .storage(storage)
+            .bind(([127, 0, 0, 1], 0).into())
+            .dht(config)
+            .start()
+            .await
+            .unwrap();
+
+        // Only the public entry is announced, with the server's port
+        let hash = info_hash(b"Hello!");
+        let announcement = announcements.recv().await.unwrap();
+        assert_eq!(announcement.bytes("info_hash"), Some(hash.as_slice()));
+        assert_eq!(announcement.bytes("token"), Some(b"token".as_slice()));
+        assert_eq!(
+            announcement.0.get(b"port".as_slice()),
+            Some(&Bencode::Int(server.local_addr().port() as i64))
+        );
+        assert!(announcements.try_recv().is_err());
+
+        let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        let response = reqwest::get(format!("{}/content/{}", server.url(), hex))
+            .await
+            .unwrap();
+        assert_eq!(response.bytes().await.unwrap(), "Hello!");
+        let hex: String = info_hash(b"Secret")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let response = reqwest::get(format!("{}/content/{}", server.url(), hex))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        server.shutdown().await;
+    }
+}
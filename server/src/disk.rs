@@ -0,0 +1,716 @@
+//! Storage backend on disk
+//!
+//! A [`DiskBackend`] keeps every entry as a file under a data directory, so
+//! its contents can be inspected, backed up and restored with standard
+//! tools. The entry at `pub/posts/1.md` of a user lives at
+//! `{dir}/{public_key}/pub~/posts~/1.md`: directories get a `~` suffix,
+//! which file names never have, so `pub/a` and `pub/a/b` can both be
+//! entries. Bytes of a path segment other than ASCII letters, digits, `-`,
+//! `_` and `.` are percent-encoded, as is a leading `.`, so no path escapes
+//! its user's directory. Empty segments are stored as `%`. The
+//! [metadata](crate::EntryMeta) of an entry is kept as JSON in a hidden
+//! file next to it, `.1.md.meta` for `1.md`, and the account state of the
+//! storage in `{dir}/.state.json`.
+//!
+//! Writes go to a hidden temporary file first and are renamed into place,
+//! so readers and crashes never see half of a value. Uploads are streamed
+//! into the temporary file as they arrive.
+
+use pubky_common::PublicKey;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::backend::{comes_after, Commit, StorageBackend};
+use crate::storage::EntryMeta;
+
+/// Suffix of the directories holding the entries under a path segment
+const DIR_SUFFIX: char = '~';
+
+/// File holding the account state, hidden among the users' directories
+const STATE_FILE: &str = ".state.json";
+
+/// Entries as files under a data directory
+#[derive(Debug)]
+pub struct DiskBackend {
+    dir: PathBuf,
+}
+
+impl DiskBackend {
+    /// Keep entries under `dir`, creating it if needed
+    ///
+    /// Entries already there are served as they are.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// The data directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Directory holding the entries under `{dirs}/`, or all the user's
+    fn dir_of(&self, public_key: &PublicKey, dirs: Option<&str>) -> PathBuf {
+        let mut dir = self.dir.join(public_key.to_z32());
+        for segment in dirs.into_iter().flat_map(|dirs| dirs.split('/')) {
+            dir.push(format!("{}{}", encode(segment), DIR_SUFFIX));
+        }
+        dir
+    }
+
+    /// File holding the entry at `path`
+    fn file(&self, public_key: &PublicKey, path: &str) -> PathBuf {
+        match path.rsplit_once('/') {
+            Some((dirs, name)) => self.dir_of(public_key, Some(dirs)).join(encode(name)),
+            None => self.dir_of(public_key, None).join(encode(path)),
+        }
+    }
+
+    /// Hidden file holding the metadata of the entry at `path`
+    fn meta_file(&self, public_key: &PublicKey, path: &str) -> PathBuf {
+        let file = self.file(public_key, path);
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        file.with_file_name(format!(".{}.meta", name))
+    }
+
+    /// Call `visit` with the path and file of every entry of every user
+    fn walk_users(
+        &self,
+        visit: &mut dyn FnMut(&PublicKey, String, &Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        for user in fs::read_dir(&self.dir)? {
+            let user = user?;
+            let name = user.file_name();
+            let Some(public_key) = name.to_str().and_then(|z32| PublicKey::from_z32(z32).ok())
+            else {
+                continue;
+            };
+            walk(&user.path(), "", &mut |path, file| {
+                visit(&public_key, path, file)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for DiskBackend {
+    fn name(&self) -> &'static str {
+        "disk"
+    }
+
+    fn put(&self, public_key: PublicKey, path: String, value: Vec<u8>) -> io::Result<()> {
+        self.stage(public_key, path, &mut value.as_slice())?()
+    }
+
+    fn stage<'a>(
+        &'a self,
+        public_key: PublicKey,
+        path: String,
+        reader: &mut dyn Read,
+    ) -> io::Result<Commit<'a>> {
+        let file = self.file(&public_key, &path);
+        let mut tmp = TempFile::create(&file)?;
+        io::copy(reader, &mut tmp.file)?;
+        Ok(Box::new(move || tmp.persist(&file)))
+    }
+
+    fn get(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.file(public_key, path)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn size(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<u64>> {
+        match fs::metadata(self.file(public_key, path)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn modified(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<u64>> {
+        let modified = match fs::metadata(self.file(public_key, path)) {
+            Ok(metadata) => metadata.modified()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(Some(since_epoch.as_millis() as u64))
+    }
+
+    fn get_range(
+        &self,
+        public_key: &PublicKey,
+        path: &str,
+        range: Range<u64>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut file = match fs::File::open(self.file(public_key, path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut value = Vec::new();
+        file.take(range.end.saturating_sub(range.start))
+            .read_to_end(&mut value)?;
+        Ok(Some(value))
+    }
+
+    fn put_meta(&self, public_key: PublicKey, path: String, meta: EntryMeta) -> io::Result<()> {
+        let file = self.meta_file(&public_key, &path);
+        if meta.is_empty() {
+            return match fs::remove_file(&file) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut tmp = TempFile::create(&file)?;
+        serde_json::to_writer(&mut tmp.file, &meta)?;
+        tmp.persist(&file)
+    }
+
+    fn meta(&self, public_key: &PublicKey, path: &str) -> io::Result<EntryMeta> {
+        match fs::read(self.meta_file(public_key, path)) {
+            Ok(json) => Ok(serde_json::from_slice(&json)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(EntryMeta::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn delete(&self, public_key: &PublicKey, path: &str) -> io::Result<bool> {
+        let file = self.file(public_key, path);
+        match fs::remove_file(self.meta_file(public_key, path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        match fs::remove_file(&file) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        // Drop the directories left empty, the user's included
+        let mut dir = file.parent();
+        while let Some(parent) = dir.filter(|dir| *dir != self.dir) {
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        Ok(true)
+    }
+
+    fn list(&self, public_key: &PublicKey, prefix: &str) -> io::Result<Vec<String>> {
+        // Matches are all under the directory of the prefix's complete
+        // segments
+        let (dirs, base) = match prefix.rsplit_once('/') {
+            Some((dirs, _)) => (Some(dirs), &prefix[..dirs.len() + 1]),
+            None => (None, ""),
+        };
+        let mut paths = Vec::new();
+        walk(&self.dir_of(public_key, dirs), base, &mut |path, _| {
+            if path.starts_with(prefix) {
+                paths.push(path);
+            }
+            Ok(())
+        })?;
+        Ok(paths)
+    }
+
+    fn list_page(
+        &self,
+        public_key: &PublicKey,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        reverse: bool,
+    ) -> io::Result<Vec<String>> {
+        let (dirs, base) = match prefix.rsplit_once('/') {
+            Some((dirs, _)) => (Some(dirs), &prefix[..dirs.len() + 1]),
+            None => (None, ""),
+        };
+        let mut page = Page {
+            prefix,
+            after,
+            limit,
+            reverse,
+            paths: Vec::new(),
+        };
+        walk_sorted(&self.dir_of(public_key, dirs), base, &mut page)?;
+        Ok(page.paths)
+    }
+
+    fn keeps_state(&self) -> bool {
+        true
+    }
+
+    fn load_state(&self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(STATE_FILE)) {
+            Ok(state) => Ok(Some(state)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save_state(&self, state: &[u8]) -> io::Result<()> {
+        let file = self.dir.join(STATE_FILE);
+        let mut tmp = TempFile::create(&file)?;
+        tmp.file.write_all(state)?;
+        tmp.persist(&file)
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(&PublicKey, &str, &[u8])) -> io::Result<()> {
+        self.walk_users(&mut |public_key, path, file| {
+            let value = match fs::read(file) {
+                Ok(value) => value,
+                // Deleted since it was listed
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            visit(public_key, &path, &value);
+            Ok(())
+        })
+    }
+
+    fn for_each_size(&self, visit: &mut dyn FnMut(&PublicKey, &str, u64)) -> io::Result<()> {
+        self.walk_users(&mut |public_key, path, file| {
+            let size = match fs::metadata(file) {
+                Ok(metadata) => metadata.len(),
+                // Deleted since it was listed
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            visit(public_key, &path, size);
+            Ok(())
+        })
+    }
+}
+
+/// A hidden temporary file next to the file it replaces, removed unless
+/// renamed into place
+struct TempFile {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl TempFile {
+    /// An empty temporary file for `file`, creating its directory if needed
+    fn create(file: &Path) -> io::Result<Self> {
+        let parent = file.parent().expect("files are under the data directory");
+        fs::create_dir_all(parent)?;
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let path = parent.join(format!(".{}.{:016x}.tmp", name, rand::random::<u64>()));
+        let file = fs::File::create_new(&path)?;
+        Ok(Self { path, file })
+    }
+
+    /// Rename the temporary file to `file`, replacing it
+    fn persist(mut self, file: &Path) -> io::Result<()> {
+        fs::rename(&self.path, file)?;
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Call `visit` with the path and file of every entry under `dir`, whose
+/// entries have paths starting with `base`
+fn walk(
+    dir: &Path,
+    base: &str,
+    visit: &mut dyn FnMut(String, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        // Temporary files are hidden, and nothing else is
+        let Some(name) = name.to_str().filter(|name| !name.starts_with('.')) else {
+            continue;
+        };
+        match name.strip_suffix(DIR_SUFFIX) {
+            Some(dir) => {
+                let Some(segment) = decode(dir) else { continue };
+                walk(&entry.path(), &format!("{}{}/", base, segment), visit)?;
+            }
+            None => {
+                let Some(segment) = decode(name) else {
+                    continue;
+                };
+                visit(format!("{}{}", base, segment), &entry.path())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A page of paths being listed, see [`StorageBackend::list_page`]
+struct Page<'a> {
+    prefix: &'a str,
+    after: Option<&'a str>,
+    limit: usize,
+    reverse: bool,
+    paths: Vec<String>,
+}
+
+/// Add the paths of the files under `dir`, named from `base`, to `page` in
+/// order until it is full
+///
+/// Directories holding no path of the page are skipped unread.
+fn walk_sorted(dir: &Path, base: &str, page: &mut Page) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    // A directory sorts by its path with a trailing `/`, which puts it
+    // right where its entries belong among its siblings
+    let mut children = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str().filter(|name| !name.starts_with('.')) else {
+            continue;
+        };
+        let child = match name.strip_suffix(DIR_SUFFIX) {
+            Some(dir) => decode(dir).map(|segment| (format!("{}{}/", base, segment), true)),
+            None => decode(name).map(|segment| (format!("{}{}", base, segment), false)),
+        };
+        if let Some((path, is_dir)) = child {
+            children.push((path, is_dir, entry.path()));
+        }
+    }
+    children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    if page.reverse {
+        children.reverse();
+    }
+
+    for (path, is_dir, file) in children {
+        if page.paths.len() >= page.limit {
+            break;
+        }
+        if !is_dir {
+            let after = page.after;
+            if path.starts_with(page.prefix)
+                && after.is_none_or(|after| comes_after(&path, after, page.reverse))
+            {
+                page.paths.push(path);
+            }
+            continue;
+        }
+
+        // Every path in the directory starts with its own
+        let in_prefix = path.starts_with(page.prefix) || page.prefix.starts_with(&path);
+        let past_cursor = page.after.is_none_or(|after| match page.reverse {
+            true => path.as_str() < after,
+            false => path.as_str() >= after || after.starts_with(&path),
+        });
+        if in_prefix && past_cursor {
+            walk_sorted(&file, &path, page)?;
+        }
+    }
+    Ok(())
+}
+
+/// File name of a path segment
+pub(crate) fn encode(segment: &str) -> String {
+    if segment.is_empty() {
+        return "%".to_string();
+    }
+    let mut name = String::with_capacity(segment.len());
+    for (i, byte) in segment.bytes().enumerate() {
+        let plain = byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.');
+        if plain && !(i == 0 && byte == b'.') {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    name
+}
+
+/// Path segment of a file name, if it is one written by [`encode`]
+pub(crate) fn decode(name: &str) -> Option<String> {
+    if name == "%" {
+        return Some(String::new());
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{MockClock, SystemClock};
+    use crate::{Session, Storage};
+    use pubky_common::Keypair;
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_disk_backend() {
+        let dir = std::env::temp_dir().join(format!("pubky-disk-{}", rand::random::<u64>()));
+        let backend = DiskBackend::open(&dir).unwrap();
+        let public_key = Keypair::random().public_key();
+        let user = dir.join(public_key.to_z32());
+
+        // Files are laid out by path, and nothing escapes the user's directory
+        let paths = [
+            "pub/a",
+            "pub/a/b",
+            "pub/ü x.txt",
+            "../../etc",
+            "a//b/",
+            ".hidden",
+        ];
+        for path in paths {
+            let value = path.as_bytes().to_vec();
+            backend.put(public_key, path.to_string(), value).unwrap();
+        }
+        assert_eq!(fs::read(user.join("pub~/a~/b")).unwrap(), b"pub/a/b");
+        assert_eq!(fs::read(user.join("pub~/a")).unwrap(), b"pub/a");
+        assert!(user.join("pub~/%C3%BC%20x.txt").is_file());
+        assert!(user.join("%2E.~/%2E.~/etc").is_file());
+        assert!(user.join("a~/%~/b~/%").is_file());
+        assert!(user.join("%2Ehidden").is_file());
+        for path in paths {
+            let value = backend.get(&public_key, path).unwrap().unwrap();
+            assert_eq!(value, path.as_bytes());
+        }
+        assert_eq!(backend.get(&public_key, "pub").unwrap(), None);
+
+        // Metadata sits next to the value, out of listings
+        let meta = EntryMeta {
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        backend
+            .put_meta(public_key, "pub/a".to_string(), meta.clone())
+            .unwrap();
+        assert!(user.join("pub~/.a.meta").is_file());
+        assert_eq!(backend.meta(&public_key, "pub/a").unwrap(), meta);
+        assert!(backend.meta(&public_key, "pub/a/b").unwrap().is_empty());
+
+        // Listings match the prefix exactly, even within a segment
+        let list = |prefix: &str| {
+            let mut paths = backend.list(&public_key, prefix).unwrap();
+            paths.sort();
+            paths
+        };
+        assert_eq!(list("pub/a"), ["pub/a", "pub/a/b"]);
+        assert_eq!(list("pub/"), ["pub/a", "pub/a/b", "pub/ü x.txt"]);
+        assert_eq!(list("pub/ü"), ["pub/ü x.txt"]);
+        assert_eq!(list("a//"), ["a//b/"]);
+        assert_eq!(list("").len(), paths.len());
+        assert!(list("nothing/here").is_empty());
+
+        // Deletes leave no empty directories behind
+        assert!(backend.delete(&public_key, "a//b/").unwrap());
+        assert!(!backend.delete(&public_key, "a//b/").unwrap());
+        assert!(!user.join("a~").exists());
+        assert!(user.join("pub~").exists());
+
+        // Entries survive a restart, without temporary files
+        let backend = DiskBackend::open(&dir).unwrap();
+        let storage = Storage::with_backend(Arc::new(backend), Arc::new(SystemClock));
+        assert_eq!(
+            storage.get(&public_key, "pub/a").unwrap().unwrap(),
+            b"pub/a"
+        );
+        assert_eq!(storage.meta(&public_key, "pub/a").unwrap(), meta);
+        let users = storage.users();
+        assert_eq!(users[0].entries, paths.len() - 1);
+        let bytes: usize = paths.iter().map(|path| path.len()).sum();
+        assert_eq!(users[0].bytes, bytes - "a//b/".len());
+        assert_eq!(storage.purge(&public_key), paths.len() - 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_state_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("pubky-disk-{}", rand::random::<u64>()));
+        let open = || {
+            let backend = DiskBackend::open(&dir).unwrap();
+            Storage::with_backend(Arc::new(backend), Arc::new(SystemClock))
+        };
+        let storage = open();
+        let public_key = Keypair::random().public_key();
+        let tags = BTreeSet::from(["draft".to_string()]);
+        let session = Session {
+            id: "s1".to_string(),
+            public_key,
+            device: None,
+            capabilities: "/:rw".to_string(),
+            created_at: 0,
+            last_used_at: 0,
+            expires_at: u64::MAX,
+        };
+        storage.register(public_key);
+        storage.add_invite("invite".to_string());
+        storage.insert_session("token".to_string(), session.clone());
+        storage.freeze(public_key, "Legal hold".to_string(), false);
+        storage.set_moved(public_key, "https://example.com".to_string());
+        assert!(storage.claim_handle("alice".to_string(), public_key));
+        storage.set_domain("alice.example".to_string(), public_key);
+        storage
+            .put(public_key, "pub/a".to_string(), b"a".to_vec())
+            .unwrap();
+        storage.set_tags(public_key, "pub/a", tags.clone()).unwrap();
+        let (version, _) = storage
+            .put_versioned(
+                public_key,
+                "pub/doc".to_string(),
+                b"doc".to_vec(),
+                EntryMeta::default(),
+                "laptop",
+                &Default::default(),
+            )
+            .unwrap();
+        assert!(dir.join(".state.json").is_file());
+        drop(storage);
+
+        let storage = open();
+        assert!(storage.is_registered(&public_key));
+        assert_eq!(storage.invites(), ["invite"]);
+        assert_eq!(storage.session("token").unwrap().id, session.id);
+        assert_eq!(storage.frozen(&public_key).unwrap().reason, "Legal hold");
+        assert!(storage.moved_to(&public_key).is_some());
+        assert_eq!(storage.handle_owner("alice"), Some(public_key));
+        assert_eq!(storage.domain_owner("alice.example"), Some(public_key));
+        assert_eq!(
+            storage.tagged(&public_key, "", &["draft".to_string()]),
+            ["pub/a"]
+        );
+        assert_eq!(
+            storage.version(&public_key, "pub/doc").unwrap(),
+            Some(version)
+        );
+        assert_eq!(storage.users()[0].entries, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_timestamps_survive_restart() {
+        let dir = std::env::temp_dir().join(format!("pubky-disk-{}", rand::random::<u64>()));
+        let clock = MockClock::at(1_700_000_000_000);
+        let open = || {
+            let backend = DiskBackend::open(&dir).unwrap();
+            Storage::with_backend(Arc::new(backend), Arc::new(clock.clone()))
+        };
+        let storage = open();
+        let public_key = Keypair::random().public_key();
+        storage
+            .put(public_key, "pub/a".to_string(), b"a".to_vec())
+            .unwrap();
+        clock.advance(Duration::from_secs(60));
+        storage
+            .put(public_key, "pub/a".to_string(), b"b".to_vec())
+            .unwrap();
+        let timestamps = storage.timestamps(&public_key, "pub/a").unwrap();
+        assert_eq!(timestamps.created, 1_700_000_000_000);
+        assert_eq!(timestamps.modified, 1_700_000_060_000);
+        drop(storage);
+
+        // Not taken from the file, written after the clock's time
+        clock.advance(Duration::from_secs(60));
+        let storage = open();
+        assert_eq!(storage.timestamps(&public_key, "pub/a"), Some(timestamps));
+        assert!(storage.meta(&public_key, "pub/a").unwrap().is_empty());
+
+        // Tags set since keep them
+        let tags = BTreeSet::from(["draft".to_string()]);
+        storage.set_tags(public_key, "pub/a", tags).unwrap();
+        drop(storage);
+        let storage = open();
+        assert_eq!(storage.timestamps(&public_key, "pub/a"), Some(timestamps));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A reader failing once its bytes run out, like an upload cut short
+    struct CutShort(&'static [u8]);
+
+    impl Read for CutShort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::Error::from(ErrorKind::ConnectionReset)),
+                n => Ok(n),
+            }
+        }
+    }
+
+    #[test]
+    fn test_streamed_writes() {
+        let dir = std::env::temp_dir().join(format!("pubky-disk-{}", rand::random::<u64>()));
+        let backend = DiskBackend::open(&dir).unwrap();
+        let storage = Storage::with_backend(Arc::new(backend), Arc::new(SystemClock));
+        let public_key = Keypair::random().public_key();
+        let path = "pub/large.bin".to_string();
+        storage
+            .put(public_key, path.clone(), b"old".to_vec())
+            .unwrap();
+
+        // Failed writes leave the previous value, and no temporary file
+        let error = storage
+            .put_from(public_key, path.clone(), &mut CutShort(b"partial"))
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(storage.get(&public_key, &path).unwrap().unwrap(), b"old");
+        // Only the value and its metadata are left
+        let files = dir.join(public_key.to_z32()).join("pub~");
+        assert_eq!(fs::read_dir(&files).unwrap().count(), 2);
+        assert_eq!(storage.head_seq(), 1);
+
+        let mut value = io::repeat(7).take(3 << 20);
+        storage
+            .put_from(public_key, path.clone(), &mut value)
+            .unwrap();
+        assert_eq!(storage.size(&public_key, &path).unwrap(), Some(3 << 20));
+        assert_eq!(storage.head_seq(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn storage() -> Storage {
+            let dir = std::env::temp_dir().join(format!("pubky-disk-{}", rand::random::<u64>()));
+            let backend = DiskBackend::open(dir).unwrap();
+            Storage::with_backend(Arc::new(backend), Arc::new(SystemClock))
+        }
+
+        crate::storage_conformance_tests!(storage);
+    }
+}
@@ -0,0 +1,290 @@
+//! Domain aliases
+//!
+//! A user who controls a domain such as `alice.com` publishes a signed
+//! [`DomainProof`] in a TXT record at `_pubky.alice.com`, then asks the
+//! homeserver to verify it with `POST /domains`. The homeserver looks the
+//! record up over DNS-over-HTTPS and, if the proof is valid for a user it
+//! hosts, serves `GET /.well-known/pubky` for requests to that domain, so
+//! `https://alice.com/.well-known/pubky` names the user's public key.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use pubky_common::domain::{self, DomainProof};
+use pubky_common::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::routes::ApiError;
+use crate::session::authenticate_root;
+use crate::storage::Storage;
+
+/// DNS-over-HTTPS resolver used unless another one is configured
+pub const DEFAULT_DOH_RESOLVER: &str = "https://cloudflare-dns.com/dns-query";
+
+/// Timeout of DNS-over-HTTPS queries
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Type of TXT records
+const TXT: u16 = 16;
+
+#[derive(Clone)]
+struct DomainState {
+    storage: Arc<Storage>,
+    http: reqwest::Client,
+    /// URL of the DNS-over-HTTPS JSON API
+    resolver: Arc<str>,
+}
+
+/// Create the domain alias routes
+pub(crate) fn domain_routes<S>(storage: Arc<Storage>, resolver: &str) -> Router<S> {
+    Router::new()
+        .route("/.well-known/pubky", get(well_known))
+        .route("/domains", post(verify_domain))
+        .route("/domains/{domain}", delete(remove_domain))
+        .with_state(DomainState {
+            storage,
+            http: reqwest::Client::new(),
+            resolver: resolver.into(),
+        })
+}
+
+/// A domain aliasing a public key
+#[derive(Debug, Serialize, Deserialize)]
+struct DomainAlias {
+    domain: String,
+    #[serde(default, skip_deserializing)]
+    public_key: String,
+}
+
+/// Body of `GET /.well-known/pubky`
+#[derive(Debug, Serialize)]
+struct WellKnown {
+    public_key: String,
+    /// Canonical `pubky://` URL of the user
+    url: String,
+}
+
+/// POST /domains
+/// Verify the proof published for a domain and serve its alias
+async fn verify_domain(
+    State(state): State<DomainState>,
+    Json(request): Json<DomainAlias>,
+) -> Result<Json<DomainAlias>, ApiError> {
+    let domain = domain::normalize(&request.domain);
+    if !domain::is_valid_domain(&domain) {
+        return Err(ApiError::BadRequest(format!("Invalid domain: {}", domain)));
+    }
+
+    let record_name = DomainProof::record_name(&domain);
+    let public_key = lookup_txt(&state, &record_name)
+        .await?
+        .iter()
+        .filter_map(|txt| DomainProof::from_txt(txt).ok())
+        .filter(|proof| proof.verify(&domain).is_ok())
+        .map(|proof| proof.public_key)
+        .find(|public_key| state.storage.hosts(public_key))
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!(
+                "No valid proof for a user of this server at {}",
+                record_name
+            ))
+        })?;
+
+    state.storage.set_domain(domain.clone(), public_key);
+    tracing::info!("Verified {} as an alias of {}", domain, public_key);
+    Ok(Json(DomainAlias {
+        domain,
+        public_key: public_key.to_z32(),
+    }))
+}
+
+/// DELETE /domains/{domain}
+/// Stop serving a domain alias of the current account
+async fn remove_domain(
+    State(state): State<DomainState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let (_, session) = authenticate_root(&state.storage, &headers)?;
+    match state
+        .storage
+        .remove_domain(&domain::normalize(&name), &session.public_key)
+    {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::NotFound),
+    }
+}
+
+/// GET /.well-known/pubky
+/// The public key aliased by the domain the request was sent to
+async fn well_known(
+    State(state): State<DomainState>,
+    headers: HeaderMap,
+) -> Result<Json<WellKnown>, ApiError> {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(ApiError::NotFound)?;
+    let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    let public_key: PublicKey = state
+        .storage
+        .domain_owner(&domain::normalize(name))
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(WellKnown {
+        public_key: public_key.to_z32(),
+        url: format!("pubky://{}", public_key),
+    }))
+}
+
+/// Answer of the DNS-over-HTTPS JSON API
+#[derive(Deserialize)]
+struct DnsResponse {
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DnsAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+/// Texts of the TXT records at `name`
+async fn lookup_txt(state: &DomainState, name: &str) -> Result<Vec<String>, ApiError> {
+    let unreachable = |e: reqwest::Error| {
+        ApiError::InternalError(format!("Failed to query the DNS resolver: {}", e))
+    };
+    let response: DnsResponse = state
+        .http
+        .get(&*state.resolver)
+        .query(&[("name", name), ("type", "TXT")])
+        .header(header::ACCEPT, "application/dns-json")
+        .timeout(TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(unreachable)?
+        .json()
+        .await
+        .map_err(unreachable)?;
+
+    Ok(response
+        .answer
+        .into_iter()
+        .filter(|answer| answer.record_type == TXT)
+        .map(|answer| txt_data(&answer.data))
+        .collect())
+}
+
+/// Join the quoted character strings of a TXT record, such as
+/// `"pk=..." " sig=..."`
+fn txt_data(data: &str) -> String {
+    if !data.starts_with('"') {
+        return data.to_string();
+    }
+    data.split('"').skip(1).step_by(2).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::ROOT_CAPABILITIES;
+    use crate::storage::Session;
+    use crate::Server;
+    use pubky_common::Keypair;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_domain_aliases() {
+        // A resolver serving the proof of alice.com
+        let keypair = Keypair::random();
+        let txt = DomainProof::sign(&keypair, "alice.com").to_txt();
+        let (first, second) = txt.split_at(40);
+        let data = format!("\"{}\" \"{}\"", first, second);
+        let dns = Router::new().route(
+            "/dns-query",
+            get(move || {
+                let data = data.clone();
+                async move {
+                    Json(json!({ "Status": 0, "Answer": [
+                        { "name": "_pubky.alice.com", "type": TXT, "TTL": 300, "data": data }
+                    ] }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, dns).await });
+
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .dns_resolver(resolver)
+            .start()
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let verify = |domain: &str| {
+            http.post(format!("{}/domains", server.url()))
+                .json(&json!({ "domain": domain }))
+                .send()
+        };
+
+        // Only users of the server can be aliased
+        let response = verify("alice.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        server.storage().register(keypair.public_key());
+        let response = verify("Alice.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let alias: Value = response.json().await.unwrap();
+        assert_eq!(alias["domain"], "alice.com");
+        assert_eq!(alias["public_key"], keypair.public_key().to_z32());
+        let response = verify("bob.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Requests to the domain find the public key
+        let well_known = format!("{}/.well-known/pubky", server.url());
+        let response = http
+            .get(&well_known)
+            .header(header::HOST, "alice.com")
+            .send()
+            .await
+            .unwrap();
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["url"], format!("pubky://{}", keypair.public_key()));
+        let response = http.get(&well_known).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Only root sessions of the account remove its aliases
+        let session = |id: &str, capabilities: &str| Session {
+            id: id.to_string(),
+            public_key: keypair.public_key(),
+            device: None,
+            capabilities: capabilities.to_string(),
+            created_at: 0,
+            last_used_at: 0,
+            expires_at: u64::MAX,
+        };
+        let storage = server.storage();
+        storage.insert_session("app".to_string(), session("app", "/pub/my-app/:rw"));
+        storage.insert_session("root".to_string(), session("root", ROOT_CAPABILITIES));
+        let remove = |token: &str| {
+            http.delete(format!("{}/domains/alice.com", server.url()))
+                .bearer_auth(token)
+                .send()
+        };
+        let response = remove("app").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(storage.domain_owner("alice.com").is_some());
+        let response = remove("root").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(storage.domain_owner("alice.com").is_none());
+
+        server.shutdown().await;
+    }
+}
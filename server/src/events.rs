@@ -0,0 +1,226 @@
+//! Change feed
+//!
+//! `GET /events/{public_key}` streams the changes to a user's entries as
+//! server-sent events, built on the storage event log. Each event's `id`
+//! is its sequence number: clients resume after a disconnect by sending it
+//! back as `Last-Event-ID` or `?cursor=`. A cursor older than the retained
+//! log is answered with `410 Gone`, and the client has to resynchronize.
+//!
+//! Clients accepting `application/cbor-seq` get the changes as a sequence of
+//! CBOR items instead, without the event stream framing.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap},
+    response::sse::{Event as SseEvent, KeepAlive, Sse},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use futures_util::stream;
+use pubky_common::dto::{ChangeEvent, ChangeOp};
+use pubky_common::PublicKey;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+use crate::cbor;
+use crate::routes::{self, ApiError};
+use crate::storage::{Event, EventOp, Storage};
+
+/// Number of events read from the log at a time
+const BATCH_SIZE: usize = 256;
+
+#[derive(Debug, Deserialize)]
+struct WatchQuery {
+    /// Only changes to paths starting with this prefix
+    prefix: Option<String>,
+    /// Resume after this sequence number; defaults to the latest event
+    cursor: Option<u64>,
+}
+
+#[derive(Clone)]
+struct EventState {
+    storage: Arc<Storage>,
+    /// Cancelled when the server shuts down, ending all feeds
+    closing: CancellationToken,
+}
+
+pub(crate) fn event_routes<S>(storage: Arc<Storage>, closing: CancellationToken) -> Router<S> {
+    Router::new()
+        .route("/{public_key}", get(watch))
+        .with_state(EventState { storage, closing })
+}
+
+/// GET /events/{public_key}
+/// Stream changes to a user's entries
+async fn watch(
+    State(EventState { storage, closing }): State<EventState>,
+    Path(public_key_str): Path<String>,
+    Query(query): Query<WatchQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let public_key = PublicKey::from_z32(&public_key_str)
+        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+    routes::ensure_readable(&storage, &public_key)?;
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let cursor = query
+        .cursor
+        .or(last_event_id)
+        .unwrap_or_else(|| storage.head_seq());
+    if storage.events_since(cursor, 0).is_none() {
+        return Err(ApiError::Gone(
+            "Cursor is older than the retained change feed".to_string(),
+        ));
+    }
+
+    let feed = Feed {
+        storage,
+        closing,
+        public_key,
+        prefix: query.prefix.unwrap_or_default(),
+        cursor,
+        pending: VecDeque::new(),
+    };
+    if cbor::accepts(&headers, true) {
+        let items = stream::unfold(feed, |mut feed| async move {
+            let event = feed.next().await?;
+            Some((Ok::<_, Infallible>(Bytes::from(cbor::to_vec(&event))), feed))
+        });
+        let content_type = [(header::CONTENT_TYPE, cbor::CBOR_SEQ)];
+        return Ok((content_type, Body::from_stream(items)).into_response());
+    }
+
+    let events = stream::unfold(feed, |mut feed| async move {
+        let event = feed.next().await?;
+        let sse = SseEvent::default()
+            .id(event.seq.to_string())
+            .json_data(&event)
+            .expect("change events serialize");
+        Some((Ok::<_, Infallible>(sse), feed))
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// A user's view of the event log
+struct Feed {
+    storage: Arc<Storage>,
+    closing: CancellationToken,
+    public_key: PublicKey,
+    prefix: String,
+    cursor: u64,
+    pending: VecDeque<ChangeEvent>,
+}
+
+impl Feed {
+    /// The next matching change, waiting for one if necessary
+    ///
+    /// Ends the stream when the server shuts down, or if the feed fell
+    /// behind the retained log; the client reconnects and learns that its
+    /// cursor is gone.
+    async fn next(&mut self) -> Option<ChangeEvent> {
+        loop {
+            if self.closing.is_cancelled() {
+                return None;
+            }
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+
+            let events = self.storage.events_since(self.cursor, BATCH_SIZE)?;
+            let Some(last) = events.last() else {
+                tokio::select! {
+                    _ = self.storage.wait_for_events(self.cursor) => continue,
+                    _ = self.closing.cancelled() => return None,
+                }
+            };
+            self.cursor = last.seq;
+            self.pending.extend(
+                events
+                    .into_iter()
+                    .filter(|e| e.public_key == self.public_key)
+                    .filter(|e| e.path.starts_with(&self.prefix))
+                    .map(change_event),
+            );
+        }
+    }
+}
+
+fn change_event(event: Event) -> ChangeEvent {
+    ChangeEvent {
+        seq: event.seq,
+        timestamp: event.timestamp,
+        op: match event.op {
+            EventOp::Put => ChangeOp::Put,
+            EventOp::Delete => ChangeOp::Delete,
+        },
+        path: event.path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+
+    #[tokio::test]
+    async fn test_feed() {
+        let storage = Arc::new(Storage::new());
+        let alice = Keypair::random().public_key();
+        let bob = Keypair::random().public_key();
+        storage
+            .put(alice, "app/old.txt".to_string(), vec![])
+            .unwrap();
+
+        let closing = CancellationToken::new();
+        let mut feed = Feed {
+            storage: storage.clone(),
+            closing: closing.clone(),
+            public_key: alice,
+            prefix: "app/".to_string(),
+            cursor: storage.head_seq(),
+            pending: VecDeque::new(),
+        };
+        storage.put(bob, "app/a.txt".to_string(), vec![]).unwrap();
+        storage
+            .put(alice, "other/a.txt".to_string(), vec![])
+            .unwrap();
+        storage.put(alice, "app/a.txt".to_string(), vec![]).unwrap();
+        storage.delete(&alice, "app/a.txt").unwrap();
+
+        let event = feed.next().await.unwrap();
+        assert_eq!(
+            (event.op, event.path.as_str()),
+            (ChangeOp::Put, "app/a.txt")
+        );
+        let event = feed.next().await.unwrap();
+        assert_eq!(event.op, ChangeOp::Delete);
+        assert_eq!(event.seq, storage.head_seq());
+
+        // Waits for the next change
+        let waiting = tokio::spawn(async move {
+            let event = feed.next().await;
+            (feed, event)
+        });
+        tokio::task::yield_now().await;
+        storage.put(alice, "app/b.txt".to_string(), vec![]).unwrap();
+        let (mut feed, event) = waiting.await.unwrap();
+        assert_eq!(event.unwrap().path, "app/b.txt");
+
+        // Shutting down ends the feed
+        let waiting = tokio::spawn(async move { feed.next().await });
+        tokio::task::yield_now().await;
+        closing.cancel();
+        assert_eq!(waiting.await.unwrap(), None);
+    }
+}
@@ -0,0 +1,500 @@
+//! User data export jobs
+//!
+//! `POST /exports/{public_key}` enqueues a background job that packages all
+//! of a user's entries into a tar archive with a `manifest.json` describing
+//! them. Only a root session of the user may start one. With
+//! `?format=car`, the job instead packages the user's `pub/` tree as an IPFS
+//! CAR file, see [`car`](crate::car). Clients poll the job for progress and
+//! download the archive once it is complete. Finished jobs are kept for
+//! [`JOB_TTL`], and a user has at most [`MAX_JOBS_PER_USER`] at a time.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use pubky_common::PublicKey;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::car;
+use crate::routes::{self, ApiError};
+use crate::session;
+use crate::storage::Storage;
+
+/// How long finished export jobs remain downloadable
+pub const JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Most export jobs kept per user, running or finished
+pub const MAX_JOBS_PER_USER: usize = 4;
+
+/// Lifecycle of an export job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExportStatus {
+    Pending = 0,
+    Running = 1,
+    Complete = 2,
+    Failed = 3,
+}
+
+impl ExportStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ExportStatus::Pending,
+            1 => ExportStatus::Running,
+            2 => ExportStatus::Complete,
+            _ => ExportStatus::Failed,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ExportStatus::Pending => "pending",
+            ExportStatus::Running => "running",
+            ExportStatus::Complete => "complete",
+            ExportStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Archive format of an export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Every entry in a tar archive with a `manifest.json`
+    #[default]
+    Tar,
+    /// The `pub/` tree as content-addressed blocks in a CAR file
+    Car,
+}
+
+/// A single export job and its result
+pub struct ExportJob {
+    id: String,
+    public_key: PublicKey,
+    format: ExportFormat,
+    /// Unix timestamp in milliseconds
+    created: u64,
+    status: AtomicU8,
+    done: AtomicU64,
+    total: AtomicU64,
+    archive: Mutex<Option<Vec<u8>>>,
+    /// Root CID of a CAR archive
+    root: Mutex<Option<String>>,
+    error: Mutex<Option<String>>,
+}
+
+impl ExportJob {
+    fn new(id: String, public_key: PublicKey, format: ExportFormat, created: u64) -> Self {
+        Self {
+            id,
+            public_key,
+            format,
+            created,
+            status: AtomicU8::new(ExportStatus::Pending as u8),
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            archive: Mutex::new(None),
+            root: Mutex::new(None),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// Current status of the job
+    pub fn status(&self) -> ExportStatus {
+        ExportStatus::from_u8(self.status.load(Ordering::Acquire))
+    }
+
+    fn set_status(&self, status: ExportStatus) {
+        self.status.store(status as u8, Ordering::Release);
+    }
+
+    fn to_json(&self) -> Value {
+        let status = self.status();
+        let download_url =
+            (status == ExportStatus::Complete).then(|| format!("/exports/{}/download", self.id));
+
+        json!({
+            "id": self.id,
+            "public_key": self.public_key.to_z32(),
+            "format": match self.format {
+                ExportFormat::Tar => "tar",
+                ExportFormat::Car => "car",
+            },
+            "status": status.as_str(),
+            "progress": {
+                "done": self.done.load(Ordering::Relaxed),
+                "total": self.total.load(Ordering::Relaxed),
+            },
+            "download_url": download_url,
+            "root": *self.root.lock().unwrap(),
+            "error": *self.error.lock().unwrap(),
+        })
+    }
+
+    /// Build the archive, updating progress as entries are added
+    async fn run(self: Arc<Self>, storage: Arc<Storage>) {
+        self.set_status(ExportStatus::Running);
+
+        let archive = match self.format {
+            ExportFormat::Tar => self.build_archive(&storage).await,
+            ExportFormat::Car => self.build_car(&storage).await,
+        };
+        match archive {
+            Ok(archive) => {
+                *self.archive.lock().unwrap() = Some(archive);
+                self.set_status(ExportStatus::Complete);
+                tracing::info!("Export {} for {} complete", self.id, self.public_key);
+            }
+            Err(e) => {
+                tracing::error!("Export {} for {} failed: {}", self.id, self.public_key, e);
+                *self.error.lock().unwrap() = Some(e.to_string());
+                self.set_status(ExportStatus::Failed);
+            }
+        }
+    }
+
+    async fn build_archive(&self, storage: &Storage) -> std::io::Result<Vec<u8>> {
+        let mut paths = storage.list(&self.public_key, "");
+        paths.sort();
+        self.total.store(paths.len() as u64, Ordering::Relaxed);
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut manifest = Vec::new();
+
+        for path in paths {
+            // Entries deleted while the export runs are skipped
+            if let Some(value) = storage.get(&self.public_key, &path)? {
+                append_file(
+                    &mut builder,
+                    &format!("data/{}", path),
+                    &value,
+                    self.created,
+                )?;
+                let meta = storage.meta(&self.public_key, &path)?;
+                manifest.push(json!({
+                    "path": path,
+                    "size": value.len(),
+                    "content_type": meta.content_type,
+                    "meta": meta.custom,
+                }));
+            }
+            self.done.fetch_add(1, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+        }
+
+        let manifest = json!({
+            "public_key": self.public_key.to_z32(),
+            "created": self.created,
+            "entries": manifest,
+        });
+        let manifest = serde_json::to_vec_pretty(&manifest).map_err(std::io::Error::other)?;
+        append_file(&mut builder, "manifest.json", &manifest, self.created)?;
+
+        builder.into_inner()
+    }
+
+    async fn build_car(&self, storage: &Storage) -> std::io::Result<Vec<u8>> {
+        let mut paths = storage.list(&self.public_key, "pub/");
+        paths.sort();
+        self.total.store(paths.len() as u64, Ordering::Relaxed);
+
+        let mut entries = Vec::with_capacity(paths.len());
+        for path in paths {
+            if let Some(value) = storage.get(&self.public_key, &path)? {
+                entries.push((path, value));
+            }
+            self.done.fetch_add(1, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+        }
+
+        let (archive, root) = car::build(&self.public_key, &entries);
+        *self.root.lock().unwrap() = Some(root.to_string());
+        Ok(archive)
+    }
+}
+
+fn append_file(
+    builder: &mut tar::Builder<Vec<u8>>,
+    path: &str,
+    contents: &[u8],
+    created: u64,
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(created / 1000);
+    builder.append_data(&mut header, path, contents)
+}
+
+/// Registry of export jobs
+#[derive(Default)]
+pub struct ExportJobs {
+    jobs: Mutex<HashMap<String, Arc<ExportJob>>>,
+}
+
+impl ExportJobs {
+    /// Enqueue an export for the given user and start it in the background
+    ///
+    /// Fails while the user already has [`MAX_JOBS_PER_USER`] jobs, until
+    /// the oldest of them expires.
+    pub fn enqueue(
+        &self,
+        storage: Arc<Storage>,
+        public_key: PublicKey,
+        format: ExportFormat,
+    ) -> Result<Arc<ExportJob>, ApiError> {
+        let id = format!("{:016x}", rand::random::<u64>());
+        let now = storage.now_millis();
+        let ttl = JOB_TTL.as_millis() as u64;
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| now.saturating_sub(job.created) < ttl);
+        let mut created: Vec<u64> = jobs
+            .values()
+            .filter(|job| job.public_key == public_key)
+            .map(|job| job.created)
+            .collect();
+        if created.len() >= MAX_JOBS_PER_USER {
+            created.sort_unstable();
+            let retry_after = (created[0] + ttl).saturating_sub(now);
+            return Err(ApiError::RateLimited(Duration::from_millis(retry_after)));
+        }
+
+        let job = Arc::new(ExportJob::new(id.clone(), public_key, format, now));
+        jobs.insert(id, job.clone());
+
+        tokio::spawn(job.clone().run(storage));
+        Ok(job)
+    }
+
+    /// Look up a job by id
+    pub fn get(&self, id: &str) -> Option<Arc<ExportJob>> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+}
+
+#[derive(Clone)]
+struct ExportState {
+    storage: Arc<Storage>,
+    jobs: Arc<ExportJobs>,
+}
+
+/// Create the export routes
+pub(crate) fn export_routes<S>(storage: Arc<Storage>, jobs: Arc<ExportJobs>) -> Router<S> {
+    Router::new()
+        .route("/{public_key}", post(create_export))
+        .route("/{id}/status", get(export_status))
+        .route("/{id}/download", get(download_export))
+        .with_state(ExportState { storage, jobs })
+}
+
+/// Query parameters of export requests
+#[derive(Debug, Default, Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// POST /exports/{public_key}
+/// Enqueue an export of all of a user's entries, or of their `pub/` tree as
+/// a CAR file
+async fn create_export(
+    State(state): State<ExportState>,
+    Path(public_key_str): Path<String>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let public_key = PublicKey::from_z32(&public_key_str)
+        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+
+    // Exports hold every entry, so only the owner's root sessions start them
+    let (_, session) = session::authenticate_root(&state.storage, &headers)?;
+    if session.public_key != public_key {
+        return Err(ApiError::Forbidden);
+    }
+    routes::ensure_readable(&state.storage, &public_key)?;
+    let job = state
+        .jobs
+        .enqueue(state.storage.clone(), public_key, query.format)?;
+    let mut body = job.to_json();
+    body["status_url"] = json!(format!("/exports/{}/status", job.id));
+
+    Ok((StatusCode::ACCEPTED, Json(body)))
+}
+
+/// GET /exports/{id}/status
+/// Poll the progress of an export job
+async fn export_status(
+    State(state): State<ExportState>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let job = state.jobs.get(&id).ok_or(ApiError::NotFound)?;
+    Ok(Json(job.to_json()))
+}
+
+/// GET /exports/{id}/download
+/// Download the archive of a completed export job
+async fn download_export(
+    State(state): State<ExportState>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let job = state.jobs.get(&id).ok_or(ApiError::NotFound)?;
+    let archive = job
+        .archive
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or(ApiError::NotFound)?;
+
+    let (content_type, extension) = match job.format {
+        ExportFormat::Tar => ("application/x-tar", "tar"),
+        ExportFormat::Car => ("application/vnd.ipld.car", "car"),
+    };
+    let disposition = format!(
+        "attachment; filename=\"pubky-export-{}.{}\"",
+        job.public_key.to_z32(),
+        extension
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        archive,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::ROOT_CAPABILITIES;
+    use crate::storage::{EntryMeta, Session};
+    use crate::Server;
+    use pubky_common::Keypair;
+    use std::io::Read as _;
+
+    #[tokio::test]
+    async fn test_export_job() {
+        let storage = Arc::new(Storage::new());
+        let public_key = Keypair::random().public_key();
+        let meta = EntryMeta {
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        storage
+            .put_with_meta(public_key, "app/a.txt".to_string(), b"alpha".to_vec(), meta)
+            .unwrap();
+        storage
+            .put(public_key, "app/b.txt".to_string(), b"beta".to_vec())
+            .unwrap();
+        storage
+            .put(
+                Keypair::random().public_key(),
+                "other.txt".to_string(),
+                vec![0],
+            )
+            .unwrap();
+
+        let jobs = ExportJobs::default();
+        let job = jobs
+            .enqueue(storage.clone(), public_key, ExportFormat::Tar)
+            .unwrap();
+        for _ in 0..100 {
+            if job.status() == ExportStatus::Complete {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(job.status(), ExportStatus::Complete);
+        assert_eq!(job.to_json()["progress"]["done"], 2);
+
+        let archive = job.archive.lock().unwrap().clone().unwrap();
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let mut files = HashMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            files.insert(path, contents);
+        }
+
+        assert_eq!(files["data/app/a.txt"], "alpha");
+        assert_eq!(files["data/app/b.txt"], "beta");
+        let manifest: Value = serde_json::from_str(&files["manifest.json"]).unwrap();
+        assert_eq!(manifest["entries"].as_array().unwrap().len(), 2);
+        assert_eq!(manifest["entries"][0]["content_type"], "text/plain");
+        assert_eq!(manifest["public_key"], public_key.to_z32());
+
+        // CAR exports only hold the public tree
+        storage
+            .put(public_key, "pub/site.html".to_string(), b"hi".to_vec())
+            .unwrap();
+        let job = jobs
+            .enqueue(storage.clone(), public_key, ExportFormat::Car)
+            .unwrap();
+        while job.status() != ExportStatus::Complete {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let json = job.to_json();
+        assert_eq!(json["progress"]["total"], 1);
+        assert!(json["root"].as_str().unwrap().starts_with("bafy"));
+
+        // Jobs are capped per user until the oldest expires
+        for _ in 2..MAX_JOBS_PER_USER {
+            jobs.enqueue(storage.clone(), public_key, ExportFormat::Tar)
+                .unwrap();
+        }
+        let result = jobs.enqueue(storage.clone(), public_key, ExportFormat::Tar);
+        assert!(matches!(result, Err(ApiError::RateLimited(_))));
+        let other = Keypair::random().public_key();
+        assert!(jobs.enqueue(storage, other, ExportFormat::Tar).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_export_requires_root_session() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let storage = server.storage();
+        let public_key = Keypair::random().public_key();
+        storage.register(public_key);
+        let session = |public_key: PublicKey, capabilities: &str| Session {
+            id: capabilities.to_string(),
+            public_key,
+            device: None,
+            capabilities: capabilities.to_string(),
+            created_at: 0,
+            last_used_at: 0,
+            expires_at: u64::MAX,
+        };
+        let other = Keypair::random().public_key();
+        storage.insert_session("app".to_string(), session(public_key, "/pub/my-app/:rw"));
+        storage.insert_session("other".to_string(), session(other, ROOT_CAPABILITIES));
+        storage.insert_session("root".to_string(), session(public_key, ROOT_CAPABILITIES));
+
+        let http = reqwest::Client::new();
+        let url = format!("{}/exports/{}", server.url(), public_key);
+        let response = http.post(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        for token in ["app", "other"] {
+            let response = http.post(&url).bearer_auth(token).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        let response = http.post(&url).bearer_auth("root").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        server.shutdown().await;
+    }
+}
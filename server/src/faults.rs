@@ -0,0 +1,567 @@
+//! Fault injection for storage
+//!
+//! A [`FaultyStorage`] wraps the user data routes, whatever the storage
+//! behind them, and makes them misbehave the way a real backend under load
+//! does: requests are slowed down, fail with a transient
+//! `503 Service Unavailable` before reaching storage, or reach it and then
+//! lose their response to a `500 Internal Server Error`. Tests of routes,
+//! the client's retries and replication can then check their error paths
+//! against realistic failures:
+//!
+//! ```
+//! use pubky_server::faults::FaultyStorage;
+//! use pubky_server::Server;
+//! use std::time::Duration;
+//!
+//! let faults = FaultyStorage::new()
+//!     .seed(7)
+//!     .latency(Duration::from_millis(1), Duration::from_millis(20))
+//!     .transient_errors(0.2)
+//!     .partial_failures(0.05);
+//! let router = Server::builder().storage_layer(faults.clone()).router();
+//! // ... exercise the router, then let it recover
+//! faults.set_enabled(false);
+//! ```
+//!
+//! The same faults can be injected one level down, into the
+//! [`StorageBackend`] itself, with [`FaultyStorage::backend`]: operations on
+//! entries are slowed down, fail with a transient [`io::Error`] before
+//! reaching the backend, or write and then fail, so the storage's own
+//! handling of backend errors is exercised too:
+//!
+//! ```
+//! use pubky_server::faults::FaultyStorage;
+//! use pubky_server::{MemoryBackend, Server};
+//! use std::sync::Arc;
+//!
+//! let faults = FaultyStorage::new().seed(7).transient_errors(0.2);
+//! let backend = faults.backend(Arc::new(MemoryBackend::new()));
+//! let router = Server::builder().backend(Arc::new(backend)).router();
+//! ```
+//!
+//! Faults are drawn from a seeded generator, so a failing run can be
+//! replayed. Available with the `faults` feature.
+
+use crate::backend::{Commit, StorageBackend};
+use crate::storage::EntryMeta;
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::future::BoxFuture;
+use pubky_common::dto::ErrorResponse;
+use pubky_common::PublicKey;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::convert::Infallible;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+
+/// Counts of the faults injected so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Requests, or backend operations, seen while enabled
+    pub requests: u64,
+    /// Requests slowed down
+    pub delayed: u64,
+    /// Requests failed before reaching storage
+    pub transient_errors: u64,
+    /// Requests that reached storage, or writes that reached the backend,
+    /// and then failed
+    pub partial_failures: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    delayed: AtomicU64,
+    transient_errors: AtomicU64,
+    partial_failures: AtomicU64,
+}
+
+/// What happens to a request
+struct Fault {
+    delay: Option<Duration>,
+    transient: bool,
+    partial: bool,
+}
+
+/// Middleware injecting faults around the storage routes, for
+/// [`ServerBuilder::storage_layer`](crate::ServerBuilder::storage_layer)
+///
+/// Clones share their generator, counters and switch.
+#[derive(Clone)]
+pub struct FaultyStorage {
+    latency: Option<(Duration, Duration)>,
+    transient_errors: f64,
+    partial_failures: f64,
+    rng: Arc<Mutex<StdRng>>,
+    counters: Arc<Counters>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl FaultyStorage {
+    /// Middleware injecting no faults until configured, seeded at random
+    pub fn new() -> Self {
+        Self {
+            latency: None,
+            transient_errors: 0.0,
+            partial_failures: 0.0,
+            rng: Arc::new(Mutex::new(StdRng::from_os_rng())),
+            counters: Default::default(),
+            enabled: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Draw faults from a generator seeded with `seed`, the same in every
+    /// run
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(seed)));
+        self
+    }
+
+    /// Delay every request by between `min` and `max`
+    pub fn latency(mut self, min: Duration, max: Duration) -> Self {
+        self.latency = Some((min, max.max(min)));
+        self
+    }
+
+    /// Fail this fraction of requests, from `0.0` to `1.0`, with
+    /// `503 Service Unavailable` before they reach storage
+    pub fn transient_errors(mut self, rate: f64) -> Self {
+        self.transient_errors = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Fail this fraction of requests, from `0.0` to `1.0`, with
+    /// `500 Internal Server Error` after storage handled them, so writes
+    /// land without the client knowing
+    pub fn partial_failures(mut self, rate: f64) -> Self {
+        self.partial_failures = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Turn fault injection on or off, for every clone
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Wrap `inner` in a backend injecting the same faults into its
+    /// operations on entries, sharing the generator, counters and switch
+    ///
+    /// Latency blocks the calling thread, as a slow disk does. Partial
+    /// failures only hit writes, which land before failing.
+    pub fn backend(&self, inner: Arc<dyn StorageBackend>) -> FaultyBackend {
+        FaultyBackend {
+            inner,
+            faults: self.clone(),
+        }
+    }
+
+    /// Counts of the faults injected so far
+    pub fn stats(&self) -> FaultStats {
+        let counters = &self.counters;
+        FaultStats {
+            requests: counters.requests.load(Ordering::Relaxed),
+            delayed: counters.delayed.load(Ordering::Relaxed),
+            transient_errors: counters.transient_errors.load(Ordering::Relaxed),
+            partial_failures: counters.partial_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Draw the fault of the next request, or `None` when disabled
+    fn draw(&self) -> Option<Fault> {
+        self.draw_op(true)
+    }
+
+    /// Draw the fault of the next request or backend operation, only
+    /// failing writes partially
+    fn draw_op(&self, write: bool) -> Option<Fault> {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut rng = self.rng.lock().unwrap();
+        let delay = self
+            .latency
+            .map(|(min, max)| rng.random_range(min..=max))
+            .filter(|delay| !delay.is_zero());
+        let transient = rng.random_bool(self.transient_errors);
+        let partial = write && !transient && rng.random_bool(self.partial_failures);
+
+        let counters = &self.counters;
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        let counts = [
+            (delay.is_some(), &counters.delayed),
+            (transient, &counters.transient_errors),
+            (partial, &counters.partial_failures),
+        ];
+        for (happened, counter) in counts {
+            if happened {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Some(Fault {
+            delay,
+            transient,
+            partial,
+        })
+    }
+}
+
+impl Default for FaultyStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for FaultyStorage {
+    type Service = FaultyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        FaultyService {
+            inner,
+            faults: self.clone(),
+        }
+    }
+}
+
+/// Service created by [`FaultyStorage`]
+#[derive(Clone)]
+pub struct FaultyService<S> {
+    inner: S,
+    faults: FaultyStorage,
+}
+
+impl<S> Service<Request> for FaultyService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Call the instance that was polled ready, leaving a fresh clone
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let fault = self.faults.draw();
+        Box::pin(async move {
+            let Some(fault) = fault else {
+                return inner.call(request).await;
+            };
+            if let Some(delay) = fault.delay {
+                tokio::time::sleep(delay).await;
+            }
+            if fault.transient {
+                return Ok(injected(StatusCode::SERVICE_UNAVAILABLE, "unavailable"));
+            }
+            let response = inner.call(request).await?;
+            if fault.partial {
+                return Ok(injected(StatusCode::INTERNAL_SERVER_ERROR, "internal"));
+            }
+            Ok(response)
+        })
+    }
+}
+
+fn injected(status: StatusCode, code: &str) -> Response {
+    let body = ErrorResponse::new(code, "Injected fault");
+    (status, Json(body)).into_response()
+}
+
+/// Backend created by [`FaultyStorage::backend`]
+pub struct FaultyBackend {
+    inner: Arc<dyn StorageBackend>,
+    faults: FaultyStorage,
+}
+
+impl FaultyBackend {
+    /// Run a read of the inner backend, unless a transient fault fails it
+    fn read<T>(&self, op: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        self.run(false, op)
+    }
+
+    /// Run a write to the inner backend, unless a transient fault fails it
+    /// first or a partial failure after
+    fn write<T>(&self, op: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        self.run(true, op)
+    }
+
+    fn run<T>(&self, write: bool, op: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        let Some(fault) = self.faults.draw_op(write) else {
+            return op();
+        };
+        if let Some(delay) = fault.delay {
+            std::thread::sleep(delay);
+        }
+        if fault.transient {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Injected fault"));
+        }
+        let result = op()?;
+        if fault.partial {
+            return Err(io::Error::other("Injected fault"));
+        }
+        Ok(result)
+    }
+}
+
+impl StorageBackend for FaultyBackend {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn put(&self, public_key: PublicKey, path: String, value: Vec<u8>) -> io::Result<()> {
+        self.write(|| self.inner.put(public_key, path, value))
+    }
+
+    fn stage<'a>(
+        &'a self,
+        public_key: PublicKey,
+        path: String,
+        reader: &mut dyn Read,
+    ) -> io::Result<Commit<'a>> {
+        let commit = self.read(|| self.inner.stage(public_key, path, reader))?;
+        Ok(Box::new(move || self.write(commit)))
+    }
+
+    fn stage_value<'a>(
+        &'a self,
+        public_key: PublicKey,
+        path: String,
+        value: Vec<u8>,
+    ) -> io::Result<Commit<'a>> {
+        let commit = self.read(|| self.inner.stage_value(public_key, path, value))?;
+        Ok(Box::new(move || self.write(commit)))
+    }
+
+    fn get(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<Vec<u8>>> {
+        self.read(|| self.inner.get(public_key, path))
+    }
+
+    fn size(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<u64>> {
+        self.read(|| self.inner.size(public_key, path))
+    }
+
+    fn modified(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<u64>> {
+        self.read(|| self.inner.modified(public_key, path))
+    }
+
+    fn get_range(
+        &self,
+        public_key: &PublicKey,
+        path: &str,
+        range: Range<u64>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        self.read(|| self.inner.get_range(public_key, path, range))
+    }
+
+    fn put_meta(&self, public_key: PublicKey, path: String, meta: EntryMeta) -> io::Result<()> {
+        self.write(|| self.inner.put_meta(public_key, path, meta))
+    }
+
+    fn meta(&self, public_key: &PublicKey, path: &str) -> io::Result<EntryMeta> {
+        self.read(|| self.inner.meta(public_key, path))
+    }
+
+    fn delete(&self, public_key: &PublicKey, path: &str) -> io::Result<bool> {
+        self.write(|| self.inner.delete(public_key, path))
+    }
+
+    fn list(&self, public_key: &PublicKey, prefix: &str) -> io::Result<Vec<String>> {
+        self.read(|| self.inner.list(public_key, prefix))
+    }
+
+    fn list_page(
+        &self,
+        public_key: &PublicKey,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        reverse: bool,
+    ) -> io::Result<Vec<String>> {
+        self.read(|| {
+            self.inner
+                .list_page(public_key, prefix, after, limit, reverse)
+        })
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(&PublicKey, &str, &[u8])) -> io::Result<()> {
+        self.read(|| self.inner.for_each(visit))
+    }
+
+    fn for_each_size(&self, visit: &mut dyn FnMut(&PublicKey, &str, u64)) -> io::Result<()> {
+        self.read(|| self.inner.for_each_size(visit))
+    }
+
+    fn keeps_state(&self) -> bool {
+        self.inner.keeps_state()
+    }
+
+    fn load_state(&self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.load_state()
+    }
+
+    fn save_state(&self, state: &[u8]) -> io::Result<()> {
+        self.inner.save_state(state)
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.inner.memory_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryBackend, Server, Storage};
+    use axum::body::Body;
+    use axum::Router;
+    use pubky_common::auth::{WriteSignature, SIGNATURE_HEADER};
+    use pubky_common::Keypair;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_faulty_storage() {
+        let storage = Arc::new(Storage::new());
+        let faults = FaultyStorage::new().seed(1).transient_errors(1.0);
+        let router = Server::builder()
+            .storage(storage.clone())
+            .storage_layer(faults.clone())
+            .router();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let put = |path: &str| {
+            let uri = format!("/{}/{}", public_key, path);
+            let request = Request::put(uri).body(Body::from("data")).unwrap();
+            router.clone().oneshot(request)
+        };
+
+        // Transient errors never reach storage
+        let response = put("app/a.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(storage.get(&public_key, "app/a.txt").unwrap().is_none());
+
+        // Partial failures do
+        let faults = FaultyStorage::new().seed(1).partial_failures(1.0);
+        let identity = Keypair::random().public_key();
+        let router = Server::builder()
+            .storage(storage.clone())
+            .storage_layer(faults.clone())
+            .identity(identity)
+            .router();
+        let uri = format!("/{}/app/b.txt", public_key);
+        let signature = WriteSignature::sign(&keypair, &identity, "PUT", "app/b.txt", b"data");
+        let request = Request::put(&uri)
+            .header(SIGNATURE_HEADER, signature.to_string())
+            .body(Body::from("data"))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            storage.get(&public_key, "app/b.txt").unwrap().unwrap(),
+            b"data"
+        );
+
+        // Routes outside storage are left alone, and so is everything once
+        // disabled
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        faults.set_enabled(false);
+        let request = Request::get(&uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            faults.stats(),
+            FaultStats {
+                requests: 1,
+                partial_failures: 1,
+                ..Default::default()
+            }
+        );
+
+        // Faults come at the configured rates
+        let faults = FaultyStorage::new()
+            .seed(42)
+            .latency(Duration::ZERO, Duration::from_millis(2))
+            .transient_errors(0.5);
+        let router = Server::builder().storage_layer(faults.clone()).router();
+        let mut failures = Vec::new();
+        for _ in 0..100 {
+            let request = Request::get(&uri).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            failures.push(response.status() == StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let stats = faults.stats();
+        assert_eq!(stats.requests, 100);
+        assert!((30..70).contains(&stats.transient_errors));
+        assert!(stats.delayed > 50);
+        let count = failures.iter().filter(|failed| **failed).count();
+        assert_eq!(count as u64, stats.transient_errors);
+    }
+
+    #[tokio::test]
+    async fn test_faulty_backend() {
+        let inner = Arc::new(MemoryBackend::new());
+        let faults = FaultyStorage::new().seed(1).transient_errors(1.0);
+        let identity = Keypair::random().public_key();
+        let router = Server::builder()
+            .backend(Arc::new(faults.backend(inner.clone())))
+            .identity(identity)
+            .router();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let uri = format!("/{}/app/a.txt", public_key);
+        let put = |router: Router| {
+            let signature = WriteSignature::sign(&keypair, &identity, "PUT", "app/a.txt", b"data");
+            let request = Request::put(&uri)
+                .header(SIGNATURE_HEADER, signature.to_string())
+                .body(Body::from("data"))
+                .unwrap();
+            router.oneshot(request)
+        };
+
+        // Transient errors are 503s, and never reach the backend
+        let response = put(router.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(inner.list(&public_key, "").unwrap().is_empty());
+        let request = Request::get(&uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Partial failures are 500s, after the write landed
+        let faults = FaultyStorage::new().seed(1).partial_failures(1.0);
+        let router = Server::builder()
+            .backend(Arc::new(faults.backend(inner.clone())))
+            .identity(identity)
+            .router();
+        let response = put(router.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            inner.get(&public_key, "app/a.txt").unwrap().unwrap(),
+            b"data"
+        );
+        assert!(faults.stats().partial_failures > 0);
+
+        // Reads only fail transiently
+        let request = Request::get(&uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        faults.set_enabled(false);
+        let response = put(router.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+}
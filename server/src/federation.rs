@@ -0,0 +1,545 @@
+//! Federation gateway
+//!
+//! With [`ServerBuilder::federate`](crate::ServerBuilder::federate), reads
+//! for public keys this server doesn't host are served from the owner's
+//! homeserver, found through pkarr relays. Responses are proxied and cached
+//! briefly, or answered with a redirect, so any homeserver can act as a
+//! gateway to the wider network.
+//!
+//! With [`FederationConfig::mirror`], public entries stay cached after they
+//! expire: they are revalidated with their `ETag` when the origin has one,
+//! and served as they were last seen while their homeserver is down. The
+//! headers the origin describes an entry with, such as its version, are
+//! kept with the copy, so clients can tell what they got.
+//!
+//! Proxied responses are read whole to be cached, so those over
+//! [`FederationConfig::max_body`] fail instead.
+
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use bytes::Bytes;
+use pubky_common::pkarr::{self, SignedPacket, DEFAULT_RELAYS};
+use pubky_common::version::{SIBLINGS_HEADER, VERSION_HEADER};
+use pubky_common::PublicKey;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::routes::ApiError;
+use crate::storage::Storage;
+
+/// `Via` token marking requests proxied by a homeserver, so they are never
+/// proxied again
+const VIA: &str = "1.1 pubky-homeserver";
+
+/// Shortest time a resolved homeserver is cached
+const MIN_TTL: Duration = Duration::from_secs(60);
+
+/// Longest time a resolved homeserver is cached
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Timeout of requests to relays and foreign homeservers
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most proxied responses kept in the cache
+const MAX_CACHED_RESPONSES: usize = 1024;
+
+/// Headers of proxied responses passed on to clients
+const FORWARDED_HEADERS: [HeaderName; 6] = [
+    header::CACHE_CONTROL,
+    header::CONTENT_TYPE,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    HeaderName::from_static(VERSION_HEADER),
+    HeaderName::from_static(SIBLINGS_HEADER),
+];
+
+/// How to serve reads for foreign public keys
+#[derive(Debug, Clone)]
+pub struct FederationConfig {
+    /// Base URLs of the pkarr relays used to find homeservers
+    pub relays: Vec<String>,
+    /// Redirect clients to the owner's homeserver instead of proxying
+    pub redirect: bool,
+    /// How long proxied responses are cached
+    pub cache_ttl: Duration,
+    /// Keep public entries after they expire, and serve them while their
+    /// homeserver is unreachable
+    pub mirror: bool,
+    /// Largest response body proxied, in bytes
+    pub max_body: usize,
+}
+
+impl FederationConfig {
+    /// Proxy through the default relays, caching responses of up to 2 MiB
+    /// for a minute
+    pub fn new() -> Self {
+        Self {
+            relays: DEFAULT_RELAYS.iter().map(|r| r.to_string()).collect(),
+            redirect: false,
+            cache_ttl: Duration::from_secs(60),
+            mirror: false,
+            max_body: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A proxied response
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Unix timestamps in milliseconds
+    fetched_at: u64,
+    expires_at: u64,
+}
+
+impl CachedResponse {
+    fn to_response(&self, now: u64) -> Response {
+        let mut response = (self.status, self.body.clone()).into_response();
+        let headers = response.headers_mut();
+        headers.extend(self.headers.clone());
+        headers.insert(header::VIA, HeaderValue::from_static(VIA));
+        let age = now.saturating_sub(self.fetched_at) / 1000;
+        headers.insert(header::AGE, age.into());
+        response
+    }
+}
+
+/// Gateway state shared by all requests
+pub(crate) struct Federation {
+    config: FederationConfig,
+    storage: Arc<Storage>,
+    http: reqwest::Client,
+    homeservers: Mutex<HashMap<PublicKey, (Option<String>, u64)>>,
+    responses: Mutex<HashMap<String, CachedResponse>>,
+}
+
+impl Federation {
+    pub(crate) fn new(config: FederationConfig, storage: Arc<Storage>) -> Self {
+        Self {
+            config,
+            storage,
+            http: reqwest::Client::new(),
+            homeservers: Mutex::new(HashMap::new()),
+            responses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Base URL of the homeserver of `public_key`, if it can be found
+    async fn homeserver(&self, public_key: &PublicKey) -> Option<String> {
+        if let Some((homeserver, expires_at)) = self.homeservers.lock().unwrap().get(public_key) {
+            if *expires_at > self.storage.now_millis() {
+                return homeserver.clone();
+            }
+        }
+
+        let found = pkarr::resolve_homeserver(public_key, |key| self.fetch(key)).await;
+        let (homeserver, ttl) = match found {
+            Ok(Some((url, ttl))) => (Some(url), Duration::from_secs(ttl.into())),
+            Ok(None) => (None, MIN_TTL),
+            Err(e) => {
+                tracing::debug!("Failed to resolve {}: {}", public_key, e);
+                (None, MIN_TTL)
+            }
+        };
+        let expires_at = self.storage.now_millis() + ttl.clamp(MIN_TTL, MAX_TTL).as_millis() as u64;
+        self.homeservers
+            .lock()
+            .unwrap()
+            .insert(*public_key, (homeserver.clone(), expires_at));
+        homeserver
+    }
+
+    /// Fetch the packet of `public_key` from the first relay that has it
+    async fn fetch(&self, public_key: PublicKey) -> Result<Option<SignedPacket>, String> {
+        let mut error = None;
+        for relay in &self.config.relays {
+            let url = format!("{}/{}", relay.trim_end_matches('/'), public_key);
+            match self.http.get(&url).timeout(TIMEOUT).send().await {
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => {}
+                Ok(response) if response.status().is_success() => {
+                    let payload = response.bytes().await.map_err(|e| e.to_string())?;
+                    return SignedPacket::from_relay_payload(&public_key, &payload)
+                        .map(Some)
+                        .map_err(|e| e.to_string());
+                }
+                Ok(response) => error = Some(format!("{} returned {}", relay, response.status())),
+                Err(e) => error = Some(e.to_string()),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    /// Serve `uri` from `homeserver`, through the response cache
+    async fn proxy(&self, homeserver: &str, uri: &str) -> Result<Response, ApiError> {
+        let cached = self.responses.lock().unwrap().get(uri).cloned();
+        let now = self.storage.now_millis();
+        if let Some(cached) = cached.as_ref().filter(|c| c.expires_at > now) {
+            return Ok(cached.to_response(now));
+        }
+
+        let url = format!("{}{}", homeserver, uri);
+        let mut request = self
+            .http
+            .get(&url)
+            .header(header::VIA, VIA)
+            .timeout(TIMEOUT);
+        if let Some(etag) = cached.as_ref().and_then(|c| c.headers.get(header::ETAG)) {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let fetched = match request.send().await {
+            Ok(response) => self.read(response).await,
+            Err(e) => Err(e.to_string()),
+        };
+
+        let response = match (fetched, cached) {
+            (Ok(fetched), Some(mut cached)) if fetched.status == StatusCode::NOT_MODIFIED => {
+                cached.fetched_at = fetched.fetched_at;
+                cached.expires_at = fetched.expires_at;
+                self.cache(uri.to_string(), cached.clone());
+                cached
+            }
+            (Ok(fetched), Some(cached))
+                if fetched.status.is_server_error() && self.mirrors(uri) =>
+            {
+                tracing::debug!("Serving a mirrored {}: {} failed", uri, homeserver);
+                cached
+            }
+            (Err(e), Some(cached)) if self.mirrors(uri) => {
+                tracing::debug!("Serving a mirrored {}: {}", uri, e);
+                cached
+            }
+            (Err(e), _) => {
+                return Err(ApiError::InternalError(format!(
+                    "Failed to reach {}: {}",
+                    homeserver, e
+                )))
+            }
+            (Ok(fetched), _) => {
+                let no_store = fetched
+                    .headers
+                    .get(header::CACHE_CONTROL)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| value.contains("no-store") || value.contains("private"));
+                if fetched.status.is_success() && !no_store {
+                    self.cache(uri.to_string(), fetched.clone());
+                } else if matches!(fetched.status, StatusCode::NOT_FOUND | StatusCode::GONE) {
+                    self.responses.lock().unwrap().remove(uri);
+                }
+                fetched
+            }
+        };
+        Ok(response.to_response(self.storage.now_millis()))
+    }
+
+    /// Read a response of a foreign homeserver for the cache, up to
+    /// [`FederationConfig::max_body`]
+    async fn read(&self, mut response: reqwest::Response) -> Result<CachedResponse, String> {
+        let status = response.status();
+        let mut headers = HeaderMap::new();
+        for name in &FORWARDED_HEADERS {
+            if let Some(value) = response.headers().get(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+        let max_body = self.config.max_body;
+        let too_large = || format!("the response is over {} bytes", max_body);
+        if response
+            .content_length()
+            .is_some_and(|length| length > max_body as u64)
+        {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            if body.len() + chunk.len() > max_body {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let body = Bytes::from(body);
+        let fetched_at = self.storage.now_millis();
+        Ok(CachedResponse {
+            status,
+            headers,
+            body,
+            fetched_at,
+            expires_at: fetched_at + self.config.cache_ttl.as_millis() as u64,
+        })
+    }
+
+    /// The mirrored copy of `uri`, if any
+    fn mirrored(&self, uri: &str) -> Option<Response> {
+        if !self.mirrors(uri) {
+            return None;
+        }
+        let responses = self.responses.lock().unwrap();
+        let now = self.storage.now_millis();
+        responses.get(uri).map(|cached| cached.to_response(now))
+    }
+
+    /// Whether `uri` is kept after it expires: public entries, when
+    /// mirroring
+    fn mirrors(&self, uri: &str) -> bool {
+        self.config.mirror
+            && uri
+                .trim_start_matches('/')
+                .split_once('/')
+                .is_some_and(|(_, path)| path.starts_with("pub/"))
+    }
+
+    fn cache(&self, uri: String, response: CachedResponse) {
+        let mut responses = self.responses.lock().unwrap();
+        if responses.len() >= MAX_CACHED_RESPONSES && !responses.contains_key(&uri) {
+            let now = self.storage.now_millis();
+            responses.retain(|uri, cached| cached.expires_at > now || self.mirrors(uri));
+            if responses.len() >= MAX_CACHED_RESPONSES {
+                // Make room by dropping the copy fetched the longest ago
+                let oldest = responses
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.fetched_at)
+                    .map(|(uri, _)| uri.clone());
+                if let Some(oldest) = oldest {
+                    responses.remove(&oldest);
+                }
+            }
+        }
+        responses.insert(uri, response);
+    }
+}
+
+/// Middleware serving reads for public keys this server doesn't host from
+/// their own homeserver
+pub(crate) async fn serve_foreign(
+    State(federation): State<Arc<Federation>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let proxied = request
+        .headers()
+        .get_all(header::VIA)
+        .iter()
+        .any(|via| via.as_bytes() == VIA.as_bytes());
+    if !matches!(*request.method(), Method::GET | Method::HEAD) || proxied {
+        return Ok(next.run(request).await);
+    }
+
+    // Nested routers see a stripped path, so use the original one
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.0.clone())
+        .unwrap_or_else(|| request.uri().clone());
+    let public_key = uri
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .and_then(|z32| PublicKey::from_z32(z32).ok());
+    let Some(public_key) = public_key.filter(|pk| !federation.storage.hosts(pk)) else {
+        return Ok(next.run(request).await);
+    };
+    let uri = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let response = match federation.homeserver(&public_key).await {
+        Some(homeserver) if federation.config.redirect => {
+            return Ok(Redirect::temporary(&format!("{}{}", homeserver, uri)).into_response());
+        }
+        Some(homeserver) => federation.proxy(&homeserver, uri).await?,
+        // Relays may be down too
+        None => match federation.mirrored(uri) {
+            Some(mirrored) => mirrored,
+            None => return Ok(next.run(request).await),
+        },
+    };
+    match *request.method() {
+        Method::HEAD => {
+            let (parts, _) = response.into_parts();
+            Ok(Response::from_parts(parts, Body::empty()))
+        }
+        _ => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PkarrConfig, Server};
+    use pubky_common::pkarr::{Record, RecordData, PUBKY_RECORD};
+    use pubky_common::Keypair;
+
+    #[tokio::test]
+    async fn test_federation_gateway() {
+        let relay = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr_relay(true)
+            .start()
+            .await
+            .unwrap();
+        let relays = vec![format!("{}/pkarr", relay.url())];
+        let mut config = PkarrConfig::new(Keypair::random(), "127.0.0.1");
+        config.relays = relays.clone();
+        let home = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr(config)
+            .start()
+            .await
+            .unwrap();
+        let mut federation = FederationConfig::new();
+        federation.relays = relays.clone();
+        let gateway = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .federate(federation.clone())
+            .start()
+            .await
+            .unwrap();
+        federation.redirect = true;
+        let redirector = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .federate(federation)
+            .start()
+            .await
+            .unwrap();
+
+        // A user hosted on `home` announces it
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        home.storage()
+            .put(public_key, "pub/hello.txt".to_string(), b"hello".to_vec())
+            .unwrap();
+        let record = Record::new(
+            PUBKY_RECORD,
+            300,
+            RecordData::Svcb {
+                priority: 0,
+                target: home.public_key().unwrap().to_z32(),
+                port: None,
+            },
+        );
+        let packet = SignedPacket::sign(&keypair, vec![record]).unwrap();
+        let http = reqwest::Client::new();
+        assert_eq!(crate::pkarr::publish(&http, &relays, &packet).await, 1);
+        let home_record = format!("{}/pkarr/{}", relay.url(), home.public_key().unwrap());
+        while !http
+            .get(&home_record)
+            .send()
+            .await
+            .unwrap()
+            .status()
+            .is_success()
+        {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // Proxied, then served from the cache
+        let url = format!("{}/{}/pub/hello.txt", gateway.url(), public_key);
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::VIA], VIA);
+        assert_eq!(response.text().await.unwrap(), "hello");
+        home.storage().delete(&public_key, "pub/hello.txt").unwrap();
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "hello");
+
+        // Or redirected to the owner's homeserver
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let url = format!("{}/{}/pub/hello.txt", redirector.url(), public_key);
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            format!("{}/{}/pub/hello.txt", home.url(), public_key).as_str()
+        );
+
+        // Hosted users and unknown keys are served locally
+        let stranger = Keypair::random().public_key();
+        gateway
+            .storage()
+            .put(stranger, "a.txt".to_string(), b"local".to_vec())
+            .unwrap();
+        let url = format!("{}/{}/a.txt", gateway.url(), stranger);
+        assert_eq!(
+            http.get(&url).send().await.unwrap().text().await.unwrap(),
+            "local"
+        );
+        let url = format!("{}/{}/a.txt", gateway.url(), Keypair::random().public_key());
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        redirector.shutdown().await;
+        gateway.shutdown().await;
+        home.shutdown().await;
+        relay.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_mirror() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // An origin answering revalidations, counting full responses
+        let served = Arc::new(AtomicUsize::new(0));
+        let origin = axum::Router::new().fallback({
+            let served = served.clone();
+            move |headers: axum::http::HeaderMap| async move {
+                if headers.get(header::IF_NONE_MATCH) == Some(&HeaderValue::from_static("\"v1\"")) {
+                    return StatusCode::NOT_MODIFIED.into_response();
+                }
+                served.fetch_add(1, Ordering::SeqCst);
+                ([(header::ETAG, "\"v1\"")], "hello").into_response()
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let homeserver = format!("http://{}", listener.local_addr().unwrap());
+        let task = tokio::spawn(async move { axum::serve(listener, origin).await });
+
+        let mut config = FederationConfig::new();
+        config.cache_ttl = Duration::ZERO;
+        config.mirror = true;
+        let federation = Federation::new(config, Arc::new(Storage::new()));
+        let public_key = pubky_common::Keypair::random().public_key();
+        let public = format!("/{}/pub/hello.txt", public_key);
+        let private = format!("/{}/private.txt", public_key);
+
+        let text = |response: Response| async {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX);
+            String::from_utf8(body.await.unwrap().to_vec()).unwrap()
+        };
+        let response = federation.proxy(&homeserver, &public).await.unwrap();
+        assert_eq!(response.headers()[header::ETAG], "\"v1\"");
+        assert_eq!(text(response).await, "hello");
+        // Expired at once, then revalidated
+        let response = federation.proxy(&homeserver, &public).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(text(response).await, "hello");
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+        federation.proxy(&homeserver, &private).await.unwrap();
+
+        // Public entries outlive their homeserver
+        task.abort();
+        let _ = task.await;
+        let response = federation.proxy(&homeserver, &public).await.unwrap();
+        assert!(response.headers().contains_key(header::AGE));
+        assert_eq!(text(response).await, "hello");
+        assert!(federation.mirrored(&public).is_some());
+        assert!(federation.proxy(&homeserver, &private).await.is_err());
+        assert!(federation.mirrored(&private).is_none());
+    }
+}
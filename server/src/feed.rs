@@ -0,0 +1,295 @@
+//! Atom feeds of posts
+//!
+//! `GET /{public_key}/pub/posts/feed.xml` serves an Atom feed of the
+//! entries under `pub/posts/`, generated on each request unless the user
+//! stored an entry at that path, so feed readers can subscribe to anyone
+//! hosted here.
+//!
+//! Posts are read by extension:
+//!
+//! - `.json` posts are objects with a `content` and optional `title`,
+//!   `published` and `updated` RFC 3339 dates.
+//! - `.md` posts are titled by their first `# ` heading.
+//! - Other text posts are titled by their name.
+//!
+//! Posts without a date are dated by a `YYYY-MM-DD` prefix of their name,
+//! or by the time of the request. Like the ActivityPub outbox, the feed
+//! lists the last paths first.
+
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+};
+use pubky_common::PublicKey;
+use serde::Deserialize;
+use std::io;
+
+use crate::activitypub::POSTS_PREFIX;
+use crate::storage::Storage;
+
+/// Path of the generated feed
+pub const FEED_PATH: &str = "pub/posts/feed.xml";
+
+/// Media type of Atom feeds
+const ATOM_XML: &str = "application/atom+xml; charset=utf-8";
+
+/// Most posts in a feed
+const MAX_POSTS: usize = 50;
+
+/// A post stored as JSON
+#[derive(Debug, Deserialize)]
+struct JsonPost {
+    title: Option<String>,
+    content: String,
+    published: Option<String>,
+    updated: Option<String>,
+}
+
+/// A post as it appears in the feed
+struct Post {
+    name: String,
+    title: String,
+    content: String,
+    published: Option<String>,
+    updated: String,
+}
+
+/// The feed of `public_key`, or `None` if they have no posts
+pub(crate) fn atom_response(
+    storage: &Storage,
+    public_key: &PublicKey,
+) -> io::Result<Option<Response>> {
+    let feed = atom(storage, public_key, &rfc3339(storage.now_millis()))?;
+    Ok(feed.map(|feed| ([(header::CONTENT_TYPE, ATOM_XML)], feed).into_response()))
+}
+
+/// Render the feed, dating undated posts `now`
+fn atom(storage: &Storage, public_key: &PublicKey, now: &str) -> io::Result<Option<String>> {
+    let mut paths = storage.list(public_key, POSTS_PREFIX);
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    paths.sort_unstable_by(|a, b| b.cmp(a));
+    let mut posts = Vec::new();
+    for path in &paths {
+        if posts.len() == MAX_POSTS {
+            break;
+        }
+        posts.extend(post(storage, public_key, path, now)?);
+    }
+
+    let author = storage
+        .handle_of(public_key)
+        .unwrap_or_else(|| public_key.to_z32());
+    let updated = posts
+        .iter()
+        .map(|post| post.updated.as_str())
+        .max()
+        .unwrap_or(now);
+    let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    feed.push_str(&format!(
+        "  <id>pubky://{}/{}</id>\n",
+        public_key, POSTS_PREFIX
+    ));
+    feed.push_str(&format!("  <title>{}</title>\n", escape(&author)));
+    feed.push_str(&format!("  <updated>{}</updated>\n", escape(updated)));
+    feed.push_str(&format!(
+        "  <link rel=\"self\" href=\"/{}/{}\"/>\n",
+        public_key, FEED_PATH
+    ));
+    feed.push_str(&format!(
+        "  <author><name>{}</name></author>\n",
+        escape(&author)
+    ));
+    for post in posts {
+        let path = format!("{}{}", POSTS_PREFIX, post.name);
+        feed.push_str("  <entry>\n");
+        feed.push_str(&format!(
+            "    <id>pubky://{}/{}</id>\n",
+            public_key,
+            escape(&path)
+        ));
+        feed.push_str(&format!("    <title>{}</title>\n", escape(&post.title)));
+        feed.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            escape(&post.updated)
+        ));
+        if let Some(published) = &post.published {
+            feed.push_str(&format!(
+                "    <published>{}</published>\n",
+                escape(published)
+            ));
+        }
+        feed.push_str(&format!(
+            "    <link href=\"/{}/{}\"/>\n",
+            public_key,
+            escape(&path)
+        ));
+        feed.push_str(&format!(
+            "    <content type=\"text\">{}</content>\n",
+            escape(&post.content)
+        ));
+        feed.push_str("  </entry>\n");
+    }
+    feed.push_str("</feed>\n");
+    Ok(Some(feed))
+}
+
+/// The post at `path`, if it holds text
+fn post(
+    storage: &Storage,
+    public_key: &PublicKey,
+    path: &str,
+    now: &str,
+) -> io::Result<Option<Post>> {
+    let Some(name) = path.strip_prefix(POSTS_PREFIX) else {
+        return Ok(None);
+    };
+    if path == FEED_PATH || name.ends_with('/') {
+        return Ok(None);
+    }
+    let text = storage
+        .get(public_key, path)?
+        .and_then(|value| String::from_utf8(value).ok());
+    Ok(text.and_then(|text| parse_post(name, text, now)))
+}
+
+/// The post named `name` holding `text`
+fn parse_post(name: &str, text: String, now: &str) -> Option<Post> {
+    let dated = date_prefix(name).unwrap_or_else(|| now.to_string());
+
+    if name.ends_with(".json") {
+        let post: JsonPost = serde_json::from_str(&text).ok()?;
+        let updated = post.updated.or(post.published.clone()).unwrap_or(dated);
+        return Some(Post {
+            name: name.to_string(),
+            title: post.title.unwrap_or_else(|| name.to_string()),
+            content: post.content,
+            published: post.published,
+            updated,
+        });
+    }
+
+    let title = match name.ends_with(".md") {
+        true => text
+            .lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .map(|title| title.trim().to_string()),
+        false => None,
+    };
+    Some(Post {
+        name: name.to_string(),
+        title: title.unwrap_or_else(|| name.to_string()),
+        content: text,
+        published: None,
+        updated: dated,
+    })
+}
+
+/// Midnight UTC of a `YYYY-MM-DD` prefix of `name`
+fn date_prefix(name: &str) -> Option<String> {
+    let date = name.get(..10)?;
+    let valid = date.char_indices().all(|(i, c)| match i {
+        4 | 7 => c == '-',
+        _ => c.is_ascii_digit(),
+    });
+    valid.then(|| format!("{}T00:00:00Z", date))
+}
+
+/// RFC 3339 date of a Unix timestamp in milliseconds
+fn rfc3339(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (days, time) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch, after Howard Hinnant
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Escape text for XML, dropping characters XML can't hold
+fn escape(text: &str) -> String {
+    text.chars()
+        .filter(|&c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .fold(String::with_capacity(text.len()), |mut escaped, c| {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                _ => escaped.push(c),
+            }
+            escaped
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use pubky_common::Keypair;
+
+    #[tokio::test]
+    async fn test_atom_feed() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1_709_210_096_000), "2024-02-29T12:34:56Z");
+
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let public_key = Keypair::random().public_key();
+        let url = format!("{}/{}/{}", server.url(), public_key, FEED_PATH);
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        let storage = server.storage();
+        let put = |path: &str, value: &str| {
+            storage
+                .put(public_key, path.to_string(), value.as_bytes().to_vec())
+                .unwrap()
+        };
+        put("pub/posts/2024-01-01-hello.md", "# Hello\n\nFirst <post>");
+        put(
+            "pub/posts/2024-02-01.json",
+            r#"{"title":"JSON","content":"Second","published":"2024-02-01T10:00:00Z"}"#,
+        );
+        put("pub/posts/2024-03-01.json", "not json");
+
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], ATOM_XML);
+        let feed = response.text().await.unwrap();
+        assert!(feed.contains("<updated>2024-02-01T10:00:00Z</updated>\n  <link rel"));
+        assert!(feed.contains("<title>Hello</title>"));
+        assert!(feed.contains("<content type=\"text\"># Hello\n\nFirst &lt;post&gt;</content>"));
+        assert!(feed.contains("<updated>2024-01-01T00:00:00Z</updated>"));
+        assert!(!feed.contains("not json"));
+        // Last path first
+        assert!(feed.find("<title>JSON</title>") < feed.find("<title>Hello</title>"));
+
+        // A stored feed takes precedence
+        put(FEED_PATH, "<feed/>");
+        assert_eq!(
+            reqwest::get(&url).await.unwrap().text().await.unwrap(),
+            "<feed/>"
+        );
+
+        server.shutdown().await;
+    }
+}
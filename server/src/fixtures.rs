@@ -0,0 +1,321 @@
+//! Declarative test data
+//!
+//! A [`Fixture`] describes users and the trees of entries they own, and
+//! loads them into a storage in one call, so tests state the data they need
+//! instead of writing it entry by entry:
+//!
+//! ```
+//! use pubky_server::fixtures::Fixture;
+//! use pubky_server::Storage;
+//!
+//! let storage = Storage::new();
+//! let users = Fixture::new()
+//!     .user("alice", |alice| {
+//!         alice
+//!             .entry("pub/profile.json", r#"{"name":"Alice"}"#)
+//!             .dir("pub/posts", |posts| posts.many("post-{}.md", 20, 512))
+//!             .sized("pub/photo.jpg", 1 << 20)
+//!     })
+//!     .user("bob", |bob| bob.tagged("pub/todo.txt", "Milk", ["chores"]))
+//!     .load(&storage);
+//!
+//! assert_eq!(storage.list(&users.public_key("alice"), "pub/posts/").len(), 20);
+//! ```
+//!
+//! Storage keeps no content types: generated bodies follow the extension of
+//! their path, which is what routes go by. Entries written [`at`](UserFixture::at)
+//! a time get it as the timestamp of their events when the fixture moves a
+//! [`MockClock`], given with [`Fixture::clock`]. Users have the same
+//! keypairs in every run, shared with `pubky-testnet`. Available with the
+//! `fixtures` feature.
+
+use pubky_common::{Keypair, PublicKey};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::clock::MockClock;
+use crate::storage::Storage;
+
+/// Users and entries to load into a storage
+#[derive(Debug, Clone, Default)]
+pub struct Fixture {
+    users: Vec<(String, UserFixture)>,
+    clock: Option<MockClock>,
+}
+
+impl Fixture {
+    /// An empty fixture
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the user named `name`, owning the entries added by `build`
+    pub fn user(mut self, name: &str, build: impl FnOnce(UserFixture) -> UserFixture) -> Self {
+        let user = build(UserFixture::new(""));
+        self.users.push((name.to_string(), user));
+        self
+    }
+
+    /// Move `clock`, the clock of the storage, to the times given with
+    /// [`UserFixture::at`] while loading
+    pub fn clock(mut self, clock: MockClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Register the users and write their entries to `storage`, in the
+    /// order they were added
+    ///
+    /// Panics if the storage fails: fixtures only back tests and demos.
+    pub fn load(&self, storage: &Storage) -> Users {
+        let mut users = BTreeMap::new();
+        for (name, user) in &self.users {
+            let keypair = keypair(name);
+            let public_key = keypair.public_key();
+            if user.registered {
+                storage.register(public_key);
+            }
+            for entry in &user.entries {
+                if let (Some(clock), Some(at)) = (&self.clock, entry.at) {
+                    clock.set(at);
+                }
+                storage
+                    .put(public_key, entry.path.clone(), entry.value.clone())
+                    .expect("Failed to store a fixture entry");
+                if !entry.tags.is_empty() {
+                    storage
+                        .set_tags(public_key, &entry.path, entry.tags.clone())
+                        .expect("Failed to tag a fixture entry");
+                }
+            }
+            users.insert(name.clone(), keypair);
+        }
+        Users(users)
+    }
+}
+
+/// An entry of a [`UserFixture`]
+#[derive(Debug, Clone)]
+struct EntryFixture {
+    path: String,
+    value: Vec<u8>,
+    tags: BTreeSet<String>,
+    at: Option<u64>,
+}
+
+/// The entries of a user, or of a directory of theirs
+#[derive(Debug, Clone)]
+pub struct UserFixture {
+    prefix: String,
+    entries: Vec<EntryFixture>,
+    at: Option<u64>,
+    registered: bool,
+}
+
+impl UserFixture {
+    fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            entries: Vec::new(),
+            at: None,
+            registered: true,
+        }
+    }
+
+    fn push(mut self, path: &str, value: Vec<u8>, tags: BTreeSet<String>) -> Self {
+        self.entries.push(EntryFixture {
+            path: format!("{}{}", self.prefix, path),
+            value,
+            tags,
+            at: self.at,
+        });
+        self
+    }
+
+    /// Add the entry at `path` holding `value`
+    pub fn entry(self, path: &str, value: impl Into<Vec<u8>>) -> Self {
+        self.push(path, value.into(), BTreeSet::new())
+    }
+
+    /// Add the entry at `path` holding `value`, tagged with `tags`
+    pub fn tagged<'a>(
+        self,
+        path: &str,
+        value: impl Into<Vec<u8>>,
+        tags: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let tags = tags.into_iter().map(str::to_string).collect();
+        self.push(path, value.into(), tags)
+    }
+
+    /// Add the entry at `path` holding `size` generated bytes fitting its
+    /// extension
+    pub fn sized(self, path: &str, size: usize) -> Self {
+        let value = generate(path, size);
+        self.push(path, value, BTreeSet::new())
+    }
+
+    /// Add `count` entries of `size` generated bytes, at `pattern` with
+    /// `{}` replaced by their number, counting from 1
+    pub fn many(mut self, pattern: &str, count: usize, size: usize) -> Self {
+        for n in 1..=count {
+            self = self.sized(&pattern.replace("{}", &n.to_string()), size);
+        }
+        self
+    }
+
+    /// Add the entries added by `build` under the directory `path`
+    pub fn dir(mut self, path: &str, build: impl FnOnce(UserFixture) -> UserFixture) -> Self {
+        let mut dir = UserFixture::new(&format!("{}{}/", self.prefix, path.trim_end_matches('/')));
+        dir.at = self.at;
+        let dir = build(dir);
+        self.entries.extend(dir.entries);
+        self
+    }
+
+    /// Write the entries added after this at `millis`, in Unix time
+    pub fn at(mut self, millis: u64) -> Self {
+        self.at = Some(millis);
+        self
+    }
+
+    /// Write the entries without registering the user
+    pub fn unregistered(mut self) -> Self {
+        self.registered = false;
+        self
+    }
+}
+
+/// The keypairs of the users of a loaded [`Fixture`], by name
+#[derive(Debug, Clone)]
+pub struct Users(BTreeMap<String, Keypair>);
+
+impl Users {
+    /// The keypair of the user named `name`
+    ///
+    /// Panics if the fixture has no such user.
+    pub fn keypair(&self, name: &str) -> &Keypair {
+        self.0
+            .get(name)
+            .unwrap_or_else(|| panic!("no user named {:?} in the fixture", name))
+    }
+
+    /// The public key of the user named `name`
+    ///
+    /// Panics if the fixture has no such user.
+    pub fn public_key(&self, name: &str) -> PublicKey {
+        self.keypair(name).public_key()
+    }
+}
+
+/// The keypair of the user named `name`, the same in every run
+pub fn keypair(name: &str) -> Keypair {
+    // The domain predates this module: testnet users came first
+    let seed: [u8; 32] = Sha256::new()
+        .chain_update(b"pubky-testnet:")
+        .chain_update(name.as_bytes())
+        .finalize()
+        .into();
+    Keypair::from_secret_key(&seed)
+}
+
+/// `size` bytes fitting the extension of `path`, the same in every run
+fn generate(path: &str, size: usize) -> Vec<u8> {
+    let extension = path.rsplit_once('.').map(|(_, extension)| extension);
+    match extension {
+        Some("txt" | "md" | "html" | "csv") => {
+            let text = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ";
+            text.bytes().cycle().take(size).collect()
+        }
+        // The shortest JSON document padded to size is `""`
+        Some("json") if size >= 2 => {
+            let mut value = vec![b'"'; size];
+            value[1..size - 1].fill(b'x');
+            value
+        }
+        _ => {
+            // xorshift, so binary entries don't compress
+            let mut state: u32 = 0x9e37_79b9;
+            (0..size)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::EventOp;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_fixture() {
+        let clock = MockClock::at(0);
+        let storage = Storage::with_clock(Arc::new(clock.clone()));
+        let users = Fixture::new()
+            .user("alice", |alice| {
+                alice
+                    .at(1_000)
+                    .entry("pub/profile.json", "{}")
+                    .dir("pub/posts/", |posts| {
+                        posts.many("{}.md", 3, 100).dir("drafts", |drafts| {
+                            drafts.at(2_000).tagged("next.md", "Soon", ["draft"])
+                        })
+                    })
+                    .sized("pub/data.json", 10)
+                    .sized("pub/photo.jpg", 4096)
+            })
+            .user("mallory", |mallory| mallory.unregistered().entry("x", "y"))
+            .clock(clock.clone())
+            .load(&storage);
+
+        let alice = users.public_key("alice");
+        assert_eq!(alice, keypair("alice").public_key());
+        assert!(storage.is_registered(&alice));
+        assert!(!storage.is_registered(&users.public_key("mallory")));
+        let mut posts = storage.list(&alice, "pub/posts/");
+        posts.sort();
+        assert_eq!(
+            posts,
+            [
+                "pub/posts/1.md",
+                "pub/posts/2.md",
+                "pub/posts/3.md",
+                "pub/posts/drafts/next.md"
+            ]
+        );
+        let post = storage.get(&alice, "pub/posts/1.md").unwrap().unwrap();
+        assert_eq!(post.len(), 100);
+        assert!(post.starts_with(b"Lorem ipsum"));
+        assert_eq!(
+            storage.get(&alice, "pub/data.json").unwrap().unwrap(),
+            b"\"xxxxxxxx\""
+        );
+        assert_eq!(
+            storage.get(&alice, "pub/photo.jpg").unwrap().unwrap().len(),
+            4096
+        );
+        assert_eq!(
+            storage.tags(&alice, "pub/posts/drafts/next.md").unwrap(),
+            ["draft"]
+        );
+
+        // Timestamps carry down into directories until changed
+        let events = storage.events_since(0, 100).unwrap();
+        let at = |path: &str| {
+            let event = events.iter().find(|event| event.path == path).unwrap();
+            assert_eq!(event.op, EventOp::Put);
+            event.timestamp
+        };
+        assert_eq!(at("pub/profile.json"), 1_000);
+        assert_eq!(at("pub/posts/3.md"), 1_000);
+        assert_eq!(at("pub/posts/drafts/next.md"), 2_000);
+        assert_eq!(at("pub/photo.jpg"), 1_000);
+    }
+}
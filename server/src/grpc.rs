@@ -0,0 +1,572 @@
+//! gRPC API
+//!
+//! With [`ServerBuilder::grpc`](crate::ServerBuilder::grpc), the server also
+//! serves the `Sessions` and `Storage` services of `proto/pubky.proto` on a
+//! separate TCP port, for backend services that prefer typed RPC to REST.
+//!
+//! Each call is translated into the matching HTTP request and routed through
+//! the same router as the HTTP listener, so sessions, capabilities,
+//! throttling and moderation apply alike. The session token is sent as
+//! `authorization: Bearer <token>` metadata. Failed calls carry the HTTP
+//! API's error code, such as `not_found`, in their status details.
+
+// The services return tonic's `Status`, so helpers do too, however large
+#![allow(clippy::result_large_err)]
+
+use axum::{
+    body::{Body, Bytes},
+    extract::ConnectInfo,
+    http::{header, HeaderValue, Method, Request, Response, StatusCode},
+    Router,
+};
+use futures_util::{stream, Stream, StreamExt};
+use pubky_common::auth::AuthToken;
+use pubky_common::dto::{self, ChangeOp, ErrorResponse, ListResponse, SessionInfo};
+use pubky_common::PublicKey;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio::task::JoinHandle;
+use tonic::{Code, Status};
+use tower::ServiceExt;
+
+use crate::routes;
+
+mod proto {
+    tonic::include_proto!("pubky.v1");
+}
+
+use proto::sessions_server::{Sessions, SessionsServer};
+use proto::storage_server::{Storage, StorageServer};
+
+/// Largest response body read back from the router, other than streams
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Configuration of the gRPC endpoint
+#[derive(Debug, Clone)]
+pub struct GrpcConfig {
+    /// TCP address of the endpoint
+    pub bind: SocketAddr,
+}
+
+impl GrpcConfig {
+    pub fn new(bind: SocketAddr) -> Self {
+        Self { bind }
+    }
+}
+
+/// Bind the endpoint and serve `app` over it in a background task,
+/// returning the task and the bound address
+pub(crate) async fn spawn(
+    config: &GrpcConfig,
+    app: Router,
+) -> io::Result<(JoinHandle<()>, SocketAddr)> {
+    let listener = tokio::net::TcpListener::bind(config.bind).await?;
+    let local_addr = listener.local_addr()?;
+    let gateway = Gateway { app };
+
+    tracing::info!("Serving gRPC on {}", local_addr);
+    let task = tokio::spawn(async move {
+        let serve = tonic::transport::Server::builder()
+            .add_service(SessionsServer::new(gateway.clone()))
+            .add_service(StorageServer::new(gateway))
+            .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener));
+        if let Err(e) = serve.await {
+            tracing::error!("gRPC server error: {}", e);
+        }
+    });
+    Ok((task, local_addr))
+}
+
+/// Translates calls into requests to the HTTP router
+#[derive(Clone)]
+struct Gateway {
+    app: Router,
+}
+
+/// Who made a call: its session and address
+struct Caller {
+    authorization: Option<HeaderValue>,
+    remote: Option<SocketAddr>,
+}
+
+impl Caller {
+    /// The caller of `call`, and its message
+    fn of<T>(call: tonic::Request<T>) -> Result<(Self, T), Status> {
+        let authorization = call
+            .metadata()
+            .get("authorization")
+            .map(|value| HeaderValue::from_bytes(value.as_bytes()))
+            .transpose()
+            .map_err(|e| Status::unauthenticated(e.to_string()))?;
+        let caller = Self {
+            authorization,
+            remote: call.remote_addr(),
+        };
+        Ok((caller, call.into_inner()))
+    }
+}
+
+impl Gateway {
+    /// Route `request` as made by `caller`, turning error responses into
+    /// statuses
+    async fn send(
+        &self,
+        caller: &Caller,
+        mut request: Request<Body>,
+    ) -> Result<Response<Body>, Status> {
+        if let Some(authorization) = &caller.authorization {
+            request
+                .headers_mut()
+                .insert(header::AUTHORIZATION, authorization.clone());
+        }
+        if let Some(remote) = caller.remote {
+            request.extensions_mut().insert(ConnectInfo(remote));
+        }
+
+        let response = match self.app.clone().oneshot(request).await {
+            Ok(response) => response,
+            Err(infallible) => match infallible {},
+        };
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let body = read_body(response).await?;
+        let error = serde_json::from_slice(&body).unwrap_or_else(|_| ErrorResponse {
+            error: String::from_utf8_lossy(&body).into_owned(),
+            code: None,
+            expected: None,
+            actual: None,
+        });
+        Err(error_status(status, error))
+    }
+
+    /// Route a request with a JSON body and parse the JSON response
+    async fn send_json<R>(
+        &self,
+        caller: &Caller,
+        method: Method,
+        uri: &str,
+        body: &impl Serialize,
+    ) -> Result<R, Status>
+    where
+        R: DeserializeOwned,
+    {
+        let body = serde_json::to_vec(body).expect("requests serialize");
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(invalid_argument)?;
+        let body = read_body(self.send(caller, request).await?).await?;
+        serde_json::from_slice(&body).map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+#[tonic::async_trait]
+impl Sessions for Gateway {
+    async fn signup(
+        &self,
+        call: tonic::Request<proto::SignupRequest>,
+    ) -> Result<tonic::Response<proto::Session>, Status> {
+        let (caller, signup) = Caller::of(call)?;
+        let request = dto::SignupRequest {
+            token: auth_token(signup.token)?,
+            invite_code: signup.invite_code,
+        };
+        let session: SessionInfo = self
+            .send_json(&caller, Method::POST, "/signup", &request)
+            .await?;
+        Ok(tonic::Response::new(session.into()))
+    }
+
+    async fn signin(
+        &self,
+        call: tonic::Request<proto::SigninRequest>,
+    ) -> Result<tonic::Response<proto::Session>, Status> {
+        let (caller, signin) = Caller::of(call)?;
+        let token = auth_token(signin.token)?;
+        let session: SessionInfo = self
+            .send_json(&caller, Method::POST, "/session", &token)
+            .await?;
+        Ok(tonic::Response::new(session.into()))
+    }
+
+    async fn signout(
+        &self,
+        call: tonic::Request<proto::SignoutRequest>,
+    ) -> Result<tonic::Response<proto::SignoutResponse>, Status> {
+        let (caller, _) = Caller::of(call)?;
+        let request = http_request(Method::DELETE, "/session".to_string(), Body::empty())?;
+        self.send(&caller, request).await?;
+        Ok(tonic::Response::new(proto::SignoutResponse {}))
+    }
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<proto::ChangeEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Storage for Gateway {
+    async fn put(
+        &self,
+        call: tonic::Request<proto::PutRequest>,
+    ) -> Result<tonic::Response<proto::PutResponse>, Status> {
+        let (caller, put) = Caller::of(call)?;
+        let uri = entry_uri(&put.public_key, &put.path)?;
+        let request = http_request(Method::PUT, uri, Body::from(put.value))?;
+        self.send(&caller, request).await?;
+        Ok(tonic::Response::new(proto::PutResponse {}))
+    }
+
+    async fn get(
+        &self,
+        call: tonic::Request<proto::GetRequest>,
+    ) -> Result<tonic::Response<proto::GetResponse>, Status> {
+        let (caller, get) = Caller::of(call)?;
+        if get.path.ends_with('/') {
+            return Err(Status::invalid_argument(
+                "Paths of entries don't end with /",
+            ));
+        }
+        let uri = entry_uri(&get.public_key, &get.path)?;
+        let request = http_request(Method::GET, uri, Body::empty())?;
+        let value = read_body(self.send(&caller, request).await?).await?;
+        Ok(tonic::Response::new(proto::GetResponse {
+            value: value.to_vec(),
+        }))
+    }
+
+    async fn delete(
+        &self,
+        call: tonic::Request<proto::DeleteRequest>,
+    ) -> Result<tonic::Response<proto::DeleteResponse>, Status> {
+        let (caller, delete) = Caller::of(call)?;
+        let uri = entry_uri(&delete.public_key, &delete.path)?;
+        let request = http_request(Method::DELETE, uri, Body::empty())?;
+        self.send(&caller, request).await?;
+        Ok(tonic::Response::new(proto::DeleteResponse {}))
+    }
+
+    async fn list(
+        &self,
+        call: tonic::Request<proto::ListRequest>,
+    ) -> Result<tonic::Response<proto::ListResponse>, Status> {
+        let (caller, list) = Caller::of(call)?;
+        if !list.prefix.is_empty() && !list.prefix.ends_with('/') {
+            return Err(Status::invalid_argument("Prefixes end with /"));
+        }
+        let mut query = vec![("details", "true".to_string())];
+        if let Some(limit) = list.limit {
+            query.push(("limit", limit.to_string()));
+        }
+        if let Some(cursor) = &list.cursor {
+            query.push(("cursor", cursor.clone()));
+        }
+        if list.reverse {
+            query.push(("reverse", "true".to_string()));
+        }
+        if list.shallow {
+            query.push(("shallow", "true".to_string()));
+        }
+        let uri = format!(
+            "{}?{}",
+            entry_uri(&list.public_key, &list.prefix)?,
+            encode_query(&query)
+        );
+        let request = http_request(Method::GET, uri, Body::empty())?;
+        let body = read_body(self.send(&caller, request).await?).await?;
+        let list: ListResponse =
+            serde_json::from_slice(&body).map_err(|e| Status::internal(e.to_string()))?;
+
+        let entries = list
+            .entries
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| proto::ListEntry {
+                path: entry.path,
+                size: entry.size,
+            })
+            .collect();
+        Ok(tonic::Response::new(proto::ListResponse {
+            entries,
+            next_cursor: list.next_cursor,
+        }))
+    }
+
+    type WatchStream = WatchStream;
+
+    async fn watch(
+        &self,
+        call: tonic::Request<proto::WatchRequest>,
+    ) -> Result<tonic::Response<WatchStream>, Status> {
+        let (caller, watch) = Caller::of(call)?;
+        let public_key = parse_public_key(&watch.public_key)?;
+        let mut uri = format!("/events/{}", public_key);
+        if let Some(after) = watch.after {
+            uri.push_str(&format!("?cursor={}", after));
+        }
+        let request = http_request(Method::GET, uri, Body::empty())?;
+        let body = self.send(&caller, request).await?.into_body();
+        Ok(tonic::Response::new(Box::pin(change_events(body))))
+    }
+}
+
+/// The change events of a server-sent event stream
+fn change_events(body: Body) -> impl Stream<Item = Result<proto::ChangeEvent, Status>> {
+    let state = (body.into_data_stream(), Vec::new());
+    stream::unfold(state, |(mut body, mut buffer)| async move {
+        loop {
+            // Events end with a blank line; other fields and comments,
+            // such as keep-alives, are skipped
+            if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                let frame: Vec<u8> = buffer.drain(..end + 2).collect();
+                let Some(event) = parse_event(&frame) else {
+                    continue;
+                };
+                return Some((Ok(event), (body, buffer)));
+            }
+            match body.next().await? {
+                Ok(chunk) => buffer.extend_from_slice(&chunk),
+                Err(e) => return Some((Err(Status::internal(e.to_string())), (body, buffer))),
+            }
+        }
+    })
+}
+
+/// The change event in the `data` field of a server-sent event
+fn parse_event(frame: &[u8]) -> Option<proto::ChangeEvent> {
+    let frame = std::str::from_utf8(frame).ok()?;
+    let data = frame.lines().find_map(|line| line.strip_prefix("data:"))?;
+    let event: dto::ChangeEvent = serde_json::from_str(data.trim_start()).ok()?;
+    let op = match event.op {
+        ChangeOp::Put => proto::change_event::Op::Put,
+        ChangeOp::Delete => proto::change_event::Op::Delete,
+    };
+    Some(proto::ChangeEvent {
+        seq: event.seq,
+        timestamp: event.timestamp,
+        op: op.into(),
+        path: event.path,
+    })
+}
+
+impl From<SessionInfo> for proto::Session {
+    fn from(session: SessionInfo) -> Self {
+        Self {
+            public_key: session.public_key,
+            token: session.token,
+            created_at: session.created_at,
+            expires_at: session.expires_at,
+        }
+    }
+}
+
+fn auth_token(token: Option<proto::AuthToken>) -> Result<AuthToken, Status> {
+    let token = token.ok_or_else(|| Status::invalid_argument("Missing auth token"))?;
+    Ok(AuthToken {
+        public_key: token.public_key,
+        audience: token.audience,
+        nonce: token.nonce,
+        timestamp: token.timestamp,
+        signature: token.signature,
+    })
+}
+
+fn parse_public_key(public_key: &str) -> Result<PublicKey, Status> {
+    PublicKey::from_z32(public_key).map_err(invalid_argument)
+}
+
+/// The URI of an entry, or of a prefix ending with `/`
+fn entry_uri(public_key: &str, path: &str) -> Result<String, Status> {
+    Ok(routes::uri_path(&parse_public_key(public_key)?, path))
+}
+
+fn http_request(method: Method, uri: String, body: Body) -> Result<Request<Body>, Status> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(body)
+        .map_err(invalid_argument)
+}
+
+/// Percent-encode query parameters
+fn encode_query(query: &[(&str, String)]) -> String {
+    let encode = |value: &str| {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+                byte if byte.is_ascii_alphanumeric() => (byte as char).to_string(),
+                byte => format!("%{:02X}", byte),
+            })
+            .collect::<String>()
+    };
+    query
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+async fn read_body(response: Response<Body>) -> Result<Bytes, Status> {
+    axum::body::to_bytes(response.into_body(), MAX_BODY_SIZE)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))
+}
+
+fn invalid_argument(e: impl std::fmt::Display) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+/// The status of an HTTP error response, with its error code as details
+fn error_status(status: StatusCode, error: ErrorResponse) -> Status {
+    let code = match status {
+        StatusCode::BAD_REQUEST | StatusCode::RANGE_NOT_SATISFIABLE => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN | StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT => Code::AlreadyExists,
+        StatusCode::GONE | StatusCode::PRECONDITION_FAILED | StatusCode::LOCKED => {
+            Code::FailedPrecondition
+        }
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::SERVICE_UNAVAILABLE | StatusCode::BAD_GATEWAY => Code::Unavailable,
+        StatusCode::NOT_IMPLEMENTED | StatusCode::METHOD_NOT_ALLOWED => Code::Unimplemented,
+        _ => Code::Internal,
+    };
+    let details = error.code.unwrap_or_default();
+    Status::with_details(code, error.error, Bytes::from(details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use proto::sessions_client::SessionsClient;
+    use proto::storage_client::StorageClient;
+    use pubky_common::Keypair;
+
+    #[tokio::test]
+    async fn test_grpc_api() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .grpc(GrpcConfig::new(([127, 0, 0, 1], 0).into()))
+            .start()
+            .await
+            .unwrap();
+        let endpoint = format!("http://{}", server.grpc_addr().unwrap());
+        let mut sessions = SessionsClient::connect(endpoint.clone()).await.unwrap();
+        let mut storage = StorageClient::connect(endpoint).await.unwrap();
+
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key().to_z32();
+        let token = AuthToken::sign(&keypair, &server.identity());
+        let session = sessions
+            .signup(proto::SignupRequest {
+                token: Some(proto::AuthToken {
+                    public_key: token.public_key,
+                    timestamp: token.timestamp,
+                    signature: token.signature,
+                    audience: token.audience,
+                    nonce: token.nonce,
+                }),
+                invite_code: None,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(session.public_key, public_key);
+        let bearer = format!("Bearer {}", session.token);
+
+        // Writes need the session, like over HTTP
+        let put = proto::PutRequest {
+            public_key: public_key.clone(),
+            path: "pub/hello world.txt".to_string(),
+            value: b"Hello!".to_vec(),
+        };
+        let status = storage.put(put.clone()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert_eq!(status.details(), b"unauthorized");
+        storage.put(with_session(put, &bearer)).await.unwrap();
+        let value = storage
+            .get(proto::GetRequest {
+                public_key: public_key.clone(),
+                path: "pub/hello world.txt".to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .value;
+        assert_eq!(value, b"Hello!");
+        let stored = server
+            .storage()
+            .get(&keypair.public_key(), "pub/hello world.txt")
+            .unwrap();
+        assert_eq!(stored.as_deref(), Some(b"Hello!".as_slice()));
+
+        let list = storage
+            .list(proto::ListRequest {
+                public_key: public_key.clone(),
+                prefix: "pub/".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(list.entries.len(), 1);
+        assert_eq!(list.entries[0].path, "pub/hello world.txt");
+        assert_eq!(list.entries[0].size, Some(6));
+
+        // Changes stream as they happen
+        let mut changes = storage
+            .watch(proto::WatchRequest {
+                public_key: public_key.clone(),
+                after: Some(0),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let change = changes.message().await.unwrap().unwrap();
+        assert_eq!(change.path, "pub/hello world.txt");
+        assert_eq!(change.op(), proto::change_event::Op::Put);
+
+        let delete = proto::DeleteRequest {
+            public_key: public_key.clone(),
+            path: "pub/hello world.txt".to_string(),
+        };
+        storage.delete(with_session(delete, &bearer)).await.unwrap();
+        let change = changes.message().await.unwrap().unwrap();
+        assert_eq!(change.op(), proto::change_event::Op::Delete);
+        let status = storage
+            .get(proto::GetRequest {
+                public_key: public_key.clone(),
+                path: "pub/hello world.txt".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.details(), b"not_found");
+
+        sessions
+            .signout(with_session(proto::SignoutRequest {}, &bearer))
+            .await
+            .unwrap();
+        let status = sessions
+            .signout(with_session(proto::SignoutRequest {}, &bearer))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        server.shutdown().await;
+    }
+
+    fn with_session<T>(message: T, bearer: &str) -> tonic::Request<T> {
+        let mut call = tonic::Request::new(message);
+        call.metadata_mut()
+            .insert("authorization", bearer.parse().unwrap());
+        call
+    }
+}
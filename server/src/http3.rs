@@ -0,0 +1,213 @@
+//! HTTP/3 listener
+//!
+//! With [`ServerBuilder::http3`](crate::ServerBuilder::http3), the server
+//! also serves its routes over HTTP/3 on a QUIC endpoint bound to the UDP
+//! port of the same number as its TCP listener, and advertises it to
+//! HTTP/1.1 clients with an `Alt-Svc` header. QUIC saves round trips when
+//! connecting, and its connections survive network changes, which helps
+//! mobile clients syncing on the move.
+//!
+//! Request bodies are read whole before routing; response bodies are
+//! streamed, so the change feed works over HTTP/3 too.
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::Response,
+    Router,
+};
+use bytes::{Buf, BytesMut};
+use futures_util::StreamExt;
+use std::io;
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+use tower::ServiceExt;
+
+use crate::quic;
+use crate::tls::TlsIdentity;
+
+/// How long clients may cache the `Alt-Svc` advertisement, in seconds
+const ALT_SVC_MAX_AGE: u64 = 24 * 60 * 60;
+
+/// Largest request body read over HTTP/3
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Configuration of the HTTP/3 endpoint
+#[derive(Debug, Clone)]
+pub struct Http3Config {
+    pub identity: TlsIdentity,
+    /// UDP port advertised to clients; the bound port by default
+    pub port: Option<u16>,
+}
+
+impl Http3Config {
+    pub fn new(identity: TlsIdentity) -> Self {
+        Self {
+            identity,
+            port: None,
+        }
+    }
+
+    /// Value of the `Alt-Svc` header advertising the endpoint on `port`
+    pub(crate) fn alt_svc(&self, port: u16) -> String {
+        format!(
+            "h3=\":{}\"; ma={}",
+            self.port.unwrap_or(port),
+            ALT_SVC_MAX_AGE
+        )
+    }
+}
+
+/// Bind the QUIC endpoint at `addr` and serve `app` over it in a
+/// background task
+pub(crate) fn spawn(
+    config: &Http3Config,
+    addr: SocketAddr,
+    app: Router,
+) -> io::Result<JoinHandle<()>> {
+    let endpoint = quic::endpoint(&config.identity, b"h3", addr)?;
+
+    tracing::info!("Serving HTTP/3 on udp://{}", endpoint.local_addr()?);
+    Ok(tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let app = app.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(incoming, app).await {
+                    tracing::debug!("HTTP/3 connection failed: {}", e);
+                }
+            });
+        }
+    }))
+}
+
+/// Serve the requests of one QUIC connection
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    app: Router,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let connection = incoming.await?;
+    let remote = connection.remote_address();
+    let mut connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+    while let Some(resolver) = connection.accept().await? {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let (request, stream) = match resolver.resolve_request().await {
+                Ok(resolved) => resolved,
+                Err(e) => return tracing::debug!("Invalid HTTP/3 request: {}", e),
+            };
+            if let Err(e) = serve_request(request, stream, remote, app).await {
+                tracing::debug!("HTTP/3 request failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Route a request and stream back its response
+async fn serve_request<S>(
+    request: Request<()>,
+    mut stream: h3::server::RequestStream<S, bytes::Bytes>,
+    remote: SocketAddr,
+    app: Router,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: h3::quic::BidiStream<bytes::Bytes>,
+{
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        if body.len() + chunk.remaining() > MAX_BODY_SIZE {
+            let response = Response::builder().status(413).body(())?;
+            stream.send_response(response).await?;
+            return Ok(stream.finish().await?);
+        }
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    let mut request = request.map(|()| Body::from(body.freeze()));
+    request.extensions_mut().insert(ConnectInfo(remote));
+
+    let response = app.oneshot(request).await?;
+    let (parts, body) = response.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        stream.send_data(chunk?).await?;
+    }
+    Ok(stream.finish().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use axum::http::{header, StatusCode};
+    use pubky_common::Keypair;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_http3() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let identity = TlsIdentity::from_pem(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .http3(Http3Config::new(identity))
+            .start()
+            .await
+            .unwrap();
+        let public_key = Keypair::random().public_key();
+        server
+            .storage()
+            .put(public_key, "pub/hello.txt".to_string(), b"Hello!".to_vec())
+            .unwrap();
+
+        // HTTP/1.1 responses advertise the endpoint
+        let response = reqwest::get(server.url()).await.unwrap();
+        let port = server.local_addr().port();
+        assert_eq!(
+            response.headers()[header::ALT_SVC],
+            format!("h3=\":{}\"; ma={}", port, ALT_SVC_MAX_AGE)
+        );
+
+        // A client trusting the certificate reads over HTTP/3
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let quic = quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap();
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(quic)));
+        let connection = endpoint
+            .connect(server.local_addr(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+            .await
+            .unwrap();
+        tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+        let uri = format!("https://localhost:{}/{}/pub/hello.txt", port, public_key);
+        let request = Request::get(uri).body(()).unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        assert_eq!(body, b"Hello!");
+
+        server.shutdown().await;
+    }
+}
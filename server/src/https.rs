@@ -0,0 +1,284 @@
+//! HTTPS listener
+//!
+//! With [`ServerBuilder::tls`](crate::ServerBuilder::tls), the server
+//! listens on HTTPS instead of plain HTTP, negotiating HTTP/2 or HTTP/1.1.
+//! It can also answer plain HTTP on another address, such as port 80,
+//! with redirects to the same URL over HTTPS. Handshakes run concurrently
+//! and time out, so slow clients don't hold up others.
+//!
+//! The certificate is either given, or obtained and renewed over ACME with
+//! the `acme` feature (see [`AcmeConfig`](crate::AcmeConfig)).
+//! Available with the `tls` feature.
+
+use axum::{
+    extract::State,
+    http::{header, uri::Authority, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    serve::Listener,
+    Router,
+};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::tls::TlsIdentity;
+
+/// How long a client may take to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshaken connections waiting to be served
+const BACKLOG: usize = 128;
+
+/// Configuration of the HTTPS listener
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub certificate: TlsCertificate,
+    /// Also listen for plain HTTP at this address, redirecting every
+    /// request to HTTPS
+    pub redirect_from: Option<SocketAddr>,
+}
+
+/// Where the HTTPS listener gets its certificate
+#[derive(Debug, Clone)]
+pub enum TlsCertificate {
+    /// A fixed certificate chain and key
+    Identity(TlsIdentity),
+    /// Certificates ordered and renewed over ACME
+    #[cfg(feature = "acme")]
+    Acme(crate::AcmeConfig),
+}
+
+impl TlsConfig {
+    pub fn new(identity: TlsIdentity) -> Self {
+        Self {
+            certificate: TlsCertificate::Identity(identity),
+            redirect_from: None,
+        }
+    }
+
+    /// Serve certificates ordered and renewed over ACME
+    #[cfg(feature = "acme")]
+    pub fn acme(config: crate::AcmeConfig) -> Self {
+        Self {
+            certificate: TlsCertificate::Acme(config),
+            redirect_from: None,
+        }
+    }
+}
+
+/// A TCP listener handing out connections once their TLS handshake
+/// completed
+pub(crate) struct TlsListener {
+    local_addr: SocketAddr,
+    handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    /// The accept loop, and the renewal of ACME certificates
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl TlsListener {
+    /// Accept connections on `listener` and run their handshakes in the
+    /// background
+    pub(crate) fn new(listener: TcpListener, certificate: &TlsCertificate) -> io::Result<Self> {
+        let mut tasks = Vec::new();
+        let mut tls = match certificate {
+            TlsCertificate::Identity(identity) => server_config(identity)?,
+            #[cfg(feature = "acme")]
+            TlsCertificate::Acme(config) => {
+                let (tls, renewing) = crate::acme::spawn(config)?;
+                tasks.push(renewing);
+                tls
+            }
+        };
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        #[cfg(feature = "acme")]
+        if matches!(certificate, TlsCertificate::Acme(_)) {
+            tls.alpn_protocols.push(crate::acme::ACME_TLS_ALPN.to_vec());
+        }
+        let acceptor = TlsAcceptor::from(Arc::new(tls));
+
+        let local_addr = listener.local_addr()?;
+        let (tx, handshaken) = mpsc::channel(BACKLOG);
+        tasks.push(tokio::spawn(async move {
+            loop {
+                let (stream, remote_addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::debug!("Failed to accept a connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        continue;
+                    }
+                };
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    let handshake = acceptor.accept(stream);
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) if is_challenge(&stream) => {
+                            tracing::debug!("Answered an ACME challenge");
+                        }
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, remote_addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake failed: {}", e),
+                        Err(_) => tracing::debug!("TLS handshake timed out"),
+                    }
+                });
+            }
+        }));
+
+        Ok(Self {
+            local_addr,
+            handshaken,
+            tasks,
+        })
+    }
+}
+
+/// TLS configuration serving a fixed certificate
+fn server_config(identity: &TlsIdentity) -> io::Result<rustls::ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| {
+            builder
+                .with_no_client_auth()
+                .with_single_cert(identity.cert_chain.clone(), identity.key.clone_key())
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Whether a connection only validated a domain for ACME, and carries no
+/// requests
+#[cfg_attr(not(feature = "acme"), allow(unused_variables))]
+fn is_challenge(stream: &TlsStream<TcpStream>) -> bool {
+    #[cfg(feature = "acme")]
+    if stream.get_ref().1.alpn_protocol() == Some(crate::acme::ACME_TLS_ALPN) {
+        return true;
+    }
+    false
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(connection) => connection,
+            // The accept loop only ends when aborted on drop
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Answer plain HTTP at `addr` with redirects to HTTPS on `https_port`
+/// in a background task, returning it with the bound address
+pub(crate) async fn spawn_redirect(
+    addr: SocketAddr,
+    https_port: u16,
+) -> io::Result<(JoinHandle<()>, SocketAddr)> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    let app = Router::new().fallback(redirect).with_state(https_port);
+
+    tracing::info!("Redirecting http://{} to HTTPS", local_addr);
+    let task = tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("HTTPS redirect error: {}", e);
+        }
+    });
+    Ok((task, local_addr))
+}
+
+/// Redirect to the same host and path over HTTPS
+async fn redirect(State(https_port): State<u16>, headers: HeaderMap, uri: Uri) -> Response {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+    let Some(host) = host else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let location = match https_port {
+        443 => format!("https://{}{}", host.host(), path),
+        port => format!("https://{}:{}{}", host.host(), port, path),
+    };
+    Redirect::permanent(&location).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use pubky_common::Keypair;
+
+    #[tokio::test]
+    async fn test_https() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let identity = TlsIdentity::from_pem(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let mut config = TlsConfig::new(identity);
+        config.redirect_from = Some(([127, 0, 0, 1], 0).into());
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .tls(config)
+            .start()
+            .await
+            .unwrap();
+        let public_key = Keypair::random().public_key();
+        server
+            .storage()
+            .put(public_key, "pub/hello.txt".to_string(), b"Hello!".to_vec())
+            .unwrap();
+        assert!(server.url().starts_with("https://"));
+
+        // A client trusting the certificate reads over HTTPS
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(cert.cert.der()).unwrap())
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let port = server.local_addr().port();
+        let url = format!("https://localhost:{}/{}/pub/hello.txt", port, public_key);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), "Hello!");
+
+        // Plain HTTP is refused on the HTTPS port
+        let plain = format!("http://localhost:{}/", port);
+        assert!(client.get(&plain).send().await.is_err());
+
+        // And redirected to HTTPS on the redirect address
+        let redirect_port = server.redirect_addr().unwrap().port();
+        let plain = format!(
+            "http://localhost:{}/{}/pub/hello.txt",
+            redirect_port, public_key
+        );
+        let response = client.get(&plain).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], url.as_str());
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,128 @@
+//! Pubky MVP Server
+//!
+//! A simple HTTP server providing key-value storage with public key addressing.
+//!
+//! The server can be run through the `server` binary, or embedded in-process:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use pubky_server::Server;
+//!
+//! let server = Server::builder()
+//!     .bind(([127, 0, 0, 1], 0).into())
+//!     .start()
+//!     .await?;
+//!
+//! println!("Homeserver running at {}", server.url());
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "acme")]
+mod acme;
+mod activitypub;
+mod admin;
+mod audit;
+mod authorize;
+mod backend;
+#[cfg(feature = "quic-blobs")]
+mod blobs;
+mod car;
+mod cbor;
+mod clock;
+mod conditional;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod dev;
+mod dht;
+mod disk;
+mod domains;
+mod events;
+mod export;
+#[cfg(any(test, feature = "faults"))]
+pub mod faults;
+mod federation;
+mod feed;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "http3")]
+mod http3;
+#[cfg(feature = "tls")]
+mod https;
+mod memory;
+mod metrics;
+mod migration;
+mod mirror;
+mod moderation;
+#[cfg(feature = "multi-alg")]
+mod nostr;
+#[cfg(feature = "object-store")]
+mod objects;
+mod pkarr;
+#[cfg(feature = "quic")]
+mod quic;
+mod range;
+mod relay;
+mod replica;
+mod routes;
+mod scan;
+#[cfg(feature = "search")]
+mod search;
+mod server;
+mod session;
+mod storage;
+mod throttle;
+#[cfg(any(feature = "quic", feature = "tls"))]
+mod tls;
+mod tunnel;
+mod uploads;
+mod webfinger;
+mod write_auth;
+
+#[cfg(feature = "acme")]
+pub use acme::{AcmeConfig, LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY};
+pub use activitypub::ActivityPubConfig;
+pub use admin::{BackupEntry, ADMIN_PASSWORD_HEADER};
+pub use audit::{AuditConfig, AuditLog, AuditQuery, AuditRecord};
+pub use authorize::AUTH_REQUEST_TTL;
+pub use backend::{Commit, MemoryBackend, StorageBackend};
+#[cfg(feature = "quic-blobs")]
+pub use blobs::BlobConfig;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use dht::{DhtConfig, DEFAULT_BOOTSTRAP};
+pub use disk::DiskBackend;
+pub use domains::DEFAULT_DOH_RESOLVER;
+pub use federation::FederationConfig;
+pub use feed::FEED_PATH;
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcConfig;
+#[cfg(feature = "http3")]
+pub use http3::Http3Config;
+#[cfg(feature = "tls")]
+pub use https::{TlsCertificate, TlsConfig};
+#[cfg(feature = "alloc-metrics")]
+pub use memory::CountingAllocator;
+pub use memory::{allocated_bytes, resident_bytes};
+pub use metrics::{Histogram, StorageMetrics, StorageOp};
+pub use mirror::{MirrorConfig, ReplicationBatch, ReplicationEvent};
+pub use moderation::{HashBlocklist, ModerationHook, NoModeration, Verdict};
+#[cfg(feature = "object-store")]
+pub use objects::ObjectBackend;
+pub use pkarr::{PkarrConfig, DEFAULT_RELAYS};
+pub use replica::ReplicaConfig;
+pub use scan::{CommandScanner, ScanConfig, Scanner};
+#[cfg(feature = "search")]
+pub use search::{SearchConfig, SEARCH_PATH};
+pub use server::{Server, ServerBuilder, DEFAULT_BIND};
+pub use session::{SESSION_COOKIE, SESSION_TTL};
+pub use storage::{
+    AuthRequest, CurrentEntry, EntryMeta, Quarantined, Session, Siblings, Storage, Timestamps,
+};
+pub use throttle::ThrottleConfig;
+#[cfg(any(feature = "quic", feature = "tls"))]
+pub use tls::TlsIdentity;
+pub use tunnel::TunnelConfig;
+pub use uploads::{UploadConfig, UPLOAD_LENGTH_HEADER, UPLOAD_OFFSET_HEADER};
+pub use write_auth::Authenticated;
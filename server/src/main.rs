@@ -1,55 +1,283 @@
 //! Pubky MVP Server
 //!
-//! A simple HTTP server providing key-value storage with public key addressing.
+//! Binary entry point: runs the homeserver, or performs admin tasks
+//! against a running server's admin API.
 
-mod routes;
-mod storage;
+mod cli;
+mod config;
 
-use axum::{routing::get, Router};
+use clap::Parser;
+use pubky_common::Keypair;
+use pubky_server::{
+    dev, ActivityPubConfig, AuditConfig, AuditLog, CommandScanner, DhtConfig, DiskBackend, FederationConfig, HashBlocklist, MirrorConfig, PkarrConfig, ReplicaConfig, ScanConfig, Server, ThrottleConfig, TunnelConfig, UploadConfig,
+};
 use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
-use tower_http::trace::TraceLayer;
+use std::time::Duration;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use storage::Storage;
+use cli::{Cli, Command};
+use config::{Config, LogFormat, StorageConfig};
+
+/// Directory of resumable uploads under the data directory, hidden from the
+/// storage as it isn't a public key
+const UPLOAD_DIR: &str = ".uploads";
+
+#[cfg(feature = "alloc-metrics")]
+#[global_allocator]
+static ALLOCATOR: pubky_server::CountingAllocator = pubky_server::CountingAllocator;
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Admin(args)) => {
+            if let Err(e) = cli::run_admin(args).await {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        None => serve(cli.serve).await,
+    }
+}
+
+/// Run the homeserver until it fails
+async fn serve(args: cli::ServeArgs) {
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "pubky_server=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    // Create shared storage
-    let storage = Arc::new(Storage::new());
-
-    // Configure CORS
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-
-    // Build the application router
-    let app = Router::new()
-        .route("/", get(|| async { "Pubky MVP Server" }))
-        .nest("/:public_key", routes::storage_routes())
-        .layer(cors)
-        .layer(TraceLayer::new_for_http())
-        .with_state(storage);
-
-    // Start server
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
-        .await
-        .expect("Failed to bind to port 3000");
-
-    tracing::info!("Server listening on http://127.0.0.1:3000");
-    tracing::info!("Example: PUT http://127.0.0.1:3000/<public_key>/my-app/data.txt");
-
-    axum::serve(listener, app)
-        .await
-        .expect("Server error");
+    let filter = tracing_subscriber::EnvFilter::new(&config.log_filter);
+    let registry = tracing_subscriber::registry().with(filter);
+    match config.log_format {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry.with(tracing_subscriber::fmt::layer().json()).init(),
+    }
+
+    if args.dev {
+        print_dev_credentials(config.bind, args.admin_password.as_deref());
+    }
+
+    let mut builder = Server::builder()
+        .bind(config.bind)
+        .cors_origins(&config.cors_origins)
+        .dev(args.dev)
+        .require_invite(args.require_invite);
+    if let Some(bytes) = config.body_limit {
+        builder = builder.body_limit(bytes);
+    }
+    let upload_dir = args.upload_dir.clone().or_else(|| match &config.storage {
+        StorageConfig::Disk(dir) => Some(dir.join(UPLOAD_DIR)),
+        _ => None,
+    });
+    if let Some(dir) = upload_dir {
+        builder = builder.uploads(UploadConfig::new(dir));
+    }
+    match config.storage {
+        StorageConfig::Memory => {}
+        StorageConfig::Disk(dir) => {
+            let backend = DiskBackend::open(dir).expect("Failed to open the data directory");
+            builder = builder.backend(Arc::new(backend));
+        }
+        #[cfg(feature = "object-store")]
+        StorageConfig::ObjectStore { url, options } => {
+            let backend = pubky_server::ObjectBackend::open(&url, options)
+                .expect("Failed to open the object store");
+            builder = builder.backend(Arc::new(backend));
+        }
+    }
+    if let Some(password) = args.admin_password {
+        builder = builder.admin_password(password);
+    }
+    if let Some(primary_url) = args.replica_of {
+        let password = args.primary_password.expect("checked on load");
+        let mut config = ReplicaConfig::new(primary_url, password);
+        config.sync_interval = Duration::from_secs(args.replica_sync_secs);
+        builder = builder.replica_of(config);
+    }
+    if args.write_throttle {
+        let defaults = ThrottleConfig::default();
+        builder = builder.write_throttle(ThrottleConfig {
+            ops_per_sec: args.throttle_ops.unwrap_or(defaults.ops_per_sec),
+            ops_burst: args.throttle_ops_burst.unwrap_or(defaults.ops_burst),
+            bytes_per_sec: args.throttle_bytes.unwrap_or(defaults.bytes_per_sec),
+            bytes_burst: args.throttle_bytes_burst.unwrap_or(defaults.bytes_burst),
+        });
+    }
+    if args.audit {
+        let config = AuditConfig {
+            file: args.audit_file,
+            retention: Duration::from_secs(args.audit_retention_days * 24 * 60 * 60),
+            ..Default::default()
+        };
+        let audit = AuditLog::new(config).expect("Failed to open audit log file");
+        builder = builder.audit_log(Arc::new(audit));
+    }
+    if let Some(path) = args.blocklist {
+        let list = std::fs::read_to_string(path).expect("Failed to read the blocklist");
+        let blocklist = HashBlocklist::parse(&list).quarantine(args.blocklist_quarantine);
+        builder = builder.moderation(Arc::new(blocklist));
+    }
+    if let Some(command) = args.scan_command {
+        let scanner = CommandScanner::parse(&command).expect("The scan command is empty");
+        let mut config = ScanConfig::new(Arc::new(scanner));
+        config.threshold = args.scan_threshold;
+        builder = builder.scan_uploads(config);
+    }
+    if let Some(mirror_url) = args.mirror_to {
+        let password = args.mirror_password.expect("checked on load");
+        let config = MirrorConfig::new(mirror_url, password);
+        builder = builder.mirror_to(config);
+    }
+    let keypair = match args.pkarr_secret_key {
+        Some(hex) => Keypair::from_secret_key(
+            &from_hex(hex.trim()).expect("The pkarr secret key must be 64 hex digits"),
+        ),
+        None => Keypair::random(),
+    };
+    builder = builder.identity(keypair.public_key());
+    #[cfg(feature = "tls")]
+    if let Some(settings) = config.tls {
+        builder = builder.tls(tls_config(settings, &keypair));
+    }
+    if let Some(host) = args.pkarr_host {
+        let mut config = PkarrConfig::new(keypair.clone(), host);
+        if !args.pkarr_relays.is_empty() {
+            config.relays = args.pkarr_relays;
+        }
+        builder = builder.pkarr(config);
+    }
+    builder = builder.pkarr_relay(args.pkarr_relay_server);
+    if let Some(relay_url) = args.tunnel_relay {
+        builder = builder.tunnel(TunnelConfig::new(relay_url, keypair));
+    }
+    builder = builder.tunnel_relay(args.tunnel_relay_server);
+    builder = builder.auth_relay(args.auth_relay_server);
+    #[cfg(feature = "quic")]
+    if let (Some(cert), Some(key)) = (args.quic_cert, args.quic_key) {
+        let read = |path| std::fs::read(path).expect("Failed to read the QUIC certificate");
+        let identity = pubky_server::TlsIdentity::from_pem(&read(cert), &read(key))
+            .expect("Invalid QUIC certificate or key");
+        #[cfg(feature = "http3")]
+        if args.http3 {
+            builder = builder.http3(pubky_server::Http3Config::new(identity.clone()));
+        }
+        #[cfg(feature = "quic-blobs")]
+        if let Some(bind) = args.blob_bind {
+            builder = builder.blob_transfer(pubky_server::BlobConfig::new(identity, bind));
+        }
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(bind) = args.grpc_bind {
+        builder = builder.grpc(pubky_server::GrpcConfig::new(bind));
+    }
+    #[cfg(feature = "search")]
+    if args.search {
+        builder = builder.search(pubky_server::SearchConfig::new());
+    }
+    if args.federate {
+        let mut config = FederationConfig::new();
+        config.redirect = args.federation_redirect;
+        config.mirror = args.federation_mirror;
+        if !args.federation_relays.is_empty() {
+            config.relays = args.federation_relays;
+        }
+        builder = builder.federate(config);
+    }
+    builder = builder.dns_resolver(args.doh_resolver);
+    if let Some(url) = args.activitypub_url {
+        builder = builder.activitypub(ActivityPubConfig::new(url));
+    }
+    if let Some(prefix) = args.dht_prefix {
+        let mut config = DhtConfig::new(prefix);
+        if !args.dht_bootstrap.is_empty() {
+            config.bootstrap = args.dht_bootstrap;
+        }
+        builder = builder.dht(config);
+    }
+
+    builder.run().await.expect("Server error");
+}
+
+/// Configure HTTPS with a certificate read from files, of the identity
+/// `keypair`, or ordered over ACME
+#[cfg(feature = "tls")]
+fn tls_config(settings: config::TlsSettings, keypair: &Keypair) -> pubky_server::TlsConfig {
+    use config::CertificateSource;
+
+    let mut tls = match settings.certificate {
+        CertificateSource::Files { cert, key } => {
+            let read = |path| std::fs::read(path).expect("Failed to read the TLS certificate");
+            let identity = pubky_server::TlsIdentity::from_pem(&read(cert), &read(key))
+                .expect("Invalid TLS certificate or key");
+            pubky_server::TlsConfig::new(identity)
+        }
+        CertificateSource::SelfSigned => {
+            tracing::info!("Serving a certificate of {}", keypair.public_key());
+            let identity = pubky_server::TlsIdentity::from_keypair(keypair)
+                .expect("Failed to create the self-signed certificate");
+            pubky_server::TlsConfig::new(identity)
+        }
+        #[cfg(feature = "acme")]
+        CertificateSource::Acme {
+            domains,
+            contact,
+            directory_url,
+            cache_dir,
+        } => {
+            if cache_dir.is_none() {
+                tracing::warn!("No data directory: ACME certificates are ordered on every start");
+            }
+            let mut acme = pubky_server::AcmeConfig::new(domains);
+            acme.contact = contact;
+            acme.directory_url = directory_url;
+            acme.cache_dir = cache_dir;
+            pubky_server::TlsConfig::acme(acme)
+        }
+    };
+    tls.redirect_from = settings.redirect_from;
+    tls
+}
+
+/// Print the well-known developer identities and how to use them
+fn print_dev_credentials(bind: std::net::SocketAddr, admin_password: Option<&str>) {
+    println!("=== Developer mode ===");
+    println!("These keys are public knowledge: never use them for real data.\n");
+    for user in dev::users() {
+        let secret_key: String = user
+            .keypair
+            .secret_key()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        println!("{}", user.name);
+        println!("  public key: {}", user.keypair.public_key());
+        println!("  secret key: {}", secret_key);
+        println!(
+            "  try:        curl http://{}/{}/pub/profile.json\n",
+            bind,
+            user.keypair.public_key()
+        );
+    }
+    println!(
+        "Admin password: {}\n",
+        admin_password.unwrap_or(dev::DEV_ADMIN_PASSWORD)
+    );
+}
+
+/// Parse a 64-digit hex string into a secret key
+fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
 }
@@ -0,0 +1,172 @@
+//! Memory usage metrics
+//!
+//! Reports the resident set size of the process, the bytes live on the heap
+//! when the [`CountingAllocator`] is the global allocator (`alloc-metrics`
+//! feature), and estimates of the bytes held by each part of the storage,
+//! so operators can spot leaks and size deployments. They are exported by
+//! `GET /metrics` and `GET /admin/metrics`.
+
+use serde_json::{json, Map, Value};
+use std::fmt::Write as _;
+
+use crate::storage::Storage;
+
+#[cfg(feature = "alloc-metrics")]
+pub use allocator::CountingAllocator;
+
+#[cfg(feature = "alloc-metrics")]
+mod allocator {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    pub(super) static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+    pub(super) static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+    /// The system allocator, counting the bytes it has live
+    ///
+    /// Install it in the binary to report allocated bytes:
+    ///
+    /// ```
+    /// #[global_allocator]
+    /// static ALLOCATOR: pubky_server::CountingAllocator = pubky_server::CountingAllocator;
+    /// ```
+    #[derive(Debug, Default)]
+    pub struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc_zeroed(layout);
+            if !ptr.is_null() {
+                ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            ALLOCATED.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new = System.realloc(ptr, layout, new_size);
+            if !new.is_null() {
+                ALLOCATED.fetch_add(new_size as u64, Ordering::Relaxed);
+                ALLOCATED.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+            }
+            new
+        }
+    }
+}
+
+/// Bytes live on the heap, if the [`CountingAllocator`] is the global
+/// allocator
+pub fn allocated_bytes() -> Option<u64> {
+    #[cfg(feature = "alloc-metrics")]
+    {
+        use std::sync::atomic::Ordering;
+        if allocator::ALLOCATIONS.load(Ordering::Relaxed) > 0 {
+            return Some(allocator::ALLOCATED.load(Ordering::Relaxed));
+        }
+    }
+    None
+}
+
+/// Resident set size of the process, where the OS reports it (Linux)
+pub fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Render the memory metrics in the Prometheus text exposition format
+pub(crate) fn render_prometheus(storage: &Storage, out: &mut String) {
+    let gauges = [
+        (
+            "pubky_memory_resident_bytes",
+            "Resident set size of the process",
+            resident_bytes(),
+        ),
+        (
+            "pubky_memory_allocated_bytes",
+            "Bytes live on the heap",
+            allocated_bytes(),
+        ),
+    ];
+    for (name, help, value) in gauges {
+        if let Some(value) = value {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+    }
+
+    let name = "pubky_memory_estimated_bytes";
+    let _ = writeln!(
+        out,
+        "# HELP {} Estimated bytes held by each subsystem",
+        name
+    );
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (subsystem, bytes) in storage.memory_estimates() {
+        let _ = writeln!(out, "{}{{subsystem=\"{}\"}} {}", name, subsystem, bytes);
+    }
+}
+
+/// Memory metrics as JSON, with estimates by subsystem
+pub(crate) fn to_json(storage: &Storage) -> Value {
+    let estimates: Map<String, Value> = storage
+        .memory_estimates()
+        .into_iter()
+        .map(|(subsystem, bytes)| (subsystem.to_string(), bytes.into()))
+        .collect();
+    json!({
+        "resident_bytes": resident_bytes(),
+        "allocated_bytes": allocated_bytes(),
+        "estimated_bytes": estimates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pubky_common::Keypair;
+
+    #[test]
+    fn test_memory_metrics() {
+        let storage = Storage::new();
+        let public_key = Keypair::random().public_key();
+        let estimate = |name| {
+            let estimates = storage.memory_estimates();
+            estimates.into_iter().find(|(n, _)| *n == name).unwrap().1
+        };
+        let entries = estimate("entries");
+        storage
+            .put(public_key, "big".to_string(), vec![0; 100_000])
+            .unwrap();
+        assert!(estimate("entries") >= entries + 100_000);
+        assert!(estimate("events") > 0);
+
+        let mut out = String::new();
+        render_prometheus(&storage, &mut out);
+        assert!(out.contains("pubky_memory_estimated_bytes{subsystem=\"entries\"}"));
+        if cfg!(target_os = "linux") {
+            assert!(resident_bytes().unwrap() > 0);
+            assert!(out.contains("pubky_memory_resident_bytes "));
+        }
+        let json = to_json(&storage);
+        assert!(json["estimated_bytes"]["sessions"].is_u64());
+        // Tests run on the system allocator
+        assert_eq!(json["allocated_bytes"], Value::Null);
+    }
+}
@@ -0,0 +1,234 @@
+//! Storage operation metrics
+//!
+//! Records per-operation latency histograms for the storage backend and
+//! exports them in the Prometheus text format and as JSON percentiles.
+
+use serde_json::{json, Map, Value};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the histogram buckets, in microseconds
+const BUCKETS_MICROS: [u64; 16] = [
+    1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 250_000, 1_000_000,
+];
+
+/// Storage operations with their own latency histogram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageOp {
+    Put,
+    Get,
+    Delete,
+    List,
+}
+
+impl StorageOp {
+    pub const ALL: [StorageOp; 4] = [
+        StorageOp::Put,
+        StorageOp::Get,
+        StorageOp::Delete,
+        StorageOp::List,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageOp::Put => "put",
+            StorageOp::Get => "get",
+            StorageOp::Delete => "delete",
+            StorageOp::List => "list",
+        }
+    }
+}
+
+/// Fixed-bucket latency histogram
+#[derive(Default)]
+pub struct Histogram {
+    /// Observation counts per bucket, plus a final overflow bucket
+    buckets: [AtomicU64; BUCKETS_MICROS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    /// Record a single observation
+    pub fn observe(&self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let index = BUCKETS_MICROS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(BUCKETS_MICROS.len());
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of recorded observations
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Estimate the given quantile (0.0..=1.0) as the upper bound of the
+    /// bucket containing it, or `None` if nothing was recorded
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((q * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                let micros = BUCKETS_MICROS
+                    .get(index)
+                    .copied()
+                    .unwrap_or(BUCKETS_MICROS[BUCKETS_MICROS.len() - 1]);
+                return Some(Duration::from_micros(micros));
+            }
+        }
+
+        None
+    }
+}
+
+/// Latency histograms for every operation of one storage backend
+pub struct StorageMetrics {
+    backend: &'static str,
+    ops: [Histogram; StorageOp::ALL.len()],
+}
+
+impl StorageMetrics {
+    /// Create empty metrics for the named backend
+    pub fn new(backend: &'static str) -> Self {
+        Self {
+            backend,
+            ops: Default::default(),
+        }
+    }
+
+    /// Name of the backend these metrics belong to
+    pub fn backend(&self) -> &'static str {
+        self.backend
+    }
+
+    /// Histogram of the given operation
+    pub fn histogram(&self, op: StorageOp) -> &Histogram {
+        &self.ops[op as usize]
+    }
+
+    /// Start timing an operation; the latency is recorded when the guard drops
+    pub fn time(&self, op: StorageOp) -> Timer<'_> {
+        Timer {
+            histogram: self.histogram(op),
+            start: Instant::now(),
+        }
+    }
+
+    /// Render the histograms in the Prometheus text exposition format
+    pub fn render_prometheus(&self, out: &mut String) {
+        let name = "pubky_storage_op_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Latency of storage operations", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        for op in StorageOp::ALL {
+            let histogram = self.histogram(op);
+            let labels = format!("backend=\"{}\",op=\"{}\"", self.backend, op.as_str());
+
+            let mut cumulative = 0;
+            for (bound, bucket) in BUCKETS_MICROS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = *bound as f64 / 1_000_000.0;
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name, labels, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"+Inf\"}} {}",
+                name,
+                labels,
+                histogram.count()
+            );
+
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count());
+        }
+    }
+
+    /// Per-operation counts and p50/p95/p99 latencies in microseconds
+    pub fn to_json(&self) -> Value {
+        let mut ops = Map::new();
+        for op in StorageOp::ALL {
+            let histogram = self.histogram(op);
+            let quantile = |q| histogram.quantile(q).map(|d| d.as_micros() as u64);
+            ops.insert(
+                op.as_str().to_string(),
+                json!({
+                    "count": histogram.count(),
+                    "p50_us": quantile(0.50),
+                    "p95_us": quantile(0.95),
+                    "p99_us": quantile(0.99),
+                }),
+            );
+        }
+
+        json!({
+            "backend": self.backend,
+            "ops": ops,
+        })
+    }
+}
+
+/// Guard recording the elapsed time of an operation when dropped
+pub struct Timer<'a> {
+    histogram: &'a Histogram,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.histogram.observe(self.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_quantiles() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for _ in 0..90 {
+            histogram.observe(Duration::from_micros(3));
+        }
+        for _ in 0..10 {
+            histogram.observe(Duration::from_millis(3));
+        }
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile(0.50), Some(Duration::from_micros(5)));
+        assert_eq!(histogram.quantile(0.95), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_prometheus_rendering() {
+        let metrics = StorageMetrics::new("memory");
+        drop(metrics.time(StorageOp::Get));
+
+        let mut out = String::new();
+        metrics.render_prometheus(&mut out);
+        assert!(out
+            .contains("pubky_storage_op_duration_seconds_count{backend=\"memory\",op=\"get\"} 1"));
+        assert!(out.contains(
+            "pubky_storage_op_duration_seconds_bucket{backend=\"memory\",op=\"put\",le=\"+Inf\"} 0"
+        ));
+    }
+}
@@ -0,0 +1,212 @@
+//! Account migration between homeservers
+//!
+//! A user signs a [`MigrationIntent`] naming the homeserver they leave and
+//! the one they move to, and posts it to the new one with
+//! `POST /migrations`. The new homeserver fetches the entry manifest from
+//! the old one with `POST /migrations/export`, copies every entry and checks
+//! it against the manifest's hash, then confirms with
+//! `POST /migrations/complete`. From then on the old homeserver redirects
+//! all requests for the account to the new one.
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::post,
+    Json, Router,
+};
+use pubky_common::auth::MigrationIntent;
+use pubky_common::dto::{MigrationEntry, MigrationManifest, MigrationReport};
+use pubky_common::PublicKey;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::routes::ApiError;
+use crate::storage::{EntryMeta, Storage};
+
+/// Create the migration routes
+pub(crate) fn migration_routes<S>(storage: Arc<Storage>) -> Router<S> {
+    Router::new()
+        .route("/", post(import))
+        .route("/export", post(export))
+        .route("/complete", post(complete))
+        .with_state(storage)
+}
+
+/// POST /migrations
+/// Copy an account from the homeserver it leaves, on the new homeserver
+async fn import(
+    State(storage): State<Arc<Storage>>,
+    Json(intent): Json<MigrationIntent>,
+) -> Result<(StatusCode, Json<MigrationReport>), ApiError> {
+    let public_key = verify(&storage, &intent)?;
+    let from = intent.from.trim_end_matches('/');
+    let http = reqwest::Client::new();
+    let unreachable = |e: reqwest::Error| {
+        ApiError::InternalError(format!("Failed to reach the old homeserver: {}", e))
+    };
+
+    let response = http
+        .post(format!("{}/migrations/export", from))
+        .json(&intent)
+        .send()
+        .await
+        .map_err(unreachable)?;
+    if !response.status().is_success() {
+        return Err(ApiError::BadRequest(format!(
+            "The old homeserver refused the export: {}",
+            response.status()
+        )));
+    }
+    let manifest: MigrationManifest = response.json().await.map_err(unreachable)?;
+
+    // Copy nothing unless every entry arrives intact
+    let mut entries = Vec::with_capacity(manifest.entries.len());
+    for entry in manifest.entries {
+        let url = format!("{}/{}/{}", from, public_key, entry.path);
+        let response = http.get(&url).send().await.map_err(unreachable)?;
+        let value = response.bytes().await.map_err(unreachable)?;
+        if value.len() as u64 != entry.size || sha256(&value) != entry.sha256 {
+            return Err(ApiError::InternalError(format!(
+                "Entry {} doesn't match the manifest",
+                entry.path
+            )));
+        }
+        let meta = EntryMeta {
+            content_type: entry.content_type,
+            custom: entry.meta,
+            tags: entry.tags.into_iter().collect(),
+            ..Default::default()
+        };
+        entries.push((entry.path, value.to_vec(), meta));
+    }
+
+    let report = MigrationReport {
+        entries: entries.len(),
+        bytes: entries.iter().map(|(_, value, _)| value.len() as u64).sum(),
+    };
+    storage.register(public_key);
+    for (path, value, meta) in entries {
+        storage.put_with_meta(public_key, path, value, meta)?;
+    }
+
+    let response = http
+        .post(format!("{}/migrations/complete", from))
+        .json(&intent)
+        .send()
+        .await
+        .map_err(unreachable)?;
+    if !response.status().is_success() {
+        tracing::warn!(
+            "{} didn't confirm the migration of {}: {}",
+            from,
+            public_key,
+            response.status()
+        );
+    }
+
+    tracing::info!(
+        "Migrated {} entries of {} from {}",
+        report.entries,
+        public_key,
+        from
+    );
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+/// POST /migrations/export
+/// List an account's entries with their hashes, for the homeserver it
+/// moves to
+async fn export(
+    State(storage): State<Arc<Storage>>,
+    Json(intent): Json<MigrationIntent>,
+) -> Result<Json<MigrationManifest>, ApiError> {
+    let public_key = verify(&storage, &intent)?;
+    if !storage.hosts(&public_key) || storage.moved_to(&public_key).is_some() {
+        return Err(ApiError::NotFound);
+    }
+
+    let mut paths = storage.list(&public_key, "");
+    paths.sort();
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let Some(value) = storage.get(&public_key, &path)? else {
+            continue;
+        };
+        let meta = storage.meta(&public_key, &path)?;
+        entries.push(MigrationEntry {
+            path,
+            size: value.len() as u64,
+            sha256: sha256(&value),
+            content_type: meta.content_type,
+            meta: meta.custom,
+            tags: meta.tags.into_iter().collect(),
+        });
+    }
+
+    Ok(Json(MigrationManifest {
+        public_key: public_key.to_z32(),
+        entries,
+    }))
+}
+
+/// POST /migrations/complete
+/// Redirect requests for an account to the homeserver it moved to
+async fn complete(
+    State(storage): State<Arc<Storage>>,
+    Json(intent): Json<MigrationIntent>,
+) -> Result<StatusCode, ApiError> {
+    let public_key = verify(&storage, &intent)?;
+    if !storage.hosts(&public_key) {
+        return Err(ApiError::NotFound);
+    }
+
+    let to = intent.to.trim_end_matches('/').to_string();
+    tracing::info!("{} moved to {}", public_key, to);
+    storage.set_moved(public_key, to);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Middleware redirecting requests for accounts that moved away
+pub(crate) async fn redirect_moved(
+    State(storage): State<Arc<Storage>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Nested routers see a stripped path, so use the original one
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.0.clone())
+        .unwrap_or_else(|| request.uri().clone());
+    let moved_to = uri
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .and_then(|z32| PublicKey::from_z32(z32).ok())
+        .and_then(|public_key| storage.moved_to(&public_key));
+
+    match moved_to {
+        Some(homeserver) => {
+            let uri = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+            Redirect::permanent(&format!("{}{}", homeserver, uri)).into_response()
+        }
+        None => next.run(request).await,
+    }
+}
+
+fn verify(storage: &Storage, intent: &MigrationIntent) -> Result<PublicKey, ApiError> {
+    intent
+        .verify_at(storage.now_millis())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid migration intent: {}", e)))
+}
+
+/// Hex-encoded SHA-256 of `data`
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -0,0 +1,322 @@
+//! Asynchronous replication to a mirror server
+//!
+//! The primary streams its mutation event log to a secondary server, which
+//! applies each batch to its own storage through the admin replication
+//! endpoint. The mirror starts with a full snapshot and falls back to one
+//! whenever it lags behind the retained event log. Puts carry the entry's
+//! [metadata](EntryMeta) with its value. Batches are sent as CBOR, which is
+//! smaller and faster to parse than JSON.
+
+use axum::http::header;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use pubky_common::PublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::admin::ADMIN_PASSWORD_HEADER;
+use crate::cbor;
+use crate::storage::{EntryMeta, Event, EventOp, Storage};
+
+/// Default number of events sent per batch
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// Delay before retrying after a failed batch
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Connection details for the secondary server
+#[derive(Debug, Clone)]
+pub struct MirrorConfig {
+    /// Base URL of the secondary, e.g. `http://10.0.0.2:3000`
+    pub url: String,
+    /// Admin password of the secondary
+    pub admin_password: String,
+    /// Maximum number of events per batch
+    pub batch_size: usize,
+}
+
+impl MirrorConfig {
+    /// Create a config for the given secondary with the default batch size
+    pub fn new(url: impl Into<String>, admin_password: impl Into<String>) -> Self {
+        Self {
+            url: url.into().trim_end_matches('/').to_string(),
+            admin_password: admin_password.into(),
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+}
+
+/// A batch of mutations sent to the secondary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationBatch {
+    /// Clear all data before applying the events (full snapshot)
+    #[serde(default)]
+    pub reset: bool,
+    pub events: Vec<ReplicationEvent>,
+}
+
+/// A single mutation in a [`ReplicationBatch`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationEvent {
+    pub seq: u64,
+    /// `"put"` or `"delete"`
+    pub op: String,
+    pub public_key: String,
+    pub path: String,
+    /// Base64-encoded value for puts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// Metadata of the value for puts
+    #[serde(default, skip_serializing_if = "EntryMeta::is_empty")]
+    pub meta: EntryMeta,
+}
+
+/// Replication progress shared with the admin API
+#[derive(Default)]
+pub(crate) struct ReplicationStatus {
+    /// Last sequence number applied from a primary (on a secondary)
+    pub applied_seq: AtomicU64,
+    /// Progress of the outgoing mirror (on a primary)
+    pub mirror: Option<MirrorStatus>,
+}
+
+/// Progress of the outgoing mirror stream
+#[derive(Default)]
+pub(crate) struct MirrorStatus {
+    url: String,
+    acked_seq: AtomicU64,
+    last_success: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl ReplicationStatus {
+    pub(crate) fn with_mirror(url: &str) -> Self {
+        Self {
+            applied_seq: AtomicU64::new(0),
+            mirror: Some(MirrorStatus {
+                url: url.to_string(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Replication status and lag metrics as JSON
+    pub(crate) fn to_json(&self, storage: &Storage) -> Value {
+        let head_seq = storage.head_seq();
+        let mirror = self.mirror.as_ref().map(|mirror| {
+            let acked_seq = mirror.acked_seq.load(Ordering::Relaxed);
+            let lag_ms = storage
+                .events_since(acked_seq, 1)
+                .and_then(|events| {
+                    events
+                        .first()
+                        .map(|e| storage.now_millis().saturating_sub(e.timestamp))
+                })
+                .unwrap_or(0);
+
+            json!({
+                "url": mirror.url,
+                "acked_seq": acked_seq,
+                "lag_events": head_seq.saturating_sub(acked_seq),
+                "lag_ms": lag_ms,
+                "last_success": mirror.last_success.load(Ordering::Relaxed),
+                "last_error": *mirror.last_error.lock().unwrap(),
+            })
+        });
+
+        json!({
+            "head_seq": head_seq,
+            "applied_seq": self.applied_seq.load(Ordering::Relaxed),
+            "mirror": mirror,
+        })
+    }
+}
+
+/// Apply a batch received from a primary, returning the last applied sequence
+pub(crate) fn apply_batch(storage: &Storage, batch: ReplicationBatch) -> Result<u64, String> {
+    if batch.reset {
+        storage.restore(Vec::new());
+    }
+
+    let mut last_seq = 0;
+    for event in batch.events {
+        let public_key = PublicKey::from_z32(&event.public_key).map_err(|e| e.to_string())?;
+        match (event.op.as_str(), event.value) {
+            ("put", Some(value)) => {
+                let value = BASE64.decode(value).map_err(|e| e.to_string())?;
+                storage
+                    .put_with_meta(public_key, event.path, value, event.meta)
+                    .map_err(|e| e.to_string())?;
+            }
+            ("delete", _) => {
+                storage
+                    .delete(&public_key, &event.path)
+                    .map_err(|e| e.to_string())?;
+            }
+            (op, _) => return Err(format!("Invalid replication event: {}", op)),
+        }
+        last_seq = event.seq;
+    }
+
+    Ok(last_seq)
+}
+
+/// Outgoing replication stream to a secondary server
+#[derive(Clone)]
+pub(crate) struct Mirror {
+    config: Arc<MirrorConfig>,
+    http: reqwest::Client,
+    pub status: Arc<ReplicationStatus>,
+}
+
+impl Mirror {
+    pub(crate) fn new(config: MirrorConfig) -> Self {
+        let status = Arc::new(ReplicationStatus::with_mirror(&config.url));
+        Self {
+            config: Arc::new(config),
+            http: reqwest::Client::new(),
+            status,
+        }
+    }
+
+    pub(crate) fn url(&self) -> &str {
+        &self.config.url
+    }
+
+    fn mirror_status(&self) -> &MirrorStatus {
+        self.status.mirror.as_ref().expect("mirror status")
+    }
+
+    /// Stream events to the secondary until the task is aborted
+    pub(crate) async fn run(self, storage: Arc<Storage>) {
+        let mut acked_seq = 0;
+        let mut needs_reset = true;
+
+        loop {
+            if !needs_reset {
+                storage.wait_for_events(acked_seq).await;
+            }
+
+            let batch = match storage.events_since(acked_seq, self.config.batch_size) {
+                Some(events) if !needs_reset => {
+                    let Some(last_seq) = events.last().map(|e| e.seq) else {
+                        continue;
+                    };
+                    self.events_batch(&storage, events)
+                        .map(|batch| (batch, last_seq))
+                }
+                _ => self.snapshot_batch(&storage),
+            };
+            let sent = match batch {
+                Ok((batch, last_seq)) => self.send(&batch).await.map(|()| last_seq),
+                Err(e) => Err(format!("reading the storage failed: {}", e)),
+            };
+
+            match sent {
+                Ok(last_seq) => {
+                    acked_seq = last_seq;
+                    needs_reset = false;
+                    let status = self.mirror_status();
+                    status.acked_seq.store(acked_seq, Ordering::Relaxed);
+                    status
+                        .last_success
+                        .store(storage.now_millis(), Ordering::Relaxed);
+                    *status.last_error.lock().unwrap() = None;
+                }
+                Err(e) => {
+                    tracing::warn!("Mirror replication to {} failed: {}", self.config.url, e);
+                    *self.mirror_status().last_error.lock().unwrap() = Some(e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// Build a batch from logged events, reading the current values
+    fn events_batch(&self, storage: &Storage, events: Vec<Event>) -> io::Result<ReplicationBatch> {
+        let mut batch = Vec::with_capacity(events.len());
+        for event in events {
+            let (value, meta) = match event.op {
+                EventOp::Put => {
+                    // Deleted since, which a later event carries
+                    let Some(value) = storage.get(&event.public_key, &event.path)? else {
+                        continue;
+                    };
+                    let meta = storage.meta(&event.public_key, &event.path)?;
+                    (Some(BASE64.encode(value)), meta)
+                }
+                EventOp::Delete => (None, EntryMeta::default()),
+            };
+            batch.push(ReplicationEvent {
+                seq: event.seq,
+                op: op_name(event.op).to_string(),
+                public_key: event.public_key.to_z32(),
+                path: event.path,
+                value,
+                meta,
+            });
+        }
+
+        Ok(ReplicationBatch {
+            reset: false,
+            events: batch,
+        })
+    }
+
+    /// Build a full snapshot batch of all current entries
+    ///
+    /// Returns the batch and the sequence number it is consistent with.
+    fn snapshot_batch(&self, storage: &Storage) -> io::Result<(ReplicationBatch, u64)> {
+        // Read the head first: events racing with the snapshot are re-sent
+        let head_seq = storage.head_seq();
+        let events = storage
+            .entries()
+            .into_iter()
+            .map(|(public_key, path, value)| {
+                Ok(ReplicationEvent {
+                    seq: head_seq,
+                    op: op_name(EventOp::Put).to_string(),
+                    public_key: public_key.to_z32(),
+                    meta: storage.meta(&public_key, &path)?,
+                    path,
+                    value: Some(BASE64.encode(value)),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let batch = ReplicationBatch {
+            reset: true,
+            events,
+        };
+        Ok((batch, head_seq))
+    }
+
+    async fn send(&self, batch: &ReplicationBatch) -> Result<(), String> {
+        let url = format!("{}/admin/replication", self.config.url);
+        let response = self
+            .http
+            .post(&url)
+            .header(ADMIN_PASSWORD_HEADER, &self.config.admin_password)
+            .header(header::CONTENT_TYPE, cbor::CBOR)
+            .body(cbor::to_vec(batch))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("secondary returned {}", response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+fn op_name(op: EventOp) -> &'static str {
+    match op {
+        EventOp::Put => "put",
+        EventOp::Delete => "delete",
+    }
+}
@@ -0,0 +1,325 @@
+//! Moderation of public content
+//!
+//! With [`ServerBuilder::moderation`](crate::ServerBuilder::moderation), a
+//! [`ModerationHook`] judges every HTTP write under `pub/`, and optionally
+//! every read of a public entry. It can accept the content, reject it, or
+//! quarantine it: quarantined values are held back from readers until an
+//! admin releases or discards them with the admin API.
+//!
+//! [`HashBlocklist`] is a ready-made hook matching content against a list of
+//! SHA-256 hashes; operators plug their own policy engines in by
+//! implementing the trait.
+//!
+//! Writes are read whole, up to the body limit, to be judged. Reads of
+//! entries larger than the body limit, such as resumable uploads, are
+//! served without a check: they were judged when written.
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Path, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use pubky_common::blob::sha256_hex;
+use pubky_common::PublicKey;
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+use crate::routes::{ensure_writable, read_body, ApiError};
+use crate::storage::Storage;
+
+/// What a [`ModerationHook`] decides about content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// Refuse the content, for the given reason
+    Reject(String),
+    /// Hold the content back from readers until an admin reviews it, for
+    /// the given reason
+    Quarantine(String),
+}
+
+/// Policy deciding which public content the server accepts and serves
+///
+/// Hooks run while the request waits, so they should answer quickly.
+/// Everything is accepted by default.
+pub trait ModerationHook: Send + Sync {
+    /// Judge `value`, about to be written at `path` of `public_key`
+    ///
+    /// Quarantined writes leave the current value of the entry in place.
+    fn check_write(&self, public_key: &PublicKey, path: &str, value: &[u8]) -> Verdict {
+        let _ = (public_key, path, value);
+        Verdict::Accept
+    }
+
+    /// Whether [`check_read`](Self::check_read) should be called
+    fn moderates_reads(&self) -> bool {
+        false
+    }
+
+    /// Judge `value`, about to be served from `path` of `public_key`
+    ///
+    /// Quarantined entries are taken down until an admin releases them.
+    fn check_read(&self, public_key: &PublicKey, path: &str, value: &[u8]) -> Verdict {
+        let _ = (public_key, path, value);
+        Verdict::Accept
+    }
+}
+
+/// A hook accepting everything
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoModeration;
+
+impl ModerationHook for NoModeration {}
+
+/// A hook refusing content whose SHA-256 is listed, such as known abusive
+/// files, on writes and on reads
+///
+/// Hashes can be added while the server runs, which takes down entries
+/// stored before they were listed on their next read.
+#[derive(Debug, Default)]
+pub struct HashBlocklist {
+    hashes: RwLock<HashSet<String>>,
+    quarantine: bool,
+}
+
+impl HashBlocklist {
+    /// Refuse content with any of the given hex-encoded SHA-256 hashes
+    pub fn new<S: AsRef<str>>(hashes: impl IntoIterator<Item = S>) -> Self {
+        let hashes = hashes
+            .into_iter()
+            .map(|hash| hash.as_ref().trim().to_ascii_lowercase())
+            .collect();
+        Self {
+            hashes: RwLock::new(hashes),
+            quarantine: false,
+        }
+    }
+
+    /// Read a list of one hash per line, skipping blank lines and `#`
+    /// comments
+    pub fn parse(list: &str) -> Self {
+        Self::new(
+            list.lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty()),
+        )
+    }
+
+    /// Quarantine listed content for review instead of rejecting it
+    pub fn quarantine(mut self, enabled: bool) -> Self {
+        self.quarantine = enabled;
+        self
+    }
+
+    /// List another hex-encoded SHA-256 hash
+    pub fn insert(&self, hash: &str) {
+        let hash = hash.trim().to_ascii_lowercase();
+        self.hashes.write().unwrap().insert(hash);
+    }
+
+    fn verdict(&self, value: &[u8]) -> Verdict {
+        if !self.hashes.read().unwrap().contains(&sha256_hex(value)) {
+            return Verdict::Accept;
+        }
+        let reason = "Content is blocklisted".to_string();
+        match self.quarantine {
+            true => Verdict::Quarantine(reason),
+            false => Verdict::Reject(reason),
+        }
+    }
+}
+
+impl ModerationHook for HashBlocklist {
+    fn check_write(&self, _: &PublicKey, _: &str, value: &[u8]) -> Verdict {
+        self.verdict(value)
+    }
+
+    fn moderates_reads(&self) -> bool {
+        true
+    }
+
+    fn check_read(&self, _: &PublicKey, _: &str, value: &[u8]) -> Verdict {
+        self.verdict(value)
+    }
+}
+
+/// State of the moderation middleware: the hook, storage and the largest
+/// entry checked on reads, in bytes
+pub(crate) type Moderation = (Arc<dyn ModerationHook>, Arc<Storage>, usize);
+
+/// Middleware running the moderation hook on public writes and reads
+pub(crate) async fn moderate(
+    State((hook, storage, read_limit)): State<Moderation>,
+    Path((public_key, path)): Path<(String, String)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let public_key = PublicKey::from_z32(&public_key);
+    let public_key = match public_key {
+        Ok(public_key) if path.starts_with("pub/") && !path.ends_with('/') => public_key,
+        _ => return Ok(next.run(request).await),
+    };
+
+    match *request.method() {
+        Method::PUT => {
+            let (parts, body) = read_body(request).await?;
+            match hook.check_write(&public_key, &path, &body) {
+                Verdict::Accept => {
+                    let request = Request::from_parts(parts, Body::from(body));
+                    Ok(next.run(request).await)
+                }
+                Verdict::Reject(reason) => Err(ApiError::Rejected(reason)),
+                Verdict::Quarantine(reason) => {
+                    ensure_writable(&storage, &public_key)?;
+                    tracing::info!("Quarantined a write to {}/{}: {}", public_key, path, reason);
+                    storage.quarantine(public_key, path, body.to_vec(), reason);
+                    Ok(StatusCode::ACCEPTED.into_response())
+                }
+            }
+        }
+        Method::GET | Method::HEAD if hook.moderates_reads() => {
+            let response = next.run(request).await;
+            if response.status() != StatusCode::OK {
+                return Ok(response);
+            }
+            let length = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+            if length.is_some_and(|length| length > read_limit) {
+                return Ok(response);
+            }
+            let (parts, body) = response.into_parts();
+            let body = to_bytes(body, read_limit)
+                .await
+                .map_err(|e| ApiError::InternalError(e.to_string()))?;
+            match hook.check_read(&public_key, &path, &body) {
+                Verdict::Accept => Ok(Response::from_parts(parts, Body::from(body))),
+                Verdict::Reject(reason) => Err(ApiError::Rejected(reason)),
+                Verdict::Quarantine(reason) => {
+                    // Generated entries, such as feeds, have nothing to take down
+                    if let Some(value) = storage.get(&public_key, &path)? {
+                        tracing::info!("Took down {}/{}: {}", public_key, path, reason);
+                        storage.quarantine(public_key, path.clone(), value, reason);
+                        storage.delete(&public_key, &path)?;
+                    }
+                    Err(ApiError::NotFound)
+                }
+            }
+        }
+        _ => Ok(next.run(request).await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_auth::SignWrite;
+    use crate::{Server, ADMIN_PASSWORD_HEADER};
+    use pubky_common::Keypair;
+
+    #[tokio::test]
+    async fn test_moderation() {
+        let list = format!("# Known bad\n{}\n\n", sha256_hex(b"bad"));
+        let blocklist = Arc::new(HashBlocklist::parse(&list));
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .admin_password("secret")
+            .moderation(blocklist.clone())
+            .start()
+            .await
+            .unwrap();
+        let quarantining = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .admin_password("secret")
+            .moderation(Arc::new(HashBlocklist::parse(&list).quarantine(true)))
+            .start()
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let put = |server: &Server, path: &str, body: &'static str| {
+            let url = format!("{}/{}/{}", server.url(), public_key, path);
+            http.put(url)
+                .body(body)
+                .signed(&keypair, &server.identity())
+                .send()
+        };
+        let get = |server: &Server, path: &str| {
+            let url = format!("{}/{}/{}", server.url(), public_key, path);
+            http.get(url).send()
+        };
+
+        // Listed content is rejected, unless private
+        let response = put(&server, "pub/a.txt", "bad").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        assert_eq!(
+            put(&server, "pub/a.txt", "good").await.unwrap().status(),
+            201
+        );
+        assert_eq!(
+            put(&server, "private/a.txt", "bad").await.unwrap().status(),
+            201
+        );
+
+        // Content listed later is taken down when read
+        assert_eq!(
+            put(&server, "pub/b.txt", "worse").await.unwrap().status(),
+            201
+        );
+        assert_eq!(get(&server, "pub/b.txt").await.unwrap().status(), 200);
+        blocklist.insert(&sha256_hex(b"worse"));
+        let response = get(&server, "pub/b.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+
+        // Or held for review, and released by an admin
+        assert_eq!(
+            put(&quarantining, "pub/c.txt", "fine")
+                .await
+                .unwrap()
+                .status(),
+            201
+        );
+        let response = put(&quarantining, "pub/c.txt", "bad").await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            get(&quarantining, "pub/c.txt")
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap(),
+            "fine"
+        );
+        let admin = |method, path: &str| {
+            let url = format!("{}/admin/quarantine{}", quarantining.url(), path);
+            http.request(method, url)
+                .header(ADMIN_PASSWORD_HEADER, "secret")
+                .send()
+        };
+        let listed: serde_json::Value = admin(Method::GET, "").await.unwrap().json().await.unwrap();
+        assert_eq!(listed["count"], 1);
+        assert_eq!(listed["entries"][0]["path"], "pub/c.txt");
+        assert_eq!(listed["entries"][0]["reason"], "Content is blocklisted");
+        let entry = format!("/{}/pub/c.txt", public_key);
+        assert_eq!(admin(Method::POST, &entry).await.unwrap().status(), 204);
+        assert_eq!(admin(Method::POST, &entry).await.unwrap().status(), 404);
+        let stored = quarantining
+            .storage()
+            .get(&public_key, "pub/c.txt")
+            .unwrap();
+        assert_eq!(stored.as_deref(), Some(&b"bad"[..]));
+
+        // Released content is quarantined again on its next read
+        assert_eq!(get(&quarantining, "pub/c.txt").await.unwrap().status(), 404);
+        assert_eq!(quarantining.storage().quarantined().len(), 1);
+        assert_eq!(admin(Method::DELETE, &entry).await.unwrap().status(), 204);
+        assert!(quarantining.storage().quarantined().is_empty());
+
+        quarantining.shutdown().await;
+        server.shutdown().await;
+    }
+}
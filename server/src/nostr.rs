@@ -0,0 +1,218 @@
+//! Nostr HTTP authentication routes
+//!
+//! Nostr users sign in with their existing secp256k1 identity instead of
+//! the account's Ed25519 key. A session with root capabilities first links
+//! the identity by posting a [`NostrEvent`] signed for `POST /nostr/link`.
+//! From then on, `POST /nostr/session` with an `Authorization: Nostr
+//! <base64 event>` header, as described by NIP-98, starts a session for
+//! that account with the capabilities of the session that linked it.
+
+use axum::{
+    body::Bytes,
+    extract::{OriginalUri, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use pubky_common::nostr::NostrEvent;
+use std::sync::Arc;
+
+use crate::routes::ApiError;
+use crate::session::{authenticate_root, start_session};
+use crate::storage::Storage;
+
+/// Create the Nostr routes
+pub(crate) fn nostr_routes<S>(storage: Arc<Storage>) -> Router<S> {
+    Router::new()
+        .route("/link", post(link))
+        .route("/link/{nostr_key}", delete(unlink))
+        .route("/session", post(signin))
+        .with_state(storage)
+}
+
+/// POST /nostr/link
+/// Link the Nostr identity that signed the event to the current account
+async fn link(
+    State(storage): State<Arc<Storage>>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    Json(event): Json<NostrEvent>,
+) -> Result<StatusCode, ApiError> {
+    let (_, session) = authenticate_root(&storage, &headers)?;
+    let nostr_key = verify(&event, &headers, uri.path(), "POST", b"")?;
+    storage.link_nostr(
+        nostr_key.to_string(),
+        session.public_key,
+        session.capabilities,
+    );
+    tracing::info!(
+        "Linked Nostr identity {} to {}",
+        nostr_key,
+        session.public_key
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE /nostr/link/{nostr_key}
+/// Unlink a Nostr identity from the current account
+async fn unlink(
+    State(storage): State<Arc<Storage>>,
+    Path(nostr_key): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let (_, session) = authenticate_root(&storage, &headers)?;
+    match storage.unlink_nostr(&nostr_key, &session.public_key) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::NotFound),
+    }
+}
+
+/// POST /nostr/session
+/// Sign in to the account a Nostr identity is linked to
+async fn signin(
+    State(storage): State<Arc<Storage>>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let event = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Nostr "))
+        .and_then(|value| BASE64.decode(value.trim()).ok())
+        .and_then(|json| serde_json::from_slice::<NostrEvent>(&json).ok())
+        .ok_or(ApiError::Unauthorized)?;
+    let nostr_key = verify(&event, &headers, uri.path(), "POST", &body)?;
+    let link = storage
+        .nostr_link(nostr_key)
+        .ok_or(ApiError::Unauthorized)?;
+
+    let session = start_session(&storage, link.public_key, &link.capabilities, &headers);
+    Ok(session.into_response())
+}
+
+/// Verify that the event authenticates this request, returning the Nostr
+/// public key that signed it
+///
+/// The URL is rebuilt from the `Host` header, so that it matches the one
+/// the client signed even when the server is reached through a proxy.
+fn verify<'a>(
+    event: &'a NostrEvent,
+    headers: &HeaderMap,
+    path: &str,
+    method: &str,
+    body: &[u8],
+) -> Result<&'a str, ApiError> {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| ApiError::BadRequest("Missing Host header".to_string()))?;
+    event
+        .verify_http_auth(&format!("{}{}", host, path), method, body)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid Nostr event: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use pubky_common::dto::SessionInfo;
+    use pubky_common::nostr::NostrKeypair;
+    use pubky_common::Keypair;
+    use tower::ServiceExt;
+
+    use crate::session::ROOT_CAPABILITIES;
+
+    async fn call(router: &Router, request: Request<Body>) -> (StatusCode, Option<SessionInfo>) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).ok())
+    }
+
+    fn signin_request(event: &NostrEvent) -> Request<Body> {
+        let event = BASE64.encode(serde_json::to_vec(event).unwrap());
+        Request::post("/nostr/session")
+            .header("host", "example.com")
+            .header("authorization", format!("Nostr {}", event))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_nostr_signin() {
+        let storage = Arc::new(Storage::new());
+        let router = Router::new().nest("/nostr", nostr_routes(storage.clone()));
+        let account = Keypair::random().public_key();
+        let nostr = NostrKeypair::random();
+        let url = "https://example.com/nostr/session";
+
+        // Unlinked identities can't sign in
+        let event = NostrEvent::http_auth(&nostr, url, "POST", None);
+        let (status, _) = call(&router, signin_request(&event)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Linking needs a session and an event signed for the link route
+        let link = NostrEvent::http_auth(&nostr, "https://example.com/nostr/link", "POST", None);
+        let link_request = |event: &NostrEvent, token: &str| {
+            Request::post("/nostr/link")
+                .header("host", "example.com")
+                .header("authorization", format!("Bearer {}", token))
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(event).unwrap()))
+                .unwrap()
+        };
+        let (status, _) = call(&router, link_request(&link, "unknown")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let session = |id: &str, capabilities: &str| crate::storage::Session {
+            id: id.to_string(),
+            public_key: account,
+            device: None,
+            capabilities: capabilities.to_string(),
+            created_at: 0,
+            last_used_at: 0,
+            expires_at: u64::MAX,
+        };
+
+        // App sessions can't link identities, which would sign in as root
+        storage.insert_session("app".to_string(), session("app", "/pub/my-app/:rw"));
+        let (status, _) = call(&router, link_request(&link, "app")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(storage.nostr_link(&nostr.public_key()).is_none());
+
+        let token = "token".to_string();
+        storage.insert_session(token.clone(), session("id", ROOT_CAPABILITIES));
+        let (status, _) = call(&router, link_request(&event, &token)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&router, link_request(&link, &token)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        // The linked identity signs in to the account
+        let (status, info) = call(&router, signin_request(&event)).await;
+        assert_eq!(status, StatusCode::OK);
+        let info = info.unwrap();
+        assert_eq!(info.public_key, account.to_z32());
+        let signed_in = storage.session(&info.token).unwrap();
+        assert_eq!(signed_in.capabilities, ROOT_CAPABILITIES);
+        let other = NostrEvent::http_auth(&nostr, "https://example.com/other", "POST", None);
+        let (status, _) = call(&router, signin_request(&other)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let unlink = |token: &str| {
+            Request::delete(format!("/nostr/link/{}", nostr.public_key()))
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (status, _) = call(&router, unlink("app")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(&router, unlink(&token)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&router, signin_request(&event)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
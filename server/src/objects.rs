@@ -0,0 +1,631 @@
+//! Storage backend on an object store
+//!
+//! An [`ObjectBackend`] keeps every entry as an object in an S3 or GCS
+//! bucket, or any other [`ObjectStore`], so the server itself holds no
+//! entries and can be replaced or scaled out freely. The entry at
+//! `pub/posts/1.md` of a user is the object
+//! `{prefix}/{public_key}/pub/posts/1.md`, with path segments encoded as by
+//! the [`DiskBackend`](crate::DiskBackend), and its
+//! [metadata](crate::EntryMeta) the JSON object
+//! `{prefix}/{public_key}/pub/posts/.1.md.meta` next to it. The account
+//! state of the storage is the object `{prefix}/.state.json`.
+//!
+//! Stores are opened from a URL such as `s3://bucket/prefix` or
+//! `gs://bucket/prefix`, with credentials and other settings read from the
+//! usual `AWS_*` and `GOOGLE_*` environment variables or given as options:
+//!
+//! ```no_run
+//! use pubky_server::{ObjectBackend, Server};
+//! use std::sync::Arc;
+//!
+//! let backend = ObjectBackend::open(
+//!     "s3://my-bucket/homeserver",
+//!     [("aws_region", "eu-central-1")],
+//! )?;
+//! let router = Server::builder().backend(Arc::new(backend)).router();
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Values are uploaded to a hidden object next to the one they replace
+//! before they are stored, which only copies them within the store. Large
+//! values are sent and fetched as a stream of parts, uploaded
+//! concurrently, instead of in a single request. Uploads are sent part by
+//! part as they arrive, holding no more than a few parts in memory, while
+//! reads hold values whole, except for ranges of them, which are fetched
+//! alone. Requests run on a runtime of the backend's own while the calling
+//! thread waits for them, handing its other tasks over to the rest of the
+//! runtime when it is a worker of a multi-threaded one. Available with the
+//! `object-store` feature.
+
+use futures_util::TryStreamExt;
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload, WriteMultipart};
+use pubky_common::PublicKey;
+use std::future::Future;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
+
+use crate::backend::{Commit, StorageBackend};
+use crate::disk::{decode, encode};
+use crate::storage::EntryMeta;
+
+/// Values larger than this are uploaded in parts of this size
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Parts of a value uploaded at the same time
+const CONCURRENT_PARTS: usize = 4;
+
+/// Name of the object holding the account state, under the prefix
+const STATE_OBJECT: &str = ".state.json";
+
+/// Entries as objects in a bucket
+pub struct ObjectBackend {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+    /// Taken on drop, to shut down without blocking
+    runtime: Option<Runtime>,
+}
+
+impl ObjectBackend {
+    /// Keep entries in the store at `url`, under the path it ends with
+    ///
+    /// `options` are settings of the store, such as `aws_access_key_id` or
+    /// `google_service_account`, taking precedence over the environment.
+    /// Unknown options are ignored.
+    pub fn open<K, V>(url: &str, options: impl IntoIterator<Item = (K, V)>) -> io::Result<Self>
+    where
+        K: AsRef<str>,
+        V: Into<String>,
+    {
+        let url = reqwest::Url::parse(url).map_err(|e| invalid_input(e.to_string()))?;
+        let mut settings: Vec<(String, String)> = std::env::vars()
+            .filter(|(key, _)| key.starts_with("AWS_") || key.starts_with("GOOGLE_"))
+            .map(|(key, value)| (key.to_ascii_lowercase(), value))
+            .collect();
+        settings.extend(
+            options
+                .into_iter()
+                .map(|(key, value)| (key.as_ref().to_string(), value.into())),
+        );
+        let (store, prefix) = object_store::parse_url_opts(&url, settings)
+            .map_err(|e| invalid_input(e.to_string()))?;
+        Self::new(Arc::from(store), prefix)
+    }
+
+    /// Keep entries in `store`, under `prefix`
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: Path) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("pubky-object-store")
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            store,
+            prefix,
+            runtime: Some(runtime),
+        })
+    }
+
+    /// Run a request of the store to completion
+    fn run<T, F>(&self, request: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: Future<Output = object_store::Result<T>> + Send + 'static,
+    {
+        let runtime = self.runtime.as_ref().expect("runtime until dropped");
+        let (tx, rx) = std::sync::mpsc::channel();
+        runtime.spawn(async move {
+            let _ = tx.send(request.await);
+        });
+        // Waiting stalls every task of a worker thread unless they are moved
+        // off it, which only a multi-threaded runtime can do
+        let wait = || rx.recv();
+        let result = match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(wait)
+            }
+            _ => wait(),
+        };
+        result
+            .map_err(|_| io::Error::other("object store runtime stopped"))?
+            .map_err(into_io)
+    }
+
+    /// Prefix of the objects of `public_key`, and of those under `{dirs}/`
+    fn dir_of(&self, public_key: &PublicKey, dirs: Option<&str>) -> Path {
+        let dirs = dirs.into_iter().flat_map(|dirs| dirs.split('/'));
+        self.under(public_key, dirs)
+    }
+
+    /// Object holding the entry at `path`
+    fn object(&self, public_key: &PublicKey, path: &str) -> Path {
+        self.under(public_key, path.split('/'))
+    }
+
+    /// Hidden object holding the metadata of the entry at `path`
+    fn meta_object(&self, public_key: &PublicKey, path: &str) -> Path {
+        self.hidden(public_key, path, "meta")
+    }
+
+    /// Hidden object next to the entry at `path`, named after it with
+    /// `suffix`
+    fn hidden(&self, public_key: &PublicKey, path: &str, suffix: &str) -> Path {
+        let (dirs, name) = match path.rsplit_once('/') {
+            Some((dirs, name)) => (Some(dirs), name),
+            None => (None, path),
+        };
+        let dir = self.dir_of(public_key, dirs);
+        let hidden = format!("{}/.{}.{}", dir, encode(name), suffix);
+        Path::parse(hidden).expect("encoded segments are valid")
+    }
+
+    /// Path of `segments` under the prefix of `public_key`
+    fn under<'a>(&self, public_key: &PublicKey, segments: impl Iterator<Item = &'a str>) -> Path {
+        // Encoded segments are valid as they are, and must not be escaped
+        // again
+        let parts = self.prefix.parts().map(|part| part.as_ref().to_string());
+        let parts: Vec<String> = parts
+            .chain([public_key.to_z32()])
+            .chain(segments.map(encode))
+            .collect();
+        Path::parse(parts.join("/")).expect("encoded segments are valid")
+    }
+
+    /// Every object under `dir`, with the path of its entry relative to it
+    fn objects_under(&self, dir: &Path) -> io::Result<Vec<(Path, String, u64)>> {
+        let (store, prefix) = (self.store.clone(), dir.clone());
+        let objects = self.run(async move {
+            let list = store
+                .list(Some(&prefix))
+                .map_ok(|meta| (meta.location, meta.size));
+            list.try_collect::<Vec<_>>().await
+        })?;
+        Ok(objects
+            .into_iter()
+            // Metadata objects are hidden, and nothing else is
+            .filter(|(object, _)| !object.filename().is_some_and(|name| name.starts_with('.')))
+            .filter_map(|(object, size)| {
+                let segments: Option<Vec<String>> = object
+                    .prefix_match(dir)?
+                    .map(|part| decode(part.as_ref()))
+                    .collect();
+                Some((object.clone(), segments?.join("/"), size))
+            })
+            .collect())
+    }
+
+    /// Upload the value read from `reader` to `object`, in parts if it is
+    /// larger than one
+    fn upload(&self, object: Path, reader: &mut dyn Read) -> io::Result<()> {
+        let mut part = read_part(reader)?;
+        let store = self.store.clone();
+        if part.len() < PART_SIZE {
+            return self.run(async move {
+                store.put(&object, PutPayload::from(part)).await?;
+                Ok(())
+            });
+        }
+        let upload = self.run(async move { store.put_multipart(&object).await })?;
+        let mut upload = Upload {
+            backend: self,
+            parts: Some(WriteMultipart::new_with_chunk_size(upload, PART_SIZE)),
+        };
+        while !part.is_empty() {
+            upload.write(part)?;
+            part = read_part(reader)?;
+        }
+        upload.finish()
+    }
+
+    /// The value of `object`, if it exists
+    fn fetch(&self, object: Path) -> io::Result<Option<Vec<u8>>> {
+        let store = self.store.clone();
+        let fetched = self.run(async move {
+            let result = store.get(&object).await?;
+            let mut value = Vec::with_capacity(result.meta.size as usize);
+            let mut stream = result.into_stream();
+            while let Some(chunk) = stream.try_next().await? {
+                value.extend_from_slice(&chunk);
+            }
+            Ok(value)
+        });
+        match fetched {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A multipart upload of a value, aborted unless finished
+struct Upload<'a> {
+    backend: &'a ObjectBackend,
+    /// Taken while writing and once finished
+    parts: Option<WriteMultipart>,
+}
+
+impl Upload<'_> {
+    /// Upload `part` once fewer than [`CONCURRENT_PARTS`] are in flight
+    fn write(&mut self, part: Vec<u8>) -> io::Result<()> {
+        let mut parts = self.parts.take().expect("unfinished upload");
+        let (parts, written) = self.backend.run(async move {
+            let written = parts.wait_for_capacity(CONCURRENT_PARTS).await;
+            if written.is_ok() {
+                parts.write(&part);
+            }
+            Ok((parts, written))
+        })?;
+        self.parts = Some(parts);
+        written.map_err(into_io)
+    }
+
+    /// Upload the last part and complete the object
+    fn finish(mut self) -> io::Result<()> {
+        let parts = self.parts.take().expect("unfinished upload");
+        self.backend
+            .run(async move { parts.finish().await.map(drop) })
+    }
+}
+
+impl Drop for Upload<'_> {
+    fn drop(&mut self) {
+        if let Some(parts) = self.parts.take() {
+            let _ = self.backend.run(async move { parts.abort().await });
+        }
+    }
+}
+
+/// A value uploaded to a hidden object next to the one it replaces, removed
+/// once copied into place or dropped
+struct Staged<'a> {
+    backend: &'a ObjectBackend,
+    object: Path,
+    staged: Path,
+}
+
+impl Staged<'_> {
+    /// Copy the value into place, within the store
+    fn commit(self) -> io::Result<()> {
+        let store = self.backend.store.clone();
+        let (staged, object) = (self.staged.clone(), self.object.clone());
+        self.backend
+            .run(async move { store.copy(&staged, &object).await })
+    }
+}
+
+impl Drop for Staged<'_> {
+    fn drop(&mut self) {
+        // Nothing waits for the removal, which leaves a hidden object
+        // behind at worst
+        let (store, staged) = (self.backend.store.clone(), self.staged.clone());
+        if let Some(runtime) = &self.backend.runtime {
+            runtime.spawn(async move { store.delete(&staged).await });
+        }
+    }
+}
+
+/// Read up to [`PART_SIZE`] bytes, fewer only at the end of `reader`
+fn read_part(reader: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let mut part = Vec::new();
+    reader.take(PART_SIZE as u64).read_to_end(&mut part)?;
+    Ok(part)
+}
+
+impl StorageBackend for ObjectBackend {
+    fn name(&self) -> &'static str {
+        "object_store"
+    }
+
+    fn put(&self, public_key: PublicKey, path: String, value: Vec<u8>) -> io::Result<()> {
+        self.upload(self.object(&public_key, &path), &mut value.as_slice())
+    }
+
+    fn stage<'a>(
+        &'a self,
+        public_key: PublicKey,
+        path: String,
+        reader: &mut dyn Read,
+    ) -> io::Result<Commit<'a>> {
+        // Values are uploaded whole before the commit, which only copies
+        // them within the store
+        let staged = Staged {
+            backend: self,
+            object: self.object(&public_key, &path),
+            staged: self.hidden(
+                &public_key,
+                &path,
+                &format!("{:016x}.staged", rand::random::<u64>()),
+            ),
+        };
+        self.upload(staged.staged.clone(), reader)?;
+        Ok(Box::new(move || staged.commit()))
+    }
+
+    fn stage_value<'a>(
+        &'a self,
+        public_key: PublicKey,
+        path: String,
+        value: Vec<u8>,
+    ) -> io::Result<Commit<'a>> {
+        self.stage(public_key, path, &mut value.as_slice())
+    }
+
+    fn get(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<Vec<u8>>> {
+        self.fetch(self.object(public_key, path))
+    }
+
+    fn size(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<u64>> {
+        let (store, object) = (self.store.clone(), self.object(public_key, path));
+        match self.run(async move { store.head(&object).await }) {
+            Ok(meta) => Ok(Some(meta.size)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn modified(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<u64>> {
+        let (store, object) = (self.store.clone(), self.object(public_key, path));
+        match self.run(async move { store.head(&object).await }) {
+            Ok(meta) => Ok(Some(meta.last_modified.timestamp_millis().max(0) as u64)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_range(
+        &self,
+        public_key: &PublicKey,
+        path: &str,
+        range: Range<u64>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        // Stores reject ranges past the end, so clamp to the size first
+        let (store, object) = (self.store.clone(), self.object(public_key, path));
+        let fetched = self.run(async move {
+            let size = store.head(&object).await?.size;
+            let end = range.end.min(size);
+            match range.start < end {
+                true => Ok(store.get_range(&object, range.start..end).await?.to_vec()),
+                false => Ok(Vec::new()),
+            }
+        });
+        match fetched {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put_meta(&self, public_key: PublicKey, path: String, meta: EntryMeta) -> io::Result<()> {
+        let (store, object) = (self.store.clone(), self.meta_object(&public_key, &path));
+        if meta.is_empty() {
+            return self.run(async move {
+                match store.delete(&object).await {
+                    Err(object_store::Error::NotFound { .. }) => Ok(()),
+                    result => result,
+                }
+            });
+        }
+        let json = serde_json::to_vec(&meta)?;
+        self.run(async move {
+            store.put(&object, PutPayload::from(json)).await?;
+            Ok(())
+        })
+    }
+
+    fn meta(&self, public_key: &PublicKey, path: &str) -> io::Result<EntryMeta> {
+        match self.fetch(self.meta_object(public_key, path))? {
+            Some(json) => Ok(serde_json::from_slice(&json)?),
+            None => Ok(EntryMeta::default()),
+        }
+    }
+
+    fn delete(&self, public_key: &PublicKey, path: &str) -> io::Result<bool> {
+        // Deleting a missing object succeeds in most stores, so look first
+        let (store, object) = (self.store.clone(), self.object(public_key, path));
+        let meta = self.meta_object(public_key, path);
+        self.run(async move {
+            match store.delete(&meta).await {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => return Err(e),
+            }
+            match store.head(&object).await {
+                Ok(_) => store.delete(&object).await.map(|_| true),
+                Err(object_store::Error::NotFound { .. }) => Ok(false),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn list(&self, public_key: &PublicKey, prefix: &str) -> io::Result<Vec<String>> {
+        // Matches are all under the prefix's complete segments
+        let (dirs, base) = match prefix.rsplit_once('/') {
+            Some((dirs, _)) => (Some(dirs), &prefix[..dirs.len() + 1]),
+            None => (None, ""),
+        };
+        let objects = self.objects_under(&self.dir_of(public_key, dirs))?;
+        Ok(objects
+            .into_iter()
+            .map(|(_, path, _)| format!("{}{}", base, path))
+            .filter(|path| path.starts_with(prefix))
+            .collect())
+    }
+
+    fn keeps_state(&self) -> bool {
+        true
+    }
+
+    fn load_state(&self) -> io::Result<Option<Vec<u8>>> {
+        self.fetch(self.prefix.child(STATE_OBJECT))
+    }
+
+    fn save_state(&self, state: &[u8]) -> io::Result<()> {
+        let (store, object) = (self.store.clone(), self.prefix.child(STATE_OBJECT));
+        let state = PutPayload::from(state.to_vec());
+        self.run(async move {
+            store.put(&object, state).await?;
+            Ok(())
+        })
+    }
+
+    fn for_each(&self, visit: &mut dyn FnMut(&PublicKey, &str, &[u8])) -> io::Result<()> {
+        for (object, path, _) in self.objects_under(&self.prefix)? {
+            let Some((user, path)) = path.split_once('/') else {
+                continue;
+            };
+            let Ok(public_key) = PublicKey::from_z32(user) else {
+                continue;
+            };
+            // Deleted since it was listed
+            let Some(value) = self.fetch(object)? else {
+                continue;
+            };
+            visit(&public_key, path, &value);
+        }
+        Ok(())
+    }
+
+    fn for_each_size(&self, visit: &mut dyn FnMut(&PublicKey, &str, u64)) -> io::Result<()> {
+        // Sizes come with the listing, without fetching any value
+        for (_, path, size) in self.objects_under(&self.prefix)? {
+            let Some((user, path)) = path.split_once('/') else {
+                continue;
+            };
+            let Ok(public_key) = PublicKey::from_z32(user) else {
+                continue;
+            };
+            visit(&public_key, path, size);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ObjectBackend {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+fn into_io(e: object_store::Error) -> io::Error {
+    match e {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, e),
+        e => io::Error::other(e),
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::Storage;
+    use object_store::memory::InMemory;
+    use pubky_common::Keypair;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_object_backend() {
+        let store = Arc::new(InMemory::new());
+        let backend = ObjectBackend::new(store.clone(), Path::from("home")).unwrap();
+        let public_key = Keypair::random().public_key();
+
+        // Objects are laid out by path, even for paths a store rejects
+        let paths = ["pub/a", "pub/a/b", "pub/ü x.txt", "../etc", "a//b/"];
+        for path in paths {
+            let value = path.as_bytes().to_vec();
+            backend.put(public_key, path.to_string(), value).unwrap();
+        }
+        let object = |path: &str| {
+            let store = store.clone();
+            let object = Path::parse(format!("home/{}/{}", public_key, path)).unwrap();
+            async move { store.get(&object).await.unwrap().bytes().await.unwrap() }
+        };
+        assert_eq!(object("pub/a/b").await, "pub/a/b");
+        assert_eq!(object("%2E./etc").await, "../etc");
+        assert_eq!(object("a/%/b/%").await, "a//b/");
+        for path in paths {
+            let value = backend.get(&public_key, path).unwrap().unwrap();
+            assert_eq!(value, path.as_bytes());
+        }
+
+        // Metadata sits next to the value, out of listings
+        let meta = EntryMeta {
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        backend
+            .put_meta(public_key, "pub/a".to_string(), meta.clone())
+            .unwrap();
+        assert_eq!(
+            object("pub/.a.meta").await,
+            r#"{"content_type":"text/plain"}"#
+        );
+        assert_eq!(backend.meta(&public_key, "pub/a").unwrap(), meta);
+
+        let mut listed = backend.list(&public_key, "pub/a").unwrap();
+        listed.sort();
+        assert_eq!(listed, ["pub/a", "pub/a/b"]);
+        assert!(backend.delete(&public_key, "pub/a").unwrap());
+        assert!(!backend.delete(&public_key, "pub/a").unwrap());
+        assert_eq!(backend.get(&public_key, "pub/a").unwrap(), None);
+        assert!(backend.meta(&public_key, "pub/a").unwrap().is_empty());
+
+        // Large values go up in parts and come back whole
+        let large: Vec<u8> = (0..PART_SIZE * 2 + 7).map(|i| i as u8).collect();
+        let path = "pub/large.bin".to_string();
+        backend
+            .put(public_key, path.clone(), large.clone())
+            .unwrap();
+        assert_eq!(backend.get(&public_key, &path).unwrap().unwrap(), large);
+
+        // Staged values are only stored once committed
+        let staged = "pub/staged.bin".to_string();
+        let commit = backend
+            .stage(public_key, staged.clone(), &mut large.as_slice())
+            .unwrap();
+        assert_eq!(backend.get(&public_key, &staged).unwrap(), None);
+        drop(commit);
+        assert_eq!(backend.get(&public_key, &staged).unwrap(), None);
+        let commit = backend
+            .stage(public_key, staged.clone(), &mut large.as_slice())
+            .unwrap();
+        assert!(!backend.list(&public_key, "pub/").unwrap().contains(&staged));
+        commit().unwrap();
+        assert_eq!(backend.get(&public_key, &staged).unwrap().unwrap(), large);
+        assert!(backend.delete(&public_key, &staged).unwrap());
+
+        // Entries are shared by every server on the store, and so are
+        // accounts
+        let backend = ObjectBackend::new(store.clone(), Path::from("home")).unwrap();
+        let storage = Storage::with_backend(Arc::new(backend), Arc::new(SystemClock));
+        assert_eq!(
+            storage.get(&public_key, "pub/a/b").unwrap().unwrap(),
+            b"pub/a/b"
+        );
+        let users = storage.users();
+        assert_eq!(users[0].entries, paths.len());
+        let bytes: usize = paths[1..].iter().map(|path| path.len()).sum();
+        assert_eq!(users[0].bytes, bytes + large.len());
+        storage.register(public_key);
+        let backend = ObjectBackend::new(store, Path::from("home")).unwrap();
+        let storage = Storage::with_backend(Arc::new(backend), Arc::new(SystemClock));
+        assert!(storage.is_registered(&public_key));
+        assert_eq!(storage.users().len(), 1);
+    }
+
+    mod conformance {
+        use super::*;
+
+        fn storage() -> Storage {
+            let backend = ObjectBackend::new(Arc::new(InMemory::new()), Path::from("home"));
+            Storage::with_backend(Arc::new(backend.unwrap()), Arc::new(SystemClock))
+        }
+
+        crate::storage_conformance_tests!(storage);
+    }
+}
@@ -0,0 +1,219 @@
+//! Homeserver announcement over pkarr
+//!
+//! With [`ServerBuilder::pkarr`](crate::ServerBuilder::pkarr), the server
+//! signs an `HTTPS` record pointing at its public endpoint with its own
+//! keypair, and publishes it to pkarr relays on an interval. Relays store
+//! the packet on the Mainline DHT, so clients can find the homeserver from
+//! its public key alone.
+//!
+//! The server can also act as a pkarr relay itself under `/pkarr`, keeping
+//! packets in memory instead of on the DHT, for testnets and private
+//! networks.
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Router,
+};
+pub use pubky_common::pkarr::DEFAULT_RELAYS;
+use pubky_common::pkarr::{Record, RecordData, SignedPacket};
+use pubky_common::{Keypair, PublicKey};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Delay before retrying after every relay failed
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How to announce the homeserver
+#[derive(Clone)]
+pub struct PkarrConfig {
+    /// Keypair identifying the homeserver
+    pub keypair: Keypair,
+    /// Host name or IP address clients reach the homeserver at
+    pub host: String,
+    /// Port clients reach the homeserver at; the bound port by default
+    pub port: Option<u16>,
+    /// Base URLs of the pkarr relays to publish to
+    pub relays: Vec<String>,
+    /// How often to publish the record again
+    pub interval: Duration,
+}
+
+impl PkarrConfig {
+    /// Announce the homeserver at `host` with the default relays, hourly
+    pub fn new(keypair: Keypair, host: impl Into<String>) -> Self {
+        Self {
+            keypair,
+            host: host.into(),
+            port: None,
+            relays: DEFAULT_RELAYS.iter().map(|r| r.to_string()).collect(),
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// The signed packet announcing the homeserver on `port`
+    pub fn packet(&self, port: u16) -> pubky_common::Result<SignedPacket> {
+        let record = Record::new(
+            ".",
+            self.interval.as_secs().min(u32::MAX as u64) as u32,
+            RecordData::Https {
+                priority: 0,
+                target: self.host.clone(),
+                port: Some(self.port.unwrap_or(port)),
+            },
+        );
+        SignedPacket::sign(&self.keypair, vec![record])
+    }
+}
+
+/// Publish the homeserver's packet to the relays on the configured
+/// interval, until the task is aborted
+pub(crate) async fn publish_loop(config: PkarrConfig, port: u16) {
+    let http = reqwest::Client::new();
+    let public_key = config.keypair.public_key();
+    loop {
+        let published = match config.packet(port) {
+            Ok(packet) => publish(&http, &config.relays, &packet).await,
+            Err(e) => {
+                tracing::error!("Failed to sign the pkarr packet: {}", e);
+                0
+            }
+        };
+        let delay = match published {
+            0 => {
+                tracing::warn!("Failed to publish {} to any pkarr relay", public_key);
+                RETRY_DELAY.min(config.interval)
+            }
+            n => {
+                tracing::info!("Published {} to {} pkarr relay(s)", public_key, n);
+                config.interval
+            }
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Publish a packet to each relay, returning how many accepted it
+pub(crate) async fn publish(
+    http: &reqwest::Client,
+    relays: &[String],
+    packet: &SignedPacket,
+) -> usize {
+    let payload = packet.to_relay_payload();
+    let mut published = 0;
+    for relay in relays {
+        let url = format!("{}/{}", relay.trim_end_matches('/'), packet.public_key());
+        match http.put(&url).body(payload.clone()).send().await {
+            Ok(response) if response.status().is_success() => published += 1,
+            Ok(response) => tracing::debug!("{} rejected the packet: {}", relay, response.status()),
+            Err(e) => tracing::debug!("Failed to reach {}: {}", relay, e),
+        }
+    }
+    published
+}
+
+/// Packets stored by the built-in relay
+pub(crate) type RelayPackets = Arc<RwLock<HashMap<PublicKey, SignedPacket>>>;
+
+/// Create the pkarr relay routes
+pub(crate) fn relay_routes<S>(packets: RelayPackets) -> Router<S> {
+    Router::new()
+        .route("/{public_key}", get(get_packet).put(put_packet))
+        .with_state(packets)
+}
+
+/// GET /pkarr/{public_key}
+/// The latest packet published for a public key
+async fn get_packet(
+    State(packets): State<RelayPackets>,
+    Path(public_key): Path<String>,
+) -> Result<Vec<u8>, StatusCode> {
+    let public_key = PublicKey::from_z32(&public_key).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let packets = packets.read().unwrap();
+    let packet = packets.get(&public_key).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(packet.to_relay_payload())
+}
+
+/// PUT /pkarr/{public_key}
+/// Store a signed packet newer than the one stored
+async fn put_packet(
+    State(packets): State<RelayPackets>,
+    Path(public_key): Path<String>,
+    body: Bytes,
+) -> StatusCode {
+    let Ok(public_key) = PublicKey::from_z32(&public_key) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let Ok(packet) = SignedPacket::from_relay_payload(&public_key, &body) else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    let mut packets = packets.write().unwrap();
+    match packets.get(&public_key) {
+        Some(stored) if stored.timestamp() >= packet.timestamp() => StatusCode::CONFLICT,
+        _ => {
+            packets.insert(public_key, packet);
+            StatusCode::NO_CONTENT
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+
+    #[tokio::test]
+    async fn test_pkarr_publishing() {
+        let relay = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr_relay(true)
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let mut config = PkarrConfig::new(keypair.clone(), "localhost");
+        config.relays = vec![format!("{}/pkarr", relay.url())];
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .pkarr(config)
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(server.public_key(), Some(keypair.public_key()));
+
+        // Published right after start
+        let url = format!("{}/pkarr/{}", relay.url(), keypair.public_key());
+        let mut payload = None;
+        for _ in 0..50 {
+            let response = reqwest::get(&url).await.unwrap();
+            if response.status().is_success() {
+                payload = Some(response.bytes().await.unwrap());
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let packet =
+            SignedPacket::from_relay_payload(&keypair.public_key(), &payload.unwrap()).unwrap();
+        assert_eq!(
+            packet.records()[0].data,
+            RecordData::Https {
+                priority: 0,
+                target: "localhost".to_string(),
+                port: Some(server.local_addr().port()),
+            }
+        );
+
+        // Relays keep the newest packet only
+        let http = reqwest::Client::new();
+        let relays = vec![format!("{}/pkarr", relay.url())];
+        let old = SignedPacket::sign_at(&keypair, vec![], 1).unwrap();
+        assert_eq!(publish(&http, &relays, &old).await, 0);
+
+        server.shutdown().await;
+        relay.shutdown().await;
+    }
+}
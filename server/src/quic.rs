@@ -0,0 +1,33 @@
+//! QUIC endpoints
+//!
+//! The HTTP/3 listener and the blob transfer endpoint both serve QUIC with
+//! a [`TlsIdentity`].
+
+use quinn::crypto::rustls::QuicServerConfig;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::tls::TlsIdentity;
+
+/// Bind a QUIC server endpoint at `addr` speaking the `alpn` protocol
+pub(crate) fn endpoint(
+    identity: &TlsIdentity,
+    alpn: &[u8],
+    addr: SocketAddr,
+) -> io::Result<quinn::Endpoint> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .and_then(|builder| {
+            builder
+                .with_no_client_auth()
+                .with_single_cert(identity.cert_chain.clone(), identity.key.clone_key())
+        })
+        .map_err(invalid)?;
+    tls.alpn_protocols = vec![alpn.to_vec()];
+    let quic = QuicServerConfig::try_from(tls)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(quic)), addr)
+}
@@ -0,0 +1,157 @@
+//! Byte ranges of entries
+//!
+//! GET requests for an entry may ask for part of it with a `Range` header,
+//! such as `bytes=0-1023`, `bytes=1024-` or `bytes=-1024` for the last
+//! 1024 bytes, to resume an interrupted download or read a large entry in
+//! pieces. The part is returned with `206 Partial Content` and a
+//! `Content-Range` header; ranges starting past the end get
+//! `416 Range Not Satisfiable`. Requests for several ranges at once, and
+//! malformed ones, get the whole entry, as HTTP allows.
+
+use std::ops::Range;
+
+/// Unit of the only ranges supported, advertised in `Accept-Ranges`
+pub(crate) const BYTES: &str = "bytes";
+
+/// A range requested in a `Range` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    /// From an offset to an inclusive end, or to the end of the entry
+    From { start: u64, end: Option<u64> },
+    /// The last bytes of the entry
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parse a `Range` header, or `None` unless it asks for a single byte
+    /// range
+    pub(crate) fn parse(header: &str) -> Option<Self> {
+        let spec = header
+            .trim()
+            .strip_prefix(BYTES)?
+            .trim_start()
+            .strip_prefix('=')?;
+        let (start, end) = spec.trim().split_once('-')?;
+        let number = |digits: &str| match digits.chars().all(|c| c.is_ascii_digit()) {
+            true => digits.parse::<u64>().ok(),
+            false => None,
+        };
+        match (start, end) {
+            ("", suffix) => Some(Self::Suffix(number(suffix)?)),
+            (start, "") => Some(Self::From {
+                start: number(start)?,
+                end: None,
+            }),
+            (start, end) => {
+                let (start, end) = (number(start)?, number(end)?);
+                (start <= end).then_some(Self::From {
+                    start,
+                    end: Some(end),
+                })
+            }
+        }
+    }
+
+    /// The bytes of an entry of `size` bytes in the range, or `None` if
+    /// there are none
+    pub(crate) fn resolve(self, size: u64) -> Option<Range<u64>> {
+        let range = match self {
+            Self::From { start, end } => {
+                let end = end.map_or(size, |end| end.saturating_add(1).min(size));
+                start..end
+            }
+            Self::Suffix(len) => size.saturating_sub(len)..size,
+        };
+        (!range.is_empty()).then_some(range)
+    }
+}
+
+/// `Content-Range` of the bytes in `range` of an entry of `size` bytes
+pub(crate) fn content_range(range: &Range<u64>, size: u64) -> String {
+    format!("{} {}-{}/{}", BYTES, range.start, range.end - 1, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use axum::http::header;
+    use pubky_common::Keypair;
+
+    #[test]
+    fn test_byte_ranges() {
+        let resolve = |header: &str| ByteRange::parse(header).and_then(|range| range.resolve(100));
+        assert_eq!(resolve("bytes=0-9"), Some(0..10));
+        assert_eq!(resolve("bytes=90-"), Some(90..100));
+        assert_eq!(resolve("bytes=-10"), Some(90..100));
+        assert_eq!(resolve("bytes = 50-500"), Some(50..100));
+        assert_eq!(resolve("bytes=-500"), Some(0..100));
+
+        // Unsatisfiable
+        assert_eq!(resolve("bytes=100-"), None);
+        assert_eq!(resolve("bytes=-0"), None);
+        assert!(ByteRange::parse("bytes=100-").is_some());
+
+        // Ignored
+        for header in [
+            "bytes=9-0",
+            "bytes=0-1,5-6",
+            "items=0-1",
+            "bytes=a-1",
+            "bytes=+1-2",
+        ] {
+            assert_eq!(ByteRange::parse(header), None, "{}", header);
+        }
+        assert_eq!(content_range(&(0..10), 100), "bytes 0-9/100");
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let public_key = Keypair::random().public_key();
+        server
+            .storage()
+            .put(public_key, "a.txt".to_string(), b"0123456789".to_vec())
+            .unwrap();
+        let url = format!("{}/{}/a.txt", server.url(), public_key);
+        let client = reqwest::Client::new();
+        let get = |range: &str| client.get(&url).header(header::RANGE, range).send();
+
+        let response = get("bytes=2-4").await.unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], BYTES);
+        assert_eq!(response.text().await.unwrap(), "234");
+
+        let response = get("bytes=-3").await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(response.text().await.unwrap(), "789");
+
+        let response = get("bytes=10-").await.unwrap();
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+
+        // Several ranges get the whole entry
+        let response = get("bytes=0-1,5-6").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], BYTES);
+        assert_eq!(response.text().await.unwrap(), "0123456789");
+
+        assert_eq!(
+            client
+                .get(format!("{}/{}/b.txt", server.url(), public_key))
+                .header(header::RANGE, "bytes=0-1")
+                .send()
+                .await
+                .unwrap()
+                .status(),
+            404
+        );
+
+        server.shutdown().await;
+    }
+}
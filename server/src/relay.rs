@@ -0,0 +1,246 @@
+//! Auth relay for third-party sign-in
+//!
+//! A web app on another origin can't reach the device holding a user's
+//! keypair, and doesn't know the user's homeserver yet. With
+//! [`ServerBuilder::auth_relay`](crate::ServerBuilder::auth_relay), the
+//! server relays their exchange instead: the app opens a channel with
+//! `POST /relay/channels` and shows its id in an authorization URL, the key
+//! holder delivers its signed grant to the channel, and the app long-polls
+//! the channel for it, then redeems the grant at the user's homeserver.
+//!
+//! Messages are opaque to the relay: clients seal grants with a key that
+//! only appears in the authorization URL. A channel carries one message and
+//! expires after [`AUTH_REQUEST_TTL`](crate::AUTH_REQUEST_TTL).
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use pubky_common::dto::RelayChannel;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::authorize::AUTH_REQUEST_TTL;
+use crate::clock::Clock;
+use crate::routes::ApiError;
+
+/// How long a poll waits for the message before the relay answers with
+/// `204 No Content`
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// Longest message accepted
+const MAX_MESSAGE_LEN: usize = 16 * 1024;
+
+/// Most channels open at once
+const MAX_CHANNELS: usize = 10_000;
+
+/// A channel waiting for its message
+struct Channel {
+    secret: String,
+    expires_at: u64,
+    message: Option<Bytes>,
+    delivered: Arc<Notify>,
+}
+
+#[derive(Clone)]
+struct RelayState {
+    channels: Arc<Mutex<HashMap<String, Channel>>>,
+    clock: Arc<dyn Clock>,
+    /// Cancelled on shutdown, ending pending polls
+    closing: CancellationToken,
+}
+
+/// Create the auth relay routes
+pub(crate) fn relay_routes<S>(clock: Arc<dyn Clock>, closing: CancellationToken) -> Router<S> {
+    Router::new()
+        .route("/channels", post(open_channel))
+        .route("/channels/{id}", get(receive).post(deliver))
+        .with_state(RelayState {
+            channels: Default::default(),
+            clock,
+            closing,
+        })
+}
+
+/// POST /relay/channels
+/// Open a channel for one message
+async fn open_channel(
+    State(state): State<RelayState>,
+) -> Result<(StatusCode, Json<RelayChannel>), ApiError> {
+    let now = state.clock.now_millis();
+    let mut channels = state.channels.lock().unwrap();
+    channels.retain(|_, channel| channel.expires_at > now);
+    if channels.len() >= MAX_CHANNELS {
+        return Err(ApiError::RateLimited(Duration::from_secs(1)));
+    }
+
+    let info = RelayChannel {
+        id: BASE64_URL.encode(rand::random::<[u8; 12]>()),
+        secret: BASE64_URL.encode(rand::random::<[u8; 32]>()),
+        expires_at: now + AUTH_REQUEST_TTL.as_millis() as u64,
+    };
+    let channel = Channel {
+        secret: info.secret.clone(),
+        expires_at: info.expires_at,
+        message: None,
+        delivered: Arc::new(Notify::new()),
+    };
+    channels.insert(info.id.clone(), channel);
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+/// POST /relay/channels/{id}
+/// Deliver the message of a channel
+async fn deliver(
+    State(state): State<RelayState>,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    if body.len() > MAX_MESSAGE_LEN {
+        return Err(ApiError::BadRequest("Message too long".to_string()));
+    }
+    let mut channels = state.channels.lock().unwrap();
+    let channel = channels
+        .get_mut(&id)
+        .filter(|channel| channel.expires_at > state.clock.now_millis())
+        .ok_or(ApiError::NotFound)?;
+    if channel.message.is_some() {
+        return Err(ApiError::Conflict("Message already delivered".to_string()));
+    }
+    channel.message = Some(body);
+    channel.delivered.notify_waiters();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /relay/channels/{id}
+/// Receive the message of a channel, closing it, or `204 No Content` if
+/// none came in time
+async fn receive(
+    State(state): State<RelayState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let secret = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let deadline = tokio::time::Instant::now() + POLL_TIMEOUT;
+    let delivered = state
+        .channels
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|channel| channel.delivered.clone())
+        .ok_or(ApiError::NotFound)?;
+
+    loop {
+        // Listen before looking, so a delivery in between isn't missed
+        let notified = delivered.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        {
+            let mut channels = state.channels.lock().unwrap();
+            let channel = channels
+                .get(&id)
+                .filter(|channel| channel.expires_at > state.clock.now_millis())
+                .ok_or(ApiError::NotFound)?;
+            if secret != Some(channel.secret.as_str()) {
+                return Err(ApiError::Unauthorized);
+            }
+            if let Some(message) = channel.message.clone() {
+                channels.remove(&id);
+                return Ok(message.into_response());
+            }
+        }
+
+        tokio::select! {
+            woken = tokio::time::timeout_at(deadline, notified) => {
+                if woken.is_err() {
+                    return Ok(StatusCode::NO_CONTENT.into_response());
+                }
+            }
+            _ = state.closing.cancelled() => {
+                return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    async fn send(router: &Router, request: Request<Body>) -> (StatusCode, Bytes) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        (
+            status,
+            response.into_body().collect().await.unwrap().to_bytes(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_auth_relay() {
+        let router: Router = relay_routes(Arc::new(SystemClock), CancellationToken::new());
+        let request = Request::post("/channels").body(Body::empty()).unwrap();
+        let (status, body) = send(&router, request).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let channel: RelayChannel = serde_json::from_slice(&body).unwrap();
+        let uri = format!("/channels/{}", channel.id);
+        let receive = |secret: &str| {
+            Request::get(&uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", secret))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // The app waits until the key holder delivers
+        let waiting = tokio::spawn({
+            let router = router.clone();
+            let request = receive(&channel.secret);
+            async move { send(&router, request).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let deliver =
+            |message: &'static str| Request::post(&uri).body(Body::from(message)).unwrap();
+        assert_eq!(
+            send(&router, deliver("sealed")).await.0,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            send(&router, deliver("again")).await.0,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            waiting.await.unwrap(),
+            (StatusCode::OK, Bytes::from("sealed"))
+        );
+
+        // The channel closes once received
+        let (status, _) = send(&router, receive(&channel.secret)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::post("/channels").body(Body::empty()).unwrap();
+        let channel: RelayChannel =
+            serde_json::from_slice(&send(&router, request).await.1).unwrap();
+        let uri = format!("/channels/{}", channel.id);
+        let request = Request::get(&uri)
+            .header(header::AUTHORIZATION, "Bearer wrong")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&router, request).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,221 @@
+//! Read replica mode
+//!
+//! A replica serves reads from its own copy of storage, which it refreshes
+//! by periodically reconciling it with the primary's through the admin
+//! reconciliation endpoints, fetching only the entries that differ. Writes
+//! are forwarded to the primary and, once accepted there, applied locally as
+//! well so clients can read their own writes.
+//!
+//! Sessions live on the primary, so writes are forwarded before the
+//! replica's [write authorization](crate::write_auth) and the primary
+//! authorizes them. Signed writes are verified against the primary's
+//! identity, which the replica should share for clients to sign for it.
+
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Request, State},
+    http::{header, HeaderMap, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use bytes::Bytes;
+use pubky_common::reconcile::{Diff, Range, RangeReply};
+use pubky_common::PublicKey;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::admin::{self, BackupEntry, ADMIN_PASSWORD_HEADER};
+use crate::routes::{self, ApiError};
+use crate::storage::Storage;
+
+/// Default interval between reconciliations with the primary
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Connection details for the primary server of a replica
+#[derive(Debug, Clone)]
+pub struct ReplicaConfig {
+    /// Base URL of the primary, e.g. `http://10.0.0.1:3000`
+    pub primary_url: String,
+    /// Admin password of the primary, used to reconcile with it
+    pub admin_password: String,
+    /// How often to reconcile with the primary
+    pub sync_interval: Duration,
+}
+
+impl ReplicaConfig {
+    /// Create a config for the given primary with the default sync interval
+    pub fn new(primary_url: impl Into<String>, admin_password: impl Into<String>) -> Self {
+        Self {
+            primary_url: primary_url.into().trim_end_matches('/').to_string(),
+            admin_password: admin_password.into(),
+            sync_interval: DEFAULT_SYNC_INTERVAL,
+        }
+    }
+}
+
+/// Replica state shared between the write forwarder and the sync task
+#[derive(Clone)]
+pub(crate) struct Replica {
+    config: Arc<ReplicaConfig>,
+    http: reqwest::Client,
+}
+
+impl Replica {
+    pub(crate) fn new(config: ReplicaConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Reconcile local storage with the primary, returning how many
+    /// entries changed
+    ///
+    /// Only the ranges of entries whose fingerprints differ are compared, so
+    /// a replica that is nearly up to date finds the changes in a few round
+    /// trips and fetches nothing else.
+    pub(crate) async fn sync_once(&self, storage: &Storage) -> Result<usize, String> {
+        let local = storage.item_set();
+        let mut diff = Diff::default();
+        let mut ranges = vec![Range::full()];
+        while !ranges.is_empty() {
+            let replies: Vec<RangeReply> = self.admin_post("/admin/reconcile", &ranges).await?;
+            ranges = local.compare(&replies, &mut diff);
+        }
+
+        let keys: Vec<String> = diff.remote_only.into_iter().chain(diff.different).collect();
+        let entries: Vec<BackupEntry> = match keys.is_empty() {
+            true => Vec::new(),
+            false => self.admin_post("/admin/reconcile/entries", &keys).await?,
+        };
+        let mut changed = 0;
+        for entry in entries {
+            let public_key = PublicKey::from_z32(&entry.public_key).map_err(|e| e.to_string())?;
+            let value = BASE64.decode(&entry.value).map_err(|e| e.to_string())?;
+            storage
+                .put_with_meta(public_key, entry.path, value, entry.meta)
+                .map_err(|e| e.to_string())?;
+            changed += 1;
+        }
+        for key in diff.local_only {
+            if let Some((public_key, path)) = admin::split_key(&key) {
+                storage
+                    .delete(&public_key, path)
+                    .map_err(|e| e.to_string())?;
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Send an admin request to the primary
+    async fn admin_post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<T, String> {
+        let url = format!("{}{}", self.config.primary_url, path);
+        let response = self
+            .http
+            .post(&url)
+            .header(ADMIN_PASSWORD_HEADER, &self.config.admin_password)
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("primary returned {}", response.status()));
+        }
+        response.json().await.map_err(|e| e.to_string())
+    }
+
+    /// Keep local storage in sync with the primary until the task is aborted
+    pub(crate) async fn sync_loop(self, storage: Arc<Storage>) {
+        let mut interval = tokio::time::interval(self.config.sync_interval);
+        loop {
+            interval.tick().await;
+            match self.sync_once(&storage).await {
+                Ok(count) => {
+                    tracing::debug!("Replica synced {} changed entries from primary", count)
+                }
+                Err(e) => tracing::warn!("Replica sync failed: {}", e),
+            }
+        }
+    }
+
+    /// Forward a write request to the primary
+    async fn forward(
+        &self,
+        method: Method,
+        uri: &str,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Result<Response, ApiError> {
+        let url = format!("{}{}", self.config.primary_url, uri);
+        let mut headers = headers.clone();
+        headers.remove(header::HOST);
+
+        let response = self
+            .http
+            .request(method, &url)
+            .headers(headers)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| ApiError::InternalError(format!("Failed to reach primary: {}", e)))?;
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await.map_err(|e| {
+            ApiError::InternalError(format!("Failed to read primary response: {}", e))
+        })?;
+
+        let mut response = (status, body).into_response();
+        *response.headers_mut() = headers;
+        Ok(response)
+    }
+}
+
+/// Marks a write the primary accepted, in the extensions of its local copy
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AcceptedByPrimary;
+
+/// Middleware forwarding writes to the primary before applying them locally
+pub(crate) async fn forward_writes(
+    State(replica): State<Replica>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return Ok(next.run(request).await);
+    }
+
+    // Nested routers see a stripped path, so forward the original one
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.0.clone())
+        .unwrap_or_else(|| request.uri().clone());
+    let uri = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+
+    let (mut parts, body) = routes::read_body(request).await?;
+
+    let primary_response = replica
+        .forward(parts.method.clone(), uri, &parts.headers, body.clone())
+        .await?;
+    if !primary_response.status().is_success() {
+        return Ok(primary_response);
+    }
+
+    // Apply the accepted write locally so the client can read it back
+    parts.extensions.insert(AcceptedByPrimary);
+    next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    Ok(primary_response)
+}
@@ -1,50 +1,239 @@
 //! HTTP routes for storage operations
 //!
 //! Provides PUT/GET/DELETE endpoints for key-value storage.
+//!
+//! Values of writes are streamed into storage as they arrive, unless
+//! versioned.
+//!
+//! Writes naming their writer in the `X-Pubky-Writer` header are versioned:
+//! see [`pubky_common::version`] for how concurrent writes are kept as
+//! siblings.
+//!
+//! Writes can tag entries with `X-Pubky-Tag` headers, and listings filter
+//! by tag with `?tag=`: see [`pubky_common::tags`].
+//!
+//! Reads can ask for a [range](crate::range) of bytes of an entry.
+//!
+//! HEAD requests of entries answer with the headers of a read, the size
+//! included, without reading the value.
+//!
+//! Unless stored, `pub/posts/feed.xml` is an Atom [feed](crate::feed) of
+//! the user's posts.
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    body::{to_bytes, Body, Bytes},
+    extract::{Path, Query, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Response},
     routing::{delete, get, put},
-    Json, Router,
+    Json, RequestExt as _, Router,
 };
-use bytes::Bytes;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use futures_util::TryStreamExt;
+use http_body_util::LengthLimitError;
+use pubky_common::dto::{ErrorResponse, ListEntry, ListResponse, Sibling};
+use pubky_common::meta::{self, MAX_META_FIELDS, MAX_META_LEN, META_HEADER_PREFIX};
+use pubky_common::tags::{self, MAX_TAGS, TAG_HEADER};
+use pubky_common::url::PubkyUrl;
+use pubky_common::version::{self, VersionVector, SIBLINGS_HEADER, VERSION_HEADER, WRITER_HEADER};
 use pubky_common::PublicKey;
-use serde_json::json;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::io::{StreamReader, SyncIoBridge};
 
-use crate::storage::Storage;
+use crate::backend;
+use crate::conditional::{self, Preconditions};
+use crate::range::{self, ByteRange};
+use crate::storage::{CurrentEntry, EntryMeta, Storage};
+use crate::{cbor, feed};
 
 /// Application state containing shared storage
 type AppState = Arc<Storage>;
 
+/// Longest `Content-Type` stored with an entry, in bytes
+const MAX_CONTENT_TYPE_LEN: usize = 256;
+
+/// Default number of keys per list page
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+/// Most keys returned per list page
+const MAX_LIST_LIMIT: usize = 1000;
+
 /// Custom error type for route handlers
 #[derive(Debug)]
-enum ApiError {
+pub(crate) enum ApiError {
     InvalidPublicKey(String),
+    BadRequest(String),
+    Unauthorized,
+    /// The session doesn't grant access to the entry
+    Forbidden,
     NotFound,
+    /// The requested resource no longer exists, e.g. an expired cursor
+    Gone(String),
+    /// The request conflicts with the current state, e.g. a taken name
+    Conflict(String),
+    /// The account is frozen by an admin and rejects writes
+    Frozen,
+    /// The account is frozen by an admin and rejects reads
+    Blocked,
+    /// The moderation hook refused the content, for the given reason
+    Rejected(String),
+    /// Too many writes; retry after the given delay
+    RateLimited(Duration),
+    /// The request body is larger than the server accepts
+    PayloadTooLarge,
+    /// The requested range starts past the end of the entry, of the given
+    /// size
+    RangeNotSatisfiable(u64),
+    /// The entry doesn't meet the request's preconditions: it doesn't have
+    /// the expected tag, if any, but the given one or none
+    PreconditionFailed {
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+    /// The storage backend is unreachable for now
+    Unavailable,
     InternalError(String),
 }
 
+impl ApiError {
+    /// Status and body of the error, for protocols other than HTTP too
+    pub(crate) fn status_and_body(&self) -> (StatusCode, ErrorResponse) {
+        let (status, code, message) = match self {
+            ApiError::InvalidPublicKey(msg) => {
+                (StatusCode::BAD_REQUEST, "invalid_public_key", msg.clone())
+            }
+            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, "bad_request", msg.clone()),
+            ApiError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+                "Unauthorized".to_string(),
+            ),
+            ApiError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "Outside the capabilities of the session".to_string(),
+            ),
+            ApiError::NotFound => (StatusCode::NOT_FOUND, "not_found", "Not found".to_string()),
+            ApiError::Gone(msg) => (StatusCode::GONE, "gone", msg.clone()),
+            ApiError::Conflict(msg) => (StatusCode::CONFLICT, "conflict", msg.clone()),
+            ApiError::Frozen => (
+                StatusCode::LOCKED,
+                "frozen",
+                "Account is frozen".to_string(),
+            ),
+            ApiError::Blocked => (
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                "blocked",
+                "Account is unavailable".to_string(),
+            ),
+            ApiError::Rejected(msg) => (
+                StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+                "rejected",
+                msg.clone(),
+            ),
+            ApiError::RateLimited(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "Too many requests".to_string(),
+            ),
+            ApiError::PayloadTooLarge => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "Payload too large".to_string(),
+            ),
+            ApiError::RangeNotSatisfiable(_) => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                "range_not_satisfiable",
+                "Range not satisfiable".to_string(),
+            ),
+            ApiError::PreconditionFailed { expected, actual } => {
+                let body = ErrorResponse {
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                    ..ErrorResponse::new("conflict", "Entry changed")
+                };
+                return (StatusCode::PRECONDITION_FAILED, body);
+            }
+            ApiError::Unavailable => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Storage is unavailable".to_string(),
+            ),
+            ApiError::InternalError(msg) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal", msg.clone())
+            }
+        };
+        (status, ErrorResponse::new(code, message))
+    }
+}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        let (status, message) = match self {
-            ApiError::InvalidPublicKey(msg) => (StatusCode::BAD_REQUEST, msg),
-            ApiError::NotFound => (StatusCode::NOT_FOUND, "Not found".to_string()),
-            ApiError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-        };
+        let (status, body) = self.status_and_body();
+        if let ApiError::RateLimited(retry_after) = self {
+            let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            return (
+                status,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                Json(body),
+            )
+                .into_response();
+        }
+        if let ApiError::RangeNotSatisfiable(size) = self {
+            let content_range = format!("{} */{}", range::BYTES, size);
+            return (status, [(header::CONTENT_RANGE, content_range)], Json(body)).into_response();
+        }
+
+        (status, Json(body)).into_response()
+    }
+}
 
-        (status, Json(json!({ "error": message }))).into_response()
+/// Errors of the storage backend, already logged by the storage: those
+/// worth retrying are `503 Service Unavailable`
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        use io::ErrorKind::*;
+        match e.kind() {
+            TimedOut | Interrupted | WouldBlock | ConnectionRefused | ConnectionReset
+            | ConnectionAborted | NotConnected | BrokenPipe => ApiError::Unavailable,
+            _ => ApiError::InternalError("Storage failed".to_string()),
+        }
     }
 }
 
+/// Query parameters of list requests
+#[derive(Debug, Default, Deserialize)]
+struct ListQuery {
+    /// Maximum number of entries to return, up to [`MAX_LIST_LIMIT`]
+    limit: Option<usize>,
+    /// Only return entries after this path, as given by `next_cursor`
+    cursor: Option<String>,
+    /// List in descending order
+    #[serde(default)]
+    reverse: bool,
+    /// Collapse deeper paths into their first directory, such as `images/`
+    #[serde(default)]
+    shallow: bool,
+    /// Return the size, type, hash and other details of each entry
+    #[serde(default)]
+    details: bool,
+    /// Return every concurrent version of an entry
+    #[serde(default)]
+    siblings: bool,
+    /// Only list entries carrying these tags, separated by commas
+    tag: Option<String>,
+}
+
 /// Create the storage routes
 pub fn storage_routes() -> Router<AppState> {
     Router::new()
-        .route("/*path", put(put_data))
-        .route("/*path", get(get_data))
-        .route("/*path", delete(delete_data))
+        .route("/{*path}", put(put_data))
+        .route("/{*path}", get(get_data))
+        .route("/{*path}", delete(delete_data))
 }
 
 /// PUT /{public_key}/{path}
@@ -52,16 +241,240 @@ pub fn storage_routes() -> Router<AppState> {
 async fn put_data(
     State(storage): State<AppState>,
     Path((public_key_str, path)): Path<(String, String)>,
-    body: Bytes,
-) -> Result<StatusCode, ApiError> {
+    request: Request,
+) -> Result<Response, ApiError> {
     tracing::debug!("PUT /{}/{}", public_key_str, path);
 
-    let public_key = PublicKey::from_z32(&public_key_str)
-        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+    let PubkyUrl { public_key, path } = entry_url(&public_key_str, &path)?;
+
+    ensure_writable(&storage, &public_key)?;
+    let (parts, body) = request.with_limited_body().into_parts();
+    let headers = parts.headers;
+    let meta = EntryMeta {
+        tags: entry_tags(&headers)?,
+        ..entry_meta(&headers)?
+    };
+    let preconditions = Preconditions::of_write(&headers);
+    let Some(writer) = headers.get(WRITER_HEADER) else {
+        let expected = preconditions.if_match.clone();
+        let stored = stream_into(
+            &storage,
+            public_key,
+            path.clone(),
+            meta,
+            body,
+            preconditions,
+        );
+        if !stored.await? {
+            return Err(precondition_failed(&storage, &public_key, &path, expected));
+        }
+        let mut response = StatusCode::CREATED.into_response();
+        validator_headers(&mut response, &storage, &public_key, &path);
+        return Ok(response);
+    };
+
+    let writer = writer
+        .to_str()
+        .ok()
+        .filter(|writer| version::is_valid_writer(writer))
+        .ok_or_else(|| ApiError::BadRequest("Invalid writer id".to_string()))?;
+    if !preconditions.is_empty() {
+        // Concurrent versioned writes are kept as siblings instead
+        return Err(ApiError::BadRequest(
+            "Preconditions don't apply to versioned writes".to_string(),
+        ));
+    }
+    let context = match headers.get(VERSION_HEADER) {
+        Some(context) => context
+            .to_str()
+            .map_err(|e| ApiError::BadRequest(e.to_string()))?
+            .parse()
+            .map_err(|e: pubky_common::Error| ApiError::BadRequest(e.to_string()))?,
+        None => VersionVector::new(),
+    };
+    // Siblings are kept in memory, so versioned values are read whole
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| body_error(&e))?;
+    let (version, siblings) = storage.put_versioned(
+        public_key,
+        path.clone(),
+        body.to_vec(),
+        meta,
+        writer,
+        &context,
+    )?;
+
+    let mut response = StatusCode::CREATED.into_response();
+    version_headers(&mut response, &version, siblings);
+    validator_headers(&mut response, &storage, &public_key, &path);
+    Ok(response)
+}
+
+/// Add the `ETag` and `Last-Modified` of the entry at `path`, if it is
+/// stored, to `response`
+fn validator_headers(
+    response: &mut Response,
+    storage: &Storage,
+    public_key: &PublicKey,
+    path: &str,
+) {
+    let etag = storage
+        .hash(public_key, path)
+        .map(|hash| conditional::etag(&hash));
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    let modified = storage
+        .timestamps(public_key, path)
+        .map(|timestamps| conditional::last_modified(timestamps.modified));
+    if let Some(modified) = modified.and_then(|modified| HeaderValue::from_str(&modified).ok()) {
+        response
+            .headers_mut()
+            .insert(header::LAST_MODIFIED, modified);
+    }
+}
+
+/// The error of a request whose preconditions on the entry at `path`
+/// don't hold, expecting the tag `expected`
+fn precondition_failed(
+    storage: &Storage,
+    public_key: &PublicKey,
+    path: &str,
+    expected: Option<String>,
+) -> ApiError {
+    let actual = storage.hash(public_key, path);
+    ApiError::PreconditionFailed {
+        expected,
+        actual: actual.map(|hash| conditional::etag(&hash)),
+    }
+}
+
+/// Stream `body` into the entry at `path`, with its metadata, unless
+/// `preconditions` rule it out, on a blocking thread as the storage writes
+/// synchronously
+///
+/// Returns whether the entry was written.
+async fn stream_into(
+    storage: &AppState,
+    public_key: PublicKey,
+    path: String,
+    meta: EntryMeta,
+    body: Body,
+    preconditions: Preconditions,
+) -> Result<bool, ApiError> {
+    let chunks = body.into_data_stream().map_err(io::Error::other);
+    let mut reader = SyncIoBridge::new(StreamReader::new(chunks));
+    let stored = tokio::task::spawn_blocking({
+        let (storage, path) = (storage.clone(), path.clone());
+        move || {
+            let condition = |current: &CurrentEntry| {
+                let modified = || current.timestamps().map(|timestamps| timestamps.modified);
+                preconditions.hold(|| current.hash(), modified)
+            };
+            storage.put_from_if(public_key, path, meta, &mut reader, &condition)
+        }
+    })
+    .await
+    .map_err(|e| ApiError::InternalError(e.to_string()))?;
+    stored.map_err(|e| match e.get_ref().and_then(|e| e.downcast_ref()) {
+        Some(e) => body_error(e),
+        None => {
+            tracing::error!("Failed to store {} for {}: {}", path, public_key, e);
+            ApiError::from(e)
+        }
+    })
+}
+
+/// Read the whole body of a request, up to the body limit
+pub(crate) async fn read_body(request: Request) -> Result<(Parts, Bytes), ApiError> {
+    let (parts, body) = request.with_limited_body().into_parts();
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| body_error(&e))?;
+    Ok((parts, body))
+}
+
+/// The error of a request body that couldn't be read
+pub(crate) fn body_error(error: &axum::Error) -> ApiError {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(e) = source {
+        if e.is::<LengthLimitError>() {
+            return ApiError::PayloadTooLarge;
+        }
+        source = e.source();
+    }
+    ApiError::BadRequest(format!("Failed to read the body: {}", error))
+}
+
+/// Read the tags of a write from its headers
+fn entry_tags(headers: &HeaderMap) -> Result<BTreeSet<String>, ApiError> {
+    let values = headers.get_all(TAG_HEADER);
+    let mut tags = BTreeSet::new();
+    for value in values {
+        let tag = value
+            .to_str()
+            .ok()
+            .filter(|tag| tags::is_valid_tag(tag))
+            .ok_or_else(|| ApiError::BadRequest("Invalid tag".to_string()))?;
+        tags.insert(tag.to_string());
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(ApiError::BadRequest(format!(
+            "At most {} tags per entry",
+            MAX_TAGS
+        )));
+    }
+    Ok(tags)
+}
 
-    storage.put(public_key, path, body.to_vec());
+/// Read the metadata of a write from its headers
+fn entry_meta(headers: &HeaderMap) -> Result<EntryMeta, ApiError> {
+    let invalid = |what: &str| ApiError::BadRequest(format!("Invalid {}", what));
+    let mut custom = BTreeMap::new();
+    let mut len = 0;
+    for (name, value) in headers {
+        if !name.as_str().starts_with(META_HEADER_PREFIX) {
+            continue;
+        }
+        let field = meta::meta_name(name.as_str()).ok_or_else(|| invalid("metadata name"))?;
+        let value = value
+            .to_str()
+            .ok()
+            .filter(|value| meta::is_valid_meta_value(value))
+            .ok_or_else(|| invalid("metadata value"))?;
+        len += field.len() + value.len();
+        let previous = custom.insert(field.to_string(), value.to_string());
+        if previous.is_some() {
+            return Err(ApiError::BadRequest(format!(
+                "Metadata field {} given twice",
+                field
+            )));
+        }
+    }
+    if custom.len() > MAX_META_FIELDS || len > MAX_META_LEN {
+        return Err(ApiError::BadRequest(format!(
+            "At most {} metadata fields of {} bytes per entry",
+            MAX_META_FIELDS, MAX_META_LEN
+        )));
+    }
 
-    Ok(StatusCode::CREATED)
+    let content_type = match headers.get(header::CONTENT_TYPE) {
+        Some(value) => {
+            let content_type = value
+                .to_str()
+                .ok()
+                .filter(|value| !value.trim().is_empty() && value.len() <= MAX_CONTENT_TYPE_LEN)
+                .ok_or_else(|| invalid("Content-Type"))?;
+            Some(content_type.to_string())
+        }
+        None => None,
+    };
+    Ok(EntryMeta {
+        content_type,
+        custom,
+        ..Default::default()
+    })
 }
 
 /// GET /{public_key}/{path}
@@ -69,27 +482,274 @@ async fn put_data(
 async fn get_data(
     State(storage): State<AppState>,
     Path((public_key_str, path)): Path<(String, String)>,
+    Query(query): Query<ListQuery>,
+    method: Method,
+    headers: HeaderMap,
 ) -> Result<Response, ApiError> {
-    tracing::debug!("GET /{}/{}", public_key_str, path);
+    tracing::debug!("{} /{}/{}", method, public_key_str, path);
+
+    let PubkyUrl { public_key, path } = entry_url(&public_key_str, &path)?;
 
-    let public_key = PublicKey::from_z32(&public_key_str)
-        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+    ensure_readable(&storage, &public_key)?;
 
-    // If path ends with /, list all keys with that prefix
+    // If path ends with /, list the keys with that prefix
     if path.ends_with('/') {
-        let keys = storage.list(&public_key, &path);
-        return Ok(Json(json!({
-            "keys": keys,
-            "count": keys.len()
-        }))
-        .into_response());
+        let page = list_page(&storage, &public_key, &path, query)?;
+        return Ok(cbor::negotiate(&headers, &page));
     }
 
-    // Otherwise, get the value
-    match storage.get(&public_key, &path) {
-        Some(data) => Ok(data.into_response()),
-        None => Err(ApiError::NotFound),
+    if query.siblings {
+        let siblings: Vec<Sibling> = storage
+            .siblings(&public_key, &path)?
+            .into_iter()
+            .map(|(version, value)| Sibling {
+                version,
+                value: BASE64.encode(value),
+            })
+            .collect();
+        if siblings.is_empty() {
+            return Err(ApiError::NotFound);
+        }
+        return Ok(Json(siblings).into_response());
+    }
+
+    // Otherwise, get the value or the requested range of it, unless the
+    // client has it already or expects another
+    if let Some(hash) = storage.hash(&public_key, &path) {
+        let modified = storage
+            .timestamps(&public_key, &path)
+            .map(|timestamps| timestamps.modified);
+        let preconditions = Preconditions::of(&headers);
+        if !preconditions.hold(|| Some(hash.clone()), || modified) {
+            let expected = preconditions.if_match;
+            return Err(precondition_failed(&storage, &public_key, &path, expected));
+        }
+        if conditional::not_modified(&headers, &conditional::etag(&hash), modified) {
+            let mut response = StatusCode::NOT_MODIFIED.into_response();
+            validator_headers(&mut response, &storage, &public_key, &path);
+            return Ok(response);
+        }
+    }
+    let requested = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(ByteRange::parse);
+    let mut response = match requested {
+        Some(requested) => match storage.size(&public_key, &path)? {
+            Some(size) => partial_response(&storage, &public_key, &path, requested, size)?,
+            None => return missing_entry(&storage, &public_key, &path),
+        },
+        // The body of HEAD responses is dropped, so only the size is read
+        None if method == Method::HEAD => match storage.size(&public_key, &path)? {
+            Some(size) => (
+                [
+                    (
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/octet-stream"),
+                    ),
+                    (header::CONTENT_LENGTH, HeaderValue::from(size)),
+                ],
+                (),
+            )
+                .into_response(),
+            None => return missing_entry(&storage, &public_key, &path),
+        },
+        None => match storage.get(&public_key, &path)? {
+            Some(data) => data.into_response(),
+            None => return missing_entry(&storage, &public_key, &path),
+        },
+    };
+    response.headers_mut().insert(
+        header::ACCEPT_RANGES,
+        HeaderValue::from_static(range::BYTES),
+    );
+    let meta = storage.meta(&public_key, &path)?;
+    let content_type = meta
+        .content_type
+        .and_then(|t| HeaderValue::from_str(&t).ok());
+    if let Some(content_type) = content_type {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, content_type);
+    }
+    for (name, value) in meta.custom {
+        let name = HeaderName::try_from(meta::meta_header(&name));
+        if let (Ok(name), Ok(value)) = (name, HeaderValue::from_str(&value)) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    validator_headers(&mut response, &storage, &public_key, &path);
+    if let Some(version) = storage.version(&public_key, &path)? {
+        let siblings = storage.siblings(&public_key, &path)?.len();
+        version_headers(&mut response, &version, siblings);
     }
+    for tag in meta.tags {
+        if let Ok(tag) = HeaderValue::from_str(&tag) {
+            response.headers_mut().append(TAG_HEADER, tag);
+        }
+    }
+    Ok(response)
+}
+
+/// The response to a read of an entry that isn't stored
+fn missing_entry(
+    storage: &Storage,
+    public_key: &PublicKey,
+    path: &str,
+) -> Result<Response, ApiError> {
+    if path == feed::FEED_PATH {
+        return feed::atom_response(storage, public_key)?.ok_or(ApiError::NotFound);
+    }
+    Err(ApiError::NotFound)
+}
+
+/// The bytes in `requested` of an entry of `size` bytes
+fn partial_response(
+    storage: &Storage,
+    public_key: &PublicKey,
+    path: &str,
+    requested: ByteRange,
+    size: u64,
+) -> Result<Response, ApiError> {
+    let range = requested
+        .resolve(size)
+        .ok_or(ApiError::RangeNotSatisfiable(size))?;
+    let data = storage
+        .get_range(public_key, path, range.clone())?
+        .ok_or(ApiError::NotFound)?;
+    // The entry may have shrunk since its size was read
+    if data.is_empty() {
+        return Err(ApiError::RangeNotSatisfiable(size));
+    }
+    let range = range.start..range.start + data.len() as u64;
+    let content_range = range::content_range(&range, size);
+    Ok((
+        StatusCode::PARTIAL_CONTENT,
+        [(header::CONTENT_RANGE, content_range)],
+        data,
+    )
+        .into_response())
+}
+
+/// Add the version and sibling count of a versioned entry to a response
+fn version_headers(response: &mut Response, version: &VersionVector, siblings: usize) {
+    let headers = response.headers_mut();
+    if let Ok(version) = HeaderValue::from_str(&version.to_string()) {
+        headers.insert(VERSION_HEADER, version);
+    }
+    headers.insert(SIBLINGS_HEADER, HeaderValue::from(siblings));
+}
+
+/// A page of the keys under `prefix`, collapsed into directories with
+/// `shallow`
+///
+/// Keys are read from storage a page at a time, or from the tag index.
+fn list_page(
+    storage: &Storage,
+    public_key: &PublicKey,
+    prefix: &str,
+    query: ListQuery,
+) -> io::Result<ListResponse> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let tagged = query.tag.as_ref().map(|tag| {
+        let tags: Vec<String> = tag.split(',').map(str::to_string).collect();
+        let mut keys = storage.tagged(public_key, prefix, &tags);
+        keys.sort_unstable();
+        if query.reverse {
+            keys.reverse();
+        }
+        keys
+    });
+    let next_keys = |after: Option<&str>| match &tagged {
+        Some(keys) => {
+            let start = after.map_or(0, |after| {
+                keys.partition_point(|key| !backend::comes_after(key, after, query.reverse))
+            });
+            keys[start..].iter().take(limit + 1).cloned().collect()
+        }
+        None => storage.list_page(public_key, prefix, after, limit + 1, query.reverse),
+    };
+    // Keys under a directory all come after it, or all before it in
+    // reverse, so listing resumes past a directory without reading them
+    let resume = |key: &str| match query.shallow && key.ends_with('/') && !query.reverse {
+        true => format!("{}{}", key, char::MAX),
+        false => key.to_string(),
+    };
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut after = query.cursor.as_deref().map(resume);
+    loop {
+        let batch = next_keys(after.as_deref());
+        let exhausted = batch.len() <= limit;
+        for key in batch {
+            after = Some(key.clone());
+            let key = match query.shallow {
+                // Paths sharing a directory are adjacent in order
+                true => match key[prefix.len()..].find('/') {
+                    Some(end) => key[..prefix.len() + end + 1].to_string(),
+                    None => key,
+                },
+                false => key,
+            };
+            if keys.last() == Some(&key) || query.cursor.as_ref() == Some(&key) {
+                continue;
+            }
+            keys.push(key);
+        }
+        if exhausted || keys.len() > limit {
+            break;
+        }
+        if let Some(last) = keys.last() {
+            let resumed = resume(last);
+            if after
+                .as_deref()
+                .is_none_or(|after| backend::comes_after(&resumed, after, query.reverse))
+            {
+                after = Some(resumed);
+            }
+        }
+    }
+
+    let mut next_cursor = None;
+    if keys.len() > limit {
+        keys.truncate(limit);
+        next_cursor = keys.last().cloned();
+    }
+    let details = |path: &String| -> io::Result<ListEntry> {
+        // Directories of shallow listings have no details
+        if path.ends_with('/') {
+            return Ok(ListEntry {
+                path: path.clone(),
+                ..Default::default()
+            });
+        }
+        let meta = storage.meta(public_key, path)?;
+        Ok(ListEntry {
+            path: path.clone(),
+            size: storage.size(public_key, path)?,
+            content_type: meta.content_type,
+            modified: storage
+                .timestamps(public_key, path)
+                .map(|timestamps| timestamps.modified),
+            hash: storage.hash(public_key, path),
+            tags: meta.tags.into_iter().collect(),
+            meta: meta.custom,
+        })
+    };
+    let entries = match query.details {
+        true => Some(keys.iter().map(details).collect::<io::Result<_>>()?),
+        false => None,
+    };
+
+    Ok(ListResponse {
+        count: keys.len(),
+        keys,
+        entries,
+        next_cursor,
+    })
 }
 
 /// DELETE /{public_key}/{path}
@@ -97,15 +757,61 @@ async fn get_data(
 async fn delete_data(
     State(storage): State<AppState>,
     Path((public_key_str, path)): Path<(String, String)>,
+    headers: HeaderMap,
 ) -> Result<StatusCode, ApiError> {
     tracing::debug!("DELETE /{}/{}", public_key_str, path);
 
-    let public_key = PublicKey::from_z32(&public_key_str)
-        .map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+    let PubkyUrl { public_key, path } = entry_url(&public_key_str, &path)?;
+
+    ensure_writable(&storage, &public_key)?;
+    let preconditions = Preconditions::of_write(&headers);
+    let condition = |current: &CurrentEntry| {
+        let modified = || current.timestamps().map(|timestamps| timestamps.modified);
+        preconditions.hold(|| current.hash(), modified)
+    };
+    match storage.delete_if(&public_key, &path, &condition)? {
+        Some(true) => Ok(StatusCode::NO_CONTENT),
+        Some(false) => Err(ApiError::NotFound),
+        None => {
+            let expected = preconditions.if_match;
+            Err(precondition_failed(&storage, &public_key, &path, expected))
+        }
+    }
+}
+
+/// The entry addressed by a request, rejecting invalid keys and paths
+pub(crate) fn entry_url(public_key: &str, path: &str) -> Result<PubkyUrl, ApiError> {
+    PubkyUrl::from_parts(public_key, path).map_err(|e| match e {
+        pubky_common::Error::InvalidPath(_) => ApiError::BadRequest(e.to_string()),
+        e => ApiError::InvalidPublicKey(e.to_string()),
+    })
+}
+
+/// The URI path of an entry, percent-encoding its path
+pub(crate) fn uri_path(public_key: &PublicKey, path: &str) -> String {
+    let mut uri = format!("/{}/", public_key);
+    for byte in path.bytes() {
+        match byte {
+            b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(byte as char),
+            byte if byte.is_ascii_alphanumeric() => uri.push(byte as char),
+            byte => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// Reject writes to accounts frozen by an admin
+pub(crate) fn ensure_writable(storage: &Storage, public_key: &PublicKey) -> Result<(), ApiError> {
+    match storage.frozen(public_key) {
+        Some(_) => Err(ApiError::Frozen),
+        None => Ok(()),
+    }
+}
 
-    if storage.delete(&public_key, &path) {
-        Ok(StatusCode::NO_CONTENT)
-    } else {
-        Err(ApiError::NotFound)
+/// Reject reads from accounts frozen by an admin with reads blocked
+pub(crate) fn ensure_readable(storage: &Storage, public_key: &PublicKey) -> Result<(), ApiError> {
+    match storage.frozen(public_key) {
+        Some(freeze) if freeze.block_reads => Err(ApiError::Blocked),
+        _ => Ok(()),
     }
 }
@@ -0,0 +1,298 @@
+//! Content scanning of large uploads
+//!
+//! With [`ServerBuilder::scan_uploads`](crate::ServerBuilder::scan_uploads),
+//! writes under `pub/` of at least [`ScanConfig::threshold`] bytes are
+//! answered with `202 Accepted` and held back, pending, until a
+//! [`Scanner`] such as an antivirus checks them in the background. Clean
+//! content is then stored, rejected content dropped, and quarantined
+//! content kept for review. Admins can settle pending content early with
+//! the quarantine endpoints of the admin API.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures_util::future::{BoxFuture, FutureExt};
+use pubky_common::PublicKey;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use crate::moderation::Verdict;
+use crate::routes::{ensure_writable, read_body, ApiError};
+use crate::storage::Storage;
+
+/// Checks uploaded content in the background
+pub trait Scanner: Send + Sync {
+    /// Judge `value`, written at `path` of `public_key`
+    fn scan(&self, public_key: &PublicKey, path: &str, value: Bytes)
+        -> BoxFuture<'static, Verdict>;
+}
+
+/// Configuration of content scanning
+#[derive(Clone)]
+pub struct ScanConfig {
+    pub scanner: Arc<dyn Scanner>,
+    /// Smallest write scanned, in bytes
+    pub threshold: usize,
+    /// Most scans running at once; others wait their turn
+    pub max_concurrent: usize,
+}
+
+impl ScanConfig {
+    /// Scan writes of 1 MiB or more with `scanner`
+    pub fn new(scanner: Arc<dyn Scanner>) -> Self {
+        Self {
+            scanner,
+            threshold: 1024 * 1024,
+            max_concurrent: 4,
+        }
+    }
+}
+
+/// A scanner running a command, such as `clamdscan --no-summary -`, with
+/// the content on its standard input
+///
+/// Following ClamAV, exit code 0 accepts the content and 1 rejects it.
+/// Other exit codes, or failing to run the command, quarantine it for
+/// review.
+#[derive(Debug, Clone)]
+pub struct CommandScanner {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandScanner {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Parse a command line, split on whitespace
+    pub fn parse(command: &str) -> Option<Self> {
+        let mut words = command.split_whitespace();
+        let scanner = Self::new(words.next()?);
+        Some(scanner.args(words))
+    }
+
+    /// Pass arguments to the command
+    pub fn args<S: Into<String>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    async fn run(&self, value: Bytes) -> std::io::Result<std::process::Output> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // Scanners may exit before reading everything
+        let _ = stdin.write_all(&value).await;
+        drop(stdin);
+        child.wait_with_output().await
+    }
+}
+
+impl Scanner for CommandScanner {
+    fn scan(&self, _: &PublicKey, _: &str, value: Bytes) -> BoxFuture<'static, Verdict> {
+        let scanner = self.clone();
+        async move {
+            match scanner.run(value).await {
+                Ok(output) => match output.status.code() {
+                    Some(0) => Verdict::Accept,
+                    Some(1) => {
+                        let stdout = String::from_utf8_lossy(&output.stdout);
+                        let reason = stdout.lines().next().unwrap_or_default().trim();
+                        match reason.is_empty() {
+                            true => Verdict::Reject("Content failed the scan".to_string()),
+                            false => Verdict::Reject(reason.to_string()),
+                        }
+                    }
+                    _ => Verdict::Quarantine(format!("Scan failed with {}", output.status)),
+                },
+                Err(e) => Verdict::Quarantine(format!("Scan failed: {}", e)),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// State of the scanning middleware
+#[derive(Clone)]
+pub(crate) struct Scanning {
+    config: ScanConfig,
+    storage: Arc<Storage>,
+    slots: Arc<Semaphore>,
+}
+
+impl Scanning {
+    pub(crate) fn new(config: ScanConfig, storage: Arc<Storage>) -> Self {
+        let slots = Arc::new(Semaphore::new(config.max_concurrent.max(1)));
+        Self {
+            config,
+            storage,
+            slots,
+        }
+    }
+}
+
+/// Middleware holding large public writes back until they are scanned
+pub(crate) async fn scan_uploads(
+    State(state): State<Scanning>,
+    Path((public_key, path)): Path<(String, String)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let public_key = match PublicKey::from_z32(&public_key) {
+        Ok(public_key) if request.method() == Method::PUT && path.starts_with("pub/") => public_key,
+        _ => return Ok(next.run(request).await),
+    };
+
+    let (parts, body) = read_body(request).await?;
+    if body.len() < state.config.threshold {
+        return Ok(next.run(Request::from_parts(parts, Body::from(body))).await);
+    }
+
+    ensure_writable(&state.storage, &public_key)?;
+    state
+        .storage
+        .hold_for_scan(public_key, path.clone(), body.to_vec());
+    tokio::spawn(async move {
+        let Ok(_slot) = state.slots.acquire().await else {
+            return;
+        };
+        let verdict = state.config.scanner.scan(&public_key, &path, body.clone());
+        let verdict = verdict.await;
+        tracing::debug!("Scanned {}/{}: {:?}", public_key, path, verdict);
+        // The storage logs backend failures and keeps the value held
+        let _ = state
+            .storage
+            .settle_scan(&public_key, &path, &body, verdict);
+    });
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_auth::SignWrite;
+    use crate::{Server, ADMIN_PASSWORD_HEADER};
+    use pubky_common::Keypair;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    /// Rejects content containing "virus" once allowed to answer
+    struct GatedScanner(Arc<Notify>);
+
+    impl Scanner for GatedScanner {
+        fn scan(&self, _: &PublicKey, _: &str, value: Bytes) -> BoxFuture<'static, Verdict> {
+            let gate = self.0.clone();
+            async move {
+                gate.notified().await;
+                match value.windows(5).any(|window| window == b"virus") {
+                    true => Verdict::Reject("Infected".to_string()),
+                    false => Verdict::Accept,
+                }
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_uploads() {
+        let command = CommandScanner::parse("sh -c").unwrap();
+        let command = command.args(["grep -q virus && exit 1; exit 0"]);
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let scan = |value: &'static [u8]| command.scan(&public_key, "", Bytes::from(value));
+        assert_eq!(scan(b"clean").await, Verdict::Accept);
+        assert_eq!(
+            scan(b"a virus").await,
+            Verdict::Reject("Content failed the scan".to_string())
+        );
+        let missing = CommandScanner::new("/nonexistent/scanner");
+        let verdict = missing.scan(&public_key, "", Bytes::new()).await;
+        assert!(matches!(verdict, Verdict::Quarantine(_)));
+
+        let gate = Arc::new(Notify::new());
+        let mut config = ScanConfig::new(Arc::new(GatedScanner(gate.clone())));
+        config.threshold = 8;
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .admin_password("secret")
+            .scan_uploads(config)
+            .start()
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let url = |path: &str| format!("{}/{}/{}", server.url(), public_key, path);
+        let status = |path: &'static str| {
+            let request = http.get(url(path)).send();
+            async { request.await.unwrap().status() }
+        };
+        let put = |path: &str, body: &'static str| {
+            let request = http.put(url(path)).body(body);
+            request.signed(&keypair, &server.identity()).send()
+        };
+        let settled = || async {
+            for _ in 0..100 {
+                if !server.storage().quarantined().iter().any(|q| q.2.pending) {
+                    return;
+                }
+                gate.notify_waiters();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("scans didn't settle");
+        };
+
+        // Small writes are stored right away
+        assert_eq!(put("pub/small", "tiny").await.unwrap().status(), 201);
+        assert_eq!(status("pub/small").await, 200);
+
+        // Large ones once they pass the scan
+        assert_eq!(
+            put("pub/clean", "large enough").await.unwrap().status(),
+            202
+        );
+        assert_eq!(put("pub/bad", "a large virus").await.unwrap().status(), 202);
+        assert_eq!(status("pub/clean").await, 404);
+        let pending = server.storage().quarantined();
+        assert_eq!(pending.len(), 2);
+        assert!(pending.iter().all(|(_, _, held)| held.pending));
+        settled().await;
+        assert_eq!(status("pub/clean").await, 200);
+        assert_eq!(status("pub/bad").await, 404);
+        assert!(server.storage().quarantined().is_empty());
+
+        // Admins can release pending content before its scan settles
+        assert_eq!(
+            put("pub/early", "another virus").await.unwrap().status(),
+            202
+        );
+        let release = format!("{}/admin/quarantine/{}/pub/early", server.url(), public_key);
+        let response = http
+            .post(release)
+            .header(ADMIN_PASSWORD_HEADER, "secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+        assert_eq!(status("pub/early").await, 200);
+        gate.notify_waiters();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(status("pub/early").await, 200);
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,469 @@
+//! Full-text search over users' entries
+//!
+//! With [`ServerBuilder::search`](crate::ServerBuilder::search), entries
+//! whose media type is in [`SearchConfig::content_types`] are indexed with
+//! tantivy as they are written, and `GET /{public_key}/search?q=` returns
+//! the paths of the best matches, so apps don't have to download everything
+//! to grep it.
+//!
+//! Media types are guessed from the extension of the path. Anyone can
+//! search public entries, under `pub/`; sessions of the owner also search
+//! the private entries their capabilities allow reading. Without a `q`
+//! parameter, `search` is an ordinary path.
+
+use axum::{
+    extract::{OriginalUri, Query, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use pubky_common::capabilities::Action;
+use pubky_common::dto::{SearchResponse, SearchResult};
+use pubky_common::PublicKey;
+use serde::Deserialize;
+use std::sync::Arc;
+use tantivy::collector::TopDocs;
+use tantivy::query::{BooleanQuery, Occur, Query as TantivyQuery, QueryParser, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value, STORED, STRING, TEXT};
+use tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+use crate::routes::{ensure_readable, ApiError};
+use crate::session::authenticate;
+use crate::storage::{Event, EventOp, Session, Storage};
+use crate::write_auth;
+
+/// Last segment of the search path
+pub const SEARCH_PATH: &str = "search";
+
+/// Default number of results
+const DEFAULT_LIMIT: usize = 20;
+
+/// Most results returned at once
+const MAX_LIMIT: usize = 100;
+
+/// Longest query accepted
+const MAX_QUERY_LEN: usize = 1024;
+
+/// Events indexed per commit
+const BATCH_SIZE: usize = 1000;
+
+/// Memory of the index writer, the least tantivy accepts
+const WRITER_MEMORY: usize = 15_000_000;
+
+/// Which entries are indexed
+#[derive(Debug, Clone)]
+pub struct SearchConfig {
+    /// Media types of the indexed entries
+    pub content_types: Vec<String>,
+    /// Size of the largest entry indexed, in bytes
+    pub max_entry_size: usize,
+}
+
+impl SearchConfig {
+    /// Index plain text, Markdown, JSON, HTML and CSV entries of up to
+    /// 1 MiB
+    pub fn new() -> Self {
+        Self {
+            content_types: ["text/plain", "text/markdown", "application/json"]
+                .into_iter()
+                .chain(["text/html", "text/csv"])
+                .map(String::from)
+                .collect(),
+            max_entry_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Media type of the entry at `path`, guessed from its extension
+fn content_type(path: &str) -> Option<&'static str> {
+    let (_, extension) = path.rsplit_once('.')?;
+    let content_type = match extension.to_ascii_lowercase().as_str() {
+        "txt" | "text" => "text/plain",
+        "md" | "markdown" => "text/markdown",
+        "json" => "application/json",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+struct Fields {
+    /// Public key and path, identifying the entry
+    id: Field,
+    owner: Field,
+    path: Field,
+    /// Whether the entry is public
+    public: Field,
+    body: Field,
+}
+
+/// The search index, shared by the indexing task and the search route
+pub(crate) struct SearchIndex {
+    config: SearchConfig,
+    index: Index,
+    reader: IndexReader,
+    fields: Fields,
+}
+
+impl SearchIndex {
+    pub(crate) fn new(config: SearchConfig) -> tantivy::Result<Self> {
+        let mut schema = Schema::builder();
+        let fields = Fields {
+            id: schema.add_text_field("id", STRING),
+            owner: schema.add_text_field("owner", STRING),
+            path: schema.add_text_field("path", STRING | STORED),
+            public: schema.add_text_field("public", STRING),
+            body: schema.add_text_field("body", TEXT),
+        };
+        let index = Index::create_in_ram(schema.build());
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(Self {
+            config,
+            index,
+            reader,
+            fields,
+        })
+    }
+
+    /// Index an entry, if it is text of an indexed media type
+    fn add(
+        &self,
+        writer: &IndexWriter,
+        public_key: &PublicKey,
+        path: &str,
+        value: &[u8],
+    ) -> tantivy::Result<()> {
+        let indexed = content_type(path).is_some_and(|content_type| {
+            self.config.content_types.iter().any(|t| t == content_type)
+        });
+        if !indexed || value.len() > self.config.max_entry_size {
+            return Ok(());
+        }
+        let Ok(text) = std::str::from_utf8(value) else {
+            return Ok(());
+        };
+        let fields = &self.fields;
+        writer.add_document(doc!(
+            fields.id => id(public_key, path),
+            fields.owner => public_key.to_z32(),
+            fields.path => path,
+            fields.public => public(path),
+            fields.body => text,
+        ))?;
+        Ok(())
+    }
+
+    /// Apply a mutation of the storage to the index
+    fn apply(&self, writer: &IndexWriter, storage: &Storage, event: &Event) -> tantivy::Result<()> {
+        let id = id(&event.public_key, &event.path);
+        writer.delete_term(Term::from_field_text(self.fields.id, &id));
+        if event.op == EventOp::Put {
+            if let Some(value) = storage.get(&event.public_key, &event.path)? {
+                self.add(writer, &event.public_key, &event.path, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index every stored entry, replacing the index
+    fn rebuild(&self, writer: &IndexWriter, storage: &Storage) -> tantivy::Result<()> {
+        writer.delete_all_documents()?;
+        for (public_key, path, value) in storage.entries() {
+            self.add(writer, &public_key, &path, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Paths of the entries of `owner` matching `query`, best first
+    ///
+    /// Private entries are included if `reader` is a session whose
+    /// capabilities allow reading them.
+    fn search(
+        &self,
+        owner: &PublicKey,
+        query: &str,
+        reader: Option<&Session>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<SearchResult>, ApiError> {
+        let mut parser = QueryParser::for_index(&self.index, vec![self.fields.body]);
+        parser.set_conjunction_by_default();
+        let query = parser
+            .parse_query(query)
+            .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+        let term = |field, text: &str| -> Box<dyn TantivyQuery> {
+            let term = Term::from_field_text(field, text);
+            Box::new(TermQuery::new(term, IndexRecordOption::Basic))
+        };
+        let mut clauses = vec![
+            (Occur::Must, query),
+            (Occur::Must, term(self.fields.owner, &owner.to_z32())),
+        ];
+        if reader.is_none() {
+            clauses.push((Occur::Must, term(self.fields.public, "true")));
+        }
+        let query = BooleanQuery::new(clauses);
+        let visible = |path: &str| {
+            reader.is_none_or(|session| {
+                path.starts_with("pub/") || write_auth::allows(session, path, Action::Read)
+            })
+        };
+
+        // Skip the hits the reader can't see, widening the search until
+        // enough are left or there are no more
+        let internal = |e: tantivy::TantivyError| ApiError::InternalError(e.to_string());
+        let searcher = self.reader.searcher();
+        let wanted = offset.saturating_add(limit);
+        let mut window = wanted;
+        loop {
+            let hits = searcher
+                .search(&query, &TopDocs::with_limit(window))
+                .map_err(internal)?;
+            let exhausted = hits.len() < window;
+            let mut results = Vec::with_capacity(hits.len());
+            for (score, address) in hits {
+                let doc: TantivyDocument = searcher.doc(address).map_err(internal)?;
+                if let Some(path) = doc.get_first(self.fields.path).and_then(|v| v.as_str()) {
+                    if visible(path) {
+                        results.push(SearchResult {
+                            path: path.to_string(),
+                            score,
+                        });
+                    }
+                }
+            }
+            if exhausted || results.len() >= wanted {
+                return Ok(results.into_iter().skip(offset).take(limit).collect());
+            }
+            window = window.saturating_mul(2);
+        }
+    }
+}
+
+fn id(public_key: &PublicKey, path: &str) -> String {
+    format!("{}/{}", public_key, path)
+}
+
+fn public(path: &str) -> &'static str {
+    match path.starts_with("pub/") {
+        true => "true",
+        false => "false",
+    }
+}
+
+/// Keep the index in step with the storage event log, until the task is
+/// aborted
+pub(crate) async fn index_loop(index: Arc<SearchIndex>, storage: Arc<Storage>) {
+    let mut writer: IndexWriter = match index.index.writer_with_num_threads(1, WRITER_MEMORY) {
+        Ok(writer) => writer,
+        Err(e) => {
+            tracing::error!("Failed to open the search index: {}", e);
+            return;
+        }
+    };
+
+    // Rebuilt first, and whenever the event log moved past the cursor
+    let mut cursor = None;
+    loop {
+        let indexed = match cursor.and_then(|after| storage.events_since(after, BATCH_SIZE)) {
+            Some(events) if events.is_empty() => {
+                storage.wait_for_events(cursor.unwrap_or_default()).await;
+                continue;
+            }
+            Some(events) => {
+                cursor = events.last().map(|event| event.seq);
+                events
+                    .iter()
+                    .try_for_each(|event| index.apply(&writer, &storage, event))
+            }
+            None => {
+                cursor = Some(storage.head_seq());
+                index.rebuild(&writer, &storage)
+            }
+        };
+        let committed = indexed
+            .and_then(|_| writer.commit())
+            .and_then(|_| index.reader.reload());
+        if let Err(e) = committed {
+            tracing::error!("Failed to update the search index: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// Middleware answering `GET /{public_key}/search?q=`
+pub(crate) async fn serve_search(
+    State((index, storage)): State<(Arc<SearchIndex>, Arc<Storage>)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    // Nested routers see a stripped path, so use the original one
+    let uri = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.0.clone())
+        .unwrap_or_else(|| request.uri().clone());
+    let public_key = match uri.path().trim_start_matches('/').split_once('/') {
+        Some((z32, SEARCH_PATH)) if request.method() == axum::http::Method::GET => z32,
+        _ => return Ok(next.run(request).await),
+    };
+    let query = Query::<SearchQuery>::try_from_uri(&uri)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?
+        .0;
+    let Some(q) = query.q else {
+        return Ok(next.run(request).await);
+    };
+
+    let public_key =
+        PublicKey::from_z32(public_key).map_err(|e| ApiError::InvalidPublicKey(e.to_string()))?;
+    ensure_readable(&storage, &public_key)?;
+    if q.len() > MAX_QUERY_LEN {
+        return Err(ApiError::BadRequest("Query too long".to_string()));
+    }
+    let reader = authenticate(&storage, request.headers())
+        .map(|(_, session)| session)
+        .filter(|session| session.public_key == public_key);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0);
+    let results = index.search(&public_key, &q, reader.as_ref(), limit, offset)?;
+
+    let count = results.len();
+    Ok(Json(SearchResponse { results, count }).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::ROOT_CAPABILITIES;
+    use crate::Server;
+    use pubky_common::Keypair;
+
+    #[tokio::test]
+    async fn test_search() {
+        assert_eq!(content_type("pub/notes/a.MD"), Some("text/markdown"));
+        assert_eq!(content_type("pub/photo.jpg"), None);
+
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .search(SearchConfig::new())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let storage = server.storage();
+        let put = |path: &str, value: &[u8]| {
+            storage
+                .put(public_key, path.to_string(), value.to_vec())
+                .unwrap()
+        };
+        put("pub/notes/rust.md", b"# Rust\n\nOwnership and borrowing");
+        put("pub/notes/go.txt", b"Goroutines, not ownership");
+        put("private/diary.txt", b"Borrowing a book on ownership");
+        put("pub/binary.jpg", b"ownership");
+        put("pub/notes/gone.txt", b"ownership");
+        storage.delete(&public_key, "pub/notes/gone.txt").unwrap();
+
+        let http = reqwest::Client::new();
+        let search = |q: &str, token: Option<&str>| {
+            let url = format!("{}/{}/search", server.url(), public_key);
+            let mut request = http.get(url).query(&[("q", q)]);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            async move {
+                let response = request.send().await.unwrap();
+                assert_eq!(response.status(), 200);
+                let response: SearchResponse = response.json().await.unwrap();
+                let mut paths: Vec<String> = response.results.into_iter().map(|r| r.path).collect();
+                paths.sort();
+                paths
+            }
+        };
+
+        // Indexed in the background
+        let mut paths = search("ownership", None).await;
+        while paths.len() < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            paths = search("ownership", None).await;
+        }
+        assert_eq!(paths, ["pub/notes/go.txt", "pub/notes/rust.md"]);
+        assert_eq!(
+            search("ownership borrowing", None).await,
+            ["pub/notes/rust.md"]
+        );
+
+        // The owner also finds private entries
+        storage.register(public_key);
+        let session = Session {
+            id: "search".to_string(),
+            public_key,
+            device: None,
+            capabilities: ROOT_CAPABILITIES.to_string(),
+            created_at: 0,
+            last_used_at: 0,
+            expires_at: u64::MAX,
+        };
+        storage.insert_session("token".to_string(), session);
+        assert_eq!(
+            search("borrowing", Some("token")).await,
+            ["private/diary.txt", "pub/notes/rust.md"]
+        );
+
+        // App sessions, allowed to read `search` like any entry, only find
+        // the private entries they may read
+        let scoped = |id: &str, capabilities: &str| Session {
+            id: id.to_string(),
+            capabilities: capabilities.to_string(),
+            ..storage.session("token").unwrap()
+        };
+        storage.insert_session(
+            "app".to_string(),
+            scoped("app", "/pub/my-app/:rw,/search:r"),
+        );
+        storage.insert_session(
+            "diary".to_string(),
+            scoped("diary", "/private/:r,/search:r"),
+        );
+        assert_eq!(
+            search("borrowing", Some("app")).await,
+            ["pub/notes/rust.md"]
+        );
+        assert_eq!(
+            search("borrowing", Some("diary")).await,
+            ["private/diary.txt", "pub/notes/rust.md"]
+        );
+
+        // Without a query, `search` is an ordinary entry
+        let url = format!("{}/{}/search", server.url(), public_key);
+        assert_eq!(http.get(&url).send().await.unwrap().status(), 404);
+        let response = http
+            .get(&url)
+            .query(&[("q", "AND (")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        server.shutdown().await;
+    }
+}
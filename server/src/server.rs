@@ -0,0 +1,1846 @@
+//! Embeddable homeserver
+//!
+//! Provides [`Server`] and its [`ServerBuilder`], used both by the `server`
+//! binary and by applications or tests that run a homeserver in-process.
+//!
+//! Applications extend the server without forking it by registering their
+//! own routes with [`ServerBuilder::routes`], middleware with
+//! [`ServerBuilder::layer`] and [`ServerBuilder::storage_layer`], and
+//! background jobs with [`ServerBuilder::background`].
+
+use axum::{
+    extract::{DefaultBodyLimit, Request, State},
+    http::{header, HeaderValue},
+    middleware,
+    response::IntoResponse,
+    routing::{get, Route},
+    Router,
+};
+use futures_util::future::{BoxFuture, FutureExt};
+use pubky_common::PublicKey;
+use std::convert::Infallible;
+use std::future::{Future, IntoFuture};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tower::{Layer, Service};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::trace::TraceLayer;
+
+use crate::activitypub::{self, ActivityPubConfig};
+use crate::admin::AdminState;
+use crate::audit::{self, AuditLog};
+use crate::authorize;
+use crate::backend::StorageBackend;
+use crate::clock::SystemClock;
+use crate::dht::{self, ContentIndex, DhtConfig};
+use crate::domains::{self, DEFAULT_DOH_RESOLVER};
+use crate::events;
+use crate::export::{self, ExportJobs};
+use crate::federation::{self, Federation, FederationConfig};
+use crate::migration;
+use crate::mirror::{Mirror, MirrorConfig};
+use crate::moderation::{self, ModerationHook};
+use crate::pkarr::{self, PkarrConfig, RelayPackets};
+use crate::relay;
+use crate::replica::{self, Replica, ReplicaConfig};
+use crate::scan::{self, ScanConfig, Scanning};
+use crate::session::{self, SessionState};
+use crate::storage::Storage;
+use crate::throttle::{self, ThrottleConfig, WriteThrottle};
+use crate::tunnel::{self, TunnelConfig};
+use crate::uploads::{self, UploadConfig, Uploads};
+use crate::{admin, dev, routes, webfinger, write_auth};
+
+/// Default address the server binds to
+pub const DEFAULT_BIND: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 3000);
+
+/// Largest request body accepted without a [`ServerBuilder::body_limit`],
+/// as in axum
+const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Middleware registered by an application, applied to a router
+type RouterLayer = Box<dyn Fn(Router<Arc<Storage>>) -> Router<Arc<Storage>> + Send + Sync>;
+
+/// Background job registered by an application
+type BackgroundJob = Box<dyn Fn(Arc<Storage>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Builder for configuring and running a [`Server`]
+pub struct ServerBuilder {
+    storage: Option<Arc<Storage>>,
+    backend: Option<Arc<dyn StorageBackend>>,
+    bind: SocketAddr,
+    cors_origins: Vec<HeaderValue>,
+    body_limit: Option<usize>,
+    admin_password: Option<String>,
+    replica: Option<ReplicaConfig>,
+    mirror: Option<Mirror>,
+    dev: bool,
+    audit: Option<Arc<AuditLog>>,
+    throttle: Option<ThrottleConfig>,
+    require_invite: bool,
+    pkarr: Option<PkarrConfig>,
+    pkarr_relay: bool,
+    federation: Option<FederationConfig>,
+    activitypub: Option<ActivityPubConfig>,
+    dns_resolver: String,
+    dht: Option<DhtConfig>,
+    content: ContentIndex,
+    tunnel: Option<TunnelConfig>,
+    tunnel_relay: bool,
+    auth_relay: bool,
+    identity: Option<PublicKey>,
+    /// Identity used when none is configured or announced
+    random_identity: PublicKey,
+    moderation: Option<Arc<dyn ModerationHook>>,
+    scan: Option<ScanConfig>,
+    uploads: Option<UploadConfig>,
+    routes: Vec<Router<Arc<Storage>>>,
+    layers: Vec<RouterLayer>,
+    storage_layers: Vec<RouterLayer>,
+    background: Vec<BackgroundJob>,
+    #[cfg(feature = "grpc")]
+    grpc: Option<crate::GrpcConfig>,
+    #[cfg(feature = "http3")]
+    http3: Option<crate::Http3Config>,
+    #[cfg(feature = "quic-blobs")]
+    blobs: Option<crate::BlobConfig>,
+    #[cfg(feature = "search")]
+    search: Option<Arc<crate::search::SearchIndex>>,
+    #[cfg(feature = "tls")]
+    tls: Option<crate::TlsConfig>,
+}
+
+impl ServerBuilder {
+    /// Use the given storage instead of a fresh, empty one
+    pub fn storage(mut self, storage: Arc<Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Keep entries in `backend` instead of memory
+    ///
+    /// Ignored when a storage is given with [`ServerBuilder::storage`].
+    pub fn backend(mut self, backend: Arc<dyn StorageBackend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Set the address to listen on (use port 0 for a random free port)
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = addr;
+        self
+    }
+
+    /// Only allow cross-origin requests from these origins, such as
+    /// `https://app.example`, instead of from any
+    ///
+    /// # Panics
+    ///
+    /// If an origin isn't a valid header value.
+    pub fn cors_origins(mut self, origins: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        self.cors_origins = origins
+            .into_iter()
+            .map(|origin| HeaderValue::from_str(origin.as_ref()).expect("valid CORS origin"))
+            .collect();
+        self
+    }
+
+    /// Reject request bodies larger than `bytes`, instead of 2 MiB
+    pub fn body_limit(mut self, bytes: usize) -> Self {
+        self.body_limit = Some(bytes);
+        self
+    }
+
+    /// Enable the admin API under `/admin`, protected by the given password
+    pub fn admin_password(mut self, password: impl Into<String>) -> Self {
+        self.admin_password = Some(password.into());
+        self
+    }
+
+    /// Run as a read replica of another server
+    ///
+    /// Reads are served from a local copy refreshed from the primary, and
+    /// writes are forwarded to the primary. Give the replica the primary's
+    /// [`identity`](Self::identity), so signed writes verify on both.
+    pub fn replica_of(mut self, config: ReplicaConfig) -> Self {
+        self.replica = Some(config);
+        self
+    }
+
+    /// Asynchronously replicate every mutation to a secondary server
+    ///
+    /// The secondary must have the admin API enabled with the configured
+    /// password. Replication lag is reported by `GET /admin/replication`.
+    pub fn mirror_to(mut self, config: MirrorConfig) -> Self {
+        self.mirror = Some(Mirror::new(config));
+        self
+    }
+
+    /// Enable developer mode
+    ///
+    /// Seeds storage with the well-known [`dev::users`] and sample entries,
+    /// and enables the admin API with [`dev::DEV_ADMIN_PASSWORD`] unless an
+    /// admin password is configured.
+    pub fn dev(mut self, enabled: bool) -> Self {
+        self.dev = enabled;
+        self
+    }
+
+    /// Record mutating requests in the given audit log
+    ///
+    /// Records can be queried through `GET /admin/audit`.
+    pub fn audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Limit the write throughput of each public key
+    ///
+    /// Throttled writes are rejected with `429 Too Many Requests` and a
+    /// `Retry-After` header.
+    pub fn write_throttle(mut self, config: ThrottleConfig) -> Self {
+        self.throttle = Some(config);
+        self
+    }
+
+    /// Only accept signups carrying an invite code created by an admin
+    ///
+    /// Accounts that already signed up can still sign in.
+    pub fn require_invite(mut self, required: bool) -> Self {
+        self.require_invite = required;
+        self
+    }
+
+    /// Announce the server over pkarr with the configured keypair
+    ///
+    /// The signed record is published to the configured relays on start and
+    /// then on an interval, so clients can discover the server from its
+    /// public key.
+    pub fn pkarr(mut self, config: PkarrConfig) -> Self {
+        self.pkarr = Some(config);
+        self
+    }
+
+    /// Identify the server by `public_key`, which clients address their
+    /// signed auth tokens and writes to
+    ///
+    /// Defaults to the keypair announced over pkarr or reachable through a
+    /// tunnel, and otherwise to a random key, new on every start.
+    pub fn identity(mut self, public_key: PublicKey) -> Self {
+        self.identity = Some(public_key);
+        self
+    }
+
+    /// Act as a pkarr relay under `/pkarr`, keeping packets in memory
+    ///
+    /// Packets are not forwarded to the DHT: this is meant for testnets and
+    /// private networks.
+    pub fn pkarr_relay(mut self, enabled: bool) -> Self {
+        self.pkarr_relay = enabled;
+        self
+    }
+
+    /// Serve reads for public keys this server doesn't host from their own
+    /// homeserver, found through pkarr
+    pub fn federate(mut self, config: FederationConfig) -> Self {
+        self.federation = Some(config);
+        self
+    }
+
+    /// Look up the TXT records proving domain aliases through this
+    /// DNS-over-HTTPS resolver, speaking the JSON API, instead of
+    /// [`DEFAULT_DOH_RESOLVER`]
+    pub fn dns_resolver(mut self, url: impl Into<String>) -> Self {
+        self.dns_resolver = url.into();
+        self
+    }
+
+    /// Expose the posts of users, the entries under `pub/posts/`, as
+    /// read-only ActivityPub actors found through WebFinger
+    pub fn activitypub(mut self, config: ActivityPubConfig) -> Self {
+        self.activitypub = Some(config);
+        self
+    }
+
+    /// Announce the content hashes of the entries under `config.prefix` on
+    /// the Mainline DHT, and serve them from `GET /content/{hash}`
+    ///
+    /// Only use a prefix holding public data, such as `pub/`: anyone can
+    /// look up what is announced.
+    pub fn dht(mut self, config: DhtConfig) -> Self {
+        self.dht = Some(config);
+        self
+    }
+
+    /// Stay reachable from behind NAT through a tunnel to a relay
+    ///
+    /// The server keeps polling the relay for the requests clients send to
+    /// [`TunnelConfig::public_url`].
+    pub fn tunnel(mut self, config: TunnelConfig) -> Self {
+        self.tunnel = Some(config);
+        self
+    }
+
+    /// Relay requests under `/tunnel/{public_key}` to the homeserver with
+    /// that public key, if it opened a tunnel
+    pub fn tunnel_relay(mut self, enabled: bool) -> Self {
+        self.tunnel_relay = enabled;
+        self
+    }
+
+    /// Relay signed grants under `/relay` to apps on other origins that
+    /// request sign-in from a user's key holder
+    pub fn auth_relay(mut self, enabled: bool) -> Self {
+        self.auth_relay = enabled;
+        self
+    }
+
+    /// Also serve HTTP/3 on the UDP port of the same number as the TCP
+    /// listener, advertised with an `Alt-Svc` header
+    /// Judge public writes, and optionally reads, with a moderation hook
+    ///
+    /// Quarantined content is listed by `GET /admin/quarantine`.
+    pub fn moderation(mut self, hook: Arc<dyn ModerationHook>) -> Self {
+        self.moderation = Some(hook);
+        self
+    }
+
+    /// Hold large public writes back until they pass a content scan
+    ///
+    /// Pending content is listed by `GET /admin/quarantine`.
+    pub fn scan_uploads(mut self, config: ScanConfig) -> Self {
+        self.scan = Some(config);
+        self
+    }
+
+    /// Accept resumable uploads under `/uploads`, keeping their bytes in
+    /// the configured directory until complete
+    pub fn uploads(mut self, config: UploadConfig) -> Self {
+        self.uploads = Some(config);
+        self
+    }
+
+    /// Serve extra routes next to the built-in ones
+    ///
+    /// Handlers can extract the server's `State<Arc<Storage>>`. Routes
+    /// overlapping built-in ones make the server panic when it starts.
+    pub fn routes(mut self, routes: Router<Arc<Storage>>) -> Self {
+        self.routes.push(routes);
+        self
+    }
+
+    /// Wrap every route, including extra ones, in a middleware layer
+    ///
+    /// Layers registered later wrap the ones registered before.
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request, Error = Infallible> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.layers
+            .push(Box::new(move |router| router.layer(layer.clone())));
+        self
+    }
+
+    /// Wrap the user data routes under `/{public_key}` in a middleware
+    /// layer, run after the matched route is known
+    ///
+    /// These layers see requests after accounts that moved away are
+    /// redirected, and before any built-in processing such as federation
+    /// or moderation.
+    pub fn storage_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request, Error = Infallible> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.storage_layers
+            .push(Box::new(move |router| router.route_layer(layer.clone())));
+        self
+    }
+
+    /// Run a job in the background for as long as the server runs
+    ///
+    /// The job is started with the server's storage, and aborted when the
+    /// server shuts down.
+    pub fn background<F, Fut>(mut self, job: F) -> Self
+    where
+        F: Fn(Arc<Storage>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.background
+            .push(Box::new(move |storage| job(storage).boxed()));
+        self
+    }
+
+    /// Also serve the API of `proto/pubky.proto` over gRPC at the
+    /// configured address
+    #[cfg(feature = "grpc")]
+    pub fn grpc(mut self, config: crate::GrpcConfig) -> Self {
+        self.grpc = Some(config);
+        self
+    }
+
+    #[cfg(feature = "http3")]
+    pub fn http3(mut self, config: crate::Http3Config) -> Self {
+        self.http3 = Some(config);
+        self
+    }
+
+    /// Also transfer large entries over raw QUIC streams at the configured
+    /// address
+    #[cfg(feature = "quic-blobs")]
+    pub fn blob_transfer(mut self, config: crate::BlobConfig) -> Self {
+        self.blobs = Some(config);
+        self
+    }
+
+    /// Index text entries for full-text search, answered by
+    /// `GET /{public_key}/search?q=`
+    #[cfg(feature = "search")]
+    pub fn search(mut self, config: crate::SearchConfig) -> Self {
+        let index = crate::search::SearchIndex::new(config).expect("in-memory indexes open");
+        self.search = Some(Arc::new(index));
+        self
+    }
+
+    /// Listen on HTTPS with the configured identity instead of plain HTTP
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: crate::TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Build the application router without binding a listener
+    pub fn router(mut self) -> Router {
+        let storage = self.prepare_storage();
+        self.build_router(storage, CancellationToken::new())
+    }
+
+    /// Bind the listener and serve requests until the server fails
+    pub async fn run(mut self) -> io::Result<()> {
+        let storage = self.prepare_storage();
+        let listener = tokio::net::TcpListener::bind(self.bind).await?;
+        let local_addr = listener.local_addr()?;
+        let scheme = self.scheme();
+
+        tracing::info!("Server listening on {}://{}", scheme, local_addr);
+        tracing::info!(
+            "Example: PUT {}://{}/<public_key>/my-app/data.txt",
+            scheme,
+            local_addr
+        );
+
+        // Advertise the bound port rather than 0
+        self.bind = local_addr;
+        let mut _background = self.spawn_background(&storage, local_addr);
+        _background.extend(self.spawn_blobs(&storage)?.map(|(task, _)| task));
+        let app = self.build_router(storage, CancellationToken::new());
+        _background.extend(self.spawn_http3(local_addr, &app)?);
+        _background.extend(self.spawn_grpc(&app).await?.map(|(task, _)| task));
+        _background.extend(self.spawn_redirect(local_addr).await?.map(|(task, _)| task));
+        self.serve(listener, app, std::future::pending())?.await
+    }
+
+    /// Bind the listener and serve requests in a background task
+    ///
+    /// The returned [`Server`] handle stops the server when dropped.
+    pub async fn start(mut self) -> io::Result<Server> {
+        let storage = self.prepare_storage();
+        let listener = tokio::net::TcpListener::bind(self.bind).await?;
+        let local_addr = listener.local_addr()?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        self.bind = local_addr;
+        let mut background = self.spawn_background(&storage, local_addr);
+
+        // Long-lived responses like change feeds end when this is cancelled,
+        // so graceful shutdown doesn't wait for them
+        let closing = CancellationToken::new();
+        let app = self.build_router(storage.clone(), closing.clone());
+        background.extend(self.spawn_http3(local_addr, &app)?);
+        let blobs = self.spawn_blobs(&storage)?;
+        let blob_addr = blobs.as_ref().map(|(_, addr)| *addr);
+        background.extend(blobs.map(|(task, _)| task));
+        let grpc = self.spawn_grpc(&app).await?;
+        let grpc_addr = grpc.as_ref().map(|(_, addr)| *addr);
+        background.extend(grpc.map(|(task, _)| task));
+        let redirect = self.spawn_redirect(local_addr).await?;
+        let redirect_addr = redirect.as_ref().map(|(_, addr)| *addr);
+        background.extend(redirect.map(|(task, _)| task));
+        let serve = self.serve(listener, app, async {
+            let _ = shutdown_rx.await;
+        })?;
+
+        let task = tokio::spawn(async move {
+            if let Err(e) = serve.await {
+                tracing::error!("Server error: {}", e);
+            }
+        });
+
+        tracing::info!("Server listening on {}://{}", self.scheme(), local_addr);
+
+        Ok(Server {
+            local_addr,
+            scheme: self.scheme(),
+            storage,
+            public_key: self.public_key(),
+            identity: self.identity_key(),
+            closing,
+            blob_addr,
+            grpc_addr,
+            redirect_addr,
+            shutdown: Some(shutdown_tx),
+            task: Some(task),
+            background,
+        })
+    }
+
+    /// Serve `app` on `listener`, over TLS if configured, until `shutdown`
+    /// completes
+    fn serve(
+        &self,
+        listener: tokio::net::TcpListener,
+        app: Router,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> io::Result<BoxFuture<'static, io::Result<()>>> {
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            use axum::serve::ListenerExt;
+            // Tapping gives connections of any listener their remote address
+            let listener = crate::https::TlsListener::new(listener, &config.certificate)?;
+            let serve = axum::serve(listener.tap_io(|_| {}), app);
+            return Ok(serve.with_graceful_shutdown(shutdown).into_future().boxed());
+        }
+        let serve = axum::serve(listener, app);
+        Ok(serve.with_graceful_shutdown(shutdown).into_future().boxed())
+    }
+
+    /// Scheme of the server's URLs
+    fn scheme(&self) -> &'static str {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return "https";
+        }
+        "http"
+    }
+
+    /// Redirect plain HTTP to the HTTPS listener on `local_addr`, if
+    /// configured, returning the task and the bound address
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    async fn spawn_redirect(
+        &self,
+        local_addr: SocketAddr,
+    ) -> io::Result<Option<(JoinHandle<()>, SocketAddr)>> {
+        #[cfg(feature = "tls")]
+        if let Some(addr) = self.tls.as_ref().and_then(|config| config.redirect_from) {
+            return crate::https::spawn_redirect(addr, local_addr.port())
+                .await
+                .map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Create the storage if none was given and apply developer mode
+    fn prepare_storage(&mut self) -> Arc<Storage> {
+        let backend = self.backend.clone();
+        let storage = self
+            .storage
+            .get_or_insert_with(|| match backend {
+                Some(backend) => Arc::new(Storage::with_backend(backend, Arc::new(SystemClock))),
+                None => Arc::new(Storage::new()),
+            })
+            .clone();
+
+        if self.dev {
+            if self.admin_password.is_none() {
+                self.admin_password = Some(dev::DEV_ADMIN_PASSWORD.to_string());
+            }
+            if storage.users().is_empty() {
+                if let Err(e) = dev::seed(&storage) {
+                    tracing::error!("Failed to seed the developer identities: {}", e);
+                }
+            }
+        }
+
+        storage
+    }
+
+    /// Spawn background tasks, such as replica sync, for the given storage
+    fn spawn_background(
+        &self,
+        storage: &Arc<Storage>,
+        local_addr: SocketAddr,
+    ) -> Vec<JoinHandle<()>> {
+        let mut tasks = Vec::new();
+
+        if let Some(config) = &self.replica {
+            tracing::info!("Running as read replica of {}", config.primary_url);
+            let replica = Replica::new(config.clone());
+            tasks.push(tokio::spawn(replica.sync_loop(storage.clone())));
+        }
+
+        if let Some(mirror) = &self.mirror {
+            tracing::info!("Mirroring mutations to {}", mirror.url());
+            tasks.push(tokio::spawn(mirror.clone().run(storage.clone())));
+        }
+
+        if let Some(config) = &self.pkarr {
+            tracing::info!("Announcing as {} over pkarr", config.keypair.public_key());
+            tasks.push(tokio::spawn(pkarr::publish_loop(
+                config.clone(),
+                local_addr.port(),
+            )));
+        }
+
+        if let Some(config) = &self.tunnel {
+            tracing::info!("Opening a tunnel to {}", config.relay_url);
+            tasks.push(tokio::spawn(tunnel::tunnel_loop(
+                config.clone(),
+                local_addr,
+            )));
+        }
+
+        if let Some(config) = &self.dht {
+            tracing::info!("Announcing entries under {} on the DHT", config.prefix);
+            tasks.push(tokio::spawn(dht::announce_loop(
+                config.clone(),
+                storage.clone(),
+                self.content.clone(),
+                local_addr.port(),
+            )));
+        }
+
+        #[cfg(feature = "search")]
+        if let Some(index) = &self.search {
+            tasks.push(tokio::spawn(crate::search::index_loop(
+                index.clone(),
+                storage.clone(),
+            )));
+        }
+
+        for job in &self.background {
+            tasks.push(tokio::spawn(job(storage.clone())));
+        }
+
+        tasks
+    }
+
+    /// Serve `app` over HTTP/3 next to the TCP listener at `local_addr`, if
+    /// configured
+    #[cfg_attr(not(feature = "http3"), allow(unused_variables))]
+    fn spawn_http3(
+        &self,
+        local_addr: SocketAddr,
+        app: &Router,
+    ) -> io::Result<Option<JoinHandle<()>>> {
+        #[cfg(feature = "http3")]
+        if let Some(config) = &self.http3 {
+            return crate::http3::spawn(config, local_addr, app.clone()).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Serve blob transfers over QUIC, if configured, returning the task
+    /// and the bound address
+    #[cfg_attr(not(feature = "quic-blobs"), allow(unused_variables))]
+    fn spawn_blobs(
+        &self,
+        storage: &Arc<Storage>,
+    ) -> io::Result<Option<(JoinHandle<()>, SocketAddr)>> {
+        #[cfg(feature = "quic-blobs")]
+        if let Some(config) = &self.blobs {
+            return crate::blobs::spawn(config, storage.clone()).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Serve `app` over gRPC, if configured, returning the task and the
+    /// bound address
+    #[cfg_attr(not(feature = "grpc"), allow(unused_variables))]
+    async fn spawn_grpc(&self, app: &Router) -> io::Result<Option<(JoinHandle<()>, SocketAddr)>> {
+        #[cfg(feature = "grpc")]
+        if let Some(config) = &self.grpc {
+            return crate::grpc::spawn(config, app.clone()).await.map(Some);
+        }
+        Ok(None)
+    }
+
+    /// The public key the server announces over pkarr or is reachable by
+    /// through a tunnel, if configured
+    fn public_key(&self) -> Option<PublicKey> {
+        self.pkarr
+            .as_ref()
+            .map(|c| c.keypair.public_key())
+            .or(self.tunnel.as_ref().map(|c| c.keypair.public_key()))
+    }
+
+    /// The largest request body accepted, in bytes
+    fn max_body_len(&self) -> usize {
+        self.body_limit.unwrap_or(DEFAULT_BODY_LIMIT)
+    }
+
+    /// The public key clients address signed messages to
+    fn identity_key(&self) -> PublicKey {
+        self.identity
+            .or(self.public_key())
+            .unwrap_or(self.random_identity)
+    }
+
+    /// Build the application router around the given storage
+    fn build_router(&self, storage: Arc<Storage>, closing: CancellationToken) -> Router {
+        // Configure CORS
+        let origins = match self.cors_origins.is_empty() {
+            true => AllowOrigin::any(),
+            false => AllowOrigin::list(self.cors_origins.clone()),
+        };
+        let cors = CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(Any)
+            .allow_headers(Any)
+            // Metadata headers have names of the apps' choosing
+            .expose_headers(Any);
+
+        let mut storage_routes = routes::storage_routes();
+        // Scans start once moderation accepted the content
+        if let Some(config) = &self.scan {
+            storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+                Scanning::new(config.clone(), storage.clone()),
+                scan::scan_uploads,
+            ));
+        }
+
+        if let Some(hook) = &self.moderation {
+            storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+                (hook.clone(), storage.clone(), self.max_body_len()),
+                moderation::moderate,
+            ));
+        }
+
+        if let Some(config) = self.throttle {
+            storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+                Arc::new(WriteThrottle::new(config, storage.clock().clone())),
+                throttle::throttle_writes,
+            ));
+        }
+
+        if let Some(audit) = &self.audit {
+            storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+                audit.clone(),
+                audit::record_requests,
+            ));
+        }
+
+        #[cfg(feature = "search")]
+        if let Some(index) = &self.search {
+            storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+                (index.clone(), storage.clone()),
+                crate::search::serve_search,
+            ));
+        }
+
+        // Reads for users hosted elsewhere, including searches, are served
+        // by their homeserver
+        if let Some(config) = &self.federation {
+            storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+                Arc::new(Federation::new(config.clone(), storage.clone())),
+                federation::serve_foreign,
+            ));
+        }
+
+        // Only owners may write, so nothing else sees unauthorized writes
+        storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+            (storage.clone(), self.identity_key()),
+            write_auth::authorize_access,
+        ));
+
+        // Replicas don't hold the primary's sessions, so they forward writes
+        // for the primary to authorize
+        if let Some(config) = &self.replica {
+            storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+                Replica::new(config.clone()),
+                replica::forward_writes,
+            ));
+        }
+
+        for layer in &self.storage_layers {
+            storage_routes = layer(storage_routes);
+        }
+
+        // Accounts that moved away are redirected before anything else
+        storage_routes = storage_routes.route_layer(middleware::from_fn_with_state(
+            storage.clone(),
+            migration::redirect_moved,
+        ));
+
+        // Completed uploads are written through the storage routes, whole
+        let uploads = self.uploads.as_ref().map(|config| {
+            let writes = Router::new()
+                .nest("/{public_key}", storage_routes.clone())
+                .layer(DefaultBodyLimit::disable())
+                .with_state(storage.clone());
+            Uploads::new(config.clone(), storage.clone(), writes)
+        });
+
+        let mut router = Router::new()
+            .route("/", get(|| async { "Pubky MVP Server" }))
+            .route("/metrics", get(metrics))
+            .merge(session::session_routes(SessionState {
+                storage: storage.clone(),
+                require_invite: self.require_invite,
+                homeserver: self.public_key(),
+                identity: self.identity_key(),
+            }))
+            .nest(
+                "/auth",
+                authorize::authorize_routes(SessionState {
+                    storage: storage.clone(),
+                    require_invite: self.require_invite,
+                    homeserver: self.public_key(),
+                    identity: self.identity_key(),
+                }),
+            )
+            .nest(
+                "/events",
+                events::event_routes(storage.clone(), closing.clone()),
+            )
+            .nest("/migrations", migration::migration_routes(storage.clone()))
+            .nest(
+                "/exports",
+                export::export_routes(storage.clone(), Arc::new(ExportJobs::default())),
+            )
+            .merge(webfinger::webfinger_routes(
+                storage.clone(),
+                self.activitypub.clone(),
+            ))
+            .merge(domains::domain_routes(storage.clone(), &self.dns_resolver))
+            .nest("/{public_key}", storage_routes);
+
+        if let Some(uploads) = uploads {
+            router = router.nest("/uploads", uploads::upload_routes(uploads));
+        }
+
+        for routes in &self.routes {
+            router = router.merge(routes.clone());
+        }
+
+        #[cfg(feature = "multi-alg")]
+        {
+            router = router.nest("/nostr", crate::nostr::nostr_routes(storage.clone()));
+        }
+
+        if let Some(config) = &self.activitypub {
+            router = router.merge(activitypub::activitypub_routes(
+                storage.clone(),
+                config.clone(),
+            ));
+        }
+
+        if self.dht.is_some() {
+            router = router.merge(dht::content_routes(storage.clone(), self.content.clone()));
+        }
+
+        if self.tunnel_relay {
+            router = router.nest(
+                "/tunnel",
+                tunnel::relay_routes(storage.clone(), self.identity_key(), closing.clone()),
+            );
+        }
+
+        if self.auth_relay {
+            router = router.nest(
+                "/relay",
+                relay::relay_routes(storage.clock().clone(), closing),
+            );
+        }
+
+        if self.pkarr_relay {
+            router = router.nest("/pkarr", pkarr::relay_routes(RelayPackets::default()));
+        }
+
+        if let Some(password) = &self.admin_password {
+            let mut state = AdminState::new(storage.clone(), password);
+            if let Some(mirror) = &self.mirror {
+                state.replication = mirror.status.clone();
+            }
+            state.audit = self.audit.clone();
+            router = router.nest("/admin", admin::admin_routes(state));
+        }
+
+        #[cfg(feature = "http3")]
+        if let Some(config) = &self.http3 {
+            let alt_svc = axum::http::HeaderValue::from_str(&config.alt_svc(self.bind.port()))
+                .expect("Alt-Svc values are valid headers");
+            router = router.layer(middleware::map_response(
+                move |mut response: axum::response::Response| {
+                    response
+                        .headers_mut()
+                        .insert(header::ALT_SVC, alt_svc.clone());
+                    async move { response }
+                },
+            ));
+        }
+
+        for layer in &self.layers {
+            router = layer(router);
+        }
+
+        if let Some(bytes) = self.body_limit {
+            router = router.layer(DefaultBodyLimit::max(bytes));
+        }
+
+        router
+            .layer(cors)
+            .layer(TraceLayer::new_for_http())
+            .with_state(storage)
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            storage: None,
+            backend: None,
+            bind: DEFAULT_BIND,
+            cors_origins: Vec::new(),
+            body_limit: None,
+            admin_password: None,
+            replica: None,
+            mirror: None,
+            dev: false,
+            audit: None,
+            throttle: None,
+            require_invite: false,
+            pkarr: None,
+            pkarr_relay: false,
+            federation: None,
+            activitypub: None,
+            dns_resolver: DEFAULT_DOH_RESOLVER.to_string(),
+            dht: None,
+            content: ContentIndex::default(),
+            tunnel: None,
+            tunnel_relay: false,
+            auth_relay: false,
+            identity: None,
+            random_identity: pubky_common::Keypair::random().public_key(),
+            moderation: None,
+            scan: None,
+            uploads: None,
+            routes: Vec::new(),
+            layers: Vec::new(),
+            storage_layers: Vec::new(),
+            background: Vec::new(),
+            #[cfg(feature = "grpc")]
+            grpc: None,
+            #[cfg(feature = "http3")]
+            http3: None,
+            #[cfg(feature = "quic-blobs")]
+            blobs: None,
+            #[cfg(feature = "search")]
+            search: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// A homeserver running in a background task
+pub struct Server {
+    local_addr: SocketAddr,
+    scheme: &'static str,
+    storage: Arc<Storage>,
+    public_key: Option<PublicKey>,
+    identity: PublicKey,
+    closing: CancellationToken,
+    blob_addr: Option<SocketAddr>,
+    grpc_addr: Option<SocketAddr>,
+    redirect_addr: Option<SocketAddr>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+    background: Vec<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Create a builder with default settings
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The base URL of the server, without a trailing slash
+    pub fn url(&self) -> String {
+        format!("{}://{}", self.scheme, self.local_addr)
+    }
+
+    /// The public key the server announces over pkarr or is reachable by
+    /// through a tunnel, if configured
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.public_key
+    }
+
+    /// The public key clients address signed messages to, as returned by
+    /// `GET /identity`
+    pub fn identity(&self) -> PublicKey {
+        self.identity
+    }
+
+    /// The UDP address of the blob transfer endpoint, if configured
+    pub fn blob_addr(&self) -> Option<SocketAddr> {
+        self.blob_addr
+    }
+
+    /// The address of the gRPC endpoint, if configured
+    pub fn grpc_addr(&self) -> Option<SocketAddr> {
+        self.grpc_addr
+    }
+
+    /// The address redirecting plain HTTP to HTTPS, if configured
+    pub fn redirect_addr(&self) -> Option<SocketAddr> {
+        self.redirect_addr
+    }
+
+    /// The storage backing this server
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    /// Gracefully stop the server and wait for it to finish
+    pub async fn shutdown(mut self) {
+        for task in self.background.drain(..) {
+            task.abort();
+        }
+        self.closing.cancel();
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for task in &self.background {
+            task.abort();
+        }
+        self.closing.cancel();
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+/// GET /metrics
+/// Storage metrics in the Prometheus text format
+async fn metrics(State(storage): State<Arc<Storage>>) -> impl IntoResponse {
+    let mut body = String::new();
+    storage.metrics().render_prometheus(&mut body);
+    crate::memory::render_prometheus(&storage, &mut body);
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_auth::SignWrite;
+    use pubky_common::Keypair;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn http_get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, addr
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_embedded_server() {
+        let storage = Arc::new(Storage::new());
+        let public_key = Keypair::random().public_key();
+        storage
+            .put(public_key, "app/hello.txt".to_string(), b"Hello!".to_vec())
+            .unwrap();
+
+        let server = Server::builder()
+            .storage(storage.clone())
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+
+        assert_ne!(server.local_addr().port(), 0);
+        assert!(Arc::ptr_eq(server.storage(), &storage));
+
+        let response = http_get(server.local_addr(), "/").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("Pubky MVP Server"));
+
+        let path = format!("/{}/app/hello.txt", public_key);
+        let response = http_get(server.local_addr(), &path).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("Hello!"));
+
+        // Addresses are validated like pubky:// URLs
+        for path in [
+            "/nope/app/hello.txt",
+            "/{}/app/%2E%2E/hello.txt",
+            "/{}/app//hello.txt",
+        ] {
+            let path = path.replace("{}", &public_key.to_z32());
+            let response = http_get(server.local_addr(), &path).await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", path);
+        }
+
+        // Auth tokens are signed for the server's identity
+        let response = http_get(server.local_addr(), "/identity").await;
+        assert!(response.ends_with(&server.identity().to_z32()));
+        server.shutdown().await;
+
+        let identity = Keypair::random().public_key();
+        let server = Server::builder()
+            .identity(identity)
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(server.identity(), identity);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_body_limit_and_cors_origins() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .body_limit(8)
+            .cors_origins(["https://app.example"])
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let url = format!("{}/{}/app/file.txt", server.url(), keypair.public_key());
+        let client = reqwest::Client::new();
+
+        let put = |body: &'static str| {
+            client
+                .put(&url)
+                .body(body)
+                .signed(&keypair, &server.identity())
+                .send()
+        };
+        assert_eq!(put("12345678").await.unwrap().status(), 201);
+        assert_eq!(put("123456789").await.unwrap().status(), 413);
+
+        // Only the configured origins are allowed
+        let allowed = |origin: &'static str| {
+            let request = client.get(&url).header(header::ORIGIN, origin).send();
+            async move {
+                let response = request.await.unwrap();
+                response
+                    .headers()
+                    .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            }
+        };
+        assert!(allowed("https://app.example").await);
+        assert!(!allowed("https://evil.example").await);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_content_type() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let url = format!("{}/{}/pub/avatar.png", server.url(), keypair.public_key());
+        let client = reqwest::Client::new();
+        let put = |content_type: Option<&str>| {
+            let mut request = client.put(&url).body("png");
+            if let Some(content_type) = content_type {
+                request = request.header(header::CONTENT_TYPE, content_type);
+            }
+            request.signed(&keypair, &server.identity()).send()
+        };
+
+        assert_eq!(put(Some("image/png")).await.unwrap().status(), 201);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let response = client
+            .get(&url)
+            .header(header::RANGE, "bytes=0-0")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let list: pubky_common::dto::ListResponse = client
+            .get(format!(
+                "{}/{}/pub/?details=true",
+                server.url(),
+                keypair.public_key()
+            ))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let content_type = list.entries.unwrap()[0].content_type.clone();
+        assert_eq!(content_type.as_deref(), Some("image/png"));
+
+        // The type belongs to the value it was written with
+        put(None).await.unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+
+        let too_long = format!("text/{}", "x".repeat(256));
+        assert_eq!(put(Some(&too_long)).await.unwrap().status(), 400);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let url = format!("{}/{}/pub/avatar.png", server.url(), keypair.public_key());
+        let client = reqwest::Client::new();
+
+        assert_eq!(client.head(&url).send().await.unwrap().status(), 404);
+        let response = client
+            .put(&url)
+            .header(header::CONTENT_TYPE, "image/png")
+            .body("png data")
+            .signed(&keypair, &server.identity())
+            .send()
+            .await
+            .unwrap();
+        let etag = response.headers()[header::ETAG].clone();
+
+        let response = client.head(&url).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "8");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(response.headers()[header::ETAG], etag);
+        assert!(response.bytes().await.unwrap().is_empty());
+
+        let response = client
+            .head(&url)
+            .header(header::IF_NONE_MATCH, etag)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 304);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_custom_metadata() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let url = format!("{}/{}/pub/files/a.enc", server.url(), keypair.public_key());
+        let client = reqwest::Client::new();
+        let put = |fields: &[(&str, &str)]| {
+            let mut request = client.put(&url).body("sealed");
+            for (name, value) in fields {
+                request = request.header(*name, *value);
+            }
+            request.signed(&keypair, &server.identity()).send()
+        };
+
+        let fields = [
+            ("X-Pubky-Meta-Filename", "holiday photo.jpg"),
+            ("x-pubky-meta-enc.nonce", "q83v"),
+        ];
+        assert_eq!(put(&fields).await.unwrap().status(), 201);
+        let response = client.get(&url).send().await.unwrap();
+        let filename = &response.headers()["x-pubky-meta-filename"];
+        assert_eq!(filename, "holiday photo.jpg");
+        assert_eq!(response.headers()["x-pubky-meta-enc.nonce"], "q83v");
+
+        let list: pubky_common::dto::ListResponse = client
+            .get(format!(
+                "{}/{}/pub/files/?details=true",
+                server.url(),
+                keypair.public_key()
+            ))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let meta = &list.entries.unwrap()[0].meta;
+        assert_eq!(meta["filename"], "holiday photo.jpg");
+        assert_eq!(meta["enc.nonce"], "q83v");
+
+        // Metadata belongs to the value it was written with
+        put(&[]).await.unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        assert!(!response.headers().contains_key("x-pubky-meta-filename"));
+
+        let names: Vec<_> = (0..17).map(|i| format!("x-pubky-meta-f{}", i)).collect();
+        let too_many: Vec<_> = names.iter().map(|name| (name.as_str(), "x")).collect();
+        assert_eq!(put(&too_many).await.unwrap().status(), 400);
+        let response = put(&[("x-pubky-meta-place", "Zürich")]).await.unwrap();
+        assert_eq!(response.status(), 400);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_read_replica() {
+        let primary = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .admin_password("secret")
+            .start()
+            .await
+            .unwrap();
+
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        primary
+            .storage()
+            .put(public_key, "app/existing.txt".to_string(), b"old".to_vec())
+            .unwrap();
+
+        let mut config = ReplicaConfig::new(primary.url(), "secret");
+        config.sync_interval = std::time::Duration::from_millis(50);
+        let replica = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .replica_of(config)
+            .identity(primary.identity())
+            .start()
+            .await
+            .unwrap();
+
+        // Writes to the replica land on the primary and are readable locally
+        let url = format!("{}/{}/app/new.txt", replica.url(), public_key);
+        let response = reqwest::Client::new()
+            .put(&url)
+            .body("new")
+            .signed(&keypair, &replica.identity())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(
+            primary.storage().get(&public_key, "app/new.txt").unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(
+            replica.storage().get(&public_key, "app/new.txt").unwrap(),
+            Some(b"new".to_vec())
+        );
+
+        // Sessions of the primary write through the replica, within their
+        // capabilities
+        let session = crate::Session {
+            id: "app".to_string(),
+            public_key,
+            device: None,
+            capabilities: "/app/:rw".to_string(),
+            created_at: 0,
+            last_used_at: 0,
+            expires_at: u64::MAX,
+        };
+        primary
+            .storage()
+            .insert_session("app-token".to_string(), session);
+        let put = |path: &str| {
+            reqwest::Client::new()
+                .put(format!("{}/{}/{}", replica.url(), public_key, path))
+                .bearer_auth("app-token")
+                .body("session")
+                .send()
+        };
+        assert_eq!(put("app/session.txt").await.unwrap().status(), 201);
+        assert_eq!(
+            replica
+                .storage()
+                .get(&public_key, "app/session.txt")
+                .unwrap(),
+            Some(b"session".to_vec())
+        );
+        assert_eq!(put("other/session.txt").await.unwrap().status(), 403);
+        assert!(primary
+            .storage()
+            .get(&public_key, "other/session.txt")
+            .unwrap()
+            .is_none());
+        assert!(replica
+            .storage()
+            .get(&public_key, "other/session.txt")
+            .unwrap()
+            .is_none());
+
+        // Data written directly to the primary shows up after a sync
+        let mut synced = false;
+        for _ in 0..50 {
+            if replica
+                .storage()
+                .get(&public_key, "app/existing.txt")
+                .unwrap()
+                .is_some()
+            {
+                synced = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(synced);
+
+        // Only the entries that differ are synced, deletions included
+        primary
+            .storage()
+            .delete(&public_key, "app/existing.txt")
+            .unwrap();
+        let replica_state = Replica::new(ReplicaConfig::new(primary.url(), "secret"));
+        let storage = Storage::new();
+        storage
+            .put(public_key, "app/new.txt".to_string(), b"new".to_vec())
+            .unwrap();
+        storage
+            .put(public_key, "app/stale.txt".to_string(), b"stale".to_vec())
+            .unwrap();
+        let path = "app/session.txt".to_string();
+        storage.put(public_key, path, b"session".to_vec()).unwrap();
+        assert_eq!(replica_state.sync_once(&storage).await.unwrap(), 1);
+        assert_eq!(storage.entries(), primary.storage().entries());
+
+        // Entries whose metadata differs are synced with it
+        let meta = crate::storage::EntryMeta {
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        primary
+            .storage()
+            .put_with_meta(
+                public_key,
+                "app/new.txt".to_string(),
+                b"new".to_vec(),
+                meta.clone(),
+            )
+            .unwrap();
+        assert_eq!(replica_state.sync_once(&storage).await.unwrap(), 1);
+        assert_eq!(storage.meta(&public_key, "app/new.txt").unwrap(), meta);
+
+        replica.shutdown().await;
+        primary.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .start()
+            .await
+            .unwrap();
+        let public_key = Keypair::random().public_key();
+        let storage = server.storage();
+        let mut paths = vec!["app/a".to_string(), "app/z".to_string()];
+        paths.extend((0..150).map(|i| format!("app/dir/{:03}", i)));
+        for path in &paths {
+            storage
+                .put(public_key, path.clone(), b"x".to_vec())
+                .unwrap();
+        }
+
+        let client = reqwest::Client::new();
+        let list = |query: &str| {
+            let url = format!("{}/{}/app/?{}", server.url(), public_key, query);
+            let request = client.get(url).send();
+            async move {
+                let list: pubky_common::dto::ListResponse =
+                    request.await.unwrap().json().await.unwrap();
+                list
+            }
+        };
+
+        // Pages are limited by default, and resume after their cursor
+        let page = list("").await;
+        assert_eq!(page.count, 100);
+        assert_eq!(page.keys[1], "app/dir/000");
+        let cursor = page.next_cursor.unwrap();
+        assert_eq!(cursor, "app/dir/098");
+        let page = list(&format!("cursor={}&limit=1000", cursor)).await;
+        assert_eq!(page.keys.first().unwrap(), "app/dir/099");
+        assert_eq!(page.keys.last().unwrap(), "app/z");
+        assert_eq!(page.next_cursor, None);
+
+        // Shallow pages skip past the entries of a directory, either way
+        let page = list("shallow=true&limit=2").await;
+        assert_eq!(page.keys, ["app/a", "app/dir/"]);
+        let query = format!("shallow=true&limit=2&cursor={}", page.next_cursor.unwrap());
+        let page = list(&query).await;
+        assert_eq!(page.keys, ["app/z"]);
+        assert_eq!(page.next_cursor, None);
+        let page = list("shallow=true&limit=1&reverse=true&cursor=app/dir/").await;
+        assert_eq!(page.keys, ["app/a"]);
+        let page = list("shallow=true&limit=1&reverse=true&cursor=app/z").await;
+        assert_eq!(page.keys, ["app/dir/"]);
+        assert_eq!(page.next_cursor.as_deref(), Some("app/dir/"));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_mirror_replication() {
+        let secondary = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .admin_password("secondary-secret")
+            .start()
+            .await
+            .unwrap();
+
+        let storage = Arc::new(Storage::new());
+        let public_key = Keypair::random().public_key();
+        storage
+            .put(public_key, "app/before.txt".to_string(), b"before".to_vec())
+            .unwrap();
+
+        let primary = Server::builder()
+            .storage(storage.clone())
+            .bind(([127, 0, 0, 1], 0).into())
+            .admin_password("secret")
+            .mirror_to(MirrorConfig::new(secondary.url(), "secondary-secret"))
+            .start()
+            .await
+            .unwrap();
+
+        storage
+            .put(public_key, "app/after.txt".to_string(), b"after".to_vec())
+            .unwrap();
+        storage.delete(&public_key, "app/before.txt").unwrap();
+        // Metadata, tags and versions go with the values
+        let meta = crate::storage::EntryMeta {
+            content_type: Some("text/markdown".to_string()),
+            custom: [("title".to_string(), "Hi".to_string())].into(),
+            tags: ["post".to_string()].into(),
+            ..Default::default()
+        };
+        let path = "app/post.md".to_string();
+        let context = Default::default();
+        for (writer, value) in [("laptop", b"# Hi"), ("phone", b"# Yo")] {
+            let (value, meta) = (value.to_vec(), meta.clone());
+            storage
+                .put_versioned(public_key, path.clone(), value, meta, writer, &context)
+                .unwrap();
+        }
+        let meta = storage.meta(&public_key, &path).unwrap();
+        assert!(!meta.siblings.is_empty());
+
+        let mut replicated = false;
+        for _ in 0..100 {
+            let mirrored = secondary
+                .storage()
+                .get(&public_key, "app/after.txt")
+                .unwrap();
+            let deleted = secondary
+                .storage()
+                .get(&public_key, "app/before.txt")
+                .unwrap()
+                .is_none();
+            let post = secondary.storage().meta(&public_key, &path).unwrap() == meta;
+            if mirrored.is_some() && deleted && post {
+                replicated = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(replicated);
+        assert_eq!(
+            secondary
+                .storage()
+                .tagged(&public_key, "", &["post".to_string()]),
+            [path]
+        );
+
+        let status: serde_json::Value = reqwest::Client::new()
+            .get(format!("{}/admin/replication", primary.url()))
+            .header(crate::ADMIN_PASSWORD_HEADER, "secret")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(status["head_seq"], 5);
+        assert_eq!(status["mirror"]["acked_seq"], 5);
+        assert_eq!(status["mirror"]["lag_events"], 0);
+
+        primary.shutdown().await;
+        secondary.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let audit = Arc::new(crate::AuditLog::new(Default::default()).unwrap());
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .admin_password("secret")
+            .audit_log(audit.clone())
+            .start()
+            .await
+            .unwrap();
+
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let url = format!("{}/{}/app/file.txt", server.url(), public_key);
+        let client = reqwest::Client::new();
+        let put = client
+            .put(&url)
+            .body("data")
+            .signed(&keypair, &server.identity());
+        put.send().await.unwrap();
+        client.get(&url).send().await.unwrap();
+        for _ in 0..2 {
+            client
+                .delete(&url)
+                .signed(&keypair, &server.identity())
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let json: serde_json::Value = client
+            .get(format!(
+                "{}/admin/audit?public_key={}",
+                server.url(),
+                public_key
+            ))
+            .header(crate::ADMIN_PASSWORD_HEADER, "secret")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        // Reads are not audited; records are returned newest first
+        assert_eq!(json["count"], 3);
+        let records = json["records"].as_array().unwrap();
+        assert_eq!(records[0]["method"], "DELETE");
+        assert_eq!(records[0]["status"], 404);
+        assert_eq!(records[2]["method"], "PUT");
+        assert_eq!(records[2]["path"], "app/file.txt");
+        assert_eq!(records[2]["status"], 201);
+        assert!(records[2]["client"]
+            .as_str()
+            .unwrap()
+            .starts_with("127.0.0.1:"));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_frozen_account() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .admin_password("secret")
+            .start()
+            .await
+            .unwrap();
+        let client = reqwest::Client::new();
+
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let url = format!("{}/{}/app/file.txt", server.url(), public_key);
+        let put = |body: &'static str| {
+            client
+                .put(&url)
+                .body(body)
+                .signed(&keypair, &server.identity())
+                .send()
+        };
+        put("data").await.unwrap();
+
+        let freeze_url = format!("{}/admin/users/{}/freeze", server.url(), public_key);
+        let response = client
+            .put(&freeze_url)
+            .header(crate::ADMIN_PASSWORD_HEADER, "secret")
+            .json(&serde_json::json!({ "reason": "investigation" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        // Writes and deletes are rejected, reads continue
+        let response = put("new").await.unwrap();
+        assert_eq!(response.status(), 423);
+        let response = client
+            .delete(&url)
+            .signed(&keypair, &server.identity())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 423);
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "data");
+
+        // Frozen data is excluded from purges
+        let response = client
+            .delete(format!("{}/admin/users/{}", server.url(), public_key))
+            .header(crate::ADMIN_PASSWORD_HEADER, "secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 423);
+
+        // Reads can be blocked too
+        client
+            .put(&freeze_url)
+            .header(crate::ADMIN_PASSWORD_HEADER, "secret")
+            .json(&serde_json::json!({ "block_reads": true }))
+            .send()
+            .await
+            .unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), 451);
+
+        let response = client
+            .delete(&freeze_url)
+            .header(crate::ADMIN_PASSWORD_HEADER, "secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+        let response = put("new").await.unwrap();
+        assert_eq!(response.status(), 201);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_write_throttle() {
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .write_throttle(ThrottleConfig {
+                ops_per_sec: 0.1,
+                ops_burst: 2.0,
+                ..Default::default()
+            })
+            .start()
+            .await
+            .unwrap();
+        let client = reqwest::Client::new();
+
+        let alice = Keypair::random();
+        let url = format!("{}/{}/app/file.txt", server.url(), alice.public_key());
+        let put = |url: &str, keypair| {
+            client
+                .put(url)
+                .body("data")
+                .signed(keypair, &server.identity())
+                .send()
+        };
+        for _ in 0..2 {
+            let response = put(&url, &alice).await.unwrap();
+            assert_eq!(response.status(), 201);
+        }
+
+        let response = put(&url, &alice).await.unwrap();
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()["retry-after"], "10");
+
+        // Reads and other users are not affected
+        assert_eq!(client.get(&url).send().await.unwrap().status(), 200);
+        let bob = Keypair::random();
+        let url = format!("{}/{}/app/file.txt", server.url(), bob.public_key());
+        let response = put(&url, &bob).await.unwrap();
+        assert_eq!(response.status(), 201);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_extensions() {
+        let public_key = Keypair::random().public_key();
+        let stats =
+            Router::new().route(
+                "/stats",
+                get(|State(storage): State<Arc<Storage>>| async move {
+                    storage.users().len().to_string()
+                }),
+            );
+        let stamp = |name: &'static str| {
+            middleware::map_response(move |mut response: axum::response::Response| async move {
+                response.headers_mut().insert(name, "1".parse().unwrap());
+                response
+            })
+        };
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .routes(stats)
+            .layer(stamp("x-everywhere"))
+            .storage_layer(stamp("x-storage"))
+            .background(move |storage| async move {
+                storage
+                    .put(public_key, "pub/job.txt".to_string(), b"done".to_vec())
+                    .unwrap();
+            })
+            .start()
+            .await
+            .unwrap();
+
+        let response = reqwest::get(format!("{}/stats", server.url()))
+            .await
+            .unwrap();
+        assert!(response.headers().contains_key("x-everywhere"));
+        assert!(!response.headers().contains_key("x-storage"));
+
+        // The job ran against the server's storage
+        let url = format!("{}/{}/pub/job.txt", server.url(), public_key);
+        let mut response = reqwest::get(&url).await.unwrap();
+        for _ in 0..50 {
+            if response.status() == 200 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            response = reqwest::get(&url).await.unwrap();
+        }
+        assert!(response.headers().contains_key("x-everywhere"));
+        assert!(response.headers().contains_key("x-storage"));
+        assert_eq!(response.text().await.unwrap(), "done");
+        let response = reqwest::get(format!("{}/stats", server.url()))
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "1");
+
+        server.shutdown().await;
+    }
+}
@@ -0,0 +1,484 @@
+//! Signup and session routes
+//!
+//! Clients sign up or sign in with a signed [`AuthToken`] and receive a
+//! session token, returned in the response body and as a `session` cookie.
+//! Tokens must be addressed to the homeserver's identity, which
+//! `GET /identity` returns, and each is accepted only once.
+//! Later requests identify the session with either the cookie or an
+//! `Authorization: Bearer <token>` header. Sessions last [`SESSION_TTL`].
+//!
+//! An account can list its sessions, one per signed-in device, and revoke
+//! any of them, for example to sign out a lost phone. Routes acting on the
+//! whole account, such as these, need a session with root capabilities.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use pubky_common::auth::AuthToken;
+use pubky_common::capabilities::Action;
+use pubky_common::dto::{ActiveSession, SessionInfo, SignupRequest};
+use pubky_common::pkarr::HOMESERVER_HEADER;
+use pubky_common::PublicKey;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::routes::ApiError;
+use crate::storage::{Session, Storage};
+use crate::write_auth;
+
+/// How long a session stays valid after signup or signin
+pub const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Name of the session cookie
+pub const SESSION_COOKIE: &str = "session";
+
+/// Longest nonce accepted in signed messages, in bytes
+pub(crate) const MAX_NONCE_LEN: usize = 64;
+
+/// Capabilities of sessions started by signing with the root key
+pub(crate) const ROOT_CAPABILITIES: &str = "/:rw";
+
+/// State shared by session handlers
+#[derive(Clone)]
+pub(crate) struct SessionState {
+    pub storage: Arc<Storage>,
+    /// Only accept signups carrying a valid invite code
+    pub require_invite: bool,
+    /// Public key the homeserver announces itself under, told to users
+    /// signing up
+    pub homeserver: Option<PublicKey>,
+    /// Public key auth tokens must be addressed to
+    pub identity: PublicKey,
+}
+
+/// Create the signup and session routes
+pub(crate) fn session_routes<S>(state: SessionState) -> Router<S> {
+    Router::new()
+        .route("/identity", get(identity))
+        .route("/signup", post(signup))
+        .route("/session", post(signin).get(get_session).delete(signout))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{id}", delete(revoke_session))
+        .with_state(state)
+}
+
+/// GET /identity
+/// The z-base-32 public key auth tokens must be addressed to
+async fn identity(State(state): State<SessionState>) -> String {
+    state.identity.to_z32()
+}
+
+/// POST /signup
+/// Register the signer of the token and start a session, naming the
+/// homeserver's public key in [`HOMESERVER_HEADER`] if it announces itself
+async fn signup(
+    State(state): State<SessionState>,
+    headers: HeaderMap,
+    Json(request): Json<SignupRequest>,
+) -> Result<Response, ApiError> {
+    let public_key = verify(&state, &request.token)?;
+
+    if state.require_invite && !state.storage.is_registered(&public_key) {
+        let code = request.invite_code.as_deref().unwrap_or_default();
+        if !state.storage.take_invite(code) {
+            return Err(ApiError::Unauthorized);
+        }
+    }
+
+    if state.storage.register(public_key) {
+        tracing::info!("New signup: {}", public_key);
+    }
+
+    let response = start_session(&state.storage, public_key, ROOT_CAPABILITIES, &headers);
+    let mut response = (StatusCode::CREATED, response).into_response();
+    if let Some(homeserver) = state.homeserver {
+        let value = HeaderValue::from_str(&homeserver.to_z32()).expect("z-base-32 is ASCII");
+        response.headers_mut().insert(HOMESERVER_HEADER, value);
+    }
+    Ok(response)
+}
+
+/// POST /session
+/// Sign in to a registered account
+async fn signin(
+    State(state): State<SessionState>,
+    headers: HeaderMap,
+    Json(token): Json<AuthToken>,
+) -> Result<Response, ApiError> {
+    let public_key = verify(&state, &token)?;
+    if !state.storage.is_registered(&public_key) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(start_session(&state.storage, public_key, ROOT_CAPABILITIES, &headers).into_response())
+}
+
+/// GET /session
+/// Inspect the current session
+async fn get_session(
+    State(state): State<SessionState>,
+    headers: HeaderMap,
+) -> Result<Json<SessionInfo>, ApiError> {
+    let (token, session) = authenticate(&state.storage, &headers).ok_or(ApiError::Unauthorized)?;
+    Ok(Json(session_info(token, &session)))
+}
+
+/// DELETE /session
+/// Sign out, ending the current session
+async fn signout(
+    State(state): State<SessionState>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let (token, _) = authenticate(&state.storage, &headers).ok_or(ApiError::Unauthorized)?;
+    state.storage.remove_session(&token);
+
+    let cookie = format!("{}=; Path=/; HttpOnly; Max-Age=0", SESSION_COOKIE);
+    Ok((StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)]).into_response())
+}
+
+/// GET /sessions
+/// List the sessions of the current account
+async fn list_sessions(
+    State(state): State<SessionState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ActiveSession>>, ApiError> {
+    let (_, current) = authenticate_root(&state.storage, &headers)?;
+    let sessions = state
+        .storage
+        .sessions_of(&current.public_key)
+        .into_iter()
+        .map(|session| ActiveSession {
+            current: session.id == current.id,
+            id: session.id,
+            device: session.device,
+            capabilities: session.capabilities,
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            expires_at: session.expires_at,
+        })
+        .collect();
+    Ok(Json(sessions))
+}
+
+/// DELETE /sessions/{id}
+/// Revoke a session of the current account
+async fn revoke_session(
+    State(state): State<SessionState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let (_, current) = authenticate_root(&state.storage, &headers)?;
+    match state.storage.revoke_session(&current.public_key, &id) {
+        true => {
+            tracing::info!("Revoked session {} of {}", id, current.public_key);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        false => Err(ApiError::NotFound),
+    }
+}
+
+/// Find the session identified by the request headers
+///
+/// The bearer token takes precedence over the session cookie.
+pub(crate) fn authenticate(storage: &Storage, headers: &HeaderMap) -> Option<(String, Session)> {
+    let token = bearer_token(headers).or_else(|| session_cookie(headers))?;
+    let session = storage.session(&token)?;
+    Some((token, session))
+}
+
+/// Find the session identified by the request headers, requiring it to
+/// hold root capabilities
+///
+/// Routes acting on the account as a whole, rather than on its entries,
+/// are closed to app sessions limited to some paths.
+pub(crate) fn authenticate_root(
+    storage: &Storage,
+    headers: &HeaderMap,
+) -> Result<(String, Session), ApiError> {
+    let (token, session) = authenticate(storage, headers).ok_or(ApiError::Unauthorized)?;
+    match is_root(&session) {
+        true => Ok((token, session)),
+        false => Err(ApiError::Forbidden),
+    }
+}
+
+/// Whether the capabilities of `session` cover the whole account
+pub(crate) fn is_root(session: &Session) -> bool {
+    write_auth::allows(session, "/", Action::Read)
+        && write_auth::allows(session, "/", Action::Write)
+}
+
+/// Verify that `token` is addressed to this homeserver and wasn't used
+/// before, returning its signer
+fn verify(state: &SessionState, token: &AuthToken) -> Result<PublicKey, ApiError> {
+    let invalid = |reason: &dyn std::fmt::Display| {
+        ApiError::BadRequest(format!("Invalid auth token: {}", reason))
+    };
+    let public_key = token
+        .verify_at(&state.identity, state.storage.now_millis())
+        .map_err(|e| invalid(&e))?;
+    if token.nonce.is_empty() || token.nonce.len() > MAX_NONCE_LEN {
+        return Err(invalid(&"bad nonce"));
+    }
+    let expires_at = token.timestamp + AuthToken::MAX_CLOCK_SKEW_MS;
+    let storage = &state.storage;
+    if !storage.use_nonce(&public_key, &token.nonce, expires_at) {
+        return Err(invalid(&"already used"));
+    }
+    Ok(public_key)
+}
+
+/// Create a session and build the response that hands it to the client
+pub(crate) fn start_session(
+    storage: &Storage,
+    public_key: PublicKey,
+    capabilities: &str,
+    headers: &HeaderMap,
+) -> impl IntoResponse {
+    let token = BASE64_URL.encode(rand::random::<[u8; 32]>());
+    let created_at = storage.now_millis();
+    let device = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let session = Session {
+        id: BASE64_URL.encode(rand::random::<[u8; 12]>()),
+        public_key,
+        device,
+        capabilities: capabilities.to_string(),
+        created_at,
+        last_used_at: created_at,
+        expires_at: created_at + SESSION_TTL.as_millis() as u64,
+    };
+    storage.insert_session(token.clone(), session.clone());
+
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        SESSION_COOKIE,
+        token,
+        SESSION_TTL.as_secs()
+    );
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(session_info(token, &session)),
+    )
+}
+
+fn session_info(token: String, session: &Session) -> SessionInfo {
+    SessionInfo {
+        public_key: session.public_key.to_z32(),
+        token,
+        created_at: session.created_at,
+        expires_at: session.expires_at,
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            (name == SESSION_COOKIE).then(|| value.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use pubky_common::Keypair;
+    use tower::ServiceExt;
+
+    async fn call(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: Option<String>,
+    ) -> (StatusCode, HeaderMap, Option<SessionInfo>) {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        let request = request
+            .body(body.map(Body::from).unwrap_or_else(Body::empty))
+            .unwrap();
+
+        let response = router.clone().oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (
+            parts.status,
+            parts.headers,
+            serde_json::from_slice(&body).ok(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_signup_and_sessions() {
+        let storage = Arc::new(Storage::new());
+        storage.add_invite("CODE".to_string());
+        let identity = Keypair::random().public_key();
+        let router = session_routes(SessionState {
+            storage: storage.clone(),
+            require_invite: true,
+            homeserver: None,
+            identity,
+        });
+        let keypair = Keypair::random();
+        let token = || serde_json::to_string(&AuthToken::sign(&keypair, &identity)).unwrap();
+
+        // Unknown accounts can't sign in, and signup needs an invite
+        let (status, _, _) = call(&router, "POST", "/session", &[], Some(token())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, _) = call(&router, "POST", "/signup", &[], Some(token())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let signup = SignupRequest {
+            token: AuthToken::sign(&keypair, &identity),
+            invite_code: Some("CODE".to_string()),
+        };
+        let body = serde_json::to_string(&signup).unwrap();
+        let (status, headers, session) = call(&router, "POST", "/signup", &[], Some(body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let session = session.unwrap();
+        assert_eq!(session.public_key, keypair.public_key().to_z32());
+        assert!(headers[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .starts_with(&format!("session={};", session.token)));
+        assert!(storage.invites().is_empty());
+
+        // The session is found by bearer token or cookie
+        let bearer = format!("Bearer {}", session.token);
+        let (status, _, info) = call(
+            &router,
+            "GET",
+            "/session",
+            &[("authorization", &bearer)],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(info.unwrap(), session);
+        let cookie = format!("theme=dark; session={}", session.token);
+        let (status, _, _) = call(&router, "GET", "/session", &[("cookie", &cookie)], None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Registered accounts sign in without an invite
+        let token = token();
+        let (status, _, other) = call(&router, "POST", "/session", &[], Some(token.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        let other = other.unwrap();
+        assert_ne!(other.token, session.token);
+
+        // Tokens can't be replayed, nor used with another homeserver
+        let (status, _, _) = call(&router, "POST", "/session", &[], Some(token)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let elsewhere = AuthToken::sign(&keypair, &Keypair::random().public_key());
+        let elsewhere = serde_json::to_string(&elsewhere).unwrap();
+        let (status, _, _) = call(&router, "POST", "/session", &[], Some(elsewhere)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // App sessions can neither list nor revoke the account's sessions
+        let first = storage.session(&session.token).unwrap();
+        let app = Session {
+            id: "app".to_string(),
+            capabilities: "/pub/my-app/:rw".to_string(),
+            ..first.clone()
+        };
+        storage.insert_session("app".to_string(), app);
+        let app_bearer = "Bearer app";
+        let (status, _, _) = call(
+            &router,
+            "GET",
+            "/sessions",
+            &[("authorization", app_bearer)],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let uri = format!("/sessions/{}", first.id);
+        let (status, _, _) = call(
+            &router,
+            "DELETE",
+            &uri,
+            &[("authorization", app_bearer)],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(storage.revoke_session(&keypair.public_key(), "app"));
+
+        // Each session is listed, and one can revoke another
+        assert_eq!(storage.sessions_of(&keypair.public_key()).len(), 2);
+        let other_bearer = format!("Bearer {}", other.token);
+        let (status, _, _) = call(
+            &router,
+            "DELETE",
+            &uri,
+            &[("authorization", &other_bearer)],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _, _) = call(
+            &router,
+            "GET",
+            "/session",
+            &[("authorization", &bearer)],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, _) = call(
+            &router,
+            "DELETE",
+            &uri,
+            &[("authorization", &other_bearer)],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let bearer = other_bearer;
+
+        let (status, _, _) = call(
+            &router,
+            "DELETE",
+            "/session",
+            &[("authorization", &bearer)],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _, _) = call(
+            &router,
+            "GET",
+            "/session",
+            &[("authorization", &bearer)],
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,54 +1,1828 @@
-//! In-memory storage backend
+//! Storage
 //!
-//! Provides a simple HashMap-based storage implementation.
-//! In production, this would be replaced with LMDB or another persistent store.
+//! Keeps the entries of every account in a [`StorageBackend`], in memory
+//! unless told otherwise, with their metadata, tags and versions. Accounts,
+//! sessions, invites, freezes, handles, domains and moves are kept in
+//! memory and saved with the backend as they change, so backends that
+//! outlive the process restore them on start. Timestamps are kept with the
+//! metadata of each entry, hashes and the tag index are rebuilt from the
+//! entries as needed, while the event log, quarantine and pending
+//! authorizations are lost on restart.
 
+use pubky_common::blob::sha256_hex;
+use pubky_common::reconcile::{Item, ItemSet};
+use pubky_common::version::VersionVector;
 use pubky_common::PublicKey;
-use std::collections::HashMap;
-use std::sync::RwLock;
 
-/// In-memory key-value storage
+use crate::backend::{Commit, MemoryBackend, StorageBackend};
+use crate::clock::{Clock, SystemClock};
+use crate::metrics::{StorageMetrics, StorageOp};
+use crate::moderation::Verdict;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+
+/// Maximum number of mutation events retained in the event log
+pub const MAX_EVENTS: usize = 100_000;
+
+/// Kind of mutation recorded in the event log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventOp {
+    Put,
+    Delete,
+}
+
+/// A mutation recorded in the event log
+///
+/// Events carry no values: consumers read the current value when they
+/// process a `Put`, and a later `Delete` always follows if it is gone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Monotonically increasing sequence number, starting at 1
+    pub seq: u64,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    pub op: EventOp,
+    pub public_key: PublicKey,
+    pub path: String,
+}
+
+/// Bounded, sequenced log of mutations
+struct EventLog {
+    events: VecDeque<Event>,
+    head_seq: u64,
+}
+
+/// Tags of entries, and the entries carrying each tag
+///
+/// The tags of an account's entries are read from the backend the first
+/// time the account's entries are searched by tag, and kept up to date
+/// from then on.
+#[derive(Default)]
+struct TagIndex {
+    indexed: HashSet<PublicKey>,
+    by_entry: HashMap<(PublicKey, String), BTreeSet<String>>,
+    by_tag: HashMap<(PublicKey, String), BTreeSet<String>>,
+}
+
+impl TagIndex {
+    fn insert(&mut self, public_key: PublicKey, path: &str, tags: BTreeSet<String>) {
+        self.remove(&public_key, path);
+        for tag in &tags {
+            self.by_tag
+                .entry((public_key, tag.clone()))
+                .or_default()
+                .insert(path.to_string());
+        }
+        if !tags.is_empty() {
+            self.by_entry.insert((public_key, path.to_string()), tags);
+        }
+    }
+
+    fn remove(&mut self, public_key: &PublicKey, path: &str) {
+        let Some(tags) = self.by_entry.remove(&(*public_key, path.to_string())) else {
+            return;
+        };
+        for tag in tags {
+            let key = (*public_key, tag);
+            if let Some(paths) = self.by_tag.get_mut(&key) {
+                paths.remove(path);
+                if paths.is_empty() {
+                    self.by_tag.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Nonces of signed messages already accepted, by signer, and when they
+/// expire
+#[derive(Default)]
+struct UsedNonces {
+    by_signer: HashSet<(PublicKey, String)>,
+    by_expiry: BTreeMap<u64, Vec<(PublicKey, String)>>,
+}
+
+impl UsedNonces {
+    /// Forget the nonces that expired by `now`
+    fn expire(&mut self, now: u64) {
+        while let Some(entry) = self.by_expiry.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+            for key in entry.remove() {
+                self.by_signer.remove(&key);
+            }
+        }
+    }
+}
+
+/// Summary of the data stored for a single public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserUsage {
+    pub public_key: PublicKey,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// An administrative freeze (legal hold) on an account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Freeze {
+    /// Reason recorded by the admin
+    pub reason: String,
+    /// Whether reads are rejected as well as writes
+    pub block_reads: bool,
+    /// Unix timestamp in milliseconds when the freeze started
+    pub since: u64,
+}
+
+/// A Nostr identity linked to an account
+#[cfg(feature = "multi-alg")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NostrLink {
+    #[serde(with = "z32")]
+    pub public_key: PublicKey,
+    /// Capabilities of the session that linked the identity, given to the
+    /// sessions it signs in to
+    pub capabilities: String,
+}
+
+/// A value held back from readers by moderation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quarantined {
+    pub value: Vec<u8>,
+    /// Why the value was held back
+    pub reason: String,
+    /// Unix timestamp in milliseconds when the value was held back
+    pub since: u64,
+    /// Whether the value awaits a content scan rather than review
+    pub pending: bool,
+}
+
+/// An authenticated session for a signed-up account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    /// Identifier of the session that, unlike its token, may be shown
+    pub id: String,
+    #[serde(with = "z32")]
+    pub public_key: PublicKey,
+    /// User agent of the device that signed in, if it sent one
+    pub device: Option<String>,
+    /// Paths the session may access, such as `/:rw` for everything
+    pub capabilities: String,
+    /// Unix timestamp in milliseconds when the session was created
+    pub created_at: u64,
+    /// Unix timestamp in milliseconds when the session was last used
+    pub last_used_at: u64,
+    /// Unix timestamp in milliseconds when the session expires
+    pub expires_at: u64,
+}
+
+/// A device's pending request to be authorized by another device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRequest {
+    pub challenge: String,
+    /// Secret the requesting device redeems the session with
+    pub secret: String,
+    pub capabilities: String,
+    /// Unix timestamp in milliseconds when the request expires
+    pub expires_at: u64,
+    /// Account that approved the request, once approved
+    pub approved_by: Option<PublicKey>,
+}
+
+/// Concurrent versions of an entry with their values, oldest first
+pub type Siblings = Vec<(VersionVector, Vec<u8>)>;
+
+/// When an entry was created and last modified, as Unix timestamps in
+/// milliseconds
+///
+/// Entries stored without them, such as those put into the backend
+/// directly, count as created when they were last modified, as far as the
+/// backend knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timestamps {
+    pub created: u64,
+    pub modified: u64,
+}
+
+/// What an entry was written with besides its value
+///
+/// Kept by the backend with the value, and dropped when the value is
+/// replaced or deleted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryMeta {
+    /// Media type of the value, as given by the `Content-Type` of the write
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Custom metadata, by field name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom: BTreeMap<String, String>,
+    /// Tags of the entry
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+    /// Concurrent versions of the entry, if it was written with a writer id
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "base64_siblings"
+    )]
+    pub siblings: Siblings,
+    /// When the entry was created and last modified, kept with it by the
+    /// storage and left out of the metadata it returns
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamps: Option<Timestamps>,
+}
+
+impl EntryMeta {
+    /// Whether the entry was written without any metadata
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Rough bytes the metadata holds in memory
+    pub(crate) fn memory_bytes(&self) -> usize {
+        let content_type = self.content_type.as_ref().map_or(0, String::len);
+        let custom: usize = self
+            .custom
+            .iter()
+            .map(|(name, value)| 2 * size_of::<String>() + name.len() + value.len())
+            .sum();
+        let tags: usize = self
+            .tags
+            .iter()
+            .map(|tag| size_of::<String>() + tag.len())
+            .sum();
+        let siblings: usize = self
+            .siblings
+            .iter()
+            .map(|(version, value)| {
+                size_of::<(VersionVector, Vec<u8>)>() + version.to_string().len() + value.len()
+            })
+            .sum();
+        size_of::<Self>() + content_type + custom + tags + siblings
+    }
+}
+
+/// Account state kept by the backend across restarts, with public keys in
+/// z-base-32
+#[derive(Default, Serialize, Deserialize)]
+struct SavedState {
+    #[serde(default)]
+    invites: BTreeSet<String>,
+    #[serde(default)]
+    accounts: BTreeSet<String>,
+    /// Sessions by the hash of their token
+    #[serde(default)]
+    sessions: BTreeMap<String, Session>,
+    #[serde(default)]
+    frozen: BTreeMap<String, Freeze>,
+    #[serde(default)]
+    moved: BTreeMap<String, String>,
+    #[serde(default)]
+    handles: BTreeMap<String, String>,
+    #[serde(default)]
+    domains: BTreeMap<String, String>,
+    #[cfg(feature = "multi-alg")]
+    #[serde(default)]
+    nostr_links: BTreeMap<String, NostrLink>,
+}
+
+/// The entry a conditional write would replace, looked up on demand
+pub struct CurrentEntry<'a> {
+    storage: &'a Storage,
+    key: &'a (PublicKey, String),
+}
+
+impl CurrentEntry<'_> {
+    /// Hex SHA-256 hash of the value, if there is one
+    pub fn hash(&self) -> Option<String> {
+        self.storage.locked_hash(self.key)
+    }
+
+    /// When the entry was created and last modified, if known
+    pub fn timestamps(&self) -> Option<Timestamps> {
+        self.storage.locked_timestamps(self.key)
+    }
+}
+
+/// Key-value storage of entries, with the state around them
 pub struct Storage {
-    data: RwLock<HashMap<(PublicKey, String), Vec<u8>>>,
+    backend: Arc<dyn StorageBackend>,
+    /// Held while writing entries, so events follow the order of writes
+    writes: Mutex<()>,
+    /// Hex SHA-256 hashes of the stored values, set as they are written or
+    /// first asked for
+    hashes: RwLock<HashMap<(PublicKey, String), String>>,
+    /// When entries were created and last modified, set as they are written
+    /// or first asked for
+    timestamps: RwLock<HashMap<(PublicKey, String), Timestamps>>,
+    tags: RwLock<TagIndex>,
+    /// Values held back by moderation, apart from the stored entries
+    quarantine: RwLock<HashMap<(PublicKey, String), Quarantined>>,
+    invites: RwLock<HashSet<String>>,
+    accounts: RwLock<HashSet<PublicKey>>,
+    /// Sessions by the hash of their token
+    sessions: RwLock<HashMap<String, Session>>,
+    auth_requests: RwLock<HashMap<String, AuthRequest>>,
+    /// Signatures of grants redeemed for sessions, with when they expire
+    redeemed_grants: RwLock<HashMap<[u8; 64], u64>>,
+    used_nonces: Mutex<UsedNonces>,
+    frozen: RwLock<HashMap<PublicKey, Freeze>>,
+    /// Homeservers that accounts migrated to
+    moved: RwLock<HashMap<PublicKey, String>>,
+    /// Accounts by the handle they claimed, such as `alice`
+    handles: RwLock<HashMap<String, PublicKey>>,
+    /// Accounts by the verified domain aliasing them
+    domains: RwLock<HashMap<String, PublicKey>>,
+    /// Accounts of linked Nostr identities, by hex-encoded public key
+    #[cfg(feature = "multi-alg")]
+    nostr_links: RwLock<HashMap<String, NostrLink>>,
+    /// Whether the account state is saved with the backend, which it isn't
+    /// if the backend keeps none or the saved state couldn't be loaded
+    saves_state: bool,
+    /// Held while saving the account state, so the latest is saved last
+    saving: Mutex<()>,
+    events: RwLock<EventLog>,
+    events_notify: Notify,
+    metrics: StorageMetrics,
+    clock: Arc<dyn Clock>,
 }
 
 impl Storage {
     /// Create a new empty storage
     pub fn new() -> Self {
-        Self {
-            data: RwLock::new(HashMap::new()),
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Create a new empty storage telling time with `clock`
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self::with_backend(Arc::new(MemoryBackend::new()), clock)
+    }
+
+    /// Create a storage keeping its entries in `backend`, telling time
+    /// with `clock`
+    ///
+    /// Entries already in the backend are served as they are, without
+    /// events, and the account state it saved is restored.
+    pub fn with_backend(backend: Arc<dyn StorageBackend>, clock: Arc<dyn Clock>) -> Self {
+        let mut storage = Self {
+            metrics: StorageMetrics::new(backend.name()),
+            backend,
+            writes: Mutex::new(()),
+            hashes: RwLock::new(HashMap::new()),
+            timestamps: RwLock::new(HashMap::new()),
+            tags: RwLock::new(TagIndex::default()),
+            quarantine: RwLock::new(HashMap::new()),
+            invites: RwLock::new(HashSet::new()),
+            accounts: RwLock::new(HashSet::new()),
+            sessions: RwLock::new(HashMap::new()),
+            auth_requests: RwLock::new(HashMap::new()),
+            redeemed_grants: RwLock::new(HashMap::new()),
+            used_nonces: Mutex::new(UsedNonces::default()),
+            frozen: RwLock::new(HashMap::new()),
+            moved: RwLock::new(HashMap::new()),
+            handles: RwLock::new(HashMap::new()),
+            domains: RwLock::new(HashMap::new()),
+            #[cfg(feature = "multi-alg")]
+            nostr_links: RwLock::new(HashMap::new()),
+            saves_state: false,
+            saving: Mutex::new(()),
+            events: RwLock::new(EventLog {
+                events: VecDeque::new(),
+                head_seq: 0,
+            }),
+            events_notify: Notify::new(),
+            clock,
+        };
+        storage.saves_state = storage.backend.keeps_state() && storage.load_state();
+        storage
+    }
+
+    /// The backend holding the entries
+    pub fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    /// The clock of the server
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Current Unix time in milliseconds, according to the clock
+    pub fn now_millis(&self) -> u64 {
+        self.clock.now_millis()
+    }
+
+    /// Store a value at the given public key and path, without metadata
+    ///
+    /// The entry loses its version and siblings, if it had any.
+    pub fn put(&self, public_key: PublicKey, path: String, value: Vec<u8>) -> io::Result<()> {
+        self.put_with_meta(public_key, path, value, EntryMeta::default())
+    }
+
+    /// Store a value with its metadata, as [`put`](Self::put) does
+    pub fn put_with_meta(
+        &self,
+        public_key: PublicKey,
+        path: String,
+        value: Vec<u8>,
+        meta: EntryMeta,
+    ) -> io::Result<()> {
+        let hash = sha256_hex(&value);
+        let commit = self.stage(public_key, &path, value)?;
+        let _writes = self.writes.lock().unwrap();
+        self.store(public_key, path, commit, hash, meta)
+    }
+
+    /// Store the value read from `reader` at the given public key and path,
+    /// as [`put`](Self::put) does
+    ///
+    /// The value is staged in the backend as it is read, without holding
+    /// back other writes, and stored once complete. Failures leave the
+    /// previous value in place.
+    pub fn put_from(
+        &self,
+        public_key: PublicKey,
+        path: String,
+        reader: &mut dyn Read,
+    ) -> io::Result<()> {
+        self.put_from_if(public_key, path, EntryMeta::default(), reader, &|_| true)
+            .map(|_| ())
+    }
+
+    /// Store the value read from `reader` with its metadata as
+    /// [`put_from`](Self::put_from) does, if `condition` holds for the
+    /// current entry
+    ///
+    /// The condition is checked once the value is staged, and no other
+    /// write can happen before the value is stored. Returns whether it was.
+    pub fn put_from_if(
+        &self,
+        public_key: PublicKey,
+        path: String,
+        meta: EntryMeta,
+        reader: &mut dyn Read,
+        condition: &dyn Fn(&CurrentEntry) -> bool,
+    ) -> io::Result<bool> {
+        let mut reader = HashingReader {
+            inner: reader,
+            hasher: Sha256::new(),
+        };
+        let commit = self.backend.stage(public_key, path.clone(), &mut reader)?;
+        let hash = hex(&reader.hasher.finalize());
+        let key = (public_key, path.clone());
+        let _writes = self.writes.lock().unwrap();
+        let current = CurrentEntry {
+            storage: self,
+            key: &key,
+        };
+        if !condition(&current) {
+            return Ok(false);
         }
+        self.store(public_key, path, commit, hash, meta)
+            .map(|()| true)
+    }
+
+    /// Store a value written by `writer`, who last saw the entry at version
+    /// `context`
+    ///
+    /// Siblings seen by `context` are replaced, and the others are kept
+    /// alongside the new value. Returns the entry's version, which covers
+    /// all its siblings, and the number of siblings.
+    pub fn put_versioned(
+        &self,
+        public_key: PublicKey,
+        path: String,
+        value: Vec<u8>,
+        meta: EntryMeta,
+        writer: &str,
+        context: &VersionVector,
+    ) -> io::Result<(VersionVector, usize)> {
+        let hash = sha256_hex(&value);
+        let commit = self.stage(public_key, &path, value.clone())?;
+        let _writes = self.writes.lock().unwrap();
+        let siblings = self.meta(&public_key, &path)?.siblings;
+
+        // Count past every write of this writer, even those the context missed
+        let mut version = context.clone();
+        let seen = siblings.iter().map(|(v, _)| v.get(writer)).max();
+        version.insert(writer, context.get(writer).max(seen.unwrap_or(0)) + 1);
+
+        let mut siblings: Vec<_> = siblings
+            .into_iter()
+            .filter(|(v, _)| !context.descends(v))
+            .collect();
+        siblings.push((version, value.clone()));
+        let merged = merge(&siblings);
+        let count = siblings.len();
+        self.store(
+            public_key,
+            path,
+            commit,
+            hash,
+            EntryMeta { siblings, ..meta },
+        )?;
+        Ok((merged, count))
     }
 
-    /// Store a value at the given public key and path
-    pub fn put(&self, public_key: PublicKey, path: String, value: Vec<u8>) {
-        let mut data = self.data.write().unwrap();
-        data.insert((public_key, path), value);
+    /// Version of an entry written with a writer id, covering all its
+    /// siblings
+    pub fn version(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<VersionVector>> {
+        let siblings = self.meta(public_key, path)?.siblings;
+        Ok((!siblings.is_empty()).then(|| merge(&siblings)))
+    }
+
+    /// Concurrent versions of an entry, oldest first
+    ///
+    /// Entries written without a writer id have a single sibling with an
+    /// empty version.
+    pub fn siblings(&self, public_key: &PublicKey, path: &str) -> io::Result<Siblings> {
+        let siblings = self.meta(public_key, path)?.siblings;
+        if !siblings.is_empty() {
+            return Ok(siblings);
+        }
+        Ok(match self.get(public_key, path)? {
+            Some(value) => vec![(VersionVector::new(), value)],
+            None => Vec::new(),
+        })
+    }
+
+    /// Stage `value` for `path` in the backend, without holding back other
+    /// writes
+    fn stage(&self, public_key: PublicKey, path: &str, value: Vec<u8>) -> io::Result<Commit<'_>> {
+        self.backend
+            .stage_value(public_key, path.to_string(), value)
+            .map_err(|e| logged("stage", &public_key, path, e))
+    }
+
+    /// Commit a staged value with its hash and metadata, while holding the
+    /// writes lock
+    ///
+    /// A value stored without its metadata is still recorded, as it
+    /// replaced the previous one.
+    fn store(
+        &self,
+        public_key: PublicKey,
+        path: String,
+        commit: Commit,
+        hash: String,
+        meta: EntryMeta,
+    ) -> io::Result<()> {
+        let _timer = self.metrics.time(StorageOp::Put);
+        let key = (public_key, path.clone());
+        let timestamps = self.next_timestamps(&key);
+        if let Err(e) = commit() {
+            self.hashes.write().unwrap().remove(&key);
+            self.timestamps.write().unwrap().remove(&key);
+            return Err(logged("store", &public_key, &path, e));
+        }
         tracing::debug!("Stored data for {} at path", public_key);
+        let meta = EntryMeta {
+            timestamps: Some(timestamps),
+            ..meta
+        };
+        let stored = self.store_meta(public_key, path.clone(), meta);
+        self.hashes.write().unwrap().insert(key.clone(), hash);
+        self.timestamps.write().unwrap().insert(key, timestamps);
+        self.record(EventOp::Put, public_key, path);
+        stored
+    }
+
+    /// Replace the metadata of a stored value, while holding the writes
+    /// lock
+    fn store_meta(&self, public_key: PublicKey, path: String, meta: EntryMeta) -> io::Result<()> {
+        let tags = meta.tags.clone();
+        match self.backend.put_meta(public_key, path.clone(), meta) {
+            Ok(()) => {
+                self.tags.write().unwrap().insert(public_key, &path, tags);
+                Ok(())
+            }
+            Err(e) => {
+                // The previous tags were dropped with the previous metadata
+                self.tags.write().unwrap().remove(&public_key, &path);
+                Err(logged("store the metadata of", &public_key, &path, e))
+            }
+        }
+    }
+
+    /// Timestamps of the entry at `key` once written now, while holding the
+    /// writes lock
+    fn next_timestamps(&self, key: &(PublicKey, String)) -> Timestamps {
+        let now = self.now_millis();
+        Timestamps {
+            created: self.locked_timestamps(key).map_or(now, |t| t.created),
+            modified: now,
+        }
+    }
+
+    /// When the entry at the given public key and path was created and last
+    /// modified
+    ///
+    /// Entries stored before the server started are asked of the backend.
+    pub fn timestamps(&self, public_key: &PublicKey, path: &str) -> Option<Timestamps> {
+        let key = (*public_key, path.to_string());
+        if let Some(timestamps) = self.timestamps.read().unwrap().get(&key) {
+            return Some(*timestamps);
+        }
+        let _writes = self.writes.lock().unwrap();
+        self.locked_timestamps(&key)
+    }
+
+    /// Timestamps of the entry at `key`, while holding the writes lock
+    fn locked_timestamps(&self, key: &(PublicKey, String)) -> Option<Timestamps> {
+        if let Some(timestamps) = self.timestamps.read().unwrap().get(key) {
+            return Some(*timestamps);
+        }
+        let stored = self
+            .backend
+            .meta(&key.0, &key.1)
+            .map(|meta| meta.timestamps)
+            .unwrap_or_else(|e| failed("read the metadata of", &key.0, &key.1, e));
+        let timestamps = match stored {
+            Some(timestamps) => timestamps,
+            None => {
+                let modified = self
+                    .backend
+                    .modified(&key.0, &key.1)
+                    .unwrap_or_else(|e| failed("read", &key.0, &key.1, e))?;
+                Timestamps {
+                    created: modified,
+                    modified,
+                }
+            }
+        };
+        self.timestamps
+            .write()
+            .unwrap()
+            .insert(key.clone(), timestamps);
+        Some(timestamps)
+    }
+
+    /// Hex SHA-256 hash of the value at the given public key and path
+    ///
+    /// Values stored before the server started are hashed when first asked
+    /// for.
+    pub fn hash(&self, public_key: &PublicKey, path: &str) -> Option<String> {
+        let key = (*public_key, path.to_string());
+        if let Some(hash) = self.hashes.read().unwrap().get(&key) {
+            return Some(hash.clone());
+        }
+        // Writes can't change the value while it is hashed
+        let _writes = self.writes.lock().unwrap();
+        self.locked_hash(&key)
+    }
+
+    /// Hash of the value at `key`, while holding the writes lock
+    fn locked_hash(&self, key: &(PublicKey, String)) -> Option<String> {
+        if let Some(hash) = self.hashes.read().unwrap().get(key) {
+            return Some(hash.clone());
+        }
+        let value = self
+            .get(&key.0, &key.1)
+            .unwrap_or_else(|e| failed("hash", &key.0, &key.1, e))?;
+        let hash = sha256_hex(&value);
+        self.hashes
+            .write()
+            .unwrap()
+            .insert(key.clone(), hash.clone());
+        Some(hash)
     }
 
     /// Retrieve a value at the given public key and path
-    pub fn get(&self, public_key: &PublicKey, path: &str) -> Option<Vec<u8>> {
-        let data = self.data.read().unwrap();
-        data.get(&(*public_key, path.to_string())).cloned()
+    pub fn get(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<Vec<u8>>> {
+        let _timer = self.metrics.time(StorageOp::Get);
+        self.backend
+            .get(public_key, path)
+            .map_err(|e| logged("read", public_key, path, e))
     }
 
-    /// Delete a value at the given public key and path
-    pub fn delete(&self, public_key: &PublicKey, path: &str) -> bool {
-        let mut data = self.data.write().unwrap();
-        data.remove(&(*public_key, path.to_string())).is_some()
+    /// Size in bytes of the value at the given public key and path
+    pub fn size(&self, public_key: &PublicKey, path: &str) -> io::Result<Option<u64>> {
+        let _timer = self.metrics.time(StorageOp::Get);
+        self.backend
+            .size(public_key, path)
+            .map_err(|e| logged("read", public_key, path, e))
+    }
+
+    /// Retrieve the bytes in `range` of a value, cut short at its end
+    pub fn get_range(
+        &self,
+        public_key: &PublicKey,
+        path: &str,
+        range: Range<u64>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let _timer = self.metrics.time(StorageOp::Get);
+        self.backend
+            .get_range(public_key, path, range)
+            .map_err(|e| logged("read", public_key, path, e))
+    }
+
+    /// Delete a value at the given public key and path, returning whether
+    /// there was one
+    pub fn delete(&self, public_key: &PublicKey, path: &str) -> io::Result<bool> {
+        self.delete_if(public_key, path, &|_| true)
+            .map(|deleted| deleted == Some(true))
+    }
+
+    /// Delete a value as [`delete`](Self::delete) does, if `condition` holds
+    /// for the current entry
+    ///
+    /// No other write can happen between the check and the deletion.
+    /// Returns `None`, deleting nothing, if the condition doesn't hold, and
+    /// else whether there was a value.
+    pub fn delete_if(
+        &self,
+        public_key: &PublicKey,
+        path: &str,
+        condition: &dyn Fn(&CurrentEntry) -> bool,
+    ) -> io::Result<Option<bool>> {
+        let _timer = self.metrics.time(StorageOp::Delete);
+        let key = (*public_key, path.to_string());
+        let _writes = self.writes.lock().unwrap();
+        let current = CurrentEntry {
+            storage: self,
+            key: &key,
+        };
+        if !condition(&current) {
+            return Ok(None);
+        }
+        self.tags.write().unwrap().remove(public_key, path);
+        self.hashes.write().unwrap().remove(&key);
+        self.timestamps.write().unwrap().remove(&key);
+        let removed = self
+            .backend
+            .delete(public_key, path)
+            .map_err(|e| logged("delete", public_key, path, e))?;
+        if removed {
+            self.record(EventOp::Delete, *public_key, path.to_string());
+        }
+        Ok(Some(removed))
+    }
+
+    /// Replace the tags of a stored entry
+    ///
+    /// Writing or deleting the entry drops its tags, so set them after
+    /// writing it, or write it with them in its metadata.
+    pub fn set_tags(
+        &self,
+        public_key: PublicKey,
+        path: &str,
+        tags: BTreeSet<String>,
+    ) -> io::Result<()> {
+        let _writes = self.writes.lock().unwrap();
+        if self.size(&public_key, path)?.is_none() {
+            return Ok(());
+        }
+        let meta = EntryMeta {
+            tags,
+            ..self.stored_meta(&public_key, path)?
+        };
+        self.store_meta(public_key, path.to_string(), meta)
+    }
+
+    /// Metadata of an entry, empty if it was written without any
+    pub fn meta(&self, public_key: &PublicKey, path: &str) -> io::Result<EntryMeta> {
+        let meta = self.stored_meta(public_key, path)?;
+        Ok(EntryMeta {
+            timestamps: None,
+            ..meta
+        })
+    }
+
+    /// Metadata of an entry as the backend keeps it, with its timestamps
+    fn stored_meta(&self, public_key: &PublicKey, path: &str) -> io::Result<EntryMeta> {
+        let _timer = self.metrics.time(StorageOp::Get);
+        self.backend
+            .meta(public_key, path)
+            .map_err(|e| logged("read the metadata of", public_key, path, e))
+    }
+
+    /// Tags of an entry, in order
+    pub fn tags(&self, public_key: &PublicKey, path: &str) -> io::Result<Vec<String>> {
+        Ok(self.meta(public_key, path)?.tags.into_iter().collect())
+    }
+
+    /// Paths of the entries of `public_key` carrying every one of `tags`
+    /// under `prefix`
+    pub fn tagged(&self, public_key: &PublicKey, prefix: &str, tags: &[String]) -> Vec<String> {
+        self.index_tags(public_key);
+        let _timer = self.metrics.time(StorageOp::List);
+        let index = self.tags.read().unwrap();
+        let mut sets = Vec::with_capacity(tags.len());
+        for tag in tags {
+            match index.by_tag.get(&(*public_key, tag.clone())) {
+                Some(paths) => sets.push(paths),
+                None => return Vec::new(),
+            }
+        }
+        // Check the rarest tag's entries against the other tags
+        sets.sort_by_key(|paths| paths.len());
+        let Some((rarest, others)) = sets.split_first() else {
+            return Vec::new();
+        };
+        rarest
+            .iter()
+            .filter(|path| path.starts_with(prefix))
+            .filter(|path| others.iter().all(|paths| paths.contains(*path)))
+            .cloned()
+            .collect()
+    }
+
+    /// Add the tags of the entries of `public_key` to the index, unless
+    /// they already are
+    fn index_tags(&self, public_key: &PublicKey) {
+        if self.tags.read().unwrap().indexed.contains(public_key) {
+            return;
+        }
+        let _writes = self.writes.lock().unwrap();
+        let tagged: Vec<_> = self
+            .list(public_key, "")
+            .into_iter()
+            .map(|path| {
+                let meta = self.meta(public_key, &path).unwrap_or_default();
+                (meta.tags, path)
+            })
+            .filter(|(tags, _)| !tags.is_empty())
+            .collect();
+        let mut index = self.tags.write().unwrap();
+        if index.indexed.insert(*public_key) {
+            for (tags, path) in tagged {
+                index.insert(*public_key, &path, tags);
+            }
+        }
     }
 
     /// List all paths for a given public key with a prefix
     pub fn list(&self, public_key: &PublicKey, prefix: &str) -> Vec<String> {
-        let data = self.data.read().unwrap();
-        data.keys()
-            .filter(|(pk, path)| pk == public_key && path.starts_with(prefix))
-            .map(|(_, path)| path.clone())
+        let _timer = self.metrics.time(StorageOp::List);
+        self.backend
+            .list(public_key, prefix)
+            .unwrap_or_else(|e| failed("list", public_key, prefix, e))
+    }
+
+    /// Up to `limit` paths for a given public key with a prefix, in order,
+    /// after `after` if given
+    ///
+    /// Descending with `reverse`, in which case `after` is the path the
+    /// page ends before.
+    pub fn list_page(
+        &self,
+        public_key: &PublicKey,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+        reverse: bool,
+    ) -> Vec<String> {
+        let _timer = self.metrics.time(StorageOp::List);
+        self.backend
+            .list_page(public_key, prefix, after, limit, reverse)
+            .unwrap_or_else(|e| failed("list", public_key, prefix, e))
+    }
+
+    /// Call `visit` with every entry, logging backend errors
+    pub(crate) fn for_each(&self, visit: impl FnMut(&PublicKey, &str, &[u8])) {
+        if let Err(e) = self.try_for_each(visit) {
+            tracing::error!("Failed to iterate over the entries: {}", e);
+        }
+    }
+
+    /// Call `visit` with every entry, stopping at a backend error
+    pub(crate) fn try_for_each(
+        &self,
+        mut visit: impl FnMut(&PublicKey, &str, &[u8]),
+    ) -> io::Result<()> {
+        self.backend.for_each(&mut visit)
+    }
+
+    /// Latency metrics of the storage operations
+    pub fn metrics(&self) -> &StorageMetrics {
+        &self.metrics
+    }
+
+    /// Rough bytes held by each part of the storage, ignoring the
+    /// overhead of the maps holding them
+    pub fn memory_estimates(&self) -> Vec<(&'static str, usize)> {
+        const KEY: usize = size_of::<(PublicKey, String)>();
+        let entries = self.backend.memory_bytes();
+        let hashes = {
+            let hashes = self.hashes.read().unwrap();
+            hashes
+                .iter()
+                .map(|((_, path), hash)| KEY + path.len() + size_of::<String>() + hash.len())
+                .sum()
+        };
+        let timestamps = {
+            let timestamps = self.timestamps.read().unwrap();
+            timestamps
+                .keys()
+                .map(|(_, path)| KEY + path.len() + size_of::<Timestamps>())
+                .sum()
+        };
+        let tags = {
+            let index = self.tags.read().unwrap();
+            let strings = |set: &BTreeSet<String>| -> usize {
+                set.iter().map(|s| size_of::<String>() + s.len()).sum()
+            };
+            index
+                .by_entry
+                .iter()
+                .chain(&index.by_tag)
+                .map(|((_, name), set)| KEY + name.len() + strings(set))
+                .sum()
+        };
+        let quarantine = {
+            let quarantine = self.quarantine.read().unwrap();
+            quarantine
+                .iter()
+                .map(|((_, path), held)| {
+                    KEY + path.len()
+                        + size_of::<Quarantined>()
+                        + held.value.len()
+                        + held.reason.len()
+                })
+                .sum()
+        };
+        let sessions = {
+            let sessions = self.sessions.read().unwrap();
+            sessions
+                .iter()
+                .map(|(token_hash, session)| {
+                    size_of::<(String, Session)>()
+                        + token_hash.len()
+                        + session.id.len()
+                        + session.device.as_ref().map_or(0, String::len)
+                        + session.capabilities.len()
+                })
+                .sum()
+        };
+        let auth = {
+            let requests = self.auth_requests.read().unwrap();
+            let grants = self.redeemed_grants.read().unwrap();
+            let requests: usize = requests
+                .iter()
+                .map(|(id, request)| {
+                    size_of::<(String, AuthRequest)>()
+                        + id.len()
+                        + request.challenge.len()
+                        + request.secret.len()
+                        + request.capabilities.len()
+                })
+                .sum();
+            let grants = grants.len() * size_of::<([u8; 64], u64)>();
+            // Each nonce is held twice, by signer and by expiry
+            let nonces: usize = self
+                .used_nonces
+                .lock()
+                .unwrap()
+                .by_signer
+                .iter()
+                .map(|(_, nonce)| 2 * (KEY + nonce.len()))
+                .sum();
+            requests + grants + nonces
+        };
+        let events = {
+            let log = self.events.read().unwrap();
+            log.events
+                .iter()
+                .map(|event| size_of::<Event>() + event.path.len())
+                .sum()
+        };
+
+        vec![
+            ("entries", entries),
+            ("hashes", hashes),
+            ("timestamps", timestamps),
+            ("tags", tags),
+            ("quarantine", quarantine),
+            ("sessions", sessions),
+            ("auth", auth),
+            ("events", events),
+        ]
+    }
+
+    /// List every public key with stored data, sorted by key
+    ///
+    /// Sizes come from the backend, without reading any value.
+    pub fn users(&self) -> Vec<UserUsage> {
+        let mut usage: HashMap<PublicKey, UserUsage> = HashMap::new();
+        let mut visit = |public_key: &PublicKey, _: &str, size: u64| {
+            let user = usage.entry(*public_key).or_insert(UserUsage {
+                public_key: *public_key,
+                entries: 0,
+                bytes: 0,
+            });
+            user.entries += 1;
+            user.bytes += size as usize;
+        };
+        if let Err(e) = self.backend.for_each_size(&mut visit) {
+            tracing::error!("Failed to iterate over the entry sizes: {}", e);
+        }
+
+        let mut users: Vec<UserUsage> = usage.into_values().collect();
+        users.sort_by_key(|user| user.public_key.to_z32());
+        users
+    }
+
+    /// Delete every entry stored for a public key, returning how many were removed
+    pub fn purge(&self, public_key: &PublicKey) -> usize {
+        let writes = self.writes.lock().unwrap();
+        let mut removed = 0;
+        for path in self.list(public_key, "") {
+            let deleted = self
+                .backend
+                .delete(public_key, &path)
+                .unwrap_or_else(|e| failed("delete", public_key, &path, e));
+            if deleted {
+                self.record(EventOp::Delete, *public_key, path);
+                removed += 1;
+            }
+        }
+        self.hashes
+            .write()
+            .unwrap()
+            .retain(|(pk, _), _| pk != public_key);
+        self.timestamps
+            .write()
+            .unwrap()
+            .retain(|(pk, _), _| pk != public_key);
+        let mut tags = self.tags.write().unwrap();
+        tags.by_entry.retain(|(pk, _), _| pk != public_key);
+        tags.by_tag.retain(|(pk, _), _| pk != public_key);
+        drop((tags, writes));
+        self.quarantine
+            .write()
+            .unwrap()
+            .retain(|(pk, _), _| pk != public_key);
+        tracing::debug!("Purged {} entries for {}", removed, public_key);
+        removed
+    }
+
+    /// Snapshot of all entries, sorted by public key and path
+    pub fn entries(&self) -> Vec<(PublicKey, String, Vec<u8>)> {
+        let mut entries = Vec::new();
+        self.for_each(|pk, path, value| entries.push((*pk, path.to_string(), value.to_vec())));
+        entries.sort_by(|a, b| (a.0.to_z32(), &a.1).cmp(&(b.0.to_z32(), &b.1)));
+        entries
+    }
+
+    /// Every entry keyed by `{public_key}/{path}`, for reconciliation
+    ///
+    /// Items of entries with metadata cover it too, so a value rewritten
+    /// with other metadata differs.
+    pub fn item_set(&self) -> ItemSet {
+        let mut items = Vec::new();
+        self.for_each(|pk, path, value| {
+            let key = format!("{}/{}", pk, path);
+            let meta = self.meta(pk, path).unwrap_or_default();
+            if meta.is_empty() {
+                return items.push(Item::new(key, value));
+            }
+            let mut hasher = Sha256::new();
+            hasher.update(Sha256::digest(value));
+            hasher.update(serde_json::to_vec(&meta).expect("metadata serializes"));
+            let hash = hex(&hasher.finalize());
+            items.push(Item { key, hash });
+        });
+        ItemSet::new(items)
+    }
+
+    /// Replace all stored entries with the given snapshot
+    ///
+    /// Records a put for every restored entry and a delete for every other
+    /// entry removed, as writes do.
+    pub fn restore(&self, entries: Vec<(PublicKey, String, Vec<u8>)>) {
+        self.quarantine.write().unwrap().clear();
+        let _writes = self.writes.lock().unwrap();
+        *self.tags.write().unwrap() = TagIndex::default();
+        self.hashes.write().unwrap().clear();
+        let mut timestamps = self.timestamps.write().unwrap();
+        timestamps.clear();
+        let now = self.now_millis();
+        let mut existing = Vec::new();
+        self.for_each(|pk, path, _| existing.push((*pk, path.to_string())));
+        let restored: HashSet<_> = entries.iter().map(|(pk, path, _)| (*pk, path)).collect();
+        for (pk, path) in existing {
+            match self.backend.delete(&pk, &path) {
+                Ok(_) if !restored.contains(&(pk, &path)) => self.record(EventOp::Delete, pk, path),
+                Ok(_) => {}
+                Err(e) => failed("delete", &pk, &path, e),
+            }
+        }
+        let restored = Timestamps {
+            created: now,
+            modified: now,
+        };
+        let meta = EntryMeta {
+            timestamps: Some(restored),
+            ..Default::default()
+        };
+        for (pk, path, value) in entries {
+            match self.backend.put(pk, path.clone(), value) {
+                Ok(()) => {
+                    if let Err(e) = self.backend.put_meta(pk, path.clone(), meta.clone()) {
+                        failed("store the metadata of", &pk, &path, e)
+                    }
+                    timestamps.insert((pk, path.clone()), restored);
+                    self.record(EventOp::Put, pk, path);
+                }
+                Err(e) => failed("store", &pk, &path, e),
+            }
+        }
+    }
+
+    /// Sequence number of the latest recorded event (0 if none)
+    pub fn head_seq(&self) -> u64 {
+        self.events.read().unwrap().head_seq
+    }
+
+    /// Events with a sequence number greater than `after`, oldest first
+    ///
+    /// Returns `None` if some of those events were already dropped from the
+    /// bounded log, in which case the consumer has to resynchronize fully.
+    pub fn events_since(&self, after: u64, limit: usize) -> Option<Vec<Event>> {
+        let log = self.events.read().unwrap();
+        let oldest = log
+            .events
+            .front()
+            .map(|e| e.seq)
+            .unwrap_or(log.head_seq + 1);
+        if after + 1 < oldest {
+            return None;
+        }
+
+        Some(
+            log.events
+                .iter()
+                .skip_while(|e| e.seq <= after)
+                .take(limit)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Wait until an event with a sequence number greater than `after` exists
+    pub async fn wait_for_events(&self, after: u64) {
+        loop {
+            let notified = self.events_notify.notified();
+            if self.head_seq() > after {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Append an event to the log; callers hold the write lock
+    fn record(&self, op: EventOp, public_key: PublicKey, path: String) {
+        let mut log = self.events.write().unwrap();
+        log.head_seq += 1;
+        let event = Event {
+            seq: log.head_seq,
+            timestamp: self.now_millis(),
+            op,
+            public_key,
+            path,
+        };
+        log.events.push_back(event);
+        if log.events.len() > MAX_EVENTS {
+            log.events.pop_front();
+        }
+        drop(log);
+        self.events_notify.notify_waiters();
+    }
+
+    /// Freeze an account, replacing any existing freeze
+    ///
+    /// Frozen accounts reject writes and deletes at the API and are excluded
+    /// from purges and any other cleanup until unfrozen.
+    pub fn freeze(&self, public_key: PublicKey, reason: String, block_reads: bool) -> Freeze {
+        let freeze = Freeze {
+            reason,
+            block_reads,
+            since: self.now_millis(),
+        };
+        self.frozen
+            .write()
+            .unwrap()
+            .insert(public_key, freeze.clone());
+        self.save_state();
+        tracing::info!("Froze account {}", public_key);
+        freeze
+    }
+
+    /// Lift the freeze on an account, returning whether it was frozen
+    pub fn unfreeze(&self, public_key: &PublicKey) -> bool {
+        let unfrozen = self.frozen.write().unwrap().remove(public_key).is_some();
+        if unfrozen {
+            self.save_state();
+        }
+        unfrozen
+    }
+
+    /// The freeze on an account, if any
+    pub fn frozen(&self, public_key: &PublicKey) -> Option<Freeze> {
+        self.frozen.read().unwrap().get(public_key).cloned()
+    }
+
+    /// All frozen accounts, sorted by key
+    pub fn frozen_accounts(&self) -> Vec<(PublicKey, Freeze)> {
+        let mut accounts: Vec<_> = self
+            .frozen
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(pk, freeze)| (*pk, freeze.clone()))
+            .collect();
+        accounts.sort_by_key(|(pk, _)| pk.to_z32());
+        accounts
+    }
+
+    /// Hold a value for `path` back from readers, replacing any value
+    /// already held for it
+    ///
+    /// The stored entry, if any, is left as it is.
+    pub fn quarantine(&self, public_key: PublicKey, path: String, value: Vec<u8>, reason: String) {
+        let quarantined = Quarantined {
+            value,
+            reason,
+            since: self.now_millis(),
+            pending: false,
+        };
+        self.quarantine
+            .write()
+            .unwrap()
+            .insert((public_key, path), quarantined);
+    }
+
+    /// Hold a value for `path` back from readers until its content scan
+    /// settles, replacing any value already held for it
+    pub fn hold_for_scan(&self, public_key: PublicKey, path: String, value: Vec<u8>) {
+        let quarantined = Quarantined {
+            value,
+            reason: "Pending scan".to_string(),
+            since: self.now_millis(),
+            pending: true,
+        };
+        self.quarantine
+            .write()
+            .unwrap()
+            .insert((public_key, path), quarantined);
+    }
+
+    /// Settle the scan of `value`, held for `path`: accepted values are
+    /// stored, rejected ones dropped and quarantined ones kept for review
+    ///
+    /// Returns false, changing nothing, if the value was released,
+    /// discarded or replaced since the scan started. Accepted values the
+    /// backend fails to store stay held.
+    pub fn settle_scan(
+        &self,
+        public_key: &PublicKey,
+        path: &str,
+        value: &[u8],
+        verdict: Verdict,
+    ) -> io::Result<bool> {
+        let key = (*public_key, path.to_string());
+        let mut quarantine = self.quarantine.write().unwrap();
+        match quarantine.get_mut(&key) {
+            Some(held) if held.pending && held.value == value => match verdict {
+                Verdict::Accept => {
+                    let held = quarantine.remove(&key).expect("held value exists");
+                    drop(quarantine);
+                    self.put_held(key, held)?;
+                }
+                Verdict::Reject(_) => {
+                    quarantine.remove(&key);
+                }
+                Verdict::Quarantine(reason) => {
+                    held.reason = reason;
+                    held.pending = false;
+                }
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Values held back by moderation, sorted by key and path
+    pub fn quarantined(&self) -> Vec<(PublicKey, String, Quarantined)> {
+        let mut entries: Vec<_> = self
+            .quarantine
+            .read()
+            .unwrap()
+            .iter()
+            .map(|((pk, path), quarantined)| (*pk, path.clone(), quarantined.clone()))
+            .collect();
+        entries.sort_by(|a, b| (a.0.to_z32(), &a.1).cmp(&(b.0.to_z32(), &b.1)));
+        entries
+    }
+
+    /// Store a held-back value at its path, returning whether there was one
+    ///
+    /// The value stays held if the backend fails to store it.
+    pub fn release(&self, public_key: &PublicKey, path: &str) -> io::Result<bool> {
+        let key = (*public_key, path.to_string());
+        let Some(quarantined) = self.quarantine.write().unwrap().remove(&key) else {
+            return Ok(false);
+        };
+        self.put_held(key, quarantined)?;
+        Ok(true)
+    }
+
+    /// Store a value taken out of the quarantine, holding it again on failure
+    fn put_held(&self, key: (PublicKey, String), held: Quarantined) -> io::Result<()> {
+        let stored = self.put(key.0, key.1.clone(), held.value.clone());
+        if stored.is_err() {
+            self.quarantine.write().unwrap().entry(key).or_insert(held);
+        }
+        stored
+    }
+
+    /// Drop a held-back value, returning whether there was one
+    pub fn discard(&self, public_key: &PublicKey, path: &str) -> bool {
+        let key = (*public_key, path.to_string());
+        self.quarantine.write().unwrap().remove(&key).is_some()
+    }
+
+    /// Register a new invite code
+    pub fn add_invite(&self, code: String) {
+        self.invites.write().unwrap().insert(code);
+        self.save_state();
+    }
+
+    /// Consume an invite code, returning whether it was valid
+    pub fn take_invite(&self, code: &str) -> bool {
+        let taken = self.invites.write().unwrap().remove(code);
+        if taken {
+            self.save_state();
+        }
+        taken
+    }
+
+    /// List all unused invite codes
+    pub fn invites(&self) -> Vec<String> {
+        let mut invites: Vec<String> = self.invites.read().unwrap().iter().cloned().collect();
+        invites.sort();
+        invites
+    }
+
+    /// Register a signed-up account, returning whether it is new
+    pub fn register(&self, public_key: PublicKey) -> bool {
+        let new = self.accounts.write().unwrap().insert(public_key);
+        if new {
+            self.save_state();
+        }
+        new
+    }
+
+    /// Whether the account has signed up
+    pub fn is_registered(&self, public_key: &PublicKey) -> bool {
+        self.accounts.read().unwrap().contains(public_key)
+    }
+
+    /// Record that the account moved to the homeserver at `url`
+    pub fn set_moved(&self, public_key: PublicKey, url: String) {
+        self.moved.write().unwrap().insert(public_key, url);
+        self.save_state();
+    }
+
+    /// Base URL of the homeserver the account moved to, if it did
+    pub fn moved_to(&self, public_key: &PublicKey) -> Option<String> {
+        self.moved.read().unwrap().get(public_key).cloned()
+    }
+
+    /// Claim a handle for an account, releasing its previous one
+    ///
+    /// Returns false if another account holds the handle.
+    pub fn claim_handle(&self, handle: String, public_key: PublicKey) -> bool {
+        let mut handles = self.handles.write().unwrap();
+        if handles
+            .get(&handle)
+            .is_some_and(|owner| *owner != public_key)
+        {
+            return false;
+        }
+        handles.retain(|_, owner| *owner != public_key);
+        handles.insert(handle, public_key);
+        drop(handles);
+        self.save_state();
+        true
+    }
+
+    /// Account holding a handle, if any
+    pub fn handle_owner(&self, handle: &str) -> Option<PublicKey> {
+        self.handles.read().unwrap().get(handle).copied()
+    }
+
+    /// Handle claimed by an account, if any
+    pub fn handle_of(&self, public_key: &PublicKey) -> Option<String> {
+        let handles = self.handles.read().unwrap();
+        handles
+            .iter()
+            .find(|(_, owner)| *owner == public_key)
+            .map(|(handle, _)| handle.clone())
+    }
+
+    /// Release the handle of an account, returning whether it had one
+    pub fn release_handle(&self, public_key: &PublicKey) -> bool {
+        let mut handles = self.handles.write().unwrap();
+        let before = handles.len();
+        handles.retain(|_, owner| owner != public_key);
+        let released = handles.len() != before;
+        drop(handles);
+        if released {
+            self.save_state();
+        }
+        released
+    }
+
+    /// Alias an account by a verified domain, replacing any previous alias
+    pub fn set_domain(&self, domain: String, public_key: PublicKey) {
+        self.domains.write().unwrap().insert(domain, public_key);
+        self.save_state();
+    }
+
+    /// Account a domain aliases, if any
+    pub fn domain_owner(&self, domain: &str) -> Option<PublicKey> {
+        self.domains.read().unwrap().get(domain).copied()
+    }
+
+    /// Remove a domain alias of an account, returning whether it existed
+    pub fn remove_domain(&self, domain: &str, public_key: &PublicKey) -> bool {
+        let mut domains = self.domains.write().unwrap();
+        if domains.get(domain) != Some(public_key) {
+            return false;
+        }
+        domains.remove(domain);
+        drop(domains);
+        self.save_state();
+        true
+    }
+
+    /// Whether the public key has an account or any entries here
+    pub fn hosts(&self, public_key: &PublicKey) -> bool {
+        self.is_registered(public_key) || !self.list(public_key, "").is_empty()
+    }
+
+    /// Link a Nostr identity to an account with the capabilities its
+    /// sessions get, replacing any previous link
+    #[cfg(feature = "multi-alg")]
+    pub fn link_nostr(&self, nostr_key: String, public_key: PublicKey, capabilities: String) {
+        let link = NostrLink {
+            public_key,
+            capabilities,
+        };
+        self.nostr_links.write().unwrap().insert(nostr_key, link);
+        self.save_state();
+    }
+
+    /// Link of a Nostr identity to an account, if any
+    #[cfg(feature = "multi-alg")]
+    pub fn nostr_link(&self, nostr_key: &str) -> Option<NostrLink> {
+        self.nostr_links.read().unwrap().get(nostr_key).cloned()
+    }
+
+    /// Unlink a Nostr identity from an account, returning whether it was
+    /// linked to it
+    #[cfg(feature = "multi-alg")]
+    pub fn unlink_nostr(&self, nostr_key: &str, public_key: &PublicKey) -> bool {
+        let mut links = self.nostr_links.write().unwrap();
+        if links.get(nostr_key).map(|link| link.public_key) != Some(*public_key) {
+            return false;
+        }
+        links.remove(nostr_key);
+        drop(links);
+        self.save_state();
+        true
+    }
+
+    /// Store a session under the given token
+    pub fn insert_session(&self, token: String, session: Session) {
+        let now = self.now_millis();
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(sha256_hex(token.as_bytes()), session);
+        drop(sessions);
+        self.save_state();
+    }
+
+    /// Look up an unexpired session by token, recording its use
+    ///
+    /// Uses are saved with the next change to the account state, so a
+    /// restart may forget the latest.
+    pub fn session(&self, token: &str) -> Option<Session> {
+        let now = self.now_millis();
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions
+            .get_mut(&sha256_hex(token.as_bytes()))
+            .filter(|session| session.expires_at > now)?;
+        session.last_used_at = now;
+        Some(session.clone())
+    }
+
+    /// Remove a session, returning whether it existed
+    pub fn remove_session(&self, token: &str) -> bool {
+        let token_hash = sha256_hex(token.as_bytes());
+        let removed = self.sessions.write().unwrap().remove(&token_hash).is_some();
+        if removed {
+            self.save_state();
+        }
+        removed
+    }
+
+    /// Store an authorization request under the given id
+    pub fn insert_auth_request(&self, id: String, request: AuthRequest) {
+        let now = self.now_millis();
+        let mut requests = self.auth_requests.write().unwrap();
+        requests.retain(|_, request| request.expires_at > now);
+        requests.insert(id, request);
+    }
+
+    /// Look up an unexpired authorization request by id
+    pub fn auth_request(&self, id: &str) -> Option<AuthRequest> {
+        let requests = self.auth_requests.read().unwrap();
+        requests
+            .get(id)
+            .filter(|request| request.expires_at > self.now_millis())
+            .cloned()
+    }
+
+    /// Record the approval of a pending authorization request, returning
+    /// whether it was pending
+    pub fn approve_auth_request(&self, id: &str, public_key: PublicKey) -> bool {
+        let mut requests = self.auth_requests.write().unwrap();
+        match requests.get_mut(id) {
+            Some(request) if request.approved_by.is_none() => {
+                request.approved_by = Some(public_key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove an authorization request, returning it if it existed
+    pub fn remove_auth_request(&self, id: &str) -> Option<AuthRequest> {
+        self.auth_requests.write().unwrap().remove(id)
+    }
+
+    /// Record that the grant with `signature` was redeemed, returning
+    /// whether it wasn't already; grants are forgotten once they expire
+    pub fn redeem_grant(&self, signature: [u8; 64], expires_at: u64) -> bool {
+        let now = self.now_millis();
+        let mut grants = self.redeemed_grants.write().unwrap();
+        grants.retain(|_, expires_at| *expires_at > now);
+        grants.insert(signature, expires_at).is_none()
+    }
+
+    /// Record that `public_key` signed a message with `nonce`, returning
+    /// whether it hadn't before; nonces are forgotten once they expire,
+    /// when the message is too old to be accepted anyway
+    pub fn use_nonce(&self, public_key: &PublicKey, nonce: &str, expires_at: u64) -> bool {
+        let mut nonces = self.used_nonces.lock().unwrap();
+        nonces.expire(self.now_millis());
+        let key = (*public_key, nonce.to_string());
+        if !nonces.by_signer.insert(key.clone()) {
+            return false;
+        }
+        nonces.by_expiry.entry(expires_at).or_default().push(key);
+        true
+    }
+
+    /// Forget that `public_key` used `nonce`, for a message whose request
+    /// failed and may be sent again
+    pub fn release_nonce(&self, public_key: &PublicKey, nonce: &str) {
+        let key = (*public_key, nonce.to_string());
+        self.used_nonces.lock().unwrap().by_signer.remove(&key);
+    }
+
+    /// Unexpired sessions of an account, oldest first
+    pub fn sessions_of(&self, public_key: &PublicKey) -> Vec<Session> {
+        let now = self.now_millis();
+        let sessions = self.sessions.read().unwrap();
+        let mut sessions: Vec<_> = sessions
+            .values()
+            .filter(|session| session.public_key == *public_key && session.expires_at > now)
+            .cloned()
+            .collect();
+        sessions.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        sessions
+    }
+
+    /// Revoke the session of an account with the given id, returning
+    /// whether it existed
+    pub fn revoke_session(&self, public_key: &PublicKey, id: &str) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.public_key != *public_key || session.id != id);
+        let revoked = sessions.len() != before;
+        drop(sessions);
+        if revoked {
+            self.save_state();
+        }
+        revoked
+    }
+
+    /// Save the account state with the backend, if it keeps it
+    fn save_state(&self) {
+        if !self.saves_state {
+            return;
+        }
+        let _saving = self.saving.lock().unwrap();
+        let z32 = |public_key: &PublicKey| public_key.to_z32();
+        let state = SavedState {
+            invites: self.invites.read().unwrap().iter().cloned().collect(),
+            accounts: self.accounts.read().unwrap().iter().map(z32).collect(),
+            sessions: self.sessions.read().unwrap().clone().into_iter().collect(),
+            frozen: self
+                .frozen
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(pk, freeze)| (z32(pk), freeze.clone()))
+                .collect(),
+            moved: self
+                .moved
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(pk, url)| (z32(pk), url.clone()))
+                .collect(),
+            handles: self
+                .handles
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(handle, pk)| (handle.clone(), z32(pk)))
+                .collect(),
+            domains: self
+                .domains
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(domain, pk)| (domain.clone(), z32(pk)))
+                .collect(),
+            #[cfg(feature = "multi-alg")]
+            nostr_links: self
+                .nostr_links
+                .read()
+                .unwrap()
+                .clone()
+                .into_iter()
+                .collect(),
+        };
+        let state = serde_json::to_vec(&state).expect("state serializes");
+        if let Err(e) = self.backend.save_state(&state) {
+            tracing::error!("Failed to save the account state: {}", e);
+        }
+    }
+
+    /// Restore the account state the backend saved, if any, returning
+    /// whether there was none or it was restored
+    ///
+    /// A state that fails to load is left as it is rather than replaced.
+    fn load_state(&self) -> bool {
+        let state = match self.backend.load_state() {
+            Ok(Some(json)) => serde_json::from_slice::<SavedState>(&json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Ok(None) => return true,
+            Err(e) => Err(e),
+        };
+        let state = match state {
+            Ok(state) => state,
+            Err(e) => {
+                tracing::error!("Failed to load the account state, not saving it: {}", e);
+                return false;
+            }
+        };
+        let key = |z32: String| PublicKey::from_z32(&z32).ok();
+        *self.invites.write().unwrap() = state.invites.into_iter().collect();
+        *self.accounts.write().unwrap() = state.accounts.into_iter().filter_map(key).collect();
+        *self.sessions.write().unwrap() = state.sessions.into_iter().collect();
+        *self.frozen.write().unwrap() = state
+            .frozen
+            .into_iter()
+            .filter_map(|(pk, freeze)| Some((key(pk)?, freeze)))
+            .collect();
+        *self.moved.write().unwrap() = state
+            .moved
+            .into_iter()
+            .filter_map(|(pk, url)| Some((key(pk)?, url)))
+            .collect();
+        *self.handles.write().unwrap() = state
+            .handles
+            .into_iter()
+            .filter_map(|(handle, pk)| Some((handle, key(pk)?)))
+            .collect();
+        *self.domains.write().unwrap() = state
+            .domains
+            .into_iter()
+            .filter_map(|(domain, pk)| Some((domain, key(pk)?)))
+            .collect();
+        #[cfg(feature = "multi-alg")]
+        {
+            *self.nostr_links.write().unwrap() = state.nostr_links.into_iter().collect();
+        }
+        true
+    }
+}
+
+/// Public keys serialized in z-base-32
+mod z32 {
+    use pubky_common::PublicKey;
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        public_key: &PublicKey,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&public_key.to_z32())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PublicKey, D::Error> {
+        let z32 = String::deserialize(deserializer)?;
+        PublicKey::from_z32(&z32).map_err(D::Error::custom)
+    }
+}
+
+/// Siblings serialized with base64-encoded values
+mod base64_siblings {
+    use super::Siblings;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+    use pubky_common::version::VersionVector;
+    use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Sibling {
+        version: VersionVector,
+        value: String,
+    }
+
+    pub fn serialize<S: Serializer>(siblings: &Siblings, serializer: S) -> Result<S::Ok, S::Error> {
+        let siblings: Vec<_> = siblings
+            .iter()
+            .map(|(version, value)| Sibling {
+                version: version.clone(),
+                value: BASE64.encode(value),
+            })
+            .collect();
+        siblings.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Siblings, D::Error> {
+        Vec::<Sibling>::deserialize(deserializer)?
+            .into_iter()
+            .map(|sibling| {
+                let value = BASE64.decode(&sibling.value).map_err(D::Error::custom)?;
+                Ok((sibling.version, value))
+            })
             .collect()
     }
 }
 
+/// Version covering every sibling
+fn merge(siblings: &Siblings) -> VersionVector {
+    let mut merged = VersionVector::new();
+    for (version, _) in siblings {
+        merged.merge(version);
+    }
+    merged
+}
+
+/// A reader hashing what it reads
+struct HashingReader<'a> {
+    inner: &'a mut dyn Read,
+    hasher: Sha256,
+}
+
+impl Read for HashingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Hex encoding of a hash
+fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Log a failed backend operation, treating the entry as missing
+fn failed<T: Default>(op: &str, public_key: &PublicKey, path: &str, e: std::io::Error) -> T {
+    logged(op, public_key, path, e);
+    T::default()
+}
+
+/// Log a backend error, returning it
+fn logged(op: &str, public_key: &PublicKey, path: &str, e: std::io::Error) -> std::io::Error {
+    tracing::error!("Failed to {} {} for {}: {}", op, path, public_key, e);
+    e
+}
+
 impl Default for Storage {
     fn default() -> Self {
         Self::new()
@@ -58,7 +1832,10 @@ impl Default for Storage {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
     use pubky_common::Keypair;
+    use std::collections::BTreeMap;
 
     #[test]
     fn test_storage_operations() {
@@ -69,15 +1846,22 @@ mod tests {
         let value = b"Hello, World!".to_vec();
 
         // Put
-        storage.put(public_key, path.clone(), value.clone());
+        storage
+            .put(public_key, path.clone(), value.clone())
+            .unwrap();
 
         // Get
-        let retrieved = storage.get(&public_key, &path);
+        let retrieved = storage.get(&public_key, &path).unwrap();
         assert_eq!(retrieved, Some(value));
 
         // Delete
-        assert!(storage.delete(&public_key, &path));
-        assert_eq!(storage.get(&public_key, &path), None);
+        assert!(storage.delete(&public_key, &path).unwrap());
+        assert_eq!(storage.get(&public_key, &path).unwrap(), None);
+
+        // Every operation is timed
+        assert_eq!(storage.metrics().histogram(StorageOp::Put).count(), 1);
+        assert_eq!(storage.metrics().histogram(StorageOp::Get).count(), 2);
+        assert_eq!(storage.metrics().histogram(StorageOp::Delete).count(), 1);
     }
 
     #[test]
@@ -86,13 +1870,270 @@ mod tests {
         let keypair = Keypair::random();
         let public_key = keypair.public_key();
 
-        storage.put(public_key, "app/file1.txt".to_string(), vec![1]);
-        storage.put(public_key, "app/file2.txt".to_string(), vec![2]);
-        storage.put(public_key, "other/file3.txt".to_string(), vec![3]);
+        storage
+            .put(public_key, "app/file1.txt".to_string(), vec![1])
+            .unwrap();
+        storage
+            .put(public_key, "app/file2.txt".to_string(), vec![2])
+            .unwrap();
+        storage
+            .put(public_key, "other/file3.txt".to_string(), vec![3])
+            .unwrap();
 
         let app_files = storage.list(&public_key, "app/");
         assert_eq!(app_files.len(), 2);
         assert!(app_files.contains(&"app/file1.txt".to_string()));
         assert!(app_files.contains(&"app/file2.txt".to_string()));
     }
+
+    #[test]
+    fn test_storage_admin_operations() {
+        let storage = Storage::new();
+        let alice = Keypair::random().public_key();
+        let bob = Keypair::random().public_key();
+
+        storage
+            .put(alice, "app/a.txt".to_string(), vec![1, 2, 3])
+            .unwrap();
+        storage
+            .put(alice, "app/b.txt".to_string(), vec![4])
+            .unwrap();
+        storage
+            .put(bob, "app/c.txt".to_string(), vec![5, 6])
+            .unwrap();
+
+        let users = storage.users();
+        assert_eq!(users.len(), 2);
+        let alice_usage = users.iter().find(|u| u.public_key == alice).unwrap();
+        assert_eq!((alice_usage.entries, alice_usage.bytes), (2, 4));
+
+        let snapshot = storage.entries();
+        assert_eq!(snapshot.len(), 3);
+
+        assert_eq!(storage.purge(&alice), 2);
+        assert_eq!(storage.users().len(), 1);
+        assert_eq!(storage.get(&bob, "app/c.txt").unwrap(), Some(vec![5, 6]));
+
+        // Restores are recorded as writes
+        let head = storage.head_seq();
+        storage.restore(snapshot);
+        assert_eq!(storage.users().len(), 2);
+        let events = storage.events_since(head, 10).unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| event.op == EventOp::Put));
+        storage.restore(Vec::new());
+        let events = storage.events_since(head + 3, 10).unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| event.op == EventOp::Delete));
+
+        storage.add_invite("CODE".to_string());
+        assert_eq!(storage.invites(), vec!["CODE".to_string()]);
+        assert!(storage.take_invite("CODE"));
+        assert!(!storage.take_invite("CODE"));
+    }
+
+    #[test]
+    fn test_storage_event_log() {
+        let storage = Storage::new();
+        let public_key = Keypair::random().public_key();
+
+        storage
+            .put(public_key, "a.txt".to_string(), vec![1])
+            .unwrap();
+        storage
+            .put(public_key, "b.txt".to_string(), vec![2])
+            .unwrap();
+        assert!(storage.delete(&public_key, "a.txt").unwrap());
+        assert!(!storage.delete(&public_key, "missing.txt").unwrap());
+        assert_eq!(storage.head_seq(), 3);
+
+        let events = storage.events_since(1, 10).unwrap();
+        let ops: Vec<_> = events
+            .iter()
+            .map(|e| (e.seq, e.op, e.path.as_str()))
+            .collect();
+        assert_eq!(
+            ops,
+            vec![(2, EventOp::Put, "b.txt"), (3, EventOp::Delete, "a.txt")]
+        );
+
+        assert_eq!(storage.purge(&public_key), 1);
+        assert_eq!(storage.events_since(3, 10).unwrap()[0].op, EventOp::Delete);
+        assert!(storage.events_since(4, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_storage_freeze() {
+        let storage = Storage::new();
+        let public_key = Keypair::random().public_key();
+
+        assert!(storage.frozen(&public_key).is_none());
+        storage.freeze(public_key, "investigation".to_string(), false);
+        assert_eq!(storage.frozen(&public_key).unwrap().reason, "investigation");
+        assert_eq!(storage.frozen_accounts().len(), 1);
+
+        assert!(storage.unfreeze(&public_key));
+        assert!(!storage.unfreeze(&public_key));
+        assert!(storage.frozen_accounts().is_empty());
+    }
+
+    #[test]
+    fn test_storage_versions() {
+        let storage = Storage::new();
+        let public_key = Keypair::random().public_key();
+        let path = "notes.txt".to_string();
+
+        let (base, count) = storage
+            .put_versioned(
+                public_key,
+                path.clone(),
+                vec![1],
+                EntryMeta::default(),
+                "laptop",
+                &VersionVector::new(),
+            )
+            .unwrap();
+        assert_eq!((base.to_string().as_str(), count), ("laptop=1", 1));
+
+        // Two devices edit the same version while offline
+        let meta = EntryMeta::default;
+        let (_, count) = storage
+            .put_versioned(public_key, path.clone(), vec![2], meta(), "laptop", &base)
+            .unwrap();
+        assert_eq!(count, 1);
+        let (version, count) = storage
+            .put_versioned(public_key, path.clone(), vec![3], meta(), "phone", &base)
+            .unwrap();
+        assert_eq!(
+            (version.to_string().as_str(), count),
+            ("laptop=2,phone=1", 2)
+        );
+        let values: Vec<_> = storage
+            .siblings(&public_key, &path)
+            .unwrap()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(values, vec![vec![2], vec![3]]);
+
+        // A write that saw both siblings resolves the conflict
+        let (_, count) = storage
+            .put_versioned(public_key, path.clone(), vec![4], meta(), "phone", &version)
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(storage.get(&public_key, &path).unwrap(), Some(vec![4]));
+
+        // Unversioned writes drop the version
+        storage.put(public_key, path.clone(), vec![5]).unwrap();
+        assert_eq!(storage.version(&public_key, &path).unwrap(), None);
+        assert_eq!(
+            storage.siblings(&public_key, &path).unwrap(),
+            vec![(VersionVector::new(), vec![5])]
+        );
+    }
+
+    #[test]
+    fn test_storage_tags() {
+        let storage = Storage::new();
+        let public_key = Keypair::random().public_key();
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect();
+        let tagged = |prefix: &str, tags: &[&str]| {
+            let tags: Vec<String> = tags.iter().map(|tag| tag.to_string()).collect();
+            let mut paths = storage.tagged(&public_key, prefix, &tags);
+            paths.sort();
+            paths
+        };
+        for (path, photo_tags) in [
+            ("photos/a.jpg", &["album:2024", "place:alps"][..]),
+            ("photos/b.jpg", &["album:2024"]),
+            ("photos/c.jpg", &["album:2023", "place:alps"]),
+            ("notes/alps.md", &["place:alps"]),
+        ] {
+            storage.put(public_key, path.to_string(), vec![1]).unwrap();
+            storage
+                .set_tags(public_key, path, tags(photo_tags))
+                .unwrap();
+        }
+
+        assert_eq!(
+            tagged("", &["album:2024"]),
+            ["photos/a.jpg", "photos/b.jpg"]
+        );
+        assert_eq!(tagged("", &["album:2024", "place:alps"]), ["photos/a.jpg"]);
+        assert_eq!(
+            tagged("photos/", &["place:alps"]),
+            ["photos/a.jpg", "photos/c.jpg"]
+        );
+        assert!(tagged("", &["album:2025"]).is_empty());
+        assert_eq!(
+            storage.tags(&public_key, "photos/a.jpg").unwrap(),
+            ["album:2024", "place:alps"]
+        );
+
+        // Tags belong to the value they were written with
+        storage
+            .put(public_key, "photos/a.jpg".to_string(), vec![2])
+            .unwrap();
+        assert!(storage
+            .tags(&public_key, "photos/a.jpg")
+            .unwrap()
+            .is_empty());
+        storage.delete(&public_key, "photos/b.jpg").unwrap();
+        assert!(tagged("", &["album:2024"]).is_empty());
+        storage.purge(&public_key);
+        assert!(tagged("", &["place:alps"]).is_empty());
+    }
+
+    /// A write or delete of one of a few paths
+    #[derive(Debug, Clone)]
+    enum Op {
+        Put(String, Vec<u8>),
+        Delete(String),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let path = "[a-c]{1,2}(/[a-c._-]{1,3}){0,2}";
+        prop_oneof![
+            (path, vec(any::<u8>(), 0..64)).prop_map(|(path, value)| Op::Put(path, value)),
+            path.prop_map(Op::Delete),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_storage_invariants(ops in vec(op(), 0..64), prefix in "[a-c]{0,2}/?") {
+            let storage = Storage::new();
+            let public_key = Keypair::random().public_key();
+            let other = Keypair::random().public_key();
+            storage.put(other, "a".to_string(), vec![1]).unwrap();
+            let mut model = BTreeMap::new();
+
+            for op in ops {
+                match op {
+                    Op::Put(path, value) => {
+                        storage.put(public_key, path.clone(), value.clone()).unwrap();
+                        // Put then get
+                        prop_assert_eq!(storage.get(&public_key, &path).unwrap(), Some(value.clone()));
+                        model.insert(path, value);
+                    }
+                    Op::Delete(path) => {
+                        let existed = model.remove(&path).is_some();
+                        prop_assert_eq!(storage.delete(&public_key, &path).unwrap(), existed);
+                        // Delete then miss
+                        prop_assert_eq!(storage.get(&public_key, &path).unwrap(), None);
+                    }
+                }
+            }
+
+            for (path, value) in &model {
+                prop_assert_eq!(storage.get(&public_key, path).unwrap(), Some(value.clone()));
+            }
+            let mut listed = storage.list(&public_key, &prefix);
+            listed.sort();
+            let expected: Vec<_> = model.keys().filter(|path| path.starts_with(&prefix)).cloned().collect();
+            prop_assert_eq!(listed, expected);
+            // Other keys are untouched
+            prop_assert_eq!(storage.list(&other, ""), vec!["a".to_string()]);
+        }
+    }
 }
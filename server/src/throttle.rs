@@ -0,0 +1,262 @@
+//! Per-user write throttling
+//!
+//! Caps the write throughput (operations and bytes per second) of each
+//! public key with token buckets, so a single runaway client cannot
+//! monopolize the server. Each bucket allows bursts up to its capacity.
+
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::routes::{self, ApiError};
+
+/// Number of tracked users above which idle buckets are pruned
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Write throughput limits applied to each public key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThrottleConfig {
+    /// Sustained write operations per second
+    pub ops_per_sec: f64,
+    /// Maximum burst of write operations
+    pub ops_burst: f64,
+    /// Sustained bytes written per second
+    pub bytes_per_sec: f64,
+    /// Maximum burst of bytes written
+    pub bytes_burst: f64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            ops_per_sec: 10.0,
+            ops_burst: 50.0,
+            bytes_per_sec: 1024.0 * 1024.0,
+            bytes_burst: 16.0 * 1024.0 * 1024.0,
+        }
+    }
+}
+
+/// A token bucket refilled continuously at a fixed rate
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    /// Unix timestamp in milliseconds of the last refill
+    updated: u64,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64, now: u64) -> Self {
+        Self {
+            tokens: capacity,
+            capacity,
+            rate,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.updated) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = self.updated.max(now);
+    }
+
+    /// Time until `cost` tokens are available, or zero if they are now
+    ///
+    /// Costs larger than the capacity are allowed once the bucket is full,
+    /// leaving it in debt, so oversized writes are slowed but not rejected
+    /// forever.
+    fn wait_time(&self, cost: f64) -> Duration {
+        let needed = cost.min(self.capacity);
+        if self.tokens >= needed {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((needed - self.tokens) / self.rate)
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+/// Per-user pair of operation and byte buckets
+struct UserBuckets {
+    ops: TokenBucket,
+    bytes: TokenBucket,
+}
+
+/// Write throttle tracking every public key that recently wrote
+pub struct WriteThrottle {
+    config: ThrottleConfig,
+    users: Mutex<HashMap<String, UserBuckets>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl WriteThrottle {
+    /// Create a throttle with the given limits, refilling buckets as
+    /// `clock` moves
+    pub fn new(config: ThrottleConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            config,
+            users: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Charge one write of `bytes` to a user
+    ///
+    /// Returns how long the client should wait if the write is throttled.
+    pub fn check(&self, user: &str, bytes: u64) -> Result<(), Duration> {
+        self.check_at(user, bytes, self.clock.now_millis())
+    }
+
+    fn check_at(&self, user: &str, bytes: u64, now: u64) -> Result<(), Duration> {
+        let mut users = self.users.lock().unwrap();
+
+        if users.len() > PRUNE_THRESHOLD {
+            users.retain(|_, buckets| {
+                buckets.ops.refill(now);
+                buckets.bytes.refill(now);
+                !(buckets.ops.is_full() && buckets.bytes.is_full())
+            });
+        }
+
+        let config = &self.config;
+        let buckets = users
+            .entry(user.to_string())
+            .or_insert_with(|| UserBuckets {
+                ops: TokenBucket::new(config.ops_burst, config.ops_per_sec, now),
+                bytes: TokenBucket::new(config.bytes_burst, config.bytes_per_sec, now),
+            });
+        buckets.ops.refill(now);
+        buckets.bytes.refill(now);
+
+        let bytes = bytes as f64;
+        let wait = buckets
+            .ops
+            .wait_time(1.0)
+            .max(buckets.bytes.wait_time(bytes));
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        buckets.ops.tokens -= 1.0;
+        buckets.bytes.tokens -= bytes;
+        Ok(())
+    }
+}
+
+/// Middleware throttling writes per public key
+pub(crate) async fn throttle_writes(
+    State(throttle): State<Arc<WriteThrottle>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return Ok(next.run(request).await);
+    }
+
+    // Nested routers see a stripped path, so use the original one
+    let path = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.0.path().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let user = path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    // Without a Content-Length the body has to be measured
+    let (request, bytes) = match content_length {
+        Some(bytes) => (request, bytes),
+        None => {
+            let (parts, body) = routes::read_body(request).await?;
+            let bytes = body.len() as u64;
+            (Request::from_parts(parts, Body::from(body)), bytes)
+        }
+    };
+
+    if let Err(retry_after) = throttle.check(&user, bytes) {
+        tracing::debug!("Throttled write by {} ({} bytes)", user, bytes);
+        return Err(ApiError::RateLimited(retry_after));
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+
+    fn config() -> ThrottleConfig {
+        ThrottleConfig {
+            ops_per_sec: 1.0,
+            ops_burst: 2.0,
+            bytes_per_sec: 100.0,
+            bytes_burst: 200.0,
+        }
+    }
+
+    #[test]
+    fn test_ops_burst_and_refill() {
+        let throttle = WriteThrottle::new(config(), Arc::new(SystemClock));
+        let start = 0;
+
+        assert!(throttle.check_at("alice", 0, start).is_ok());
+        assert!(throttle.check_at("alice", 0, start).is_ok());
+        let wait = throttle.check_at("alice", 0, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+
+        // Users are throttled independently
+        assert!(throttle.check_at("bob", 0, start).is_ok());
+
+        let later = start + 1_000;
+        assert!(throttle.check_at("alice", 0, later).is_ok());
+        assert!(throttle.check_at("alice", 0, later).is_err());
+    }
+
+    #[test]
+    fn test_bytes_limit() {
+        let throttle = WriteThrottle::new(config(), Arc::new(SystemClock));
+        let start = 0;
+
+        assert!(throttle.check_at("alice", 150, start).is_ok());
+        let wait = throttle.check_at("alice", 100, start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_oversized_write_goes_into_debt() {
+        let throttle = WriteThrottle::new(config(), Arc::new(SystemClock));
+        let start = 0;
+
+        // Larger than the burst, but allowed on a full bucket
+        assert!(throttle.check_at("alice", 500, start).is_ok());
+        // The debt has to be paid off before the next write
+        let wait = throttle.check_at("alice", 1, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs_f64(3.01));
+    }
+}
@@ -0,0 +1,62 @@
+//! TLS identities
+//!
+//! The QUIC endpoints and the HTTPS listener serve TLS with a certificate
+//! chain and private key given as PEM, or with a self-signed certificate of
+//! the server's own Ed25519 key. Clients can't check the latter against a
+//! certificate authority, but authenticate the server by pinning its public
+//! key.
+
+use pubky_common::Keypair;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::io;
+
+/// DER of a PKCS #8 Ed25519 private key up to the 32-byte secret key
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// Certificate chain and private key of a TLS endpoint
+#[derive(Debug)]
+pub struct TlsIdentity {
+    /// Certificate chain, leaf first
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl TlsIdentity {
+    /// Read the certificate chain and private key from PEM
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<Self> {
+        let cert_chain = rustls_pemfile::certs(&mut &*cert_pem).collect::<io::Result<Vec<_>>>()?;
+        let key = rustls_pemfile::private_key(&mut &*key_pem)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No private key in PEM"))?;
+        Ok(Self { cert_chain, key })
+    }
+
+    /// A self-signed certificate of the Ed25519 key of `keypair`, named
+    /// after its z-base-32 public key
+    pub fn from_keypair(keypair: &Keypair) -> io::Result<Self> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let mut der = ED25519_PKCS8_PREFIX.to_vec();
+        der.extend_from_slice(&keypair.secret_key());
+        let pkcs8 = PrivatePkcs8KeyDer::from(der);
+
+        let key_pair = rcgen::KeyPair::from_pkcs8_der_and_sign_algo(&pkcs8, &rcgen::PKCS_ED25519)
+            .map_err(invalid)?;
+        let cert = rcgen::CertificateParams::new(vec![keypair.public_key().to_z32()])
+            .and_then(|params| params.self_signed(&key_pair))
+            .map_err(invalid)?;
+        Ok(Self {
+            cert_chain: vec![cert.der().clone()],
+            key: PrivateKeyDer::Pkcs8(pkcs8),
+        })
+    }
+}
+
+impl Clone for TlsIdentity {
+    fn clone(&self) -> Self {
+        Self {
+            cert_chain: self.cert_chain.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
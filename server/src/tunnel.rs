@@ -0,0 +1,503 @@
+//! Tunnels through a public relay
+//!
+//! A homeserver behind NAT can't accept connections, but it can open them.
+//! With [`ServerBuilder::tunnel`](crate::ServerBuilder::tunnel), it signs in
+//! to a relay with its keypair and long-polls the relay for requests. The
+//! relay, a server built with
+//! [`ServerBuilder::tunnel_relay`](crate::ServerBuilder::tunnel_relay),
+//! queues requests sent to `/tunnel/{public_key}/...` for the homeserver
+//! with that public key and answers them with the responses it posts back,
+//! so the homeserver is reachable at `<relay>/tunnel/<public_key>` as long
+//! as it can reach the relay.
+//!
+//! Requests and responses are relayed whole, so streaming responses such
+//! as change feeds can't go through a tunnel.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, RawQuery, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use pubky_common::auth::AuthToken;
+use pubky_common::{Keypair, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use crate::session::MAX_NONCE_LEN;
+use crate::storage::Storage;
+
+/// How long a poll waits for a request before the relay answers with
+/// `204 No Content`
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// How long the relay waits for the homeserver to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests queued for a homeserver before the relay rejects new ones
+const MAX_QUEUED: usize = 64;
+
+/// Delay before reconnecting after the relay failed
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Headers that describe a single connection, and aren't relayed
+const HOP_BY_HOP: [HeaderName; 4] = [
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::HOST,
+    header::TRANSFER_ENCODING,
+];
+
+/// How to reach the relay from behind NAT
+#[derive(Clone)]
+pub struct TunnelConfig {
+    /// Base URL of the relay, e.g. `https://relay.example.com`
+    pub relay_url: String,
+    /// Keypair the homeserver is reachable by on the relay
+    pub keypair: Keypair,
+}
+
+impl TunnelConfig {
+    /// Tunnel through the relay at `relay_url` as `keypair`
+    pub fn new(relay_url: impl Into<String>, keypair: Keypair) -> Self {
+        Self {
+            relay_url: relay_url.into().trim_end_matches('/').to_string(),
+            keypair,
+        }
+    }
+
+    /// Base URL the homeserver is reachable at through the relay
+    pub fn public_url(&self) -> String {
+        format!("{}/tunnel/{}", self.relay_url, self.keypair.public_key())
+    }
+}
+
+/// A client request relayed to a homeserver
+#[derive(Debug, Serialize, Deserialize)]
+struct TunnelRequest {
+    id: String,
+    method: String,
+    /// Path and query on the homeserver
+    uri: String,
+    headers: Vec<(String, String)>,
+    /// Base64-encoded body
+    body: String,
+}
+
+/// A homeserver's response to a [`TunnelRequest`]
+#[derive(Debug, Serialize, Deserialize)]
+struct TunnelResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Base64-encoded body
+    body: String,
+}
+
+/// Body of `POST /tunnel/connect` responses
+#[derive(Debug, Serialize, Deserialize)]
+struct Connected {
+    /// Bearer token of the polls and responses of this connection
+    secret: String,
+}
+
+/// Keep the headers that can be relayed, as strings
+fn relayed_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| !HOP_BY_HOP.contains(name))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+fn header_map(headers: &[(String, String)]) -> HeaderMap {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = HeaderName::try_from(name.as_str()).ok()?;
+            let value = HeaderValue::try_from(value.as_str()).ok()?;
+            Some((name, value)).filter(|(name, _)| !HOP_BY_HOP.contains(name))
+        })
+        .collect()
+}
+
+/// A homeserver connected to the relay
+struct Tunnel {
+    secret: String,
+    requests: mpsc::Sender<TunnelRequest>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<TunnelRequest>>,
+    /// Clients waiting for a response, by request id
+    pending: Mutex<HashMap<String, oneshot::Sender<TunnelResponse>>>,
+}
+
+#[derive(Clone)]
+struct RelayState {
+    tunnels: Arc<Mutex<HashMap<PublicKey, Arc<Tunnel>>>>,
+    /// Remembers the nonces of the tokens homeservers connected with
+    storage: Arc<Storage>,
+    /// Public key the tokens must be addressed to
+    identity: PublicKey,
+    /// Cancelled on shutdown, ending pending polls
+    closing: CancellationToken,
+}
+
+impl RelayState {
+    /// The tunnel whose secret is the bearer token of the request
+    fn authenticate(&self, headers: &HeaderMap) -> Option<Arc<Tunnel>> {
+        let secret = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+        let tunnels = self.tunnels.lock().unwrap();
+        tunnels.values().find(|t| t.secret == secret).cloned()
+    }
+}
+
+/// Create the tunnel relay routes
+pub(crate) fn relay_routes<S>(
+    storage: Arc<Storage>,
+    identity: PublicKey,
+    closing: CancellationToken,
+) -> Router<S> {
+    Router::new()
+        .route("/connect", post(connect))
+        .route("/poll", get(poll))
+        .route("/respond/{id}", post(respond))
+        .route("/{public_key}/{*path}", any(forward))
+        .with_state(RelayState {
+            tunnels: Default::default(),
+            storage,
+            identity,
+            closing,
+        })
+}
+
+/// POST /tunnel/connect
+/// Open a tunnel for the signer of the token, replacing any previous one
+async fn connect(
+    State(state): State<RelayState>,
+    Json(token): Json<AuthToken>,
+) -> Result<Json<Connected>, StatusCode> {
+    let public_key = token
+        .verify_at(&state.identity, state.storage.now_millis())
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let expires_at = token.timestamp + AuthToken::MAX_CLOCK_SKEW_MS;
+    let storage = &state.storage;
+    if token.nonce.len() > MAX_NONCE_LEN
+        || !storage.use_nonce(&public_key, &token.nonce, expires_at)
+    {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let secret = BASE64.encode(rand::random::<[u8; 32]>());
+    let (requests, incoming) = mpsc::channel(MAX_QUEUED);
+    let tunnel = Tunnel {
+        secret: secret.clone(),
+        requests,
+        incoming: tokio::sync::Mutex::new(incoming),
+        pending: Default::default(),
+    };
+    state
+        .tunnels
+        .lock()
+        .unwrap()
+        .insert(public_key, Arc::new(tunnel));
+
+    tracing::info!("Opened a tunnel to {}", public_key);
+    Ok(Json(Connected { secret }))
+}
+
+/// GET /tunnel/poll
+/// The next request for the homeserver, or `204 No Content` if none came
+/// in time
+async fn poll(State(state): State<RelayState>, headers: HeaderMap) -> Response {
+    let Some(tunnel) = state.authenticate(&headers) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let mut incoming = tunnel.incoming.lock().await;
+    tokio::select! {
+        received = tokio::time::timeout(POLL_TIMEOUT, incoming.recv()) => match received {
+            Ok(Some(request)) => Json(request).into_response(),
+            _ => StatusCode::NO_CONTENT.into_response(),
+        },
+        _ = state.closing.cancelled() => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+/// POST /tunnel/respond/{id}
+/// Answer a relayed request
+async fn respond(
+    State(state): State<RelayState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(response): Json<TunnelResponse>,
+) -> StatusCode {
+    let Some(tunnel) = state.authenticate(&headers) else {
+        return StatusCode::UNAUTHORIZED;
+    };
+    let waiting = tunnel.pending.lock().unwrap().remove(&id);
+    match waiting.map(|client| client.send(response)) {
+        Some(Ok(())) => StatusCode::NO_CONTENT,
+        // The client gave up waiting
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+/// ANY /tunnel/{public_key}/{*path}
+/// Relay a client request to the homeserver and wait for its response
+async fn forward(
+    State(state): State<RelayState>,
+    Path((public_key, path)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Ok(public_key) = PublicKey::from_z32(&public_key) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some(tunnel) = state.tunnels.lock().unwrap().get(&public_key).cloned() else {
+        return (StatusCode::BAD_GATEWAY, "No tunnel to this homeserver").into_response();
+    };
+
+    let id = format!("{:016x}", rand::random::<u64>());
+    let (respond, response) = oneshot::channel();
+    tunnel.pending.lock().unwrap().insert(id.clone(), respond);
+    let request = TunnelRequest {
+        id: id.clone(),
+        method: method.to_string(),
+        uri: match query {
+            Some(query) => format!("/{}?{}", path, query),
+            None => format!("/{}", path),
+        },
+        headers: relayed_headers(&headers),
+        body: BASE64.encode(&body),
+    };
+    if tunnel.requests.try_send(request).is_err() {
+        tunnel.pending.lock().unwrap().remove(&id);
+        return (StatusCode::SERVICE_UNAVAILABLE, "Too many queued requests").into_response();
+    }
+
+    let response = match tokio::time::timeout(RESPONSE_TIMEOUT, response).await {
+        Ok(Ok(response)) => response,
+        // The tunnel was replaced, dropping its pending clients
+        Ok(Err(_)) => return StatusCode::BAD_GATEWAY.into_response(),
+        Err(_) => {
+            tunnel.pending.lock().unwrap().remove(&id);
+            return StatusCode::GATEWAY_TIMEOUT.into_response();
+        }
+    };
+    let Ok(body) = BASE64.decode(&response.body) else {
+        return StatusCode::BAD_GATEWAY.into_response();
+    };
+    let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::BAD_GATEWAY);
+    (status, header_map(&response.headers), Body::from(body)).into_response()
+}
+
+/// Keep a tunnel to the relay open and serve the requests it relays, until
+/// the task is aborted
+pub(crate) async fn tunnel_loop(config: TunnelConfig, local_addr: SocketAddr) {
+    let http = reqwest::Client::new();
+    let mut local = local_addr;
+    if local.ip().is_unspecified() {
+        local.set_ip(std::net::Ipv4Addr::LOCALHOST.into());
+    }
+    let local = format!("http://{}", local);
+
+    loop {
+        match open(&http, &config).await {
+            Ok(secret) => {
+                tracing::info!("Reachable at {}", config.public_url());
+                let error = serve(&http, &config, &local, &secret).await;
+                tracing::warn!("Lost the tunnel to {}: {}", config.relay_url, error);
+            }
+            Err(e) => tracing::warn!("Failed to open a tunnel to {}: {}", config.relay_url, e),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// Sign in to the relay, returning the secret of the connection
+async fn open(
+    http: &reqwest::Client,
+    config: &TunnelConfig,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let identity = http
+        .get(format!("{}/identity", config.relay_url))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let identity = PublicKey::from_z32(identity.trim())?;
+    let connected: Connected = http
+        .post(format!("{}/tunnel/connect", config.relay_url))
+        .json(&AuthToken::sign(&config.keypair, &identity))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(connected.secret)
+}
+
+/// Poll the relay and answer the requests it relays, until polling fails
+async fn serve(
+    http: &reqwest::Client,
+    config: &TunnelConfig,
+    local: &str,
+    secret: &str,
+) -> reqwest::Error {
+    loop {
+        let response = http
+            .get(format!("{}/tunnel/poll", config.relay_url))
+            .bearer_auth(secret)
+            .timeout(POLL_TIMEOUT + RETRY_DELAY)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let request: TunnelRequest = match response {
+            Ok(response) if response.status() == StatusCode::NO_CONTENT => continue,
+            Ok(response) => match response.json().await {
+                Ok(request) => request,
+                Err(e) => return e,
+            },
+            Err(e) => return e,
+        };
+
+        let (http, relay_url, local, secret) = (
+            http.clone(),
+            config.relay_url.clone(),
+            local.to_string(),
+            secret.to_string(),
+        );
+        tokio::spawn(async move {
+            let id = request.id.clone();
+            let response = handle(&http, &local, request).await;
+            let url = format!("{}/tunnel/respond/{}", relay_url, id);
+            if let Err(e) = http
+                .post(url)
+                .bearer_auth(secret)
+                .json(&response)
+                .send()
+                .await
+            {
+                tracing::debug!("Failed to answer a relayed request: {}", e);
+            }
+        });
+    }
+}
+
+/// Answer a relayed request by sending it to the homeserver itself
+async fn handle(http: &reqwest::Client, local: &str, request: TunnelRequest) -> TunnelResponse {
+    let response = async {
+        let method = Method::from_bytes(request.method.as_bytes()).map_err(|e| e.to_string())?;
+        let body = BASE64.decode(&request.body).map_err(|e| e.to_string())?;
+        let response = http
+            .request(method, format!("{}{}", local, request.uri))
+            .headers(header_map(&request.headers))
+            .body(body)
+            .timeout(RESPONSE_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+        let headers = relayed_headers(response.headers());
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        Ok::<_, String>(TunnelResponse {
+            status,
+            headers,
+            body: BASE64.encode(&body),
+        })
+    };
+    response.await.unwrap_or_else(|e| TunnelResponse {
+        status: StatusCode::BAD_GATEWAY.as_u16(),
+        headers: Vec::new(),
+        body: BASE64.encode(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_auth::SignWrite;
+    use crate::Server;
+
+    #[tokio::test]
+    async fn test_tunnel() {
+        let relay = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .tunnel_relay(true)
+            .start()
+            .await
+            .unwrap();
+        let keypair = Keypair::random();
+        let config = TunnelConfig::new(relay.url(), keypair.clone());
+        let url = config.public_url();
+
+        // Nothing is reachable before the homeserver connects
+        let http = reqwest::Client::new();
+        let owner = Keypair::random();
+        let user = owner.public_key();
+        let entry = format!("{}/{}/pub/hello.txt", url, user);
+        let response = http.get(&entry).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let homeserver = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .tunnel(config)
+            .start()
+            .await
+            .unwrap();
+        homeserver
+            .storage()
+            .put(user, "pub/hello.txt".to_string(), b"Hello!".to_vec())
+            .unwrap();
+
+        let mut response = None;
+        for _ in 0..50 {
+            let sent = http.get(&entry).send().await.unwrap();
+            if sent.status() != StatusCode::BAD_GATEWAY {
+                response = Some(sent);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let response = response.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), "Hello!");
+
+        // Queries, methods and error statuses go through too
+        let response = http
+            .get(format!("{}/{}/pub/?limit=1", url, user))
+            .send()
+            .await
+            .unwrap();
+        let keys: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(keys["keys"][0], "pub/hello.txt");
+        let delete = http.delete(&entry).signed(&owner, &homeserver.identity());
+        let response = delete.send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = http.get(&entry).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Polls need the secret of a connection
+        let response = http
+            .get(format!("{}/tunnel/poll", relay.url()))
+            .bearer_auth("guess")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        homeserver.shutdown().await;
+        relay.shutdown().await;
+    }
+}
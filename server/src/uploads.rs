@@ -0,0 +1,507 @@
+//! Resumable uploads
+//!
+//! With [`ServerBuilder::uploads`](crate::ServerBuilder::uploads), large
+//! values can be uploaded over several requests, so a dropped connection
+//! only loses the bytes in flight:
+//!
+//! - `POST /uploads/{public_key}/{path}` with an `Upload-Length` header
+//!   starts an upload of that many bytes to the entry, at the `Location` it
+//!   answers with
+//! - `PATCH /uploads/{id}` with an `Upload-Offset` header appends its body
+//!   at that offset, answering with the new `Upload-Offset`
+//! - `HEAD /uploads/{id}` tells the offset to resume from after a failure
+//! - `DELETE /uploads/{id}` cancels the upload
+//!
+//! Every request needs a session of the owner allowed to write the entry.
+//! Bytes received are kept in a file under [`UploadConfig::dir`] until the
+//! request appending the last of them, which is forwarded to the storage
+//! routes as a `PUT` of the whole value with its headers: the write is
+//! authorized, moderated and stored as any other, and its response is the
+//! upload's. Failed writes keep the upload, to retry with an empty `PATCH`
+//! at its end. Uploads receiving nothing for [`UploadConfig::ttl`] are
+//! dropped, as are those of previous runs of the server.
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{patch, post},
+    Json, RequestExt as _, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use futures_util::StreamExt;
+use pubky_common::capabilities::Action;
+use pubky_common::url::PubkyUrl;
+use pubky_common::PublicKey;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tower::ServiceExt;
+
+use crate::routes::{self, ApiError};
+use crate::session;
+use crate::storage::Storage;
+use crate::write_auth;
+
+/// Header giving the size of an upload, in bytes
+pub const UPLOAD_LENGTH_HEADER: &str = "upload-length";
+
+/// Header giving the bytes of an upload received so far
+pub const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+
+/// Suffix of the files holding uploads
+const PART_SUFFIX: &str = ".part";
+
+/// Configuration of resumable uploads
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// Directory of the files holding the bytes received so far
+    pub dir: PathBuf,
+    /// Largest upload accepted, in bytes
+    pub max_length: u64,
+    /// How long an upload is kept while receiving nothing
+    pub ttl: Duration,
+}
+
+impl UploadConfig {
+    /// Keep uploads of up to 4 GiB under `dir`, for a day without progress
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_length: 4 << 30,
+            ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// An upload in progress, whose file is removed when dropped
+struct Upload {
+    public_key: PublicKey,
+    path: String,
+    length: u64,
+    file: PathBuf,
+    /// `Content-Type` of the request starting the upload, for the entry
+    content_type: Option<HeaderValue>,
+    /// Bytes received so far
+    offset: AtomicU64,
+    /// Unix timestamp in milliseconds when bytes were last received
+    touched: AtomicU64,
+    /// Held while appending, so requests don't interleave
+    appending: tokio::sync::Mutex<()>,
+}
+
+impl Upload {
+    fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Acquire)
+    }
+
+    fn to_json(&self, id: &str) -> Value {
+        json!({
+            "id": id,
+            "url": PubkyUrl::new(self.public_key, self.path.clone()).to_string(),
+            "length": self.length,
+            "offset": self.offset(),
+        })
+    }
+
+    /// Headers telling the progress of the upload
+    fn headers(&self) -> [(&'static str, String); 2] {
+        [
+            (UPLOAD_LENGTH_HEADER, self.length.to_string()),
+            (UPLOAD_OFFSET_HEADER, self.offset().to_string()),
+        ]
+    }
+
+    /// Append `body` at the current offset, keeping what was received if
+    /// it fails midway
+    async fn append(&self, body: Body) -> Result<(), ApiError> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&self.file)
+            .await
+            .map_err(internal)?;
+        let mut offset = self.offset();
+        file.seek(SeekFrom::Start(offset)).await.map_err(internal)?;
+
+        let mut chunks = body.into_data_stream();
+        let appended = async {
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk.map_err(|e| routes::body_error(&e))?;
+                if offset + chunk.len() as u64 > self.length {
+                    return Err(ApiError::BadRequest(
+                        "Body runs past the end of the upload".to_string(),
+                    ));
+                }
+                file.write_all(&chunk).await.map_err(internal)?;
+                offset += chunk.len() as u64;
+                self.offset.store(offset, Ordering::Release);
+            }
+            Ok(())
+        };
+        let appended = appended.await;
+        file.flush().await.map_err(internal)?;
+        appended
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.file);
+    }
+}
+
+/// Uploads in progress, and where completed ones are written
+#[derive(Clone)]
+pub(crate) struct Uploads {
+    config: UploadConfig,
+    storage: Arc<Storage>,
+    uploads: Arc<RwLock<HashMap<String, Arc<Upload>>>>,
+    /// The storage routes, without a body limit
+    writes: Router,
+}
+
+impl Uploads {
+    /// Track uploads under the directory of `config`, dropping the files
+    /// left by previous runs, and write completed ones through `writes`
+    pub(crate) fn new(config: UploadConfig, storage: Arc<Storage>, writes: Router) -> Self {
+        if let Ok(files) = std::fs::read_dir(&config.dir) {
+            for file in files.flatten() {
+                if file.file_name().to_string_lossy().ends_with(PART_SUFFIX) {
+                    let _ = std::fs::remove_file(file.path());
+                }
+            }
+        }
+        Self {
+            config,
+            storage,
+            uploads: Arc::new(RwLock::new(HashMap::new())),
+            writes,
+        }
+    }
+
+    /// The upload `id`, if the request may append to it
+    fn get(&self, id: &str, headers: &HeaderMap) -> Result<Arc<Upload>, ApiError> {
+        self.expire();
+        let upload = self.uploads.read().unwrap().get(id).cloned();
+        let upload = upload.ok_or(ApiError::NotFound)?;
+        authorize(&self.storage, headers, &upload.public_key, &upload.path)?;
+        Ok(upload)
+    }
+
+    /// Drop the uploads that received nothing for too long
+    fn expire(&self) {
+        let ttl = self.config.ttl.as_millis() as u64;
+        let now = self.storage.now_millis();
+        self.uploads
+            .write()
+            .unwrap()
+            .retain(|_, upload| now.saturating_sub(upload.touched.load(Ordering::Relaxed)) < ttl);
+    }
+
+    /// Write the completed upload `id` through the storage routes, as a
+    /// `PUT` with the headers of the request completing it, but the
+    /// `Content-Type` of the one starting it
+    async fn finish(
+        &self,
+        id: &str,
+        upload: &Upload,
+        mut headers: HeaderMap,
+        remote: Option<ConnectInfo<SocketAddr>>,
+    ) -> Result<Response, ApiError> {
+        let file = tokio::fs::File::open(&upload.file)
+            .await
+            .map_err(internal)?;
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(upload.length));
+        headers.remove(header::CONTENT_TYPE);
+        if let Some(content_type) = &upload.content_type {
+            headers.insert(header::CONTENT_TYPE, content_type.clone());
+        }
+        let body = Body::from_stream(tokio_util::io::ReaderStream::new(file));
+        let mut request = Request::put(routes::uri_path(&upload.public_key, &upload.path))
+            .body(body)
+            .map_err(internal)?;
+        *request.headers_mut() = headers;
+        if let Some(remote) = remote {
+            request.extensions_mut().insert(remote);
+        }
+
+        let response = match self.writes.clone().oneshot(request).await {
+            Ok(response) => response,
+            Err(infallible) => match infallible {},
+        };
+        if response.status().is_success() {
+            self.uploads.write().unwrap().remove(id);
+        }
+        Ok(response)
+    }
+}
+
+/// Create the upload routes
+pub(crate) fn upload_routes<S>(uploads: Uploads) -> Router<S> {
+    Router::new()
+        .route("/{public_key}/{*path}", post(create_upload))
+        .route(
+            "/{id}",
+            patch(append_upload)
+                .get(upload_status)
+                .delete(cancel_upload),
+        )
+        .with_state(uploads)
+}
+
+/// POST /uploads/{public_key}/{path}
+/// Start an upload of `Upload-Length` bytes to an entry
+async fn create_upload(
+    State(uploads): State<Uploads>,
+    Path((public_key, path)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let PubkyUrl { public_key, path } = routes::entry_url(&public_key, &path)?;
+    authorize(&uploads.storage, &headers, &public_key, &path)?;
+    routes::ensure_writable(&uploads.storage, &public_key)?;
+    let length = headers
+        .get(UPLOAD_LENGTH_HEADER)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+        .ok_or_else(|| ApiError::BadRequest("Missing or invalid Upload-Length".to_string()))?;
+    if length > uploads.config.max_length {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    uploads.expire();
+    let id = BASE64_URL.encode(rand::random::<[u8; 16]>());
+    let file = uploads.config.dir.join(format!("{}{}", id, PART_SUFFIX));
+    tokio::fs::create_dir_all(&uploads.config.dir)
+        .await
+        .map_err(internal)?;
+    tokio::fs::File::create_new(&file).await.map_err(internal)?;
+    let upload = Arc::new(Upload {
+        public_key,
+        path,
+        length,
+        file,
+        content_type: headers.get(header::CONTENT_TYPE).cloned(),
+        offset: AtomicU64::new(0),
+        touched: AtomicU64::new(uploads.storage.now_millis()),
+        appending: tokio::sync::Mutex::new(()),
+    });
+    uploads
+        .uploads
+        .write()
+        .unwrap()
+        .insert(id.clone(), upload.clone());
+
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION.as_str(), format!("/uploads/{}", id))],
+        upload.headers(),
+        Json(upload.to_json(&id)),
+    )
+        .into_response())
+}
+
+/// GET /uploads/{id}
+/// The progress of an upload, also in the headers of `HEAD` requests
+async fn upload_status(
+    State(uploads): State<Uploads>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let upload = uploads.get(&id, &headers)?;
+    Ok((upload.headers(), Json(upload.to_json(&id))).into_response())
+}
+
+/// PATCH /uploads/{id}
+/// Append the body at `Upload-Offset`, writing the entry once complete
+async fn append_upload(
+    State(uploads): State<Uploads>,
+    Path(id): Path<String>,
+    request: Request,
+) -> Result<Response, ApiError> {
+    let upload = uploads.get(&id, request.headers())?;
+    let offset = request
+        .headers()
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|offset| offset.to_str().ok()?.parse::<u64>().ok())
+        .ok_or_else(|| ApiError::BadRequest("Missing or invalid Upload-Offset".to_string()))?;
+    let Ok(_appending) = upload.appending.try_lock() else {
+        return Err(ApiError::Conflict("Upload is busy".to_string()));
+    };
+    if offset != upload.offset() {
+        return Err(ApiError::Conflict(format!(
+            "Upload is at offset {}",
+            upload.offset()
+        )));
+    }
+
+    let remote = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .cloned();
+    let (parts, body) = request.with_limited_body().into_parts();
+    let appended = upload.append(body).await;
+    upload
+        .touched
+        .store(uploads.storage.now_millis(), Ordering::Relaxed);
+    appended?;
+    if upload.offset() < upload.length {
+        return Ok((StatusCode::NO_CONTENT, upload.headers()).into_response());
+    }
+    uploads.finish(&id, &upload, parts.headers, remote).await
+}
+
+/// DELETE /uploads/{id}
+/// Cancel an upload
+async fn cancel_upload(
+    State(uploads): State<Uploads>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    uploads.get(&id, &headers)?;
+    uploads.uploads.write().unwrap().remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Check that the request has a session of `public_key` allowed to write
+/// `path`
+fn authorize(
+    storage: &Storage,
+    headers: &HeaderMap,
+    public_key: &PublicKey,
+    path: &str,
+) -> Result<(), ApiError> {
+    let (_, session) = session::authenticate(storage, headers).ok_or(ApiError::Unauthorized)?;
+    if session.public_key != *public_key {
+        return Err(ApiError::Unauthorized);
+    }
+    match write_auth::allows(&session, path, Action::Write) {
+        true => Ok(()),
+        false => Err(ApiError::Forbidden),
+    }
+}
+
+/// An internal error of the upload's files
+fn internal(e: impl std::fmt::Display) -> ApiError {
+    ApiError::InternalError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use pubky_common::{auth::AuthToken, dto::SessionInfo, Keypair};
+
+    #[tokio::test]
+    async fn test_resumable_upload() {
+        let dir = std::env::temp_dir().join(format!("pubky-uploads-{}", rand::random::<u64>()));
+        let mut config = UploadConfig::new(&dir);
+        config.max_length = 100;
+        let server = Server::builder()
+            .bind(([127, 0, 0, 1], 0).into())
+            .uploads(config)
+            .start()
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let keypair = Keypair::random();
+        let public_key = keypair.public_key();
+        let response = http
+            .post(format!("{}/signup", server.url()))
+            .json(&AuthToken::sign(&keypair, &server.identity()))
+            .send()
+            .await
+            .unwrap();
+        let session: SessionInfo = response.json().await.unwrap();
+
+        let start = |length: u64| {
+            http.post(format!(
+                "{}/uploads/{}/pub/my file.txt",
+                server.url(),
+                public_key
+            ))
+            .bearer_auth(&session.token)
+            .header(UPLOAD_LENGTH_HEADER, length)
+            .send()
+        };
+        assert_eq!(start(101).await.unwrap().status(), 413);
+        let response = start(10).await.unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.headers()[UPLOAD_OFFSET_HEADER], "0");
+        let url = format!(
+            "{}{}",
+            server.url(),
+            response.headers()[header::LOCATION].to_str().unwrap()
+        );
+        let append = |offset: u64, body: &'static str| {
+            http.patch(&url)
+                .bearer_auth(&session.token)
+                .header(UPLOAD_OFFSET_HEADER, offset)
+                .body(body)
+                .send()
+        };
+
+        let response = append(0, "01234").await.unwrap();
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.headers()[UPLOAD_OFFSET_HEADER], "5");
+        assert_eq!(append(3, "34").await.unwrap().status(), 409);
+        assert_eq!(append(5, "56789ab").await.unwrap().status(), 400);
+        let unauthorized = http.patch(&url).header(UPLOAD_OFFSET_HEADER, 5).body("5");
+        assert_eq!(unauthorized.send().await.unwrap().status(), 401);
+
+        // Resume from where the server got to
+        let response = http
+            .head(&url)
+            .bearer_auth(&session.token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[UPLOAD_OFFSET_HEADER], "5");
+        assert_eq!(
+            server
+                .storage()
+                .get(&public_key, "pub/my file.txt")
+                .unwrap(),
+            None
+        );
+        let response = append(5, "56789").await.unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(
+            server
+                .storage()
+                .get(&public_key, "pub/my file.txt")
+                .unwrap()
+                .unwrap(),
+            b"0123456789"
+        );
+        let response = http
+            .get(&url)
+            .bearer_auth(&session.token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Cancelled uploads are dropped with their files
+        let response = start(10).await.unwrap();
+        let url = format!(
+            "{}{}",
+            server.url(),
+            response.headers()[header::LOCATION].to_str().unwrap()
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let cancel = http.delete(&url).bearer_auth(&session.token).send();
+        assert_eq!(cancel.await.unwrap().status(), 204);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        server.shutdown().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
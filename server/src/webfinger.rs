@@ -0,0 +1,304 @@
+//! Handles and WebFinger
+//!
+//! Signed-in accounts can claim a handle, such as `alice`, with
+//! `PUT /handle`. `GET /.well-known/webfinger?resource=acct:alice@<domain>`
+//! then resolves it to the account's public key and canonical `pubky://`
+//! URL, so people can share a memorable name instead of a key. Public keys
+//! of accounts hosted here resolve as handles of their own.
+//!
+//! The domain of a resource must be the host the request was sent to.
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use pubky_common::PublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::activitypub::{ActivityPubConfig, ACTIVITY_JSON};
+use crate::routes::{ensure_writable, ApiError};
+use crate::session::authenticate_root;
+use crate::storage::Storage;
+
+/// Longest handle
+pub const MAX_HANDLE_LEN: usize = 32;
+
+/// Property of WebFinger responses holding the z-base-32 public key
+const PUBLIC_KEY_PROPERTY: &str = "https://pubky.org/ns/public-key";
+
+#[derive(Clone)]
+struct WebFingerState {
+    storage: Arc<Storage>,
+    activitypub: Option<Arc<ActivityPubConfig>>,
+}
+
+/// Create the handle and WebFinger routes
+///
+/// With `activitypub`, responses also link to the account's actor.
+pub(crate) fn webfinger_routes<S>(
+    storage: Arc<Storage>,
+    activitypub: Option<ActivityPubConfig>,
+) -> Router<S> {
+    Router::new()
+        .route("/.well-known/webfinger", get(webfinger))
+        .route(
+            "/handle",
+            get(get_handle).put(claim_handle).delete(release_handle),
+        )
+        .with_state(WebFingerState {
+            storage,
+            activitypub: activitypub.map(Arc::new),
+        })
+}
+
+/// Body of `PUT /handle` and `GET /handle`
+#[derive(Debug, Serialize, Deserialize)]
+struct Handle {
+    handle: String,
+}
+
+/// Whether `handle` can be claimed: 1 to [`MAX_HANDLE_LEN`] lowercase ASCII
+/// letters, digits, `-`, `_` and `.`
+pub fn is_valid_handle(handle: &str) -> bool {
+    (1..=MAX_HANDLE_LEN).contains(&handle.len())
+        && handle.bytes().all(|b| {
+            b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'-' | b'_' | b'.')
+        })
+}
+
+/// PUT /handle
+/// Claim a handle for the current account, replacing its previous one
+async fn claim_handle(
+    State(state): State<WebFingerState>,
+    headers: HeaderMap,
+    Json(request): Json<Handle>,
+) -> Result<StatusCode, ApiError> {
+    let (_, session) = authenticate_root(&state.storage, &headers)?;
+    ensure_writable(&state.storage, &session.public_key)?;
+    let handle = request.handle.to_ascii_lowercase();
+    if !is_valid_handle(&handle) {
+        return Err(ApiError::BadRequest(format!("Invalid handle: {}", handle)));
+    }
+    if !state
+        .storage
+        .claim_handle(handle.clone(), session.public_key)
+    {
+        return Err(ApiError::Conflict(format!("Handle {} is taken", handle)));
+    }
+
+    tracing::info!("{} claimed the handle {}", session.public_key, handle);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /handle
+/// The handle of the current account
+async fn get_handle(
+    State(state): State<WebFingerState>,
+    headers: HeaderMap,
+) -> Result<Json<Handle>, ApiError> {
+    let (_, session) = authenticate_root(&state.storage, &headers)?;
+    let handle = state
+        .storage
+        .handle_of(&session.public_key)
+        .ok_or(ApiError::NotFound)?;
+    Ok(Json(Handle { handle }))
+}
+
+/// DELETE /handle
+/// Release the handle of the current account
+async fn release_handle(
+    State(state): State<WebFingerState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let (_, session) = authenticate_root(&state.storage, &headers)?;
+    match state.storage.release_handle(&session.public_key) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::NotFound),
+    }
+}
+
+#[derive(Deserialize)]
+struct WebFingerQuery {
+    resource: String,
+}
+
+/// GET /.well-known/webfinger?resource=acct:{handle}@{domain}
+/// Resolve a handle or public key to the account
+async fn webfinger(
+    State(state): State<WebFingerState>,
+    headers: HeaderMap,
+    Query(query): Query<WebFingerQuery>,
+) -> Result<Response, ApiError> {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let name = query
+        .resource
+        .strip_prefix("acct:")
+        .unwrap_or(&query.resource)
+        .rsplit_once('@')
+        .filter(|(_, domain)| domain.eq_ignore_ascii_case(host))
+        .ok_or(ApiError::NotFound)?
+        .0;
+    let public_key = resolve(&state.storage, name).ok_or(ApiError::NotFound)?;
+
+    let mut aliases = vec![format!("pubky://{}", public_key)];
+    let mut links = Vec::new();
+    if let Some(config) = &state.activitypub {
+        let actor = config.actor_id(&public_key);
+        aliases.push(actor.clone());
+        links.push(json!({ "rel": "self", "type": ACTIVITY_JSON, "href": actor }));
+    }
+    let body = json!({
+        "subject": query.resource,
+        "aliases": aliases,
+        "properties": { PUBLIC_KEY_PROPERTY: public_key.to_z32() },
+        "links": links,
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "application/jrd+json")],
+        body.to_string(),
+    )
+        .into_response())
+}
+
+/// The account a handle or public key names, if hosted here
+fn resolve(storage: &Storage, name: &str) -> Option<PublicKey> {
+    if let Some(public_key) = storage.handle_owner(&name.to_ascii_lowercase()) {
+        return Some(public_key);
+    }
+    PublicKey::from_z32(name)
+        .ok()
+        .filter(|public_key| storage.hosts(public_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use pubky_common::Keypair;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use crate::session::ROOT_CAPABILITIES;
+    use crate::storage::Session;
+
+    async fn call(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        token: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("host", "example.com")
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn sign_in(storage: &Storage, token: &str) -> PublicKey {
+        let public_key = Keypair::random().public_key();
+        storage.register(public_key);
+        storage.insert_session(
+            token.to_string(),
+            Session {
+                id: token.to_string(),
+                public_key,
+                device: None,
+                capabilities: ROOT_CAPABILITIES.to_string(),
+                created_at: 0,
+                last_used_at: 0,
+                expires_at: u64::MAX,
+            },
+        );
+        public_key
+    }
+
+    #[tokio::test]
+    async fn test_handles() {
+        let storage = Arc::new(Storage::new());
+        let config = ActivityPubConfig::new("https://example.com");
+        let router = webfinger_routes(storage.clone(), Some(config));
+        let alice = sign_in(&storage, "alice");
+        sign_in(&storage, "bob");
+
+        let claim = |handle: &str| Some(json!({ "handle": handle }));
+        assert_eq!(
+            call(&router, "PUT", "/handle", "alice", claim("Alice"))
+                .await
+                .0,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            call(&router, "PUT", "/handle", "bob", claim("alice"))
+                .await
+                .0,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            call(&router, "PUT", "/handle", "bob", claim("b@d")).await.0,
+            StatusCode::BAD_REQUEST
+        );
+        let (_, handle) = call(&router, "GET", "/handle", "alice", None).await;
+        assert_eq!(handle["handle"], "alice");
+
+        // App sessions can't take or release the account's handle
+        let app = Session {
+            id: "app".to_string(),
+            capabilities: "/pub/my-app/:rw".to_string(),
+            ..storage.session("alice").unwrap()
+        };
+        storage.insert_session("app".to_string(), app);
+        for (method, body) in [("PUT", claim("mallory")), ("DELETE", None)] {
+            let (status, _) = call(&router, method, "/handle", "app", body).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        assert_eq!(storage.handle_of(&alice).unwrap(), "alice");
+
+        // Handles and public keys resolve to the account
+        let uri = "/.well-known/webfinger?resource=acct:alice@example.com";
+        let (status, jrd) = call(&router, "GET", uri, "", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(jrd["subject"], "acct:alice@example.com");
+        assert_eq!(jrd["aliases"][0], format!("pubky://{}", alice));
+        assert_eq!(jrd["properties"][PUBLIC_KEY_PROPERTY], alice.to_z32());
+        assert_eq!(
+            jrd["links"][0]["href"],
+            format!("https://example.com/ap/users/{}", alice)
+        );
+        let uri = format!("/.well-known/webfinger?resource=acct:{}@example.com", alice);
+        assert_eq!(call(&router, "GET", &uri, "", None).await.0, StatusCode::OK);
+        let uri = "/.well-known/webfinger?resource=acct:alice@other.com";
+        assert_eq!(
+            call(&router, "GET", uri, "", None).await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        // Released handles no longer resolve
+        assert_eq!(
+            call(&router, "DELETE", "/handle", "alice", None).await.0,
+            StatusCode::NO_CONTENT
+        );
+        let uri = "/.well-known/webfinger?resource=acct:alice@example.com";
+        assert_eq!(
+            call(&router, "GET", uri, "", None).await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Write authorization
+//!
+//! Only the owner of a public key may write under it: every PUT and DELETE
+//! under `/{public_key}` must come with a session of the owner, as a bearer
+//! token or cookie, or carry a [`WriteSignature`] of the method, path and
+//! body by that key in the [`SIGNATURE_HEADER`]. Other writes, including
+//! those signed for another homeserver, outside the allowed clock skew or
+//! with a nonce that already authorized a write, are rejected with
+//! `401 Unauthorized` before moderation, scanning, throttling or storage see
+//! them. Signed bodies are read whole, up to the body limit, to check the
+//! signature; a nonce is only spent once its write succeeded, so failed
+//! writes can be retried as they were signed.
+//!
+//! Sessions are held to their [`Capabilities`]: an app granted
+//! `/pub/my-app/:rw` gets `403 Forbidden` when it reads or writes the
+//! owner's other entries with its session. Reads without a session stay
+//! public.
+//!
+//! On a [replica](crate::replica), writes reach this layer only once the
+//! primary accepted them, and are not authorized again.
+//!
+//! Requests that authenticate are given their account as an
+//! [`Authenticated`] extension, for the layers and routes behind.
+
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use pubky_common::auth::{AuthToken, WriteSignature, SIGNATURE_HEADER};
+use pubky_common::capabilities::{Action, Capabilities};
+use pubky_common::PublicKey;
+use std::sync::Arc;
+
+use crate::replica::AcceptedByPrimary;
+use crate::routes::{self, ApiError};
+use crate::session::{self, MAX_NONCE_LEN};
+use crate::storage::{Session, Storage};
+
+/// The account a request authenticated as, in its extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Authenticated(pub PublicKey);
+
+/// Middleware rejecting requests the owner of the entry didn't authorize
+pub(crate) async fn authorize_access(
+    State((storage, identity)): State<(Arc<Storage>, PublicKey)>,
+    Path((public_key, path)): Path<(String, String)>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let session = session::authenticate(&storage, request.headers()).map(|(_, session)| session);
+    if let Some(session) = &session {
+        request
+            .extensions_mut()
+            .insert(Authenticated(session.public_key));
+    }
+
+    if request.extensions().get::<AcceptedByPrimary>().is_some() {
+        if let Ok(public_key) = PublicKey::from_z32(&public_key) {
+            request.extensions_mut().insert(Authenticated(public_key));
+        }
+        return Ok(next.run(request).await);
+    }
+
+    let action = match *request.method() {
+        Method::GET | Method::HEAD => Action::Read,
+        Method::PUT | Method::DELETE => Action::Write,
+        _ => return Ok(next.run(request).await),
+    };
+    // Invalid keys are reported by the routes
+    let Ok(public_key) = PublicKey::from_z32(&public_key) else {
+        return Ok(next.run(request).await);
+    };
+    // Sessions only speak for their own account
+    let allowed = session
+        .filter(|session| session.public_key == public_key)
+        .map(|session| allows(&session, &path, action));
+    match (action, allowed) {
+        (_, Some(true)) | (Action::Read, None) => return Ok(next.run(request).await),
+        (Action::Read, Some(false)) => return Err(ApiError::Forbidden),
+        // The keypair can still sign writes outside the session
+        (Action::Write, _) => {}
+    }
+    let denied = match allowed {
+        Some(_) => ApiError::Forbidden,
+        None => ApiError::Unauthorized,
+    };
+
+    let signature: WriteSignature = request
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or(denied)?;
+    let (mut parts, body) = routes::read_body(request).await?;
+    let now = storage.now_millis();
+    let method = parts.method.as_str();
+    signature
+        .verify_at(&public_key, &identity, method, &path, &body, now)
+        .map_err(|_| ApiError::Unauthorized)?;
+    let expires_at = signature.timestamp + AuthToken::MAX_CLOCK_SKEW_MS;
+    if signature.nonce.len() > MAX_NONCE_LEN
+        || !storage.use_nonce(&public_key, &signature.nonce, expires_at)
+    {
+        return Err(ApiError::Unauthorized);
+    }
+    parts.extensions.insert(Authenticated(public_key));
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if !response.status().is_success() {
+        storage.release_nonce(&public_key, &signature.nonce);
+    }
+    Ok(response)
+}
+
+/// Whether the capabilities of `session` allow `action` on `path`
+pub(crate) fn allows(session: &Session, path: &str, action: Action) -> bool {
+    // Capabilities that don't parse allow nothing
+    session
+        .capabilities
+        .parse::<Capabilities>()
+        .is_ok_and(|capabilities| capabilities.allows(path, action))
+}
+
+/// Signing of writes sent with reqwest, for tests
+#[cfg(test)]
+pub(crate) trait SignWrite {
+    /// Sign the write with `keypair`, which must own the entry it targets,
+    /// for the homeserver `audience`
+    fn signed(self, keypair: &pubky_common::Keypair, audience: &PublicKey) -> Self;
+}
+
+#[cfg(test)]
+impl SignWrite for reqwest::RequestBuilder {
+    fn signed(self, keypair: &pubky_common::Keypair, audience: &PublicKey) -> Self {
+        let (client, request) = self.build_split();
+        let request = request.expect("valid request");
+        let owner = format!("/{}/", keypair.public_key());
+        let url_path = request.url().path();
+        let path = url_path.split_once(&owner).map_or("", |(_, path)| path);
+        let body = request.body().and_then(|body| body.as_bytes());
+        let method = request.method().as_str();
+        let body = body.unwrap_or_default();
+        let signature = WriteSignature::sign(keypair, audience, method, path, body);
+        reqwest::RequestBuilder::from_parts(client, request)
+            .header(SIGNATURE_HEADER, signature.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Session;
+    use crate::Server;
+    use axum::http::StatusCode;
+    use axum::Router;
+    use pubky_common::Keypair;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_write_authorization() {
+        let storage = Arc::new(Storage::new());
+        let identity = Keypair::random().public_key();
+        let router = Server::builder()
+            .storage(storage.clone())
+            .identity(identity)
+            .body_limit(16)
+            .router();
+        let keypair = Keypair::random();
+        let uri = format!("/{}/pub/a.txt", keypair.public_key());
+        let send = |method: Method, signature: Option<WriteSignature>, body: &'static str| {
+            let mut request = Request::builder().method(method).uri(&uri);
+            if let Some(signature) = signature {
+                request = request.header(SIGNATURE_HEADER, signature.to_string());
+            }
+            let request = request.body(Body::from(body)).unwrap();
+            router.clone().oneshot(request)
+        };
+
+        let status = |response: Result<Response, _>| response.unwrap().status();
+        assert_eq!(
+            status(send(Method::PUT, None, "data").await),
+            StatusCode::UNAUTHORIZED
+        );
+        let signature = WriteSignature::sign(&keypair, &identity, "PUT", "pub/a.txt", b"data");
+        assert_eq!(
+            status(send(Method::PUT, Some(signature.clone()), "data").await),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            storage
+                .get(&keypair.public_key(), "pub/a.txt")
+                .unwrap()
+                .unwrap(),
+            b"data"
+        );
+
+        // Signatures can't be replayed, nor carried over to other bodies,
+        // methods, signers or homeservers
+        assert_eq!(
+            status(send(Method::PUT, Some(signature.clone()), "data").await),
+            StatusCode::UNAUTHORIZED
+        );
+        let signature = WriteSignature::sign(&keypair, &identity, "PUT", "pub/a.txt", b"data");
+        assert_eq!(
+            status(send(Method::PUT, Some(signature.clone()), "evil").await),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(send(Method::DELETE, Some(signature), "").await),
+            StatusCode::UNAUTHORIZED
+        );
+        let stranger = Keypair::random();
+        let forged = WriteSignature::sign(&stranger, &identity, "DELETE", "pub/a.txt", b"");
+        assert_eq!(
+            status(send(Method::DELETE, Some(forged), "").await),
+            StatusCode::UNAUTHORIZED
+        );
+        let elsewhere = Keypair::random().public_key();
+        let signature = WriteSignature::sign(&keypair, &elsewhere, "DELETE", "pub/a.txt", b"");
+        assert_eq!(
+            status(send(Method::DELETE, Some(signature), "").await),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Bodies over the limit are rejected before they're read whole
+        let body = "a body over the limit";
+        let signature =
+            WriteSignature::sign(&keypair, &identity, "PUT", "pub/a.txt", body.as_bytes());
+        assert_eq!(
+            status(send(Method::PUT, Some(signature), body).await),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        // Failed writes can be retried with their signature
+        let signature = WriteSignature::sign(&keypair, &identity, "PUT", "pub/a.txt", b"new");
+        let request = Request::put(&uri)
+            .header(SIGNATURE_HEADER, signature.to_string())
+            .header("if-match", "\"stale\"")
+            .body(Body::from("new"))
+            .unwrap();
+        assert_eq!(
+            status(router.clone().oneshot(request).await),
+            StatusCode::PRECONDITION_FAILED
+        );
+        assert_eq!(
+            status(send(Method::PUT, Some(signature), "new").await),
+            StatusCode::CREATED
+        );
+        let signature = WriteSignature::sign(&keypair, &identity, "DELETE", "pub/a.txt", b"");
+        assert_eq!(
+            status(send(Method::DELETE, Some(signature), "").await),
+            StatusCode::NO_CONTENT
+        );
+
+        // Reads need no signature
+        assert_eq!(
+            status(send(Method::GET, None, "").await),
+            StatusCode::NOT_FOUND
+        );
+
+        // Sessions of the owner authorize what their capabilities allow
+        let session = |public_key: PublicKey, capabilities: &str| Session {
+            id: "id".to_string(),
+            public_key,
+            device: None,
+            capabilities: capabilities.to_string(),
+            created_at: 0,
+            last_used_at: 0,
+            expires_at: u64::MAX,
+        };
+        let sessions = [
+            ("root", keypair.public_key(), "/:rw"),
+            ("app", keypair.public_key(), "/pub/:rw"),
+            ("reader", keypair.public_key(), "/pub/a.txt:r"),
+            ("elsewhere", keypair.public_key(), "/pub/other/:rw"),
+            ("other", stranger.public_key(), "/:rw"),
+        ];
+        for (token, public_key, capabilities) in sessions {
+            storage.insert_session(token.to_string(), session(public_key, capabilities));
+        }
+        let call = |method: Method, token: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(&uri)
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::from("session"))
+                .unwrap();
+            router.clone().oneshot(request)
+        };
+        assert_eq!(status(call(Method::PUT, "root").await), StatusCode::CREATED);
+        assert_eq!(status(call(Method::PUT, "app").await), StatusCode::CREATED);
+        assert_eq!(
+            status(call(Method::PUT, "reader").await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(call(Method::GET, "reader").await), StatusCode::OK);
+        assert_eq!(
+            status(call(Method::GET, "elsewhere").await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(call(Method::DELETE, "elsewhere").await),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(call(Method::PUT, "other").await),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            storage
+                .get(&keypair.public_key(), "pub/a.txt")
+                .unwrap()
+                .unwrap(),
+            b"session"
+        );
+
+        // Routes behind see who the request authenticated as
+        let (parts, _) = Request::put(&uri)
+            .header("authorization", "Bearer root")
+            .body(Body::empty())
+            .unwrap()
+            .into_parts();
+        let seen = Router::new()
+            .route(
+                "/{public_key}/{*path}",
+                axum::routing::put(|request: Request| async move {
+                    let authenticated = request.extensions().get::<Authenticated>().copied();
+                    assert_eq!(authenticated, Some(Authenticated(keypair.public_key())));
+                    StatusCode::CREATED
+                }),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                (storage.clone(), identity),
+                authorize_access,
+            ));
+        let request = Request::from_parts(parts, Body::empty());
+        assert_eq!(status(seen.oneshot(request).await), StatusCode::CREATED);
+    }
+}
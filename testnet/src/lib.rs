@@ -0,0 +1,251 @@
+//! Hermetic homeservers for integration tests
+//!
+//! A [`Testnet`] runs a homeserver inside the test process, on a random
+//! local port or without any socket at all, and hands out clients already
+//! configured for it, so apps can test their pubky integration end to end
+//! without setting anything up:
+//!
+//! ```no_run
+//! # async fn example() -> pubky_client::Result<()> {
+//! use pubky_testnet::Testnet;
+//!
+//! let testnet = Testnet::run().await?;
+//! let (alice, client) = testnet.user("alice").await?;
+//!
+//! client.put(alice.public_key(), "pub/hello.txt", "Hello!").await?;
+//! let stored = testnet.storage().get(&alice.public_key(), "pub/hello.txt")?;
+//! assert_eq!(stored.unwrap(), b"Hello!");
+//!
+//! testnet.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Users named the same get the same keypair in every run, see [`keypair`].
+//!
+//! A [`Testnet::simulated`] homeserver tells time with a [`MockClock`], so
+//! tests can [`advance`](Testnet::advance) it past session and request TTLs
+//! instead of waiting them out.
+
+use axum::Router;
+use pubky_client::{PubkyClient, PubkyClientBuilder, Result};
+use pubky_common::Keypair;
+use pubky_server::{MockClock, Server, ServerBuilder, Storage};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use pubky_server::fixtures::{keypair, Fixture, Users};
+
+/// A homeserver running in this process
+pub struct Testnet {
+    storage: Arc<Storage>,
+    transport: Transport,
+    clock: Option<MockClock>,
+}
+
+/// How clients reach the homeserver
+enum Transport {
+    /// Over TCP, on a random local port
+    Tcp(Box<Server>),
+    /// By calling the router directly
+    InProcess(Router),
+}
+
+impl Testnet {
+    /// Start a default homeserver on a random local port
+    pub async fn run() -> io::Result<Self> {
+        Self::run_with(Server::builder()).await
+    }
+
+    /// Start a homeserver configured by `builder` on a random local port,
+    /// replacing any configured address
+    pub async fn run_with(builder: ServerBuilder) -> io::Result<Self> {
+        let server = builder.bind(([127, 0, 0, 1], 0).into()).start().await?;
+        Ok(Self {
+            storage: server.storage().clone(),
+            transport: Transport::Tcp(Box::new(server)),
+            clock: None,
+        })
+    }
+
+    /// Serve a default homeserver to clients without opening sockets
+    pub fn in_memory() -> Self {
+        Self::in_memory_with(Server::builder())
+    }
+
+    /// Serve a homeserver configured by `builder` to clients without opening
+    /// sockets, replacing any configured storage
+    ///
+    /// Background jobs of the builder, such as replication, don't run.
+    pub fn in_memory_with(builder: ServerBuilder) -> Self {
+        Self::serve_in_memory(builder, Storage::new(), None)
+    }
+
+    /// Serve a default homeserver without opening sockets, on virtual time
+    pub fn simulated() -> Self {
+        Self::simulated_with(Server::builder())
+    }
+
+    /// Serve a homeserver configured by `builder` without opening sockets,
+    /// on a clock that only moves with [`advance`](Self::advance)
+    ///
+    /// Virtual time starts at the current time. Clients sign auth tokens
+    /// with the real time, so signing in fails once virtual time runs ahead
+    /// by more than the allowed clock skew. An audit log given to the
+    /// builder keeps its own clock.
+    pub fn simulated_with(builder: ServerBuilder) -> Self {
+        let clock = MockClock::new();
+        let storage = Storage::with_clock(Arc::new(clock.clone()));
+        Self::serve_in_memory(builder, storage, Some(clock))
+    }
+
+    fn serve_in_memory(builder: ServerBuilder, storage: Storage, clock: Option<MockClock>) -> Self {
+        let storage = Arc::new(storage);
+        let router = builder.storage(storage.clone()).router();
+        Self {
+            storage,
+            transport: Transport::InProcess(router),
+            clock,
+        }
+    }
+
+    /// The URL of the homeserver, unless it's served in memory
+    pub fn url(&self) -> Option<String> {
+        self.server().map(Server::url)
+    }
+
+    /// The running server, unless it's served in memory
+    pub fn server(&self) -> Option<&Server> {
+        match &self.transport {
+            Transport::Tcp(server) => Some(&**server),
+            Transport::InProcess(_) => None,
+        }
+    }
+
+    /// The virtual clock of the homeserver, if it's simulated
+    pub fn clock(&self) -> Option<&MockClock> {
+        self.clock.as_ref()
+    }
+
+    /// Move the virtual time of the homeserver forward by `by`
+    ///
+    /// # Panics
+    ///
+    /// Unless the homeserver is [simulated](Self::simulated).
+    pub fn advance(&self, by: Duration) {
+        let clock = self
+            .clock
+            .as_ref()
+            .expect("only simulated testnets have virtual time");
+        clock.advance(by);
+    }
+
+    /// The storage of the homeserver, to seed or inspect it
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.storage
+    }
+
+    /// A builder for clients of the homeserver, to configure them further
+    pub fn client_builder(&self) -> PubkyClientBuilder {
+        match &self.transport {
+            Transport::Tcp(server) => PubkyClient::builder().homeserver(server.url()),
+            Transport::InProcess(router) => PubkyClient::builder().in_process(router.clone()),
+        }
+    }
+
+    /// A client of the homeserver, signed out
+    pub fn client(&self) -> PubkyClient {
+        self.client_builder()
+            .build()
+            .expect("default HTTP client configuration is valid")
+    }
+
+    /// Sign up the user named `name`, returning their keypair and a client
+    /// signed in as them
+    ///
+    /// Signups succeed on homeservers requiring invites too.
+    pub async fn user(&self, name: &str) -> Result<(Keypair, PubkyClient)> {
+        let keypair = keypair(name);
+        let client = self.client();
+        let invite = format!("testnet-{}", keypair.public_key());
+        self.storage.add_invite(invite.clone());
+        let signup = client.signup(&keypair, Some(&invite)).await;
+        // Drop the invite if the homeserver didn't need it
+        self.storage.take_invite(&invite);
+        signup?;
+        Ok((keypair, client))
+    }
+
+    /// Load `fixture` into the homeserver's storage, at the times it gives
+    /// on a simulated homeserver
+    ///
+    /// Its users have the same keypairs as those signed up with
+    /// [`user`](Self::user).
+    pub fn load(&self, fixture: Fixture) -> Users {
+        let fixture = match &self.clock {
+            Some(clock) => fixture.clock(clock.clone()),
+            None => fixture,
+        };
+        fixture.load(&self.storage)
+    }
+
+    /// Stop the homeserver and wait for it to finish
+    pub async fn shutdown(self) {
+        if let Transport::Tcp(server) = self.transport {
+            server.shutdown().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_testnet() {
+        assert_eq!(keypair("alice").public_key(), keypair("alice").public_key());
+        assert_ne!(keypair("alice").public_key(), keypair("bob").public_key());
+
+        let invited = Server::builder().require_invite(true);
+        for testnet in [
+            Testnet::run_with(invited).await.unwrap(),
+            Testnet::in_memory(),
+        ] {
+            let (alice, client) = testnet.user("alice").await.unwrap();
+            let alice = alice.public_key();
+            client.put(alice, "pub/hello.txt", "Hello!").await.unwrap();
+            assert!(testnet.storage().invites().is_empty());
+
+            // Anyone can read it back
+            let read = testnet.client().get(alice, "pub/hello.txt").await;
+            assert_eq!(read.unwrap().unwrap(), "Hello!");
+            let stored = testnet.storage().get(&alice, "pub/hello.txt").unwrap();
+            assert_eq!(stored.unwrap(), b"Hello!");
+
+            // Fixtures share the users' keypairs
+            let fixture = Fixture::new().user("bob", |bob| bob.many("pub/{}.txt", 3, 10));
+            let bob = testnet.load(fixture).public_key("bob");
+            assert_eq!(bob, keypair("bob").public_key());
+            let read = testnet.client().get(bob, "pub/3.txt").await;
+            assert_eq!(read.unwrap().unwrap(), "Lorem ipsu");
+
+            assert_eq!(testnet.url().is_some(), testnet.server().is_some());
+            testnet.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulated() {
+        let testnet = Testnet::simulated();
+        let (_, client) = testnet.user("alice").await.unwrap();
+        assert_eq!(client.sessions().await.unwrap().len(), 1);
+
+        // The session outlives its TTL by a second of virtual time only
+        testnet.advance(pubky_server::SESSION_TTL - Duration::from_secs(1));
+        assert_eq!(client.sessions().await.unwrap().len(), 1);
+        testnet.advance(Duration::from_secs(1));
+        assert!(client.sessions().await.is_err());
+        assert!(Testnet::in_memory().clock().is_none());
+    }
+}